@@ -0,0 +1,71 @@
+/*
+ * A C API for the bittorrent-starter-rust engine. Build the shared library with
+ *
+ *     cargo rustc --lib --release --crate-type cdylib
+ *
+ * and link against target/release/libbittorrent_starter_rust.so (.dylib on macOS).
+ *
+ * Functions that fail return NULL or -1; bt_last_error() then says why.
+ */
+#ifndef BITTORRENT_H
+#define BITTORRENT_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* A torrent read from a .torrent file. */
+typedef struct bt_torrent bt_torrent;
+
+/* A torrent downloading, and then seeding, until stopped. */
+typedef struct bt_download bt_download;
+
+/* What a torrent describes. The strings live as long as the torrent. */
+typedef struct bt_torrent_info {
+    const char *name;
+    /* 40 hex digits. */
+    const char *info_hash;
+    uint64_t length;
+    uint64_t piece_length;
+    size_t piece_count;
+    /* Zero for a single-file torrent. */
+    size_t file_count;
+} bt_torrent_info;
+
+/*
+ * How many pieces are verified, of how many, and the bytes they hold. Called on a thread of
+ * the download's own; it should return quickly and must not stop the download.
+ */
+typedef void (*bt_progress_callback)(void *user_data, size_t pieces, size_t piece_count,
+                                     uint64_t bytes);
+
+/* Why the last call on this thread that failed did, or NULL. Valid until the next failure. */
+const char *bt_last_error(void);
+
+/* Reads a .torrent file. Free the result with bt_torrent_free. */
+bt_torrent *bt_torrent_open(const char *path);
+
+/* Fills in info. Returns 0, or -1 if either argument is NULL. */
+int bt_torrent_get_info(const bt_torrent *torrent, bt_torrent_info *info);
+
+/* Frees a torrent. Downloads started from it carry on. */
+void bt_torrent_free(bt_torrent *torrent);
+
+/*
+ * Downloads torrent into download_dir, accepting peers on port, or a free port if 0. What is
+ * already on disk is checked first. callback may be NULL. End it with bt_download_stop.
+ */
+bt_download *bt_download_start(const bt_torrent *torrent, const char *download_dir, uint16_t port,
+                               bt_progress_callback callback, void *user_data);
+
+/* Stops a download, waiting for it to leave its swarm and save resume data, and frees it. */
+void bt_download_stop(bt_download *download);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -0,0 +1,80 @@
+//! Property tests without a framework: [`check`] runs a property against a few hundred
+//! generators, each seeded differently, and names the seed of the first case that fails so it
+//! can be replayed. Seeds are fixed, so every run tries the same cases.
+//!
+//! It stands in for proptest, which the crate's locked dependencies leave out, and does without
+//! shrinking: a failing case is reported as it was generated, not cut down to a smallest one.
+
+use std::panic::{self, AssertUnwindSafe};
+
+/// How many cases each property is tried against.
+const CASES: u64 = 256;
+
+/// Makes up test inputs from a seed, with xorshift64.
+#[derive(Debug)]
+pub struct Gen {
+    state: u64,
+}
+
+impl Gen {
+    pub fn new(seed: u64) -> Self {
+        // Spread small seeds out, as xorshift is slow to get going from them.
+        Self {
+            state: seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1,
+        }
+    }
+
+    pub fn u64(&mut self) -> u64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.state
+    }
+
+    /// A number below `bound`, which must not be zero.
+    pub fn below(&mut self, bound: usize) -> usize {
+        (self.u64() % bound as u64) as usize
+    }
+
+    pub fn bool(&mut self) -> bool {
+        self.u64() & 1 == 1
+    }
+
+    pub fn byte(&mut self) -> u8 {
+        self.u64() as u8
+    }
+
+    /// Up to `max_len` arbitrary bytes.
+    pub fn bytes(&mut self, max_len: usize) -> Vec<u8> {
+        let len = self.below(max_len + 1);
+        (0..len).map(|_| self.byte()).collect()
+    }
+
+    pub fn array<const N: usize>(&mut self) -> [u8; N] {
+        std::array::from_fn(|_| self.byte())
+    }
+
+    /// Up to `max_len` characters, mostly ASCII with some from anywhere in Unicode.
+    pub fn string(&mut self, max_len: usize) -> String {
+        let len = self.below(max_len + 1);
+        (0..len)
+            .map(|_| {
+                if self.below(4) == 0 {
+                    char::from_u32(self.u64() as u32 % 0x11_0000).unwrap_or('\u{fffd}')
+                } else {
+                    (b' ' + self.below(95) as u8) as char
+                }
+            })
+            .collect()
+    }
+}
+
+/// Runs `property` against every case, panicking with the failing case's seed if it panics.
+pub fn check(property: impl Fn(&mut Gen)) {
+    for seed in 0..CASES {
+        let result = panic::catch_unwind(AssertUnwindSafe(|| property(&mut Gen::new(seed))));
+        if result.is_err() {
+            panic!("property failed for the case with seed {}", seed);
+        }
+    }
+}
@@ -0,0 +1,165 @@
+use std::{net::SocketAddr, ops::Range};
+
+/// The size of the blocks a piece is requested in, unless configured otherwise.
+pub const BLOCK_SIZE: usize = 16384;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Block {
+    Missing,
+    Requested,
+    Received,
+}
+
+/// Where a block came from, so a corrupt piece can be blamed on whoever sent it.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum BlockSource {
+    Peer(SocketAddr),
+    WebSeed(String),
+}
+
+/// A piece put together from blocks that may come from different sources. The peer works
+/// through missing blocks from the front while web seeds take runs from the back, so a large
+/// piece is not held up by a single slow source.
+pub struct PieceAssembly {
+    data: Vec<u8>,
+    blocks: Vec<Block>,
+    sources: Vec<BlockSource>,
+    block_size: usize,
+}
+
+impl PieceAssembly {
+    /// Assembles a piece into `buffer`, which is as long as the piece, in blocks of
+    /// `block_size` bytes.
+    pub fn new(buffer: Vec<u8>, block_size: usize) -> Self {
+        Self {
+            blocks: vec![Block::Missing; buffer.len().div_ceil(block_size)],
+            data: buffer,
+            sources: Vec::new(),
+            block_size,
+        }
+    }
+
+    /// The byte range within the piece covered by `blocks`.
+    pub fn byte_range(&self, blocks: Range<usize>) -> Range<usize> {
+        blocks.start * self.block_size..usize::min(blocks.end * self.block_size, self.data.len())
+    }
+
+    /// The blocks that `bytes` within the piece touch.
+    pub fn blocks_covering(&self, bytes: Range<usize>) -> Range<usize> {
+        bytes.start / self.block_size..bytes.end.div_ceil(self.block_size)
+    }
+
+    /// Claims the first block nobody has asked for yet.
+    pub fn next_missing(&mut self) -> Option<usize> {
+        let block = self
+            .blocks
+            .iter()
+            .position(|block| *block == Block::Missing)?;
+        self.blocks[block] = Block::Requested;
+        Some(block)
+    }
+
+    /// Claims every block, for a source that fetches the whole piece in one go.
+    pub fn take_all(&mut self) -> Option<Range<usize>> {
+        if self.blocks.iter().any(|block| *block != Block::Missing) {
+            return None;
+        }
+        self.blocks.fill(Block::Requested);
+        Some(0..self.blocks.len())
+    }
+
+    /// Claims the back half of the last run of missing blocks, leaving the front of it to
+    /// whoever is already working through the piece.
+    pub fn take_back_half(&mut self) -> Option<Range<usize>> {
+        let end = self
+            .blocks
+            .iter()
+            .rposition(|block| *block == Block::Missing)?
+            + 1;
+        let start = self.blocks[..end]
+            .iter()
+            .rposition(|block| *block != Block::Missing)
+            .map_or(0, |block| block + 1);
+
+        let run = start + (end - start) / 2..end;
+        self.blocks[run.clone()].fill(Block::Requested);
+        Some(run)
+    }
+
+    /// Hands claimed blocks back, for a source that failed to deliver them.
+    pub fn release(&mut self, blocks: Range<usize>) {
+        for block in &mut self.blocks[blocks] {
+            if *block == Block::Requested {
+                *block = Block::Missing;
+            }
+        }
+    }
+
+    /// The piece's buffer, which blocks are written into at their offset.
+    pub fn buffer_mut(&mut self) -> &mut [u8] {
+        &mut self.data
+    }
+
+    /// Marks the blocks in `range` as written into the buffer by `source`.
+    pub fn received(&mut self, blocks: Range<usize>, source: BlockSource) {
+        self.blocks[blocks].fill(Block::Received);
+        if !self.sources.contains(&source) {
+            self.sources.push(source);
+        }
+    }
+
+    pub fn number_missing(&self) -> usize {
+        self.blocks
+            .iter()
+            .filter(|block| **block == Block::Missing)
+            .count()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.blocks.iter().all(|block| *block == Block::Received)
+    }
+
+    /// The piece's data and everyone who supplied part of it.
+    pub fn finish(self) -> (Vec<u8>, Vec<BlockSource>) {
+        (self.data, self.sources)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BlockSource, PieceAssembly, BLOCK_SIZE};
+
+    #[test]
+    fn splits_blocks_between_sources() {
+        let mut assembly = PieceAssembly::new(vec![0; BLOCK_SIZE * 7 + 100], BLOCK_SIZE);
+        assert_eq!(assembly.next_missing(), Some(0));
+        assert_eq!(assembly.take_all(), None);
+
+        // The web seed takes the back half of the seven blocks left.
+        let run = assembly.take_back_half().unwrap();
+        assert_eq!(run, 4..8);
+        assert_eq!(assembly.byte_range(run.clone()).end, BLOCK_SIZE * 7 + 100);
+        assert_eq!(assembly.number_missing(), 3);
+        assert_eq!(
+            assembly.blocks_covering(BLOCK_SIZE * 4 + 1..BLOCK_SIZE * 7 + 100),
+            run
+        );
+
+        assembly.release(run.clone());
+        assert_eq!(assembly.number_missing(), 7);
+        assert_eq!(assembly.take_back_half(), Some(4..8));
+
+        let peer = BlockSource::Peer(([127, 0, 0, 1], 6881).into());
+        let web_seed = BlockSource::WebSeed("http://example.com/payload".to_string());
+        assembly.received(0..1, peer.clone());
+        while let Some(block) = assembly.next_missing() {
+            assembly.received(block..block + 1, peer.clone());
+        }
+        assert!(!assembly.is_complete());
+        assert_eq!(assembly.take_back_half(), None);
+
+        assembly.received(run, web_seed.clone());
+        assert!(assembly.is_complete());
+        assert_eq!(assembly.finish().1, vec![peer, web_seed]);
+    }
+}
@@ -0,0 +1,239 @@
+use std::{
+    fmt::Display,
+    str::FromStr,
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::shutdown::Shutdown;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    Unlimited,
+    BytesPerSecond(u64),
+    Paused,
+}
+
+impl FromStr for Limit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "unlimited" => Ok(Limit::Unlimited),
+            "paused" => Ok(Limit::Paused),
+            rate => rate
+                .parse::<u64>()
+                .map(Limit::BytesPerSecond)
+                .map_err(|_| format!("Invalid rate limit: {}", rate)),
+        }
+    }
+}
+
+impl Display for Limit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Limit::Unlimited => write!(f, "unlimited"),
+            Limit::Paused => write!(f, "paused"),
+            Limit::BytesPerSecond(rate) => write!(f, "{}", rate),
+        }
+    }
+}
+
+/// A limit that applies between two times of day (UTC), written `HH:MM-HH:MM=<limit>` where the
+/// limit is a number of bytes per second, `unlimited` or `paused`. Windows may wrap past midnight.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScheduleWindow {
+    start: u32,
+    end: u32,
+    limit: Limit,
+}
+
+impl ScheduleWindow {
+    fn contains(&self, minute: u32) -> bool {
+        if self.start <= self.end {
+            minute >= self.start && minute < self.end
+        } else {
+            minute >= self.start || minute < self.end
+        }
+    }
+}
+
+impl FromStr for ScheduleWindow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (times, limit) = s
+            .split_once('=')
+            .ok_or_else(|| format!("Expected HH:MM-HH:MM=<limit>, got {}", s))?;
+        let (start, end) = times
+            .split_once('-')
+            .ok_or_else(|| format!("Expected HH:MM-HH:MM, got {}", times))?;
+
+        Ok(Self {
+            start: parse_time_of_day(start)?,
+            end: parse_time_of_day(end)?,
+            limit: limit.parse()?,
+        })
+    }
+}
+
+fn parse_time_of_day(time: &str) -> Result<u32, String> {
+    let invalid = || format!("Invalid time of day: {}", time);
+    let (hours, minutes) = time.split_once(':').ok_or_else(invalid)?;
+    let hours = hours.parse::<u32>().map_err(|_| invalid())?;
+    let minutes = minutes.parse::<u32>().map_err(|_| invalid())?;
+    if hours > 24 || minutes > 59 || (hours == 24 && minutes != 0) {
+        return Err(invalid());
+    }
+
+    Ok(hours * 60 + minutes)
+}
+
+/// Which limit applies at each time of day. The first matching window wins, and the default
+/// applies outside every window.
+#[derive(Debug, Clone)]
+pub struct BandwidthSchedule {
+    windows: Vec<ScheduleWindow>,
+    default: Limit,
+}
+
+impl BandwidthSchedule {
+    pub fn new(default: Limit, windows: Vec<ScheduleWindow>) -> Self {
+        Self { windows, default }
+    }
+
+    pub fn limit_at(&self, minute_of_day: u32) -> Limit {
+        self.windows
+            .iter()
+            .find(|window| window.contains(minute_of_day))
+            .map(|window| window.limit)
+            .unwrap_or(self.default)
+    }
+
+    /// Replaces the limit that applies outside every window.
+    pub fn set_default(&mut self, limit: Limit) {
+        self.default = limit;
+    }
+
+    pub fn current_limit(&self) -> Limit {
+        let seconds = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or(0);
+        self.limit_at((seconds % 86_400 / 60) as u32)
+    }
+}
+
+/// A token bucket whose rate follows a `BandwidthSchedule`. Callers block in `acquire` until
+/// they are allowed to transfer.
+#[derive(Debug)]
+pub struct RateLimiter {
+    schedule: BandwidthSchedule,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(schedule: BandwidthSchedule) -> Self {
+        Self {
+            schedule,
+            tokens: 0.0,
+            last_refill: Instant::now(),
+        }
+    }
+
+    pub fn schedule_mut(&mut self) -> &mut BandwidthSchedule {
+        &mut self.schedule
+    }
+
+    pub fn current_limit(&self) -> Limit {
+        self.schedule.current_limit()
+    }
+
+    /// Waits until `bytes` may be transferred. Returns early if a shutdown is requested while
+    /// transfers are paused.
+    pub fn acquire(&mut self, bytes: usize, shutdown: &Shutdown) {
+        loop {
+            let rate = match self.schedule.current_limit() {
+                Limit::Unlimited => return,
+                Limit::Paused => {
+                    if shutdown.is_requested() {
+                        return;
+                    }
+                    thread::sleep(Duration::from_secs(1));
+                    self.last_refill = Instant::now();
+                    continue;
+                }
+                Limit::BytesPerSecond(rate) => rate.max(1) as f64,
+            };
+
+            let now = Instant::now();
+            let burst = rate.max(bytes as f64);
+            self.tokens = (self.tokens + now.duration_since(self.last_refill).as_secs_f64() * rate)
+                .min(burst);
+            self.last_refill = now;
+
+            if self.tokens >= bytes as f64 {
+                self.tokens -= bytes as f64;
+                return;
+            }
+
+            let wait = (bytes as f64 - self.tokens) / rate;
+            thread::sleep(Duration::from_secs_f64(wait.min(1.0)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BandwidthSchedule, Limit, ScheduleWindow};
+
+    #[test]
+    fn parses_windows() {
+        let window = "09:00-17:30=1048576".parse::<ScheduleWindow>().unwrap();
+        assert_eq!(
+            window,
+            ScheduleWindow {
+                start: 540,
+                end: 1050,
+                limit: Limit::BytesPerSecond(1048576)
+            }
+        );
+        assert!("9-17=paused".parse::<ScheduleWindow>().is_err());
+        assert!("09:00-25:00=paused".parse::<ScheduleWindow>().is_err());
+    }
+
+    #[test]
+    fn limits_print_as_they_parse() {
+        for limit in ["unlimited", "paused", "1048576"] {
+            assert_eq!(limit.parse::<Limit>().unwrap().to_string(), limit);
+        }
+    }
+
+    #[test]
+    fn windows_can_wrap_midnight() {
+        let schedule = BandwidthSchedule::new(
+            Limit::BytesPerSecond(1024),
+            vec!["22:00-06:00=unlimited".parse().unwrap()],
+        );
+
+        assert_eq!(schedule.limit_at(23 * 60), Limit::Unlimited);
+        assert_eq!(schedule.limit_at(5 * 60), Limit::Unlimited);
+        assert_eq!(schedule.limit_at(12 * 60), Limit::BytesPerSecond(1024));
+    }
+
+    #[test]
+    fn first_matching_window_wins() {
+        let schedule = BandwidthSchedule::new(
+            Limit::Unlimited,
+            vec![
+                "12:00-13:00=paused".parse().unwrap(),
+                "09:00-17:00=2048".parse().unwrap(),
+            ],
+        );
+
+        assert_eq!(schedule.limit_at(12 * 60 + 30), Limit::Paused);
+        assert_eq!(schedule.limit_at(10 * 60), Limit::BytesPerSecond(2048));
+        assert_eq!(schedule.limit_at(18 * 60), Limit::Unlimited);
+    }
+}
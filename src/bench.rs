@@ -0,0 +1,223 @@
+//! Measures how fast we decode bencode, hash pieces and move pieces between two peers over
+//! loopback, so a slower engine shows up as a smaller number.
+
+use std::{
+    collections::HashMap,
+    fs,
+    hint::black_box,
+    io,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use sha1::{Digest, Sha1};
+
+use crate::{
+    bencode::{Bencode, Value},
+    bitfield::Bitfield,
+    coordinator::DownloadCoordinator,
+    create::{TorrentCreator, TorrentVersion},
+    listener::Listener,
+    peer_manager::PeerManager,
+    piece_cache::{PieceCache, DEFAULT_CACHE_SIZE},
+    seeding::Seeder,
+    storage::NullStorage,
+    torrent::Torrent,
+};
+
+// Hashing and transfers work a piece of this size at a time, a common size for real torrents.
+const PIECE_LENGTH: usize = 256 * 1024;
+// File entries in the document decoded over and over, making it about half a MiB.
+const DOCUMENT_FILES: usize = 8 * 1024;
+// How often the seeding side looks for the downloader to have connected.
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum BenchMode {
+    /// Decode a large torrent-like bencoded document
+    Decode,
+    /// SHA-1 piece hashing, as verification does it
+    Hash,
+    /// Download a torrent from a seeder in this process over loopback
+    Transfer,
+}
+
+/// How much a benchmark got through, and how long it took.
+#[derive(Debug, Clone, Copy)]
+pub struct Measurement {
+    pub bytes: u64,
+    pub elapsed: Duration,
+}
+
+impl Measurement {
+    /// Bytes per second.
+    pub fn rate(&self) -> f64 {
+        self.bytes as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+/// Runs the benchmark for `mode` over at least `size` bytes.
+pub fn run(mode: BenchMode, size: u64) -> io::Result<Measurement> {
+    match mode {
+        BenchMode::Decode => Ok(decode(size)),
+        BenchMode::Hash => Ok(hash(size)),
+        BenchMode::Transfer => transfer(size),
+    }
+}
+
+fn decode(size: u64) -> Measurement {
+    let document = Bencode::encode(&sample_document());
+    let started = Instant::now();
+    let mut bytes = 0;
+    while bytes < size {
+        let decoded = Bencode::new(&document).decode();
+        black_box(decoded.expect("The sample document is valid bencode"));
+        bytes += document.len() as u64;
+    }
+    Measurement {
+        bytes,
+        elapsed: started.elapsed(),
+    }
+}
+
+/// The sort of metainfo a large multi-file torrent has.
+fn sample_document() -> Value {
+    let files = (0..DOCUMENT_FILES)
+        .map(|index| {
+            let path = vec![
+                Value::String("disc".to_string()),
+                Value::String(format!("track-{:05}.flac", index)),
+            ];
+            Value::Dictionary(HashMap::from([
+                ("length".to_string(), Value::Number(index as i64 * 4099)),
+                ("path".to_string(), Value::List(path)),
+            ]))
+        })
+        .collect();
+    let info = HashMap::from([
+        ("name".to_string(), Value::String("bench".to_string())),
+        (
+            "piece length".to_string(),
+            Value::Number(PIECE_LENGTH as i64),
+        ),
+        ("pieces".to_string(), Value::Blob(pseudo_random(20 * 4096))),
+        ("files".to_string(), Value::List(files)),
+    ]);
+    Value::Dictionary(HashMap::from([
+        (
+            "announce".to_string(),
+            Value::String("http://tracker.example/announce".to_string()),
+        ),
+        ("info".to_string(), Value::Dictionary(info)),
+    ]))
+}
+
+fn hash(size: u64) -> Measurement {
+    let piece = pseudo_random(PIECE_LENGTH);
+    let started = Instant::now();
+    let mut bytes = 0;
+    while bytes < size {
+        black_box(Sha1::digest(black_box(&piece)));
+        bytes += piece.len() as u64;
+    }
+    Measurement {
+        bytes,
+        elapsed: started.elapsed(),
+    }
+}
+
+/// Makes a torrent of `size` bytes, seeds it from a listener on a loopback port and times a
+/// coordinator downloading and verifying all of it. Nothing touches a tracker.
+fn transfer(size: u64) -> io::Result<Measurement> {
+    let dir = tempfile::tempdir()?;
+    let data = dir.path().join("payload");
+    fs::write(&data, pseudo_random(size.max(1) as usize))?;
+    let creator = TorrentCreator {
+        announce: "http://127.0.0.1:1/announce".to_string(),
+        piece_length: PIECE_LENGTH,
+        private: true,
+        version: TorrentVersion::V1,
+    };
+    let torrent_path = dir.path().join("bench.torrent");
+    fs::write(&torrent_path, creator.create(&data)?.bytes)?;
+    let torrent = Torrent::open(&torrent_path).map_err(io::Error::other)?;
+
+    let seeding = Arc::new(Mutex::new(PeerManager::new()));
+    let listener = Listener::bind(0, vec![torrent.info_hash()])?;
+    let port = listener.port();
+    listener.spawn(seeding.clone());
+    let mut completed = Bitfield::new(torrent.info.pieces.len());
+    for index in 0..completed.len() {
+        completed.set(index);
+    }
+    let seeder = Seeder {
+        info: torrent.info.clone(),
+        path: data,
+        info_hash: torrent.info_hash_bytes(),
+        completed,
+        peer_manager: seeding.clone(),
+        piece_cache: Arc::new(Mutex::new(PieceCache::new(DEFAULT_CACHE_SIZE))),
+    };
+    thread::spawn(move || loop {
+        let peer_manager = seeding.lock().expect("Peer manager lock poisoned");
+        if let Some(peer) = peer_manager.inbound().first() {
+            if let Ok(socket) = peer.socket.try_clone() {
+                seeder.serve(peer.addr, socket);
+            }
+            return;
+        }
+        drop(peer_manager);
+        thread::sleep(ACCEPT_POLL_INTERVAL);
+    });
+
+    let length = torrent.info.length as u64;
+    let started = Instant::now();
+    let mut coordinator =
+        DownloadCoordinator::new(torrent, 0, Arc::new(Mutex::new(PeerManager::new())));
+    let peer = coordinator
+        .connect(&[format!("127.0.0.1:{}", port)])
+        .map_err(io::Error::other)?;
+    let (mut peer, _) = coordinator.handshake(peer).map_err(io::Error::other)?;
+    coordinator
+        .download_all_pieces(&mut peer, &mut NullStorage)
+        .map_err(io::Error::other)?;
+    let elapsed = started.elapsed();
+    peer.close();
+
+    if !coordinator.is_complete() {
+        return Err(io::Error::other("the transfer stopped before finishing"));
+    }
+    Ok(Measurement {
+        bytes: length,
+        elapsed,
+    })
+}
+
+/// `length` bytes that will not compress or repeat, from a fixed seed.
+fn pseudo_random(length: usize) -> Vec<u8> {
+    let mut state: u64 = 0x9e37_79b9_7f4a_7c15;
+    (0..length)
+        .map(|_| {
+            // xorshift64
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state >> 24) as u8
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{run, BenchMode};
+
+    #[test]
+    fn every_mode_gets_through_the_requested_size() {
+        for mode in [BenchMode::Decode, BenchMode::Hash, BenchMode::Transfer] {
+            let measurement = run(mode, 600 * 1024).unwrap();
+            assert!(measurement.bytes >= 600 * 1024, "{:?}", mode);
+            assert!(measurement.rate() > 0.0);
+        }
+    }
+}
@@ -57,27 +57,60 @@ impl Display for Value {
     }
 }
 
+// Lists and dictionaries nested deeper than this are refused, so hostile input cannot exhaust
+// the stack.
+const MAX_DEPTH: usize = 256;
+
 pub struct Bencode<'a> {
     bytes: &'a [u8],
     position: usize,
+    // How many lists and dictionaries we are inside.
+    depth: usize,
+}
+
+impl Value {
+    /// The value as JSON. Blobs are not valid UTF-8, so they become hex strings.
+    pub fn to_json(&self) -> serde_json::Value {
+        match self {
+            Value::String(string) => serde_json::Value::from(string.as_str()),
+            Value::Blob(blob) => serde_json::Value::from(hex::encode(blob)),
+            Value::Number(number) => serde_json::Value::from(*number),
+            Value::List(list) => list.iter().map(Value::to_json).collect(),
+            Value::Dictionary(map) => map
+                .iter()
+                .map(|(key, value)| (key.clone(), value.to_json()))
+                .collect::<serde_json::Map<_, _>>()
+                .into(),
+        }
+    }
 }
 
 impl<'a> Bencode<'a> {
     pub fn new(bytes: &'a [u8]) -> Self {
-        Self { bytes, position: 0 }
+        Self {
+            bytes,
+            position: 0,
+            depth: 0,
+        }
     }
 
-    pub fn decode(&mut self) -> Value {
+    pub fn decode(&mut self) -> Result<Value, BencodeError> {
         match self.peek() {
             Some('d') => self.decode_dictionary(),
             Some('l') => self.decode_list(),
             Some('i') => self.decode_integer(),
             Some(c) if c.is_ascii_digit() => self.decode_string(),
-            Some(c) => panic!("Unexpected character: {}", c),
-            None => panic!("Unexpected end of input"),
+            Some(c) => Err(BencodeError::Unexpected(c, self.position)),
+            None => Err(BencodeError::UnexpectedEnd),
         }
     }
 
+    /// How many bytes have been decoded so far. Anything after a decoded value is left alone,
+    /// so this is where trailing data such as a `ut_metadata` piece starts.
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
     // TODO: encode needs to take a custom value structure to differentiate between blobs and arrays of numbers
     #[allow(dead_code)]
     pub fn encode(value: &Value) -> Vec<u8> {
@@ -125,65 +158,85 @@ impl<'a> Bencode<'a> {
         }
     }
 
-    fn decode_string(&mut self) -> Value {
-        let string_length = self.decode_integer_number();
-        self.consume(':').expect("Expected ':' after string length");
+    fn decode_string(&mut self) -> Result<Value, BencodeError> {
+        let start = self.position;
+        let string_length = usize::try_from(self.decode_integer_number()?)
+            .map_err(|_| BencodeError::InvalidNumber(start))?;
+        self.consume(':')?;
 
-        let string_slice = &self.bytes[self.position..self.position + string_length as usize];
-        self.position += string_length as usize;
+        let string_slice = self
+            .position
+            .checked_add(string_length)
+            .and_then(|end| self.bytes.get(self.position..end))
+            .ok_or(BencodeError::UnexpectedEnd)?;
+        self.position += string_length;
 
         if let Ok(string) = std::str::from_utf8(string_slice) {
-            Value::String(string.to_string())
+            Ok(Value::String(string.to_string()))
         } else {
-            Value::Blob(string_slice.to_vec())
+            Ok(Value::Blob(string_slice.to_vec()))
         }
     }
 
-    fn decode_integer(&mut self) -> Value {
-        self.consume('i').expect("Expected 'i' at start of integer");
-        let integer = self.decode_integer_number();
-        self.consume('e').expect("Expected 'e' at end of integer");
+    fn decode_integer(&mut self) -> Result<Value, BencodeError> {
+        self.consume('i')?;
+        let integer = self.decode_integer_number()?;
+        self.consume('e')?;
 
-        Value::Number(integer)
+        Ok(Value::Number(integer))
     }
 
-    fn decode_list(&mut self) -> Value {
-        self.consume('l').expect("Expected 'l' at start of list");
+    fn decode_list(&mut self) -> Result<Value, BencodeError> {
+        self.enter()?;
+        self.consume('l')?;
 
         let mut values = Vec::new();
         while self.peek() != Some('e') {
-            values.push(self.decode());
+            values.push(self.decode()?);
         }
 
-        self.consume('e').expect("Expected 'e' at end of list");
+        self.consume('e')?;
+        self.depth -= 1;
 
-        Value::List(values)
+        Ok(Value::List(values))
     }
 
-    fn decode_dictionary(&mut self) -> Value {
-        self.consume('d')
-            .expect("Expected 'd' at start of dictionary");
+    fn decode_dictionary(&mut self) -> Result<Value, BencodeError> {
+        self.enter()?;
+        self.consume('d')?;
 
         let mut map = HashMap::new();
         while self.peek() != Some('e') {
-            let key = self
-                .decode_string()
-                .to_string()
-                .strip_prefix('\"')
-                .unwrap()
-                .strip_suffix('\"')
-                .unwrap()
-                .to_string();
-            let value = self.decode();
+            // Keys that are not UTF-8, like the pieces roots keying v2 piece layers, are kept
+            // lossily. We never look those up.
+            let key = match self.decode_string()? {
+                Value::String(key) => key,
+                Value::Blob(key) => String::from_utf8_lossy(&key).into_owned(),
+                _ => unreachable!("decode_string only returns strings and blobs"),
+            };
+            let value = self.decode()?;
             map.insert(key, value);
         }
 
-        self.consume('e')
-            .expect("Expected 'e' at end of dictionary");
-        Value::Dictionary(map)
+        self.consume('e')?;
+        self.depth -= 1;
+        Ok(Value::Dictionary(map))
+    }
+
+    /// Goes one list or dictionary deeper.
+    fn enter(&mut self) -> Result<(), BencodeError> {
+        if self.depth == MAX_DEPTH {
+            return Err(BencodeError::TooDeep(self.position));
+        }
+        self.depth += 1;
+        Ok(())
     }
 
-    fn decode_integer_number(&mut self) -> i64 {
+    fn decode_integer_number(&mut self) -> Result<i64, BencodeError> {
+        let start = self.position;
+        if self.peek().is_none() {
+            return Err(BencodeError::UnexpectedEnd);
+        }
         let mut number_string = String::new();
         loop {
             match self.peek() {
@@ -194,7 +247,9 @@ impl<'a> Bencode<'a> {
                 _ => break,
             }
         }
-        number_string.parse::<i64>().expect("Invalid number")
+        number_string
+            .parse::<i64>()
+            .map_err(|_| BencodeError::InvalidNumber(start))
     }
 
     fn next(&mut self) -> Option<char> {
@@ -207,30 +262,45 @@ impl<'a> Bencode<'a> {
         self.bytes.get(self.position).map(|b| *b as char)
     }
 
-    fn consume(&mut self, expected: char) -> Result<char, String> {
+    fn consume(&mut self, expected: char) -> Result<(), BencodeError> {
         match self.next() {
-            Some(c) if c == expected => Ok(c),
-            Some(c) => Err(format!("Was expecting {}, but got {}.", expected, c)),
-            None => Err(format!(
-                "Was expecting {}, but reached end of input",
-                expected
-            )),
+            Some(c) if c == expected => Ok(()),
+            Some(c) => Err(BencodeError::Unexpected(c, self.position - 1)),
+            None => Err(BencodeError::UnexpectedEnd),
         }
     }
 }
 
+/// Why bytes are not valid bencode, with the offset of the byte at fault.
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum BencodeError {
+    #[error("unexpected end of input")]
+    UnexpectedEnd,
+    #[error("unexpected {0:?} at byte {1}")]
+    Unexpected(char, usize),
+    #[error("invalid number at byte {0}")]
+    InvalidNumber(usize),
+    #[error("nested too deeply at byte {0}")]
+    TooDeep(usize),
+}
+
+#[cfg(test)]
 mod tests {
+    use std::collections::HashMap;
+
+    use crate::arbitrary::{self, Gen};
+
     #[test]
     fn hello_string() {
         let mut bencode = super::Bencode::new("5:hello".as_bytes());
-        let decoded_value = bencode.decode();
+        let decoded_value = bencode.decode().unwrap();
         assert_eq!(decoded_value, super::Value::String("hello".to_string()));
     }
 
     #[test]
     fn long_string() {
         let mut bencode = super::Bencode::new("11:hello world".as_bytes());
-        let decoded_value = bencode.decode();
+        let decoded_value = bencode.decode().unwrap();
         assert_eq!(
             decoded_value,
             super::Value::String("hello world".to_string())
@@ -240,21 +310,21 @@ mod tests {
     #[test]
     fn positive_integer() {
         let mut bencode = super::Bencode::new("i123e".as_bytes());
-        let decoded_value = bencode.decode();
+        let decoded_value = bencode.decode().unwrap();
         assert_eq!(decoded_value, super::Value::Number(123.into()));
     }
 
     #[test]
     fn negative_integer() {
         let mut bencode = super::Bencode::new("i-123e".as_bytes());
-        let decoded_value = bencode.decode();
+        let decoded_value = bencode.decode().unwrap();
         assert_eq!(decoded_value, super::Value::Number((-123).into()));
     }
 
     #[test]
     fn simple_list() {
         let mut bencode = super::Bencode::new("l4:spam4:eggse".as_bytes());
-        let decoded_value = bencode.decode();
+        let decoded_value = bencode.decode().unwrap();
         assert_eq!(
             decoded_value,
             super::Value::List(vec![
@@ -267,7 +337,7 @@ mod tests {
     #[test]
     fn multi_type_list() {
         let mut bencode = super::Bencode::new("li123e5:helloe".as_bytes());
-        let decoded_value = bencode.decode();
+        let decoded_value = bencode.decode().unwrap();
         assert_eq!(
             decoded_value,
             super::Value::List(vec![
@@ -280,7 +350,7 @@ mod tests {
     #[test]
     fn list_inside_a_list() {
         let mut bencode = super::Bencode::new("lli467e9:blueberryee".as_bytes());
-        let decoded_value = bencode.decode();
+        let decoded_value = bencode.decode().unwrap();
         assert_eq!(
             decoded_value,
             super::Value::List(vec![super::Value::List(vec![
@@ -293,20 +363,97 @@ mod tests {
     #[test]
     fn dictionary() {
         let mut bencode = super::Bencode::new("d3:foo3:bar5:helloi52ee".as_bytes());
-        let decoded_value = bencode.decode();
+        let decoded_value = bencode.decode().unwrap();
         assert_eq!(
             decoded_value,
             super::Value::Dictionary(
                 vec![
-                    (
-                        "\"foo\"".to_string(),
-                        super::Value::String("bar".to_string())
-                    ),
-                    ("\"hello\"".to_string(), super::Value::Number(52.into()))
+                    ("foo".to_string(), super::Value::String("bar".to_string())),
+                    ("hello".to_string(), super::Value::Number(52.into()))
                 ]
                 .into_iter()
                 .collect()
             )
         );
     }
+
+    #[test]
+    fn malformed_input_is_an_error() {
+        use super::BencodeError;
+
+        let decode = |bytes: &str| super::Bencode::new(bytes.as_bytes()).decode();
+        assert_eq!(decode("10:short"), Err(BencodeError::UnexpectedEnd));
+        assert_eq!(decode("li1e"), Err(BencodeError::UnexpectedEnd));
+        assert_eq!(decode("i12x"), Err(BencodeError::Unexpected('x', 3)));
+        assert_eq!(decode("x"), Err(BencodeError::Unexpected('x', 0)));
+        assert_eq!(decode("i-e"), Err(BencodeError::InvalidNumber(1)));
+        assert_eq!(decode("d-1:ae"), Err(BencodeError::InvalidNumber(1)));
+    }
+
+    #[test]
+    fn deep_nesting_is_an_error() {
+        use super::{BencodeError, MAX_DEPTH};
+
+        let nested = |depth: usize| "l".repeat(depth) + &"e".repeat(depth);
+        assert!(super::Bencode::new(nested(MAX_DEPTH).as_bytes())
+            .decode()
+            .is_ok());
+        assert_eq!(
+            super::Bencode::new(nested(MAX_DEPTH + 1).as_bytes()).decode(),
+            Err(BencodeError::TooDeep(MAX_DEPTH))
+        );
+    }
+
+    /// Any value, nested up to `depth` lists or dictionaries deep. Blobs are never valid UTF-8,
+    /// as those decode as strings.
+    fn value(gen: &mut Gen, depth: usize) -> super::Value {
+        match gen.below(if depth == 0 { 3 } else { 5 }) {
+            0 => super::Value::String(gen.string(20)),
+            1 => {
+                let mut blob = gen.bytes(20);
+                blob.insert(gen.below(blob.len() + 1), 0xff);
+                super::Value::Blob(blob)
+            }
+            2 => super::Value::Number(match gen.below(4) {
+                0 => i64::MIN,
+                1 => i64::MAX,
+                _ => gen.u64() as i64,
+            }),
+            3 => super::Value::List((0..gen.below(5)).map(|_| value(gen, depth - 1)).collect()),
+            _ => super::Value::Dictionary(
+                (0..gen.below(5))
+                    .map(|_| (gen.string(10), value(gen, depth - 1)))
+                    .collect::<HashMap<_, _>>(),
+            ),
+        }
+    }
+
+    #[test]
+    fn decoding_an_encoded_value_gives_it_back() {
+        arbitrary::check(|gen| {
+            let value = value(gen, 3);
+            let encoded = super::Bencode::encode(&value);
+            let mut bencode = super::Bencode::new(&encoded);
+            assert_eq!(bencode.decode().unwrap(), value);
+            assert_eq!(bencode.position(), encoded.len());
+        });
+    }
+
+    #[test]
+    fn encoding_is_canonical() {
+        arbitrary::check(|gen| {
+            let encoded = super::Bencode::encode(&value(gen, 3));
+            let decoded = super::Bencode::new(&encoded).decode().unwrap();
+            assert_eq!(super::Bencode::encode(&decoded), encoded);
+        });
+    }
+
+    #[test]
+    fn every_truncated_encoding_is_an_error() {
+        arbitrary::check(|gen| {
+            let encoded = super::Bencode::encode(&value(gen, 3));
+            let cut = gen.below(encoded.len());
+            assert!(super::Bencode::new(&encoded[..cut]).decode().is_err());
+        });
+    }
 }
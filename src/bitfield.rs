@@ -0,0 +1,103 @@
+/// A set of piece indices packed the way the peer wire protocol sends them: most significant
+/// bit of the first byte is piece 0.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bitfield {
+    bytes: Vec<u8>,
+    len: usize,
+}
+
+impl Bitfield {
+    pub fn new(len: usize) -> Self {
+        Self {
+            bytes: vec![0; len.div_ceil(8)],
+            len,
+        }
+    }
+
+    /// Builds a bitfield from a `Bitfield` message payload, ignoring any spare trailing bits.
+    pub fn from_bytes(bytes: &[u8], len: usize) -> Self {
+        let mut bitfield = Self::new(len);
+        for (target, source) in bitfield.bytes.iter_mut().zip(bytes) {
+            *target = *source;
+        }
+        bitfield.clear_spare_bits();
+        bitfield
+    }
+
+    /// The packed bytes, as sent in a `Bitfield` message.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// How many pieces are set.
+    pub fn count(&self) -> usize {
+        self.bytes
+            .iter()
+            .map(|byte| byte.count_ones() as usize)
+            .sum()
+    }
+
+    pub fn has(&self, index: usize) -> bool {
+        if index >= self.len {
+            return false;
+        }
+
+        self.bytes[index / 8] & (0x80 >> (index % 8)) != 0
+    }
+
+    pub fn set(&mut self, index: usize) {
+        if index < self.len {
+            self.bytes[index / 8] |= 0x80 >> (index % 8);
+        }
+    }
+
+    /// Whether this bitfield has any piece that `other` is missing.
+    pub fn has_any_missing_from(&self, other: &Bitfield) -> bool {
+        self.bytes
+            .iter()
+            .zip(other.bytes.iter())
+            .any(|(ours, theirs)| ours & !theirs != 0)
+    }
+
+    fn clear_spare_bits(&mut self) {
+        let spare = self.bytes.len() * 8 - self.len;
+        if let Some(last) = self.bytes.last_mut() {
+            *last &= 0xff << spare;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Bitfield;
+
+    #[test]
+    fn first_piece_is_most_significant_bit() {
+        let bitfield = Bitfield::from_bytes(&[0b1000_0001], 8);
+        assert!(bitfield.has(0));
+        assert!(!bitfield.has(1));
+        assert!(bitfield.has(7));
+    }
+
+    #[test]
+    fn spare_bits_are_ignored() {
+        let bitfield = Bitfield::from_bytes(&[0xff, 0xff], 10);
+        assert!(bitfield.has(9));
+        assert!(!bitfield.has(10));
+        assert_eq!(bitfield, Bitfield::from_bytes(&[0xff, 0xc0], 10));
+    }
+
+    #[test]
+    fn has_any_missing_from() {
+        let mut ours = Bitfield::new(3);
+        let theirs = Bitfield::from_bytes(&[0b0100_0000], 3);
+        assert!(theirs.has_any_missing_from(&ours));
+
+        ours.set(1);
+        assert!(!theirs.has_any_missing_from(&ours));
+    }
+}
@@ -0,0 +1,117 @@
+use crate::memory::{Charge, MemoryBudget, MemoryKind};
+
+/// How many pieces may be held in memory at once by default.
+pub const DEFAULT_PIECE_BUFFERS: usize = 32;
+
+/// Hands out piece-sized buffers to pieces being assembled and verified, up to a fixed number
+/// at once. When they are all in use no new piece is started until one comes back, so memory
+/// stays bounded however many sources we download from. Returned buffers are reused, unless
+/// the memory budget is exceeded.
+pub struct BufferPool {
+    capacity: usize,
+    in_use: usize,
+    free: Vec<Vec<u8>>,
+    // The bytes of the buffers in use and free.
+    charge: Charge,
+}
+
+impl BufferPool {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            in_use: 0,
+            free: Vec::new(),
+            charge: MemoryBudget::default().charge(MemoryKind::PieceBuffers, 0),
+        }
+    }
+
+    /// Charges the buffers to `memory` instead.
+    pub fn set_memory_budget(&mut self, memory: &MemoryBudget) {
+        self.charge = memory.charge(MemoryKind::PieceBuffers, self.charge.bytes());
+    }
+
+    pub fn has_free(&self) -> bool {
+        self.in_use < self.capacity
+    }
+
+    pub fn in_use(&self) -> usize {
+        self.in_use
+    }
+
+    /// A zeroed buffer of `length` bytes, or `None` if every buffer is in use.
+    pub fn take(&mut self, length: usize) -> Option<Vec<u8>> {
+        if !self.has_free() {
+            return None;
+        }
+        self.in_use += 1;
+
+        let mut buffer = self.free.pop().unwrap_or_default();
+        self.charge
+            .set(self.charge.bytes() - buffer.len() as u64 + length as u64);
+        buffer.clear();
+        buffer.resize(length, 0);
+        Some(buffer)
+    }
+
+    /// Returns a buffer handed out by [`BufferPool::take`] for reuse. It is freed instead if
+    /// the memory budget is exceeded.
+    pub fn give_back(&mut self, buffer: Vec<u8>) {
+        if self.charge.is_exceeded() {
+            self.forget(buffer.len());
+        } else {
+            self.in_use = self.in_use.saturating_sub(1);
+            self.free.push(buffer);
+        }
+    }
+
+    /// Frees the slot of a buffer of `length` bytes that has been handed on elsewhere and will
+    /// not come back.
+    pub fn forget(&mut self, length: usize) {
+        self.in_use = self.in_use.saturating_sub(1);
+        self.charge
+            .set(self.charge.bytes().saturating_sub(length as u64));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BufferPool;
+    use crate::memory::MemoryBudget;
+
+    #[test]
+    fn hands_out_a_bounded_number_of_buffers() {
+        let mut pool = BufferPool::new(2);
+        let mut first = pool.take(4).unwrap();
+        first.fill(7);
+        let second = pool.take(4).unwrap();
+        assert!(!pool.has_free());
+        assert_eq!(pool.take(4), None);
+
+        pool.give_back(first);
+        assert_eq!(pool.in_use(), 1);
+        // Reused buffers come back zeroed and resized.
+        assert_eq!(pool.take(6).unwrap(), [0; 6]);
+
+        drop(second);
+        pool.forget(4);
+        assert!(pool.has_free());
+    }
+
+    #[test]
+    fn charges_buffers_to_the_memory_budget() {
+        let memory = MemoryBudget::new(Some(10));
+        let mut pool = BufferPool::new(4);
+        pool.set_memory_budget(&memory);
+        let first = pool.take(6).unwrap();
+        let second = pool.take(6).unwrap();
+        assert_eq!(memory.usage().piece_buffers, 12);
+
+        // Over budget, so the buffer is freed rather than kept for reuse.
+        pool.give_back(first);
+        assert_eq!(memory.usage().piece_buffers, 6);
+        pool.give_back(second);
+        assert_eq!(memory.usage().piece_buffers, 6);
+        drop(pool);
+        assert_eq!(memory.usage().piece_buffers, 0);
+    }
+}
@@ -0,0 +1,208 @@
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+use serde::Serialize;
+use sha1::{Digest, Sha1};
+
+use crate::{
+    storage::{self, safe_relative_path},
+    torrent::{FileSpan, Info},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Problem {
+    /// Some of the piece's data is not on disk: a file is absent or too short.
+    Missing,
+    /// All of the piece is there but it does not match its hash.
+    Corrupt,
+}
+
+/// A piece of local data that does not match the torrent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BadPiece {
+    pub index: usize,
+    pub problem: Problem,
+    /// Where the piece lies in the torrent's content, as if its files were laid end to end.
+    pub range: Range<u64>,
+    /// The files the piece overlaps.
+    pub files: Vec<PathBuf>,
+}
+
+struct ContentFile {
+    path: PathBuf,
+    offset: u64,
+    length: u64,
+    file: Option<File>,
+    // How much of the file is on disk, which may be less than it should hold.
+    on_disk: u64,
+}
+
+impl ContentFile {
+    fn overlaps(&self, range: &Range<u64>) -> bool {
+        self.offset < range.end && range.start < self.offset + self.length
+    }
+}
+
+/// Hashes the torrent's data at `path`, its file or the directory holding a multi-file
+/// torrent's files, and returns every piece that is missing or corrupt.
+pub fn check(path: &Path, info: &Info) -> Vec<BadPiece> {
+    let mut files = content_files(path, info);
+    let mut bad = Vec::new();
+    for index in 0..info.pieces.len() {
+        let range = info.piece_range(index);
+        let overlapping = files
+            .iter_mut()
+            .filter(|file| file.overlaps(&range))
+            .collect::<Vec<_>>();
+
+        let problem = match read_range(overlapping, range.clone()) {
+            Some(piece) if Sha1::digest(&piece).as_slice() == info.pieces[index] => continue,
+            Some(_) => Problem::Corrupt,
+            None => Problem::Missing,
+        };
+        let files = files
+            .iter()
+            .filter(|file| file.overlaps(&range))
+            .map(|file| file.path.clone())
+            .collect();
+        bad.push(BadPiece {
+            index,
+            problem,
+            range,
+            files,
+        });
+    }
+    bad
+}
+
+/// How many bytes of `file` lie in pieces that are not among `bad`.
+pub fn completed_bytes(file: &FileSpan, bad: &[BadPiece]) -> u64 {
+    let end = file.offset + file.length;
+    let missing = bad
+        .iter()
+        .map(|piece| {
+            let overlap_end = piece.range.end.min(end);
+            overlap_end.saturating_sub(piece.range.start.max(file.offset))
+        })
+        .sum::<u64>();
+    file.length - missing
+}
+
+fn content_files(path: &Path, info: &Info) -> Vec<ContentFile> {
+    let lengths = if info.files.is_empty() {
+        vec![(path.to_path_buf(), info.length)]
+    } else {
+        info.files
+            .iter()
+            .map(|entry| (safe_relative_path(entry), entry.length))
+            .collect()
+    };
+
+    let mut offset = 0;
+    storage::content_paths(path, info)
+        .into_iter()
+        .zip(lengths)
+        .map(|(full_path, (path, length))| {
+            let file = File::open(full_path).ok();
+            let on_disk = file
+                .as_ref()
+                .and_then(|file| file.metadata().ok())
+                .map_or(0, |metadata| metadata.len());
+            let file = ContentFile {
+                path,
+                offset,
+                length: length as u64,
+                file,
+                on_disk,
+            };
+            offset += length as u64;
+            file
+        })
+        .collect()
+}
+
+/// Reads `range` of the content from the files it spans, or `None` if any of it is missing.
+fn read_range(files: Vec<&mut ContentFile>, range: Range<u64>) -> Option<Vec<u8>> {
+    let mut data = Vec::with_capacity((range.end - range.start) as usize);
+    for file in files {
+        let start = range.start.max(file.offset) - file.offset;
+        let end = range.end.min(file.offset + file.length) - file.offset;
+        if end > file.on_disk {
+            return None;
+        }
+
+        let handle = file.file.as_mut()?;
+        handle.seek(SeekFrom::Start(start)).ok()?;
+        let mut buffer = vec![0; (end - start) as usize];
+        handle.read_exact(&mut buffer).ok()?;
+        data.extend(buffer);
+    }
+    Some(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf};
+
+    use sha1::{Digest, Sha1};
+
+    use super::{check, completed_bytes, Problem};
+    use crate::torrent::{FileEntry, Info};
+
+    #[test]
+    fn finds_missing_and_corrupt_pieces_across_files() {
+        let data = (0..40).collect::<Vec<u8>>();
+        let info = Info {
+            length: data.len(),
+            name: "dir".to_string(),
+            piece_length: 16,
+            pieces: data
+                .chunks(16)
+                .map(|piece| Sha1::digest(piece).into())
+                .collect(),
+            files: vec![
+                FileEntry {
+                    length: 20,
+                    path: vec!["a".to_string()],
+                },
+                FileEntry {
+                    length: 20,
+                    path: vec!["b".to_string()],
+                },
+            ],
+            private: false,
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let mut a = data[..20].to_vec();
+        a[2] ^= 0xff;
+        fs::write(dir.path().join("a"), a).unwrap();
+        // The last four bytes of b never arrived.
+        fs::write(dir.path().join("b"), &data[20..36]).unwrap();
+
+        let bad = check(dir.path(), &info);
+        assert_eq!(bad.len(), 2);
+        assert_eq!(bad[0].index, 0);
+        assert_eq!(bad[0].problem, Problem::Corrupt);
+        assert_eq!(bad[0].files, vec![PathBuf::from("a")]);
+        assert_eq!(bad[1].index, 2);
+        assert_eq!(bad[1].problem, Problem::Missing);
+        assert_eq!(bad[1].range, 32..40);
+        assert_eq!(bad[1].files, vec![PathBuf::from("b")]);
+
+        let spans = info.file_spans();
+        assert_eq!(spans[0].pieces(info.piece_length), 0..2);
+        assert_eq!(spans[1].pieces(info.piece_length), 1..3);
+        // a loses its 16 bytes in piece 0; b loses the 8 in piece 2.
+        assert_eq!(completed_bytes(&spans[0], &bad), 4);
+        assert_eq!(completed_bytes(&spans[1], &bad), 12);
+
+        fs::write(dir.path().join("a"), &data[..20]).unwrap();
+        fs::write(dir.path().join("b"), &data[20..]).unwrap();
+        assert!(check(dir.path(), &info).is_empty());
+    }
+}
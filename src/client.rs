@@ -0,0 +1,242 @@
+//! Setting up the engine in one place. [`Client::builder`] gathers the settings that would
+//! otherwise be defaults spread across modules, such as the listening port, our peer id and the
+//! block size, and starts a [`Session`] with them.
+
+use std::{
+    io,
+    ops::{Deref, DerefMut},
+    path::PathBuf,
+    sync::Arc,
+};
+
+use crate::{
+    bandwidth::{BandwidthSchedule, Limit, RateLimiter},
+    executor::{self, Executor},
+    geoip::GeoIp,
+    http::{self, HttpFetch},
+    ip_filter::IpFilter,
+    peer_manager::{ConnectionLimits, PeerManager},
+    seeding::{SeedLimits, MAX_BLOCK_LENGTH},
+    session::{Session, SessionConfig},
+};
+
+/// A running engine, configured through [`ClientBuilder`]. It is a [`Session`], adding and
+/// running torrents, with the settings it was built with fixed for its lifetime.
+pub struct Client {
+    session: Session,
+}
+
+impl Client {
+    pub fn builder() -> ClientBuilder {
+        ClientBuilder::default()
+    }
+
+    pub fn into_session(self) -> Session {
+        self.session
+    }
+}
+
+impl Deref for Client {
+    type Target = Session;
+
+    fn deref(&self) -> &Session {
+        &self.session
+    }
+}
+
+impl DerefMut for Client {
+    fn deref_mut(&mut self) -> &mut Session {
+        &mut self.session
+    }
+}
+
+/// Settings for a [`Client`]. Anything left unset keeps the default the command line uses.
+#[derive(Default)]
+pub struct ClientBuilder {
+    config: SessionConfig,
+    limits: ConnectionLimits,
+    rate_limits: Option<BandwidthSchedule>,
+    ip_filter: Option<IpFilter>,
+    geoip: Option<GeoIp>,
+    http: Option<Arc<dyn HttpFetch>>,
+    executor: Option<Arc<dyn Executor>>,
+}
+
+impl ClientBuilder {
+    /// The port to accept peers on, 6881 by default. Zero picks a free one.
+    pub fn listen_port(mut self, port: u16) -> Self {
+        self.config.port = port;
+        self
+    }
+
+    /// Advertises a DHT node listening on `port` to peers that support DHT.
+    pub fn dht_port(mut self, port: u16) -> Self {
+        self.config.dht_port = Some(port);
+        self
+    }
+
+    /// Presents us as `peer_id`, instead of an id generated when the client starts.
+    pub fn peer_id(mut self, peer_id: [u8; 20]) -> Self {
+        self.config.peer_id = Some(peer_id);
+        self
+    }
+
+    /// Where downloads are saved, each under its torrent's name. The working directory by
+    /// default.
+    pub fn download_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.config.download_dir = dir.into();
+        self
+    }
+
+    /// The most peer connections open at once across every torrent.
+    pub fn max_peers(mut self, max: usize) -> Self {
+        self.limits.global = max;
+        self
+    }
+
+    /// The most peer connections open at once for a single torrent.
+    pub fn max_peers_per_torrent(mut self, max: usize) -> Self {
+        self.limits.per_torrent = max;
+        self
+    }
+
+    /// The most connections, in either direction, still handshaking at once.
+    pub fn max_half_open(mut self, max: usize) -> Self {
+        self.limits.half_open = max;
+        self
+    }
+
+    /// Throttles downloads across every torrent by `schedule`. Unlimited by default.
+    pub fn rate_limits(mut self, schedule: BandwidthSchedule) -> Self {
+        self.rate_limits = Some(schedule);
+        self
+    }
+
+    /// Keeps the pieces, cached pieces and metadata torrents hold in memory under `bytes`
+    /// between them, as far as it can. Unlimited by default.
+    pub fn memory_budget(mut self, bytes: u64) -> Self {
+        self.config.memory_budget = Some(bytes);
+        self
+    }
+
+    /// How many bytes of a piece to ask a peer for at a time, 16 KiB by default. Many peers
+    /// refuse anything larger.
+    pub fn block_size(mut self, size: usize) -> Self {
+        self.config.block_size = size;
+        self
+    }
+
+    /// When to stop seeding finished torrents. With no limits they seed until the client stops.
+    pub fn seed_limits(mut self, limits: SeedLimits) -> Self {
+        self.config.seed_limits = limits;
+        self
+    }
+
+    /// A shell command to run for each torrent that finishes downloading.
+    pub fn on_complete(mut self, command: impl Into<String>) -> Self {
+        self.config.on_complete = Some(command.into());
+        self
+    }
+
+    /// Records each torrent's daily transfers and completions in the history file at `path`.
+    pub fn history(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.history = Some(path.into());
+        self
+    }
+
+    /// Shows where peers are from the databases in `geoip`.
+    pub fn geoip(mut self, geoip: GeoIp) -> Self {
+        self.geoip = Some(geoip);
+        self
+    }
+
+    /// Refuses peers whose addresses `filter` blocks.
+    pub fn ip_filter(mut self, filter: IpFilter) -> Self {
+        self.ip_filter = Some(filter);
+        self
+    }
+
+    /// The HTTP stack to reach trackers and web seeds with, instead of reqwest. It is shared
+    /// by the whole process, see [`http::set_fetcher`].
+    pub fn http(mut self, fetcher: impl HttpFetch + 'static) -> Self {
+        self.http = Some(Arc::new(fetcher));
+        self
+    }
+
+    /// What runs each torrent, the listener and the other background work, instead of a
+    /// thread each. It is shared by the whole process, see [`executor::set_executor`].
+    pub fn executor(mut self, executor: impl Executor + 'static) -> Self {
+        self.executor = Some(Arc::new(executor));
+        self
+    }
+
+    /// Starts listening for peers with these settings.
+    pub fn build(self) -> Result<Client, ClientError> {
+        let block_size = self.config.block_size;
+        if block_size == 0 || block_size > MAX_BLOCK_LENGTH {
+            return Err(ClientError::BlockSize(block_size));
+        }
+
+        if let Some(fetcher) = self.http {
+            http::set_fetcher(fetcher);
+        }
+        if let Some(executor) = self.executor {
+            executor::set_executor(executor);
+        }
+        let mut peer_manager = PeerManager::new();
+        peer_manager.set_limits(self.limits);
+        if let Some(filter) = self.ip_filter {
+            peer_manager.set_ip_filter(filter);
+        }
+        let schedule = self
+            .rate_limits
+            .unwrap_or_else(|| BandwidthSchedule::new(Limit::Unlimited, vec![]));
+        let port = self.config.port;
+        let mut session = Session::start(self.config, peer_manager, RateLimiter::new(schedule))
+            .map_err(|error| ClientError::Listen(port, error))?;
+        if let Some(geoip) = self.geoip {
+            session.set_geoip(geoip);
+        }
+        Ok(Client { session })
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ClientError {
+    #[error("cannot listen for peers on port {0}: {1}")]
+    Listen(u16, io::Error),
+    #[error("block size {0} is not between 1 byte and 128 KiB")]
+    BlockSize(usize),
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use super::{Client, ClientError};
+
+    #[test]
+    fn builds_with_the_given_settings() {
+        let dir = tempfile::tempdir().unwrap();
+        let client = Client::builder()
+            .listen_port(0)
+            .peer_id(*b"-XX0001-abcdefghijkl")
+            .download_dir(dir.path())
+            .max_peers(10)
+            .build()
+            .unwrap();
+        assert_eq!(&client.peer_id(), b"-XX0001-abcdefghijkl");
+        assert_ne!(client.port(), 0);
+
+        assert!(matches!(
+            Client::builder().listen_port(0).block_size(0).build(),
+            Err(ClientError::BlockSize(0))
+        ));
+        let taken = TcpListener::bind("0.0.0.0:0").unwrap();
+        let port = taken.local_addr().unwrap().port();
+        assert!(matches!(
+            Client::builder().listen_port(port).build(),
+            Err(ClientError::Listen(..))
+        ));
+    }
+}
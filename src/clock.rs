@@ -0,0 +1,94 @@
+//! Where the engine reads the time from. Normally that is the system clock, but a simulated
+//! clock only moves when told to, so a simulated download takes no real time and times out,
+//! retries and re-requests at the same moments on every run.
+
+use std::{
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Clone)]
+pub struct Clock {
+    origin: Instant,
+    /// How far a simulated clock has moved on from `origin`. Clones share it.
+    simulated: Option<Arc<Mutex<Duration>>>,
+}
+
+impl Default for Clock {
+    fn default() -> Self {
+        Self::system()
+    }
+}
+
+impl Clock {
+    pub fn system() -> Self {
+        Self {
+            origin: Instant::now(),
+            simulated: None,
+        }
+    }
+
+    /// A clock standing still until it is advanced.
+    pub fn simulated() -> Self {
+        Self {
+            origin: Instant::now(),
+            simulated: Some(Arc::default()),
+        }
+    }
+
+    pub fn is_simulated(&self) -> bool {
+        self.simulated.is_some()
+    }
+
+    pub fn now(&self) -> Instant {
+        match &self.simulated {
+            Some(elapsed) => self.origin + *elapsed.lock().expect("Clock lock poisoned"),
+            None => Instant::now(),
+        }
+    }
+
+    /// How long the clock has been running.
+    pub fn elapsed(&self) -> Duration {
+        self.now().duration_since(self.origin)
+    }
+
+    /// Moves a simulated clock on to `at`, unless it is already past it. The system clock
+    /// cannot be moved.
+    pub fn advance_to(&self, at: Instant) {
+        if let Some(elapsed) = &self.simulated {
+            let mut elapsed = elapsed.lock().expect("Clock lock poisoned");
+            *elapsed = (*elapsed).max(at.saturating_duration_since(self.origin));
+        }
+    }
+
+    /// Waits for `duration`, which for a simulated clock means moving it on straight away.
+    pub fn sleep(&self, duration: Duration) {
+        if self.is_simulated() {
+            self.advance_to(self.now() + duration);
+        } else {
+            thread::sleep(duration);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::Clock;
+
+    #[test]
+    fn simulated_time_only_moves_when_told() {
+        let clock = Clock::simulated();
+        let shared = clock.clone();
+        let start = clock.now();
+        let real = Instant::now();
+
+        clock.sleep(Duration::from_secs(3600));
+        assert_eq!(shared.now() - start, Duration::from_secs(3600));
+        shared.advance_to(start + Duration::from_secs(10));
+        assert_eq!(clock.elapsed(), Duration::from_secs(3600));
+        assert!(real.elapsed() < Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,252 @@
+//! Settings the daemon can change while it runs: the rate limit and its schedule, the
+//! connection caps and the memory budget. They are read from a file of `key = value` lines,
+//! named like the daemon's options:
+//!
+//! ```text
+//! # Slow down during the day, and keep fewer peers.
+//! rate_limit = 1048576
+//! schedule = 22:00-06:00=unlimited
+//! max_connections = 100
+//! ```
+//!
+//! Settings the file leaves out keep the value given on the command line, and `schedule`
+//! lines, if any, replace the command line's windows. The daemon reads the file again when it
+//! is modified or, on Unix, when the process is sent SIGHUP. Connections already open are
+//! kept when the caps are lowered; new ones wait until there is room again.
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::SystemTime,
+};
+
+use crate::{
+    bandwidth::{BandwidthSchedule, Limit, ScheduleWindow},
+    peer_manager::ConnectionLimits,
+};
+
+/// The settings a config file can change.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Settings {
+    pub rate_limit: Limit,
+    pub schedule: Vec<ScheduleWindow>,
+    pub connection_limits: ConnectionLimits,
+    /// In bytes, or `None` for no budget.
+    pub memory_budget: Option<u64>,
+}
+
+impl Settings {
+    pub fn bandwidth_schedule(&self) -> BandwidthSchedule {
+        BandwidthSchedule::new(self.rate_limit, self.schedule.clone())
+    }
+
+    /// These settings with those `source` gives replacing them.
+    fn overridden_by(mut self, source: &str) -> Result<Self, ConfigError> {
+        let mut schedule = None::<Vec<ScheduleWindow>>;
+        for (index, line) in source.lines().enumerate() {
+            let number = index + 1;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let syntax = |error: String| ConfigError::Syntax(number, error);
+            let (key, value) = line
+                .split_once('=')
+                .map(|(key, value)| (key.trim(), value.trim()))
+                .ok_or_else(|| syntax("expected a setting, as `key = value`".to_string()))?;
+            let count = || {
+                value
+                    .parse::<usize>()
+                    .map_err(|_| syntax(format!("{} must be a number, got {}", key, value)))
+            };
+            match key {
+                "rate_limit" => self.rate_limit = value.parse().map_err(syntax)?,
+                // An empty value leaves no windows at all.
+                "schedule" if value.is_empty() => {
+                    schedule.get_or_insert_with(Vec::new);
+                }
+                "schedule" => schedule
+                    .get_or_insert_with(Vec::new)
+                    .push(value.parse().map_err(syntax)?),
+                "max_connections" => self.connection_limits.global = count()?,
+                "max_connections_per_torrent" => self.connection_limits.per_torrent = count()?,
+                "max_half_open" => self.connection_limits.half_open = count()?,
+                "memory_budget" if value == "unlimited" => self.memory_budget = None,
+                "memory_budget" => self.memory_budget = Some(count()? as u64),
+                _ => return Err(syntax(format!("unknown setting {}", key))),
+            }
+        }
+        if let Some(schedule) = schedule {
+            self.schedule = schedule;
+        }
+        Ok(self)
+    }
+}
+
+/// A config file and the settings it overrides.
+#[derive(Debug)]
+pub struct ConfigFile {
+    path: PathBuf,
+    // What the command line set, for settings the file leaves out.
+    defaults: Settings,
+    // When the file had last been modified as of the last read.
+    modified: Option<SystemTime>,
+    // Set on SIGHUP.
+    hangup: Arc<AtomicBool>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("cannot read {0}: {1}")]
+    Read(PathBuf, io::Error),
+    #[error("line {0}: {1}")]
+    Syntax(usize, String),
+}
+
+impl ConfigFile {
+    pub fn new(path: impl Into<PathBuf>, defaults: Settings) -> Self {
+        Self {
+            path: path.into(),
+            defaults,
+            modified: None,
+            hangup: Arc::default(),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Reads the settings from the file. A file that cannot be read is not read again until
+    /// it is modified.
+    pub fn load(&mut self) -> Result<Settings, ConfigError> {
+        self.modified = modified(&self.path);
+        let source = fs::read_to_string(&self.path)
+            .map_err(|error| ConfigError::Read(self.path.clone(), error))?;
+        self.defaults.clone().overridden_by(&source)
+    }
+
+    /// Whether the settings should be read again: the file has been modified since it was
+    /// last read, or SIGHUP has been received since this was last asked.
+    pub fn changed(&self) -> bool {
+        self.hangup.swap(false, Ordering::SeqCst) || modified(&self.path) != self.modified
+    }
+
+    /// Makes [`ConfigFile::changed`] true whenever the process is sent SIGHUP, instead of
+    /// SIGHUP ending the process.
+    #[cfg(unix)]
+    pub fn reload_on_hangup(&self) {
+        let hangup = self.hangup.clone();
+        std::thread::spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .expect("Failed to build signal runtime");
+
+            runtime.block_on(async {
+                let mut hangups =
+                    tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+                        .expect("Failed to listen for SIGHUP");
+                while hangups.recv().await.is_some() {
+                    hangup.store(true, Ordering::SeqCst);
+                }
+            });
+        });
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::{self, File},
+        time::{Duration, SystemTime},
+    };
+
+    use super::{ConfigError, ConfigFile, Settings};
+    use crate::{bandwidth::Limit, peer_manager::ConnectionLimits};
+
+    fn defaults() -> Settings {
+        Settings {
+            rate_limit: Limit::Unlimited,
+            schedule: vec!["12:00-13:00=paused".parse().unwrap()],
+            connection_limits: ConnectionLimits::default(),
+            memory_budget: None,
+        }
+    }
+
+    #[test]
+    fn overrides_what_the_file_sets() {
+        let settings = defaults()
+            .overridden_by(
+                "
+                # Comments and blank lines are skipped.
+
+                rate_limit = 1024
+                max_connections = 10
+                memory_budget = 1048576
+                schedule = 22:00-06:00=unlimited
+                schedule = 06:00-07:00=2048
+                ",
+            )
+            .unwrap();
+        assert_eq!(settings.rate_limit, Limit::BytesPerSecond(1024));
+        assert_eq!(settings.schedule.len(), 2);
+        assert_eq!(settings.memory_budget, Some(1048576));
+        assert_eq!(
+            settings.connection_limits,
+            ConnectionLimits {
+                global: 10,
+                ..ConnectionLimits::default()
+            }
+        );
+
+        assert_eq!(defaults().overridden_by("").unwrap(), defaults());
+        let cleared = defaults().overridden_by("schedule =").unwrap();
+        assert!(cleared.schedule.is_empty());
+
+        for (source, line) in [
+            ("rate_limit = fast", 1),
+            ("\nmax_half_open = -1", 2),
+            ("rate_limit 1024", 1),
+            ("upload_slots = 4", 1),
+        ] {
+            match defaults().overridden_by(source) {
+                Err(ConfigError::Syntax(number, _)) => assert_eq!(number, line, "{}", source),
+                other => panic!("{} parsed as {:?}", source, other),
+            }
+        }
+    }
+
+    #[test]
+    fn notices_when_the_file_is_modified() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("daemon.conf");
+        fs::write(&path, "rate_limit = paused\n").unwrap();
+        let mut config = ConfigFile::new(&path, defaults());
+
+        assert!(config.changed());
+        assert_eq!(config.load().unwrap().rate_limit, Limit::Paused);
+        assert!(!config.changed());
+
+        fs::write(&path, "rate_limit = 4096\n").unwrap();
+        File::options()
+            .write(true)
+            .open(&path)
+            .and_then(|file| file.set_modified(SystemTime::now() + Duration::from_secs(5)))
+            .unwrap();
+        assert!(config.changed());
+        assert_eq!(
+            config.load().unwrap().rate_limit,
+            Limit::BytesPerSecond(4096)
+        );
+        assert!(!config.changed());
+    }
+}
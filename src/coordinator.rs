@@ -0,0 +1,2400 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    path::Path,
+    str::FromStr,
+    sync::{mpsc::Receiver, Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    assembly::{BlockSource, PieceAssembly, BLOCK_SIZE},
+    bandwidth::RateLimiter,
+    bitfield::Bitfield,
+    buffer_pool::{BufferPool, DEFAULT_PIECE_BUFFERS},
+    clock::Clock,
+    error::{Error, Result},
+    events::{EventBus, TorrentEvent},
+    executor::Task,
+    extension,
+    hash_transfer::{HashRequest, Hashes},
+    holepunch::{HolepunchError, HolepunchKind, HolepunchMessage},
+    log,
+    memory::MemoryBudget,
+    metadata::MetadataMessage,
+    peer::{
+        resolve_addr, Connected, PeerConnection, PeerError, Received, generate_peer_id,
+        REQUEST_TIMEOUT,
+    },
+    peer_manager::{PeerManager, PeerSnapshot, PeerSource},
+    peer_worker::{PeerWorker, WorkerSetup, WorkerUpdate, DEFAULT_PEER_WORKERS},
+    phase::DownloadPhase,
+    picker::{PiecePicker, SequentialPicker},
+    piece_cache::{CacheStats, PieceCache, DEFAULT_CACHE_SIZE},
+    progress::Progress,
+    seeding::{SeedLimits, Seeder},
+    shutdown::Shutdown,
+    storage::{Storage, StorageError},
+    stream::{PieceStream, VerifiedPiece},
+    telemetry::Telemetry,
+    torrent::{Torrent, TrackerError},
+    verifier::{Verification, VerifyPool},
+    webseed::{RangeResult, WebSeed, WebSeedWorker},
+    wire::{BlockRequest, Handshake, Message, MessageId},
+};
+
+// Ban a peer, or drop a web seed, once it has sent this many pieces that fail verification.
+const MAX_HASH_FAILS: u32 = 3;
+// How many pieces a recheck reads ahead of the verification pool.
+const RECHECK_READ_AHEAD: usize = 16;
+// The most blocks we queue for a peer before ignoring further requests.
+const MAX_QUEUED_UPLOADS: usize = 256;
+// How often we check on web seeds and the verification pool when the peer has nothing to do.
+const BACKGROUND_POLL_INTERVAL: Duration = Duration::from_millis(10);
+// How often seeding picks up new inbound peers and checks its limits.
+const SEED_POLL_INTERVAL: Duration = Duration::from_millis(250);
+// How often waits on a quiet peer check whether a shutdown has been requested.
+const CANCEL_POLL_INTERVAL: Duration = Duration::from_millis(250);
+// How often the peer manager is told how the peer connection is doing.
+const PEER_SNAPSHOT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How much of a torrent to hold before a download stops, written as a number of bytes or as
+/// `<count>pieces`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopAfter {
+    Bytes(u64),
+    Pieces(usize),
+}
+
+impl FromStr for StopAfter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid quota, expected bytes or <count>pieces: {}", s);
+        match s.strip_suffix("pieces") {
+            Some(count) => count.trim().parse().map(StopAfter::Pieces),
+            None => s.parse().map(StopAfter::Bytes),
+        }
+        .map_err(|_| invalid())
+    }
+}
+
+/// Owns the torrent-wide side of a download: which pieces we have, how available each piece is
+/// across the swarm, and which piece to fetch next. Peer connections are driven by it.
+pub struct DownloadCoordinator {
+    torrent: Torrent,
+    peer_manager: Arc<Mutex<PeerManager>>,
+    completed: Bitfield,
+    // Downloaded pieces still waiting on the verification pool.
+    verifying: HashSet<usize>,
+    verifier: VerifyPool,
+    web_seeds: Vec<WebSeedWorker>,
+    // Peers downloading alongside the peer while we download from the swarm.
+    peer_workers: Vec<PeerWorker>,
+    max_peer_workers: usize,
+    // Set while downloading from the swarm, the only time other peers are brought in.
+    swarming: bool,
+    // Pieces being downloaded, whose blocks may be split between peers and web seeds.
+    assembling: HashMap<usize, PieceAssembly>,
+    // Buffers for the pieces being assembled and verified.
+    buffers: BufferPool,
+    // Who supplied each piece awaiting verification.
+    piece_sources: HashMap<usize, Vec<BlockSource>>,
+    // From a piece's first block until it is verified.
+    piece_spans: HashMap<usize, log::Span>,
+    web_seed_hash_fails: HashMap<String, u32>,
+    piece_stream: Option<PieceStream>,
+    // Blocks the peer asked us for, served from the piece cache between downloads.
+    upload_requests: Vec<BlockRequest>,
+    // Shared with the seeder, so pieces are cached across everyone we upload to.
+    piece_cache: Arc<Mutex<PieceCache>>,
+    memory: MemoryBudget,
+    telemetry: Telemetry,
+    events: EventBus,
+    availability: Vec<u32>,
+    picker: Box<dyn PiecePicker>,
+    shutdown: Shutdown,
+    clock: Clock,
+    peer_id: [u8; 20],
+    block_size: usize,
+    port: u16,
+    dht_port: Option<u16>,
+    rate_limiter: Option<Arc<Mutex<RateLimiter>>>,
+    last_peer_snapshot: Option<Instant>,
+    // Whether the tracker has heard from us, so we owe it a stopped announce when we leave.
+    announced: bool,
+    stop_after: Option<StopAfter>,
+    // Downloaded from peers we have since disconnected from.
+    downloaded: u64,
+}
+
+impl DownloadCoordinator {
+    pub fn new(torrent: Torrent, port: u16, peer_manager: Arc<Mutex<PeerManager>>) -> Self {
+        let piece_count = torrent.info.pieces.len();
+        // Web seeds serve each file of a multi-file torrent at its own URL, which we do not
+        // map ranges onto yet.
+        let url_list = if torrent.info.files.is_empty() {
+            torrent.url_list.as_slice()
+        } else {
+            &[]
+        };
+        let web_seeds = url_list
+            .iter()
+            .map(|url| WebSeed::new(url.clone(), &torrent.info.name).spawn())
+            .collect::<Vec<_>>();
+
+        Self {
+            completed: Bitfield::new(piece_count),
+            verifying: HashSet::new(),
+            verifier: VerifyPool::with_available_parallelism(),
+            // Web seeds have every piece.
+            availability: vec![web_seeds.len() as u32; piece_count],
+            web_seeds,
+            peer_workers: Vec::new(),
+            max_peer_workers: DEFAULT_PEER_WORKERS,
+            swarming: false,
+            assembling: HashMap::new(),
+            buffers: BufferPool::new(DEFAULT_PIECE_BUFFERS),
+            piece_sources: HashMap::new(),
+            piece_spans: HashMap::new(),
+            web_seed_hash_fails: HashMap::new(),
+            piece_stream: None,
+            upload_requests: Vec::new(),
+            piece_cache: Arc::new(Mutex::new(PieceCache::new(DEFAULT_CACHE_SIZE))),
+            memory: MemoryBudget::default(),
+            telemetry: Telemetry::new(),
+            events: EventBus::new(),
+            torrent,
+            peer_manager,
+            picker: Box::new(SequentialPicker),
+            shutdown: Shutdown::new(),
+            clock: Clock::system(),
+            peer_id: generate_peer_id(),
+            block_size: BLOCK_SIZE,
+            port,
+            dht_port: None,
+            rate_limiter: None,
+            last_peer_snapshot: None,
+            announced: false,
+            stop_after: None,
+            downloaded: 0,
+        }
+    }
+
+    /// Connects to the best-scoring of `addrs`, or of the peers the tracker hands out when there
+    /// are none. Peers that fail are retried with an increasing backoff until they have failed
+    /// too often.
+    pub fn connect(&mut self, addrs: &[String]) -> Result<PeerConnection<Connected>, ConnectError> {
+        self.add_candidates(addrs)?;
+        self.connect_candidate()
+    }
+
+    /// Hands `addrs`, or the peers the tracker hands out when there are none, to the peer
+    /// manager.
+    fn add_candidates(&mut self, addrs: &[String]) -> Result<(), ConnectError> {
+        // Announced before taking the lock, so a tracker that fails cannot poison it for the
+        // other torrents sharing the peer manager.
+        let (peers, source) = if addrs.is_empty() {
+            let peers = self.torrent.get_peers(&self.peer_id, self.port)?;
+            self.announced = true;
+            self.publish(TorrentEvent::TrackerAnnounced {
+                url: self.torrent.announce.clone(),
+                peers: Some(peers.len()),
+            });
+            (peers, PeerSource::Tracker)
+        } else {
+            let peers = addrs
+                .iter()
+                .map(|addr| {
+                    resolve_addr(addr).map_err(|error| ConnectError::Address(addr.clone(), error))
+                })
+                .collect::<Result<_, _>>()?;
+            (peers, PeerSource::Manual)
+        };
+        self.peer_manager
+            .lock()
+            .expect("Peer manager lock poisoned")
+            .add_candidates(peers, source);
+        Ok(())
+    }
+
+    /// Connects to the best-scoring candidate the peer manager has, waiting out backoffs.
+    fn connect_candidate(&mut self) -> Result<PeerConnection<Connected>, ConnectError> {
+        // Tries candidates in score order until one accepts the connection. Once we are at our
+        // connection limits the rest stay queued.
+        let info_hash = self.info_hash_bytes();
+        loop {
+            let mut peer_manager = self
+                .peer_manager
+                .lock()
+                .expect("Peer manager lock poisoned");
+            for addr in peer_manager.connectable_candidates(self.clock.now()) {
+                if !peer_manager.begin_connect(addr, info_hash) {
+                    log::info!(torrent = self.torrent.info.name; "connection limit reached, leaving remaining peers queued");
+                    break;
+                }
+
+                let result = PeerConnection::connect(addr, self.torrent.info.pieces.len());
+                peer_manager.finish_connect(addr, info_hash, result.is_ok());
+                match result {
+                    Ok(peer) => return Ok(peer),
+                    Err(error) => {
+                        log::warn!(torrent = self.torrent.info.name, peer = addr; "failed to connect: {}", error)
+                    }
+                }
+            }
+
+            let Some(retry_at) = peer_manager.next_retry() else {
+                // Released first so other torrents sharing the peer manager carry on.
+                drop(peer_manager);
+                return Err(ConnectError::NoPeers);
+            };
+            drop(peer_manager);
+
+            let wait = retry_at.saturating_duration_since(self.clock.now());
+            log::info!(torrent = self.torrent.info.name; "retrying peers in {:.1}s", wait.as_secs_f64());
+            if self.shutdown.sleep(wait) {
+                return Err(ConnectError::Interrupted);
+            }
+        }
+    }
+
+    fn info_hash_bytes(&self) -> [u8; 20] {
+        self.torrent.info_hash_bytes()
+    }
+
+    /// Exchanges handshakes with `peer` and waits for its bitfield, leaving it ready to download
+    /// from.
+    pub fn handshake(
+        &mut self,
+        mut peer: PeerConnection<Connected>,
+    ) -> Result<(PeerConnection, Handshake), PeerError> {
+        let _span = peer.span().enter();
+        peer.set_clock(self.clock.clone());
+        let (peer, handshake) =
+            peer.handshake(self.torrent.info_hash(), self.peer_id, self.dht_port)?;
+        self.publish(TorrentEvent::PeerConnected {
+            addr: peer.addr().to_string(),
+        });
+        let (mut peer, messages) = peer.receive_bitfield()?;
+        for message in messages {
+            self.handle_peer_message(&mut peer, &message)?;
+        }
+        Ok((peer, handshake))
+    }
+
+    /// Times requests and waits by `clock`. A simulated clock also has pieces verified on this
+    /// thread rather than a pool, so a download from the same peers takes the same steps every
+    /// time.
+    pub fn set_clock(&mut self, clock: Clock) {
+        if clock.is_simulated() {
+            self.verifier = VerifyPool::inline();
+        }
+        self.clock = clock;
+    }
+
+    /// Requests pieces from peers in blocks of `size` bytes rather than the usual 16 KiB.
+    pub fn set_block_size(&mut self, size: usize) {
+        self.block_size = size;
+    }
+
+    /// Presents us to peers and the tracker as `peer_id`.
+    pub fn set_peer_id(&mut self, peer_id: [u8; 20]) {
+        self.peer_id = peer_id;
+    }
+
+    /// Advertises our DHT node in the handshake and sends its port to peers that support DHT.
+    pub fn set_dht_port(&mut self, port: u16) {
+        self.dht_port = Some(port);
+    }
+
+    /// Chooses the strategy `download_all_pieces` uses to decide what to fetch next.
+    pub fn set_picker(&mut self, picker: Box<dyn PiecePicker>) {
+        self.picker = picker;
+    }
+
+    /// Downloads from up to `count` other peers alongside the one driving a swarm download, or
+    /// from that one alone with none.
+    pub fn set_peer_workers(&mut self, count: usize) {
+        self.max_peer_workers = count;
+    }
+
+    /// Throttles block requests through a limiter, which may be shared with other downloads.
+    pub fn set_rate_limiter(&mut self, rate_limiter: Arc<Mutex<RateLimiter>>) {
+        self.rate_limiter = Some(rate_limiter);
+    }
+
+    /// Holds at most `count` pieces in memory while they are downloaded and verified.
+    pub fn set_piece_buffers(&mut self, count: usize) {
+        self.buffers = BufferPool::new(count);
+        self.buffers.set_memory_budget(&self.memory);
+    }
+
+    /// Keeps up to `size` bytes of recently served pieces in memory.
+    pub fn set_piece_cache_size(&mut self, size: usize) {
+        let mut piece_cache = PieceCache::new(size);
+        piece_cache.set_memory_budget(&self.memory);
+        self.piece_cache = Arc::new(Mutex::new(piece_cache));
+    }
+
+    /// Charges piece buffers and cached pieces to `memory`, which may be shared with other
+    /// downloads. While it is exceeded no new pieces are started, beyond one at a time so the
+    /// download still moves, and the piece cache shrinks.
+    pub fn set_memory_budget(&mut self, memory: MemoryBudget) {
+        self.buffers.set_memory_budget(&memory);
+        self.piece_cache
+            .lock()
+            .expect("Piece cache lock poisoned")
+            .set_memory_budget(&memory);
+        self.memory = memory;
+    }
+
+    /// Streams verified pieces, in order, to the returned receiver as they download, alongside
+    /// writing them to storage. The stream ends when the coordinator is closed.
+    pub fn piece_stream(&mut self) -> Receiver<VerifiedPiece> {
+        let (stream, receiver) = PieceStream::new();
+        self.piece_stream = Some(stream);
+        receiver
+    }
+
+    /// How often requested blocks were served from the piece cache, while downloading and
+    /// seeding.
+    pub fn piece_cache_stats(&self) -> CacheStats {
+        self.piece_cache
+            .lock()
+            .expect("Piece cache lock poisoned")
+            .stats()
+    }
+
+    /// Piece timings, block sources and queue depths recorded so far.
+    pub fn telemetry(&self) -> &Telemetry {
+        &self.telemetry
+    }
+
+    /// Publishes the download's events on `events` instead of a bus of our own, so several
+    /// torrents can be followed together.
+    pub fn set_events(&mut self, events: EventBus) {
+        self.events = events;
+    }
+
+    /// The bus the download's events are published on.
+    pub fn events(&self) -> EventBus {
+        self.events.clone()
+    }
+
+    fn publish(&self, event: TorrentEvent) {
+        self.events.publish(&self.torrent.info_hash(), event);
+    }
+
+    /// Starts no new pieces once we hold, or are fetching, `quota` of the torrent. Pieces
+    /// already started are finished and verified, so a byte quota rounds up to whole pieces.
+    pub fn set_stop_after(&mut self, quota: StopAfter) {
+        self.stop_after = Some(quota);
+    }
+
+    /// Whether the download has stopped at the quota from `set_stop_after` rather than for
+    /// lack of pieces or peers.
+    pub fn reached_stop_after(&self) -> bool {
+        let Some(quota) = self.stop_after else {
+            return false;
+        };
+        let mut held = self.claimed();
+        for index in self.assembling.keys() {
+            held.set(*index);
+        }
+        let piece_count = self.torrent.info.pieces.len();
+        match quota {
+            StopAfter::Pieces(count) => held.count() >= count.min(piece_count),
+            StopAfter::Bytes(bytes) => {
+                let held_bytes = (0..piece_count)
+                    .filter(|index| held.has(*index))
+                    .map(|index| piece_size(&self.torrent, index) as u64)
+                    .sum::<u64>();
+                held_bytes >= bytes.min(self.torrent.info.length as u64)
+            }
+        }
+    }
+
+    /// Whether a piece nobody has started on may be started now.
+    fn may_start_piece(&self) -> bool {
+        self.buffers.has_free()
+            && !self.reached_stop_after()
+            && (self.buffers.in_use() == 0 || !self.memory.is_exceeded())
+    }
+
+    /// Stops on `shutdown` instead of a signal of our own, so several torrents can be stopped
+    /// together.
+    pub fn set_shutdown(&mut self, shutdown: Shutdown) {
+        self.shutdown = shutdown;
+    }
+
+    /// A handle that stops the download at the next block boundary when requested.
+    pub fn shutdown_signal(&self) -> Shutdown {
+        self.shutdown.clone()
+    }
+
+    /// Shows a progress line while downloading, counting from what we already have. It is
+    /// drawn from our events on its own thread, which ends once downloading stops.
+    pub fn show_progress(&self) -> Task<()> {
+        let progress = Progress::new(
+            self.torrent.info.pieces.len(),
+            self.torrent.info.length as u64,
+            self.completed.count(),
+            self.bytes_completed(),
+        );
+        progress.spawn(self.events.subscribe())
+    }
+
+    /// Bytes of the pieces we have downloaded and verified.
+    pub fn bytes_completed(&self) -> u64 {
+        (0..self.torrent.info.pieces.len())
+            .filter(|index| self.completed.has(*index))
+            .map(|index| piece_size(&self.torrent, index) as u64)
+            .sum()
+    }
+
+    /// Bytes peers sent us over connections that have been closed, so every connection once
+    /// the coordinator is closed.
+    pub fn bytes_downloaded(&self) -> u64 {
+        self.downloaded
+    }
+
+    /// The pieces we have downloaded and verified.
+    pub fn completed(&self) -> &Bitfield {
+        &self.completed
+    }
+
+    /// Marks pieces a previous session verified as complete without hashing them again. They
+    /// are not sent to the piece stream, as we never read them back.
+    pub fn restore(&mut self, pieces: &Bitfield) -> usize {
+        let mut restored = 0;
+        for piece_index in 0..self.torrent.info.pieces.len() {
+            if pieces.has(piece_index) {
+                self.completed.set(piece_index);
+                restored += 1;
+            }
+        }
+        self.publish_checked();
+        restored
+    }
+
+    fn publish_checked(&self) {
+        self.publish(TorrentEvent::Checked {
+            pieces: self.completed.count(),
+            piece_count: self.torrent.info.pieces.len(),
+            bytes: self.bytes_completed(),
+        });
+    }
+
+    pub fn is_complete(&self) -> bool {
+        (0..self.torrent.info.pieces.len()).all(|index| self.completed.has(index))
+    }
+
+    /// Downloads every piece we are missing from the peer and any web seeds. Pieces are
+    /// verified on the verification pool while the next one downloads. Stops early if the peer
+    /// is banned for sending corrupt data, and with an error if the peer fails us or the
+    /// download cannot be written.
+    pub fn download_all_pieces(
+        &mut self,
+        peer: &mut PeerConnection,
+        storage: &mut dyn Storage,
+    ) -> Result<()> {
+        self.publish_web_seeds();
+        let result = {
+            let _span = peer.span().enter();
+            self.download_missing(peer, storage)
+        };
+        self.publish(TorrentEvent::Stopped);
+        result
+    }
+
+    /// Downloads every piece we are missing from `addrs`, or from the peers the tracker hands
+    /// out when there are none. A peer that fails us or has nothing more for us is dropped and
+    /// the next best one takes over, with dropped peers retried after a backoff, so only
+    /// running out of peers or failing to write the download ends it with an error. Other
+    /// candidates download blocks alongside the peer, up to `set_peer_workers` of them, and
+    /// one of them takes over when the peer drops out. Returns the peer the download finished
+    /// with, or none if a shutdown interrupted connecting.
+    pub fn download_from_swarm(
+        &mut self,
+        addrs: &[String],
+        storage: &mut dyn Storage,
+    ) -> Result<Option<PeerConnection>> {
+        self.publish_web_seeds();
+        self.swarming = true;
+        let result = self.download_from_peers(addrs, storage);
+        self.swarming = false;
+        for worker in std::mem::take(&mut self.peer_workers) {
+            self.stop_peer_worker(worker, false);
+        }
+        self.publish(TorrentEvent::Stopped);
+        result
+    }
+
+    fn download_from_peers(
+        &mut self,
+        addrs: &[String],
+        storage: &mut dyn Storage,
+    ) -> Result<Option<PeerConnection>> {
+        self.add_candidates(addrs)?;
+        // Why the last peer was dropped, which says more than having no peers left.
+        let mut last_drop: Option<Error> = None;
+        loop {
+            let mut peer = match self.promote_peer_worker() {
+                Some(peer) => peer,
+                None => {
+                    let connected = match self.connect_candidate() {
+                        Ok(connected) => connected,
+                        Err(ConnectError::Interrupted) => return Ok(None),
+                        Err(error @ ConnectError::NoPeers) => {
+                            return Err(last_drop.unwrap_or(error.into()))
+                        }
+                        Err(error) => return Err(error.into()),
+                    };
+                    let addr = connected.addr();
+                    match self.handshake(connected) {
+                        Ok((peer, _)) => peer,
+                        Err(error) => {
+                            log::warn!(torrent = self.torrent.info.name, peer = addr; "dropping peer: {}", error);
+                            let mut peer_manager = self
+                                .peer_manager
+                                .lock()
+                                .expect("Peer manager lock poisoned");
+                            peer_manager.connection_closed(addr);
+                            peer_manager.record_drop(addr);
+                            last_drop = Some(error.into());
+                            continue;
+                        }
+                    }
+                }
+            };
+            let addr = peer.addr();
+
+            let result = {
+                let _span = peer.span().enter();
+                self.download_missing(&mut peer, storage)
+            };
+            match result {
+                Ok(())
+                    if self.is_complete()
+                        || self.reached_stop_after()
+                        || self.shutdown.is_requested() =>
+                {
+                    return Ok(Some(peer))
+                }
+                // Banned, or has no more of what we are missing.
+                Ok(()) => {
+                    log::info!(torrent = self.torrent.info.name, peer = addr; "dropping peer: nothing more to download from it");
+                }
+                Err(Error::Peer(error)) => {
+                    log::warn!(torrent = self.torrent.info.name, peer = addr; "dropping peer: {}", error);
+                    last_drop = Some(error.into());
+                }
+                Err(error) => {
+                    self.disconnect(&mut peer);
+                    return Err(error);
+                }
+            }
+            self.disconnect(&mut peer);
+            self.peer_manager
+                .lock()
+                .expect("Peer manager lock poisoned")
+                .record_drop(addr);
+        }
+    }
+
+    fn publish_web_seeds(&self) {
+        for seed in &self.web_seeds {
+            self.publish(TorrentEvent::PeerConnected {
+                addr: seed.url().to_string(),
+            });
+        }
+    }
+
+    fn download_missing(
+        &mut self,
+        peer: &mut PeerConnection,
+        storage: &mut dyn Storage,
+    ) -> Result<()> {
+        self.wait_until_unchoked(peer)?;
+
+        while !self.shutdown.is_requested() && !self.is_banned(peer) {
+            self.publish_peer(peer);
+            self.collect_background_work(peer, storage)?;
+            self.assign_web_seeds(peer);
+            self.add_peer_workers();
+            self.assign_peer_workers();
+
+            let Some(piece_index) = self.pick_for_peer(peer) else {
+                if !self.has_background_work() {
+                    break;
+                }
+                self.clock.sleep(BACKGROUND_POLL_INTERVAL);
+                continue;
+            };
+
+            match self.fetch_piece(peer, piece_index, storage) {
+                // The stalled block is missing again. Another peer takes it if there is one to
+                // move on to; otherwise web seeds may, or this peer is asked again.
+                Err(Error::Peer(PeerError::Stalled)) if !self.has_other_peers(peer) => {}
+                result => result?,
+            }
+        }
+
+        while !self.shutdown.is_requested()
+            && (self.web_seeds.iter().any(|seed| seed.is_busy())
+                || self.peer_workers.iter().any(|worker| worker.is_busy()))
+        {
+            self.collect_background_work(peer, storage)?;
+            self.clock.sleep(BACKGROUND_POLL_INTERVAL);
+        }
+        self.finish_verification(peer, storage)
+    }
+
+    /// Whether there is a peer besides `peer` we could connect to right away.
+    fn has_other_peers(&self, peer: &PeerConnection) -> bool {
+        self.peer_manager
+            .lock()
+            .expect("Peer manager lock poisoned")
+            .connectable_candidates(self.clock.now())
+            .into_iter()
+            .any(|addr| addr != peer.addr())
+    }
+
+    fn publish_peer(&mut self, peer: &PeerConnection) {
+        if self
+            .last_peer_snapshot
+            .is_some_and(|last| self.clock.now().duration_since(last) < PEER_SNAPSHOT_INTERVAL)
+        {
+            return;
+        }
+        self.last_peer_snapshot = Some(self.clock.now());
+
+        let mut snapshot = PeerSnapshot::new(
+            peer.addr(),
+            self.info_hash_bytes(),
+            peer.peer_id(),
+            peer.stats(),
+        );
+        snapshot.choked = peer.phase().is_choked();
+        snapshot.interested = peer.is_interested();
+        self.peer_manager
+            .lock()
+            .expect("Peer manager lock poisoned")
+            .update_peer(snapshot);
+    }
+
+    /// The next piece to fetch from the peer. Once every piece buffer is in use, or the quota
+    /// is reached, only pieces already being assembled are picked.
+    fn pick_for_peer(&mut self, peer: &PeerConnection) -> Option<usize> {
+        if self.may_start_piece() {
+            return self
+                .picker
+                .pick(&self.claimed(), peer.pieces(), &self.availability);
+        }
+
+        let mut started = Bitfield::new(self.torrent.info.pieces.len());
+        for piece_index in self.assembling.keys() {
+            if peer.pieces().has(*piece_index) {
+                started.set(*piece_index);
+            }
+        }
+        self.picker
+            .pick(&self.claimed(), &started, &self.availability)
+    }
+
+    /// Pieces we have, are verifying or have asked for every block of. Pieces being verified
+    /// are treated as done unless they turn out to be corrupt.
+    fn claimed(&self) -> Bitfield {
+        let mut claimed = self.completed.clone();
+        for index in &self.verifying {
+            claimed.set(*index);
+        }
+        for (index, assembly) in &self.assembling {
+            if assembly.number_missing() == 0 {
+                claimed.set(*index);
+            }
+        }
+        claimed
+    }
+
+    fn has_background_work(&self) -> bool {
+        self.verifier.pending() > 0
+            || self.web_seeds.iter().any(|seed| seed.is_busy())
+            || self.peer_workers.iter().any(|worker| worker.is_busy())
+    }
+
+    /// Gives each idle web seed work. Web seeds take whole pieces the peer does not have first,
+    /// then the back half of the blocks left in a piece being downloaded, then whole pieces
+    /// shared with the peer.
+    fn assign_web_seeds(&mut self, peer: &PeerConnection) {
+        let piece_count = self.torrent.info.pieces.len();
+        let mut peer_lacks = Bitfield::new(piece_count);
+        let mut everything = Bitfield::new(piece_count);
+        for index in 0..piece_count {
+            everything.set(index);
+            if !peer.pieces().has(index) {
+                peer_lacks.set(index);
+            }
+        }
+
+        for index in 0..self.web_seeds.len() {
+            if self.web_seeds[index].is_busy() {
+                continue;
+            }
+
+            // Whole pieces only come from pieces nobody has started on.
+            let mut started = self.claimed();
+            for piece_index in self.assembling.keys() {
+                started.set(*piece_index);
+            }
+            let partial = self
+                .assembling
+                .iter()
+                .filter(|(_, assembly)| assembly.number_missing() > 0)
+                .max_by_key(|(_, assembly)| assembly.number_missing())
+                .map(|(piece_index, _)| *piece_index);
+            // New pieces need a free buffer and room in the quota; partial ones already have
+            // their buffer.
+            let may_start = self.may_start_piece();
+            let (piece_index, blocks) = if let Some(piece_index) = may_start
+                .then(|| self.picker.pick(&started, &peer_lacks, &self.availability))
+                .flatten()
+            {
+                let url = self.web_seeds[index].url().to_string();
+                let assembly = self.start_piece(piece_index, url);
+                (piece_index, assembly.and_then(PieceAssembly::take_all))
+            } else if let Some(piece_index) = partial {
+                let assembly = self.assembling.get_mut(&piece_index).unwrap();
+                (piece_index, assembly.take_back_half())
+            } else if let Some(piece_index) = may_start
+                .then(|| self.picker.pick(&started, &everything, &self.availability))
+                .flatten()
+            {
+                let url = self.web_seeds[index].url().to_string();
+                let assembly = self.start_piece(piece_index, url);
+                (piece_index, assembly.and_then(PieceAssembly::take_all))
+            } else {
+                return;
+            };
+            let Some(blocks) = blocks else {
+                return;
+            };
+
+            let range = self.assembling[&piece_index].byte_range(blocks);
+            let offset = (piece_index * self.torrent.info.piece_length + range.start) as u64;
+            let seed = &mut self.web_seeds[index];
+            log::debug!(
+                torrent = self.torrent.info.name, piece = piece_index;
+                "web seed {} fetching bytes {}..{}",
+                seed.url(),
+                range.start,
+                range.end
+            );
+            seed.request(piece_index, offset, range.len() as u64);
+        }
+    }
+
+    /// The assembly for a piece, started by the first source to fetch from it.
+    fn start_piece(&mut self, piece_index: usize, source: String) -> Option<&mut PieceAssembly> {
+        if !self.assembling.contains_key(&piece_index) {
+            let buffer = self.buffers.take(piece_size(&self.torrent, piece_index))?;
+            self.assembling
+                .insert(piece_index, PieceAssembly::new(buffer, self.block_size));
+            let span = log::span!("piece", piece = piece_index, torrent = self.torrent.info.name);
+            {
+                let _span = span.enter();
+                log::debug!("piece started from {}", source);
+            }
+            self.piece_spans.insert(piece_index, span);
+            self.telemetry.piece_started(piece_index, source);
+        }
+        self.assembling.get_mut(&piece_index)
+    }
+
+    /// Brings in the best candidates to download alongside the peer, up to the worker limit,
+    /// while downloading from the swarm.
+    fn add_peer_workers(&mut self) {
+        if !self.swarming || self.peer_workers.len() >= self.max_peer_workers {
+            return;
+        }
+
+        let info_hash = self.info_hash_bytes();
+        let mut peer_manager = self
+            .peer_manager
+            .lock()
+            .expect("Peer manager lock poisoned");
+        for addr in peer_manager.connectable_candidates(self.clock.now()) {
+            if self.peer_workers.len() >= self.max_peer_workers
+                || !peer_manager.begin_connect(addr, info_hash)
+            {
+                break;
+            }
+
+            log::debug!(torrent = self.torrent.info.name, peer = addr; "downloading from peer alongside the others");
+            let setup = WorkerSetup {
+                info_hash: self.torrent.info_hash(),
+                piece_count: self.torrent.info.pieces.len(),
+                completed: self.completed.clone(),
+                peer_id: self.peer_id,
+                dht_port: self.dht_port,
+                clock: self.clock.clone(),
+            };
+            self.peer_workers
+                .push(PeerWorker::spawn(addr, setup, self.shutdown.child()));
+        }
+    }
+
+    /// Keeps each ready worker's queue topped up with blocks.
+    fn assign_peer_workers(&mut self) {
+        for index in 0..self.peer_workers.len() {
+            while self.peer_workers[index].wants_blocks() {
+                let worker = &self.peer_workers[index];
+                let (addr, pieces) = (worker.addr(), worker.pieces().cloned());
+                let Some(request) = pieces.and_then(|pieces| self.claim_block(addr, &pieces))
+                else {
+                    break;
+                };
+
+                if let Some(rate_limiter) = &self.rate_limiter {
+                    rate_limiter
+                        .lock()
+                        .expect("Rate limiter lock poisoned")
+                        .acquire(request.length as usize, &self.shutdown);
+                }
+                log::trace!(peer = addr, piece = request.index; "worker requesting block at {}", request.begin);
+                self.peer_workers[index].request(request);
+            }
+        }
+    }
+
+    /// Claims the next missing block for a peer with `pieces`, from a piece already being
+    /// downloaded if it has one, so a piece's blocks are split between peers. Otherwise the
+    /// peer starts a piece of its own.
+    fn claim_block(&mut self, addr: SocketAddr, pieces: &Bitfield) -> Option<BlockRequest> {
+        let partial = self
+            .assembling
+            .iter()
+            .filter(|(piece_index, assembly)| {
+                assembly.number_missing() > 0 && pieces.has(**piece_index)
+            })
+            .map(|(piece_index, _)| *piece_index)
+            .min();
+        let piece_index = match partial {
+            Some(piece_index) => piece_index,
+            None if self.may_start_piece() => {
+                let mut started = self.claimed();
+                for piece_index in self.assembling.keys() {
+                    started.set(*piece_index);
+                }
+                self.picker.pick(&started, pieces, &self.availability)?
+            }
+            None => return None,
+        };
+
+        let assembly = self.start_piece(piece_index, addr.to_string())?;
+        let block_index = assembly.next_missing()?;
+        let range = assembly.byte_range(block_index..block_index + 1);
+        Some(BlockRequest {
+            index: piece_index as u32,
+            begin: range.start as u32,
+            length: range.len() as u32,
+        })
+    }
+
+    /// Puts the blocks workers have fetched into their pieces and follows which pieces their
+    /// peers have. A worker whose peer fails or is banned is stopped.
+    fn collect_peer_workers(&mut self) {
+        for mut worker in std::mem::take(&mut self.peer_workers) {
+            let mut failed = None;
+            while let Some(update) = worker.update() {
+                match update {
+                    WorkerUpdate::Ready(pieces) => self.peer_worker_ready(worker.addr(), &pieces),
+                    WorkerUpdate::Have(indexes) => {
+                        for index in indexes {
+                            self.availability[index] += 1;
+                        }
+                    }
+                    WorkerUpdate::Block {
+                        request,
+                        data,
+                        latency,
+                    } => self.receive_worker_block(worker.addr(), request, &data, latency),
+                    WorkerUpdate::Failed(error) => failed = Some(error),
+                }
+            }
+
+            let banned = self
+                .peer_manager
+                .lock()
+                .expect("Peer manager lock poisoned")
+                .is_blocked(worker.addr());
+            if let Some(error) = failed {
+                log::warn!(torrent = self.torrent.info.name, peer = worker.addr(); "dropping peer: {}", error);
+                self.stop_peer_worker(worker, true);
+            } else if banned {
+                self.stop_peer_worker(worker, false);
+            } else {
+                self.peer_workers.push(worker);
+            }
+        }
+    }
+
+    fn peer_worker_ready(&mut self, addr: SocketAddr, pieces: &Bitfield) {
+        self.peer_manager
+            .lock()
+            .expect("Peer manager lock poisoned")
+            .finish_connect(addr, self.info_hash_bytes(), true);
+        for index in 0..pieces.len() {
+            if pieces.has(index) {
+                self.availability[index] += 1;
+            }
+        }
+        self.publish(TorrentEvent::PeerConnected {
+            addr: addr.to_string(),
+        });
+    }
+
+    /// Copies a block a worker fetched into its piece.
+    fn receive_worker_block(
+        &mut self,
+        addr: SocketAddr,
+        request: BlockRequest,
+        data: &[u8],
+        latency: Duration,
+    ) {
+        let piece_index = request.index as usize;
+        let begin = request.begin as usize;
+        let Some(assembly) = self.assembling.get_mut(&piece_index) else {
+            return;
+        };
+
+        let blocks = assembly.blocks_covering(begin..begin + data.len());
+        assembly.buffer_mut()[begin..begin + data.len()].copy_from_slice(data);
+        assembly.received(blocks, BlockSource::Peer(addr));
+        self.telemetry.block_received(
+            piece_index,
+            request.begin,
+            request.length,
+            addr.to_string(),
+            latency,
+        );
+
+        if assembly.is_complete() {
+            self.finish_piece(piece_index);
+        }
+    }
+
+    /// Hands a block that was asked for but never arrived back to its piece.
+    fn release_block(&mut self, request: &BlockRequest) {
+        if let Some(assembly) = self.assembling.get_mut(&(request.index as usize)) {
+            let begin = request.begin as usize;
+            assembly.release(assembly.blocks_covering(begin..begin + request.length as usize));
+        }
+    }
+
+    /// Stops a worker, handing back the blocks it had not sent, and closes its connection.
+    /// A `dropped` worker's peer is backed off before it is tried again.
+    fn stop_peer_worker(&mut self, mut worker: PeerWorker, dropped: bool) {
+        let addr = worker.addr();
+        let connection = worker.stop();
+        // Blocks sent while it stopped are handed back with the rest, as nobody is waiting on
+        // them now.
+        while let Some(update) = worker.update() {
+            match update {
+                WorkerUpdate::Ready(pieces) => self.peer_worker_ready(addr, &pieces),
+                WorkerUpdate::Have(indexes) => {
+                    for index in indexes {
+                        self.availability[index] += 1;
+                    }
+                }
+                WorkerUpdate::Block { request, .. } => self.release_block(&request),
+                WorkerUpdate::Failed(_) => {}
+            }
+        }
+        for request in worker.queued().to_vec() {
+            self.release_block(&request);
+        }
+
+        let Some(pieces) = worker.pieces() else {
+            // It never got as far as being ready.
+            if let Some(mut peer) = connection {
+                peer.close();
+            }
+            self.peer_manager
+                .lock()
+                .expect("Peer manager lock poisoned")
+                .finish_connect(addr, self.info_hash_bytes(), false);
+            return;
+        };
+        for index in 0..pieces.len() {
+            if pieces.has(index) {
+                self.availability[index] -= 1;
+            }
+        }
+        if let Some(mut peer) = connection {
+            self.disconnect(&mut peer);
+        }
+        if dropped {
+            self.peer_manager
+                .lock()
+                .expect("Peer manager lock poisoned")
+                .record_drop(addr);
+        }
+    }
+
+    /// Takes over a ready worker's connection as the peer to download from, so a peer that
+    /// drops out is replaced without connecting to another.
+    fn promote_peer_worker(&mut self) -> Option<PeerConnection> {
+        let index = self
+            .peer_workers
+            .iter()
+            .position(|worker| worker.pieces().is_some())?;
+        let mut worker = self.peer_workers.remove(index);
+        let connection = worker.stop();
+        while let Some(update) = worker.update() {
+            match update {
+                WorkerUpdate::Ready(_) | WorkerUpdate::Failed(_) => {}
+                WorkerUpdate::Have(indexes) => {
+                    for index in indexes {
+                        self.availability[index] += 1;
+                    }
+                }
+                WorkerUpdate::Block {
+                    request,
+                    data,
+                    latency,
+                } => self.receive_worker_block(worker.addr(), request, &data, latency),
+            }
+        }
+        for request in worker.queued().to_vec() {
+            self.release_block(&request);
+        }
+        log::info!(torrent = self.torrent.info.name, peer = worker.addr(); "taking over from the dropped peer");
+        connection
+    }
+
+    /// Applies finished verifications, and puts what web seeds and other peers have fetched
+    /// into their pieces, queueing finished ones for verification. A web seed or peer that
+    /// fails is dropped.
+    fn collect_background_work(
+        &mut self,
+        peer: &mut PeerConnection,
+        storage: &mut dyn Storage,
+    ) -> Result<()> {
+        for verification in self.verifier.ready() {
+            self.apply_verification(peer, verification, storage)?;
+        }
+
+        let torrent = &self.torrent.info.name;
+        let hash_fails = &self.web_seed_hash_fails;
+        let mut fetched = Vec::new();
+        let mut dropped = Vec::new();
+        self.web_seeds.retain_mut(|seed| {
+            let result = seed.ready();
+            let corrupt = hash_fails
+                .get(seed.url())
+                .is_some_and(|fails| *fails >= MAX_HASH_FAILS);
+            let keep = match &result {
+                // Let a corrupt seed finish its range first, so its blocks can be handed back.
+                _ if corrupt && !seed.is_busy() => {
+                    log::warn!(torrent = torrent; "dropping web seed {}: sent corrupt pieces", seed.url());
+                    false
+                }
+                Some(RangeResult {
+                    data: Err(error), ..
+                }) => {
+                    log::warn!(torrent = torrent; "dropping web seed {}: {}", seed.url(), error);
+                    false
+                }
+                _ => true,
+            };
+
+            if let Some(result) = result {
+                fetched.push((seed.url().to_string(), result));
+            }
+            if !keep {
+                dropped.push(seed.url().to_string());
+            }
+            keep
+        });
+        for availability in &mut self.availability {
+            *availability -= dropped.len() as u32;
+        }
+        for addr in dropped {
+            self.publish(TorrentEvent::PeerDisconnected { addr });
+        }
+
+        for (url, result) in fetched {
+            self.receive_web_seed_range(url, result);
+        }
+        self.collect_peer_workers();
+
+        self.serve_uploads(peer, storage)?;
+        Ok(())
+    }
+
+    /// Sends the peer the blocks it asked for from pieces we have.
+    fn serve_uploads(
+        &mut self,
+        peer: &mut PeerConnection,
+        storage: &mut dyn Storage,
+    ) -> Result<(), PeerError> {
+        for request in std::mem::take(&mut self.upload_requests) {
+            let piece_index = request.index as usize;
+            if piece_index >= self.torrent.info.pieces.len() || !self.completed.has(piece_index) {
+                continue;
+            }
+
+            let block = match self.piece_cache.lock().expect("Piece cache lock poisoned").read_block(
+                storage,
+                piece_index,
+                piece_size(&self.torrent, piece_index),
+                request.begin as usize,
+                request.length as usize,
+            ) {
+                Ok(block) => block,
+                Err(error) => {
+                    log::warn!(torrent = self.torrent.info.name, peer = peer.addr(); "not serving {:?}: {}", request, error);
+                    continue;
+                }
+            };
+
+            peer.send(&Message::piece(request.index, request.begin, &block))?;
+
+            peer.stats_mut().record_upload(block.len());
+            self.peer_manager
+                .lock()
+                .expect("Peer manager lock poisoned")
+                .record_upload(self.torrent.info_hash_bytes(), block.len());
+        }
+        Ok(())
+    }
+
+    /// Copies a range a web seed fetched into its piece, or hands the blocks back if the fetch
+    /// failed or the seed has been dropped for sending corrupt data.
+    fn receive_web_seed_range(&mut self, url: String, result: RangeResult) {
+        let piece_index = result.piece_index;
+        let begin = result.offset as usize - piece_index * self.torrent.info.piece_length;
+        let Some(assembly) = self.assembling.get_mut(&piece_index) else {
+            return;
+        };
+
+        let corrupt = self
+            .web_seed_hash_fails
+            .get(&url)
+            .is_some_and(|fails| *fails >= MAX_HASH_FAILS);
+        let data = match result.data {
+            Ok(data) if !corrupt => data,
+            _ => {
+                let end = begin + result.length as usize;
+                assembly.release(assembly.blocks_covering(begin..end));
+                return;
+            }
+        };
+
+        let blocks = assembly.blocks_covering(begin..begin + data.len());
+        assembly.buffer_mut()[begin..begin + data.len()].copy_from_slice(&data);
+        assembly.received(blocks, BlockSource::WebSeed(url.clone()));
+        self.telemetry.block_received(
+            piece_index,
+            begin as u32,
+            data.len() as u32,
+            url,
+            result.elapsed,
+        );
+
+        if assembly.is_complete() {
+            self.finish_piece(piece_index);
+        }
+    }
+
+    /// Hands a piece whose blocks have all arrived to the verification pool. It is written out
+    /// once it verifies.
+    fn finish_piece(&mut self, piece_index: usize) {
+        let assembly = self
+            .assembling
+            .remove(&piece_index)
+            .expect("Finished a piece that was not being assembled");
+        let (piece, sources) = assembly.finish();
+        self.piece_sources.insert(piece_index, sources);
+        if let Some(span) = self.piece_spans.get(&piece_index) {
+            let _span = span.enter();
+            log::debug!("piece downloaded, verifying");
+        }
+
+        self.telemetry.piece_downloaded(piece_index);
+
+        self.verifying.insert(piece_index);
+        self.verifier
+            .submit(piece_index, piece, self.torrent.info.pieces[piece_index]);
+    }
+
+    /// Downloads a single piece into `storage`, returning whether it passed verification.
+    pub fn download_piece(
+        &mut self,
+        peer: &mut PeerConnection,
+        piece_index: usize,
+        storage: &mut dyn Storage,
+    ) -> Result<bool> {
+        let _span = peer.span().enter();
+        // With only the one peer, a stalled block is asked for again until it answers or is
+        // given up on as unresponsive.
+        loop {
+            match self.fetch_piece(peer, piece_index, storage) {
+                Err(Error::Peer(PeerError::Stalled)) => continue,
+                result => break result?,
+            }
+        }
+        while !self.shutdown.is_requested() && self.assembling.contains_key(&piece_index) {
+            self.collect_background_work(peer, storage)?;
+            thread::sleep(BACKGROUND_POLL_INTERVAL);
+        }
+        self.finish_verification(peer, storage)?;
+        Ok(self.completed.has(piece_index))
+    }
+
+    /// Downloads a piece into memory and hands it to the verification pool. Stops early if a
+    /// shutdown is requested.
+    fn fetch_piece(
+        &mut self,
+        peer: &mut PeerConnection,
+        piece_index: usize,
+        storage: &mut dyn Storage,
+    ) -> Result<()> {
+        self.wait_until_unchoked(peer)?;
+        if self.shutdown.is_requested() {
+            return Ok(());
+        }
+
+        if !peer.pieces().has(piece_index) {
+            return Err(PeerError::MissingPiece(piece_index).into());
+        }
+
+        // Web seeds may have taken the last buffer; the piece is picked again later.
+        if self
+            .start_piece(piece_index, peer.addr().to_string())
+            .is_none()
+        {
+            return Ok(());
+        }
+        self.sample_queues(peer);
+
+        loop {
+            if self.shutdown.is_requested() || self.is_banned(peer) {
+                return Ok(());
+            }
+
+            // Web seeds and other peers may finish the rest of the piece, or join in on it,
+            // while the peer is sending blocks.
+            self.collect_background_work(peer, storage)?;
+            self.assign_web_seeds(peer);
+            self.assign_peer_workers();
+            let _span = self.piece_spans.get(&piece_index).map(log::Span::enter);
+            let Some(assembly) = self.assembling.get_mut(&piece_index) else {
+                break;
+            };
+            let Some(block_index) = assembly.next_missing() else {
+                break;
+            };
+            let range = assembly.byte_range(block_index..block_index + 1);
+
+            log::trace!(peer = peer.addr(), piece = piece_index; "requesting block {}", block_index);
+            let request = BlockRequest {
+                index: piece_index as u32,
+                begin: range.start as u32,
+                length: range.len() as u32,
+            };
+
+            if let Some(rate_limiter) = &self.rate_limiter {
+                rate_limiter
+                    .lock()
+                    .expect("Rate limiter lock poisoned")
+                    .acquire(request.length as usize, &self.shutdown);
+                if self.shutdown.is_requested() {
+                    return Ok(());
+                }
+            }
+
+            let requested_at = peer.request_block(request)?;
+            // Taken out of the map while reading, as other peer messages are
+            // handled on the way.
+            let mut assembly = self.assembling.remove(&piece_index).unwrap();
+            let read = self.read_requested_block(peer, assembly.buffer_mut());
+            let length = match read {
+                Ok(Some(length)) => length,
+                // The block goes back to missing, for whoever fetches the piece next.
+                Ok(None) => {
+                    assembly.release(block_index..block_index + 1);
+                    self.assembling.insert(piece_index, assembly);
+                    return Ok(());
+                }
+                Err(error) => {
+                    assembly.release(block_index..block_index + 1);
+                    self.assembling.insert(piece_index, assembly);
+                    return Err(error.into());
+                }
+            };
+            assembly.received(block_index..block_index + 1, BlockSource::Peer(peer.addr()));
+            self.assembling.insert(piece_index, assembly);
+
+            let latency = self.clock.now().duration_since(requested_at);
+            peer.stats_mut().record_latency(latency);
+            peer.stats_mut().record_download(length);
+            self.telemetry.block_received(
+                piece_index,
+                request.begin,
+                request.length,
+                peer.addr().to_string(),
+                latency,
+            );
+        }
+
+        // Otherwise a web seed is still fetching the rest.
+        if self
+            .assembling
+            .get(&piece_index)
+            .is_some_and(|assembly| assembly.is_complete())
+        {
+            self.finish_piece(piece_index);
+        }
+        Ok(())
+    }
+
+    fn sample_queues(&mut self, peer: &PeerConnection) {
+        let busy_web_seeds = self.web_seeds.iter().filter(|seed| seed.is_busy()).count();
+        self.telemetry.sample_queues(
+            peer.outstanding_requests(),
+            self.verifying.len(),
+            busy_web_seeds,
+            self.buffers.in_use(),
+        );
+    }
+
+    /// Waits for every piece still on the verification pool.
+    fn finish_verification(
+        &mut self,
+        peer: &mut PeerConnection,
+        storage: &mut dyn Storage,
+    ) -> Result<()> {
+        while let Some(verification) = self.verifier.next() {
+            self.apply_verification(peer, verification, storage)?;
+        }
+        Ok(())
+    }
+
+    /// Marks a verified piece complete and tells the swarm, or counts a corrupt one against
+    /// everyone who supplied part of it so it can be downloaded again.
+    fn apply_verification(
+        &mut self,
+        peer: &mut PeerConnection,
+        verification: Verification,
+        storage: &mut dyn Storage,
+    ) -> Result<()> {
+        let piece_index = verification.piece_index;
+        // Closed once the verification has been applied.
+        let span = self.piece_spans.remove(&piece_index);
+        let _span = span.as_ref().map(log::Span::enter);
+        self.verifying.remove(&piece_index);
+        let sources = self.piece_sources.remove(&piece_index).unwrap_or_default();
+        self.telemetry
+            .piece_verified(piece_index, verification.valid);
+        self.sample_queues(peer);
+
+        if verification.valid {
+            let length = verification.data.len();
+            // Only verified pieces reach the disk, each in a single write.
+            storage
+                .write_block(piece_index, 0, &verification.data)
+                .and_then(|()| storage.piece_written(piece_index))
+                .map_err(|error| StorageError::Write(piece_index, error))?;
+            match self.mark_complete(piece_index, verification.data) {
+                Some(buffer) => self.buffers.give_back(buffer),
+                None => self.buffers.forget(length),
+            }
+            self.publish(TorrentEvent::PieceVerified {
+                piece: piece_index,
+                pieces: self.completed.count(),
+                bytes: self.bytes_completed(),
+            });
+            log::debug!("piece verified and written");
+            if self.is_complete() {
+                self.publish(TorrentEvent::Completed);
+            }
+            self.broadcast_have(peer, piece_index as u32)?;
+            peer.update_interest(&self.completed)?;
+            return Ok(());
+        }
+
+        log::warn!(torrent = self.torrent.info.name, piece = piece_index; "piece failed hash verification");
+        self.publish(TorrentEvent::PieceFailed { piece: piece_index });
+        self.buffers.give_back(verification.data);
+        for source in sources {
+            match source {
+                // Peers we have since disconnected from are counted too, so one cannot send
+                // corrupt data and leave before it is found out.
+                BlockSource::Peer(addr) => {
+                    if addr == peer.addr() {
+                        peer.stats_mut().record_hash_fail();
+                    }
+                    let mut peer_manager = self
+                        .peer_manager
+                        .lock()
+                        .expect("Peer manager lock poisoned");
+                    let banned = peer_manager.record_hash_fail(addr) >= MAX_HASH_FAILS
+                        && !peer_manager.is_blocked(addr);
+                    if banned {
+                        peer_manager.ban(addr);
+                        drop(peer_manager);
+                        self.publish(TorrentEvent::PeerBanned {
+                            addr: addr.to_string(),
+                        });
+                    }
+                }
+                BlockSource::WebSeed(url) => {
+                    *self.web_seed_hash_fails.entry(url).or_default() += 1;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Marks a piece as ours, handing its data to the piece stream if there is one. Otherwise
+    /// the data is given back.
+    fn mark_complete(&mut self, piece_index: usize, data: Vec<u8>) -> Option<Vec<u8>> {
+        self.completed.set(piece_index);
+        let Some(stream) = &mut self.piece_stream else {
+            return Some(data);
+        };
+
+        let piece = VerifiedPiece {
+            index: piece_index,
+            offset: (piece_index * self.torrent.info.piece_length) as u64,
+            data,
+        };
+        if !stream.push(piece) {
+            self.piece_stream = None;
+        }
+        None
+    }
+
+    /// Hashes whatever `storage` already holds and marks the pieces that verify as complete, so
+    /// a restarted download only fetches the rest. Returns how many pieces were found.
+    pub fn recheck(&mut self, storage: &mut dyn Storage) -> usize {
+        let mut found = 0;
+        let mut apply = |coordinator: &mut Self, verification: Verification| {
+            if verification.valid {
+                coordinator.mark_complete(verification.piece_index, verification.data);
+                found += 1;
+            }
+        };
+
+        for piece_index in 0..self.torrent.info.pieces.len() {
+            while self.verifier.pending() >= RECHECK_READ_AHEAD {
+                let verification = self.verifier.next().expect("Verification pool stopped");
+                apply(self, verification);
+            }
+
+            // Reading past the end of a short or missing file means the piece is not there.
+            let length = piece_size(&self.torrent, piece_index);
+            if let Ok(piece) = storage.read_block(piece_index, 0, length) {
+                let hash = self.torrent.info.pieces[piece_index];
+                self.verifier.submit(piece_index, piece, hash);
+            }
+        }
+        while let Some(verification) = self.verifier.next() {
+            apply(self, verification);
+        }
+
+        self.publish_checked();
+        found
+    }
+
+    /// Whether we have stopped downloading from the peer, because it sent too much corrupt data.
+    fn is_banned(&self, peer: &PeerConnection) -> bool {
+        self.peer_manager
+            .lock()
+            .expect("Peer manager lock poisoned")
+            .is_blocked(peer.addr())
+    }
+
+    /// Tells the tracker we are a seed, then serves inbound peers from the data at `path` until
+    /// one of the seeding limits is hit or a shutdown is requested, when they are disconnected.
+    pub fn seed(&mut self, limits: &SeedLimits, path: &Path) {
+        // Pieces downloaded this session mean we just finished; otherwise we started complete.
+        let announced = if self.telemetry.slowest_pieces().is_empty() {
+            self.torrent.announce_seeding(&self.peer_id, self.port)
+        } else {
+            self.torrent.announce_completed(&self.peer_id, self.port)
+        };
+        match announced {
+            Ok(()) => {
+                self.announced = true;
+                self.publish(TorrentEvent::TrackerAnnounced {
+                    url: self.torrent.announce.clone(),
+                    peers: None,
+                });
+            }
+            Err(error) => log::warn!(torrent = self.torrent.info.name; "{}", error),
+        }
+
+        let seeder = Seeder {
+            info: self.torrent.info.clone(),
+            path: path.to_path_buf(),
+            info_hash: self.info_hash_bytes(),
+            completed: self.completed.clone(),
+            peer_manager: self.peer_manager.clone(),
+            piece_cache: self.piece_cache.clone(),
+        };
+        let info_hash = self.info_hash_bytes();
+        let mut serving = HashSet::new();
+        let started = Instant::now();
+        while !self.shutdown.is_requested() {
+            let peer_manager = self
+                .peer_manager
+                .lock()
+                .expect("Peer manager lock poisoned");
+            // Inbound peers stay with the peer manager, which sends them our haves.
+            let ours = peer_manager
+                .inbound()
+                .iter()
+                .filter(|peer| peer.info_hash == info_hash);
+            for peer in ours {
+                if serving.insert(peer.addr) {
+                    if let Ok(socket) = peer.socket.try_clone() {
+                        seeder.serve(peer.addr, socket);
+                    }
+                }
+            }
+            serving.retain(|addr| peer_manager.inbound().iter().any(|peer| peer.addr == *addr));
+            let ratio = peer_manager.uploaded(info_hash) as f64 / self.torrent.info.length as f64;
+            drop(peer_manager);
+
+            if let Some(stop) = limits.reached(ratio, started.elapsed()) {
+                log::info!(torrent = self.torrent.info.name; "stopping seeding: {}", stop);
+                break;
+            }
+            thread::sleep(SEED_POLL_INTERVAL);
+        }
+
+        // Hanging up on the peers we served stops their threads, so nothing more is uploaded.
+        let peer_manager = self
+            .peer_manager
+            .lock()
+            .expect("Peer manager lock poisoned");
+        for peer in peer_manager.inbound() {
+            if serving.contains(&peer.addr) {
+                let _ = peer.socket.shutdown(std::net::Shutdown::Both);
+            }
+        }
+    }
+
+    /// Leaves the swarm cleanly: closes the peer connection, flushes what we have written, ends
+    /// the piece stream and lets the tracker know we stopped, if we told it we started. Fails
+    /// if what we wrote could not be flushed, after doing the rest.
+    pub fn close(
+        &mut self,
+        peer: Option<&mut PeerConnection>,
+        storage: &mut dyn Storage,
+    ) -> Result<(), StorageError> {
+        if let Some(peer) = peer {
+            self.disconnect(peer);
+        }
+
+        let synced = storage.sync().map_err(StorageError::Sync);
+        self.piece_stream = None;
+
+        if self.announced {
+            let left = self.torrent.info.length - self.bytes_completed() as usize;
+            if let Err(error) = self
+                .torrent
+                .announce_stopped(&self.peer_id, self.port, left)
+            {
+                log::warn!(torrent = self.torrent.info.name; "{}", error);
+            }
+        }
+        synced
+    }
+
+    /// Closes the connection to `peer` and folds how it went into the peer's history.
+    fn disconnect(&mut self, peer: &mut PeerConnection) {
+        peer.close();
+        self.downloaded += peer.stats().bytes_downloaded;
+
+        let mut peer_manager = self
+            .peer_manager
+            .lock()
+            .expect("Peer manager lock poisoned");
+        peer_manager.record_session(peer.addr(), peer.stats());
+        peer_manager.connection_closed(peer.addr());
+        drop(peer_manager);
+        self.publish(TorrentEvent::PeerDisconnected {
+            addr: peer.addr().to_string(),
+        });
+    }
+
+    /// Registers our interest, if we have not yet, and waits to be unchoked or for a shutdown.
+    fn wait_until_unchoked(&mut self, peer: &mut PeerConnection) -> Result<(), PeerError> {
+        if let DownloadPhase::Idle { .. } = peer.phase() {
+            peer.update_interest(&self.completed)?;
+            if !peer.is_interested() {
+                return Err(PeerError::NothingWanted);
+            }
+        }
+
+        while peer.phase() == DownloadPhase::WaitingForUnchoke && !self.shutdown.is_requested() {
+            if peer.wait_for_data(self.clock.now() + CANCEL_POLL_INTERVAL)? {
+                let message = peer.read_message()?;
+                self.handle_peer_message(peer, &message)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Keeps track of which pieces the peer has from its `Bitfield` and `Have` messages, of its
+    /// DHT node from `Port`, of the blocks it asks us for, and of the extensions it speaks.
+    /// Merkle hashes sent for v2 torrents are checked against their proof.
+    fn handle_peer_message(
+        &mut self,
+        peer: &mut PeerConnection,
+        message: &Message,
+    ) -> Result<(), PeerError> {
+        match message.id {
+            MessageId::Port if message.payload.len() == 2 && peer.supports_dht() => {
+                let port = u16::from_be_bytes([message.payload[0], message.payload[1]]);
+                let node = SocketAddr::new(peer.addr().ip(), port);
+                log::debug!(peer = peer.addr(); "peer runs a DHT node on {}", node);
+                self.peer_manager
+                    .lock()
+                    .expect("Peer manager lock poisoned")
+                    .add_dht_node(node);
+            }
+            MessageId::Bitfield | MessageId::Have => {
+                for index in peer.record_availability(message) {
+                    self.availability[index] += 1;
+                }
+                if message.id == MessageId::Have {
+                    peer.update_interest(&self.completed)?;
+                }
+            }
+            MessageId::Request => match BlockRequest::decode(&message.payload) {
+                Some(request) if self.upload_requests.len() < MAX_QUEUED_UPLOADS => {
+                    self.upload_requests.push(request);
+                }
+                Some(_) => log::debug!(peer = peer.addr(); "peer has too many requests queued"),
+                None => log::warn!(peer = peer.addr(); "ignoring malformed request"),
+            },
+            MessageId::Cancel => {
+                if let Some(request) = BlockRequest::decode(&message.payload) {
+                    self.upload_requests.retain(|queued| *queued != request);
+                }
+            }
+            MessageId::HashRequest => {
+                // We have no v2 hash trees to serve from yet.
+                if let Some(request) = HashRequest::from_bytes(&message.payload) {
+                    peer.send(&request.reject())?;
+                }
+            }
+            MessageId::Hashes => match Hashes::from_bytes(&message.payload) {
+                Some(hashes) if hashes.verified().is_some() => log::debug!(
+                    peer = peer.addr();
+                    "received {} verified hashes from layer {}",
+                    hashes.request.length,
+                    hashes.request.base_layer
+                ),
+                _ => {
+                    peer.stats_mut().record_hash_fail();
+                    log::warn!(peer = peer.addr(); "discarding hashes that do not match their proof");
+                }
+            },
+            MessageId::HashReject => {
+                log::debug!(peer = peer.addr(); "peer rejected our hash request")
+            }
+            MessageId::Extended if !message.payload.is_empty() => {
+                let (id, payload) = message.payload.split_first().unwrap();
+                match *id {
+                    extension::HANDSHAKE_ID => {
+                        peer.record_extension_handshake(payload);
+                        if peer.supports_holepunch() {
+                            self.request_holepunches(peer)?;
+                        }
+                    }
+                    // We have a torrent file rather than the raw info dictionary, so we
+                    // cannot pass on an exact copy of it.
+                    extension::UT_METADATA_ID => {
+                        if let Some(MetadataMessage::Request { piece }) =
+                            MetadataMessage::from_bytes(payload)
+                        {
+                            let reject = MetadataMessage::Reject { piece };
+                            peer.send_extended("ut_metadata", &reject.as_bytes())?;
+                        }
+                    }
+                    extension::UT_HOLEPUNCH_ID => match HolepunchMessage::from_bytes(payload) {
+                        Some(holepunch) => self.handle_holepunch(peer, holepunch)?,
+                        None => {
+                            log::warn!(peer = peer.addr(); "ignoring malformed holepunch message")
+                        }
+                    },
+                    id => {
+                        log::debug!(peer = peer.addr(); "skipping extended message with unknown id {}", id)
+                    }
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Asks a peer that can relay for us to introduce us to the peers we could not reach.
+    fn request_holepunches(&mut self, peer: &mut PeerConnection) -> Result<(), PeerError> {
+        let unreachable = self
+            .peer_manager
+            .lock()
+            .expect("Peer manager lock poisoned")
+            .unreachable_candidates();
+
+        let relay = peer.addr();
+        for addr in unreachable.into_iter().filter(|addr| *addr != relay) {
+            log::info!(peer = relay; "asking peer to holepunch to {}", addr);
+            peer.send_holepunch(&HolepunchMessage::rendezvous(addr))?;
+        }
+        Ok(())
+    }
+
+    fn handle_holepunch(
+        &mut self,
+        peer: &mut PeerConnection,
+        message: HolepunchMessage,
+    ) -> Result<(), PeerError> {
+        match message.kind {
+            HolepunchKind::Rendezvous => {
+                // We only hold one outgoing connection, so we can never be connected to both
+                // sides and relay for them.
+                let error = if message.addr == peer.addr() {
+                    HolepunchError::NoSelf
+                } else {
+                    HolepunchError::NotConnected
+                };
+                peer.send_holepunch(&HolepunchMessage::error(message.addr, error))?;
+            }
+            HolepunchKind::Connect => {
+                log::info!(peer = message.addr; "peer is holepunching to us via {}", peer.addr());
+                self.peer_manager
+                    .lock()
+                    .expect("Peer manager lock poisoned")
+                    .record_holepunch(message.addr);
+            }
+            HolepunchKind::Error(error) => {
+                log::warn!(peer = message.addr; "holepunch failed: {}", error);
+            }
+        }
+        Ok(())
+    }
+
+    /// Lets our own peer and everyone connected to us know we can now serve this piece.
+    fn broadcast_have(
+        &mut self,
+        peer: &mut PeerConnection,
+        piece_index: u32,
+    ) -> Result<(), PeerError> {
+        let message = Message::have(piece_index);
+        peer.send(&message)?;
+
+        self.peer_manager
+            .lock()
+            .expect("Peer manager lock poisoned")
+            .broadcast(&message);
+        Ok(())
+    }
+
+    /// Reads messages until a `Piece` arrives that answers one of our outstanding requests and
+    /// appends it to `piece`, handling anything else the peer sends along the way. Returns the
+    /// length of the block, or `None` if a shutdown is requested first. A request left
+    /// unanswered past its deadline is cancelled and fails with `PeerError::Stalled`.
+    fn read_requested_block(
+        &mut self,
+        peer: &mut PeerConnection,
+        piece: &mut [u8],
+    ) -> Result<Option<usize>, PeerError> {
+        loop {
+            if self.shutdown.is_requested() {
+                return Ok(None);
+            }
+            if let Some(sent_at) = peer.oldest_request() {
+                let deadline = sent_at + REQUEST_TIMEOUT;
+                let now = self.clock.now();
+                if !peer.wait_for_data(deadline.min(now + CANCEL_POLL_INTERVAL))? {
+                    if self.clock.now() >= deadline && !peer.cancel_stalled_blocks()?.is_empty() {
+                        return Err(PeerError::Stalled);
+                    }
+                    continue;
+                }
+            }
+
+            match peer.receive_into(piece)? {
+                Received::Block(length) => return Ok(Some(length)),
+                Received::Message(message) => self.handle_peer_message(peer, &message)?,
+                Received::Nothing => {}
+            }
+        }
+    }
+}
+
+/// The length of a piece, which is shorter than the rest for the last one.
+fn piece_size(torrent: &Torrent, piece_index: usize) -> usize {
+    usize::min(
+        torrent.info.length - (piece_index * torrent.info.piece_length),
+        torrent.info.piece_length,
+    )
+}
+
+/// Why we ended up with no peer to download from.
+#[derive(Debug, thiserror::Error)]
+pub enum ConnectError {
+    #[error("invalid peer address {0}: {1}")]
+    Address(String, std::io::Error),
+    #[error(transparent)]
+    Tracker(#[from] TrackerError),
+    #[error("could not connect to any peer")]
+    NoPeers,
+    #[error("interrupted while connecting to peers")]
+    Interrupted,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashSet,
+        io::{BufRead, BufReader, Write},
+        net::TcpListener,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc, Mutex,
+        },
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use super::{DownloadCoordinator, StopAfter};
+    use crate::{
+        assembly::BlockSource,
+        clock::Clock,
+        error::Error,
+        events::TorrentEvent,
+        memory::MemoryBudget,
+        mock::{self, MockPeer},
+        peer::{PeerConnection, PeerError, REQUEST_TIMEOUT},
+        peer_manager::{PeerManager, PeerSource},
+        picker::RarestFirstPicker,
+        storage::{FileStorage, MemoryStorage, SinglePieceStorage, Storage},
+        torrent::Torrent,
+    };
+
+    const PIECE_LENGTH: usize = 32 * 1024;
+
+    fn storage() -> MemoryStorage {
+        MemoryStorage::new(PIECE_LENGTH, payload().len())
+    }
+
+    fn payload() -> Vec<u8> {
+        (0..PIECE_LENGTH * 2 + 1000)
+            .map(|i| (i % 251) as u8)
+            .collect()
+    }
+
+    fn torrent(payload: &[u8]) -> Torrent {
+        mock::torrent(payload, PIECE_LENGTH, "http://127.0.0.1:1/announce")
+    }
+
+    #[test]
+    fn downloads_every_piece_from_a_peer() {
+        let payload = payload();
+        let torrent = torrent(&payload);
+        let piece_count = torrent.info.pieces.len();
+        let seeder = MockPeer::new(&torrent, payload.clone()).spawn();
+
+        let peer_manager = Arc::new(Mutex::new(PeerManager::new()));
+        let mut coordinator = DownloadCoordinator::new(torrent, 0, peer_manager);
+        let events = coordinator.events().subscribe();
+        let peer = PeerConnection::connect(seeder, piece_count).unwrap();
+        let (mut peer, handshake) = coordinator.handshake(peer).unwrap();
+        assert_eq!(handshake.peer_id, [7; 20]);
+
+        let mut storage = storage();
+        coordinator
+            .download_all_pieces(&mut peer, &mut storage)
+            .unwrap();
+        assert!(coordinator.is_complete());
+
+        assert_eq!(storage.contents(), payload);
+        assert_eq!(peer.stats().bytes_downloaded, payload.len() as u64);
+        let events = events
+            .try_iter()
+            .map(|event| event.event)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            events[0],
+            TorrentEvent::PeerConnected {
+                addr: peer.addr().to_string()
+            }
+        );
+        // Pieces are verified in parallel, so may finish in any order.
+        let held = |event: &TorrentEvent| match event {
+            TorrentEvent::PieceVerified { pieces, bytes, .. } => Some((*pieces, *bytes)),
+            _ => None,
+        };
+        assert_eq!(held(&events[3]), Some((3, payload.len() as u64)));
+        assert_eq!(
+            events[4..],
+            [TorrentEvent::Completed, TorrentEvent::Stopped]
+        );
+    }
+
+    #[test]
+    fn fails_with_a_peer_error_when_the_peer_hangs_up() {
+        let payload = payload();
+        let torrent = torrent(&payload);
+        let piece_count = torrent.info.pieces.len();
+        let seeder = MockPeer::new(&torrent, payload).hang_up_after(0).spawn();
+
+        let peer_manager = Arc::new(Mutex::new(PeerManager::new()));
+        let mut coordinator = DownloadCoordinator::new(torrent, 0, peer_manager);
+        let peer = PeerConnection::connect(seeder, piece_count).unwrap();
+        let (mut peer, _) = coordinator.handshake(peer).unwrap();
+        let result = coordinator.download_all_pieces(&mut peer, &mut storage());
+
+        assert!(matches!(result, Err(Error::Peer(_))), "{:?}", result);
+        assert!(!coordinator.is_complete());
+    }
+
+    #[test]
+    fn moves_on_to_another_peer_when_one_hangs_up() {
+        let payload = payload();
+        let torrent = torrent(&payload);
+        // Three blocks in, part way through the second piece.
+        let flaky = MockPeer::new(&torrent, payload.clone())
+            .hang_up_after(3)
+            .spawn();
+        let reliable = MockPeer::new(&torrent, payload.clone()).spawn();
+
+        // Tracker peers rank below the one we name, so the flaky peer goes first.
+        let mut peer_manager = PeerManager::new();
+        peer_manager.add_candidates([reliable], PeerSource::Tracker);
+        let peer_manager = Arc::new(Mutex::new(peer_manager));
+        let mut coordinator = DownloadCoordinator::new(torrent, 0, peer_manager.clone());
+        // Only the one peer at a time, so the flaky peer gets far enough to hang up.
+        coordinator.set_peer_workers(0);
+        let mut storage = storage();
+        let mut peer = coordinator
+            .download_from_swarm(&[flaky.to_string()], &mut storage)
+            .unwrap()
+            .unwrap();
+        assert_eq!(peer.addr(), reliable);
+        assert!(coordinator.is_complete());
+        assert_eq!(storage.contents(), payload);
+
+        coordinator.close(Some(&mut peer), &mut storage).unwrap();
+        assert_eq!(coordinator.bytes_downloaded(), payload.len() as u64);
+        // The flaky peer is backing off before it is tried again.
+        let peer_manager = peer_manager.lock().unwrap();
+        assert_eq!(
+            peer_manager.connectable_candidates(Instant::now()),
+            vec![reliable]
+        );
+    }
+
+    #[test]
+    fn splits_a_piece_between_peers() {
+        let payload = payload();
+        let torrent = torrent(&payload);
+        // The choke holds the first peer up after one block, while the other joins in.
+        let first = MockPeer::new(&torrent, payload.clone())
+            .choke_after(1)
+            .spawn();
+        let second = MockPeer::new(&torrent, payload.clone()).spawn();
+
+        let mut peer_manager = PeerManager::new();
+        peer_manager.add_candidates([second], PeerSource::Tracker);
+        let peer_manager = Arc::new(Mutex::new(peer_manager));
+        let mut coordinator = DownloadCoordinator::new(torrent, 0, peer_manager.clone());
+        coordinator.set_block_size(1024);
+        let mut storage = storage();
+        let mut peer = coordinator
+            .download_from_swarm(&[first.to_string()], &mut storage)
+            .unwrap()
+            .unwrap();
+        assert_eq!(peer.addr(), first);
+        assert!(coordinator.is_complete());
+        assert_eq!(storage.contents(), payload);
+        assert!(peer.stats().bytes_downloaded < payload.len() as u64);
+
+        let telemetry: serde_json::Value =
+            serde_json::from_str(&coordinator.telemetry().to_json()).unwrap();
+        let sources = telemetry["pieces"]["0"]["blocks"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|block| block["source"].as_str().unwrap().to_string())
+            .collect::<HashSet<_>>();
+        assert_eq!(sources, HashSet::from([first.to_string(), second.to_string()]));
+
+        // The worker's connection was closed and counted when the download finished.
+        coordinator.close(Some(&mut peer), &mut storage).unwrap();
+        assert_eq!(coordinator.bytes_downloaded(), payload.len() as u64);
+        assert!(peer_manager
+            .lock()
+            .unwrap()
+            .connectable_candidates(Instant::now())
+            .contains(&second));
+    }
+
+    #[test]
+    fn hands_a_stalled_block_to_another_peer() {
+        let payload = payload();
+        let torrent = torrent(&payload);
+        let piece_count = torrent.info.pieces.len();
+        let stalled = MockPeer::new(&torrent, payload.clone())
+            .stall_after(3)
+            .spawn();
+        let reliable = MockPeer::new(&torrent, payload.clone()).spawn();
+
+        let mut peer_manager = PeerManager::new();
+        peer_manager.add_candidates([reliable], PeerSource::Tracker);
+        let peer_manager = Arc::new(Mutex::new(peer_manager));
+        let mut coordinator = DownloadCoordinator::new(torrent, 0, peer_manager);
+        // Simulated time runs far faster than real time, so the request times out quickly.
+        let clock = Clock::simulated();
+        coordinator.set_clock(clock.clone());
+        let done = Arc::new(AtomicBool::new(false));
+        let ticker = {
+            let (clock, done) = (clock.clone(), done.clone());
+            thread::spawn(move || {
+                while !done.load(Ordering::Relaxed) {
+                    thread::sleep(Duration::from_millis(10));
+                    clock.advance_to(clock.now() + Duration::from_secs(1));
+                }
+            })
+        };
+
+        let mut storage = storage();
+        let peer = PeerConnection::connect(stalled, piece_count).unwrap();
+        let (mut peer, _) = coordinator.handshake(peer).unwrap();
+        let result = coordinator.download_all_pieces(&mut peer, &mut storage);
+        done.store(true, Ordering::Relaxed);
+        ticker.join().unwrap();
+        assert!(
+            matches!(result, Err(Error::Peer(PeerError::Stalled))),
+            "{:?}",
+            result
+        );
+        // Given up on after the first timeout, rather than asked again.
+        assert!(clock.elapsed() < REQUEST_TIMEOUT * 3, "{:?}", clock.elapsed());
+        assert_eq!(peer.stats().timeouts, 1);
+        coordinator.close(Some(&mut peer), &mut storage).unwrap();
+
+        let peer = PeerConnection::connect(reliable, piece_count).unwrap();
+        let (mut peer, _) = coordinator.handshake(peer).unwrap();
+        coordinator
+            .download_all_pieces(&mut peer, &mut storage)
+            .unwrap();
+        assert!(coordinator.is_complete());
+        assert_eq!(storage.contents(), payload);
+    }
+
+    #[test]
+    fn downloads_a_single_piece_to_its_own_file() {
+        let payload = payload();
+        let torrent = torrent(&payload);
+        let piece_count = torrent.info.pieces.len();
+        let seeder = MockPeer::new(&torrent, payload.clone()).spawn();
+
+        let peer_manager = Arc::new(Mutex::new(PeerManager::new()));
+        let mut coordinator = DownloadCoordinator::new(torrent, 0, peer_manager);
+        let peer = PeerConnection::connect(seeder, piece_count).unwrap();
+        let (mut peer, _) = coordinator.handshake(peer).unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("piece-1");
+        let file = FileStorage::create(&path, PIECE_LENGTH).unwrap();
+        let mut storage = SinglePieceStorage::new(file, 1);
+        assert!(coordinator
+            .download_piece(&mut peer, 1, &mut storage)
+            .unwrap());
+        coordinator.close(Some(&mut peer), &mut storage).unwrap();
+        assert_eq!(
+            std::fs::read(&path).unwrap(),
+            payload[PIECE_LENGTH..PIECE_LENGTH * 2]
+        );
+    }
+
+    #[test]
+    fn stops_at_the_quota_with_what_it_fetched_verified() {
+        let payload = payload();
+        let torrent = torrent(&payload);
+        let piece_count = torrent.info.pieces.len();
+        let seeder = MockPeer::new(&torrent, payload.clone()).spawn();
+
+        let peer_manager = Arc::new(Mutex::new(PeerManager::new()));
+        let mut coordinator = DownloadCoordinator::new(torrent, 0, peer_manager);
+        // A byte past the first piece rounds up to two.
+        coordinator.set_stop_after("32769".parse().unwrap());
+        let peer = PeerConnection::connect(seeder, piece_count).unwrap();
+        let (mut peer, _) = coordinator.handshake(peer).unwrap();
+        let mut storage = storage();
+        coordinator
+            .download_all_pieces(&mut peer, &mut storage)
+            .unwrap();
+
+        assert!(!coordinator.is_complete());
+        assert!(coordinator.reached_stop_after());
+        assert_eq!(coordinator.completed().count(), 2);
+        assert_eq!(
+            &storage.contents()[..PIECE_LENGTH * 2],
+            &payload[..PIECE_LENGTH * 2]
+        );
+
+        assert_eq!("3pieces".parse(), Ok(StopAfter::Pieces(3)));
+        assert!("3MiB".parse::<StopAfter>().is_err());
+    }
+
+    #[test]
+    fn recheck_skips_pieces_already_on_disk() {
+        let payload = payload();
+        let torrent = torrent(&payload);
+        let piece_count = torrent.info.pieces.len();
+        let seeder = MockPeer::new(&torrent, payload.clone()).spawn();
+
+        let mut storage = storage();
+        storage.write_block(0, 0, &payload[..PIECE_LENGTH]).unwrap();
+        storage
+            .write_block(1, 0, &payload[PIECE_LENGTH..PIECE_LENGTH * 2])
+            .unwrap();
+
+        let peer_manager = Arc::new(Mutex::new(PeerManager::new()));
+        let mut coordinator = DownloadCoordinator::new(torrent, 0, peer_manager);
+        assert_eq!(coordinator.recheck(&mut storage), 2);
+
+        let peer = PeerConnection::connect(seeder, piece_count).unwrap();
+        let (mut peer, _) = coordinator.handshake(peer).unwrap();
+        coordinator
+            .download_all_pieces(&mut peer, &mut storage)
+            .unwrap();
+        assert!(coordinator.is_complete());
+        assert_eq!(
+            peer.stats().bytes_downloaded,
+            (payload.len() - PIECE_LENGTH * 2) as u64
+        );
+
+        assert_eq!(storage.contents(), payload);
+    }
+
+    #[test]
+    fn bans_a_peer_that_sends_corrupt_pieces() {
+        let payload = payload();
+        let torrent = torrent(&payload);
+        let piece_count = torrent.info.pieces.len();
+        let corrupt = payload.iter().map(|byte| !byte).collect();
+        let seeder = MockPeer::new(&torrent, corrupt).spawn();
+
+        let peer_manager = Arc::new(Mutex::new(PeerManager::new()));
+        let mut coordinator = DownloadCoordinator::new(torrent, 0, peer_manager.clone());
+        let peer = PeerConnection::connect(seeder, piece_count).unwrap();
+        let (mut peer, _) = coordinator.handshake(peer).unwrap();
+        let mut storage = storage();
+        coordinator
+            .download_all_pieces(&mut peer, &mut storage)
+            .unwrap();
+
+        assert!(!coordinator.is_complete());
+        assert!(peer.stats().hash_fails >= 3);
+        assert!(peer_manager.lock().unwrap().is_blocked(peer.addr()));
+        // Corrupt pieces never reach the disk.
+        assert!(storage.contents().iter().all(|byte| *byte == 0));
+    }
+
+    #[test]
+    fn bans_a_peer_that_sent_corrupt_blocks_and_left() {
+        let payload = payload();
+        let torrent = torrent(&payload);
+        let piece_count = torrent.info.pieces.len();
+        let honest = MockPeer::new(&torrent, payload).spawn();
+
+        let peer_manager = Arc::new(Mutex::new(PeerManager::new()));
+        let mut coordinator = DownloadCoordinator::new(torrent, 0, peer_manager.clone());
+        let peer = PeerConnection::connect(honest, piece_count).unwrap();
+        let (mut peer, _) = coordinator.handshake(peer).unwrap();
+        let mut storage = storage();
+
+        // Pieces the peer we were connected to before supplied, which fail once it has gone.
+        let gone = "127.0.0.1:9".parse().unwrap();
+        for _ in 0..3 {
+            let buffer = coordinator.buffers.take(PIECE_LENGTH).unwrap();
+            let hash = coordinator.torrent.info.pieces[0];
+            coordinator
+                .piece_sources
+                .insert(0, vec![BlockSource::Peer(gone)]);
+            coordinator.verifying.insert(0);
+            coordinator.verifier.submit(0, buffer, hash);
+            coordinator
+                .finish_verification(&mut peer, &mut storage)
+                .unwrap();
+        }
+
+        let peer_manager = peer_manager.lock().unwrap();
+        assert!(peer_manager.is_blocked(gone));
+        assert!(!peer_manager.is_blocked(peer.addr()));
+        assert_eq!(peer.stats().hash_fails, 0);
+    }
+
+    /// Serves range requests for `payload` over HTTP until the test ends.
+    fn spawn_web_seed(payload: Vec<u8>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/payload", listener.local_addr().unwrap());
+
+        thread::spawn(move || {
+            for socket in listener.incoming() {
+                let mut reader = BufReader::new(socket.unwrap());
+                let mut range = (0, 0);
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    let line = line.trim_end().to_ascii_lowercase();
+                    if line.is_empty() {
+                        break;
+                    }
+                    if let Some((start, end)) = line
+                        .strip_prefix("range: bytes=")
+                        .and_then(|value| value.split_once('-'))
+                    {
+                        range = (start.parse().unwrap(), end.parse::<usize>().unwrap());
+                    }
+                }
+
+                let body = &payload[range.0..=range.1];
+                let mut socket = reader.into_inner();
+                write!(
+                    socket,
+                    "HTTP/1.1 206 Partial Content\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                )
+                .unwrap();
+                socket.write_all(body).unwrap();
+            }
+        });
+
+        url
+    }
+
+    #[test]
+    fn streams_verified_pieces_in_order() {
+        let payload = payload();
+        let torrent = torrent(&payload);
+        let piece_count = torrent.info.pieces.len();
+        let seeder = MockPeer::new(&torrent, payload.clone()).spawn();
+
+        let peer_manager = Arc::new(Mutex::new(PeerManager::new()));
+        let mut coordinator = DownloadCoordinator::new(torrent, 0, peer_manager);
+        coordinator.set_picker(Box::new(RarestFirstPicker));
+        let pieces = coordinator.piece_stream();
+        let peer = PeerConnection::connect(seeder, piece_count).unwrap();
+        let (mut peer, _) = coordinator.handshake(peer).unwrap();
+        coordinator
+            .download_all_pieces(&mut peer, &mut storage())
+            .unwrap();
+
+        let streamed = pieces.try_iter().collect::<Vec<_>>();
+        assert_eq!(
+            streamed.iter().map(|piece| piece.index).collect::<Vec<_>>(),
+            (0..piece_count).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            streamed
+                .into_iter()
+                .flat_map(|piece| piece.data)
+                .collect::<Vec<_>>(),
+            payload
+        );
+    }
+
+    #[test]
+    fn shares_pieces_between_a_peer_and_a_web_seed() {
+        let payload = payload();
+        let mut torrent = torrent(&payload);
+        torrent.url_list = vec![spawn_web_seed(payload.clone())];
+        let piece_count = torrent.info.pieces.len();
+        let seeder = MockPeer::new(&torrent, payload.clone()).spawn();
+
+        let peer_manager = Arc::new(Mutex::new(PeerManager::new()));
+        let mut coordinator = DownloadCoordinator::new(torrent, 0, peer_manager);
+        let peer = PeerConnection::connect(seeder, piece_count).unwrap();
+        let (mut peer, _) = coordinator.handshake(peer).unwrap();
+
+        let mut storage = storage();
+        coordinator
+            .download_all_pieces(&mut peer, &mut storage)
+            .unwrap();
+        assert!(coordinator.is_complete());
+
+        assert_eq!(storage.contents(), payload);
+        // The web seed took at least the first piece, so the peer never sent all of it.
+        assert!(peer.stats().bytes_downloaded < payload.len() as u64);
+    }
+
+    #[test]
+    fn downloads_within_a_single_piece_buffer() {
+        let payload = payload();
+        let mut torrent = torrent(&payload);
+        torrent.url_list = vec![spawn_web_seed(payload.clone())];
+        let piece_count = torrent.info.pieces.len();
+        let seeder = MockPeer::new(&torrent, payload.clone()).spawn();
+
+        let peer_manager = Arc::new(Mutex::new(PeerManager::new()));
+        let mut coordinator = DownloadCoordinator::new(torrent, 0, peer_manager);
+        coordinator.set_piece_buffers(1);
+        let peer = PeerConnection::connect(seeder, piece_count).unwrap();
+        let (mut peer, _) = coordinator.handshake(peer).unwrap();
+
+        let mut storage = storage();
+        coordinator
+            .download_all_pieces(&mut peer, &mut storage)
+            .unwrap();
+        assert!(coordinator.is_complete());
+        assert_eq!(storage.contents(), payload);
+        assert!(coordinator
+            .telemetry()
+            .queue_depths()
+            .iter()
+            .all(|sample| sample.piece_buffers <= 1));
+    }
+
+    #[test]
+    fn holds_one_piece_at_a_time_over_the_memory_budget() {
+        let payload = payload();
+        let mut torrent = torrent(&payload);
+        torrent.url_list = vec![spawn_web_seed(payload.clone())];
+        let piece_count = torrent.info.pieces.len();
+        let seeder = MockPeer::new(&torrent, payload.clone()).spawn();
+
+        let peer_manager = Arc::new(Mutex::new(PeerManager::new()));
+        let mut coordinator = DownloadCoordinator::new(torrent, 0, peer_manager);
+        let memory = MemoryBudget::new(Some(1));
+        coordinator.set_memory_budget(memory.clone());
+        let peer = PeerConnection::connect(seeder, piece_count).unwrap();
+        let (mut peer, _) = coordinator.handshake(peer).unwrap();
+
+        let mut storage = storage();
+        coordinator
+            .download_all_pieces(&mut peer, &mut storage)
+            .unwrap();
+        assert!(coordinator.is_complete());
+        assert_eq!(storage.contents(), payload);
+        assert!(coordinator
+            .telemetry()
+            .queue_depths()
+            .iter()
+            .all(|sample| sample.piece_buffers <= 1));
+        // Buffers are freed rather than kept for reuse while over budget.
+        assert_eq!(memory.usage().piece_buffers, 0);
+    }
+}
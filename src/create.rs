@@ -0,0 +1,487 @@
+//! Making `.torrent` files from a file or directory on disk, as v1 (SHA-1 pieces), v2 (BEP 52
+//! per-file merkle trees) or hybrid torrents carrying both.
+
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, Read},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use sha1::{Digest, Sha1};
+
+use crate::{
+    assembly::BLOCK_SIZE,
+    bencode::{Bencode, Value},
+    hash_transfer::hash_pair,
+    sha256,
+};
+
+pub const DEFAULT_PIECE_LENGTH: usize = 256 * 1024;
+
+/// Which kinds of hashes a created torrent carries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum TorrentVersion {
+    V1,
+    V2,
+    /// Both, readable by v1 and v2 clients alike.
+    Hybrid,
+}
+
+impl TorrentVersion {
+    fn has_v1(self) -> bool {
+        self != TorrentVersion::V2
+    }
+
+    fn has_v2(self) -> bool {
+        self != TorrentVersion::V1
+    }
+}
+
+/// How to make a torrent: where it is announced, how it is split into pieces and which kinds of
+/// hashes it carries.
+#[derive(Debug, Clone)]
+pub struct TorrentCreator {
+    pub announce: String,
+    pub piece_length: usize,
+    pub private: bool,
+    pub version: TorrentVersion,
+}
+
+/// A freshly made torrent, ready to be written out.
+#[derive(Debug)]
+pub struct CreatedTorrent {
+    /// The bencoded `.torrent` file.
+    pub bytes: Vec<u8>,
+    pub info_hash: Option<[u8; 20]>,
+    pub info_hash_v2: Option<[u8; 32]>,
+    pub length: usize,
+    pub file_count: usize,
+}
+
+/// A file going into the torrent.
+struct SourceFile {
+    path: PathBuf,
+    /// Path components below the torrent's directory, empty for a single-file torrent.
+    components: Vec<String>,
+    length: usize,
+}
+
+impl TorrentCreator {
+    /// Hashes the file or directory at `path` into a torrent named after it.
+    pub fn create(&self, path: &Path) -> io::Result<CreatedTorrent> {
+        self.check_piece_length()?;
+        let name = path
+            .file_name()
+            .ok_or_else(|| invalid_input(format!("{} has no file name", path.display())))?
+            .to_string_lossy()
+            .into_owned();
+        let files = source_files(path)?;
+        if files.is_empty() {
+            return Err(invalid_input(format!("{} has no files", path.display())));
+        }
+        let single_file = path.is_file();
+
+        let mut info = HashMap::new();
+        info.insert("name".to_string(), Value::String(name.clone()));
+        info.insert(
+            "piece length".to_string(),
+            Value::Number(self.piece_length as i64),
+        );
+        if self.private {
+            info.insert("private".to_string(), Value::Number(1));
+        }
+
+        let mut v1_pieces = PieceHasher::new(self.piece_length);
+        let mut v1_files = Vec::new();
+        let mut file_tree = HashMap::new();
+        let mut piece_layers = Vec::new();
+        for (index, file) in files.iter().enumerate() {
+            let leaves = self.hash_file(file, &mut v1_pieces)?;
+            if self.version.has_v1() {
+                v1_files.push(file_entry(file.length, &file.components, false));
+            }
+
+            // Hybrid torrents pad every file but the last to a piece boundary, so v1 pieces
+            // line up with the v2 ones.
+            let padding = (self.piece_length - file.length % self.piece_length) % self.piece_length;
+            if self.version == TorrentVersion::Hybrid && index + 1 < files.len() && padding > 0 {
+                v1_pieces.update(&vec![0; padding]);
+                let components = vec![".pad".to_string(), padding.to_string()];
+                v1_files.push(file_entry(padding, &components, true));
+            }
+
+            if self.version.has_v2() {
+                let mut entry = HashMap::new();
+                entry.insert("length".to_string(), Value::Number(file.length as i64));
+                if file.length > 0 {
+                    let (root, layer) = merkle_tree(leaves, self.piece_length / BLOCK_SIZE);
+                    entry.insert("pieces root".to_string(), Value::Blob(root.to_vec()));
+                    if let Some(layer) = layer {
+                        piece_layers.push((root, layer));
+                    }
+                }
+                let mut leaf = HashMap::new();
+                leaf.insert(String::new(), Value::Dictionary(entry));
+
+                // A single file sits at the top of the tree under the torrent's name.
+                let components = if single_file {
+                    vec![name.clone()]
+                } else {
+                    file.components.clone()
+                };
+                insert_into_tree(&mut file_tree, &components, leaf);
+            }
+        }
+
+        let length = files.iter().map(|file| file.length).sum();
+        if self.version.has_v1() {
+            info.insert("pieces".to_string(), Value::Blob(v1_pieces.finish()));
+            if single_file {
+                info.insert("length".to_string(), Value::Number(length as i64));
+            } else {
+                info.insert("files".to_string(), Value::List(v1_files));
+            }
+        }
+        if self.version.has_v2() {
+            info.insert("meta version".to_string(), Value::Number(2));
+            info.insert("file tree".to_string(), Value::Dictionary(file_tree));
+        }
+
+        let info = Value::Dictionary(info);
+        let encoded_info = Bencode::encode(&info);
+        let info_hash = self
+            .version
+            .has_v1()
+            .then(|| Sha1::digest(&encoded_info).into());
+        let info_hash_v2 = self.version.has_v2().then(|| sha256::digest(&encoded_info));
+
+        let created = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs());
+        let mut torrent = HashMap::new();
+        torrent.insert("announce".to_string(), Value::String(self.announce.clone()));
+        torrent.insert(
+            "created by".to_string(),
+            Value::String(env!("CARGO_PKG_NAME").to_string()),
+        );
+        torrent.insert("creation date".to_string(), Value::Number(created as i64));
+        torrent.insert("info".to_string(), info);
+        let mut bytes = Bencode::encode(&Value::Dictionary(torrent));
+        if self.version.has_v2() {
+            // Piece layers are keyed by raw pieces roots, which are not strings, so they are
+            // encoded by hand. The key sorts after all the others, so goes last.
+            bytes.pop();
+            bytes.extend(Bencode::encode(&Value::String("piece layers".to_string())));
+            bytes.extend(encode_piece_layers(piece_layers));
+            bytes.push(b'e');
+        }
+
+        Ok(CreatedTorrent {
+            bytes,
+            info_hash,
+            info_hash_v2,
+            length,
+            file_count: files.len(),
+        })
+    }
+
+    fn check_piece_length(&self) -> io::Result<()> {
+        if self.piece_length == 0 {
+            return Err(invalid_input("piece length must be positive".to_string()));
+        }
+        if self.version.has_v2()
+            && (self.piece_length < BLOCK_SIZE || !self.piece_length.is_power_of_two())
+        {
+            return Err(invalid_input(format!(
+                "v2 torrents need a power of two piece length of at least {}",
+                BLOCK_SIZE
+            )));
+        }
+        Ok(())
+    }
+
+    /// Feeds the file to the v1 piece hasher and returns the SHA-256 of each of its blocks.
+    fn hash_file(
+        &self,
+        file: &SourceFile,
+        v1_pieces: &mut PieceHasher,
+    ) -> io::Result<Vec<[u8; 32]>> {
+        let mut reader = File::open(&file.path)?;
+        let mut leaves = Vec::new();
+        let mut block = vec![0; BLOCK_SIZE];
+        let mut remaining = file.length;
+        while remaining > 0 {
+            let block = &mut block[..remaining.min(BLOCK_SIZE)];
+            reader.read_exact(block)?;
+            remaining -= block.len();
+
+            if self.version.has_v1() {
+                v1_pieces.update(block);
+            }
+            if self.version.has_v2() {
+                leaves.push(sha256::digest(block));
+            }
+        }
+        Ok(leaves)
+    }
+}
+
+fn invalid_input(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+/// The files under `path` in the order they go into the torrent, sorted by path.
+fn source_files(path: &Path) -> io::Result<Vec<SourceFile>> {
+    let metadata = fs::metadata(path)?;
+    if metadata.is_file() {
+        return Ok(vec![SourceFile {
+            path: path.to_path_buf(),
+            components: vec![],
+            length: metadata.len() as usize,
+        }]);
+    }
+
+    let mut files = Vec::new();
+    let mut directories = vec![(path.to_path_buf(), Vec::new())];
+    while let Some((directory, components)) = directories.pop() {
+        for entry in fs::read_dir(&directory)? {
+            let entry = entry?;
+            let mut components = components.clone();
+            components.push(entry.file_name().to_string_lossy().into_owned());
+            let metadata = fs::metadata(entry.path())?;
+            if metadata.is_dir() {
+                directories.push((entry.path(), components));
+            } else {
+                files.push(SourceFile {
+                    path: entry.path(),
+                    components,
+                    length: metadata.len() as usize,
+                });
+            }
+        }
+    }
+    files.sort_by(|a, b| a.components.cmp(&b.components));
+    Ok(files)
+}
+
+fn file_entry(length: usize, components: &[String], padding: bool) -> Value {
+    let mut entry = HashMap::new();
+    entry.insert("length".to_string(), Value::Number(length as i64));
+    entry.insert(
+        "path".to_string(),
+        Value::List(components.iter().cloned().map(Value::String).collect()),
+    );
+    // Padding files (BEP 47) are never written to disk.
+    if padding {
+        entry.insert("attr".to_string(), Value::String("p".to_string()));
+    }
+    Value::Dictionary(entry)
+}
+
+fn insert_into_tree(
+    tree: &mut HashMap<String, Value>,
+    components: &[String],
+    leaf: HashMap<String, Value>,
+) {
+    let Some((name, rest)) = components.split_first() else {
+        return;
+    };
+    if rest.is_empty() {
+        tree.insert(name.clone(), Value::Dictionary(leaf));
+        return;
+    }
+    let child = tree
+        .entry(name.clone())
+        .or_insert_with(|| Value::Dictionary(HashMap::new()));
+    if let Value::Dictionary(child) = child {
+        insert_into_tree(child, rest, leaf);
+    }
+}
+
+/// The root of a file's merkle tree over its block hashes, with the hashes of each piece's
+/// subtree for files longer than a piece.
+fn merkle_tree(leaves: Vec<[u8; 32]>, blocks_per_piece: usize) -> ([u8; 32], Option<Vec<u8>>) {
+    let leaf_count = leaves.len();
+    let mut layer = leaves;
+    // Hashes past the end of the file are zero.
+    layer.resize(leaf_count.next_power_of_two(), [0; 32]);
+
+    let mut piece_layer = None;
+    let mut blocks_per_node = 1;
+    loop {
+        if blocks_per_node == blocks_per_piece && leaf_count > blocks_per_piece {
+            piece_layer = Some(layer[..leaf_count.div_ceil(blocks_per_piece)].concat());
+        }
+        if layer.len() == 1 {
+            return (layer[0], piece_layer);
+        }
+        layer = layer
+            .chunks_exact(2)
+            .map(|pair| hash_pair(&pair[0], &pair[1]))
+            .collect();
+        blocks_per_node *= 2;
+    }
+}
+
+fn encode_piece_layers(mut piece_layers: Vec<([u8; 32], Vec<u8>)>) -> Vec<u8> {
+    piece_layers.sort();
+    let mut bytes = b"d".to_vec();
+    for (root, layer) in piece_layers {
+        bytes.extend(Bencode::encode(&Value::Blob(root.to_vec())));
+        bytes.extend(Bencode::encode(&Value::Blob(layer)));
+    }
+    bytes.push(b'e');
+    bytes
+}
+
+/// SHA-1 hashes of consecutive `piece_length` runs of whatever it is fed.
+struct PieceHasher {
+    piece_length: usize,
+    buffer: Vec<u8>,
+    pieces: Vec<u8>,
+}
+
+impl PieceHasher {
+    fn new(piece_length: usize) -> Self {
+        Self {
+            piece_length,
+            buffer: Vec::with_capacity(piece_length),
+            pieces: Vec::new(),
+        }
+    }
+
+    fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            let take = data.len().min(self.piece_length - self.buffer.len());
+            self.buffer.extend(&data[..take]);
+            data = &data[take..];
+            if self.buffer.len() == self.piece_length {
+                self.pieces.extend(Sha1::digest(&self.buffer));
+                self.buffer.clear();
+            }
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if !self.buffer.is_empty() {
+            self.pieces.extend(Sha1::digest(&self.buffer));
+        }
+        self.pieces
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sha1::{Digest, Sha1};
+
+    use super::{merkle_tree, TorrentCreator, TorrentVersion};
+    use crate::{
+        assembly::BLOCK_SIZE,
+        bencode::{Bencode, Value},
+        hash_transfer::hash_pair,
+        sha256,
+        torrent::Torrent,
+    };
+
+    fn creator(piece_length: usize, version: TorrentVersion) -> TorrentCreator {
+        TorrentCreator {
+            announce: "http://127.0.0.1:1/announce".to_string(),
+            piece_length,
+            private: true,
+            version,
+        }
+    }
+
+    #[test]
+    fn creates_v1_torrents_we_can_open() {
+        let dir = tempfile::tempdir().unwrap();
+        let payload = (0..100u8).collect::<Vec<_>>();
+        let path = dir.path().join("payload");
+        std::fs::write(&path, &payload).unwrap();
+
+        let created = creator(32, TorrentVersion::V1).create(&path).unwrap();
+        assert_eq!(created.info_hash_v2, None);
+        let torrent_path = dir.path().join("payload.torrent");
+        std::fs::write(&torrent_path, &created.bytes).unwrap();
+
+        let torrent = Torrent::open(&torrent_path).unwrap();
+        assert_eq!(torrent.info.length, 100);
+        assert!(torrent.info.private);
+        let pieces = payload
+            .chunks(32)
+            .map(|piece| Sha1::digest(piece).into())
+            .collect::<Vec<[u8; 20]>>();
+        assert_eq!(torrent.info.pieces, pieces);
+        assert_eq!(torrent.info_hash(), hex::encode(created.info_hash.unwrap()));
+    }
+
+    #[test]
+    fn creates_hybrid_torrents_with_padding_and_piece_layers() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("content");
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        std::fs::write(root.join("a"), vec![1; BLOCK_SIZE * 3]).unwrap();
+        std::fs::write(root.join("sub").join("b"), vec![2; 10]).unwrap();
+
+        let created = creator(BLOCK_SIZE * 2, TorrentVersion::Hybrid)
+            .create(&root)
+            .unwrap();
+        assert_eq!(created.file_count, 2);
+        let Ok(Value::Dictionary(torrent)) = Bencode::new(&created.bytes).decode() else {
+            panic!("Expected a dictionary");
+        };
+        let Some(Value::Dictionary(info)) = torrent.get("info") else {
+            panic!("Expected an info dictionary");
+        };
+
+        // The first file is padded out to the end of its second piece.
+        let Some(Value::List(files)) = info.get("files") else {
+            panic!("Expected a files list");
+        };
+        assert_eq!(files.len(), 3);
+        let Some(Value::Blob(pieces)) = info.get("pieces") else {
+            panic!("Expected a pieces blob");
+        };
+        assert_eq!(pieces.len(), 3 * 20);
+
+        let Some(Value::Dictionary(tree)) = info.get("file tree") else {
+            panic!("Expected a file tree");
+        };
+        let Some(Value::Dictionary(sub)) = tree.get("sub") else {
+            panic!("Expected a sub directory");
+        };
+        assert!(sub.contains_key("b"));
+        assert_eq!(info.get("meta version"), Some(&Value::Number(2)));
+        assert!(created.info_hash_v2.is_some());
+        assert!(torrent.contains_key("piece layers"));
+
+        // v1 clients hash the whole info dictionary, v2 keys and all.
+        let torrent_path = dir.path().join("content.torrent");
+        std::fs::write(&torrent_path, &created.bytes).unwrap();
+        assert_eq!(
+            Torrent::open(&torrent_path).unwrap().info_hash(),
+            hex::encode(created.info_hash.unwrap())
+        );
+    }
+
+    #[test]
+    fn builds_merkle_trees_with_piece_layers() {
+        let leaves = (0..3u8).map(|i| sha256::digest(&[i])).collect::<Vec<_>>();
+        let pieces = [
+            hash_pair(&leaves[0], &leaves[1]),
+            hash_pair(&leaves[2], &[0; 32]),
+        ];
+
+        let (root, layer) = merkle_tree(leaves.clone(), 2);
+        assert_eq!(root, hash_pair(&pieces[0], &pieces[1]));
+        assert_eq!(layer, Some(pieces.concat()));
+
+        // Files no longer than a piece have no piece layer.
+        assert_eq!(merkle_tree(leaves[..1].to_vec(), 2), (leaves[0], None));
+        assert!(creator(BLOCK_SIZE + 1, TorrentVersion::V2)
+            .create(std::path::Path::new("."))
+            .is_err());
+    }
+}
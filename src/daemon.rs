@@ -0,0 +1,352 @@
+//! A long-running client around a [`Session`]: it answers the control API, adds torrents
+//! dropped into a watched directory or matched in its feeds, runs the rules of its [`Script`] as
+//! events arrive, applies changes to its [`ConfigFile`], and keeps the latest events for clients
+//! that poll for them.
+
+use std::{
+    collections::VecDeque,
+    io,
+    net::SocketAddr,
+    path::Path,
+    sync::mpsc::{self, Receiver, Sender},
+    time::Duration,
+};
+
+use crate::{
+    config::{ConfigFile, Settings},
+    events::{Event, EventBus},
+    feed::{FeedError, FeedWatcher},
+    log,
+    rpc::{self, RpcCall},
+    script::Script,
+    session::Session,
+    shutdown::Shutdown,
+    watch::{Found, WatchDir},
+};
+
+// How often the daemon looks for torrents whose threads have finished.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+// How many of the latest events the daemon keeps for the control API to hand out.
+const EVENT_LOG_LENGTH: usize = 1024;
+
+pub struct Daemon {
+    session: Session,
+    shutdown: Shutdown,
+    // Calls from the control API and in-process front ends, answered by `run`.
+    calls: Sender<RpcCall>,
+    received_calls: Receiver<RpcCall>,
+    // The latest events, for the control API, and how many there have been in all.
+    event_log: VecDeque<Event>,
+    events_logged: u64,
+    watch: Option<WatchDir>,
+    feeds: Option<FeedWatcher>,
+    script: Option<Script>,
+    // Events the script has yet to see.
+    script_events: Vec<Event>,
+    config: Option<ConfigFile>,
+}
+
+impl Daemon {
+    pub fn new(session: Session) -> Self {
+        let (calls, received_calls) = mpsc::channel();
+        Self {
+            session,
+            shutdown: Shutdown::new(),
+            calls,
+            received_calls,
+            event_log: VecDeque::new(),
+            events_logged: 0,
+            watch: None,
+            feeds: None,
+            script: None,
+            script_events: Vec::new(),
+            config: None,
+        }
+    }
+
+    pub fn session(&self) -> &Session {
+        &self.session
+    }
+
+    pub fn session_mut(&mut self) -> &mut Session {
+        &mut self.session
+    }
+
+    /// Accepts JSON-RPC calls on `addr` from clients presenting `token`, answered while the
+    /// daemon runs. Returns the address the API listens on.
+    pub fn serve_rpc(&mut self, addr: &str, token: String) -> io::Result<SocketAddr> {
+        let addr = rpc::serve(addr, token, self.control())?;
+        log::info!("serving the control API on http://{}/rpc", addr);
+        Ok(addr)
+    }
+
+    /// Sends calls for `run` to answer, as the control API does, for front ends running in
+    /// the same process.
+    pub fn control(&self) -> Sender<RpcCall> {
+        self.calls.clone()
+    }
+
+    /// The bus every torrent's events are published on.
+    pub fn events(&self) -> EventBus {
+        self.session.events()
+    }
+
+    /// The events kept from event number `since` on, oldest first, and the number the next
+    /// event will have. Only the latest events are kept, so a caller that falls behind misses
+    /// some.
+    pub fn events_since(&mut self, since: u64) -> (u64, Vec<Event>) {
+        self.collect_events();
+        let first = self.events_logged - self.event_log.len() as u64;
+        let skip = since.saturating_sub(first) as usize;
+        let events = self.event_log.iter().skip(skip).cloned().collect();
+        (self.events_logged, events)
+    }
+
+    /// Adds the torrents and magnet links dropped into `dir` while the daemon runs.
+    pub fn watch(&mut self, dir: &Path) -> io::Result<()> {
+        self.watch = Some(WatchDir::new(dir)?);
+        log::info!("watching {} for torrents", dir.display());
+        Ok(())
+    }
+
+    /// Adds the torrents of matching entries as `feeds` publishes them.
+    pub fn watch_feeds(&mut self, feeds: FeedWatcher) {
+        self.feeds = Some(feeds);
+    }
+
+    /// Makes the control API calls `script` asks for as events arrive.
+    pub fn set_script(&mut self, script: Script) {
+        self.script = Some(script);
+    }
+
+    /// Applies the settings in `config` again whenever it changes or, on Unix, the process is
+    /// sent SIGHUP.
+    pub fn set_config(&mut self, config: ConfigFile) {
+        #[cfg(unix)]
+        config.reload_on_hangup();
+        self.config = Some(config);
+    }
+
+    /// Applies `settings` to every torrent, without dropping their peers.
+    pub fn apply_settings(&mut self, settings: &Settings) {
+        self.session.set_rate_limits(settings.bandwidth_schedule());
+        self.session
+            .set_connection_limits(settings.connection_limits);
+        self.session.set_memory_budget(settings.memory_budget);
+    }
+
+    /// A handle that stops every torrent and then the daemon when requested.
+    pub fn shutdown_signal(&self) -> Shutdown {
+        self.shutdown.clone()
+    }
+
+    /// Runs until a shutdown is requested, answering API calls meanwhile, then stops every
+    /// torrent cleanly.
+    pub fn run(&mut self) {
+        while !self.shutdown.is_requested() {
+            self.session.reap();
+            self.reload_config();
+            self.add_watched();
+            self.add_from_feeds();
+            self.collect_events();
+            self.run_script();
+            // The daemon holds a sender itself, so the channel is never disconnected.
+            if let Ok(call) = self.received_calls.recv_timeout(POLL_INTERVAL) {
+                self.collect_events();
+                let result = rpc::dispatch(self, &call.method, call.params);
+                let _ = call.reply.send(result);
+            }
+        }
+
+        self.session.stop();
+        self.collect_events();
+    }
+
+    /// Takes in the session's events since last time, keeping the latest for the control API.
+    fn collect_events(&mut self) {
+        for event in self.session.poll_events() {
+            if self.event_log.len() == EVENT_LOG_LENGTH {
+                self.event_log.pop_front();
+            }
+            if self.script.is_some() {
+                self.script_events.push(event.clone());
+            }
+            self.event_log.push_back(event);
+            self.events_logged += 1;
+        }
+    }
+
+    /// Makes the calls the script's rules ask for, for the events since last time. Events the
+    /// calls cause are seen next time round.
+    fn run_script(&mut self) {
+        let Some(script) = &self.script else {
+            return;
+        };
+        let actions = self
+            .script_events
+            .drain(..)
+            .flat_map(|event| script.actions(&event))
+            .collect::<Vec<_>>();
+        for action in actions {
+            log::info!("script line {}: calling {} with {}", action.line, action.method, action.params);
+            if let Err(error) = rpc::dispatch(self, &action.method, action.params) {
+                log::warn!("script line {}: {} failed: {}", action.line, action.method, error);
+            }
+        }
+    }
+
+    fn reload_config(&mut self) {
+        let Some(config) = &mut self.config else {
+            return;
+        };
+        if !config.changed() {
+            return;
+        }
+        match config.load() {
+            Ok(settings) => {
+                log::info!("reloaded settings from {}", config.path().display());
+                self.apply_settings(&settings);
+            }
+            Err(error) => log::warn!("keeping the current settings: {}", error),
+        }
+    }
+
+    fn add_watched(&mut self) {
+        let Some(watch) = &mut self.watch else {
+            return;
+        };
+        for found in watch.scan() {
+            match found {
+                Ok(found) => self.add_found(found, "watched"),
+                Err(error) => log::warn!("{}", error),
+            }
+        }
+    }
+
+    fn add_from_feeds(&mut self) {
+        let Some(feeds) = &mut self.feeds else {
+            return;
+        };
+        for found in feeds.poll() {
+            match found {
+                Ok(found) => self.add_found(found, "feed"),
+                Err(error @ FeedError::Fetch(..)) => log::warn!("{}", error),
+                Err(error) => log::warn!("skipping a feed entry: {}", error),
+            }
+        }
+    }
+
+    fn add_found(&mut self, found: Found, from: &str) {
+        let added = match found {
+            Found::Torrent(torrent) => self.session.add(torrent),
+            Found::Magnet(magnet) => self.session.add_magnet(magnet),
+        };
+        if let Err(error) = added {
+            log::warn!("skipping a {} torrent: {}", from, error);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        io::{Read, Write},
+        net::TcpListener,
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use super::Daemon;
+    use crate::{
+        bandwidth::{BandwidthSchedule, Limit, RateLimiter},
+        create::{TorrentCreator, TorrentVersion},
+        events::TorrentEvent,
+        peer_manager::PeerManager,
+        session::{Session, SessionConfig, SessionError, TorrentState},
+        torrent::Torrent,
+    };
+
+    /// Answers every announce with an empty list of peers.
+    fn spawn_tracker() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for mut socket in listener.incoming().flatten() {
+                let mut request = [0; 4096];
+                let _ = socket.read(&mut request);
+                let body = b"d8:intervali60e5:peers0:e";
+                let _ = write!(
+                    socket,
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                );
+                let _ = socket.write_all(body);
+            }
+        });
+        format!("http://{}/announce", addr)
+    }
+
+    #[test]
+    fn seeds_torrents_already_on_disk_until_stopped() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = dir.path().join("payload");
+        fs::write(&data, vec![7; 40_000]).unwrap();
+        let creator = TorrentCreator {
+            announce: spawn_tracker(),
+            piece_length: 16 * 1024,
+            private: false,
+            version: TorrentVersion::V1,
+        };
+        let torrent_file = dir.path().join("payload.torrent");
+        fs::write(&torrent_file, creator.create(&data).unwrap().bytes).unwrap();
+
+        let config = SessionConfig {
+            download_dir: dir.path().to_path_buf(),
+            port: 0,
+            ..SessionConfig::default()
+        };
+        let schedule = BandwidthSchedule::new(Limit::Unlimited, vec![]);
+        let session =
+            Session::start(config, PeerManager::new(), RateLimiter::new(schedule)).unwrap();
+        let mut daemon = Daemon::new(session);
+        let session = daemon.session_mut();
+        let info_hash = session.add(Torrent::open(&torrent_file).unwrap()).unwrap();
+        assert_eq!(
+            session.add(Torrent::open(&torrent_file).unwrap()),
+            Err(SessionError::Duplicate(info_hash))
+        );
+
+        let started = Instant::now();
+        while session.status()[0].state != TorrentState::Seeding {
+            assert!(started.elapsed() < Duration::from_secs(10));
+            thread::sleep(Duration::from_millis(20));
+        }
+        daemon.shutdown_signal().request();
+        daemon.run();
+
+        assert_eq!(daemon.session().status()[0].state, TorrentState::Stopped);
+        assert_eq!(
+            (
+                daemon.session().status()[0].pieces,
+                daemon.session().status()[0].piece_count
+            ),
+            (3, 3)
+        );
+        assert!(dir.path().join("payload.resume").exists());
+
+        let (next, events) = daemon.events_since(0);
+        assert_eq!(next, events.len() as u64);
+        let seeding = TorrentEvent::StateChanged {
+            state: TorrentState::Seeding,
+        };
+        assert!(events.iter().any(|event| event.event == seeding));
+        assert_eq!(
+            events.last().unwrap().event,
+            TorrentEvent::StateChanged {
+                state: TorrentState::Stopped
+            }
+        );
+        assert!(daemon.events_since(next).1.is_empty());
+    }
+}
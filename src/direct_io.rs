@@ -0,0 +1,160 @@
+use std::{
+    fs::{File, OpenOptions},
+    io,
+    os::unix::fs::{FileExt, OpenOptionsExt},
+    path::Path,
+};
+
+use crate::{log, storage::Storage};
+
+#[cfg(any(target_arch = "aarch64", target_arch = "arm"))]
+const O_DIRECT: i32 = 0o200000;
+#[cfg(not(any(target_arch = "aarch64", target_arch = "arm")))]
+const O_DIRECT: i32 = 0o40000;
+// Offsets, lengths and buffers must all be multiples of this for direct I/O.
+const ALIGNMENT: usize = 4096;
+
+/// Reads and writes around the page cache, so a large download does not push everything else
+/// out of it. Direct I/O only works in aligned chunks, so the unaligned tail of the last piece,
+/// and everything on filesystems that refuse direct writes, goes through a buffered handle.
+pub struct DirectStorage {
+    direct: Option<File>,
+    buffered: File,
+    piece_length: usize,
+}
+
+impl DirectStorage {
+    /// Opens the file at `path` for direct I/O, creating it if needed and sizing it to hold
+    /// `length` bytes. Fails if the filesystem does not support direct I/O at all.
+    pub fn open<P: AsRef<Path>>(path: P, piece_length: usize, length: usize) -> io::Result<Self> {
+        let buffered = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+        buffered.set_len(length as u64)?;
+        let direct = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(O_DIRECT)
+            .open(&path)?;
+
+        Ok(Self {
+            direct: Some(direct),
+            buffered,
+            piece_length,
+        })
+    }
+
+    /// Splits the `length` bytes at `offset` into the part direct I/O can handle and the rest.
+    fn split(&self, offset: u64, length: usize) -> usize {
+        if self.direct.is_none() || !offset.is_multiple_of(ALIGNMENT as u64) {
+            return 0;
+        }
+        length - length % ALIGNMENT
+    }
+
+    /// Gives up on direct I/O after the filesystem rejects it, carrying on buffered.
+    fn fall_back(&mut self, error: io::Error) -> io::Result<()> {
+        if error.kind() != io::ErrorKind::InvalidInput {
+            return Err(error);
+        }
+        log::warn!("direct I/O is not supported here, falling back to buffered writes");
+        self.direct = None;
+        Ok(())
+    }
+}
+
+/// A zeroed buffer of `length` bytes whose start is aligned for direct I/O, along with the
+/// allocation holding it.
+fn aligned(length: usize) -> (Vec<u8>, usize) {
+    let allocation = vec![0; length + ALIGNMENT];
+    let start = allocation.as_ptr().align_offset(ALIGNMENT);
+    (allocation, start)
+}
+
+impl Storage for DirectStorage {
+    fn write_block(&mut self, piece_index: usize, begin: usize, data: &[u8]) -> io::Result<()> {
+        let offset = (piece_index * self.piece_length + begin) as u64;
+        let direct_length = self.split(offset, data.len());
+
+        if direct_length > 0 {
+            let (mut allocation, start) = aligned(direct_length);
+            let buffer = &mut allocation[start..start + direct_length];
+            buffer.copy_from_slice(&data[..direct_length]);
+
+            let direct = self.direct.as_ref().unwrap();
+            if let Err(error) = direct.write_all_at(buffer, offset) {
+                self.fall_back(error)?;
+                return self.buffered.write_all_at(data, offset);
+            }
+        }
+
+        self.buffered
+            .write_all_at(&data[direct_length..], offset + direct_length as u64)
+    }
+
+    fn read_block(
+        &mut self,
+        piece_index: usize,
+        begin: usize,
+        length: usize,
+    ) -> io::Result<Vec<u8>> {
+        let offset = (piece_index * self.piece_length + begin) as u64;
+        let direct_length = self.split(offset, length);
+        let mut block = vec![0; length];
+
+        if direct_length > 0 {
+            let (mut allocation, start) = aligned(direct_length);
+            let buffer = &mut allocation[start..start + direct_length];
+
+            let direct = self.direct.as_ref().unwrap();
+            match direct.read_exact_at(buffer, offset) {
+                Ok(()) => block[..direct_length].copy_from_slice(buffer),
+                Err(error) => {
+                    self.fall_back(error)?;
+                    self.buffered.read_exact_at(&mut block, offset)?;
+                    return Ok(block);
+                }
+            }
+        }
+
+        self.buffered
+            .read_exact_at(&mut block[direct_length..], offset + direct_length as u64)?;
+        Ok(block)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.buffered.sync_all()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{aligned, DirectStorage, ALIGNMENT};
+    use crate::storage::Storage;
+
+    #[test]
+    fn writes_aligned_pieces_and_unaligned_tails() {
+        let (allocation, start) = aligned(ALIGNMENT);
+        assert_eq!(allocation[start..].as_ptr() as usize % ALIGNMENT, 0);
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("payload");
+        // Some filesystems, tmpfs among them, refuse direct I/O outright.
+        let Ok(mut storage) = DirectStorage::open(&path, ALIGNMENT * 2, ALIGNMENT * 3 + 10) else {
+            return;
+        };
+
+        let first = vec![1; ALIGNMENT * 2];
+        let last = vec![2; ALIGNMENT + 10];
+        storage.write_block(1, 0, &last).unwrap();
+        storage.write_block(0, 0, &first).unwrap();
+        storage.sync().unwrap();
+
+        assert_eq!(storage.read_block(1, 0, last.len()).unwrap(), last);
+        assert_eq!(storage.read_block(0, 10, 4).unwrap(), [1; 4]);
+        assert_eq!(std::fs::read(&path).unwrap(), [first, last].concat());
+    }
+}
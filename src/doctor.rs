@@ -0,0 +1,353 @@
+//! Checks of the environment a download runs in, each ending in a finding that says what is
+//! wrong and what to do about it: whether peers can reach our port, whether we sit behind NAT,
+//! whether names resolve, whether the download directory takes our data, and whether the
+//! torrent's trackers answer.
+
+use std::{
+    io,
+    net::{IpAddr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs, UdpSocket},
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+use crate::{free_space, krpc, progress::format_bytes, tracker_check};
+
+// Connecting a UDP socket sends nothing, it only picks the route, so any outside address tells
+// us which of our addresses faces the internet.
+const ROUTE_PROBE: &str = "192.0.2.1:9";
+const SSDP_ADDR: &str = "239.255.255.250:1900";
+const GATEWAY_SEARCH: &str = "M-SEARCH * HTTP/1.1\r\n\
+    HOST: 239.255.255.250:1900\r\n\
+    MAN: \"ssdp:discover\"\r\n\
+    MX: 2\r\n\
+    ST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n\r\n";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Ok,
+    /// Works, but not as well as it could.
+    Warning,
+    /// Stops downloads from working.
+    Problem,
+}
+
+/// What one check found, with what to do about it when it is not fine.
+#[derive(Debug, Serialize)]
+pub struct Finding {
+    pub check: &'static str,
+    pub status: Status,
+    pub detail: String,
+    pub advice: Option<String>,
+}
+
+impl Finding {
+    fn ok(check: &'static str, detail: String) -> Finding {
+        Finding {
+            check,
+            status: Status::Ok,
+            detail,
+            advice: None,
+        }
+    }
+
+    fn warning(check: &'static str, detail: String, advice: String) -> Finding {
+        Finding {
+            check,
+            status: Status::Warning,
+            detail,
+            advice: Some(advice),
+        }
+    }
+
+    fn problem(check: &'static str, detail: String, advice: String) -> Finding {
+        Finding {
+            check,
+            status: Status::Problem,
+            detail,
+            advice: Some(advice),
+        }
+    }
+}
+
+/// Listens on `port` and connects to it over loopback and over the address that faces the
+/// internet, which is as far as we can see without a helper outside the network.
+pub fn listen_port(port: u16, timeout: Duration) -> Finding {
+    let listener = match TcpListener::bind(("0.0.0.0", port)) {
+        Ok(listener) => listener,
+        Err(error) if error.kind() == io::ErrorKind::AddrInUse => {
+            return Finding::problem(
+                "port",
+                format!("port {} is already in use", port),
+                "stop the program using it or pass a free port with --port".to_string(),
+            )
+        }
+        Err(error) => {
+            return Finding::problem(
+                "port",
+                format!("cannot listen on port {}: {}", port, error),
+                "pass a port above 1023 with --port".to_string(),
+            )
+        }
+    };
+    let port = listener.local_addr().map_or(port, |addr| addr.port());
+    let loopback = SocketAddr::from(([127, 0, 0, 1], port));
+    if let Err(error) = TcpStream::connect_timeout(&loopback, timeout) {
+        return Finding::problem(
+            "port",
+            format!("cannot connect to our own port {}: {}", port, error),
+            "check the local firewall allows connections to it".to_string(),
+        );
+    }
+    let Some(ip) = outbound_ip().filter(|ip| !ip.is_loopback()) else {
+        return Finding::ok("port", format!("port {} accepts connections", port));
+    };
+    match TcpStream::connect_timeout(&SocketAddr::new(ip, port), timeout) {
+        Ok(_) => Finding::ok(
+            "port",
+            format!("port {} accepts connections on {}", port, ip),
+        ),
+        Err(error) => Finding::warning(
+            "port",
+            format!("port {} is unreachable on {}: {}", port, ip, error),
+            format!(
+                "allow incoming TCP connections to port {} in the firewall",
+                port
+            ),
+        ),
+    }
+}
+
+/// Whether the address facing the internet is a private one, and if so whether a UPnP gateway
+/// answers on the local network.
+pub fn nat(port: u16, timeout: Duration) -> Finding {
+    let Some(ip) = outbound_ip() else {
+        return Finding::problem(
+            "nat",
+            "no route to the internet".to_string(),
+            "check the network connection".to_string(),
+        );
+    };
+    if is_shared(ip) {
+        return Finding::warning(
+            "nat",
+            format!("{} is behind carrier-grade NAT", ip),
+            "peers cannot connect to us, so only peers accepting connections will be found; \
+             ask the provider for a public address"
+                .to_string(),
+        );
+    }
+    if !is_private(ip) {
+        return Finding::ok("nat", format!("{} is a public address, with no NAT", ip));
+    }
+    let forward = format!("forward TCP port {} on the router to {}", port, ip);
+    match find_gateway(timeout) {
+        Some(location) => Finding::warning(
+            "nat",
+            format!(
+                "{} is behind NAT, with a UPnP gateway at {}, but ports are not mapped \
+                 automatically",
+                ip, location
+            ),
+            forward,
+        ),
+        None => Finding::warning(
+            "nat",
+            format!("{} is behind NAT, and no UPnP gateway answered", ip),
+            forward,
+        ),
+    }
+}
+
+/// Resolves the DHT bootstrap node's name, which every working resolver knows.
+pub fn dns() -> Finding {
+    let host = krpc::BOOTSTRAP_NODE;
+    let started = Instant::now();
+    match host.to_socket_addrs() {
+        Ok(mut addrs) => match addrs.next() {
+            Some(addr) => Finding::ok(
+                "dns",
+                format!(
+                    "resolved {} to {} in {} ms",
+                    host,
+                    addr.ip(),
+                    started.elapsed().as_millis()
+                ),
+            ),
+            None => Finding::problem(
+                "dns",
+                format!("{} resolved to no addresses", host),
+                "check the system's DNS servers".to_string(),
+            ),
+        },
+        Err(error) => Finding::problem(
+            "dns",
+            format!("cannot resolve {}: {}", host, error),
+            "check the network connection and the system's DNS servers".to_string(),
+        ),
+    }
+}
+
+/// Whether we can create files in `dir`, or the nearest ancestor of it that exists.
+pub fn writable(dir: &Path) -> Finding {
+    let existing = dir
+        .ancestors()
+        .map(|ancestor| {
+            if ancestor.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                ancestor
+            }
+        })
+        .find(|ancestor| ancestor.is_dir());
+    let Some(existing) = existing else {
+        return Finding::problem(
+            "disk",
+            format!("{} is not in a directory that exists", dir.display()),
+            "choose another download directory".to_string(),
+        );
+    };
+    match tempfile::NamedTempFile::new_in(existing) {
+        Ok(_) if existing == dir => Finding::ok("disk", format!("{} is writable", dir.display())),
+        Ok(_) => Finding::ok(
+            "disk",
+            format!("{} does not exist yet but can be created", dir.display()),
+        ),
+        Err(error) => Finding::problem(
+            "disk",
+            format!("cannot write in {}: {}", existing.display(), error),
+            "fix its permissions or choose another download directory".to_string(),
+        ),
+    }
+}
+
+/// Whether the filesystem holding `dir` has room for `needed` bytes, when we know how many.
+pub fn space(dir: &Path, needed: Option<u64>) -> Finding {
+    let Some(available) = free_space::available(dir) else {
+        return Finding::ok("space", "free space cannot be told here".to_string());
+    };
+    match needed {
+        Some(needed) if needed > available => Finding::problem(
+            "space",
+            format!(
+                "{} free, but the torrent needs {}",
+                format_bytes(available),
+                format_bytes(needed)
+            ),
+            "free up space or choose another download directory".to_string(),
+        ),
+        Some(needed) => Finding::ok(
+            "space",
+            format!(
+                "{} free, enough for the torrent's {}",
+                format_bytes(available),
+                format_bytes(needed)
+            ),
+        ),
+        None => Finding::ok("space", format!("{} free", format_bytes(available))),
+    }
+}
+
+/// Announces to each tracker. Failures are only problems when no tracker answers, since any
+/// one of them can give us peers.
+pub fn trackers(
+    urls: &[&str],
+    info_hash: &[u8; 20],
+    peer_id: &[u8; 20],
+    port: u16,
+    timeout: Duration,
+) -> Vec<Finding> {
+    let checks = urls
+        .iter()
+        .map(|url| tracker_check::check(url, info_hash, peer_id, port, timeout))
+        .collect::<Vec<_>>();
+    let any_announced = checks.iter().any(tracker_check::TrackerCheck::announced);
+    checks
+        .into_iter()
+        .map(|check| {
+            let announce = check.announce.as_ref();
+            if check.announced() {
+                let peers = announce.and_then(|announce| announce.peers).unwrap_or(0);
+                return Finding::ok("tracker", format!("{} gave {} peers", check.url, peers));
+            }
+            let reason = check
+                .error
+                .clone()
+                .or_else(|| announce.and_then(|announce| announce.failure.clone()))
+                .unwrap_or_else(|| "answer is not bencoded".to_string());
+            let detail = format!("{}: {}", check.url, reason);
+            let advice = format!("run tracker_test on {} for each step", check.url);
+            if any_announced {
+                Finding::warning("tracker", detail, advice)
+            } else {
+                Finding::problem("tracker", detail, advice)
+            }
+        })
+        .collect()
+}
+
+fn outbound_ip() -> Option<IpAddr> {
+    let socket = UdpSocket::bind("0.0.0.0:0").ok()?;
+    socket.connect(ROUTE_PROBE).ok()?;
+    Some(socket.local_addr().ok()?.ip())
+}
+
+fn is_private(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_private() || ip.is_link_local() || ip.is_loopback(),
+        // Unique local addresses, fc00::/7.
+        IpAddr::V6(ip) => (ip.segments()[0] & 0xfe00) == 0xfc00 || ip.is_loopback(),
+    }
+}
+
+// 100.64.0.0/10, which providers share between customers behind their own NAT.
+fn is_shared(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.octets()[0] == 100 && (ip.octets()[1] & 0xc0) == 64,
+        IpAddr::V6(_) => false,
+    }
+}
+
+/// Searches the local network for a UPnP internet gateway, returning where its description is.
+fn find_gateway(timeout: Duration) -> Option<String> {
+    let socket = UdpSocket::bind("0.0.0.0:0").ok()?;
+    socket.send_to(GATEWAY_SEARCH.as_bytes(), SSDP_ADDR).ok()?;
+    socket.set_read_timeout(Some(timeout)).ok()?;
+    let mut buffer = [0; 2048];
+    let (length, _) = socket.recv_from(&mut buffer).ok()?;
+    String::from_utf8_lossy(&buffer[..length])
+        .lines()
+        .find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            name.eq_ignore_ascii_case("location")
+                .then(|| value.trim().to_string())
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::TcpListener, time::Duration};
+
+    use super::{is_shared, listen_port, space, writable, Status};
+
+    #[test]
+    fn finds_ports_in_use_and_directories_we_cannot_use() {
+        let taken = TcpListener::bind("0.0.0.0:0").unwrap();
+        let port = taken.local_addr().unwrap().port();
+        let finding = listen_port(port, Duration::from_secs(1));
+        assert_eq!(finding.status, Status::Problem);
+        assert!(finding.advice.unwrap().contains("--port"));
+
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(writable(dir.path()).status, Status::Ok);
+        assert_eq!(writable(&dir.path().join("new/nested")).status, Status::Ok);
+        assert_eq!(space(dir.path(), Some(1)).status, Status::Ok);
+        if crate::free_space::available(dir.path()).is_some() {
+            assert_eq!(space(dir.path(), Some(u64::MAX)).status, Status::Problem);
+        }
+        assert!(is_shared("100.100.1.1".parse().unwrap()));
+        assert!(!is_shared("100.200.1.1".parse().unwrap()));
+    }
+}
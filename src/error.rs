@@ -0,0 +1,54 @@
+//! One error type for everything the crate can fail at, for callers that would rather pass
+//! failures up with `?` than match on each module's own error.
+
+use crate::{
+    bencode::BencodeError,
+    torrent::{TorrentError, TrackerError},
+};
+#[cfg(not(target_arch = "wasm32"))]
+use crate::{
+    coordinator::ConnectError, magnet::FetchError, peer::PeerError, storage::StorageError,
+};
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Bencode(#[from] BencodeError),
+    #[error(transparent)]
+    Torrent(#[from] TorrentError),
+    #[error(transparent)]
+    Tracker(#[from] TrackerError),
+    #[cfg(not(target_arch = "wasm32"))]
+    #[error(transparent)]
+    Connect(#[from] ConnectError),
+    #[cfg(not(target_arch = "wasm32"))]
+    #[error(transparent)]
+    Metadata(#[from] FetchError),
+    #[cfg(not(target_arch = "wasm32"))]
+    #[error(transparent)]
+    Peer(#[from] PeerError),
+    #[cfg(not(target_arch = "wasm32"))]
+    #[error(transparent)]
+    Storage(#[from] StorageError),
+}
+
+impl Error {
+    /// The module's own error this one wraps.
+    pub fn inner(&self) -> &(dyn std::error::Error + 'static) {
+        match self {
+            Error::Bencode(error) => error,
+            Error::Torrent(error) => error,
+            Error::Tracker(error) => error,
+            #[cfg(not(target_arch = "wasm32"))]
+            Error::Connect(error) => error,
+            #[cfg(not(target_arch = "wasm32"))]
+            Error::Metadata(error) => error,
+            #[cfg(not(target_arch = "wasm32"))]
+            Error::Peer(error) => error,
+            #[cfg(not(target_arch = "wasm32"))]
+            Error::Storage(error) => error,
+        }
+    }
+}
@@ -0,0 +1,159 @@
+//! Events a download publishes as it goes, for front ends to follow it without polling: pieces
+//! verified, peers coming and going, tracker announces and state changes. Anyone holding the
+//! [`EventBus`] can subscribe with a channel or a callback.
+
+use std::sync::{
+    mpsc::{self, Receiver, Sender},
+    Arc, Mutex, MutexGuard,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::session::TorrentState;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum TorrentEvent {
+    /// A session took the torrent on, called `name`.
+    Added {
+        name: String,
+    },
+    /// What we already had when the torrent started, from resume data or the disk.
+    Checked {
+        pieces: usize,
+        piece_count: usize,
+        bytes: u64,
+    },
+    /// The tracker at `url` answered an announce, with how many peers it gave us if we asked.
+    TrackerAnnounced {
+        url: String,
+        peers: Option<usize>,
+    },
+    /// A peer finished its handshake, or a web seed, named by its URL, started serving us.
+    PeerConnected {
+        addr: String,
+    },
+    PeerDisconnected {
+        addr: String,
+    },
+    /// The peer sent too many pieces that failed their hash, and is refused from now on.
+    PeerBanned {
+        addr: String,
+    },
+    /// A downloaded piece matched its hash and was written. `pieces` and `bytes` are how much
+    /// of the torrent we now hold.
+    PieceVerified {
+        piece: usize,
+        pieces: usize,
+        bytes: u64,
+    },
+    /// A downloaded piece failed its hash, so it will be fetched again.
+    PieceFailed {
+        piece: usize,
+    },
+    /// The last missing piece was verified.
+    Completed,
+    /// Downloading stopped, whether or not every piece arrived.
+    Stopped,
+    /// The session moved the torrent to `state`.
+    StateChanged {
+        state: TorrentState,
+    },
+}
+
+/// An event and the torrent it happened to.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Event {
+    pub info_hash: String,
+    #[serde(flatten)]
+    pub event: TorrentEvent,
+}
+
+type Callback = Box<dyn Fn(&Event) + Send>;
+
+enum Subscriber {
+    Channel(Sender<Event>),
+    Callback(Callback),
+}
+
+/// Hands every published event to each subscriber. Clones share their subscribers, so a bus
+/// can be given to several downloads and followed in one place.
+#[derive(Clone, Default)]
+pub struct EventBus {
+    subscribers: Arc<Mutex<Vec<Subscriber>>>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Receives every event published from now on. The subscription ends when the receiver is
+    /// dropped.
+    pub fn subscribe(&self) -> Receiver<Event> {
+        let (sender, receiver) = mpsc::channel();
+        self.lock().push(Subscriber::Channel(sender));
+        receiver
+    }
+
+    /// Calls `callback` with every event published from now on, on the publishing thread, so
+    /// it should be quick. It must not publish events itself.
+    pub fn on(&self, callback: impl Fn(&Event) + Send + 'static) {
+        self.lock().push(Subscriber::Callback(Box::new(callback)));
+    }
+
+    pub fn publish(&self, info_hash: &str, event: TorrentEvent) {
+        let event = Event {
+            info_hash: info_hash.to_string(),
+            event,
+        };
+        self.lock().retain(|subscriber| match subscriber {
+            Subscriber::Channel(sender) => sender.send(event.clone()).is_ok(),
+            Subscriber::Callback(callback) => {
+                callback(&event);
+                true
+            }
+        });
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Vec<Subscriber>> {
+        self.subscribers.lock().expect("Event bus lock poisoned")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use super::{EventBus, TorrentEvent};
+
+    #[test]
+    fn delivers_to_channels_and_callbacks_until_receivers_go() {
+        let bus = EventBus::new();
+        let first = bus.subscribe();
+        let second = bus.clone().subscribe();
+        let called = Arc::new(AtomicUsize::new(0));
+        let counter = called.clone();
+        bus.on(move |_| {
+            counter.fetch_add(1, Ordering::Relaxed);
+        });
+
+        bus.publish("ab", TorrentEvent::PieceFailed { piece: 3 });
+        drop(second);
+        bus.publish("ab", TorrentEvent::Completed);
+
+        let received = first.try_iter().collect::<Vec<_>>();
+        assert_eq!(received.len(), 2);
+        assert_eq!(received[0].info_hash, "ab");
+        assert_eq!(received[1].event, TorrentEvent::Completed);
+        assert_eq!(called.load(Ordering::Relaxed), 2);
+        assert_eq!(bus.lock().len(), 2);
+        assert_eq!(
+            serde_json::to_value(&received[0]).unwrap(),
+            serde_json::json!({ "info_hash": "ab", "event": "piece_failed", "piece": 3 })
+        );
+    }
+}
@@ -0,0 +1,171 @@
+//! Where the engine runs what it does in the background: each torrent of a session, the
+//! verification pool, web seed fetches, peers being seeded to, and the API and tracker servers.
+//! All of it is blocking code, so an [`Executor`] only decides which thread a task gets.
+//! [`Threads`] gives each its own thread and needs no async runtime, and is used unless another
+//! executor is set with [`set_executor`] or
+//! [`ClientBuilder::executor`](crate::ClientBuilder::executor). [`Tokio`] runs tasks on the
+//! blocking pool of a tokio runtime, for embedders that already have one.
+
+use std::{
+    panic::{self, AssertUnwindSafe},
+    sync::{Arc, Condvar, Mutex, OnceLock, RwLock},
+    thread,
+};
+
+/// The executors the command line can pick from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ExecutorKind {
+    Threads,
+    Tokio,
+}
+
+/// A task, run once to completion.
+pub type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// Something that can run blocking tasks alongside each other.
+pub trait Executor: Send + Sync {
+    /// Starts `job` on a thread of its own, without waiting for it. `name` says what the task
+    /// is, for executors that name their threads.
+    fn execute(&self, name: &str, job: Job);
+}
+
+/// Runs each task on a new OS thread.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Threads;
+
+impl Executor for Threads {
+    fn execute(&self, name: &str, job: Job) {
+        thread::Builder::new()
+            .name(name.to_string())
+            .spawn(job)
+            .expect("Failed to spawn thread");
+    }
+}
+
+/// Runs each task on the blocking pool of a tokio runtime.
+#[derive(Debug, Clone)]
+pub struct Tokio {
+    handle: tokio::runtime::Handle,
+}
+
+impl Tokio {
+    pub fn new(handle: tokio::runtime::Handle) -> Self {
+        Self { handle }
+    }
+
+    /// The runtime we are called from. Panics outside of one.
+    pub fn current() -> Self {
+        Self::new(tokio::runtime::Handle::current())
+    }
+}
+
+impl Executor for Tokio {
+    fn execute(&self, _name: &str, job: Job) {
+        // Finishing is reported through the task's own handle.
+        drop(self.handle.spawn_blocking(job));
+    }
+}
+
+/// A running task, to wait on for what it returns.
+#[derive(Debug)]
+pub struct Task<T> {
+    result: Arc<(Mutex<Option<thread::Result<T>>>, Condvar)>,
+}
+
+impl<T> Task<T> {
+    /// Whether the task has returned or panicked.
+    pub fn is_finished(&self) -> bool {
+        self.result.0.lock().expect("Task lock poisoned").is_some()
+    }
+
+    /// Waits for the task to finish, with what it returned or what it panicked with, like
+    /// joining a thread.
+    pub fn join(self) -> thread::Result<T> {
+        let (result, finished) = &*self.result;
+        let mut result = result.lock().expect("Task lock poisoned");
+        loop {
+            if let Some(result) = result.take() {
+                return result;
+            }
+            result = finished.wait(result).expect("Task lock poisoned");
+        }
+    }
+}
+
+static EXECUTOR: RwLock<Option<Arc<dyn Executor>>> = RwLock::new(None);
+static DEFAULT: OnceLock<Arc<dyn Executor>> = OnceLock::new();
+
+/// Runs every later task on `executor`, for the whole process. Tasks already running carry on
+/// where they are.
+pub fn set_executor(executor: Arc<dyn Executor>) {
+    *EXECUTOR.write().expect("Executor lock poisoned") = Some(executor);
+}
+
+/// The executor tasks run on: the one last set, or else [`Threads`].
+pub fn executor() -> Arc<dyn Executor> {
+    if let Some(executor) = &*EXECUTOR.read().expect("Executor lock poisoned") {
+        return executor.clone();
+    }
+    DEFAULT.get_or_init(|| Arc::new(Threads)).clone()
+}
+
+/// Runs `task` on the current executor.
+pub fn spawn<T, F>(name: &str, task: F) -> Task<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    spawn_on(&*executor(), name, task)
+}
+
+/// Runs `task` on `executor`.
+pub fn spawn_on<T, F>(executor: &dyn Executor, name: &str, task: F) -> Task<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    let result = Arc::new((Mutex::new(None), Condvar::new()));
+    let finished = result.clone();
+    executor.execute(
+        name,
+        Box::new(move || {
+            let returned = panic::catch_unwind(AssertUnwindSafe(task));
+            let (result, done) = &*finished;
+            *result.lock().expect("Task lock poisoned") = Some(returned);
+            done.notify_all();
+        }),
+    );
+    Task { result }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use super::{spawn_on, Executor, Threads, Tokio};
+
+    fn runs_tasks_to_completion(executor: &dyn Executor) {
+        let (release, released) = mpsc::channel::<()>();
+        let waiting = spawn_on(executor, "waiting", move || released.recv().is_ok());
+        let panicking = spawn_on(executor, "panicking", || panic!("task failed"));
+
+        assert!(!waiting.is_finished());
+        release.send(()).unwrap();
+        assert!(waiting.join().unwrap());
+        assert!(panicking.join().is_err());
+    }
+
+    #[test]
+    fn threads_run_tasks_to_completion() {
+        runs_tasks_to_completion(&Threads);
+    }
+
+    #[test]
+    fn tokio_runs_tasks_to_completion() {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .build()
+            .unwrap();
+        runs_tasks_to_completion(&Tokio::new(runtime.handle().clone()));
+    }
+}
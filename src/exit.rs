@@ -0,0 +1,189 @@
+//! How the command line fails: one line on stderr saying what went wrong, and an exit status
+//! saying what kind of failure it was, so scripts can tell a typo from a dead tracker.
+
+use std::{error::Error, io, panic};
+
+use bittorrent_starter_rust::{
+    bencode::BencodeError,
+    coordinator::ConnectError,
+    krpc::KrpcError,
+    magnet::{FetchError, MagnetError},
+    metadata::MetadataError,
+    peer::{HandshakeError, PeerError},
+    scrape::ScrapeError,
+    storage::StorageError,
+    torrent::{TorrentError, TrackerError},
+};
+
+/// The command ran but did not get everything done, such as a verify that found bad pieces.
+pub const FAILURE: i32 = 1;
+/// The arguments, or the torrent files and links they name, cannot be used. Clap exits with
+/// this too when it cannot parse the command line.
+pub const BAD_ARGS: i32 = 2;
+/// Reading or writing a file, or listening on a port, failed.
+pub const IO_ERROR: i32 = 3;
+/// The tracker could not be reached or would not give us peers.
+pub const TRACKER_ERROR: i32 = 4;
+/// No peer could be reached, or one broke the protocol.
+pub const PROTOCOL_ERROR: i32 = 5;
+/// What was downloaded failed its hash check.
+pub const CORRUPT_DATA: i32 = 6;
+/// Stopped by Ctrl-C before finishing.
+pub const INTERRUPTED: i32 = 130;
+
+/// An argument we only found to be unusable after parsing it.
+#[derive(Debug, thiserror::Error)]
+#[error("{0}")]
+pub struct InvalidArgument(pub String);
+
+/// A piece we downloaded did not match its hash.
+#[derive(Debug, thiserror::Error)]
+#[error("piece {0} failed its hash check")]
+pub struct CorruptPiece(pub usize);
+
+/// The exit status for `error`, decided by the first cause in its chain we recognise.
+pub fn code(error: &anyhow::Error) -> i32 {
+    error.chain().find_map(classify).unwrap_or(FAILURE)
+}
+
+fn classify(cause: &(dyn Error + 'static)) -> Option<i32> {
+    // It stands in for the module's error, which the chain skips over.
+    if let Some(error) = cause.downcast_ref::<bittorrent_starter_rust::Error>() {
+        return classify(error.inner());
+    }
+    if let Some(error) = cause.downcast_ref::<TorrentError>() {
+        return Some(match error {
+            TorrentError::Read(_) => IO_ERROR,
+            _ => BAD_ARGS,
+        });
+    }
+    if let Some(error) = cause.downcast_ref::<ConnectError>() {
+        return Some(match error {
+            ConnectError::Address(..) => BAD_ARGS,
+            ConnectError::Tracker(_) => TRACKER_ERROR,
+            ConnectError::NoPeers => PROTOCOL_ERROR,
+            ConnectError::Interrupted => INTERRUPTED,
+        });
+    }
+    if let Some(error) = cause.downcast_ref::<FetchError>() {
+        return Some(match error {
+            FetchError::Tracker(_) => TRACKER_ERROR,
+            FetchError::NoPeer => PROTOCOL_ERROR,
+        });
+    }
+    if cause.is::<CorruptPiece>() {
+        return Some(CORRUPT_DATA);
+    }
+    if cause.is::<InvalidArgument>() || cause.is::<MagnetError>() || cause.is::<BencodeError>() {
+        return Some(BAD_ARGS);
+    }
+    if cause.is::<TrackerError>() || cause.is::<ScrapeError>() {
+        return Some(TRACKER_ERROR);
+    }
+    if cause.is::<HandshakeError>()
+        || cause.is::<PeerError>()
+        || cause.is::<MetadataError>()
+        || cause.is::<KrpcError>()
+    {
+        return Some(PROTOCOL_ERROR);
+    }
+    (cause.is::<io::Error>() || cause.is::<StorageError>()).then_some(IO_ERROR)
+}
+
+/// `error` and its causes, leaving out any cause the message before it already spells out.
+pub fn message(error: &anyhow::Error) -> String {
+    let mut message = String::new();
+    for cause in error.chain() {
+        let cause = cause.to_string();
+        if message.contains(&cause) {
+            continue;
+        }
+        if !message.is_empty() {
+            message.push_str(": ");
+        }
+        message.push_str(&cause);
+    }
+    message
+}
+
+/// Reports panics, which are failures nothing has turned into an error yet, in one line like
+/// any other error. With `RUST_BACKTRACE` set they get the usual report instead.
+pub fn report_panics_briefly() {
+    if std::env::var_os("RUST_BACKTRACE").is_some() {
+        return;
+    }
+    panic::set_hook(Box::new(|info| {
+        eprintln!(
+            "error: {}",
+            info.payload_as_str().unwrap_or("unexpected failure")
+        );
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use anyhow::Context;
+
+    use super::{
+        code, message, CorruptPiece, BAD_ARGS, CORRUPT_DATA, FAILURE, IO_ERROR, PROTOCOL_ERROR,
+        TRACKER_ERROR,
+    };
+    use bittorrent_starter_rust::{
+        coordinator::ConnectError,
+        magnet::MagnetError,
+        peer::{HandshakeError, PeerError},
+        storage::StorageError,
+        torrent::{TorrentError, TrackerError},
+        Error,
+    };
+
+    #[test]
+    fn exit_codes_follow_the_kind_of_failure() {
+        let missing = || io::Error::new(io::ErrorKind::NotFound, "gone");
+        let cases = [
+            (anyhow::Error::new(MagnetError::NotMagnet), BAD_ARGS),
+            (TorrentError::Invalid("no name").into(), BAD_ARGS),
+            (TorrentError::Read(missing()).into(), IO_ERROR),
+            (
+                anyhow::Error::new(missing()).context("cannot write"),
+                IO_ERROR,
+            ),
+            (
+                ConnectError::Tracker(TrackerError::Malformed).into(),
+                TRACKER_ERROR,
+            ),
+            (
+                TrackerError::Failure("nope".to_string()).into(),
+                TRACKER_ERROR,
+            ),
+            (ConnectError::NoPeers.into(), PROTOCOL_ERROR),
+            (HandshakeError::Io(missing()).into(), PROTOCOL_ERROR),
+            (
+                anyhow::Error::new(Error::from(PeerError::Unresponsive)).context("download failed"),
+                PROTOCOL_ERROR,
+            ),
+            (Error::from(StorageError::Sync(missing())).into(), IO_ERROR),
+            (
+                anyhow::Error::new(CorruptPiece(1)).context("out"),
+                CORRUPT_DATA,
+            ),
+            (anyhow::anyhow!("only 1 of 2 pieces verify"), FAILURE),
+        ];
+        for (error, expected) in cases {
+            assert_eq!(code(&error), expected, "{}", error);
+        }
+    }
+
+    #[test]
+    fn messages_skip_causes_already_given() {
+        let error = Err::<(), _>(TorrentError::Read(io::Error::new(
+            io::ErrorKind::NotFound,
+            "gone",
+        )))
+        .context("a.torrent")
+        .unwrap_err();
+        assert_eq!(message(&error), "a.torrent: cannot read torrent file: gone");
+    }
+}
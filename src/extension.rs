@@ -0,0 +1,123 @@
+use std::collections::HashMap;
+
+use crate::{
+    bencode::{Bencode, Value},
+    wire::{Message, MessageId},
+};
+
+/// Extended message id reserved for the extension handshake itself (BEP 10).
+pub const HANDSHAKE_ID: u8 = 0;
+/// The id peers should use when sending us `ut_holepunch` messages.
+pub const UT_HOLEPUNCH_ID: u8 = 1;
+/// The id peers should use when sending us `ut_metadata` messages (BEP 9).
+pub const UT_METADATA_ID: u8 = 2;
+
+/// Wraps an extension payload in an `Extended` message addressed to extension `id`.
+pub fn extended_message(id: u8, payload: &[u8]) -> Message {
+    let mut bytes = vec![id];
+    bytes.extend(payload);
+    Message::new(MessageId::Extended, bytes)
+}
+
+/// The `m` dictionary of an extension handshake: which extensions a peer supports and the
+/// message id it wants each of them sent with. Peers that can send the info dictionary also
+/// tell us how long it is.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExtensionHandshake {
+    pub extensions: HashMap<String, u8>,
+    pub metadata_size: Option<usize>,
+}
+
+impl ExtensionHandshake {
+    /// The extensions we support.
+    pub fn ours() -> Self {
+        let mut extensions = HashMap::new();
+        extensions.insert("ut_holepunch".to_string(), UT_HOLEPUNCH_ID);
+        extensions.insert("ut_metadata".to_string(), UT_METADATA_ID);
+        Self {
+            extensions,
+            metadata_size: None,
+        }
+    }
+
+    pub fn id_for(&self, extension: &str) -> Option<u8> {
+        self.extensions.get(extension).copied()
+    }
+
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let m = self
+            .extensions
+            .iter()
+            .map(|(name, id)| (name.clone(), Value::Number(*id as i64)))
+            .collect();
+
+        let mut hash_map = HashMap::new();
+        hash_map.insert("m".to_string(), Value::Dictionary(m));
+        if let Some(size) = self.metadata_size {
+            hash_map.insert("metadata_size".to_string(), Value::Number(size as i64));
+        }
+        Bencode::encode(&Value::Dictionary(hash_map))
+    }
+
+    /// Reads the payload of an extension handshake. An id of 0 means the peer disabled that
+    /// extension, so it is left out, and a payload we cannot read advertises nothing.
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        let mut hash_map = match Bencode::new(bytes).decode() {
+            Ok(Value::Dictionary(hash_map)) => hash_map,
+            _ => HashMap::new(),
+        };
+        let m = match hash_map.remove("m") {
+            Some(Value::Dictionary(m)) => m,
+            _ => HashMap::new(),
+        };
+        let metadata_size = match hash_map.get("metadata_size") {
+            Some(Value::Number(size)) if *size > 0 => Some(*size as usize),
+            _ => None,
+        };
+
+        let extensions = m
+            .into_iter()
+            .filter_map(|(name, id)| match id {
+                Value::Number(id) if (1..=255).contains(&id) => Some((name, id as u8)),
+                _ => None,
+            })
+            .collect();
+
+        Self {
+            extensions,
+            metadata_size,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ExtensionHandshake, UT_HOLEPUNCH_ID, UT_METADATA_ID};
+
+    #[test]
+    fn round_trips_our_handshake() {
+        let ours = ExtensionHandshake::ours();
+        let decoded = ExtensionHandshake::from_bytes(&ours.as_bytes());
+
+        assert_eq!(decoded, ours);
+        assert_eq!(decoded.id_for("ut_holepunch"), Some(UT_HOLEPUNCH_ID));
+        assert_eq!(decoded.id_for("ut_metadata"), Some(UT_METADATA_ID));
+    }
+
+    #[test]
+    fn reads_the_metadata_size() {
+        let decoded =
+            ExtensionHandshake::from_bytes(b"d1:md11:ut_metadatai3ee13:metadata_sizei132ee");
+
+        assert_eq!(decoded.id_for("ut_metadata"), Some(3));
+        assert_eq!(decoded.metadata_size, Some(132));
+    }
+
+    #[test]
+    fn disabled_extensions_are_dropped() {
+        let decoded = ExtensionHandshake::from_bytes(b"d1:md12:ut_holepunchi0e6:ut_pexi2eee");
+
+        assert_eq!(decoded.id_for("ut_holepunch"), None);
+        assert_eq!(decoded.id_for("ut_pex"), Some(2));
+    }
+}
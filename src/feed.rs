@@ -0,0 +1,557 @@
+//! Adds torrents from RSS and Atom feeds, for series whose releases keep coming. Each feed is
+//! polled now and then, and entries whose titles pass its filters are added: from the magnet
+//! link or `.torrent` file the entry points at. Feeds and their filters are read from a file:
+//!
+//! ```text
+//! # Every episode in 1080p, but no samples.
+//! feed https://example.org/shows.rss
+//! match Some\.Show\.S\d+E\d+.*1080p
+//! exclude (?i)sample
+//! ```
+//!
+//! `match` and `exclude` are regular expressions over the title, applying to the feed above
+//! them. A title must match one of the feed's `match` lines, if it has any, and none of its
+//! `exclude` lines. Entries added are remembered in a file, so each is added once even across
+//! restarts; the session skips torrents it already has.
+
+use std::{
+    collections::HashSet,
+    fs, io,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, LazyLock, Mutex},
+    time::{Duration, Instant},
+};
+
+use regex::Regex;
+
+use crate::{
+    executor::{self, Task},
+    http::{self, HttpError, HttpFetch, HttpRequest},
+    log,
+    magnet::{Magnet, MagnetError},
+    torrent::{Torrent, TorrentError},
+    watch::Found,
+};
+
+// How long to wait for a feed, or a torrent file it links to.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+/// What the daemon calls the file of entries added, in its download directory.
+pub const SEEN_FILE: &str = "feeds.seen";
+
+static ENTRY: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?s)<(?:item|entry)\b[^>]*>(.*?)</(?:item|entry)>").unwrap());
+static ENCLOSURE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r#"<enclosure\b[^>]*\burl\s*=\s*["']([^"']*)["']"#).unwrap()
+});
+static LINK: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"<link\b([^>]*)>").unwrap());
+static MAGNET: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"magnet:\?[^"'<\s\]]+"#).unwrap());
+static OPEN_TAG: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"<([\w:.-]+)\b[^>]*>").unwrap());
+static ATTRIBUTE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"([\w:.-]+)\s*=\s*["']([^"']*)["']"#).unwrap());
+static CDATA: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?s)<!\[CDATA\[(.*?)\]\]>").unwrap());
+static ENTITY: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"&(#x[0-9a-fA-F]+|#[0-9]+|[a-z]+);").unwrap());
+
+/// A feed and which of its entries to add.
+#[derive(Debug, Clone)]
+pub struct Feed {
+    pub url: String,
+    matches: Vec<Regex>,
+    excludes: Vec<Regex>,
+}
+
+/// The feeds of a feeds file.
+#[derive(Debug, Clone, Default)]
+pub struct Feeds {
+    feeds: Vec<Feed>,
+}
+
+/// An item of an RSS feed or an entry of an Atom one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub title: String,
+    /// A magnet link or the URL of a `.torrent` file, if the entry has either.
+    pub torrent: Option<String>,
+    /// Tells the entry apart from others across polls: its guid or id, or else its link.
+    pub id: String,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum FeedError {
+    #[error("cannot read {0}: {1}")]
+    Read(PathBuf, io::Error),
+    #[error("line {0}: {1}")]
+    Syntax(usize, String),
+    #[error("cannot fetch {0}: {1}")]
+    Fetch(String, HttpError),
+    #[error("{0}: {1}")]
+    Torrent(String, TorrentError),
+    #[error("{0}: {1}")]
+    Magnet(String, MagnetError),
+}
+
+impl Feed {
+    /// Whether an entry titled `title` should be added.
+    pub fn matches(&self, title: &str) -> bool {
+        (self.matches.is_empty() || self.matches.iter().any(|regex| regex.is_match(title)))
+            && !self.excludes.iter().any(|regex| regex.is_match(title))
+    }
+}
+
+impl Feeds {
+    pub fn load(path: &Path) -> Result<Self, FeedError> {
+        fs::read_to_string(path)
+            .map_err(|error| FeedError::Read(path.to_path_buf(), error))?
+            .parse()
+    }
+
+    pub fn len(&self) -> usize {
+        self.feeds.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.feeds.is_empty()
+    }
+}
+
+impl FromStr for Feeds {
+    type Err = FeedError;
+
+    fn from_str(source: &str) -> Result<Self, Self::Err> {
+        let mut feeds = Vec::<Feed>::new();
+        for (index, line) in source.lines().enumerate() {
+            let number = index + 1;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let syntax = |error: String| FeedError::Syntax(number, error);
+            let (keyword, value) = line
+                .split_once(char::is_whitespace)
+                .map(|(keyword, value)| (keyword, value.trim()))
+                .ok_or_else(|| syntax(format!("expected a value after {}", line)))?;
+            if keyword == "feed" {
+                feeds.push(Feed {
+                    url: value.to_string(),
+                    matches: vec![],
+                    excludes: vec![],
+                });
+                continue;
+            }
+
+            let feed = feeds
+                .last_mut()
+                .ok_or_else(|| syntax(format!("{} comes before any feed", keyword)))?;
+            let regex = || Regex::new(value).map_err(|error| syntax(error.to_string()));
+            match keyword {
+                "match" => feed.matches.push(regex()?),
+                "exclude" => feed.excludes.push(regex()?),
+                _ => return Err(syntax(format!("unknown keyword {}", keyword))),
+            }
+        }
+        Ok(Self { feeds })
+    }
+}
+
+/// Polls feeds in the background, handing over the torrents of new matching entries.
+pub struct FeedWatcher {
+    feeds: Arc<Vec<Feed>>,
+    interval: Duration,
+    last_poll: Option<Instant>,
+    // The feed URL and id of every entry added, so it is only added once.
+    seen: Arc<Mutex<HashSet<(String, String)>>>,
+    seen_path: Arc<PathBuf>,
+    polling: Option<Task<Vec<Result<Found, FeedError>>>>,
+}
+
+impl FeedWatcher {
+    /// Polls `feeds` straight away, then every `interval`. The entries added are kept in
+    /// `seen_path`, picking up from what it holds already.
+    pub fn new(feeds: Feeds, interval: Duration, seen_path: PathBuf) -> Self {
+        Self {
+            feeds: Arc::new(feeds.feeds),
+            interval,
+            last_poll: None,
+            seen: Arc::new(Mutex::new(load_seen(&seen_path))),
+            seen_path: Arc::new(seen_path),
+            polling: None,
+        }
+    }
+
+    /// What the last poll found, once it has finished, starting the next poll when it is
+    /// due. Never waits on the network.
+    pub fn poll(&mut self) -> Vec<Result<Found, FeedError>> {
+        if self.polling.as_ref().is_some_and(|task| !task.is_finished()) {
+            return vec![];
+        }
+        let found = match self.polling.take().map(Task::join) {
+            Some(Ok(found)) => found,
+            Some(Err(_)) => {
+                log::warn!("polling feeds panicked");
+                vec![]
+            }
+            None => vec![],
+        };
+
+        if self
+            .last_poll
+            .is_none_or(|last_poll| last_poll.elapsed() >= self.interval)
+        {
+            self.last_poll = Some(Instant::now());
+            let feeds = self.feeds.clone();
+            let seen = self.seen.clone();
+            let seen_path = self.seen_path.clone();
+            self.polling = Some(executor::spawn("feeds", move || {
+                let fetcher = http::fetcher();
+                let found = feeds
+                    .iter()
+                    .flat_map(|feed| poll_feed(feed, &seen, fetcher.as_ref()))
+                    .collect::<Vec<_>>();
+                if found.iter().any(Result::is_ok) {
+                    let seen = seen.lock().expect("Feed lock poisoned");
+                    if let Err(error) = save_seen(&seen_path, &seen) {
+                        log::warn!("failed to save {}: {}", seen_path.display(), error);
+                    }
+                }
+                found
+            }));
+        }
+        found
+    }
+}
+
+/// Fetches `feed`, and the torrent of each matching entry not `seen` before.
+fn poll_feed(
+    feed: &Feed,
+    seen: &Mutex<HashSet<(String, String)>>,
+    fetcher: &dyn HttpFetch,
+) -> Vec<Result<Found, FeedError>> {
+    let body = match fetch(fetcher, &feed.url) {
+        Ok(body) => body,
+        Err(error) => return vec![Err(FeedError::Fetch(feed.url.clone(), error))],
+    };
+
+    let mut found = Vec::new();
+    for entry in entries(&String::from_utf8_lossy(&body)) {
+        let key = (feed.url.clone(), entry.id.clone());
+        if !feed.matches(&entry.title) || seen.lock().expect("Feed lock poisoned").contains(&key)
+        {
+            continue;
+        }
+        let Some(link) = entry.torrent else {
+            continue;
+        };
+        let result = if link.starts_with("magnet:") {
+            Magnet::parse(&link)
+                .map(Found::Magnet)
+                .map_err(|error| FeedError::Magnet(entry.title.clone(), error))
+        } else {
+            fetch(fetcher, &link)
+                .map_err(|error| FeedError::Fetch(link.clone(), error))
+                .and_then(|body| {
+                    Torrent::from_bytes(&body)
+                        .map_err(|error| FeedError::Torrent(entry.title.clone(), error))
+                })
+                .map(Found::Torrent)
+        };
+        // Entries that failed are tried again next time.
+        if result.is_ok() {
+            log::info!(feed = feed.url; "adding {}", entry.title);
+            seen.lock().expect("Feed lock poisoned").insert(key);
+        }
+        found.push(result);
+    }
+    found
+}
+
+/// The entries added before, treating a file that is missing or unreadable as none.
+fn load_seen(path: &Path) -> HashSet<(String, String)> {
+    fs::read_to_string(path)
+        .ok()
+        .and_then(|contents| serde_json::from_str(&contents).ok())
+        .unwrap_or_default()
+}
+
+/// Writes the entries added through a temporary file, so a crash never leaves half of one.
+fn save_seen(path: &Path, seen: &HashSet<(String, String)>) -> io::Result<()> {
+    let temporary = path.with_extension("tmp");
+    let contents = serde_json::to_string(seen).expect("Failed to serialize feed entries");
+    fs::write(&temporary, contents)?;
+    fs::rename(temporary, path)
+}
+
+fn fetch(fetcher: &dyn HttpFetch, url: &str) -> Result<Vec<u8>, HttpError> {
+    let request = HttpRequest::get(url).timeout(FETCH_TIMEOUT);
+    Ok(fetcher.get(&request)?.error_for_status()?.body)
+}
+
+/// The items of an RSS feed or entries of an Atom one, in the order given.
+pub fn entries(xml: &str) -> Vec<Entry> {
+    ENTRY
+        .captures_iter(xml)
+        .map(|captures| {
+            let body = &captures[1];
+            let title = element(body, "title").unwrap_or_default();
+            // RSS has the link as text, Atom as an attribute, marked as the enclosure when
+            // there are several.
+            let mut links = Vec::new();
+            links.extend(ENCLOSURE.captures(body).map(|url| unescape(&url[1])));
+            for attributes in LINK.captures_iter(body) {
+                let (Some(href), rel) = (
+                    attribute(&attributes[1], "href"),
+                    attribute(&attributes[1], "rel"),
+                ) else {
+                    continue;
+                };
+                if rel.as_deref() == Some("enclosure") {
+                    links.insert(0, href);
+                } else {
+                    links.push(href);
+                }
+            }
+            links.extend(element(body, "link").filter(|link| !link.is_empty()));
+            let magnet = MAGNET.find(body).map(|link| unescape(link.as_str()));
+
+            let torrent = magnet.or_else(|| {
+                links
+                    .iter()
+                    .find(|link| link.ends_with(".torrent"))
+                    .or(links.first())
+                    .cloned()
+            });
+            let id = element(body, "guid")
+                .or_else(|| element(body, "id"))
+                .or_else(|| torrent.clone())
+                .unwrap_or_else(|| title.clone());
+            Entry { title, torrent, id }
+        })
+        .collect()
+}
+
+/// The text of the first `<name>` element in `xml`.
+fn element(xml: &str, name: &str) -> Option<String> {
+    let close = format!("</{}>", name);
+    OPEN_TAG
+        .captures_iter(xml)
+        .filter(|tag| &tag[1] == name)
+        .find_map(|tag| {
+            let text = &xml[tag.get(0).unwrap().end()..];
+            Some(unescape(text[..text.find(&close)?].trim()))
+        })
+}
+
+fn attribute(attributes: &str, name: &str) -> Option<String> {
+    ATTRIBUTE
+        .captures_iter(attributes)
+        .find(|attribute| &attribute[1] == name)
+        .map(|attribute| unescape(&attribute[2]))
+}
+
+/// `text` with its CDATA sections unwrapped and entities outside them replaced.
+fn unescape(text: &str) -> String {
+    let replace = |text: &str| {
+        ENTITY
+            .replace_all(text, |captures: &regex::Captures| {
+                let name = &captures[1];
+                let character = match name {
+                    "amp" => Some('&'),
+                    "lt" => Some('<'),
+                    "gt" => Some('>'),
+                    "quot" => Some('"'),
+                    "apos" => Some('\''),
+                    _ => name
+                        .strip_prefix("#x")
+                        .map(|hex| u32::from_str_radix(hex, 16))
+                        .or_else(|| name.strip_prefix('#').map(str::parse))
+                        .and_then(Result::ok)
+                        .and_then(char::from_u32),
+                };
+                character.map_or_else(|| captures[0].to_string(), String::from)
+            })
+            .into_owned()
+    };
+
+    let mut unescaped = String::new();
+    let mut last = 0;
+    for section in CDATA.captures_iter(text) {
+        let whole = section.get(0).unwrap();
+        unescaped.push_str(&replace(&text[last..whole.start()]));
+        unescaped.push_str(&section[1]);
+        last = whole.end();
+    }
+    unescaped.push_str(&replace(&text[last..]));
+    unescaped
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashSet, fs, sync::Mutex};
+
+    use super::{entries, load_seen, poll_feed, save_seen, Entry, FeedError, Feeds, SEEN_FILE};
+    use crate::{
+        create::{TorrentCreator, TorrentVersion},
+        http::{HttpError, HttpFetch, HttpRequest, HttpResponse},
+        watch::Found,
+    };
+
+    #[test]
+    fn reads_rss_and_atom_entries() {
+        let rss = r#"<?xml version="1.0"?>
+            <rss version="2.0"><channel>
+              <title>Releases</title>
+              <item>
+                <title><![CDATA[Some.Show.S01E02 & more]]></title>
+                <link>https://example.org/view/2</link>
+                <guid isPermaLink="false">release-2</guid>
+                <enclosure url="https://example.org/2.torrent?a=1&amp;b=2" type="application/x-bittorrent" />
+              </item>
+              <item>
+                <title>Other &amp; Show</title>
+                <description>magnet:?xt=urn:btih:0123456789abcdef0123456789abcdef01234567&amp;dn=other</description>
+              </item>
+            </channel></rss>"#;
+        assert_eq!(
+            entries(rss),
+            [
+                Entry {
+                    title: "Some.Show.S01E02 & more".to_string(),
+                    torrent: Some("https://example.org/2.torrent?a=1&b=2".to_string()),
+                    id: "release-2".to_string(),
+                },
+                Entry {
+                    title: "Other & Show".to_string(),
+                    torrent: Some(
+                        "magnet:?xt=urn:btih:0123456789abcdef0123456789abcdef01234567&dn=other"
+                            .to_string()
+                    ),
+                    id: "magnet:?xt=urn:btih:0123456789abcdef0123456789abcdef01234567&dn=other"
+                        .to_string(),
+                },
+            ]
+        );
+
+        let atom = r#"<feed xmlns="http://www.w3.org/2005/Atom">
+              <entry>
+                <title type="text">Some.Show.S01E03</title>
+                <id>urn:uuid:3</id>
+                <link rel="alternate" href="https://example.org/view/3"/>
+                <link rel="enclosure" type="application/x-bittorrent" href="https://example.org/3"/>
+              </entry>
+            </feed>"#;
+        assert_eq!(
+            entries(atom),
+            [Entry {
+                title: "Some.Show.S01E03".to_string(),
+                torrent: Some("https://example.org/3".to_string()),
+                id: "urn:uuid:3".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn filters_titles_and_points_at_bad_lines() {
+        let feeds: Feeds = "
+            # Comments and blank lines are skipped.
+
+            feed https://example.org/shows.rss
+            match Some\\.Show\\.S\\d+E\\d+
+            exclude (?i)sample
+            feed https://example.org/everything.rss
+        "
+        .parse()
+        .unwrap();
+        assert_eq!(feeds.len(), 2);
+        let shows = &feeds.feeds[0];
+        assert!(shows.matches("Some.Show.S01E02.1080p"));
+        assert!(!shows.matches("Some.Show.S01E02.SAMPLE"));
+        assert!(!shows.matches("Other.Show.S01E02"));
+        assert!(feeds.feeds[1].matches("anything at all"));
+
+        for (source, line) in [
+            ("match .*", 1),
+            ("feed https://example.org\nmatch (", 2),
+            ("feed https://example.org\n\nfilter x", 3),
+            ("feed", 1),
+        ] {
+            match source.parse::<Feeds>() {
+                Err(FeedError::Syntax(number, _)) => assert_eq!(number, line, "{}", source),
+                other => panic!("{} parsed as {:?}", source, other),
+            }
+        }
+    }
+
+    /// Answers with the feed, or the torrent file, it was made with.
+    struct Server {
+        feed: String,
+        torrent: Vec<u8>,
+    }
+
+    impl HttpFetch for Server {
+        fn get(&self, request: &HttpRequest) -> Result<HttpResponse, HttpError> {
+            let (status, body) = match request.url.as_str() {
+                "https://example.org/feed" => (200, self.feed.clone().into_bytes()),
+                "https://example.org/1.torrent" => (200, self.torrent.clone()),
+                _ => (404, vec![]),
+            };
+            Ok(HttpResponse { status, body })
+        }
+    }
+
+    #[test]
+    fn adds_each_matching_entry_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = dir.path().join("Some.Show.S01E01");
+        fs::write(&data, vec![7; 1000]).unwrap();
+        let creator = TorrentCreator {
+            announce: "http://127.0.0.1:1/announce".to_string(),
+            piece_length: 16 * 1024,
+            private: false,
+            version: TorrentVersion::V1,
+        };
+        let server = Server {
+            feed: "<rss><channel>
+                <item><title>Some.Show.S01E01</title><link>https://example.org/1.torrent</link></item>
+                <item><title>Some.Show.S01E02</title><link>https://example.org/2.torrent</link></item>
+                <item><title>Other.Show.S01E01</title><link>https://example.org/1.torrent</link></item>
+            </channel></rss>"
+                .to_string(),
+            torrent: creator.create(&data).unwrap().bytes,
+        };
+        let feeds: Feeds = "feed https://example.org/feed\nmatch ^Some\\.Show"
+            .parse()
+            .unwrap();
+        let seen = Mutex::new(HashSet::new());
+
+        let found = poll_feed(&feeds.feeds[0], &seen, &server);
+        assert_eq!(found.len(), 2);
+        assert!(
+            matches!(&found[0], Ok(Found::Torrent(torrent)) if torrent.info.name == "Some.Show.S01E01")
+        );
+        assert!(matches!(&found[1], Err(FeedError::Fetch(url, _)) if url.ends_with("2.torrent")));
+
+        // Only the entry that failed is tried again.
+        let found = poll_feed(&feeds.feeds[0], &seen, &server);
+        assert_eq!(found.len(), 1);
+        assert!(found[0].is_err());
+
+        // Nor is the one added before a restart.
+        let seen_path = dir.path().join(SEEN_FILE);
+        assert!(load_seen(&seen_path).is_empty());
+        save_seen(&seen_path, &seen.lock().unwrap()).unwrap();
+        let restarted = Mutex::new(load_seen(&seen_path));
+        assert_eq!(*restarted.lock().unwrap(), *seen.lock().unwrap());
+        let found = poll_feed(&feeds.feeds[0], &restarted, &server);
+        assert_eq!(found.len(), 1);
+        assert!(found[0].is_err());
+
+        let missing: Feeds = "feed https://example.org/gone".parse().unwrap();
+        assert!(matches!(
+            poll_feed(&missing.feeds[0], &seen, &server).as_slice(),
+            [Err(FeedError::Fetch(..))]
+        ));
+    }
+}
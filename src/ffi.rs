@@ -0,0 +1,339 @@
+//! A C API for embedding the engine in programs not written in Rust: open a `.torrent` file,
+//! read what it describes, and download it with a callback reporting progress. The functions
+//! are declared in `include/bittorrent.h`. The manifest only builds the Rust library, so the
+//! shared library to link against is built with
+//!
+//! ```text
+//! cargo rustc --lib --release --crate-type cdylib
+//! ```
+//!
+//! Functions that fail return null or -1 and leave a message for [`bt_last_error`]. A panic in
+//! the engine is caught before it reaches C and reported the same way.
+
+use std::{
+    cell::RefCell,
+    ffi::{c_char, c_int, c_void, CStr, CString},
+    panic::{self, AssertUnwindSafe},
+    path::PathBuf,
+    ptr,
+};
+
+use crate::{client::Client, events::TorrentEvent, torrent::Torrent};
+
+/// A torrent read from a `.torrent` file, `bt_torrent` in C.
+pub struct BtTorrent {
+    torrent: Torrent,
+    // The strings `bt_torrent_get_info` points into.
+    name: CString,
+    info_hash: CString,
+}
+
+/// What a torrent describes. The strings belong to the torrent and live as long as it does.
+#[repr(C)]
+pub struct BtTorrentInfo {
+    pub name: *const c_char,
+    /// The info hash, as 40 hex digits.
+    pub info_hash: *const c_char,
+    pub length: u64,
+    pub piece_length: u64,
+    pub piece_count: usize,
+    /// Zero for a single-file torrent.
+    pub file_count: usize,
+}
+
+/// Called with how many pieces are verified, of how many, and the bytes they hold.
+pub type BtProgressCallback =
+    Option<extern "C" fn(user_data: *mut c_void, pieces: usize, piece_count: usize, bytes: u64)>;
+
+/// A torrent downloading, and then seeding, until it is stopped. `bt_download` in C.
+pub struct BtDownload {
+    client: Client,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// The pointer given to `bt_download_start`, handed back to its callback on the torrent's
+/// thread. The caller promises it can be used from there.
+struct UserData(*mut c_void);
+
+unsafe impl Send for UserData {}
+
+impl UserData {
+    fn get(&self) -> *mut c_void {
+        self.0
+    }
+}
+
+/// The message of the last call on this thread that failed, or null if none has. It stays
+/// valid until the next call that fails on this thread.
+#[no_mangle]
+pub extern "C" fn bt_last_error() -> *const c_char {
+    LAST_ERROR.with(|error| {
+        error
+            .borrow()
+            .as_ref()
+            .map_or(ptr::null(), |error| error.as_ptr())
+    })
+}
+
+/// Reads the `.torrent` file at `path`. Returns null if it cannot be read, or the torrent, to
+/// be freed with `bt_torrent_free`.
+///
+/// # Safety
+///
+/// `path` must be null or a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn bt_torrent_open(path: *const c_char) -> *mut BtTorrent {
+    guard(ptr::null_mut(), || {
+        let path = unsafe { path_from(path) }?;
+        let torrent = Torrent::open(&path)
+            .map_err(|error| format!("cannot open {}: {}", path.display(), error))?;
+        Ok(Box::into_raw(Box::new(BtTorrent {
+            name: c_string(&torrent.info.name),
+            info_hash: c_string(&torrent.info_hash()),
+            torrent,
+        })))
+    })
+}
+
+/// Fills in `info` from `torrent`. Returns 0, or -1 if either is null.
+///
+/// # Safety
+///
+/// `torrent` must be null or from `bt_torrent_open` and not yet freed, and `info` null or
+/// valid to write a `bt_torrent_info` to.
+#[no_mangle]
+pub unsafe extern "C" fn bt_torrent_get_info(
+    torrent: *const BtTorrent,
+    info: *mut BtTorrentInfo,
+) -> c_int {
+    guard(-1, || {
+        let (Some(torrent), Some(info)) = (unsafe { torrent.as_ref() }, unsafe { info.as_mut() })
+        else {
+            return Err("torrent or info is null".to_string());
+        };
+        *info = BtTorrentInfo {
+            name: torrent.name.as_ptr(),
+            info_hash: torrent.info_hash.as_ptr(),
+            length: torrent.torrent.info.length as u64,
+            piece_length: torrent.torrent.info.piece_length as u64,
+            piece_count: torrent.torrent.info.pieces.len(),
+            file_count: torrent.torrent.info.files.len(),
+        };
+        Ok(0)
+    })
+}
+
+/// Frees a torrent from `bt_torrent_open`. Downloads started from it carry on.
+///
+/// # Safety
+///
+/// `torrent` must be null or from `bt_torrent_open`, and not freed before.
+#[no_mangle]
+pub unsafe extern "C" fn bt_torrent_free(torrent: *mut BtTorrent) {
+    if !torrent.is_null() {
+        drop(unsafe { Box::from_raw(torrent) });
+    }
+}
+
+/// Starts downloading `torrent` into `download_dir`, accepting peers on `port`, or a free port
+/// if it is 0. What is already on disk is checked first, and the torrent seeds once complete.
+///
+/// `callback`, if not null, is called with `user_data` once the torrent has been checked and
+/// again as each piece is verified, on a thread of the download's own. It should return
+/// quickly, and must not stop the download. Returns null if the download cannot start, or the
+/// download, to be ended with `bt_download_stop`.
+///
+/// # Safety
+///
+/// `torrent` must be null or from `bt_torrent_open` and not yet freed, and `download_dir` null
+/// or a NUL-terminated string. `user_data` must be safe to use from another thread until the
+/// download is stopped.
+#[no_mangle]
+pub unsafe extern "C" fn bt_download_start(
+    torrent: *const BtTorrent,
+    download_dir: *const c_char,
+    port: u16,
+    callback: BtProgressCallback,
+    user_data: *mut c_void,
+) -> *mut BtDownload {
+    guard(ptr::null_mut(), || {
+        let torrent = unsafe { torrent.as_ref() }
+            .ok_or("torrent is null")?
+            .torrent
+            .clone();
+        let download_dir = unsafe { path_from(download_dir) }?;
+        let mut client = Client::builder()
+            .download_dir(download_dir)
+            .listen_port(port)
+            .build()
+            .map_err(|error| error.to_string())?;
+
+        if let Some(callback) = callback {
+            let info_hash = torrent.info_hash();
+            let piece_count = torrent.info.pieces.len();
+            let user_data = UserData(user_data);
+            client.events().on(move |event| {
+                if event.info_hash != info_hash {
+                    return;
+                }
+                match event.event {
+                    TorrentEvent::Checked { pieces, bytes, .. }
+                    | TorrentEvent::PieceVerified { pieces, bytes, .. } => {
+                        callback(user_data.get(), pieces, piece_count, bytes)
+                    }
+                    _ => {}
+                }
+            });
+        }
+        client.add(torrent).map_err(|error| error.to_string())?;
+        Ok(Box::into_raw(Box::new(BtDownload { client })))
+    })
+}
+
+/// Stops a download, waiting for it to leave its swarm and save its resume data, and frees it.
+///
+/// # Safety
+///
+/// `download` must be null or from `bt_download_start`, and not stopped before.
+#[no_mangle]
+pub unsafe extern "C" fn bt_download_stop(download: *mut BtDownload) {
+    if download.is_null() {
+        return;
+    }
+    let mut download = unsafe { Box::from_raw(download) };
+    guard((), || {
+        download.client.stop();
+        Ok(())
+    })
+}
+
+/// Runs `body`, turning the error it returns or a panic into `failed` and a message for
+/// `bt_last_error`.
+fn guard<T>(failed: T, body: impl FnOnce() -> Result<T, String>) -> T {
+    let message = match panic::catch_unwind(AssertUnwindSafe(body)) {
+        Ok(Ok(value)) => return value,
+        Ok(Err(message)) => message,
+        Err(_) => "the engine panicked".to_string(),
+    };
+    LAST_ERROR.with(|error| *error.borrow_mut() = Some(c_string(&message)));
+    failed
+}
+
+/// # Safety
+///
+/// `path` must be null or a NUL-terminated string.
+unsafe fn path_from(path: *const c_char) -> Result<PathBuf, String> {
+    if path.is_null() {
+        return Err("path is null".to_string());
+    }
+    unsafe { CStr::from_ptr(path) }
+        .to_str()
+        .map(PathBuf::from)
+        .map_err(|_| "path is not UTF-8".to_string())
+}
+
+/// `text` for C, with any NUL bytes in it dropped.
+fn c_string(text: &str) -> CString {
+    CString::new(text.replace('\0', "")).expect("NUL bytes were removed")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        ffi::{c_void, CStr, CString},
+        fs,
+        ptr,
+        sync::atomic::{AtomicUsize, Ordering},
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use super::{
+        bt_download_start, bt_download_stop, bt_last_error, bt_torrent_free, bt_torrent_get_info,
+        bt_torrent_open, BtTorrentInfo,
+    };
+    use crate::{
+        create::{TorrentCreator, TorrentVersion},
+        tracker_server,
+    };
+
+    #[test]
+    fn opens_torrents_and_reports_why_it_could_not() {
+        let path = CString::new("sample.torrent").unwrap();
+        let torrent = unsafe { bt_torrent_open(path.as_ptr()) };
+        assert!(!torrent.is_null());
+
+        let mut info = BtTorrentInfo {
+            name: ptr::null(),
+            info_hash: ptr::null(),
+            length: 0,
+            piece_length: 0,
+            piece_count: 0,
+            file_count: 0,
+        };
+        assert_eq!(unsafe { bt_torrent_get_info(torrent, &mut info) }, 0);
+        assert_eq!(unsafe { CStr::from_ptr(info.name) }.to_str(), Ok("sample.txt"));
+        assert_eq!(
+            unsafe { CStr::from_ptr(info.info_hash) }.to_str(),
+            Ok("d69f91e6b2ae4c542468d1073a71d4ea13879a7f")
+        );
+        assert_eq!((info.length, info.piece_length), (92063, 32768));
+        assert_eq!((info.piece_count, info.file_count), (3, 0));
+        unsafe { bt_torrent_free(torrent) };
+
+        let missing = CString::new("missing.torrent").unwrap();
+        assert!(unsafe { bt_torrent_open(missing.as_ptr()) }.is_null());
+        let error = unsafe { CStr::from_ptr(bt_last_error()) };
+        assert!(error.to_str().unwrap().contains("missing.torrent"));
+        assert_eq!(unsafe { bt_torrent_get_info(ptr::null(), &mut info) }, -1);
+    }
+
+    #[test]
+    fn reports_progress_until_stopped() {
+        extern "C" fn progress(user_data: *mut c_void, pieces: usize, piece_count: usize, _: u64) {
+            assert_eq!(piece_count, 3);
+            let verified = unsafe { &*(user_data as *const AtomicUsize) };
+            verified.store(pieces, Ordering::SeqCst);
+        }
+
+        // Already on disk, so checking finds every piece.
+        let dir = tempfile::tempdir().unwrap();
+        let data = dir.path().join("payload");
+        fs::write(&data, vec![7; 40_000]).unwrap();
+        let tracker = tracker_server::serve("127.0.0.1:0", Duration::from_secs(60)).unwrap();
+        let creator = TorrentCreator {
+            announce: format!("http://{}/announce", tracker),
+            piece_length: 16 * 1024,
+            private: false,
+            version: TorrentVersion::V1,
+        };
+        let torrent_file = dir.path().join("payload.torrent");
+        fs::write(&torrent_file, creator.create(&data).unwrap().bytes).unwrap();
+
+        let path = CString::new(torrent_file.to_str().unwrap()).unwrap();
+        let download_dir = CString::new(dir.path().to_str().unwrap()).unwrap();
+        let verified = AtomicUsize::new(0);
+        let torrent = unsafe { bt_torrent_open(path.as_ptr()) };
+        let download = unsafe {
+            bt_download_start(
+                torrent,
+                download_dir.as_ptr(),
+                0,
+                Some(progress),
+                &verified as *const AtomicUsize as *mut c_void,
+            )
+        };
+        unsafe { bt_torrent_free(torrent) };
+        assert!(!download.is_null());
+
+        let started = Instant::now();
+        while verified.load(Ordering::SeqCst) != 3 {
+            assert!(started.elapsed() < Duration::from_secs(10));
+            thread::sleep(Duration::from_millis(20));
+        }
+        unsafe { bt_download_stop(download) };
+    }
+}
@@ -0,0 +1,132 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+#[cfg(all(target_os = "linux", target_pointer_width = "64"))]
+mod statvfs {
+    use std::{
+        ffi::CString,
+        os::{raw::c_int, unix::ffi::OsStrExt},
+        path::Path,
+    };
+
+    // Only the leading fields are read; the padding covers the rest of the struct.
+    #[repr(C)]
+    struct Statvfs {
+        f_bsize: u64,
+        f_frsize: u64,
+        f_blocks: u64,
+        f_bfree: u64,
+        f_bavail: u64,
+        rest: [u64; 16],
+    }
+
+    extern "C" {
+        fn statvfs(path: *const std::os::raw::c_char, buf: *mut Statvfs) -> c_int;
+    }
+
+    pub fn available(path: &Path) -> Option<u64> {
+        let path = CString::new(path.as_os_str().as_bytes()).ok()?;
+        let mut stats = Statvfs {
+            f_bsize: 0,
+            f_frsize: 0,
+            f_blocks: 0,
+            f_bfree: 0,
+            f_bavail: 0,
+            rest: [0; 16],
+        };
+        if unsafe { statvfs(path.as_ptr(), &mut stats) } != 0 {
+            return None;
+        }
+        let block_size = if stats.f_frsize > 0 {
+            stats.f_frsize
+        } else {
+            stats.f_bsize
+        };
+        Some(stats.f_bavail * block_size)
+    }
+}
+
+/// Bytes free to us on the filesystem holding `path`, or the nearest ancestor of it that
+/// exists. `None` where we cannot tell.
+pub fn available(path: &Path) -> Option<u64> {
+    let existing = path
+        .ancestors()
+        .map(|ancestor| {
+            if ancestor.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                ancestor
+            }
+        })
+        .find(|ancestor| ancestor.exists())?;
+
+    #[cfg(all(target_os = "linux", target_pointer_width = "64"))]
+    return statvfs::available(existing);
+    #[cfg(not(all(target_os = "linux", target_pointer_width = "64")))]
+    {
+        let _ = existing;
+        None
+    }
+}
+
+/// Bytes the files at `paths` already take up on disk. Output files are sparse, so this can be
+/// far less than their length.
+pub fn allocated(paths: &[PathBuf]) -> u64 {
+    paths
+        .iter()
+        .filter_map(|path| fs::metadata(path).ok())
+        .map(|metadata| {
+            #[cfg(unix)]
+            return std::os::unix::fs::MetadataExt::blocks(&metadata) * 512;
+            #[cfg(not(unix))]
+            metadata.len()
+        })
+        .sum()
+}
+
+/// Checks there is room to write `length` bytes of content to `paths`, counting whatever they
+/// already take up.
+pub fn check(paths: &[PathBuf], length: u64) -> io::Result<()> {
+    let Some(first) = paths.first() else {
+        return Ok(());
+    };
+    let Some(available) = available(first) else {
+        return Ok(());
+    };
+
+    let needed = length.saturating_sub(allocated(paths));
+    if needed > available {
+        return Err(io::Error::new(
+            io::ErrorKind::StorageFull,
+            format!(
+                "not enough disk space for {}: need {} more bytes but only {} are free",
+                first.display(),
+                needed,
+                available
+            ),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{allocated, available, check};
+
+    #[test]
+    fn refuses_downloads_that_do_not_fit() {
+        let dir = tempfile::tempdir().unwrap();
+        let payload = dir.path().join("nested").join("payload");
+        let paths = [payload.clone()];
+        assert!(check(&paths, 1024).is_ok());
+        if available(&payload).is_some() {
+            assert!(check(&paths, u64::MAX).is_err());
+        }
+
+        std::fs::create_dir(dir.path().join("nested")).unwrap();
+        std::fs::write(&payload, [1; 8192]).unwrap();
+        assert!(allocated(&paths) >= 8192);
+    }
+}
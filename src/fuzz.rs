@@ -0,0 +1,264 @@
+//! Entry points for fuzzing what peers send us. Each takes arbitrary bytes, as a peer could
+//! send them, and must return without panicking, exhausting the stack or allocating much more
+//! than it was given. The cargo-fuzz targets in `fuzz/` call them, and the tests below run them
+//! over generated and mangled input.
+
+use std::{
+    io::{self, Cursor, Read, Write},
+    net::{Ipv4Addr, SocketAddr},
+    time::Duration,
+};
+
+use crate::{
+    bencode::Bencode,
+    bitfield::Bitfield,
+    extension::ExtensionHandshake,
+    hash_transfer::{HashRequest, Hashes},
+    holepunch::HolepunchMessage,
+    metadata::{self, MetadataMessage},
+    peer::{generate_peer_id, PeerConnection, Transport},
+    wire::{BlockRequest, Message, MessageId, MessageReader},
+};
+
+// How many pieces the torrent of a fuzzed connection has.
+const PIECE_COUNT: usize = 64;
+
+/// Frames messages out of `data` as a connection reads them, with both readers, and decodes
+/// each payload as the message its id says it is.
+pub fn wire_messages(data: &[u8]) {
+    let _ = Message::decode(data);
+
+    let mut socket = Cursor::new(data);
+    let mut reused = Cursor::new(data);
+    let mut reader = MessageReader::new();
+    while let Ok(message) = Message::read_from_socket(&mut socket) {
+        let read = reader
+            .read(&mut reused)
+            .expect("The readers framed a message differently");
+        match (message, read) {
+            (Some(message), Some(read)) => {
+                assert_eq!(message.id, read.id);
+                assert_eq!(message.payload, read.payload);
+                assert_eq!(message.encode().len(), 4 + message.length as usize);
+                decode_payload(&message);
+            }
+            (None, None) => {}
+            _ => panic!("The readers disagree about a keep-alive"),
+        }
+    }
+}
+
+/// Decodes `data` as the payload of an extended message: the extension's id, then its own
+/// encoding.
+pub fn extension_messages(data: &[u8]) {
+    let _ = Bencode::new(data).decode();
+    let Some((_, payload)) = data.split_first() else {
+        return;
+    };
+
+    let handshake = ExtensionHandshake::from_bytes(payload);
+    assert_eq!(
+        ExtensionHandshake::from_bytes(&handshake.as_bytes()),
+        handshake
+    );
+    if let Some(message) = MetadataMessage::from_bytes(payload) {
+        assert_eq!(
+            MetadataMessage::from_bytes(&message.as_bytes()),
+            Some(message)
+        );
+    }
+    if let Some(message) = HolepunchMessage::from_bytes(payload) {
+        assert_eq!(
+            HolepunchMessage::from_bytes(&message.as_bytes()),
+            Some(message)
+        );
+    }
+}
+
+/// Connects to a peer that sends `data`, exchanges handshakes for the torrent it names, and
+/// reads what follows as a download would. If the lowest bit of the peer's reserved bytes is
+/// set, what follows is read as the info dictionary instead, as for a magnet link.
+pub fn handshake(data: &[u8]) {
+    // Take the info hash from where the peer's handshake has it, so most handshakes succeed
+    // and the messages after them are read too.
+    let info_hash: [u8; 20] = data
+        .get(28..48)
+        .map_or([0; 20], |hash| hash.try_into().unwrap());
+    let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, 6881));
+    let peer = PeerConnection::over(Box::new(Replay::new(data)), addr, PIECE_COUNT);
+    let Ok((mut peer, handshake)) =
+        peer.handshake(hex::encode(info_hash), generate_peer_id(), Some(6881))
+    else {
+        return;
+    };
+
+    if handshake.reserved[0] & 1 == 1 {
+        let _ = metadata::fetch(&mut peer, &info_hash);
+        return;
+    }
+    let Ok((mut peer, messages)) = peer.receive_bitfield() else {
+        return;
+    };
+    for message in &messages {
+        peer.record_availability(message);
+        decode_payload(message);
+    }
+    while let Ok(message) = peer.read_message() {
+        peer.record_availability(&message);
+        decode_payload(&message);
+    }
+}
+
+fn decode_payload(message: &Message) {
+    let payload = &message.payload;
+    match message.id {
+        MessageId::Request | MessageId::Cancel => {
+            if let Some(request) = BlockRequest::decode(payload) {
+                assert_eq!(request.encode(), *payload);
+            }
+        }
+        MessageId::Bitfield => {
+            let bitfield = Bitfield::from_bytes(payload, PIECE_COUNT);
+            assert!(bitfield.count() <= PIECE_COUNT);
+        }
+        MessageId::HashRequest | MessageId::HashReject => {
+            HashRequest::from_bytes(payload);
+        }
+        MessageId::Hashes => {
+            if let Some(hashes) = Hashes::from_bytes(payload) {
+                hashes.verified();
+            }
+        }
+        MessageId::Extended => extension_messages(payload),
+        _ => {}
+    }
+}
+
+/// Plays back what a peer sent, and throws away what we send it.
+struct Replay {
+    received: Cursor<Vec<u8>>,
+}
+
+impl Replay {
+    fn new(data: &[u8]) -> Self {
+        Self {
+            received: Cursor::new(data.to_vec()),
+        }
+    }
+}
+
+impl Read for Replay {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.received.read(buf)
+    }
+}
+
+impl Write for Replay {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for Replay {
+    fn wait_readable(&mut self, _: Duration) -> io::Result<bool> {
+        Ok(true)
+    }
+
+    fn set_timeout(&mut self, _: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn shutdown(&mut self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{extension_messages, handshake, wire_messages};
+    use crate::{
+        arbitrary::{self, Gen},
+        extension::{self, ExtensionHandshake},
+        metadata::MetadataMessage,
+        wire::{Handshake, Message, MessageId},
+    };
+
+    /// What a well-behaved peer might send: its handshake, then a few messages.
+    fn conversation(gen: &mut Gen) -> Vec<u8> {
+        let mut handshake = Handshake::new(
+            "BitTorrent protocol".to_string(),
+            gen.array(),
+            gen.array(),
+        );
+        handshake.reserved = gen.array();
+        handshake.set_supports_extensions();
+        let mut bytes = handshake.encode();
+
+        let mut extensions = ExtensionHandshake::ours();
+        extensions.metadata_size = Some(gen.below(64 * 1024) + 1);
+        let messages = [
+            extension::extended_message(extension::HANDSHAKE_ID, &extensions.as_bytes()),
+            Message::new(MessageId::Bitfield, gen.bytes(8)),
+            Message::have(gen.below(80) as u32),
+            extension::extended_message(
+                extension::UT_METADATA_ID,
+                &MetadataMessage::Data {
+                    piece: 0,
+                    total_size: gen.below(64 * 1024),
+                    data: gen.bytes(64),
+                }
+                .as_bytes(),
+            ),
+            Message::new(MessageId::from(gen.byte()), gen.bytes(32)),
+        ];
+        for message in messages {
+            message.encode_into(&mut bytes);
+        }
+        bytes
+    }
+
+    /// `bytes` with a few of them overwritten, and maybe cut short.
+    fn mangle(gen: &mut Gen, mut bytes: Vec<u8>) -> Vec<u8> {
+        for _ in 0..gen.below(4) + 1 {
+            let at = gen.below(bytes.len());
+            bytes[at] = gen.byte();
+        }
+        if gen.bool() {
+            bytes.truncate(gen.below(bytes.len() + 1));
+        }
+        bytes
+    }
+
+    fn run_every_target(data: &[u8]) {
+        wire_messages(data);
+        extension_messages(data);
+        handshake(data);
+    }
+
+    #[test]
+    fn survives_arbitrary_bytes() {
+        arbitrary::check(|gen| run_every_target(&gen.bytes(512)));
+    }
+
+    #[test]
+    fn survives_mangled_conversations() {
+        arbitrary::check(|gen| {
+            let conversation = conversation(gen);
+            run_every_target(&conversation);
+            run_every_target(&mangle(gen, conversation[68..].to_vec()));
+            run_every_target(&mangle(gen, conversation));
+        });
+    }
+
+    #[test]
+    fn survives_deep_nesting_and_large_claims() {
+        let mut nested = b"d1:m".to_vec();
+        nested.resize(1 << 20, b'l');
+        run_every_target(&nested);
+        // A frame claiming the largest length allowed, and a metadata size to match.
+        run_every_target(&[0, 0x20, 0, 0, 20, 0, b'd']);
+        extension_messages(b"\0d1:md11:ut_metadatai2ee13:metadata_sizei67108864ee");
+    }
+}
@@ -0,0 +1,438 @@
+//! Where peers are, from MaxMind databases: the country an address is registered in and the
+//! autonomous system announcing it, so seedbox operators can see where their traffic goes.
+//! Databases are read in the MaxMind DB format, as GeoLite2 and GeoIP2 ship them. Country and
+//! City databases give the country and ASN databases the network, so a [`GeoIp`] can hold one
+//! of each.
+
+use std::{
+    fmt::{self, Display},
+    fs, io,
+    net::IpAddr,
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+// Starts the metadata section, the last thing in the file.
+const METADATA_MARKER: &[u8] = b"\xab\xcd\xefMaxMind.com";
+// Zero bytes between the search tree and the data section.
+const DATA_SEPARATOR: usize = 16;
+// Data nests maps in maps a few levels deep; anything much deeper is a damaged file.
+const MAX_DEPTH: usize = 32;
+
+/// What the databases say about an address.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Location {
+    /// The ISO 3166 code of the country, such as `NL`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub country: Option<String>,
+    /// The autonomous system number.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub asn: Option<u32>,
+    /// Who the autonomous system belongs to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub organization: Option<String>,
+}
+
+impl Display for Location {
+    /// As `NL AS1136 KPN B.V.`, leaving out what is not known.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let parts = [
+            self.country.clone(),
+            self.asn.map(|asn| format!("AS{}", asn)),
+            self.organization.clone(),
+        ];
+        let parts = parts.into_iter().flatten().collect::<Vec<_>>();
+        write!(f, "{}", parts.join(" "))
+    }
+}
+
+/// The databases peers are looked up in. Clones share them.
+#[derive(Debug, Clone, Default)]
+pub struct GeoIp {
+    databases: Arc<Vec<Database>>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum GeoIpError {
+    #[error("cannot read {0}: {1}")]
+    Read(PathBuf, io::Error),
+    #[error("{0} is not a MaxMind database: {1}")]
+    Invalid(PathBuf, &'static str),
+}
+
+impl GeoIp {
+    /// Opens the databases at `paths`. Where several know about an address, the first to say
+    /// something wins.
+    pub fn open<P: AsRef<Path>>(paths: &[P]) -> Result<Self, GeoIpError> {
+        let databases = paths
+            .iter()
+            .map(|path| {
+                let path = path.as_ref();
+                let bytes =
+                    fs::read(path).map_err(|error| GeoIpError::Read(path.to_path_buf(), error))?;
+                Database::new(bytes).map_err(|error| GeoIpError::Invalid(path.to_path_buf(), error))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            databases: Arc::new(databases),
+        })
+    }
+
+    /// What the databases know about `ip`, or `None` if none of them know anything.
+    pub fn lookup(&self, ip: IpAddr) -> Option<Location> {
+        let mut location = Location::default();
+        for record in self.databases.iter().filter_map(|database| database.lookup(ip)) {
+            let text = |pointer: &str| record.pointer(pointer).and_then(Value::as_str);
+            location.country = location.country.or_else(|| {
+                text("/country/iso_code")
+                    .or_else(|| text("/registered_country/iso_code"))
+                    .map(str::to_string)
+            });
+            location.asn = location.asn.or_else(|| {
+                record
+                    .get("autonomous_system_number")
+                    .and_then(Value::as_u64)
+                    .and_then(|asn| asn.try_into().ok())
+            });
+            location.organization = location
+                .organization
+                .or_else(|| text("/autonomous_system_organization").map(str::to_string));
+        }
+        Some(location).filter(|location| *location != Location::default())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.databases.is_empty()
+    }
+}
+
+/// One database, read whole into memory.
+#[derive(Debug)]
+struct Database {
+    bytes: Vec<u8>,
+    node_count: usize,
+    /// Bits in each of a node's two records: 24, 28 or 32.
+    record_size: usize,
+    /// 4 or 6. IPv4 addresses are looked up in IPv6 databases as `::a.b.c.d`.
+    ip_version: u16,
+    /// Where the data section starts and ends, at the metadata.
+    data: Range<usize>,
+}
+
+impl Database {
+    fn new(bytes: Vec<u8>) -> Result<Self, &'static str> {
+        let marker = bytes
+            .windows(METADATA_MARKER.len())
+            .rposition(|window| window == METADATA_MARKER)
+            .ok_or("no metadata")?;
+        let metadata = Decoder::new(&bytes[marker + METADATA_MARKER.len()..])
+            .decode(0, 0)?
+            .0;
+        let number = |key: &str| metadata.get(key).and_then(Value::as_u64);
+
+        let node_count = number("node_count").ok_or("no node count")? as usize;
+        let record_size = number("record_size").ok_or("no record size")? as usize;
+        if ![24, 28, 32].contains(&record_size) {
+            return Err("unsupported record size");
+        }
+        let ip_version = match number("ip_version") {
+            Some(4) => 4,
+            Some(6) => 6,
+            _ => return Err("unsupported IP version"),
+        };
+        let data_start = node_count
+            .checked_mul(record_size / 4)
+            .and_then(|tree| tree.checked_add(DATA_SEPARATOR))
+            .filter(|data_start| *data_start <= marker)
+            .ok_or("search tree runs past the end")?;
+
+        Ok(Self {
+            bytes,
+            node_count,
+            record_size,
+            ip_version,
+            data: data_start..marker,
+        })
+    }
+
+    /// The record for the network `ip` is in, if the database has one.
+    fn lookup(&self, ip: IpAddr) -> Option<Value> {
+        let bits = match (ip, self.ip_version) {
+            (IpAddr::V4(ip), 4) => ip.octets().to_vec(),
+            (IpAddr::V4(ip), _) => ip.to_ipv6_compatible().octets().to_vec(),
+            (IpAddr::V6(ip), 6) => ip.octets().to_vec(),
+            (IpAddr::V6(ip), _) => ip.to_ipv4_mapped()?.octets().to_vec(),
+        };
+
+        let mut node = 0;
+        for bit in 0..bits.len() * 8 {
+            if node >= self.node_count {
+                break;
+            }
+            let right = bits[bit / 8] & (0x80 >> (bit % 8)) != 0;
+            node = self.record(node, right)?;
+        }
+        // A node count is the record for addresses the database knows nothing about.
+        let offset = node.checked_sub(self.node_count + DATA_SEPARATOR)?;
+
+        Some(Decoder::new(&self.bytes[self.data.clone()]).decode(offset, 0).ok()?.0).filter(Value::is_object)
+    }
+
+    /// The left or right record of `node`.
+    fn record(&self, node: usize, right: bool) -> Option<usize> {
+        let size = self.record_size / 4;
+        let bytes = self.bytes.get(node * size..node * size + size)?;
+        let be = |bytes: &[u8]| bytes.iter().fold(0, |value, byte| value << 8 | *byte as usize);
+        Some(match (self.record_size, right) {
+            (24, false) => be(&bytes[..3]),
+            (24, true) => be(&bytes[3..]),
+            (28, false) => (bytes[3] as usize & 0xf0) << 20 | be(&bytes[..3]),
+            (28, true) => (bytes[3] as usize & 0x0f) << 24 | be(&bytes[4..]),
+            (_, false) => be(&bytes[..4]),
+            (_, true) => be(&bytes[4..]),
+        })
+    }
+}
+
+/// Reads values from a data section, where pointers are offsets from its start.
+struct Decoder<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    fn bytes(&self, at: usize, length: usize) -> Result<&'a [u8], &'static str> {
+        self.data
+            .get(at..at.checked_add(length).ok_or("truncated data")?)
+            .ok_or("truncated data")
+    }
+
+    fn unsigned(&self, at: usize, length: usize) -> Result<u128, &'static str> {
+        if length > 16 {
+            return Err("integer too long");
+        }
+        Ok(self
+            .bytes(at, length)?
+            .iter()
+            .fold(0, |value, byte| value << 8 | *byte as u128))
+    }
+
+    /// The value at `at`, and where the next one starts.
+    fn decode(&self, at: usize, depth: usize) -> Result<(Value, usize), &'static str> {
+        if depth > MAX_DEPTH {
+            return Err("data nested too deeply");
+        }
+        let control = self.bytes(at, 1)?[0];
+        let mut at = at + 1;
+        let mut kind = control >> 5;
+        if kind == 1 {
+            let (target, next) = self.pointer(control, at)?;
+            return Ok((self.decode(target, depth + 1)?.0, next));
+        }
+        if kind == 0 {
+            kind = 7 + self.bytes(at, 1)?[0];
+            at += 1;
+        }
+        let (size, mut at) = match control & 0x1f {
+            29 => (29 + self.unsigned(at, 1)? as usize, at + 1),
+            30 => (285 + self.unsigned(at, 2)? as usize, at + 2),
+            31 => (65_821 + self.unsigned(at, 3)? as usize, at + 3),
+            size => (size as usize, at),
+        };
+
+        let value = match kind {
+            2 => Value::String(
+                std::str::from_utf8(self.bytes(at, size)?)
+                    .map_err(|_| "string is not UTF-8")?
+                    .to_string(),
+            ),
+            3 if size == 8 => {
+                let bits = self.unsigned(at, 8)? as u64;
+                Value::from(f64::from_bits(bits))
+            }
+            15 if size == 4 => {
+                let bits = self.unsigned(at, 4)? as u32;
+                Value::from(f32::from_bits(bits) as f64)
+            }
+            // Nothing we look up is raw bytes.
+            4 => {
+                self.bytes(at, size)?;
+                Value::Null
+            }
+            5 | 6 | 9 | 10 => {
+                let value = self.unsigned(at, size)?;
+                u64::try_from(value).map_or_else(|_| Value::from(value.to_string()), Value::from)
+            }
+            8 => Value::from(self.unsigned(at, size)? as u32 as i32),
+            7 => {
+                let mut map = Map::new();
+                for _ in 0..size {
+                    let (key, next) = self.decode(at, depth + 1)?;
+                    let (value, next) = self.decode(next, depth + 1)?;
+                    let Value::String(key) = key else {
+                        return Err("map key is not a string");
+                    };
+                    map.insert(key, value);
+                    at = next;
+                }
+                return Ok((Value::Object(map), at));
+            }
+            11 => {
+                let mut array = Vec::new();
+                for _ in 0..size {
+                    let (value, next) = self.decode(at, depth + 1)?;
+                    array.push(value);
+                    at = next;
+                }
+                return Ok((Value::Array(array), at));
+            }
+            14 => return Ok((Value::Bool(size != 0), at)),
+            _ => return Err("unknown data type"),
+        };
+        Ok((value, at + size))
+    }
+
+    /// Where the pointer with `control`, whose remaining bytes start at `at`, points, and where
+    /// the next value starts.
+    fn pointer(&self, control: u8, at: usize) -> Result<(usize, usize), &'static str> {
+        let high = (control & 0x07) as usize;
+        let (target, length) = match (control >> 3) & 0x03 {
+            0 => (high << 8 | self.unsigned(at, 1)? as usize, 1),
+            1 => ((high << 16 | self.unsigned(at, 2)? as usize) + 2_048, 2),
+            2 => ((high << 24 | self.unsigned(at, 3)? as usize) + 526_336, 3),
+            _ => (self.unsigned(at, 4)? as usize, 4),
+        };
+        Ok((target, at + length))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, net::IpAddr};
+
+    use super::{Database, GeoIp, Location, METADATA_MARKER};
+
+    fn string(text: &str) -> Vec<u8> {
+        let mut bytes = match text.len() {
+            length @ 0..29 => vec![0x40 | length as u8],
+            length => vec![0x40 | 29, (length - 29) as u8],
+        };
+        bytes.extend(text.as_bytes());
+        bytes
+    }
+
+    fn unsigned(kind: u8, value: u32) -> Vec<u8> {
+        let bytes = value.to_be_bytes();
+        let bytes = &bytes[value.leading_zeros() as usize / 8..];
+        let mut encoded = match kind {
+            5 => vec![0xa0 | bytes.len() as u8],
+            _ => vec![0xc0 | bytes.len() as u8],
+        };
+        encoded.extend(bytes);
+        encoded
+    }
+
+    fn map(entries: &[(&str, Vec<u8>)]) -> Vec<u8> {
+        let mut bytes = vec![0xe0 | entries.len() as u8];
+        for (key, value) in entries {
+            bytes.extend(string(key));
+            bytes.extend(value);
+        }
+        bytes
+    }
+
+    /// An IPv4 database whose data section is `data`, with the record at `record` in it for
+    /// 10.0.0.0/8 and nothing for other addresses.
+    fn database(data: Vec<u8>, record: u32) -> Vec<u8> {
+        let prefix = 10u8;
+        let node_count = 8u32;
+        let mut bytes = Vec::new();
+        for node in 0..node_count {
+            let next = if node + 1 == node_count {
+                node_count + 16 + record
+            } else {
+                node + 1
+            };
+            let (left, right) = if prefix & (0x80 >> node) == 0 {
+                (next, node_count)
+            } else {
+                (node_count, next)
+            };
+            bytes.extend(&left.to_be_bytes()[1..]);
+            bytes.extend(&right.to_be_bytes()[1..]);
+        }
+        bytes.extend([0; 16]);
+        bytes.extend(data);
+        bytes.extend(METADATA_MARKER);
+        bytes.extend(map(&[
+            ("node_count", unsigned(6, node_count)),
+            ("record_size", unsigned(5, 24)),
+            ("ip_version", unsigned(5, 4)),
+        ]));
+        bytes
+    }
+
+    #[test]
+    fn finds_the_country_and_network_of_an_address() {
+        let dir = tempfile::tempdir().unwrap();
+        let country = dir.path().join("country.mmdb");
+        fs::write(
+            &country,
+            database(map(&[("country", map(&[("iso_code", string("NL"))]))]), 0),
+        )
+        .unwrap();
+        // The organization is a pointer to the name, at the start of the data section.
+        let mut data = string("KPN B.V.");
+        let record = data.len() as u32;
+        data.extend(map(&[
+            ("autonomous_system_number", unsigned(6, 1136)),
+            ("autonomous_system_organization", vec![0x20, 0]),
+        ]));
+        let asn = dir.path().join("asn.mmdb");
+        fs::write(&asn, database(data, record)).unwrap();
+
+        let geoip = GeoIp::open(&[&country, &asn]).unwrap();
+        let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+        let location = geoip.lookup(ip("10.1.2.3")).unwrap();
+        assert_eq!(
+            location,
+            Location {
+                country: Some("NL".to_string()),
+                asn: Some(1136),
+                organization: Some("KPN B.V.".to_string()),
+            }
+        );
+        assert_eq!(location.to_string(), "NL AS1136 KPN B.V.");
+        assert_eq!(geoip.lookup(ip("::ffff:10.0.0.1")), Some(location));
+        assert_eq!(geoip.lookup(ip("11.0.0.1")), None);
+        assert_eq!(geoip.lookup(ip("2001:db8::1")), None);
+    }
+
+    #[test]
+    fn refuses_damaged_databases() {
+        let whole = database(map(&[("country", map(&[("iso_code", string("NL"))]))]), 0);
+        assert!(Database::new(whole.clone()).is_ok());
+        assert!(Database::new(whole[..20].to_vec()).is_err());
+        assert!(Database::new(b"not a database".to_vec()).is_err());
+
+        // However much of the data section is cut off, looking up gives nothing.
+        let data_start = 8 * 6 + 16;
+        let metadata = whole
+            .windows(METADATA_MARKER.len())
+            .rposition(|window| window == METADATA_MARKER)
+            .unwrap();
+        for end in data_start..metadata {
+            let mut cut = whole[..end].to_vec();
+            cut.extend(&whole[metadata..]);
+            let database = Database::new(cut).unwrap();
+            assert_eq!(database.lookup("10.0.0.1".parse().unwrap()), None);
+        }
+    }
+}
@@ -0,0 +1,180 @@
+use crate::{
+    sha256,
+    wire::{Message, MessageId},
+};
+
+/// Asks a peer for a run of hashes from one layer of a file's merkle tree (BEP 52), along with
+/// enough uncle hashes to prove them against the file's `pieces root`. Also the payload of the
+/// `HashReject` a peer sends back when it cannot answer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HashRequest {
+    pub pieces_root: [u8; 32],
+    pub base_layer: u32,
+    pub index: u32,
+    pub length: u32,
+    pub proof_layers: u32,
+}
+
+impl HashRequest {
+    const LENGTH: usize = 48;
+
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.pieces_root.to_vec();
+        bytes.extend(self.base_layer.to_be_bytes());
+        bytes.extend(self.index.to_be_bytes());
+        bytes.extend(self.length.to_be_bytes());
+        bytes.extend(self.proof_layers.to_be_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < Self::LENGTH {
+            return None;
+        }
+
+        let field = |at: usize| u32::from_be_bytes(bytes[at..at + 4].try_into().unwrap());
+        Some(Self {
+            pieces_root: bytes[..32].try_into().unwrap(),
+            base_layer: field(32),
+            index: field(36),
+            length: field(40),
+            proof_layers: field(44),
+        })
+    }
+
+    /// Tells the requesting peer we will not answer this request.
+    pub fn reject(self) -> Message {
+        Message::new(MessageId::HashReject, self.as_bytes())
+    }
+}
+
+/// The answer to a `HashRequest`: the requested hashes followed by their uncle hashes, from
+/// the bottom of the tree upwards.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hashes {
+    pub request: HashRequest,
+    pub hashes: Vec<[u8; 32]>,
+}
+
+impl Hashes {
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let request = HashRequest::from_bytes(bytes)?;
+        let hashes = &bytes[HashRequest::LENGTH..];
+        if !hashes.len().is_multiple_of(32) {
+            return None;
+        }
+
+        Some(Self {
+            request,
+            hashes: hashes
+                .chunks_exact(32)
+                .map(|chunk| chunk.try_into().unwrap())
+                .collect(),
+        })
+    }
+
+    // Only needed once we can serve hashes for v2 torrents ourselves.
+    #[allow(dead_code)]
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.request.as_bytes();
+        for hash in &self.hashes {
+            bytes.extend(hash);
+        }
+        bytes
+    }
+
+    /// The requested hashes, if they and their proof hash up to the request's pieces root.
+    pub fn verified(&self) -> Option<&[[u8; 32]]> {
+        let length = self.request.length as usize;
+        if !length.is_power_of_two()
+            || self.hashes.len() < length
+            || !(self.request.index as usize).is_multiple_of(length)
+        {
+            return None;
+        }
+
+        let (base, proof) = self.hashes.split_at(length);
+        let mut layer = base.to_vec();
+        while layer.len() > 1 {
+            layer = layer
+                .chunks_exact(2)
+                .map(|pair| hash_pair(&pair[0], &pair[1]))
+                .collect();
+        }
+
+        let mut node = layer[0];
+        let mut position = self.request.index as usize / length;
+        for uncle in proof {
+            node = if position.is_multiple_of(2) {
+                hash_pair(&node, uncle)
+            } else {
+                hash_pair(uncle, &node)
+            };
+            position /= 2;
+        }
+
+        (node == self.request.pieces_root).then_some(base)
+    }
+}
+
+/// The hash of a merkle tree node from its two children.
+pub fn hash_pair(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    let mut bytes = left.to_vec();
+    bytes.extend(right);
+    sha256::digest(&bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{hash_pair, HashRequest, Hashes};
+
+    fn leaves() -> Vec<[u8; 32]> {
+        (0..4u8).map(|i| [i; 32]).collect()
+    }
+
+    fn root(leaves: &[[u8; 32]]) -> [u8; 32] {
+        hash_pair(
+            &hash_pair(&leaves[0], &leaves[1]),
+            &hash_pair(&leaves[2], &leaves[3]),
+        )
+    }
+
+    fn request(index: u32, length: u32, pieces_root: [u8; 32]) -> HashRequest {
+        HashRequest {
+            pieces_root,
+            base_layer: 0,
+            index,
+            length,
+            proof_layers: 1,
+        }
+    }
+
+    #[test]
+    fn round_trips_messages() {
+        let hashes = Hashes {
+            request: request(2, 2, [9; 32]),
+            hashes: leaves(),
+        };
+        assert_eq!(Hashes::from_bytes(&hashes.as_bytes()), Some(hashes));
+        assert_eq!(HashRequest::from_bytes(&[0; 47]), None);
+    }
+
+    #[test]
+    fn verifies_hashes_against_the_root() {
+        let leaves = leaves();
+        let root = root(&leaves);
+
+        let uncle = hash_pair(&leaves[0], &leaves[1]);
+        let hashes = Hashes {
+            request: request(2, 2, root),
+            hashes: vec![leaves[2], leaves[3], uncle],
+        };
+        assert_eq!(hashes.verified(), Some(&leaves[2..4]));
+
+        let tampered = Hashes {
+            request: request(2, 2, root),
+            hashes: vec![leaves[3], leaves[2], uncle],
+        };
+        assert_eq!(tampered.verified(), None);
+    }
+}
@@ -0,0 +1,335 @@
+//! Transfer history that outlives resume files, for seeders following their ratio over months:
+//! how much each torrent uploaded and downloaded each day (UTC), and when it finished
+//! downloading. The history is a file of JSON lines, appended to as each run of a torrent ends
+//! and totalled when queried, so a crash costs at most the line being written.
+
+use std::{
+    collections::BTreeMap,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::log;
+
+/// A history file, shared by every torrent recording to it.
+#[derive(Debug, Clone)]
+pub struct History {
+    path: PathBuf,
+    // Keeps lines from torrents finishing at once from interleaving.
+    lock: Arc<Mutex<()>>,
+}
+
+/// A line of the history file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum HistoryEntry {
+    /// What one run of a torrent transferred, counted on the day the run ended.
+    Transfer {
+        /// Seconds since the Unix epoch.
+        timestamp: u64,
+        info_hash: String,
+        name: String,
+        uploaded: u64,
+        downloaded: u64,
+    },
+    /// The torrent finished downloading and verified.
+    Completed {
+        timestamp: u64,
+        info_hash: String,
+        name: String,
+    },
+}
+
+impl HistoryEntry {
+    fn timestamp(&self) -> u64 {
+        match self {
+            HistoryEntry::Transfer { timestamp, .. } | HistoryEntry::Completed { timestamp, .. } => {
+                *timestamp
+            }
+        }
+    }
+
+    fn info_hash(&self) -> &str {
+        match self {
+            HistoryEntry::Transfer { info_hash, .. } | HistoryEntry::Completed { info_hash, .. } => {
+                info_hash
+            }
+        }
+    }
+}
+
+/// What a torrent transferred on one day.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DailyTotal {
+    /// The day, as YYYY-MM-DD in UTC.
+    pub day: String,
+    pub info_hash: String,
+    pub name: String,
+    pub uploaded: u64,
+    pub downloaded: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Completion {
+    pub day: String,
+    pub timestamp: u64,
+    pub info_hash: String,
+    pub name: String,
+}
+
+/// Which entries a query covers. The default covers all of them.
+#[derive(Debug, Clone, Default)]
+pub struct HistoryFilter {
+    pub info_hash: Option<String>,
+    /// The first day to include, as YYYY-MM-DD.
+    pub since: Option<String>,
+}
+
+impl HistoryFilter {
+    fn matches(&self, entry: &HistoryEntry) -> bool {
+        self.info_hash
+            .as_ref()
+            .is_none_or(|info_hash| info_hash.eq_ignore_ascii_case(entry.info_hash()))
+            && self
+                .since
+                .as_ref()
+                .is_none_or(|since| day(entry.timestamp()) >= *since)
+    }
+}
+
+impl History {
+    /// The history kept at `path`, which is created when something is first recorded.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            lock: Arc::default(),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Records what a run of a torrent transferred. Runs that moved nothing are left out.
+    pub fn record_transfer(
+        &self,
+        info_hash: &str,
+        name: &str,
+        uploaded: u64,
+        downloaded: u64,
+    ) -> io::Result<()> {
+        if uploaded == 0 && downloaded == 0 {
+            return Ok(());
+        }
+        self.append(&HistoryEntry::Transfer {
+            timestamp: now(),
+            info_hash: info_hash.to_string(),
+            name: name.to_string(),
+            uploaded,
+            downloaded,
+        })
+    }
+
+    pub fn record_completed(&self, info_hash: &str, name: &str) -> io::Result<()> {
+        self.append(&HistoryEntry::Completed {
+            timestamp: now(),
+            info_hash: info_hash.to_string(),
+            name: name.to_string(),
+        })
+    }
+
+    fn append(&self, entry: &HistoryEntry) -> io::Result<()> {
+        let mut line = serde_json::to_string(entry).expect("Failed to serialize history entry");
+        line.push('\n');
+        let _lock = self.lock.lock().expect("History lock poisoned");
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?
+            .write_all(line.as_bytes())
+    }
+
+    /// Every entry, oldest first. A missing file is an empty history, and lines that cannot be
+    /// read, such as one cut short by a crash, are skipped.
+    pub fn entries(&self) -> io::Result<Vec<HistoryEntry>> {
+        let contents = match fs::read_to_string(&self.path) {
+            Ok(contents) => contents,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(error) => return Err(error),
+        };
+        Ok(contents
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .filter_map(|(number, line)| match serde_json::from_str(line) {
+                Ok(entry) => Some(entry),
+                Err(error) => {
+                    log::warn!("skipping line {} of {}: {}", number + 1, self.path.display(), error);
+                    None
+                }
+            })
+            .collect())
+    }
+
+    /// What each torrent transferred each day, by day and then info hash.
+    pub fn daily_totals(&self, filter: &HistoryFilter) -> io::Result<Vec<DailyTotal>> {
+        let mut totals = BTreeMap::<(String, String), DailyTotal>::new();
+        for entry in self.entries()? {
+            if !filter.matches(&entry) {
+                continue;
+            }
+            let HistoryEntry::Transfer {
+                timestamp,
+                info_hash,
+                name,
+                uploaded,
+                downloaded,
+            } = entry
+            else {
+                continue;
+            };
+            let day = day(timestamp);
+            let total = totals
+                .entry((day.clone(), info_hash.clone()))
+                .or_insert_with(|| DailyTotal {
+                    day,
+                    info_hash,
+                    name: String::new(),
+                    uploaded: 0,
+                    downloaded: 0,
+                });
+            // The latest name wins, should a torrent's name have changed.
+            total.name = name;
+            total.uploaded += uploaded;
+            total.downloaded += downloaded;
+        }
+        Ok(totals.into_values().collect())
+    }
+
+    /// When torrents finished downloading, oldest first.
+    pub fn completions(&self, filter: &HistoryFilter) -> io::Result<Vec<Completion>> {
+        Ok(self
+            .entries()?
+            .into_iter()
+            .filter(|entry| filter.matches(entry))
+            .filter_map(|entry| match entry {
+                HistoryEntry::Completed {
+                    timestamp,
+                    info_hash,
+                    name,
+                } => Some(Completion {
+                    day: day(timestamp),
+                    timestamp,
+                    info_hash,
+                    name,
+                }),
+                HistoryEntry::Transfer { .. } => None,
+            })
+            .collect())
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
+
+/// The UTC day `timestamp` falls on, as YYYY-MM-DD.
+pub fn day(timestamp: u64) -> String {
+    // Howard Hinnant's civil_from_days, on days since the Unix epoch.
+    let days = (timestamp / 86_400) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{day, History, HistoryEntry, HistoryFilter};
+
+    #[test]
+    fn days_are_utc_calendar_dates() {
+        assert_eq!(day(0), "1970-01-01");
+        assert_eq!(day(951_782_400), "2000-02-29");
+        assert_eq!(day(1_792_195_199), "2026-10-16");
+        assert_eq!(day(1_792_195_200), "2026-10-17");
+    }
+
+    #[test]
+    fn totals_transfers_by_day_and_torrent() {
+        let dir = tempfile::tempdir().unwrap();
+        let history = History::new(dir.path().join("history.jsonl"));
+        assert!(history.entries().unwrap().is_empty());
+
+        let transfer = |timestamp, info_hash: &str, uploaded, downloaded| {
+            history
+                .append(&HistoryEntry::Transfer {
+                    timestamp,
+                    info_hash: info_hash.to_string(),
+                    name: format!("torrent {}", info_hash),
+                    uploaded,
+                    downloaded,
+                })
+                .unwrap()
+        };
+        transfer(86_400, "aa", 10, 100);
+        transfer(86_400 + 3_600, "aa", 5, 0);
+        transfer(86_400 + 7_200, "bb", 1, 2);
+        transfer(2 * 86_400, "aa", 7, 0);
+        history.record_completed("aa", "torrent aa").unwrap();
+        // Cut short by a crash.
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(history.path())
+            .and_then(|mut file| std::io::Write::write_all(&mut file, b"{\"event\":\"tra"))
+            .unwrap();
+
+        let totals = history.daily_totals(&HistoryFilter::default()).unwrap();
+        let summary = totals
+            .iter()
+            .map(|total| {
+                (
+                    total.day.as_str(),
+                    total.info_hash.as_str(),
+                    total.uploaded,
+                    total.downloaded,
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            [
+                ("1970-01-02", "aa", 15, 100),
+                ("1970-01-02", "bb", 1, 2),
+                ("1970-01-03", "aa", 7, 0),
+            ]
+        );
+
+        let filter = HistoryFilter {
+            info_hash: Some("AA".to_string()),
+            since: Some("1970-01-03".to_string()),
+        };
+        assert_eq!(history.daily_totals(&filter).unwrap().len(), 1);
+        let completions = history.completions(&filter).unwrap();
+        assert_eq!(completions.len(), 1);
+        assert_eq!(completions[0].name, "torrent aa");
+    }
+}
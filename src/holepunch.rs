@@ -0,0 +1,159 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+/// A `ut_holepunch` message (BEP 55). A peer behind a NAT sends `Rendezvous` to a relay it
+/// shares with the target, and the relay sends `Connect` to both sides so they can dial each
+/// other at the same time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HolepunchMessage {
+    pub kind: HolepunchKind,
+    pub addr: SocketAddr,
+}
+
+impl HolepunchMessage {
+    pub fn rendezvous(addr: SocketAddr) -> Self {
+        Self {
+            kind: HolepunchKind::Rendezvous,
+            addr,
+        }
+    }
+
+    pub fn error(addr: SocketAddr, error: HolepunchError) -> Self {
+        Self {
+            kind: HolepunchKind::Error(error),
+            addr,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let (msg_type, err_code) = match self.kind {
+            HolepunchKind::Rendezvous => (0x00, 0),
+            HolepunchKind::Connect => (0x01, 0),
+            HolepunchKind::Error(error) => (0x02, error.code()),
+        };
+
+        let mut bytes = vec![msg_type];
+        match self.addr.ip() {
+            IpAddr::V4(ip) => {
+                bytes.push(0x00);
+                bytes.extend(ip.octets());
+            }
+            IpAddr::V6(ip) => {
+                bytes.push(0x01);
+                bytes.extend(ip.octets());
+            }
+        }
+        bytes.extend(self.addr.port().to_be_bytes());
+        bytes.extend(err_code.to_be_bytes());
+        bytes
+    }
+
+    /// Parses a message payload, returning `None` for unknown types or truncated payloads.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let (&msg_type, rest) = bytes.split_first()?;
+        let (&addr_type, rest) = rest.split_first()?;
+
+        let (ip, rest): (IpAddr, _) = match addr_type {
+            0x00 if rest.len() >= 4 => {
+                let octets: [u8; 4] = rest[..4].try_into().unwrap();
+                (Ipv4Addr::from(octets).into(), &rest[4..])
+            }
+            0x01 if rest.len() >= 16 => {
+                let octets: [u8; 16] = rest[..16].try_into().unwrap();
+                (Ipv6Addr::from(octets).into(), &rest[16..])
+            }
+            _ => return None,
+        };
+        if rest.len() < 6 {
+            return None;
+        }
+        let port = u16::from_be_bytes([rest[0], rest[1]]);
+        let err_code = u32::from_be_bytes(rest[2..6].try_into().unwrap());
+
+        let kind = match msg_type {
+            0x00 => HolepunchKind::Rendezvous,
+            0x01 => HolepunchKind::Connect,
+            0x02 => HolepunchKind::Error(HolepunchError::from_code(err_code)?),
+            _ => return None,
+        };
+
+        Some(Self {
+            kind,
+            addr: SocketAddr::new(ip, port),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HolepunchKind {
+    Rendezvous,
+    Connect,
+    Error(HolepunchError),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum HolepunchError {
+    #[error("the relay does not know the target peer")]
+    NoSuchPeer,
+    #[error("the relay is not connected to the target peer")]
+    NotConnected,
+    #[error("the target peer does not support holepunching")]
+    NoSupport,
+    #[error("cannot holepunch to ourselves")]
+    NoSelf,
+}
+
+impl HolepunchError {
+    fn code(&self) -> u32 {
+        match self {
+            Self::NoSuchPeer => 1,
+            Self::NotConnected => 2,
+            Self::NoSupport => 3,
+            Self::NoSelf => 4,
+        }
+    }
+
+    fn from_code(code: u32) -> Option<Self> {
+        match code {
+            1 => Some(Self::NoSuchPeer),
+            2 => Some(Self::NotConnected),
+            3 => Some(Self::NoSupport),
+            4 => Some(Self::NoSelf),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::{HolepunchError, HolepunchKind, HolepunchMessage};
+
+    #[test]
+    fn round_trips_ipv4_and_ipv6() {
+        let v4: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::1]:51413".parse().unwrap();
+
+        for message in [
+            HolepunchMessage::rendezvous(v4),
+            HolepunchMessage {
+                kind: HolepunchKind::Connect,
+                addr: v6,
+            },
+            HolepunchMessage::error(v4, HolepunchError::NotConnected),
+        ] {
+            assert_eq!(
+                HolepunchMessage::from_bytes(&message.as_bytes()),
+                Some(message)
+            );
+        }
+    }
+
+    #[test]
+    fn encodes_the_bep_55_layout() {
+        let message =
+            HolepunchMessage::error("1.2.3.4:258".parse().unwrap(), HolepunchError::NoSupport);
+        assert_eq!(message.as_bytes(), vec![2, 0, 1, 2, 3, 4, 1, 2, 0, 0, 0, 3]);
+        assert_eq!(HolepunchMessage::from_bytes(&[1, 0, 1, 2, 3]), None);
+    }
+}
@@ -0,0 +1,84 @@
+//! Runs a command of the user's when a download finishes, so it can be moved, unpacked or
+//! indexed without polling for the output.
+
+use std::{
+    io,
+    path::Path,
+    process::{Command, ExitStatus, Stdio},
+};
+
+use crate::log;
+
+/// The download that finished, given to the command as `BT_NAME`, `BT_PATH`, `BT_INFO_HASH`
+/// and `BT_BYTES`.
+#[derive(Debug, Clone, Copy)]
+pub struct Completion<'a> {
+    pub name: &'a str,
+    /// Where the finished file, or a multi-file torrent's directory, now is.
+    pub path: &'a Path,
+    pub info_hash: &'a str,
+    pub bytes: u64,
+}
+
+/// Runs `command` through the shell for `completion`, waiting for it to exit. Its output goes
+/// to stderr, as stdout may be carrying our own results.
+pub fn run(command: &str, completion: Completion) -> io::Result<ExitStatus> {
+    let mut shell = if cfg!(windows) {
+        let mut shell = Command::new("cmd");
+        shell.arg("/C");
+        shell
+    } else {
+        let mut shell = Command::new("sh");
+        shell.arg("-c");
+        shell
+    };
+    shell
+        .arg(command)
+        .env("BT_NAME", completion.name)
+        .env("BT_PATH", completion.path)
+        .env("BT_INFO_HASH", completion.info_hash)
+        .env("BT_BYTES", completion.bytes.to_string())
+        .stdin(Stdio::null())
+        .stdout(io::stderr())
+        .status()
+}
+
+/// Runs `command` for `completion`, logging rather than returning a failure, since the download
+/// it follows has succeeded either way.
+pub fn on_complete(command: &str, completion: Completion) {
+    log::info!(torrent = completion.name; "running {}", command);
+    match run(command, completion) {
+        Ok(status) if status.success() => {}
+        Ok(status) => log::warn!(torrent = completion.name; "{} exited with {}", command, status),
+        Err(error) => log::warn!(torrent = completion.name; "cannot run {}: {}", command, error),
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::{fs, path::Path};
+
+    use super::{run, Completion};
+
+    #[test]
+    fn describes_the_download_in_the_environment() {
+        let dir = tempfile::tempdir().unwrap();
+        let report = dir.path().join("report");
+        let command = format!(
+            "echo \"$BT_NAME $BT_PATH $BT_INFO_HASH $BT_BYTES\" > {}",
+            report.display()
+        );
+        let completion = Completion {
+            name: "album",
+            path: Path::new("/downloads/album"),
+            info_hash: "abcd",
+            bytes: 1234,
+        };
+        assert!(run(&command, completion).unwrap().success());
+        assert_eq!(
+            fs::read_to_string(&report).unwrap(),
+            "album /downloads/album abcd 1234\n"
+        );
+        assert!(!run("exit 3", completion).unwrap().success());
+    }
+}
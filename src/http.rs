@@ -0,0 +1,188 @@
+//! The HTTP requests the engine makes, to trackers and web seeds, go through [`HttpFetch`].
+//! [`ReqwestFetch`] is used unless an embedder supplies their own stack with [`set_fetcher`] or
+//! [`ClientBuilder::http`](crate::ClientBuilder::http), and is the only code that uses reqwest
+//! to make requests.
+
+#[cfg(not(target_arch = "wasm32"))]
+use std::sync::{Arc, OnceLock, RwLock};
+use std::{error::Error, time::Duration};
+
+/// Something that can make a GET request. Answers are returned whatever their status; callers
+/// decide which they accept.
+pub trait HttpFetch: Send + Sync {
+    fn get(&self, request: &HttpRequest) -> Result<HttpResponse, HttpError>;
+}
+
+/// A GET request for `url`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpRequest {
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    /// How long to wait for the whole answer, or as long as the fetcher waits by default.
+    pub timeout: Option<Duration>,
+}
+
+impl HttpRequest {
+    pub fn get(url: impl Into<String>) -> Self {
+        Self {
+            url: url.into(),
+            headers: Vec::new(),
+            timeout: None,
+        }
+    }
+
+    pub fn header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpResponse {
+    pub status: u16,
+    pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+    /// The response, if its status is a success.
+    pub fn error_for_status(self) -> Result<Self, HttpError> {
+        if (200..300).contains(&self.status) {
+            Ok(self)
+        } else {
+            Err(HttpError::Status(self.status))
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum HttpError {
+    /// The request got no answer, for whatever reason the fetcher has.
+    #[error("{0}")]
+    Transport(Box<dyn Error + Send + Sync>),
+    #[error("server answered with status {0}")]
+    Status(u16),
+}
+
+impl HttpError {
+    pub fn transport(error: impl Into<Box<dyn Error + Send + Sync>>) -> Self {
+        Self::Transport(error.into())
+    }
+}
+
+/// Fetches with a blocking reqwest client.
+#[cfg(not(target_arch = "wasm32"))]
+#[derive(Debug, Default)]
+pub struct ReqwestFetch {
+    client: reqwest::blocking::Client,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl ReqwestFetch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl HttpFetch for ReqwestFetch {
+    fn get(&self, request: &HttpRequest) -> Result<HttpResponse, HttpError> {
+        let mut builder = self.client.get(&request.url);
+        for (name, value) in &request.headers {
+            builder = builder.header(name, value);
+        }
+        if let Some(timeout) = request.timeout {
+            builder = builder.timeout(timeout);
+        }
+
+        // The URL repeats the tracker and every parameter, which drowns out what went wrong.
+        let response = builder
+            .send()
+            .map_err(|error| HttpError::transport(error.without_url()))?;
+        let status = response.status().as_u16();
+        let body = response
+            .bytes()
+            .map_err(|error| HttpError::transport(error.without_url()))?;
+        Ok(HttpResponse {
+            status,
+            body: body.to_vec(),
+        })
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+static FETCHER: RwLock<Option<Arc<dyn HttpFetch>>> = RwLock::new(None);
+#[cfg(not(target_arch = "wasm32"))]
+static DEFAULT: OnceLock<Arc<dyn HttpFetch>> = OnceLock::new();
+
+/// Makes every later request through `fetcher`, for the whole process.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn set_fetcher(fetcher: Arc<dyn HttpFetch>) {
+    *FETCHER.write().expect("Fetcher lock poisoned") = Some(fetcher);
+}
+
+/// The fetcher requests go through: the one last set, or else a [`ReqwestFetch`].
+#[cfg(not(target_arch = "wasm32"))]
+pub fn fetcher() -> Arc<dyn HttpFetch> {
+    if let Some(fetcher) = &*FETCHER.read().expect("Fetcher lock poisoned") {
+        return fetcher.clone();
+    }
+    DEFAULT
+        .get_or_init(|| Arc::new(ReqwestFetch::new()))
+        .clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader, Write},
+        net::TcpListener,
+        thread,
+    };
+
+    use super::{HttpError, HttpFetch, HttpRequest, ReqwestFetch};
+
+    #[test]
+    fn reqwest_sends_headers_and_returns_any_status() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            let (socket, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(socket);
+            let mut greeting = String::new();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let line = line.trim_end().to_ascii_lowercase();
+                if line.is_empty() {
+                    break;
+                }
+                if let Some(value) = line.strip_prefix("x-greeting: ") {
+                    greeting = value.to_string();
+                }
+            }
+            let mut socket = reader.into_inner();
+            write!(
+                socket,
+                "HTTP/1.1 404 Not Found\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                greeting.len(),
+                greeting
+            )
+            .unwrap();
+        });
+
+        let request =
+            HttpRequest::get(format!("http://127.0.0.1:{}/", port)).header("X-Greeting", "hello");
+        let response = ReqwestFetch::new().get(&request).unwrap();
+        assert_eq!(response.status, 404);
+        assert_eq!(response.body, b"hello");
+        assert!(matches!(
+            response.error_for_status(),
+            Err(HttpError::Status(404))
+        ));
+    }
+}
@@ -0,0 +1,173 @@
+//! What the engine's small HTTP servers, the JSON-RPC API and the local tracker, share. Each
+//! connection is answered on a task of its own, up to a limit on how many are open at once.
+//! Sockets time out, so a client that stops sending or reading is let go, and request lines
+//! are read up to a maximum length, so no client can make us buffer without end.
+
+use std::{
+    io::{self, BufRead, Read},
+    net::{TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use crate::{executor, log};
+
+/// Connections answered at once; any more are closed straight away.
+pub const MAX_CONNECTIONS: usize = 64;
+/// How long a read or write may wait on the client.
+pub const IO_TIMEOUT: Duration = Duration::from_secs(10);
+/// The longest request or header line we read.
+pub const MAX_LINE_LENGTH: usize = 8 * 1024;
+/// The most header lines a request may have.
+pub const MAX_HEADERS: usize = 100;
+
+/// The request line and headers of a request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestHead {
+    pub method: String,
+    /// The path and query asked for.
+    pub target: String,
+    pub headers: Vec<(String, String)>,
+}
+
+impl RequestHead {
+    /// The value of the header `name`, matched case-insensitively.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Accepts connections on `listener` from a task of its own, answering each with `handle` on
+/// another. `name` says which server it is, in task names and logs.
+pub fn serve<F>(listener: TcpListener, name: &'static str, handle: F)
+where
+    F: Fn(TcpStream) -> io::Result<()> + Send + Sync + 'static,
+{
+    let handle = Arc::new(handle);
+    let open = Arc::new(AtomicUsize::new(0));
+    executor::spawn(&format!("{} server", name), move || {
+        for stream in listener.incoming().flatten() {
+            if open.load(Ordering::SeqCst) >= MAX_CONNECTIONS {
+                log::debug!("{} server is at its connection limit, dropping a connection", name);
+                continue;
+            }
+            let connection = Connection::open(&open);
+            let handle = handle.clone();
+            executor::spawn(&format!("{} connection", name), move || {
+                let _connection = connection;
+                let result = stream
+                    .set_read_timeout(Some(IO_TIMEOUT))
+                    .and_then(|()| stream.set_write_timeout(Some(IO_TIMEOUT)))
+                    .and_then(|()| handle(stream));
+                if let Err(error) = result {
+                    log::debug!("dropped {} connection: {}", name, error);
+                }
+            });
+        }
+    });
+}
+
+/// Counts a connection as open until dropped, even if answering it panics.
+struct Connection(Arc<AtomicUsize>);
+
+impl Connection {
+    fn open(count: &Arc<AtomicUsize>) -> Self {
+        count.fetch_add(1, Ordering::SeqCst);
+        Self(count.clone())
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Reads a request's line and headers, up to the blank line before its body.
+pub fn read_head<R: BufRead>(reader: &mut R) -> io::Result<RequestHead> {
+    let mut line = String::new();
+    read_line(reader, &mut line)?;
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "bad request line",
+        ));
+    };
+    let (method, target) = (method.to_string(), target.to_string());
+
+    let mut headers = Vec::new();
+    loop {
+        line.clear();
+        if read_line(reader, &mut line)? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if headers.len() == MAX_HEADERS {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "too many headers",
+            ));
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            headers.push((name.to_string(), value.trim().to_string()));
+        }
+    }
+
+    Ok(RequestHead {
+        method,
+        target,
+        headers,
+    })
+}
+
+/// Reads a line into `line` like `BufRead::read_line`, failing once it runs past
+/// `MAX_LINE_LENGTH` instead of reading on to its end.
+fn read_line<R: BufRead>(reader: &mut R, line: &mut String) -> io::Result<usize> {
+    let read = reader
+        .by_ref()
+        .take(MAX_LINE_LENGTH as u64)
+        .read_line(line)?;
+    if read == MAX_LINE_LENGTH && !line.ends_with('\n') {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "line too long"));
+    }
+    Ok(read)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufReader, ErrorKind};
+
+    use super::{read_head, MAX_LINE_LENGTH};
+
+    #[test]
+    fn reads_the_request_line_and_headers() {
+        let request = b"POST /rpc HTTP/1.1\r\nContent-Length: 2\r\nX-Empty:\r\n\r\n{}";
+        let head = read_head(&mut BufReader::new(&request[..])).unwrap();
+        assert_eq!(head.method, "POST");
+        assert_eq!(head.target, "/rpc");
+        assert_eq!(head.header("content-length"), Some("2"));
+        assert_eq!(head.header("x-empty"), Some(""));
+        assert_eq!(head.header("authorization"), None);
+    }
+
+    #[test]
+    fn refuses_overlong_lines() {
+        let request = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(MAX_LINE_LENGTH));
+        let error = read_head(&mut BufReader::new(request.as_bytes())).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+
+        let request = format!("GET / HTTP/1.1\r\n{}\r\n\r\n", "X-Header: value\r\n".repeat(101));
+        let error = read_head(&mut BufReader::new(request.as_bytes())).unwrap_err();
+        assert_eq!(error.to_string(), "too many headers");
+    }
+}
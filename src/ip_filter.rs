@@ -0,0 +1,193 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::Path,
+    str::FromStr,
+};
+
+// eMule filters block ranges with an access level at or below this.
+const EMULE_BLOCK_LEVEL: u32 = 127;
+
+/// Addresses we refuse to talk to, loaded from a list of CIDR ranges, single addresses or eMule
+/// `ipfilter.dat` lines. Blank lines and lines starting with `#` or `//` are ignored.
+#[derive(Debug, Clone, Default)]
+pub struct IpFilter {
+    ranges: Vec<IpRange>,
+}
+
+impl IpFilter {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path)
+            .map_err(|error| format!("Failed to read {}: {}", path.display(), error))?;
+        contents.parse()
+    }
+
+    pub fn is_blocked(&self, ip: IpAddr) -> bool {
+        self.ranges.iter().any(|range| range.contains(ip))
+    }
+
+    pub fn len(&self) -> usize {
+        self.ranges.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+}
+
+impl FromStr for IpFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut ranges = Vec::new();
+        for (number, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with("//") {
+                continue;
+            }
+
+            let range =
+                parse_line(line).map_err(|error| format!("line {}: {}", number + 1, error))?;
+            ranges.extend(range);
+        }
+
+        Ok(Self { ranges })
+    }
+}
+
+/// An inclusive range of addresses from a single family.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct IpRange {
+    start: IpAddr,
+    end: IpAddr,
+}
+
+impl IpRange {
+    fn contains(&self, ip: IpAddr) -> bool {
+        match (self.start, self.end, ip) {
+            (IpAddr::V4(start), IpAddr::V4(end), IpAddr::V4(ip)) => start <= ip && ip <= end,
+            (IpAddr::V6(start), IpAddr::V6(end), IpAddr::V6(ip)) => start <= ip && ip <= end,
+            (IpAddr::V4(_), IpAddr::V4(_), IpAddr::V6(ip)) => match ip.to_ipv4_mapped() {
+                Some(ip) => self.contains(ip.into()),
+                None => false,
+            },
+            _ => false,
+        }
+    }
+}
+
+/// Parses one filter line, returning `None` for eMule ranges whose access level allows them.
+fn parse_line(line: &str) -> Result<Option<IpRange>, String> {
+    if let Some((range, rest)) = line.split_once(',') {
+        let level = rest
+            .split(',')
+            .next()
+            .map(str::trim)
+            .and_then(|level| level.parse::<u32>().ok())
+            .ok_or_else(|| format!("Invalid access level in {}", line))?;
+        let (start, end) = range
+            .split_once('-')
+            .ok_or_else(|| format!("Expected <start> - <end>, got {}", range))?;
+        let range = IpRange {
+            start: parse_ip(start.trim())?,
+            end: parse_ip(end.trim())?,
+        };
+
+        return Ok((level <= EMULE_BLOCK_LEVEL).then_some(range));
+    }
+
+    if let Some((ip, prefix)) = line.split_once('/') {
+        let ip = parse_ip(ip)?;
+        let prefix = prefix
+            .parse::<u32>()
+            .map_err(|_| format!("Invalid prefix length: {}", prefix))?;
+        return cidr(ip, prefix).map(Some);
+    }
+
+    let ip = parse_ip(line)?;
+    Ok(Some(IpRange { start: ip, end: ip }))
+}
+
+fn cidr(ip: IpAddr, prefix: u32) -> Result<IpRange, String> {
+    match ip {
+        IpAddr::V4(ip) if prefix <= 32 => {
+            let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+            let start = u32::from(ip) & mask;
+            Ok(IpRange {
+                start: Ipv4Addr::from(start).into(),
+                end: Ipv4Addr::from(start | !mask).into(),
+            })
+        }
+        IpAddr::V6(ip) if prefix <= 128 => {
+            let mask = u128::MAX.checked_shl(128 - prefix).unwrap_or(0);
+            let start = u128::from(ip) & mask;
+            Ok(IpRange {
+                start: Ipv6Addr::from(start).into(),
+                end: Ipv6Addr::from(start | !mask).into(),
+            })
+        }
+        _ => Err(format!("Invalid prefix length /{} for {}", prefix, ip)),
+    }
+}
+
+/// Parses an address, accepting the zero-padded octets eMule filters use (`001.002.003.004`).
+fn parse_ip(ip: &str) -> Result<IpAddr, String> {
+    if let Ok(ip) = ip.parse() {
+        return Ok(ip);
+    }
+
+    let octets = ip
+        .split('.')
+        .map(|octet| octet.parse::<u8>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| format!("Invalid IP address: {}", ip))?;
+    let octets: [u8; 4] = octets
+        .try_into()
+        .map_err(|_| format!("Invalid IP address: {}", ip))?;
+    Ok(Ipv4Addr::from(octets).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use super::IpFilter;
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn cidr_ranges_and_single_addresses() {
+        let filter: IpFilter = "# comment\n10.0.0.0/8\n192.168.1.7\n2001:db8::/32\n"
+            .parse()
+            .unwrap();
+
+        assert!(filter.is_blocked(ip("10.255.0.1")));
+        assert!(filter.is_blocked(ip("192.168.1.7")));
+        assert!(!filter.is_blocked(ip("192.168.1.8")));
+        assert!(filter.is_blocked(ip("2001:db8::1")));
+        assert!(filter.is_blocked(ip("::ffff:10.0.0.1")));
+        assert!(!filter.is_blocked(ip("11.0.0.1")));
+    }
+
+    #[test]
+    fn emule_dat_lines() {
+        let filter: IpFilter = "001.009.096.000 - 001.009.096.255 , 000 , Bad Org\n\
+                                002.000.000.000 - 002.255.255.255 , 200 , Allowed\n"
+            .parse()
+            .unwrap();
+
+        assert_eq!(filter.len(), 1);
+        assert!(filter.is_blocked(ip("1.9.96.105")));
+        assert!(!filter.is_blocked(ip("2.1.1.1")));
+    }
+
+    #[test]
+    fn reports_bad_lines() {
+        let error = "10.0.0.0/8\nnot an address\n"
+            .parse::<IpFilter>()
+            .unwrap_err();
+        assert!(error.starts_with("line 2:"));
+    }
+}
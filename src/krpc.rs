@@ -0,0 +1,300 @@
+//! Single KRPC queries (BEP 5) to DHT nodes over UDP, for debugging how a node answers. There
+//! is no routing table here: each query goes to one node and its answer is decoded as it is.
+
+use std::{
+    collections::HashMap,
+    io,
+    net::{Ipv4Addr, SocketAddr, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use sha1::{Digest, Sha1};
+
+use crate::bencode::{Bencode, Value};
+
+/// A well-known node to ask when no other is given.
+pub const BOOTSTRAP_NODE: &str = "router.bittorrent.com:6881";
+
+// Plenty for any KRPC message, which has to fit in a single datagram.
+const MAX_DATAGRAM: usize = 65536;
+
+/// A query a DHT node understands, with its arguments.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Query {
+    Ping,
+    /// Ask for the nodes closest to an id.
+    FindNode([u8; 20]),
+    /// Ask for peers of an info hash, or failing that the nodes closest to it.
+    GetPeers([u8; 20]),
+}
+
+impl Query {
+    fn name(&self) -> &'static str {
+        match self {
+            Query::Ping => "ping",
+            Query::FindNode(_) => "find_node",
+            Query::GetPeers(_) => "get_peers",
+        }
+    }
+
+    fn encode(&self, transaction: &[u8], id: &[u8; 20]) -> Vec<u8> {
+        let mut arguments = HashMap::from([("id".to_string(), Value::Blob(id.to_vec()))]);
+        match self {
+            Query::Ping => {}
+            Query::FindNode(target) => {
+                arguments.insert("target".to_string(), Value::Blob(target.to_vec()));
+            }
+            Query::GetPeers(info_hash) => {
+                arguments.insert("info_hash".to_string(), Value::Blob(info_hash.to_vec()));
+            }
+        }
+        Bencode::encode(&Value::Dictionary(HashMap::from([
+            ("t".to_string(), Value::Blob(transaction.to_vec())),
+            ("y".to_string(), Value::String("q".to_string())),
+            ("q".to_string(), Value::String(self.name().to_string())),
+            ("a".to_string(), Value::Dictionary(arguments)),
+        ])))
+    }
+}
+
+/// What a node answered, with its compact node and peer lists decoded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Response {
+    pub id: [u8; 20],
+    /// Nodes closer to the target, each with its id.
+    pub nodes: Vec<([u8; 20], SocketAddr)>,
+    /// Peers of the info hash, from `get_peers`.
+    pub peers: Vec<SocketAddr>,
+    /// The token to present when announcing to the node, from `get_peers`.
+    pub token: Option<Vec<u8>>,
+    pub elapsed: Duration,
+}
+
+/// The id we query with. Fixed, since we never join the DHT and nobody routes to us.
+pub fn node_id() -> [u8; 20] {
+    Sha1::digest(b"codecrafters-bittorrent-rust").into()
+}
+
+/// Sends `query` to the node at `addr` and waits up to `timeout` for its answer.
+pub fn query(addr: SocketAddr, query: Query, timeout: Duration) -> Result<Response, KrpcError> {
+    let bind: SocketAddr = if addr.is_ipv4() {
+        (Ipv4Addr::UNSPECIFIED, 0).into()
+    } else {
+        "[::]:0".parse().expect("The IPv6 wildcard address parses")
+    };
+    let socket = UdpSocket::bind(bind)?;
+    let transaction = *b"cc";
+    let started = Instant::now();
+    socket.send_to(&query.encode(&transaction, &node_id()), addr)?;
+
+    let mut buffer = vec![0; MAX_DATAGRAM];
+    loop {
+        let left = timeout.saturating_sub(started.elapsed());
+        if left.is_zero() {
+            return Err(KrpcError::Timeout);
+        }
+        socket.set_read_timeout(Some(left))?;
+        let (length, from) = match socket.recv_from(&mut buffer) {
+            Ok(received) => received,
+            Err(error)
+                if matches!(
+                    error.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                return Err(KrpcError::Timeout)
+            }
+            Err(error) => return Err(error.into()),
+        };
+        // Anything else arriving on the port is not our answer.
+        let Ok(Value::Dictionary(message)) = Bencode::new(&buffer[..length]).decode() else {
+            continue;
+        };
+        if from != addr || bytes(&message, "t") != Some(&transaction[..]) {
+            continue;
+        }
+        return parse_response(&message, started.elapsed());
+    }
+}
+
+fn parse_response(
+    message: &HashMap<String, Value>,
+    elapsed: Duration,
+) -> Result<Response, KrpcError> {
+    match bytes(message, "y") {
+        Some(b"r") => {}
+        Some(b"e") => {
+            return Err(match message.get("e") {
+                Some(Value::List(error)) => match error.as_slice() {
+                    [Value::Number(code), Value::String(text)] => {
+                        KrpcError::Error(*code, text.clone())
+                    }
+                    _ => KrpcError::Malformed,
+                },
+                _ => KrpcError::Malformed,
+            })
+        }
+        _ => return Err(KrpcError::Malformed),
+    }
+    let Some(Value::Dictionary(response)) = message.get("r") else {
+        return Err(KrpcError::Malformed);
+    };
+    let id = bytes(response, "id")
+        .and_then(|id| id.try_into().ok())
+        .ok_or(KrpcError::Malformed)?;
+
+    let nodes = bytes(response, "nodes")
+        .unwrap_or_default()
+        .chunks_exact(26)
+        .map(|node| {
+            let id = node[..20].try_into().expect("Chunks are 26 bytes");
+            (id, compact_addr(&node[20..]))
+        })
+        .collect();
+    let peers = match response.get("values") {
+        Some(Value::List(values)) => values
+            .iter()
+            .filter_map(|value| match value {
+                Value::Blob(peer) if peer.len() == 6 => Some(compact_addr(peer)),
+                Value::String(peer) if peer.len() == 6 => Some(compact_addr(peer.as_bytes())),
+                _ => None,
+            })
+            .collect(),
+        _ => Vec::new(),
+    };
+    Ok(Response {
+        id,
+        nodes,
+        peers,
+        token: bytes(response, "token").map(<[u8]>::to_vec),
+        elapsed,
+    })
+}
+
+// Binary strings that happen to be valid UTF-8 decode as strings.
+fn bytes<'a>(dict: &'a HashMap<String, Value>, key: &str) -> Option<&'a [u8]> {
+    match dict.get(key) {
+        Some(Value::Blob(blob)) => Some(blob),
+        Some(Value::String(string)) => Some(string.as_bytes()),
+        _ => None,
+    }
+}
+
+fn compact_addr(bytes: &[u8]) -> SocketAddr {
+    let ip = Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]);
+    SocketAddr::from((ip, u16::from_be_bytes([bytes[4], bytes[5]])))
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum KrpcError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("no answer from the node")]
+    Timeout,
+    #[error("node answered with error {0}: {1}")]
+    Error(i64, String),
+    #[error("malformed answer from the node")]
+    Malformed,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, net::UdpSocket, thread, time::Duration};
+
+    use super::{query, KrpcError, Query};
+    use crate::bencode::{Bencode, Value};
+
+    /// A node answering one query with `reply`, after checking what was asked.
+    fn node(reply: impl FnOnce(HashMap<String, Value>) -> Value + Send + 'static) -> UdpSocket {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server = socket.try_clone().unwrap();
+        thread::spawn(move || {
+            let mut buffer = [0; 1500];
+            let (length, from) = server.recv_from(&mut buffer).unwrap();
+            let Ok(Value::Dictionary(message)) = Bencode::new(&buffer[..length]).decode() else {
+                panic!("query is not a dictionary");
+            };
+            let transaction = bytes_of(&message["t"]);
+            let mut response = match reply(message) {
+                Value::Dictionary(response) => response,
+                _ => unreachable!(),
+            };
+            response.insert("t".to_string(), Value::Blob(transaction));
+            let encoded = Bencode::encode(&Value::Dictionary(response));
+            server.send_to(&encoded, from).unwrap();
+        });
+        socket
+    }
+
+    fn bytes_of(value: &Value) -> Vec<u8> {
+        match value {
+            Value::Blob(blob) => blob.clone(),
+            Value::String(string) => string.as_bytes().to_vec(),
+            _ => panic!("not a string"),
+        }
+    }
+
+    #[test]
+    fn decodes_peers_and_nodes() {
+        let socket = node(|message| {
+            assert_eq!(message["q"], Value::String("get_peers".to_string()));
+            let Value::Dictionary(arguments) = &message["a"] else {
+                panic!("no arguments");
+            };
+            assert_eq!(bytes_of(&arguments["info_hash"]), vec![7; 20]);
+
+            let mut nodes = vec![1; 20];
+            nodes.extend([10, 0, 0, 1, 0x1a, 0xe1]);
+            let response = HashMap::from([
+                ("id".to_string(), Value::Blob(vec![9; 20])),
+                ("nodes".to_string(), Value::Blob(nodes)),
+                (
+                    "values".to_string(),
+                    Value::List(vec![Value::Blob(vec![10, 0, 0, 2, 0, 80])]),
+                ),
+                ("token".to_string(), Value::String("tk".to_string())),
+            ]);
+            Value::Dictionary(HashMap::from([
+                ("y".to_string(), Value::String("r".to_string())),
+                ("r".to_string(), Value::Dictionary(response)),
+            ]))
+        });
+
+        let addr = socket.local_addr().unwrap();
+        let response = query(addr, Query::GetPeers([7; 20]), Duration::from_secs(5)).unwrap();
+        assert_eq!(response.id, [9; 20]);
+        assert_eq!(
+            response.nodes,
+            vec![([1; 20], "10.0.0.1:6881".parse().unwrap())]
+        );
+        assert_eq!(response.peers, vec!["10.0.0.2:80".parse().unwrap()]);
+        assert_eq!(response.token, Some(b"tk".to_vec()));
+    }
+
+    #[test]
+    fn reports_errors_and_silence() {
+        let socket = node(|_| {
+            Value::Dictionary(HashMap::from([
+                ("y".to_string(), Value::String("e".to_string())),
+                (
+                    "e".to_string(),
+                    Value::List(vec![
+                        Value::Number(204),
+                        Value::String("Method Unknown".to_string()),
+                    ]),
+                ),
+            ]))
+        });
+        let addr = socket.local_addr().unwrap();
+        let error = query(addr, Query::Ping, Duration::from_secs(5)).unwrap_err();
+        assert!(matches!(error, KrpcError::Error(204, _)));
+
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let error = query(
+            silent.local_addr().unwrap(),
+            Query::Ping,
+            Duration::from_millis(50),
+        );
+        assert!(matches!(error, Err(KrpcError::Timeout)));
+    }
+}
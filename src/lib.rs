@@ -0,0 +1,149 @@
+//! A BitTorrent client engine: bencode, torrent files and magnet links, trackers and the DHT,
+//! the peer wire protocol, and the coordinator that downloads, verifies and seeds a torrent
+//! through them. The `bittorrent-starter-rust` binary is a command line over this crate.
+//!
+//! The types most embedders start from are re-exported here:
+//!
+//! - [`Bencode`] and [`Value`] to decode and encode bencoded data.
+//! - [`Torrent`], read from a `.torrent` file, or [`Magnet`], parsed from a link.
+//! - [`PeerConnection`] for talking to a single peer, in the messages of [`wire`].
+//! - [`DownloadCoordinator`] to download a whole torrent into a [`Storage`].
+//! - [`Session`] to download and seed many torrents at once, set up through
+//!   [`Client::builder`], and [`Daemon`] to serve one to remote clients.
+//! - [`EventBus`] to follow either through [`TorrentEvent`]s as they happen.
+//!
+//! ```
+//! use bittorrent_starter_rust::{Bencode, Value};
+//!
+//! let value = Bencode::new(b"l4:spami42ee").decode()?;
+//! assert_eq!(
+//!     value,
+//!     Value::List(vec![Value::String("spam".to_string()), Value::Number(42)])
+//! );
+//! # Ok::<(), bittorrent_starter_rust::BencodeError>(())
+//! ```
+//!
+//! Each module fails with its own error type, such as [`TorrentError`] or
+//! [`peer::PeerError`], and every one of them converts into [`Error`] for callers happy to
+//! treat them alike.
+//!
+//! Requests to trackers and web seeds go through [`http::HttpFetch`], made with reqwest unless
+//! another HTTP stack is plugged in with [`http::set_fetcher`]. Background work, such as each
+//! torrent of a session and the verification pool, is blocking code run on a thread each,
+//! or on a tokio runtime's blocking pool with [`executor::Tokio`]; no async runtime is needed
+//! otherwise.
+//!
+//! Programs not written in Rust can embed the engine through the C API in [`ffi`], declared in
+//! `include/bittorrent.h`.
+//!
+//! Progress and problems are reported through [`log`], which writes to stderr once
+//! [`log::init`] has been called and stays silent otherwise.
+//!
+//! On wasm32 only the parsing layer is built: [`bencode`], [`torrent`], [`magnet`] and the
+//! peer message codec in [`wire`], for inspecting torrents in a browser. Such a build also needs
+//! reqwest and tokio moved to native-only dependencies in the manifest.
+
+/// Declares items that need sockets, threads or the file system, none of which a wasm32 build
+/// has. What is left there is the parsing layer: bencode, torrent files, magnet links and the
+/// peer message codec.
+macro_rules! native {
+    ($($item:item)*) => {
+        $(
+            #[cfg(not(target_arch = "wasm32"))]
+            $item
+        )*
+    };
+}
+
+pub mod bencode;
+pub mod error;
+pub mod http;
+pub mod log;
+pub mod magnet;
+pub mod torrent;
+pub mod wire;
+
+#[cfg(test)]
+mod arbitrary;
+
+native! {
+    pub mod bandwidth;
+    pub mod bench;
+    pub mod buffer_pool;
+    pub mod check;
+    pub mod client;
+    pub mod config;
+    pub mod clock;
+    pub mod coordinator;
+    pub mod create;
+    pub mod daemon;
+    pub mod doctor;
+    pub mod events;
+    pub mod executor;
+    pub mod feed;
+    pub mod ffi;
+    pub mod free_space;
+    pub mod fuzz;
+    pub mod geoip;
+    pub mod history;
+    pub mod hook;
+    pub mod http_server;
+    pub mod ip_filter;
+    pub mod krpc;
+    pub mod listener;
+    pub mod memory;
+    pub mod metadata;
+    pub mod peer;
+    pub mod peer_manager;
+    pub mod peer_worker;
+    pub mod phase;
+    pub mod picker;
+    pub mod piece_cache;
+    pub mod progress;
+    pub mod resume;
+    pub mod rpc;
+    pub mod scrape;
+    pub mod script;
+    pub mod seeding;
+    pub mod session;
+    pub mod shutdown;
+    pub mod sim;
+    pub mod storage;
+    pub mod stream;
+    pub mod swarm;
+    pub mod tracker_check;
+    pub mod tracker_server;
+
+    mod assembly;
+    mod bitfield;
+    #[cfg(target_os = "linux")]
+    mod direct_io;
+    mod extension;
+    mod hash_transfer;
+    mod holepunch;
+    #[cfg(unix)]
+    mod mmap;
+    #[cfg(test)]
+    mod mock;
+    mod sha256;
+    mod stats;
+    mod telemetry;
+    mod verifier;
+    mod watch;
+    mod webseed;
+}
+
+pub use bencode::{Bencode, BencodeError, Value};
+pub use error::{Error, Result};
+pub use magnet::Magnet;
+pub use torrent::{Info, Torrent, TorrentError, TrackerError};
+
+native! {
+    pub use client::{Client, ClientBuilder};
+    pub use coordinator::DownloadCoordinator;
+    pub use daemon::Daemon;
+    pub use events::{EventBus, TorrentEvent};
+    pub use peer::PeerConnection;
+    pub use session::{Session, SessionConfig, TorrentHandle};
+    pub use storage::{Storage, StorageKind};
+}
@@ -0,0 +1,249 @@
+use std::{
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{Arc, Mutex, RwLock},
+    time::Duration,
+};
+
+use crate::{
+    executor::{self, Task},
+    log,
+    peer::generate_peer_id,
+    peer_manager::{InboundPeer, PeerManager},
+    wire::Handshake,
+};
+
+pub const DEFAULT_PORT: u16 = 6881;
+// How long a peer that connected to us has to send its handshake, as long as we give peers we
+// dial to answer ours.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Accepts incoming peer connections and hands the ones that handshake for a torrent we are
+/// serving over to the peer manager.
+pub struct Listener {
+    listener: TcpListener,
+    info_hashes: Arc<RwLock<Vec<String>>>,
+    peer_id: [u8; 20],
+}
+
+impl Listener {
+    pub fn bind(port: u16, info_hashes: Vec<String>) -> io::Result<Self> {
+        let listener = TcpListener::bind(("0.0.0.0", port))?;
+
+        Ok(Self {
+            listener,
+            info_hashes: Arc::new(RwLock::new(info_hashes)),
+            peer_id: generate_peer_id(),
+        })
+    }
+
+    /// Answers handshakes as `peer_id`.
+    pub fn set_peer_id(&mut self, peer_id: [u8; 20]) {
+        self.peer_id = peer_id;
+    }
+
+    /// The info hashes we accept peers for, which torrents can be added to and removed from
+    /// while we listen.
+    pub fn info_hashes(&self) -> Arc<RwLock<Vec<String>>> {
+        self.info_hashes.clone()
+    }
+
+    pub fn port(&self) -> u16 {
+        self.listener
+            .local_addr()
+            .expect("Failed to read listening address")
+            .port()
+    }
+
+    /// Accepts peers until the process ends. Each handshake is read on a task of its own, so a
+    /// peer that connects and sends nothing holds up only its own half-open slot, and only until
+    /// the handshake times out.
+    pub fn spawn(self, peer_manager: Arc<Mutex<PeerManager>>) -> Task<()> {
+        executor::spawn("listener", move || {
+            for stream in self.listener.incoming() {
+                let Ok(stream) = stream else {
+                    continue;
+                };
+
+                let Some(addr) = stream.peer_addr().ok().filter(|addr| {
+                    peer_manager
+                        .lock()
+                        .expect("Peer manager lock poisoned")
+                        .begin_accept(*addr)
+                }) else {
+                    let _ = stream.shutdown(std::net::Shutdown::Both);
+                    continue;
+                };
+
+                let info_hashes = self.info_hashes.clone();
+                let peer_id = self.peer_id;
+                let peer_manager = peer_manager.clone();
+                executor::spawn("inbound handshake", move || {
+                    let peer = accept(stream, addr, &info_hashes, peer_id, &peer_manager);
+                    let mut peer_manager = peer_manager.lock().expect("Peer manager lock poisoned");
+                    match peer {
+                        Some(peer) => peer_manager.add_inbound(peer),
+                        // Releases the slot if the handshake failed before it was checked.
+                        None => {
+                            peer_manager.finish_accept(addr, None);
+                        }
+                    }
+                });
+            }
+        })
+    }
+}
+
+/// Reads the remote handshake, checks it is for one of our torrents that has room for another
+/// peer and replies with ours.
+fn accept(
+    mut socket: TcpStream,
+    addr: SocketAddr,
+    info_hashes: &RwLock<Vec<String>>,
+    peer_id: [u8; 20],
+    peer_manager: &Mutex<PeerManager>,
+) -> Option<InboundPeer> {
+    socket.set_read_timeout(Some(HANDSHAKE_TIMEOUT)).ok()?;
+    let mut bytes = [0; 68];
+    socket.read_exact(&mut bytes).ok()?;
+    if bytes[0] != 19 {
+        log::debug!(peer = addr; "dropping inbound peer: unexpected protocol");
+        return None;
+    }
+
+    let handshake = Handshake::decode(bytes);
+    let info_hash = hex::encode(handshake.info_hash);
+    let known = info_hashes
+        .read()
+        .expect("Info hash lock poisoned")
+        .contains(&info_hash);
+    if handshake.pstr != "BitTorrent protocol" || !known {
+        log::debug!(peer = addr; "dropping inbound peer: unknown torrent");
+        return None;
+    }
+
+    let can_accept = peer_manager
+        .lock()
+        .expect("Peer manager lock poisoned")
+        .finish_accept(addr, Some(handshake.info_hash));
+    if !can_accept {
+        log::info!(peer = addr; "dropping inbound peer: connection limit reached");
+        return None;
+    }
+
+    let reply = Handshake::new("BitTorrent protocol".to_string(), handshake.info_hash, peer_id);
+    socket.write_all(&reply.encode()).ok()?;
+    socket.set_read_timeout(None).ok()?;
+
+    Some(InboundPeer {
+        addr,
+        info_hash: handshake.info_hash,
+        peer_id: handshake.peer_id,
+        socket,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::TcpStream,
+        sync::{Arc, Mutex},
+        thread,
+        time::Duration,
+    };
+
+    use super::Listener;
+    use crate::{peer_manager::PeerManager, wire::Handshake};
+
+    const INFO_HASH: &str = "d69f91e6b2ae4c542468d1073a71d4ea13879a7f";
+
+    fn connect(listener: Listener, info_hash: &str) -> (Arc<Mutex<PeerManager>>, TcpStream) {
+        let port = listener.port();
+        let peer_manager = Arc::new(Mutex::new(PeerManager::new()));
+        listener.spawn(peer_manager.clone());
+
+        let mut socket = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let info_hash = hex::decode(info_hash).unwrap().try_into().unwrap();
+        let handshake = Handshake::new("BitTorrent protocol".to_string(), info_hash, [1; 20]);
+        socket.write_all(&handshake.encode()).unwrap();
+        (peer_manager, socket)
+    }
+
+    #[test]
+    fn accepts_known_info_hash() {
+        let listener = Listener::bind(0, vec![INFO_HASH.to_string()]).unwrap();
+        let (peer_manager, mut socket) = connect(listener, INFO_HASH);
+
+        let mut reply = [0; 68];
+        socket.read_exact(&mut reply).unwrap();
+        assert_eq!(
+            hex::encode(Handshake::decode(reply).info_hash),
+            INFO_HASH
+        );
+
+        thread::sleep(Duration::from_millis(50));
+        let peer_manager = peer_manager.lock().unwrap();
+        assert_eq!(peer_manager.inbound().len(), 1);
+        assert_eq!(peer_manager.inbound()[0].peer_id, [1; 20]);
+    }
+
+    #[test]
+    fn silent_peers_do_not_hold_up_others() {
+        let listener = Listener::bind(0, vec![INFO_HASH.to_string()]).unwrap();
+        let silent = TcpStream::connect(("127.0.0.1", listener.port())).unwrap();
+        let (peer_manager, mut socket) = connect(listener, INFO_HASH);
+
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut reply = [0; 68];
+        socket.read_exact(&mut reply).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(peer_manager.lock().unwrap().inbound().len(), 1);
+        drop(silent);
+    }
+
+    #[test]
+    fn accepts_torrents_added_while_listening() {
+        let listener = Listener::bind(0, vec![]).unwrap();
+        listener
+            .info_hashes()
+            .write()
+            .unwrap()
+            .push(INFO_HASH.to_string());
+        let (_, mut socket) = connect(listener, INFO_HASH);
+
+        let mut reply = [0; 68];
+        socket.read_exact(&mut reply).unwrap();
+    }
+
+    #[test]
+    fn drops_blocked_peers_before_handshaking() {
+        let listener = Listener::bind(0, vec![INFO_HASH.to_string()]).unwrap();
+        let port = listener.port();
+        let peer_manager = Arc::new(Mutex::new(PeerManager::new()));
+        peer_manager
+            .lock()
+            .unwrap()
+            .set_ip_filter("127.0.0.1".parse().unwrap());
+        listener.spawn(peer_manager.clone());
+
+        let mut socket = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let mut reply = Vec::new();
+        socket.read_to_end(&mut reply).unwrap();
+        assert!(reply.is_empty());
+        assert!(peer_manager.lock().unwrap().inbound().is_empty());
+    }
+
+    #[test]
+    fn rejects_unknown_info_hash() {
+        let listener = Listener::bind(0, vec![INFO_HASH.to_string()]).unwrap();
+        let (peer_manager, mut socket) = connect(listener, &"00".repeat(20));
+
+        let mut reply = Vec::new();
+        socket.read_to_end(&mut reply).unwrap();
+        assert!(reply.is_empty());
+        assert!(peer_manager.lock().unwrap().inbound().is_empty());
+    }
+}
@@ -0,0 +1,501 @@
+//! Levelled log events on stderr, tagged with `key=value` fields such as the torrent, peer and
+//! piece they concern. How much is shown comes from `-v`/`-q`, overridden by `RUST_LOG`
+//! directives like `debug` or `peer=trace,coordinator=warn`.
+//!
+//! Work that spans many events, like a peer connection or a piece download, is a [`Span`]: its
+//! fields are added to every event logged inside it, and [`init_file`] keeps a JSON record of
+//! events with the ids of the spans they happened in.
+
+use std::{
+    cell::RefCell,
+    env,
+    fmt::{self, Display},
+    fs::{File, OpenOptions},
+    io::{self, IsTerminal, Write},
+    marker::PhantomData,
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use serde_json::{json, Map, Value as JsonValue};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error = 1,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    fn name(self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        }
+    }
+}
+
+// The most verbose level shown, with 0 showing nothing.
+type MaxLevel = u8;
+
+fn parse_level(level: &str) -> Option<MaxLevel> {
+    let level = match level.to_ascii_lowercase().as_str() {
+        "off" => 0,
+        "error" => Level::Error as u8,
+        "warn" => Level::Warn as u8,
+        "info" => Level::Info as u8,
+        "debug" => Level::Debug as u8,
+        "trace" => Level::Trace as u8,
+        _ => return None,
+    };
+    Some(level)
+}
+
+/// Which events are shown: a default level, and levels for particular modules.
+#[derive(Debug, PartialEq)]
+struct Filter {
+    default: MaxLevel,
+    targets: Vec<(String, MaxLevel)>,
+}
+
+impl Filter {
+    fn new(verbosity: u8, quiet: bool) -> Self {
+        let default = match (quiet, verbosity) {
+            (true, _) => Level::Warn,
+            (false, 0) => Level::Info,
+            (false, 1) => Level::Debug,
+            (false, _) => Level::Trace,
+        };
+        Self {
+            default: default as u8,
+            targets: Vec::new(),
+        }
+    }
+
+    /// Applies comma-separated `RUST_LOG` directives, each a level or `target=level`.
+    /// Directives that do not parse are skipped.
+    fn apply(&mut self, directives: &str) {
+        for directive in directives.split(',').map(str::trim) {
+            match directive.split_once('=') {
+                Some((target, level)) => {
+                    if let Some(level) = parse_level(level) {
+                        self.targets.push((target.to_string(), level));
+                    }
+                }
+                None => {
+                    if let Some(level) = parse_level(directive) {
+                        self.default = level;
+                    }
+                }
+            }
+        }
+        // The most specific target wins.
+        self.targets
+            .sort_by_key(|(target, _)| std::cmp::Reverse(target.len()));
+    }
+
+    fn allows(&self, level: Level, target: &str) -> bool {
+        let max = self
+            .targets
+            .iter()
+            .find(|(prefix, _)| {
+                is_within(target, prefix) || is_within(short_target(target), prefix)
+            })
+            .map_or(self.default, |(_, level)| *level);
+        level as u8 <= max
+    }
+}
+
+fn is_within(target: &str, prefix: &str) -> bool {
+    target
+        .strip_prefix(prefix)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+}
+
+/// A module path without the crate name, which is the same for every event.
+fn short_target(target: &str) -> &str {
+    target.split_once("::").map_or(target, |(_, module)| module)
+}
+
+static FILTER: OnceLock<Filter> = OnceLock::new();
+static STARTED: OnceLock<Instant> = OnceLock::new();
+static FILE: OnceLock<Mutex<File>> = OnceLock::new();
+static NEXT_SPAN_ID: AtomicU64 = AtomicU64::new(1);
+
+thread_local! {
+    /// The spans entered on this thread, innermost last.
+    static ENTERED: RefCell<Vec<Span>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Sets how much is logged: `-v` once for debug events, twice for trace, or `-q` for only
+/// warnings and errors. `RUST_LOG` takes precedence where it says otherwise.
+pub fn init(verbosity: u8, quiet: bool) {
+    let mut filter = Filter::new(verbosity, quiet);
+    if let Ok(directives) = env::var("RUST_LOG") {
+        filter.apply(&directives);
+    }
+    let _ = FILTER.set(filter);
+    STARTED.get_or_init(Instant::now);
+}
+
+/// Also appends every event, as a line of JSON, to the file at `path`. The file gets debug
+/// events and when each span closed whatever is shown on stderr, for looking back at a slow or
+/// failed download afterwards.
+pub fn init_file(path: &Path) -> io::Result<()> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let _ = FILE.set(Mutex::new(file));
+    STARTED.get_or_init(Instant::now);
+    Ok(())
+}
+
+fn shown(level: Level, target: &str) -> bool {
+    FILTER
+        .get_or_init(|| Filter::new(0, false))
+        .allows(level, target)
+}
+
+fn recorded(level: Level, target: &str) -> bool {
+    FILE.get().is_some() && (level <= Level::Debug || shown(level, target))
+}
+
+pub fn enabled(level: Level, target: &str) -> bool {
+    shown(level, target) || recorded(level, target)
+}
+
+/// Writes one event. Called through the [`info!`] family of macros, which check
+/// [`enabled`] first so that disabled events cost nothing to format.
+pub fn write(level: Level, target: &str, message: fmt::Arguments, fields: &[(&str, &dyn Display)]) {
+    let elapsed = STARTED.get_or_init(Instant::now).elapsed();
+    let spans = entered();
+    if recorded(level, target) {
+        let fields = fields
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string().into()))
+            .collect();
+        let spans = spans.iter().map(|span| span.inner.to_json()).collect();
+        record(level, target, &message.to_string(), fields, spans);
+    }
+    if !shown(level, target) {
+        return;
+    }
+
+    let mut line = format!(
+        "{:>8.3}s {:>5} {}: {}",
+        elapsed.as_secs_f64(),
+        level.name(),
+        short_target(target),
+        message
+    );
+    for (key, value) in fields {
+        line.push_str(&format!(" {}={}", key, value));
+    }
+    // Fields of the spans the event happened in, innermost first, unless it gave its own.
+    let mut keys = fields.iter().map(|(key, _)| *key).collect::<Vec<_>>();
+    for span in spans.iter().rev() {
+        for (key, value) in &span.inner.fields {
+            if !keys.contains(key) {
+                keys.push(key);
+                line.push_str(&format!(" {}={}", key, value));
+            }
+        }
+    }
+
+    let mut stderr = io::stderr().lock();
+    // Clear any progress line first; it is redrawn on its next update.
+    if stderr.is_terminal() {
+        let _ = write!(stderr, "\r\x1b[2K");
+    }
+    let _ = writeln!(stderr, "{}", line);
+}
+
+fn entered() -> Vec<Span> {
+    ENTERED.with(|entered| entered.borrow().clone())
+}
+
+/// Appends an event to the log file as one line of JSON.
+fn record(
+    level: Level,
+    target: &str,
+    message: &str,
+    fields: Map<String, JsonValue>,
+    spans: Vec<JsonValue>,
+) {
+    let Some(file) = FILE.get() else {
+        return;
+    };
+    let line = json_line(level, target, message, fields, spans);
+    let mut file = file.lock().expect("Log file lock poisoned");
+    let _ = writeln!(file, "{}", line);
+}
+
+fn json_line(
+    level: Level,
+    target: &str,
+    message: &str,
+    fields: Map<String, JsonValue>,
+    spans: Vec<JsonValue>,
+) -> JsonValue {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let elapsed = STARTED.get_or_init(Instant::now).elapsed();
+    json!({
+        "timestamp": timestamp.as_secs_f64(),
+        "elapsed": elapsed.as_secs_f64(),
+        "level": level.name(),
+        "target": short_target(target),
+        "message": message,
+        "fields": fields,
+        "spans": spans,
+    })
+}
+
+/// A stretch of work, such as a connection to a peer or the download of a piece, with an id
+/// and fields of its own. Events logged on a thread while a span is entered carry its fields,
+/// and the log file notes how long it lasted once the last clone is dropped.
+#[derive(Debug, Clone)]
+pub struct Span {
+    inner: Arc<SpanData>,
+}
+
+#[derive(Debug)]
+struct SpanData {
+    id: u64,
+    name: &'static str,
+    target: &'static str,
+    fields: Vec<(&'static str, String)>,
+    opened: Instant,
+}
+
+impl Span {
+    /// Called through the [`span!`] macro.
+    pub fn new(
+        name: &'static str,
+        target: &'static str,
+        fields: &[(&'static str, &dyn Display)],
+    ) -> Self {
+        Self {
+            inner: Arc::new(SpanData {
+                id: NEXT_SPAN_ID.fetch_add(1, Ordering::Relaxed),
+                name,
+                target,
+                fields: fields
+                    .iter()
+                    .map(|(key, value)| (*key, value.to_string()))
+                    .collect(),
+                opened: Instant::now(),
+            }),
+        }
+    }
+
+    /// Unique among the spans of this process.
+    pub fn id(&self) -> u64 {
+        self.inner.id
+    }
+
+    /// Makes this the innermost span on this thread until the guard is dropped, unless it is
+    /// already entered.
+    pub fn enter(&self) -> Entered {
+        let pushed = ENTERED.with(|entered| {
+            let mut entered = entered.borrow_mut();
+            let pushed = !entered.iter().any(|span| span.id() == self.id());
+            if pushed {
+                entered.push(self.clone());
+            }
+            pushed
+        });
+        Entered {
+            id: pushed.then_some(self.id()),
+            _not_send: PhantomData,
+        }
+    }
+}
+
+impl SpanData {
+    fn to_json(&self) -> JsonValue {
+        let mut span = Map::new();
+        span.insert("id".to_string(), self.id.into());
+        span.insert("name".to_string(), self.name.into());
+        for (key, value) in &self.fields {
+            span.insert(key.to_string(), value.clone().into());
+        }
+        JsonValue::Object(span)
+    }
+}
+
+impl Drop for SpanData {
+    fn drop(&mut self) {
+        if !recorded(Level::Debug, self.target) {
+            return;
+        }
+        let mut fields = Map::new();
+        fields.insert(
+            "elapsed_ms".to_string(),
+            (self.opened.elapsed().as_secs_f64() * 1000.0).into(),
+        );
+        record(
+            Level::Debug,
+            self.target,
+            "span closed",
+            fields,
+            vec![self.to_json()],
+        );
+    }
+}
+
+/// Leaves a span when dropped. It stays on the thread that entered the span.
+pub struct Entered {
+    // None if the span was already entered.
+    id: Option<u64>,
+    _not_send: PhantomData<*const ()>,
+}
+
+impl Drop for Entered {
+    fn drop(&mut self) {
+        ENTERED.with(|entered| {
+            let mut entered = entered.borrow_mut();
+            if let Some(position) = entered.iter().rposition(|span| Some(span.id()) == self.id) {
+                entered.remove(position);
+            }
+        });
+    }
+}
+
+/// Logs an event at `level`, optionally with fields before a `;`:
+/// `event!(Level::Info, peer = addr, piece = index; "piece {} done", index)`.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __log_event {
+    ($level:expr, $($key:ident = $value:expr),+ ; $($message:tt)+) => {
+        if $crate::log::enabled($level, module_path!()) {
+            $crate::log::write(
+                $level,
+                module_path!(),
+                format_args!($($message)+),
+                &[$((stringify!($key), &$value as &dyn std::fmt::Display)),+],
+            );
+        }
+    };
+    ($level:expr, $($message:tt)+) => {
+        if $crate::log::enabled($level, module_path!()) {
+            $crate::log::write($level, module_path!(), format_args!($($message)+), &[]);
+        }
+    };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __log_error {
+    ($($arg:tt)+) => { $crate::__log_event!($crate::log::Level::Error, $($arg)+) };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __log_warn {
+    ($($arg:tt)+) => { $crate::__log_event!($crate::log::Level::Warn, $($arg)+) };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __log_info {
+    ($($arg:tt)+) => { $crate::__log_event!($crate::log::Level::Info, $($arg)+) };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __log_debug {
+    ($($arg:tt)+) => { $crate::__log_event!($crate::log::Level::Debug, $($arg)+) };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __log_trace {
+    ($($arg:tt)+) => { $crate::__log_event!($crate::log::Level::Trace, $($arg)+) };
+}
+
+/// Opens a [`Span`] named `name`, with fields: `span!("piece", piece = index)`.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __log_span {
+    ($name:expr $(, $key:ident = $value:expr)* $(,)?) => {
+        $crate::log::Span::new(
+            $name,
+            module_path!(),
+            &[$((stringify!($key), &$value as &dyn std::fmt::Display)),*],
+        )
+    };
+}
+
+// Exported from the crate root under prefixed names, so other crates can use them too, and
+// called through this module as `log::info!` and so on.
+pub use crate::{
+    __log_debug as debug, __log_error as error, __log_event as event, __log_info as info,
+    __log_span as span, __log_trace as trace, __log_warn as warn,
+};
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Map};
+
+    use super::{entered, json_line, span, Filter, Level};
+
+    #[test]
+    fn filters_by_verbosity_and_directives() {
+        let target = "bittorrent_starter_rust::peer";
+        assert!(Filter::new(0, false).allows(Level::Info, target));
+        assert!(!Filter::new(0, false).allows(Level::Debug, target));
+        assert!(Filter::new(2, false).allows(Level::Trace, target));
+        assert!(!Filter::new(1, true).allows(Level::Info, target));
+
+        let mut filter = Filter::new(0, false);
+        filter.apply("warn, peer=trace ,peer_manager=off,nonsense");
+        assert!(filter.allows(Level::Trace, target));
+        assert!(!filter.allows(Level::Info, "bittorrent_starter_rust::coordinator"));
+        assert!(!filter.allows(Level::Error, "bittorrent_starter_rust::peer_manager"));
+
+        filter.apply("bittorrent_starter_rust::peer=info");
+        assert!(!filter.allows(Level::Debug, target));
+    }
+
+    #[test]
+    fn events_carry_the_spans_they_happened_in() {
+        let peer = span!("peer", peer = "10.0.0.1:6881");
+        let piece = span!("piece", piece = 3);
+        assert_ne!(peer.id(), piece.id());
+
+        {
+            let _peer = peer.enter();
+            let _piece = piece.enter();
+            let spans = entered().iter().map(|span| span.inner.to_json()).collect();
+            let mut fields = Map::new();
+            fields.insert("bytes".to_string(), "16384".into());
+            let line = json_line(
+                Level::Debug,
+                "bittorrent_starter_rust::coordinator",
+                "piece verified",
+                fields,
+                spans,
+            );
+            assert_eq!(line["level"], "DEBUG");
+            assert_eq!(line["target"], "coordinator");
+            assert_eq!(line["fields"], json!({ "bytes": "16384" }));
+            assert_eq!(
+                line["spans"],
+                json!([
+                    { "id": peer.id(), "name": "peer", "peer": "10.0.0.1:6881" },
+                    { "id": piece.id(), "name": "piece", "piece": "3" },
+                ])
+            );
+        }
+        assert!(entered().is_empty());
+    }
+}
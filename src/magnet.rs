@@ -0,0 +1,321 @@
+#[cfg(not(target_arch = "wasm32"))]
+use std::net::SocketAddr;
+
+use crate::torrent::{Info, Torrent};
+#[cfg(not(target_arch = "wasm32"))]
+use crate::{
+    bencode::{Bencode, Value},
+    log,
+    metadata::{self, MetadataError},
+    peer::{HandshakeError, Handshaked, PeerConnection, PeerError},
+    torrent::{self, TorrentError, TrackerError},
+    wire::Handshake,
+};
+
+// Magnet links do not tell us how long the content is, so we announce a nominal amount left.
+#[cfg(not(target_arch = "wasm32"))]
+const UNKNOWN_LEFT: usize = 999;
+
+/// A magnet link (BEP 9): the info hash of a torrent, and optionally its name and the trackers
+/// and web seeds that know of it. The info dictionary itself is fetched from peers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Magnet {
+    pub info_hash: [u8; 20],
+    pub name: Option<String>,
+    pub trackers: Vec<String>,
+    /// Web seed URLs (BEP 19).
+    pub web_seeds: Vec<String>,
+}
+
+impl Magnet {
+    pub fn parse(link: &str) -> Result<Self, MagnetError> {
+        let query = link
+            .strip_prefix("magnet:?")
+            .ok_or(MagnetError::NotMagnet)?;
+        let params = serde_urlencoded::from_str::<Vec<(String, String)>>(query)
+            .map_err(|_| MagnetError::NotMagnet)?;
+
+        let mut info_hash = None;
+        let mut name = None;
+        let mut trackers = Vec::new();
+        let mut web_seeds = Vec::new();
+        for (key, value) in params {
+            match key.as_str() {
+                "xt" => {
+                    if let Some(hash) = value.strip_prefix("urn:btih:") {
+                        info_hash = Some(parse_info_hash(hash)?);
+                    }
+                }
+                "dn" => name = Some(value),
+                "tr" => trackers.push(value),
+                "ws" => web_seeds.push(value),
+                _ => {}
+            }
+        }
+
+        Ok(Self {
+            info_hash: info_hash.ok_or(MagnetError::MissingInfoHash)?,
+            name,
+            trackers,
+            web_seeds,
+        })
+    }
+
+    pub fn info_hash(&self) -> String {
+        hex::encode(self.info_hash)
+    }
+
+    /// The tracker we announce to, which is the first one listed.
+    pub fn tracker(&self) -> Option<&str> {
+        self.trackers.first().map(String::as_str)
+    }
+
+    /// The torrent this link stands for, once its info dictionary has been fetched.
+    pub fn into_torrent(self, info: Info) -> Torrent {
+        let announce = self.trackers.into_iter().next().unwrap_or_default();
+        Torrent::new(announce, self.web_seeds, info, self.info_hash)
+    }
+}
+
+// Fetching the info dictionary needs the network, which a wasm32 build does without.
+#[cfg(not(target_arch = "wasm32"))]
+impl Magnet {
+    pub fn get_peers(
+        &self,
+        peer_id: &[u8; 20],
+        port: u16,
+    ) -> Result<Vec<SocketAddr>, TrackerError> {
+        let tracker = self.tracker().ok_or(TrackerError::NoTracker)?;
+        let response = torrent::announce(
+            tracker,
+            &self.info_hash(),
+            peer_id,
+            port,
+            UNKNOWN_LEFT,
+            None,
+        )?;
+        torrent::peers_from_response(&response)
+    }
+
+    /// Connects and handshakes with the peer at `addr` as `peer_id`, then waits for its
+    /// extension handshake so we know whether and how it sends metadata.
+    pub fn connect(
+        &self,
+        peer_id: [u8; 20],
+        addr: SocketAddr,
+    ) -> Result<(PeerConnection<Handshaked>, Handshake), PeerError> {
+        // We do not know how many pieces there are until we have the metadata.
+        let peer = PeerConnection::connect(addr, 0).map_err(HandshakeError::Connect)?;
+        let (mut peer, handshake) = peer.handshake(self.info_hash(), peer_id, None)?;
+        peer.receive_extension_handshake()?;
+        Ok((peer, handshake))
+    }
+
+    /// Fetches the info dictionary from the first peer in the swarm that sends a copy matching
+    /// our info hash.
+    pub fn fetch_info(&self, peer_id: [u8; 20], port: u16) -> Result<Info, FetchError> {
+        self.fetch_info_from_any(peer_id, &self.get_peers(&peer_id, port)?)
+    }
+
+    /// Like `fetch_info`, asking only `peers`.
+    pub fn fetch_info_from_any(
+        &self,
+        peer_id: [u8; 20],
+        peers: &[SocketAddr],
+    ) -> Result<Info, FetchError> {
+        for &addr in peers {
+            match self.fetch_info_from(peer_id, addr) {
+                Ok(info) => return Ok(info),
+                Err(error) => log::warn!(peer = addr; "failed to fetch metadata: {}", error),
+            }
+        }
+        Err(FetchError::NoPeer)
+    }
+
+    fn fetch_info_from(&self, peer_id: [u8; 20], addr: SocketAddr) -> Result<Info, MetadataError> {
+        let (mut peer, _) = self.connect(peer_id, addr)?;
+        let result = metadata::fetch(&mut peer, &self.info_hash);
+        peer.close();
+
+        match Bencode::new(&result?).decode() {
+            Ok(Value::Dictionary(hash_map)) => Ok(Info::try_from(&hash_map)?),
+            Ok(_) => Err(TorrentError::Invalid("not a dictionary").into()),
+            Err(error) => Err(TorrentError::Decode(error).into()),
+        }
+    }
+}
+
+/// Reads an info hash given as 40 hex digits or, in older links, 32 base32 characters.
+fn parse_info_hash(hash: &str) -> Result<[u8; 20], MagnetError> {
+    let bytes = match hash.len() {
+        40 => hex::decode(hash).ok(),
+        32 => decode_base32(hash),
+        _ => None,
+    };
+    bytes
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| MagnetError::InvalidInfoHash(hash.to_string()))
+}
+
+fn decode_base32(encoded: &str) -> Option<Vec<u8>> {
+    const ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+    let mut bytes = Vec::new();
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for c in encoded.bytes() {
+        let value = ALPHABET.iter().position(|a| *a == c.to_ascii_uppercase())?;
+        buffer = (buffer << 5) | value as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+        }
+    }
+    Some(bytes)
+}
+
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum MagnetError {
+    #[error("not a magnet link")]
+    NotMagnet,
+    #[error("magnet link has no BitTorrent info hash")]
+    MissingInfoHash,
+    #[error("invalid info hash {0}")]
+    InvalidInfoHash(String),
+}
+
+/// Why we could not get a magnet link's info dictionary.
+#[cfg(not(target_arch = "wasm32"))]
+#[derive(Debug, thiserror::Error)]
+pub enum FetchError {
+    #[error(transparent)]
+    Tracker(#[from] TrackerError),
+    #[error("no peer sent the torrent's metadata")]
+    NoPeer,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        io::{Read, Write},
+        net::{SocketAddr, TcpListener},
+        thread,
+    };
+
+    use sha1::{Digest, Sha1};
+
+    use super::{Magnet, MagnetError};
+    use crate::{
+        bencode::{Bencode, Value},
+        extension::{self, ExtensionHandshake, UT_METADATA_ID},
+        metadata::{MetadataMessage, METADATA_PIECE_SIZE},
+        peer::TEST_PEER_ID,
+        torrent::Info,
+        wire::{Handshake, Message},
+    };
+
+    /// Answers a handshake, then serves `metadata` over `ut_metadata` to whoever asks.
+    fn spawn_metadata_peer(metadata: Vec<u8>, info_hash: [u8; 20]) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        thread::spawn(move || {
+            let (mut socket, _) = listener.accept().unwrap();
+            let mut handshake = [0; 68];
+            socket.read_exact(&mut handshake).unwrap();
+            let mut reply = Handshake::new("BitTorrent protocol".to_string(), info_hash, [7; 20]);
+            reply.set_supports_extensions();
+            socket.write_all(&reply.encode()).unwrap();
+
+            let mut ours = ExtensionHandshake::default();
+            ours.extensions.insert("ut_metadata".to_string(), 3);
+            ours.metadata_size = Some(metadata.len());
+            let message = extension::extended_message(extension::HANDSHAKE_ID, &ours.as_bytes());
+            socket.write_all(&message.encode()).unwrap();
+
+            while let Ok(message) = Message::read_from_socket(&mut socket) {
+                let Some(message) = message else { continue };
+                let Some((3, payload)) = message.payload.split_first() else {
+                    continue;
+                };
+                let Some(MetadataMessage::Request { piece }) = MetadataMessage::from_bytes(payload)
+                else {
+                    continue;
+                };
+                let start = piece * METADATA_PIECE_SIZE;
+                let end = metadata.len().min(start + METADATA_PIECE_SIZE);
+                let data = MetadataMessage::Data {
+                    piece,
+                    total_size: metadata.len(),
+                    data: metadata[start..end].to_vec(),
+                };
+                let message = extension::extended_message(UT_METADATA_ID, &data.as_bytes());
+                socket.write_all(&message.encode()).unwrap();
+            }
+        });
+
+        addr
+    }
+
+    #[test]
+    fn fetches_metadata_spanning_several_pieces() {
+        let info = Info {
+            length: 1000 * 16,
+            name: "payload".to_string(),
+            piece_length: 16,
+            pieces: (0..1000).map(|index| [index as u8; 20]).collect(),
+            files: vec![],
+            private: false,
+        };
+        let metadata = Bencode::encode(&Value::Dictionary(HashMap::from(&info)));
+        assert!(metadata.len() > METADATA_PIECE_SIZE);
+        let info_hash: [u8; 20] = Sha1::digest(&metadata).into();
+
+        let magnet = Magnet {
+            info_hash,
+            name: None,
+            trackers: vec![],
+            web_seeds: vec![],
+        };
+        let addr = spawn_metadata_peer(metadata, info_hash);
+        let fetched = magnet.fetch_info_from(TEST_PEER_ID, addr).unwrap();
+        assert_eq!(fetched.pieces, info.pieces);
+        assert_eq!(fetched.length, info.length);
+    }
+
+    #[test]
+    fn parses_hex_and_base32_links() {
+        let magnet = Magnet::parse(
+            "magnet:?xt=urn:btih:ad42ce8109f54c99613ce38f9b4d87e70f24a165&dn=magnet1.gif\
+             &tr=http%3A%2F%2Fbittorrent-test-tracker.codecrafters.io%2Fannounce",
+        )
+        .unwrap();
+        assert_eq!(
+            magnet.info_hash(),
+            "ad42ce8109f54c99613ce38f9b4d87e70f24a165"
+        );
+        assert_eq!(magnet.name.as_deref(), Some("magnet1.gif"));
+        assert_eq!(
+            magnet.tracker(),
+            Some("http://bittorrent-test-tracker.codecrafters.io/announce")
+        );
+
+        let base32 = Magnet::parse("magnet:?xt=urn:btih:VVBM5AIJ6VGJSYJ44OHZWTMH44HSJILF").unwrap();
+        assert_eq!(base32.info_hash, magnet.info_hash);
+        assert_eq!(base32.tracker(), None);
+
+        assert_eq!(
+            Magnet::parse("http://example.com"),
+            Err(MagnetError::NotMagnet)
+        );
+        assert_eq!(
+            Magnet::parse("magnet:?dn=name"),
+            Err(MagnetError::MissingInfoHash)
+        );
+        assert!(matches!(
+            Magnet::parse("magnet:?xt=urn:btih:1234"),
+            Err(MagnetError::InvalidInfoHash(_))
+        ));
+    }
+}
@@ -1,33 +1,182 @@
-use crate::bencode::Bencode;
-use clap::{Parser, Subcommand};
-use torrent::Torrent;
-use tracker::Tracker;
+use std::{
+    io::{self, IsTerminal, Read},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
 
-mod bencode;
-mod torrent;
-mod tracker;
+use anyhow::Context;
+use bandwidth::{BandwidthSchedule, Limit, RateLimiter, ScheduleWindow};
+use bench::BenchMode;
+use bittorrent_starter_rust::{
+    bandwidth, bench, bencode::Bencode, buffer_pool, check, client::Client, config, coordinator,
+    create, daemon, doctor, executor, feed, free_space, geoip, history, hook, ip_filter, krpc,
+    listener, log, magnet, peer, peer_manager, picker, piece_cache, resume, scrape, script,
+    seeding, shutdown, storage, stream, swarm, torrent, tracker_check, tracker_server, wire,
+};
+use buffer_pool::DEFAULT_PIECE_BUFFERS;
+use clap::{Args, Parser, Subcommand};
+use config::{ConfigFile, Settings};
+use coordinator::{DownloadCoordinator, StopAfter};
+use create::{TorrentCreator, TorrentVersion, DEFAULT_PIECE_LENGTH};
+use daemon::Daemon;
+use executor::{ExecutorKind, Tokio};
+use exit::{CorruptPiece, InvalidArgument};
+use feed::{FeedWatcher, Feeds, SEEN_FILE};
+use geoip::GeoIp;
+use history::{History, HistoryFilter};
+use ip_filter::IpFilter;
+use listener::{Listener, DEFAULT_PORT};
+use magnet::Magnet;
+use peer::{generate_peer_id, HandshakeError, PeerConnection};
+use peer_manager::{ConnectionLimits, PeerManager};
+use picker::PickerKind;
+use piece_cache::DEFAULT_CACHE_SIZE;
+use resume::{FileState, ResumeData};
+use script::Script;
+use seeding::SeedLimits;
+use shutdown::Shutdown;
+use storage::{
+    FileStorage, FlushPolicy, FlushingStorage, NullStorage, SinglePieceStorage, Storage,
+    StorageKind,
+};
+use swarm::CrawlLimits;
+use torrent::{Torrent, TrackerError};
+use wire::Handshake;
+
+mod exit;
+mod output;
+mod tui;
 
 #[derive(Parser)]
+#[clap(rename_all = "snake_case")]
 struct Cli {
+    #[command(flatten)]
+    global: GlobalArgs,
+    /// Also append every event to this file as JSON lines, with debug events and the peer,
+    /// announce and piece spans they happened in
+    #[clap(long, alias = "log-file", global = true)]
+    log_file: Option<PathBuf>,
+    /// Run downloads, seeding and servers on a thread each, or on a tokio runtime's blocking
+    /// pool
+    #[clap(long, global = true, value_enum, default_value_t = ExecutorKind::Threads)]
+    executor: ExecutorKind,
     #[command(subcommand)]
     command: Commands,
 }
 
+#[derive(Args, Clone, Copy)]
+struct GlobalArgs {
+    /// Log debug events and print more detail, like transfer statistics; twice to also log
+    /// trace events
+    #[clap(short, long, global = true, action = clap::ArgAction::Count)]
+    verbose: u8,
+    /// Only log warnings and errors, and don't show a progress line
+    #[clap(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+    /// Print results as JSON instead of text
+    #[clap(long, global = true)]
+    json: bool,
+    // Generated once per run, so trackers and peers see the same id from every part of it.
+    #[clap(skip = generate_peer_id())]
+    peer_id: [u8; 20],
+}
+
 #[derive(Subcommand)]
 #[clap(rename_all = "snake_case")]
 enum Commands {
+    /// Decode a bencoded value given as an argument, in a file, or as `-` on stdin
     Decode {
-        encoded_value: String,
+        #[clap(required_unless_present = "file")]
+        encoded_value: Option<String>,
+        /// Decode the raw bytes of this file instead, such as a .torrent file
+        #[clap(long, conflicts_with = "encoded_value")]
+        file: Option<PathBuf>,
     },
     Info {
         torrent_file: String,
     },
+    /// List the files in a torrent, with how much of each is on disk
+    Files {
+        torrent_file: String,
+        /// The torrent's file, or the directory holding a multi-file torrent's files. Defaults
+        /// to the torrent's name in the current directory.
+        #[clap(long)]
+        data_path: Option<String>,
+    },
+    /// List each piece with its hash, its length and the parts of files it covers
+    Pieces {
+        torrent_file: String,
+    },
     Peers {
         torrent_file: String,
+        /// How many peers to ask the tracker for
+        #[clap(long)]
+        numwant: Option<u32>,
+        /// Ask this tracker instead of the torrent's own
+        #[clap(long)]
+        tracker: Option<String>,
+        /// MaxMind database (GeoLite2 Country, City or ASN) to show where peers are from.
+        /// Repeatable.
+        #[clap(long)]
+        geoip: Vec<PathBuf>,
     },
+    /// Handshake with a peer and report what it supports
     Handshake {
         torrent_file: String,
         addr: String,
+        /// Seconds to wait for the peer to accept the connection and to answer the handshake
+        #[clap(long, default_value_t = 10)]
+        timeout: u64,
+        /// How many more times to try if the handshake fails, waiting longer between each
+        #[clap(long, default_value_t = 0)]
+        retries: u32,
+    },
+    /// Ask each of the torrent's trackers how many seeders and leechers it has
+    Scrape {
+        torrent_file: String,
+    },
+    MagnetParse {
+        magnet_link: String,
+    },
+    /// Check each tracker of a torrent or magnet link, or one tracker URL, step by step
+    TrackerTest {
+        /// A .torrent file, a magnet link or an announce URL
+        target: String,
+        /// The info hash, in hex, to announce when given a bare URL [default: all zeros]
+        #[clap(long)]
+        info_hash: Option<String>,
+        /// The port to announce
+        #[clap(long, default_value_t = DEFAULT_PORT)]
+        port: u16,
+        /// Seconds to wait for each step
+        #[clap(long, default_value_t = 10)]
+        timeout: u64,
+    },
+    /// Check the port, NAT, DNS, download directory and, given a torrent, its trackers, saying
+    /// what to fix
+    Doctor {
+        /// A .torrent file whose trackers to try and whose size to check the disk for
+        torrent_file: Option<String>,
+        /// The directory downloads will be saved in
+        #[clap(long, default_value = ".")]
+        dir: String,
+        /// The port to accept peer connections on
+        #[clap(long, default_value_t = DEFAULT_PORT)]
+        port: u16,
+        /// Seconds to wait for each network check
+        #[clap(long, default_value_t = 5)]
+        timeout: u64,
+    },
+    /// Handshake with a peer from the magnet link's tracker, including the extension handshake
+    MagnetHandshake {
+        magnet_link: String,
+    },
+    /// Fetch the info dictionary of a magnet link from its swarm
+    MagnetInfo {
+        magnet_link: String,
     },
     DownloadPiece {
         #[clap(short)]
@@ -35,67 +184,1658 @@ enum Commands {
         path: String,
         torrent_file: String,
         piece_index: usize,
+        #[command(flatten)]
+        peer: PeerArgs,
     },
     Download {
+        torrent_file: String,
+        #[command(flatten)]
+        args: DownloadArgs,
+    },
+    /// Fetch a magnet link's metadata from its swarm, then download and verify one piece
+    MagnetDownloadPiece {
+        #[clap(short = 'o')]
+        path: String,
+        magnet_link: String,
+        piece_index: usize,
+        #[command(flatten)]
+        peer: PeerArgs,
+    },
+    /// Fetch a magnet link's metadata from its swarm, then download all of it
+    MagnetDownload {
+        magnet_link: String,
+        #[command(flatten)]
+        args: DownloadArgs,
+    },
+    /// Check local data against a torrent, then serve it to peers until interrupted
+    Seed {
+        torrent_file: String,
+        /// The torrent's file, or the directory holding a multi-file torrent's files
+        data_path: String,
+        #[command(flatten)]
+        peer: PeerArgs,
+        /// Stop once we have uploaded this many times the torrent's size
+        #[clap(long)]
+        seed_ratio: Option<f64>,
+        /// Stop after this many minutes
+        #[clap(long)]
+        seed_time: Option<u64>,
+    },
+    /// Hash local data against a torrent and report the pieces that are missing or corrupt
+    Verify {
+        torrent_file: String,
+        /// The torrent's file, or the directory holding a multi-file torrent's files
+        data_path: String,
+    },
+    /// Report how far a download has got, from its resume data or by hashing what is on disk
+    Status {
+        torrent_file: String,
+        /// The download's file or directory, as given to `download -o`. Defaults to the
+        /// torrent's name in the current directory.
+        data_path: Option<String>,
+    },
+    /// Show what each torrent uploaded and downloaded each day, and when it completed, from a
+    /// file given as `--history` to download or daemon
+    History {
+        file: PathBuf,
+        /// Only this torrent, by its 40 character hex info hash
+        #[clap(long)]
+        info_hash: Option<String>,
+        /// Only from this day on, as YYYY-MM-DD (UTC)
+        #[clap(long)]
+        since: Option<String>,
+    },
+    /// Send a single DHT query to a node and print its answer
+    Dht {
+        #[command(subcommand)]
+        query: DhtQuery,
+        /// Seconds to wait for the answer
+        #[clap(long, global = true, default_value_t = 5)]
+        timeout: u64,
+    },
+    /// Estimate the size of a swarm by asking the DHT for its peers, without downloading
+    /// anything or contacting them
+    Swarm {
+        /// A 40 character hex info hash or a magnet link
+        target: String,
+        /// A DHT node to start from. Can be given more than once.
+        #[clap(long, default_value = krpc::BOOTSTRAP_NODE)]
+        node: Vec<String>,
+        /// Stop once this many of the nodes closest to the info hash have been asked
+        #[clap(long, default_value_t = CrawlLimits::default().width)]
+        width: usize,
+        /// Stop after asking this many nodes in all
+        #[clap(long, default_value_t = CrawlLimits::default().max_queries)]
+        max_queries: usize,
+        /// Seconds to wait for each node
+        #[clap(long, default_value_t = 2)]
+        timeout: u64,
+    },
+    /// Run a minimal HTTP tracker, keeping swarms in memory, until interrupted
+    ServeTracker {
+        /// The address to listen on; announce to `http://<addr>/announce`
+        #[clap(long, default_value = "0.0.0.0:6969")]
+        addr: String,
+        /// Seconds peers are told to wait between announces
+        #[clap(long, default_value_t = 1800)]
+        interval: u64,
+    },
+    /// Download and seed several torrents at once until interrupted
+    Daemon {
+        torrent_files: Vec<String>,
+        #[command(flatten)]
+        args: DaemonArgs,
+    },
+    /// Run the daemon with a full-screen dashboard of torrents and peers
+    Tui {
+        torrent_files: Vec<String>,
+        #[command(flatten)]
+        args: DaemonArgs,
+    },
+    /// Hash a file or directory into a new .torrent file
+    Create {
+        path: String,
+        /// Tracker URL to announce to
+        #[clap(long)]
+        announce: String,
+        /// Bytes per piece; a power of two of at least 16 KiB for v2 and hybrid torrents
+        #[clap(long, alias = "piece-length", default_value_t = DEFAULT_PIECE_LENGTH)]
+        piece_length: usize,
+        /// Where to write the .torrent file
         #[clap(short)]
         out: String,
-        torrent_file: String,
+        /// Mark the torrent private, so clients only get peers from its tracker
+        #[clap(long)]
+        private: bool,
+        /// Which hashes to include
+        #[clap(long, value_enum, default_value_t = TorrentVersion::V1)]
+        version: TorrentVersion,
+    },
+    /// Measure how fast we decode bencode, hash pieces or transfer them between two peers
+    Bench {
+        #[clap(value_enum)]
+        mode: BenchMode,
+        /// MiB of data to get through
+        #[clap(long, default_value_t = 64)]
+        size: u64,
+    },
+}
+
+// Where we listen for peers and which peers we refuse. A plain comment, as clap would show a
+// doc comment as the description of every command that flattens this in.
+#[derive(Args)]
+#[clap(rename_all = "snake_case")]
+struct PeerArgs {
+    /// Port to accept incoming peer connections on [default: 6881, or any free port if that is
+    /// taken]
+    #[clap(long)]
+    port: Option<u16>,
+    /// Port of our DHT node, advertised to peers that support DHT
+    #[clap(long)]
+    dht_port: Option<u16>,
+    /// File of CIDR ranges, addresses or eMule ipfilter.dat lines to refuse peers from
+    #[clap(long)]
+    ip_filter: Option<String>,
+}
+
+impl PeerArgs {
+    fn port(&self) -> u16 {
+        self.port.unwrap_or(DEFAULT_PORT)
+    }
+}
+
+#[derive(Subcommand)]
+#[clap(rename_all = "snake_case")]
+enum DhtQuery {
+    /// Check that a node answers
+    Ping { addr: String },
+    /// Ask a node for the nodes it knows closest to a 40 character hex id
+    FindNode {
+        id: String,
+        #[clap(long, default_value = krpc::BOOTSTRAP_NODE)]
+        node: String,
     },
+    /// Ask a node for peers of a 40 character hex info hash
+    GetPeers {
+        info_hash: String,
+        #[clap(long, default_value = krpc::BOOTSTRAP_NODE)]
+        node: String,
+    },
+}
+
+#[derive(Args)]
+#[clap(rename_all = "snake_case")]
+struct DownloadArgs {
+    /// Where to save the download, or `-` to stream it to stdout in order [default: the
+    /// torrent's name]
+    #[clap(short)]
+    out: Option<String>,
+    /// Save into this directory, creating it if needed. A relative `-o` is taken from here.
+    #[clap(long, alias = "output-dir")]
+    output_dir: Option<String>,
+    #[command(flatten)]
+    peer: PeerArgs,
+    /// Download only from this peer, without asking the tracker. Can be given more than once
+    #[clap(long = "peer", value_name = "ADDR")]
+    peers: Vec<String>,
+    /// Maximum open peer connections across all torrents
+    #[clap(long, default_value_t = ConnectionLimits::default().global)]
+    max_connections: usize,
+    /// Maximum open peer connections for this torrent
+    #[clap(long, default_value_t = ConnectionLimits::default().per_torrent)]
+    max_connections_per_torrent: usize,
+    /// Maximum connections, in either direction, still handshaking at once
+    #[clap(long, default_value_t = ConnectionLimits::default().half_open)]
+    max_half_open: usize,
+    /// Order in which pieces are downloaded
+    #[clap(long, value_enum, default_value_t = PickerKind::Sequential)]
+    picker: PickerKind,
+    /// Download rate limit in bytes per second, `unlimited` or `paused`
+    #[clap(long, default_value = "unlimited")]
+    rate_limit: Limit,
+    /// A different limit for a time of day (UTC), as HH:MM-HH:MM=<limit>. Repeatable.
+    #[clap(long)]
+    schedule: Vec<ScheduleWindow>,
+    /// Keep seeding after the download until we have uploaded this many times its size
+    #[clap(long)]
+    seed_ratio: Option<f64>,
+    /// Keep seeding after the download for this many minutes
+    #[clap(long)]
+    seed_time: Option<u64>,
+    /// Stop once this much of the torrent is downloaded and verified: a number of bytes, rounded
+    /// up to whole pieces, or `<count>pieces`. The partial download can be resumed later.
+    #[clap(long, alias = "stop-after")]
+    stop_after: Option<StopAfter>,
+    /// Shell command to run once the download finishes, given BT_NAME, BT_PATH, BT_INFO_HASH
+    /// and BT_BYTES in its environment
+    #[clap(long, alias = "on-complete")]
+    on_complete: Option<String>,
+    /// Download and verify every piece but write nothing, then report the rate achieved
+    #[clap(
+        long,
+        alias = "dry-run",
+        conflicts_with_all = ["seed_ratio", "seed_time", "on_complete", "history"]
+    )]
+    dry_run: bool,
+    /// Append what this download transferred, and its completion, to this history file
+    #[clap(long)]
+    history: Option<PathBuf>,
+    /// How the output file is written
+    #[clap(long, value_enum, default_value_t = StorageKind::File)]
+    storage: StorageKind,
+    /// Write per-piece and per-peer timings to this file as JSON
+    #[clap(long)]
+    telemetry: Option<String>,
+    /// Where to write the download until every piece verifies [default: <out>.part]
+    #[clap(long)]
+    part_path: Option<String>,
+    /// Download into this directory and move the finished files to <out> once they verify
+    #[clap(long, conflicts_with = "part_path")]
+    incomplete_dir: Option<String>,
+    /// Carry on from partial output and its resume file, if there are any [default]
+    #[clap(long, overrides_with = "no_resume")]
+    resume: bool,
+    /// Start afresh, discarding any partial output and resume file
+    #[clap(long, alias = "no-resume", overrides_with = "resume")]
+    no_resume: bool,
+    /// When to sync written data to disk: `block`, `piece`, `completion` or every N seconds
+    #[clap(long, default_value = "completion")]
+    flush: FlushPolicy,
+    /// Bytes of recently served pieces to keep in memory
+    #[clap(long, default_value_t = DEFAULT_CACHE_SIZE)]
+    piece_cache_size: usize,
+    /// Most pieces held in memory while downloading and verifying
+    #[clap(long, default_value_t = DEFAULT_PIECE_BUFFERS)]
+    piece_buffers: usize,
+}
+
+#[derive(Args)]
+#[clap(rename_all = "snake_case")]
+struct DaemonArgs {
+    /// Directory to save downloads in, each under its torrent's name
+    #[clap(long, default_value = ".")]
+    download_dir: String,
+    #[command(flatten)]
+    peer: PeerArgs,
+    /// Maximum open peer connections across all torrents
+    #[clap(long, default_value_t = ConnectionLimits::default().global)]
+    max_connections: usize,
+    /// Maximum open peer connections for each torrent
+    #[clap(long, default_value_t = ConnectionLimits::default().per_torrent)]
+    max_connections_per_torrent: usize,
+    /// Maximum connections, in either direction, still handshaking at once
+    #[clap(long, default_value_t = ConnectionLimits::default().half_open)]
+    max_half_open: usize,
+    /// Download rate limit shared by all torrents, in bytes per second, `unlimited` or `paused`
+    #[clap(long, default_value = "unlimited")]
+    rate_limit: Limit,
+    /// A different limit for a time of day (UTC), as HH:MM-HH:MM=<limit>. Repeatable.
+    #[clap(long)]
+    schedule: Vec<ScheduleWindow>,
+    /// Bytes of pieces, cached pieces and metadata all torrents may hold in memory. Over it, no
+    /// new pieces are requested until some are written out.
+    #[clap(long)]
+    memory_budget: Option<u64>,
+    /// Stop seeding a torrent once we have uploaded this many times its size
+    #[clap(long)]
+    seed_ratio: Option<f64>,
+    /// Stop seeding a torrent after this many minutes
+    #[clap(long)]
+    seed_time: Option<u64>,
+    /// Shell command to run as each torrent finishes downloading, given BT_NAME, BT_PATH,
+    /// BT_INFO_HASH and BT_BYTES in its environment
+    #[clap(long, alias = "on-complete")]
+    on_complete: Option<String>,
+    /// Append what each torrent transfers, and when it completes, to this history file
+    #[clap(long)]
+    history: Option<PathBuf>,
+    /// File of rules making control API calls as events happen, one per line, such as
+    /// `on completed call pause` or `on state_changed if state == failed call remove`
+    #[clap(long)]
+    script: Option<PathBuf>,
+    /// File of `key = value` lines overriding rate_limit, schedule, max_connections,
+    /// max_connections_per_torrent, max_half_open and memory_budget, applied again whenever it
+    /// changes or on SIGHUP
+    #[clap(long)]
+    config: Option<PathBuf>,
+    /// Address to serve the JSON-RPC control API on, such as 127.0.0.1:9091
+    #[clap(long, requires = "rpc_token")]
+    rpc_addr: Option<String>,
+    /// Token API clients must send as `Authorization: Bearer <token>`
+    #[clap(long)]
+    rpc_token: Option<String>,
+    /// Directory to add .torrent and .magnet files from as they appear. Added files are moved
+    /// into its `processed` subdirectory.
+    #[clap(long)]
+    watch_dir: Option<String>,
+    /// File of RSS or Atom feeds to add torrents from, each a `feed <url>` line followed by
+    /// `match <regex>` and `exclude <regex>` lines filtering entries by title. Entries added
+    /// are remembered in `feeds.seen` in the download directory.
+    #[clap(long)]
+    feeds: Option<PathBuf>,
+    /// Minutes between polls of the feeds
+    #[clap(long, default_value = "15")]
+    feed_interval: u64,
+    /// MaxMind database (GeoLite2 Country, City or ASN) to show where peers are from.
+    /// Repeatable.
+    #[clap(long)]
+    geoip: Vec<PathBuf>,
 }
 
 // Usage: your_bittorrent.sh decode "<encoded_value>"
 fn main() {
     let cli = Cli::parse();
+    log::init(cli.global.verbose, cli.global.quiet);
+    exit::report_panics_briefly();
+    if let Some(path) = &cli.log_file {
+        if let Err(error) = log::init_file(path) {
+            eprintln!("error: cannot open log file {}: {}", path.display(), error);
+            std::process::exit(1);
+        }
+    }
+
+    // Kept until we exit, as the engine's tasks run on its blocking pool.
+    let runtime = match cli.executor {
+        ExecutorKind::Threads => None,
+        ExecutorKind::Tokio => {
+            let runtime = tokio::runtime::Builder::new_multi_thread()
+                .enable_all()
+                .build()
+                .expect("Failed to start the tokio runtime");
+            executor::set_executor(Arc::new(Tokio::new(runtime.handle().clone())));
+            Some(runtime)
+        }
+    };
+
+    let result = run(cli);
+    // The listener and verification workers never return, so they are not waited for.
+    if let Some(runtime) = runtime {
+        runtime.shutdown_background();
+    }
+    if let Err(error) = result {
+        eprintln!("error: {}", exit::message(&error));
+        std::process::exit(exit::code(&error));
+    }
+}
 
+fn run(cli: Cli) -> anyhow::Result<()> {
     match cli.command {
-        Commands::Decode { encoded_value } => {
-            let decoded_value = Bencode::new(encoded_value.as_bytes()).decode();
-            println!("{}", decoded_value)
+        Commands::Decode {
+            encoded_value,
+            file,
+        } => {
+            let encoded = match (encoded_value, file) {
+                (Some(value), _) if value == "-" => {
+                    let mut encoded = Vec::new();
+                    io::stdin()
+                        .read_to_end(&mut encoded)
+                        .context("cannot read stdin")?;
+                    encoded
+                }
+                (Some(value), _) => value.into_bytes(),
+                (None, Some(file)) => std::fs::read(&file)
+                    .with_context(|| format!("cannot read {}", file.display()))?,
+                (None, None) => unreachable!("clap requires a value or a file"),
+            };
+            let decoded_value = Bencode::new(&encoded).decode()?;
+            if cli.global.json {
+                println!("{}", decoded_value.to_json());
+            } else {
+                println!("{}", decoded_value)
+            }
         }
         Commands::Info { torrent_file } => {
-            let torrent = Torrent::open(torrent_file);
-            println!("Tracker URL: {}", torrent.announce);
-            println!("Length: {}", torrent.info.length);
-            println!("Info Hash: {}", torrent.info_hash());
-            println!("Piece Length: {}", torrent.info.piece_length);
-            println!("Piece Hashes:");
-            for hash in torrent.info.pieces {
-                println!("{}", hex::encode(hash));
+            let torrent = open_torrent(&torrent_file)?;
+            let info = output::TorrentInfo {
+                info_hash: torrent.info_hash(),
+                tracker_url: torrent.announce,
+                length: torrent.info.length,
+                piece_length: torrent.info.piece_length,
+                piece_hashes: torrent.info.pieces.iter().map(hex::encode).collect(),
+            };
+            output::print(&info, cli.global.json);
+        }
+        Commands::Pieces { torrent_file } => {
+            let info = open_torrent(&torrent_file)?.info;
+            let pieces = output::Pieces {
+                pieces: (0..info.pieces.len())
+                    .map(|index| {
+                        let range = info.piece_range(index);
+                        output::PieceListing {
+                            index,
+                            hash: hex::encode(info.pieces[index]),
+                            length: range.end - range.start,
+                            files: info
+                                .piece_files(index)
+                                .into_iter()
+                                .map(|(path, range)| output::PieceFile {
+                                    path,
+                                    start: range.start,
+                                    end: range.end,
+                                })
+                                .collect(),
+                        }
+                    })
+                    .collect(),
+            };
+            output::print(&pieces, cli.global.json);
+        }
+        Commands::Files {
+            torrent_file,
+            data_path,
+        } => {
+            let torrent = open_torrent(&torrent_file)?;
+            let data_path = data_path.unwrap_or_else(|| torrent.info.name.clone());
+            let bad_pieces = Path::new(&data_path)
+                .exists()
+                .then(|| check::check(Path::new(&data_path), &torrent.info));
+            let files = output::Files {
+                files: torrent
+                    .info
+                    .file_spans()
+                    .into_iter()
+                    .map(|file| {
+                        let pieces = file.pieces(torrent.info.piece_length);
+                        let completion = match &bad_pieces {
+                            Some(_) if file.length == 0 => Some(100.0),
+                            Some(bad) => Some(
+                                check::completed_bytes(&file, bad) as f64 * 100.0
+                                    / file.length as f64,
+                            ),
+                            None => None,
+                        };
+                        output::FileListing {
+                            path: file.path,
+                            length: file.length,
+                            first_piece: (!pieces.is_empty()).then_some(pieces.start),
+                            last_piece: pieces.end.checked_sub(1),
+                            completion,
+                        }
+                    })
+                    .collect(),
+            };
+            output::print(&files, cli.global.json);
+        }
+        Commands::Peers {
+            torrent_file,
+            numwant,
+            tracker,
+            geoip,
+        } => {
+            let geoip = open_geoip(&geoip)?;
+            let torrent = open_torrent(&torrent_file)?;
+            let tracker = tracker.unwrap_or_else(|| torrent.announce.clone());
+            let response = torrent
+                .query_tracker(&tracker, &cli.global.peer_id, DEFAULT_PORT, numwant)
+                .with_context(|| tracker.clone())?;
+            let peers = output::Peers {
+                peers: response.peers.iter().map(ToString::to_string).collect(),
+                locations: response
+                    .peers
+                    .iter()
+                    .filter_map(|peer| Some((peer.to_string(), geoip.lookup(peer.ip())?)))
+                    .collect(),
+                tracker,
+                interval: response.interval,
+                seeders: response.seeders,
+                leechers: response.leechers,
+                verbose: cli.global.verbose > 0,
+            };
+            output::print(&peers, cli.global.json);
+        }
+        Commands::Handshake {
+            torrent_file,
+            addr,
+            timeout,
+            retries,
+        } => {
+            let torrent = open_torrent(&torrent_file)?;
+            let addr = resolve_peer(&addr)?;
+            let (handshake, attempts) = probe_peer(
+                &torrent,
+                addr,
+                cli.global.peer_id,
+                Duration::from_secs(timeout),
+                retries,
+            )
+            .with_context(|| format!("no handshake from {}", addr))?;
+            let result = output::HandshakeResult {
+                peer_id: hex::encode(handshake.peer_id),
+                client: peer::client_name(&handshake.peer_id),
+                reserved: hex::encode(handshake.reserved),
+                capabilities: handshake
+                    .capabilities()
+                    .into_iter()
+                    .map(str::to_string)
+                    .collect(),
+                attempts,
+                verbose: cli.global.verbose > 0,
+            };
+            output::print(&result, cli.global.json);
+        }
+        Commands::Scrape { torrent_file } => {
+            let torrent = open_torrent(&torrent_file)?;
+            let info_hash = torrent.info_hash_bytes();
+            let trackers = torrent
+                .trackers()
+                .into_iter()
+                .map(|url| match scrape::scrape(url, &info_hash) {
+                    Ok(stats) => output::TrackerScrape {
+                        url: url.to_string(),
+                        seeders: Some(stats.seeders),
+                        leechers: Some(stats.leechers),
+                        completed: Some(stats.completed),
+                        error: None,
+                    },
+                    Err(error) => output::TrackerScrape {
+                        url: url.to_string(),
+                        seeders: None,
+                        leechers: None,
+                        completed: None,
+                        error: Some(error.to_string()),
+                    },
+                })
+                .collect();
+            output::print(&output::Scrape { trackers }, cli.global.json);
+        }
+        Commands::TrackerTest {
+            target,
+            info_hash,
+            port,
+            timeout,
+        } => {
+            let (trackers, info_hash) = if target.starts_with("magnet:") {
+                let magnet = Magnet::parse(&target)?;
+                (magnet.trackers, magnet.info_hash)
+            } else if target.contains("://") {
+                let info_hash = info_hash.as_deref().map(parse_id).transpose()?;
+                (vec![target.clone()], info_hash.unwrap_or_default())
+            } else {
+                let torrent = open_torrent(&target)?;
+                let trackers = torrent.trackers().into_iter().map(str::to_string).collect();
+                (trackers, torrent.info_hash_bytes())
+            };
+            if trackers.is_empty() {
+                return Err(InvalidArgument(format!("{} lists no trackers", target)).into());
+            }
+            let trackers = trackers
+                .iter()
+                .map(|url| {
+                    tracker_check::check(
+                        url,
+                        &info_hash,
+                        &cli.global.peer_id,
+                        port,
+                        Duration::from_secs(timeout),
+                    )
+                })
+                .collect::<Vec<_>>();
+            let announced = trackers.iter().any(tracker_check::TrackerCheck::announced);
+            output::print(&output::TrackerTest { trackers }, cli.global.json);
+            if !announced {
+                std::process::exit(exit::TRACKER_ERROR);
             }
         }
-        Commands::Peers { torrent_file } => {
-            let torrent = Torrent::open(torrent_file);
-            let peers = torrent.get_peers();
-            for peer in peers {
-                println!("{}", peer);
+        Commands::Doctor {
+            torrent_file,
+            dir,
+            port,
+            timeout,
+        } => {
+            let torrent = torrent_file.as_deref().map(open_torrent).transpose()?;
+            let timeout = Duration::from_secs(timeout);
+            let dir = Path::new(&dir);
+            let mut findings = vec![
+                doctor::listen_port(port, timeout),
+                doctor::nat(port, timeout),
+                doctor::dns(),
+                doctor::writable(dir),
+                doctor::space(dir, torrent.as_ref().map(|t| t.info.length as u64)),
+            ];
+            if let Some(torrent) = &torrent {
+                let info_hash = torrent.info_hash_bytes();
+                findings.extend(doctor::trackers(
+                    &torrent.trackers(),
+                    &info_hash,
+                    &cli.global.peer_id,
+                    port,
+                    timeout,
+                ));
+            }
+            let healthy = findings
+                .iter()
+                .all(|finding| finding.status != doctor::Status::Problem);
+            output::print(&output::Doctor { findings }, cli.global.json);
+            if !healthy {
+                std::process::exit(exit::FAILURE);
             }
         }
-        Commands::Handshake { torrent_file, addr } => {
-            let mut tracker = Tracker::new(Torrent::open(torrent_file), Some(addr));
-            let handshake = tracker.handshake();
-            println!("Peer ID: {}", hex::encode(handshake.peer_id));
+        Commands::MagnetParse { magnet_link } => {
+            let magnet = Magnet::parse(&magnet_link)?;
+            let link = output::MagnetLink {
+                tracker_url: magnet.tracker().map(str::to_string),
+                info_hash: magnet.info_hash(),
+                name: magnet.name,
+            };
+            output::print(&link, cli.global.json);
+        }
+        Commands::MagnetHandshake { magnet_link } => {
+            let magnet = Magnet::parse(&magnet_link)?;
+            let addr = *magnet
+                .get_peers(&cli.global.peer_id, DEFAULT_PORT)?
+                .first()
+                .ok_or(TrackerError::NoPeers)?;
+            let (peer, handshake) = magnet
+                .connect(cli.global.peer_id, addr)
+                .with_context(|| format!("no handshake from {}", addr))?;
+            let result = output::MagnetHandshake {
+                peer_id: hex::encode(handshake.peer_id),
+                metadata_extension_id: peer.extension_id("ut_metadata"),
+            };
+            output::print(&result, cli.global.json);
+        }
+        Commands::MagnetInfo { magnet_link } => {
+            let magnet = Magnet::parse(&magnet_link)?;
+            let info = magnet.fetch_info(cli.global.peer_id, DEFAULT_PORT)?;
+            let info = output::TorrentInfo {
+                tracker_url: magnet.tracker().unwrap_or_default().to_string(),
+                length: info.length,
+                info_hash: magnet.info_hash(),
+                piece_length: info.piece_length,
+                piece_hashes: info.pieces.iter().map(hex::encode).collect(),
+            };
+            output::print(&info, cli.global.json);
         }
         Commands::DownloadPiece {
             path,
             torrent_file,
             piece_index,
+            peer,
+        } => {
+            let torrent = open_torrent(&torrent_file)?;
+            download_piece(torrent, path, piece_index, peer, cli.global)?;
+        }
+        Commands::MagnetDownloadPiece {
+            path,
+            magnet_link,
+            piece_index,
+            peer,
+        } => {
+            let magnet = Magnet::parse(&magnet_link)?;
+            let info = magnet.fetch_info(cli.global.peer_id, peer.port())?;
+            download_piece(
+                magnet.into_torrent(info),
+                path,
+                piece_index,
+                peer,
+                cli.global,
+            )?;
+        }
+        Commands::Download { torrent_file, args } => {
+            let torrent = open_torrent(&torrent_file)?;
+            download(torrent, torrent_file, args, cli.global)?;
+        }
+        Commands::MagnetDownload { magnet_link, args } => {
+            let magnet = Magnet::parse(&magnet_link)?;
+            let info = if args.peers.is_empty() {
+                magnet.fetch_info(cli.global.peer_id, args.peer.port())?
+            } else {
+                let peers = args
+                    .peers
+                    .iter()
+                    .map(|addr| resolve_peer(addr))
+                    .collect::<Result<Vec<_>, _>>()?;
+                magnet.fetch_info_from_any(cli.global.peer_id, &peers)?
+            };
+            let name = magnet.name.clone().unwrap_or_else(|| info.name.clone());
+            download(magnet.into_torrent(info), name, args, cli.global)?;
+        }
+        Commands::Seed {
+            torrent_file,
+            data_path,
+            peer,
+            seed_ratio,
+            seed_time,
+        } => {
+            let torrent = open_torrent(&torrent_file)?;
+            let limits = SeedLimits {
+                ratio: seed_ratio,
+                time: seed_time.map(|minutes| Duration::from_secs(minutes * 60)),
+            };
+            seed(torrent, data_path, peer, limits, cli.global)?;
+        }
+        Commands::Verify {
+            torrent_file,
+            data_path,
+        } => {
+            let torrent = open_torrent(&torrent_file)?;
+            let bad_pieces = check::check(Path::new(&data_path), &torrent.info);
+            let piece_count = torrent.info.pieces.len();
+            let verified = output::Verified {
+                path: data_path,
+                pieces: piece_count - bad_pieces.len(),
+                piece_count,
+                bad_pieces: bad_pieces
+                    .into_iter()
+                    .map(|bad| output::BadPiece {
+                        piece: bad.index,
+                        problem: bad.problem,
+                        start: bad.range.start,
+                        end: bad.range.end,
+                        files: bad
+                            .files
+                            .iter()
+                            .map(|file| file.display().to_string())
+                            .collect(),
+                    })
+                    .collect(),
+            };
+            output::print(&verified, cli.global.json);
+            if verified.pieces < piece_count {
+                std::process::exit(exit::FAILURE);
+            }
+        }
+        Commands::Status {
+            torrent_file,
+            data_path,
+        } => {
+            let torrent = open_torrent(&torrent_file)?;
+            let out = data_path.unwrap_or_else(|| torrent.info.name.clone());
+            output::print(&status(&torrent, &out), cli.global.json);
+        }
+        Commands::History {
+            file,
+            info_hash,
+            since,
+        } => {
+            let history = History::new(file);
+            let filter = HistoryFilter { info_hash, since };
+            let cannot_read = || format!("cannot read {}", history.path().display());
+            let output = output::History {
+                days: history.daily_totals(&filter).with_context(cannot_read)?,
+                completions: history.completions(&filter).with_context(cannot_read)?,
+            };
+            output::print(&output, cli.global.json);
+        }
+        Commands::Dht { query, timeout } => {
+            let (node, query) = match query {
+                DhtQuery::Ping { addr } => (addr, krpc::Query::Ping),
+                DhtQuery::FindNode { id, node } => (node, krpc::Query::FindNode(parse_id(&id)?)),
+                DhtQuery::GetPeers { info_hash, node } => {
+                    (node, krpc::Query::GetPeers(parse_id(&info_hash)?))
+                }
+            };
+            let addr = peer::resolve_addr(&node).map_err(|error| {
+                InvalidArgument(format!("invalid node address {}: {}", node, error))
+            })?;
+            let response = krpc::query(addr, query, Duration::from_secs(timeout))
+                .with_context(|| node.clone())?;
+            let response = output::DhtResponse {
+                node,
+                id: hex::encode(response.id),
+                milliseconds: response.elapsed.as_millis(),
+                nodes: response
+                    .nodes
+                    .iter()
+                    .map(|(id, addr)| output::DhtNode {
+                        id: hex::encode(id),
+                        addr: addr.to_string(),
+                    })
+                    .collect(),
+                peers: response.peers.iter().map(ToString::to_string).collect(),
+                token: response.token.map(hex::encode),
+            };
+            output::print(&response, cli.global.json);
+        }
+        Commands::Swarm {
+            target,
+            node,
+            width,
+            max_queries,
+            timeout,
+        } => {
+            let info_hash = if target.starts_with("magnet:") {
+                Magnet::parse(&target)?.info_hash
+            } else {
+                parse_id(&target)?
+            };
+            // Bootstrap nodes that do not resolve are skipped, as long as one does.
+            let nodes = node
+                .iter()
+                .filter_map(|node| match peer::resolve_addr(node) {
+                    Ok(addr) => Some(addr),
+                    Err(error) => {
+                        log::warn!("skipping node {}: {}", node, error);
+                        None
+                    }
+                })
+                .collect::<Vec<_>>();
+            if nodes.is_empty() {
+                return Err(InvalidArgument("no DHT node to start from".to_string()).into());
+            }
+            let limits = CrawlLimits {
+                width,
+                max_queries,
+                timeout: Duration::from_secs(timeout),
+                ..CrawlLimits::default()
+            };
+            let crawl = swarm::crawl(info_hash, &nodes, limits);
+            let output = output::Swarm {
+                info_hash: hex::encode(info_hash),
+                peers: crawl.peers.iter().map(ToString::to_string).collect(),
+                storing: crawl.storing.iter().map(ToString::to_string).collect(),
+                queried: crawl.queried,
+                answered: crawl.answered,
+                milliseconds: crawl.elapsed.as_millis(),
+            };
+            output::print(&output, cli.global.json);
+        }
+        Commands::ServeTracker { addr, interval } => {
+            let addr = tracker_server::serve(&addr, Duration::from_secs(interval))
+                .with_context(|| format!("cannot listen on {}", addr))?;
+            log::info!("tracking on http://{}/announce", addr);
+            let shutdown = Shutdown::new();
+            shutdown.request_on_ctrl_c();
+            while !shutdown.is_requested() {
+                thread::sleep(Duration::from_millis(100));
+            }
+        }
+        Commands::Daemon {
+            torrent_files,
+            args,
+        } => daemon(torrent_files, args, cli.global)?,
+        Commands::Tui {
+            torrent_files,
+            args,
+        } => tui(torrent_files, args, cli.global)?,
+        Commands::Create {
+            path,
+            announce,
+            piece_length,
+            out,
+            private,
+            version,
         } => {
-            let mut tracker = Tracker::new(Torrent::open(torrent_file), None);
-            tracker.handshake();
+            let creator = TorrentCreator {
+                announce,
+                piece_length,
+                private,
+                version,
+            };
+            let torrent = creator
+                .create(Path::new(&path))
+                .with_context(|| format!("cannot create a torrent from {}", path))?;
+            std::fs::write(&out, &torrent.bytes)
+                .with_context(|| format!("cannot write {}", out))?;
+            let created = output::Created {
+                path: out,
+                info_hash: torrent.info_hash.map(hex::encode),
+                info_hash_v2: torrent.info_hash_v2.map(hex::encode),
+                length: torrent.length,
+                files: torrent.file_count,
+            };
+            output::print(&created, cli.global.json);
+        }
+        Commands::Bench { mode, size } => {
+            let name = format!("{:?}", mode).to_lowercase();
+            let measurement = bench::run(mode, size * 1024 * 1024)
+                .with_context(|| format!("the {} benchmark failed", name))?;
+            let bench = output::Bench {
+                mode: name,
+                bytes: measurement.bytes,
+                seconds: measurement.elapsed.as_secs_f64(),
+                rate: measurement.rate(),
+            };
+            output::print(&bench, cli.global.json);
+        }
+    }
+    Ok(())
+}
+
+/// Deletes the file at `path`, if there is one.
+fn remove_if_present(path: &Path) -> io::Result<()> {
+    match std::fs::remove_file(path) {
+        Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+/// Looks up a peer address given on the command line.
+fn resolve_peer(addr: &str) -> Result<SocketAddr, InvalidArgument> {
+    peer::resolve_addr(addr)
+        .map_err(|error| InvalidArgument(format!("invalid peer address {}: {}", addr, error)))
+}
+
+/// A node id or info hash given as 40 hex characters.
+fn parse_id(id: &str) -> Result<[u8; 20], InvalidArgument> {
+    hex::decode(id)
+        .ok()
+        .and_then(|id| id.try_into().ok())
+        .ok_or_else(|| InvalidArgument(format!("{} is not 40 hex characters", id)))
+}
+
+/// Reads the torrent file at `path`, naming it in any error.
+fn open_torrent(path: &str) -> anyhow::Result<Torrent> {
+    Torrent::open(path).with_context(|| path.to_string())
+}
+
+/// Downloads and verifies a single piece of `torrent` to `path`.
+fn download_piece(
+    torrent: Torrent,
+    path: String,
+    piece_index: usize,
+    args: PeerArgs,
+    global: GlobalArgs,
+) -> anyhow::Result<()> {
+    let PeerArgs {
+        port,
+        dht_port,
+        ip_filter,
+    } = args;
+    let piece_length = torrent.info.piece_length;
+    let (peer_manager, port) = start_listener(port, &torrent, ip_filter, global.peer_id)?;
+    let mut coordinator = DownloadCoordinator::new(torrent, port, peer_manager.clone());
+    coordinator.set_peer_id(global.peer_id);
+    if let Some(dht_port) = dht_port {
+        coordinator.set_dht_port(dht_port);
+    }
+    let peer = coordinator.connect(&[])?;
+    let addr = peer.addr();
+    let (mut peer, _) = coordinator
+        .handshake(peer)
+        .with_context(|| format!("no handshake from {}", addr))?;
+
+    // The file holds only this piece, so it starts at the piece's first byte.
+    let file = FileStorage::create(&path, piece_length)
+        .with_context(|| format!("cannot create {}", path))?;
+    let mut storage = SinglePieceStorage::new(file, piece_index);
+    let verified = coordinator.download_piece(&mut peer, piece_index, &mut storage);
+    coordinator
+        .close(Some(&mut peer), &mut storage)
+        .with_context(|| format!("cannot write {}", path))?;
+    if global.verbose > 0 {
+        print_peer_summary(&peer, &peer_manager);
+    }
+    // Nothing is left behind that could be mistaken for the piece.
+    let failed = match verified {
+        Ok(true) => None,
+        Ok(false) => Some(anyhow::Error::new(CorruptPiece(piece_index))),
+        Err(error) => Some(anyhow::Error::new(error)),
+    };
+    if let Some(error) = failed {
+        remove_if_present(Path::new(&path)).with_context(|| format!("cannot remove {}", path))?;
+        return Err(error).with_context(|| format!("download from {} failed", peer.addr()));
+    }
+    let downloaded = output::PieceDownloaded {
+        piece_index,
+        path,
+        verified: true,
+    };
+    output::print(&downloaded, global.json);
+    Ok(())
+}
+
+/// Downloads all of `torrent`, called `name` in what we print, then seeds it if asked to.
+fn download(
+    torrent: Torrent,
+    name: String,
+    args: DownloadArgs,
+    global: GlobalArgs,
+) -> anyhow::Result<()> {
+    let DownloadArgs {
+        out,
+        output_dir,
+        peer: PeerArgs {
+            port,
+            dht_port,
+            ip_filter,
+        },
+        peers,
+        max_connections,
+        max_connections_per_torrent,
+        max_half_open,
+        picker,
+        rate_limit,
+        schedule,
+        seed_ratio,
+        seed_time,
+        stop_after,
+        on_complete,
+        dry_run,
+        history,
+        storage,
+        telemetry,
+        part_path,
+        incomplete_dir,
+        resume: _,
+        no_resume,
+        flush,
+        piece_cache_size,
+        piece_buffers,
+    } = args;
+    let out = out.unwrap_or_else(|| storage::safe_component(&torrent.info.name));
+    let streaming = out == "-";
+    // Streamed and dry-run downloads are never written to disk, so there is nothing to resume.
+    let discarding = streaming || dry_run;
+    let out = match output_dir {
+        Some(dir) if !discarding => {
+            std::fs::create_dir_all(&dir).with_context(|| format!("cannot create {}", dir))?;
+            Path::new(&dir).join(out).display().to_string()
+        }
+        _ => out,
+    };
+    let resume = !no_resume && !discarding;
+    let part_path = match (part_path, incomplete_dir) {
+        (Some(part_path), _) => PathBuf::from(part_path),
+        (None, Some(dir)) => {
+            std::fs::create_dir_all(&dir).with_context(|| format!("cannot create {}", dir))?;
+            let name = Path::new(&out)
+                .file_name()
+                .ok_or_else(|| InvalidArgument(format!("output {} does not name a file", out)))?;
+            Path::new(&dir).join(name)
+        }
+        (None, None) => PathBuf::from(format!("{}.part", out)),
+    };
+    let (working, mut content_paths, finished_paths) = if discarding {
+        (PathBuf::from(&out), Vec::new(), Vec::new())
+    } else if resume {
+        let working = storage::working_path(Path::new(&out), &part_path);
+        let content_paths = storage::content_paths(&working, &torrent.info);
+        let finished_paths = storage::content_paths(Path::new(&out), &torrent.info);
+        (working, content_paths, finished_paths)
+    } else {
+        // A finished output is left alone until the new download verifies and replaces it.
+        let content_paths = storage::content_paths(&part_path, &torrent.info);
+        for path in &content_paths {
+            remove_if_present(path)
+                .with_context(|| format!("cannot discard {}", path.display()))?;
+        }
+        let finished_paths = storage::content_paths(Path::new(&out), &torrent.info);
+        (part_path, content_paths, finished_paths)
+    };
+    let resume_path = format!("{}.resume", out);
+    if !resume && !discarding {
+        remove_if_present(Path::new(&resume_path))
+            .with_context(|| format!("cannot discard {}", resume_path))?;
+        log::info!("starting afresh in {}", working.display());
+    }
+    free_space::check(&content_paths, torrent.info.length as u64)?;
+    let storage: Box<dyn Storage> = if discarding {
+        Box::new(NullStorage)
+    } else {
+        storage
+            .open(&working, &torrent.info)
+            .with_context(|| format!("cannot open {}", working.display()))?
+    };
+    let mut storage = FlushingStorage::new(storage, flush);
+    let info = torrent.info.clone();
+    let info_hash = torrent.info_hash();
+    let info_hash_bytes = torrent.info_hash_bytes();
+    let piece_count = torrent.info.pieces.len();
+    let (peer_manager, port) = start_listener(port, &torrent, ip_filter, global.peer_id)?;
+    peer_manager
+        .lock()
+        .expect("Peer manager lock poisoned")
+        .set_limits(ConnectionLimits {
+            global: max_connections,
+            per_torrent: max_connections_per_torrent,
+            half_open: max_half_open,
+        });
+    let mut coordinator = DownloadCoordinator::new(torrent, port, peer_manager.clone());
+    coordinator.set_peer_id(global.peer_id);
+    if let Some(dht_port) = dht_port {
+        coordinator.set_dht_port(dht_port);
+    }
+    if streaming && picker != PickerKind::Sequential {
+        log::warn!("streaming needs pieces in order, using the sequential picker");
+        coordinator.set_picker(PickerKind::Sequential.build());
+    } else {
+        coordinator.set_picker(picker.build());
+    }
+    coordinator.set_piece_cache_size(piece_cache_size);
+    coordinator.set_piece_buffers(piece_buffers);
+    if let Some(quota) = stop_after {
+        coordinator.set_stop_after(quota);
+    }
+    let schedule = BandwidthSchedule::new(rate_limit, schedule);
+    coordinator.set_rate_limiter(Arc::new(Mutex::new(RateLimiter::new(schedule))));
+    coordinator.shutdown_signal().request_on_ctrl_c();
+    let writer = streaming.then(|| {
+        stream::write_pieces(
+            coordinator.piece_stream(),
+            io::stdout(),
+            coordinator.shutdown_signal(),
+        )
+    });
 
-            // create a file at the path
-            let mut file = std::fs::File::create(path.clone()).expect("Failed to create file");
-            tracker.download_piece(piece_index, &mut file);
-            println!("Piece {} downloaded to {}.", piece_index, path);
+    // Trust the resume file if the output is exactly as we left it, otherwise hash
+    // whatever is there.
+    let resumed = resume.then(|| ResumeData::load(&resume_path)).flatten();
+    let restored = resumed.as_ref().and_then(|resume| {
+        let files = FileState::read_all(&content_paths)?;
+        resume.pieces_if_unchanged(&info_hash, &files, piece_count)
+    });
+    match restored {
+        Some(pieces) => {
+            let restored = coordinator.restore(&pieces);
+            log::info!("resumed with {} pieces from {}", restored, resume_path);
         }
-        Commands::Download { out, torrent_file } => {
-            let mut tracker = Tracker::new(Torrent::open(torrent_file.clone()), None);
-            tracker.handshake();
+        None if !resume => {}
+        None => {
+            let found = coordinator.recheck(&mut storage);
+            if found > 0 {
+                log::info!("found {} pieces already downloaded", found);
+            }
+        }
+    }
+    let mut peer = None;
+    let mut failure = None;
+    let already_complete = coordinator.is_complete();
+    let mut elapsed = Duration::ZERO;
+    if !already_complete {
+        // The progress line is redrawn in place, which only works on a terminal.
+        let progress =
+            (!global.quiet && io::stderr().is_terminal()).then(|| coordinator.show_progress());
+        let started = Instant::now();
+        // What was fetched before the swarm failed us is still saved for resuming.
+        match coordinator.download_from_swarm(&peers, &mut storage) {
+            Ok(finished) => peer = finished,
+            Err(error) => failure = Some(anyhow::Error::new(error).context("download failed")),
+        }
+        elapsed = started.elapsed();
+        if let Some(progress) = progress {
+            progress.join().expect("Progress thread panicked");
+        }
+    }
+    // Only a verified download is moved into place. Open handles follow the rename,
+    // so seeding carries on reading from it.
+    if coordinator.is_complete() && working != Path::new(&out) {
+        storage
+            .sync()
+            .with_context(|| format!("cannot write {}", working.display()))?;
+        storage::finish(&working, Path::new(&out), &info)
+            .with_context(|| format!("cannot move {} to {}", working.display(), out))?;
+        log::info!("moved {} to {}", working.display(), out);
+        content_paths = finished_paths;
+    }
+    let history = history.map(History::new);
+    let completed = coordinator.is_complete() && !already_complete;
+    if let Some(history) = &history {
+        if completed {
+            if let Err(error) = history.record_completed(&info_hash, &info.name) {
+                log::warn!(
+                    "failed to record history in {}: {}",
+                    history.path().display(),
+                    error
+                );
+            }
+        }
+    }
+    if let Some(command) = &on_complete {
+        if completed && !discarding {
+            let completion = hook::Completion {
+                name: &info.name,
+                path: Path::new(&out),
+                info_hash: &info_hash,
+                bytes: info.length as u64,
+            };
+            hook::on_complete(command, completion);
+        }
+    }
+
+    let seed_limits = SeedLimits {
+        ratio: seed_ratio,
+        time: seed_time.map(|minutes| Duration::from_secs(minutes * 60)),
+    };
+    if coordinator.is_complete() && seed_limits.is_set() {
+        if streaming {
+            log::warn!("not seeding, as the download was only streamed");
+        } else {
+            coordinator.seed(&seed_limits, Path::new(&out));
+        }
+    }
+    coordinator
+        .close(peer.as_mut(), &mut storage)
+        .with_context(|| format!("cannot write {}", working.display()))?;
+    if let Some(writer) = writer {
+        if let Err(error) = writer.join().expect("Stream writer panicked") {
+            log::warn!("stopped streaming: {}", error);
+        }
+    }
+
+    let (uploaded, downloaded) =
+        resumed.map_or((0, 0), |resume| (resume.uploaded, resume.downloaded));
+    let session_downloaded = coordinator.bytes_downloaded();
+    let session_uploaded = peer_manager
+        .lock()
+        .expect("Peer manager lock poisoned")
+        .uploaded(info_hash_bytes);
+    if let Some(history) = &history {
+        if let Err(error) =
+            history.record_transfer(&info_hash, &info.name, session_uploaded, session_downloaded)
+        {
+            log::warn!(
+                "failed to record history in {}: {}",
+                history.path().display(),
+                error
+            );
+        }
+    }
+    let files = (!discarding)
+        .then(|| FileState::read_all(&content_paths))
+        .flatten();
+    if let Some(files) = files {
+        let resume = ResumeData::new(
+            info_hash,
+            coordinator.completed(),
+            files,
+            uploaded + session_uploaded,
+            downloaded + session_downloaded,
+        );
+        if let Err(error) = resume.save(&resume_path) {
+            log::warn!("failed to save {}: {}", resume_path, error);
+        }
+    }
+    if global.verbose > 0 {
+        if let Some(peer) = &peer {
+            print_peer_summary(peer, &peer_manager);
+        }
+        eprintln!("piece cache: {}", coordinator.piece_cache_stats());
+        if let Some((index, time)) = coordinator.telemetry().slowest_pieces().first() {
+            eprintln!("slowest piece: {} ({}ms)", index, time.as_millis());
+        }
+        let deepest = coordinator
+            .telemetry()
+            .queue_depths()
+            .iter()
+            .map(|sample| sample.verifying)
+            .max()
+            .unwrap_or(0);
+        eprintln!("deepest verification queue: {} pieces", deepest);
+    }
+    if let Some(path) = telemetry {
+        std::fs::write(&path, coordinator.telemetry().to_json())
+            .with_context(|| format!("cannot write {}", path))?;
+    }
 
-            // create a file at the path
-            let mut file = std::fs::File::create(out.clone()).expect("Failed to create file");
-            tracker.download_all_pieces(&mut file);
-            println!("Downloaded {} to {}.", torrent_file, out);
+    if dry_run {
+        let seconds = elapsed.as_secs_f64();
+        let dry_run = output::DryRun {
+            torrent: name,
+            peer: peer.as_ref().map(|peer| peer.addr().to_string()),
+            pieces: (0..piece_count)
+                .filter(|index| coordinator.completed().has(*index))
+                .count(),
+            piece_count,
+            bytes: session_downloaded,
+            seconds,
+            rate: if seconds > 0.0 {
+                session_downloaded as f64 / seconds
+            } else {
+                0.0
+            },
+        };
+        if !coordinator.is_complete() && !coordinator.reached_stop_after() {
+            eprintln!("{}", dry_run);
+            if let Some(error) = failure {
+                return Err(error);
+            }
+            std::process::exit(exit::INTERRUPTED);
         }
+        output::print(&dry_run, global.json);
+        return Ok(());
     }
+    let downloaded = output::Downloaded {
+        torrent: name,
+        path: out,
+        complete: coordinator.is_complete(),
+        stopped_at_quota: !coordinator.is_complete() && coordinator.reached_stop_after(),
+        pieces: (0..piece_count)
+            .filter(|index| coordinator.completed().has(*index))
+            .count(),
+        piece_count,
+        downloaded: session_downloaded,
+        uploaded: session_uploaded,
+    };
+    // Stdout carries the streamed content, and an incomplete download is an error unless it
+    // stopped where it was asked to.
+    let finished = downloaded.complete || downloaded.stopped_at_quota;
+    if streaming || !finished {
+        if global.json {
+            eprintln!("{}", output::to_json(&downloaded));
+        } else {
+            eprintln!("{}", downloaded);
+        }
+    } else {
+        output::print(&downloaded, global.json);
+    }
+    if !finished {
+        if let Some(error) = failure {
+            return Err(error);
+        }
+        std::process::exit(exit::INTERRUPTED);
+    }
+    Ok(())
+}
+
+/// Checks the data at `path` against every piece of `torrent`, then seeds it until interrupted
+/// or one of `limits` is hit.
+fn seed(
+    torrent: Torrent,
+    path: String,
+    args: PeerArgs,
+    limits: SeedLimits,
+    global: GlobalArgs,
+) -> anyhow::Result<()> {
+    let mut storage = storage::open_existing(&path, &torrent.info)
+        .with_context(|| format!("cannot open {}", path))?;
+    let name = torrent.info.name.clone();
+    let info_hash = torrent.info_hash_bytes();
+    let piece_count = torrent.info.pieces.len();
+    let (peer_manager, port) = start_listener(args.port, &torrent, args.ip_filter, global.peer_id)?;
+    let mut coordinator = DownloadCoordinator::new(torrent, port, peer_manager.clone());
+    coordinator.set_peer_id(global.peer_id);
+    if let Some(dht_port) = args.dht_port {
+        coordinator.set_dht_port(dht_port);
+    }
+
+    let found = coordinator.recheck(storage.as_mut());
+    if found < piece_count {
+        anyhow::bail!(
+            "only {} of {} pieces in {} verify",
+            found,
+            piece_count,
+            path
+        );
+    }
+    log::info!("verified all {} pieces", piece_count);
+
+    coordinator.shutdown_signal().request_on_ctrl_c();
+    coordinator.seed(&limits, Path::new(&path));
+    coordinator
+        .close(None, storage.as_mut())
+        .with_context(|| format!("cannot write {}", path))?;
+
+    let seeded = output::Seeded {
+        torrent: name,
+        uploaded: peer_manager
+            .lock()
+            .expect("Peer manager lock poisoned")
+            .uploaded(info_hash),
+    };
+    output::print(&seeded, global.json);
+    Ok(())
+}
+
+/// How far the download of `torrent` to `out` has got, from its resume data while that still
+/// matches the files on disk, otherwise by hashing them. Touches nothing but the disk.
+fn status(torrent: &Torrent, out: &str) -> output::Status {
+    let info = &torrent.info;
+    let piece_count = info.pieces.len();
+    let working = storage::working_path(Path::new(out), Path::new(&format!("{}.part", out)));
+    let resume = ResumeData::load(format!("{}.resume", out));
+    let restored = resume.as_ref().and_then(|resume| {
+        let files = FileState::read_all(&storage::content_paths(&working, info))?;
+        resume.pieces_if_unchanged(&torrent.info_hash(), &files, piece_count)
+    });
+
+    let piece_length = |index: usize| {
+        let range = info.piece_range(index);
+        range.end - range.start
+    };
+    let (missing, source): (Vec<u64>, _) = match restored {
+        Some(pieces) => (
+            (0..piece_count)
+                .filter(|index| !pieces.has(*index))
+                .map(piece_length)
+                .collect(),
+            "resume",
+        ),
+        None if working.exists() => (
+            check::check(&working, info)
+                .iter()
+                .map(|bad| bad.range.end - bad.range.start)
+                .collect(),
+            "recheck",
+        ),
+        None => ((0..piece_count).map(piece_length).collect(), "none"),
+    };
+
+    let remaining = missing.iter().sum::<u64>();
+    let completion = if info.length == 0 {
+        100.0
+    } else {
+        (info.length as u64 - remaining) as f64 * 100.0 / info.length as f64
+    };
+    output::Status {
+        torrent: info.name.clone(),
+        path: working.display().to_string(),
+        pieces: piece_count - missing.len(),
+        piece_count,
+        completion,
+        remaining,
+        uploaded: resume.as_ref().map(|resume| resume.uploaded),
+        downloaded: resume.as_ref().map(|resume| resume.downloaded),
+        ratio: resume
+            .as_ref()
+            .map(|resume| resume.uploaded as f64 / info.length.max(1) as f64),
+        source: source.to_string(),
+    }
+}
+
+/// Runs every torrent in `torrent_files` until interrupted, then prints where each got to.
+fn daemon(torrent_files: Vec<String>, args: DaemonArgs, global: GlobalArgs) -> anyhow::Result<()> {
+    let mut daemon = start_daemon(torrent_files, args, global)?;
+    daemon.shutdown_signal().request_on_ctrl_c();
+    daemon.run();
+
+    let status = output::DaemonStatus {
+        torrents: daemon.session().status(),
+    };
+    output::print(&status, global.json);
+    Ok(())
+}
+
+/// Like `daemon`, showing the dashboard until interrupted or told to quit.
+fn tui(torrent_files: Vec<String>, args: DaemonArgs, global: GlobalArgs) -> anyhow::Result<()> {
+    let mut daemon = start_daemon(torrent_files, args, global)?;
+    let dashboard = tui::spawn(
+        daemon.control(),
+        daemon.events().subscribe(),
+        daemon.shutdown_signal(),
+    );
+    daemon.shutdown_signal().request_on_ctrl_c();
+    daemon.run();
+    dashboard.join().expect("Dashboard thread panicked");
+
+    let status = output::DaemonStatus {
+        torrents: daemon.session().status(),
+    };
+    output::print(&status, global.json);
+    Ok(())
+}
+
+fn start_daemon(
+    torrent_files: Vec<String>,
+    args: DaemonArgs,
+    global: GlobalArgs,
+) -> anyhow::Result<Daemon> {
+    std::fs::create_dir_all(&args.download_dir)
+        .with_context(|| format!("cannot create {}", args.download_dir))?;
+    let feeds_seen = Path::new(&args.download_dir).join(SEEN_FILE);
+    let mut settings = Settings {
+        rate_limit: args.rate_limit,
+        schedule: args.schedule,
+        connection_limits: ConnectionLimits {
+            global: args.max_connections,
+            per_torrent: args.max_connections_per_torrent,
+            half_open: args.max_half_open,
+        },
+        memory_budget: args.memory_budget,
+    };
+    let config = match args.config {
+        Some(path) => {
+            let mut config = ConfigFile::new(path, settings);
+            settings = config.load()?;
+            Some(config)
+        }
+        None => None,
+    };
+    let mut builder = Client::builder()
+        .peer_id(global.peer_id)
+        .listen_port(args.peer.port())
+        .download_dir(args.download_dir)
+        .max_peers(settings.connection_limits.global)
+        .max_peers_per_torrent(settings.connection_limits.per_torrent)
+        .max_half_open(settings.connection_limits.half_open)
+        .rate_limits(settings.bandwidth_schedule())
+        .seed_limits(SeedLimits {
+            ratio: args.seed_ratio,
+            time: args
+                .seed_time
+                .map(|minutes| Duration::from_secs(minutes * 60)),
+        });
+    if let Some(port) = args.peer.dht_port {
+        builder = builder.dht_port(port);
+    }
+    if let Some(command) = args.on_complete {
+        builder = builder.on_complete(command);
+    }
+    if let Some(path) = args.history {
+        builder = builder.history(path);
+    }
+    if let Some(bytes) = settings.memory_budget {
+        builder = builder.memory_budget(bytes);
+    }
+    if let Some(path) = args.peer.ip_filter {
+        builder = builder.ip_filter(open_ip_filter(&path)?);
+    }
+    if !args.geoip.is_empty() {
+        builder = builder.geoip(open_geoip(&args.geoip)?);
+    }
+    let script = args.script.as_deref().map(Script::load).transpose()?;
+    let feeds = args.feeds.as_deref().map(Feeds::load).transpose()?;
+    let mut daemon = Daemon::new(builder.build()?.into_session());
+    if let Some(script) = script {
+        daemon.set_script(script);
+    }
+    if let Some(config) = config {
+        daemon.set_config(config);
+    }
+    if let (Some(addr), Some(token)) = (args.rpc_addr, args.rpc_token) {
+        daemon
+            .serve_rpc(&addr, token)
+            .with_context(|| format!("cannot serve the control API on {}", addr))?;
+    }
+    if let Some(dir) = args.watch_dir {
+        daemon
+            .watch(Path::new(&dir))
+            .with_context(|| format!("cannot watch {}", dir))?;
+    }
+    if let Some(feeds) = feeds {
+        log::info!(
+            "polling {} feeds every {} minutes",
+            feeds.len(),
+            args.feed_interval
+        );
+        let interval = Duration::from_secs(args.feed_interval * 60);
+        daemon.watch_feeds(FeedWatcher::new(feeds, interval, feeds_seen));
+    }
+
+    for torrent_file in torrent_files {
+        if let Err(error) = daemon.session_mut().add(open_torrent(&torrent_file)?) {
+            log::warn!("skipping {}: {}", torrent_file, error);
+        }
+    }
+    Ok(daemon)
+}
+
+// How long `handshake` waits before its first retry.
+const HANDSHAKE_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Handshakes with the peer at `addr` as `peer_id`, trying up to `retries` more times with a
+/// doubling pause between tries. Returns the peer's handshake and how many tries it took.
+fn probe_peer(
+    torrent: &Torrent,
+    addr: SocketAddr,
+    peer_id: [u8; 20],
+    timeout: Duration,
+    retries: u32,
+) -> Result<(Handshake, u32), HandshakeError> {
+    let try_handshake = || {
+        PeerConnection::connect_timeout(addr, torrent.info.pieces.len(), timeout)
+            .map_err(HandshakeError::Connect)
+            .and_then(|peer| peer.handshake(torrent.info_hash(), peer_id, None))
+    };
+    let mut last_error = match try_handshake() {
+        Ok((_, handshake)) => return Ok((handshake, 1)),
+        Err(error) => error,
+    };
+
+    let mut delay = HANDSHAKE_RETRY_DELAY;
+    for attempt in 2..=retries + 1 {
+        log::warn!(peer = addr; "attempt {} failed: {}, retrying in {}s", attempt - 1, last_error, delay.as_secs());
+        thread::sleep(delay);
+        delay *= 2;
+        match try_handshake() {
+            Ok((_, handshake)) => return Ok((handshake, attempt)),
+            Err(error) => last_error = error,
+        }
+    }
+    Err(last_error)
+}
+
+/// Accepts peers for `torrent` on `port` as `peer_id`, returning the peer manager they are
+/// handed to and the port listened on. Without a port, 6881 is tried and then any free port, so
+/// a command that can do without inbound peers is not stopped by another client holding 6881.
+fn start_listener(
+    port: Option<u16>,
+    torrent: &Torrent,
+    ip_filter: Option<String>,
+    peer_id: [u8; 20],
+) -> anyhow::Result<(Arc<Mutex<PeerManager>>, u16)> {
+    let mut peer_manager = PeerManager::new();
+    if let Some(path) = ip_filter {
+        peer_manager.set_ip_filter(open_ip_filter(&path)?);
+    }
+
+    let peer_manager = Arc::new(Mutex::new(peer_manager));
+    let info_hashes = vec![torrent.info_hash()];
+    let mut listener = match port {
+        Some(port) => Listener::bind(port, info_hashes)
+            .with_context(|| format!("cannot listen for peers on port {}", port))?,
+        None => Listener::bind(DEFAULT_PORT, info_hashes.clone())
+            .or_else(|error| {
+                log::warn!("cannot listen on port {}: {}", DEFAULT_PORT, error);
+                Listener::bind(0, info_hashes)
+            })
+            .context("cannot listen for peers")?,
+    };
+    listener.set_peer_id(peer_id);
+    let port = listener.port();
+    log::info!("listening for peers on port {}", port);
+    listener.spawn(peer_manager.clone());
+    Ok((peer_manager, port))
+}
+
+fn open_geoip(paths: &[PathBuf]) -> anyhow::Result<GeoIp> {
+    let geoip = GeoIp::open(paths)?;
+    if !geoip.is_empty() {
+        log::info!("locating peers with {} GeoIP databases", paths.len());
+    }
+    Ok(geoip)
+}
+
+fn open_ip_filter(path: &str) -> anyhow::Result<IpFilter> {
+    let ip_filter = IpFilter::open(path)
+        .map_err(|error| InvalidArgument(format!("cannot load IP filter {}: {}", path, error)))?;
+    log::info!("blocking {} address ranges", ip_filter.len());
+    Ok(ip_filter)
+}
+
+fn print_peer_summary(peer: &PeerConnection, peer_manager: &Mutex<PeerManager>) {
+    eprintln!("{}: {}", peer.addr(), peer.stats());
+    let peer_manager = peer_manager.lock().expect("Peer manager lock poisoned");
+    eprintln!("inbound peers: {}", peer_manager.inbound().len());
+    eprintln!("dht nodes learned: {}", peer_manager.dht_nodes().len());
 }
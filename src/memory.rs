@@ -0,0 +1,183 @@
+//! Accounting for the memory torrents hold, so a session with many torrents stays within a
+//! budget. Piece buffers, the piece cache and torrent metadata each take a [`Charge`] against a
+//! shared [`MemoryBudget`], and while the budget is exceeded downloads start no new pieces and
+//! caches shrink, until enough has been written out or evicted.
+
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+use serde::{Deserialize, Serialize};
+
+// Stands for no limit.
+const UNLIMITED: u64 = u64::MAX;
+
+/// What memory is held for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryKind {
+    /// Pieces being downloaded, verified and written to disk.
+    PieceBuffers,
+    /// Pieces kept to serve peers from.
+    PieceCache,
+    /// Torrents' info dictionaries, mostly their piece hashes.
+    Metadata,
+}
+
+/// A limit on memory shared by everything charged to it. Clones share the same accounts.
+#[derive(Debug, Clone)]
+pub struct MemoryBudget {
+    accounts: Arc<Accounts>,
+}
+
+#[derive(Debug)]
+struct Accounts {
+    limit: AtomicU64,
+    // Bytes held, by `MemoryKind`.
+    held: [AtomicU64; 3],
+}
+
+/// Bytes held against a budget, given back when dropped.
+#[derive(Debug)]
+pub struct Charge {
+    accounts: Arc<Accounts>,
+    kind: MemoryKind,
+    bytes: u64,
+}
+
+/// How much memory is held, by what.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MemoryUsage {
+    pub piece_buffers: u64,
+    pub piece_cache: u64,
+    pub metadata: u64,
+    /// The budget in bytes, if there is one.
+    pub limit: Option<u64>,
+}
+
+impl MemoryUsage {
+    pub fn held(&self) -> u64 {
+        self.piece_buffers + self.piece_cache + self.metadata
+    }
+}
+
+impl Default for MemoryBudget {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+
+impl MemoryBudget {
+    /// A budget of `limit` bytes, or one that is never exceeded.
+    pub fn new(limit: Option<u64>) -> Self {
+        Self {
+            accounts: Arc::new(Accounts {
+                limit: AtomicU64::new(limit.unwrap_or(UNLIMITED)),
+                held: Default::default(),
+            }),
+        }
+    }
+
+    /// Changes the limit for everything charged to the budget.
+    pub fn set_limit(&self, limit: Option<u64>) {
+        self.accounts
+            .limit
+            .store(limit.unwrap_or(UNLIMITED), Ordering::Relaxed);
+    }
+
+    pub fn limit(&self) -> Option<u64> {
+        Some(self.accounts.limit.load(Ordering::Relaxed)).filter(|limit| *limit != UNLIMITED)
+    }
+
+    /// Holds `bytes` of `kind` against the budget until the charge is dropped.
+    pub fn charge(&self, kind: MemoryKind, bytes: u64) -> Charge {
+        let mut charge = Charge {
+            accounts: self.accounts.clone(),
+            kind,
+            bytes: 0,
+        };
+        charge.set(bytes);
+        charge
+    }
+
+    /// Whether more is held than the limit allows.
+    pub fn is_exceeded(&self) -> bool {
+        self.accounts.is_exceeded()
+    }
+
+    pub fn usage(&self) -> MemoryUsage {
+        let held = |kind: MemoryKind| self.accounts.held[kind as usize].load(Ordering::Relaxed);
+        MemoryUsage {
+            piece_buffers: held(MemoryKind::PieceBuffers),
+            piece_cache: held(MemoryKind::PieceCache),
+            metadata: held(MemoryKind::Metadata),
+            limit: self.limit(),
+        }
+    }
+}
+
+impl Charge {
+    /// Holds `bytes` instead of what was held before.
+    pub fn set(&mut self, bytes: u64) {
+        let held = &self.accounts.held[self.kind as usize];
+        if bytes > self.bytes {
+            held.fetch_add(bytes - self.bytes, Ordering::Relaxed);
+        } else {
+            held.fetch_sub(self.bytes - bytes, Ordering::Relaxed);
+        }
+        self.bytes = bytes;
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    /// Whether the budget charged is exceeded.
+    pub fn is_exceeded(&self) -> bool {
+        self.accounts.is_exceeded()
+    }
+}
+
+impl Accounts {
+    fn is_exceeded(&self) -> bool {
+        let held = self
+            .held
+            .iter()
+            .map(|held| held.load(Ordering::Relaxed))
+            .sum::<u64>();
+        held > self.limit.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for Charge {
+    fn drop(&mut self) {
+        self.set(0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MemoryBudget, MemoryKind};
+
+    #[test]
+    fn charges_are_held_until_dropped() {
+        let budget = MemoryBudget::new(Some(100));
+        let mut buffers = budget.charge(MemoryKind::PieceBuffers, 60);
+        let cache = budget.charge(MemoryKind::PieceCache, 30);
+        assert_eq!(budget.usage().held(), 90);
+        assert!(!budget.is_exceeded());
+
+        buffers.set(80);
+        assert!(budget.is_exceeded() && cache.is_exceeded());
+        drop(cache);
+        assert_eq!(budget.usage().piece_cache, 0);
+        assert!(!budget.is_exceeded());
+
+        budget.set_limit(Some(50));
+        assert!(budget.is_exceeded());
+        budget.set_limit(None);
+        assert!(!budget.is_exceeded());
+        drop(buffers);
+        assert_eq!(budget.usage().held(), 0);
+    }
+}
@@ -0,0 +1,194 @@
+use std::collections::HashMap;
+
+use sha1::{Digest, Sha1};
+
+use crate::{
+    bencode::{Bencode, Value},
+    extension::UT_METADATA_ID,
+    peer::{HandshakeError, Handshaked, PeerConnection, PeerError},
+    torrent::TorrentError,
+    wire::MessageId,
+};
+
+/// The info dictionary is sent in pieces of this size, all but the last of them full.
+pub const METADATA_PIECE_SIZE: usize = 16 * 1024;
+// Refuse info dictionaries larger than this rather than trusting a peer's claimed size.
+const MAX_METADATA_SIZE: usize = 64 * 1024 * 1024;
+
+/// A `ut_metadata` message (BEP 9), used to fetch a torrent's info dictionary from peers when
+/// all we have is its info hash.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MetadataMessage {
+    Request {
+        piece: usize,
+    },
+    Data {
+        piece: usize,
+        total_size: usize,
+        data: Vec<u8>,
+    },
+    Reject {
+        piece: usize,
+    },
+}
+
+impl MetadataMessage {
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let (msg_type, piece) = match self {
+            MetadataMessage::Request { piece } => (0, piece),
+            MetadataMessage::Data { piece, .. } => (1, piece),
+            MetadataMessage::Reject { piece } => (2, piece),
+        };
+
+        let mut hash_map = HashMap::new();
+        hash_map.insert("msg_type".to_string(), Value::Number(msg_type));
+        hash_map.insert("piece".to_string(), Value::Number(*piece as i64));
+        if let MetadataMessage::Data { total_size, .. } = self {
+            hash_map.insert("total_size".to_string(), Value::Number(*total_size as i64));
+        }
+
+        let mut bytes = Bencode::encode(&Value::Dictionary(hash_map));
+        if let MetadataMessage::Data { data, .. } = self {
+            bytes.extend(data);
+        }
+        bytes
+    }
+
+    /// Reads a message, whose piece data, if any, follows the bencoded dictionary. `None` if
+    /// it is not one we know.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let mut bencode = Bencode::new(bytes);
+        let Ok(Value::Dictionary(hash_map)) = bencode.decode() else {
+            return None;
+        };
+        let number = |key: &str| match hash_map.get(key) {
+            Some(Value::Number(number)) if *number >= 0 => Some(*number as usize),
+            _ => None,
+        };
+
+        let piece = number("piece")?;
+        match number("msg_type")? {
+            0 => Some(MetadataMessage::Request { piece }),
+            1 => Some(MetadataMessage::Data {
+                piece,
+                total_size: number("total_size")?,
+                data: bytes[bencode.position()..].to_vec(),
+            }),
+            2 => Some(MetadataMessage::Reject { piece }),
+            _ => None,
+        }
+    }
+}
+
+/// Fetches the info dictionary for `info_hash` from a peer we have handshaken with, piece by
+/// piece, and checks it hashes to `info_hash`.
+pub fn fetch(
+    peer: &mut PeerConnection<Handshaked>,
+    info_hash: &[u8; 20],
+) -> Result<Vec<u8>, MetadataError> {
+    let size = peer
+        .receive_extension_handshake()?
+        .and_then(|extensions| extensions.metadata_size)
+        .ok_or(MetadataError::Unsupported)?;
+    if size > MAX_METADATA_SIZE {
+        return Err(MetadataError::Size(size));
+    }
+
+    // Grown as pieces arrive rather than to the size the peer claims.
+    let mut metadata = Vec::new();
+    for piece in 0..size.div_ceil(METADATA_PIECE_SIZE) {
+        let request = MetadataMessage::Request { piece };
+        if !peer.send_extended("ut_metadata", &request.as_bytes())? {
+            return Err(MetadataError::Unsupported);
+        }
+
+        loop {
+            let message = peer.read_message()?;
+            let Some((&UT_METADATA_ID, payload)) = message
+                .payload
+                .split_first()
+                .filter(|_| message.id == MessageId::Extended)
+            else {
+                continue;
+            };
+            match MetadataMessage::from_bytes(payload) {
+                Some(MetadataMessage::Data {
+                    piece: received,
+                    data,
+                    ..
+                }) if received == piece => {
+                    if data.len() > METADATA_PIECE_SIZE || metadata.len() + data.len() > size {
+                        return Err(MetadataError::Size(metadata.len() + data.len()));
+                    }
+                    metadata.extend(data);
+                    break;
+                }
+                Some(MetadataMessage::Reject { piece: rejected }) if rejected == piece => {
+                    return Err(MetadataError::Rejected(piece));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    if metadata.len() != size {
+        return Err(MetadataError::Size(metadata.len()));
+    }
+    if Sha1::digest(&metadata).as_slice() != info_hash {
+        return Err(MetadataError::InfoHash);
+    }
+    Ok(metadata)
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum MetadataError {
+    #[error(transparent)]
+    Handshake(#[from] HandshakeError),
+    #[error(transparent)]
+    Peer(#[from] PeerError),
+    #[error("peer does not send metadata")]
+    Unsupported,
+    #[error("peer rejected our request for metadata piece {0}")]
+    Rejected(usize),
+    #[error("peer sent metadata of an unexpected size ({0} bytes)")]
+    Size(usize),
+    #[error("metadata does not match the info hash")]
+    InfoHash,
+    #[error("peer sent unusable metadata: {0}")]
+    Info(#[from] TorrentError),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MetadataMessage;
+
+    #[test]
+    fn round_trips_metadata_messages() {
+        let request = MetadataMessage::Request { piece: 1 };
+        assert_eq!(request.as_bytes(), b"d8:msg_typei0e5:piecei1ee");
+        assert_eq!(
+            MetadataMessage::from_bytes(&request.as_bytes()),
+            Some(request)
+        );
+
+        let data = MetadataMessage::Data {
+            piece: 0,
+            total_size: 5,
+            data: b"d1:ae".to_vec(),
+        };
+        assert_eq!(
+            data.as_bytes(),
+            b"d8:msg_typei1e5:piecei0e10:total_sizei5eed1:ae"
+        );
+        assert_eq!(MetadataMessage::from_bytes(&data.as_bytes()), Some(data));
+
+        assert_eq!(
+            MetadataMessage::from_bytes(b"d8:msg_typei2e5:piecei3ee"),
+            Some(MetadataMessage::Reject { piece: 3 })
+        );
+        assert_eq!(
+            MetadataMessage::from_bytes(b"d8:msg_typei7e5:piecei0ee"),
+            None
+        );
+    }
+}
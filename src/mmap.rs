@@ -0,0 +1,161 @@
+use std::{
+    fs::{File, OpenOptions},
+    io,
+    os::{
+        fd::AsRawFd,
+        raw::{c_int, c_void},
+    },
+    path::Path,
+    ptr, slice,
+};
+
+use crate::storage::Storage;
+
+const PROT_READ: c_int = 1;
+const PROT_WRITE: c_int = 2;
+const MAP_SHARED: c_int = 1;
+#[cfg(target_os = "linux")]
+const MS_SYNC: c_int = 4;
+#[cfg(not(target_os = "linux"))]
+const MS_SYNC: c_int = 0x10;
+
+extern "C" {
+    fn mmap(
+        addr: *mut c_void,
+        len: usize,
+        prot: c_int,
+        flags: c_int,
+        fd: c_int,
+        offset: i64,
+    ) -> *mut c_void;
+    fn munmap(addr: *mut c_void, len: usize) -> c_int;
+    fn msync(addr: *mut c_void, len: usize, flags: c_int) -> c_int;
+}
+
+/// Maps the whole output file into memory, so writing a block is a copy rather than a seek and
+/// a write, and reads for seeding come straight from the page cache.
+pub struct MmapStorage {
+    // Kept open for as long as the mapping exists.
+    _file: File,
+    map: *mut u8,
+    length: usize,
+    piece_length: usize,
+}
+
+// The mapping is only reached through `&mut self`, so moving it between threads is fine.
+unsafe impl Send for MmapStorage {}
+
+impl MmapStorage {
+    /// Opens the file at `path`, creating it if needed and sizing it to hold `length` bytes,
+    /// and maps it.
+    pub fn open<P: AsRef<Path>>(path: P, piece_length: usize, length: usize) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        file.set_len(length as u64)?;
+
+        // An empty mapping is an error, and there is nothing to map anyway.
+        let map = if length == 0 {
+            ptr::NonNull::dangling().as_ptr()
+        } else {
+            let map = unsafe {
+                mmap(
+                    ptr::null_mut(),
+                    length,
+                    PROT_READ | PROT_WRITE,
+                    MAP_SHARED,
+                    file.as_raw_fd(),
+                    0,
+                )
+            };
+            if map as isize == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            map as *mut u8
+        };
+
+        Ok(Self {
+            _file: file,
+            map,
+            length,
+            piece_length,
+        })
+    }
+
+    fn contents(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.map, self.length) }
+    }
+
+    fn range(&self, piece_index: usize, begin: usize, length: usize) -> io::Result<(usize, usize)> {
+        let start = piece_index * self.piece_length + begin;
+        if start + length > self.length {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "block runs past the end of the torrent",
+            ));
+        }
+        Ok((start, start + length))
+    }
+}
+
+impl Storage for MmapStorage {
+    fn write_block(&mut self, piece_index: usize, begin: usize, data: &[u8]) -> io::Result<()> {
+        let (start, end) = self.range(piece_index, begin, data.len())?;
+        self.contents()[start..end].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn read_block(
+        &mut self,
+        piece_index: usize,
+        begin: usize,
+        length: usize,
+    ) -> io::Result<Vec<u8>> {
+        let (start, end) = self.range(piece_index, begin, length)?;
+        Ok(self.contents()[start..end].to_vec())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        if self.length == 0 {
+            return Ok(());
+        }
+        if unsafe { msync(self.map as *mut c_void, self.length, MS_SYNC) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+impl Drop for MmapStorage {
+    fn drop(&mut self) {
+        if self.length > 0 {
+            unsafe { munmap(self.map as *mut c_void, self.length) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MmapStorage;
+    use crate::storage::Storage;
+
+    #[test]
+    fn writes_blocks_through_the_mapping() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("payload");
+        let mut storage = MmapStorage::open(&path, 8, 12).unwrap();
+        storage.write_block(1, 0, &[2; 4]).unwrap();
+        storage.write_block(0, 0, &[1; 8]).unwrap();
+        assert!(storage.write_block(1, 2, &[3; 4]).is_err());
+        storage.sync().unwrap();
+
+        assert_eq!(storage.read_block(0, 6, 4).unwrap(), [1, 1, 2, 2]);
+        assert_eq!(
+            std::fs::read(&path).unwrap(),
+            [&[1; 8][..], &[2; 4]].concat()
+        );
+    }
+}
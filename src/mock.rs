@@ -0,0 +1,377 @@
+//! Stand-ins for a tracker and a peer, served on loopback so tests can run whole downloads
+//! without anything outside the process. The tracker hands out a fixed list of peers and
+//! remembers every announce; the peer serves a known payload, and can be scripted to choke us,
+//! send corrupt pieces or hang up partway through.
+
+use std::{
+    collections::{HashMap, HashSet},
+    io::{self, BufReader, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use sha1::{Digest, Sha1};
+
+use crate::{
+    bencode::{Bencode, Value},
+    http_server,
+    torrent::{Info, Torrent},
+    wire::{BlockRequest, Handshake, Message, MessageId},
+    tracker_server::{parse_query, write_response},
+};
+
+/// How long a scripted choke lasts before the peer unchokes us again.
+const CHOKE_PAUSE: Duration = Duration::from_millis(50);
+
+/// A torrent of `payload` in pieces of `piece_length`, announcing to `announce`.
+pub fn torrent(payload: &[u8], piece_length: usize, announce: &str) -> Torrent {
+    let info = Info {
+        length: payload.len(),
+        name: "payload".to_string(),
+        piece_length,
+        pieces: payload
+            .chunks(piece_length)
+            .map(|piece| Sha1::digest(piece).into())
+            .collect(),
+        files: vec![],
+        private: false,
+    };
+    let info_hash = info.hash();
+    Torrent::new(announce.to_string(), vec![], info, info_hash)
+}
+
+/// An announce the tracker received.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Announce {
+    pub info_hash: [u8; 20],
+    pub port: u16,
+    pub left: u64,
+    pub event: Option<String>,
+}
+
+/// A tracker answering every announce, for any torrent, with the same peers.
+pub struct MockTracker {
+    addr: SocketAddr,
+    announces: Arc<Mutex<Vec<Announce>>>,
+}
+
+impl MockTracker {
+    pub fn serve(peers: Vec<SocketAddr>) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let announces = Arc::new(Mutex::new(Vec::new()));
+
+        let recorded = announces.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                // Clients that give up early are no concern of the test.
+                let _ = answer(stream, &peers, &recorded);
+            }
+        });
+        Self { addr, announces }
+    }
+
+    pub fn url(&self) -> String {
+        format!("http://{}/announce", self.addr)
+    }
+
+    /// Every announce so far, oldest first.
+    pub fn announces(&self) -> Vec<Announce> {
+        self.announces.lock().unwrap().clone()
+    }
+}
+
+fn answer(
+    mut stream: TcpStream,
+    peers: &[SocketAddr],
+    announces: &Mutex<Vec<Announce>>,
+) -> io::Result<()> {
+    let target = http_server::read_head(&mut BufReader::new(stream.try_clone()?))?.target;
+    let (_, query) = target.split_once('?').unwrap_or((&target, ""));
+    let query = parse_query(query).into_iter().collect::<HashMap<_, _>>();
+    let number =
+        |key: &str| -> Option<u64> { String::from_utf8_lossy(query.get(key)?).parse().ok() };
+    announces.lock().unwrap().push(Announce {
+        info_hash: query["info_hash"].as_slice().try_into().unwrap(),
+        port: number("port").unwrap() as u16,
+        left: number("left").unwrap(),
+        event: query
+            .get("event")
+            .map(|event| String::from_utf8_lossy(event).into_owned()),
+    });
+
+    let mut compact = Vec::new();
+    for peer in peers {
+        if let SocketAddr::V4(peer) = peer {
+            compact.extend(peer.ip().octets());
+            compact.extend(peer.port().to_be_bytes());
+        }
+    }
+    let body = Bencode::encode(&Value::Dictionary(HashMap::from([
+        ("interval".to_string(), Value::Number(1800)),
+        ("peers".to_string(), Value::Blob(compact)),
+    ])));
+    write_response(&mut stream, "200 OK", &body)
+}
+
+/// A seeder with every piece of a payload, following a script. Each leecher that connects gets
+/// the handshake, a full bitfield and an unchoke once it is interested, then whatever blocks it
+/// asks for.
+pub struct MockPeer {
+    payload: Arc<Vec<u8>>,
+    info_hash: [u8; 20],
+    piece_length: usize,
+    peer_id: [u8; 20],
+    choke_after: Option<usize>,
+    hang_up_after: Option<usize>,
+    stall_after: Option<usize>,
+    corrupt: HashSet<usize>,
+}
+
+impl MockPeer {
+    /// A peer serving `payload` as the content of `torrent`.
+    pub fn new(torrent: &Torrent, payload: Vec<u8>) -> Self {
+        Self {
+            payload: Arc::new(payload),
+            info_hash: torrent.info_hash_bytes(),
+            piece_length: torrent.info.piece_length,
+            peer_id: [7; 20],
+            choke_after: None,
+            hang_up_after: None,
+            stall_after: None,
+            corrupt: HashSet::new(),
+        }
+    }
+
+    /// Chokes the leecher briefly once it has sent `blocks` blocks, answering what it asks for
+    /// in the meantime after the unchoke.
+    pub fn choke_after(mut self, blocks: usize) -> Self {
+        self.choke_after = Some(blocks);
+        self
+    }
+
+    /// Closes the connection once it has sent `blocks` blocks, so straight after the bitfield
+    /// for none.
+    pub fn hang_up_after(mut self, blocks: usize) -> Self {
+        self.hang_up_after = Some(blocks);
+        self
+    }
+
+    /// Ignores every request once it has sent `blocks` blocks, while keeping the connection
+    /// open.
+    pub fn stall_after(mut self, blocks: usize) -> Self {
+        self.stall_after = Some(blocks);
+        self
+    }
+
+    /// Flips every byte of piece `index` the first time each leecher is sent it.
+    pub fn corrupt_piece(mut self, index: usize) -> Self {
+        self.corrupt.insert(index);
+        self
+    }
+
+    /// Accepts leechers until the test ends, serving each on its own thread. Returns the
+    /// address to connect to.
+    pub fn spawn(self) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let peer = Arc::new(self);
+        thread::spawn(move || {
+            for socket in listener.incoming().flatten() {
+                let peer = peer.clone();
+                thread::spawn(move || {
+                    // Leechers hanging up is how every session ends.
+                    let _ = peer.serve(socket);
+                });
+            }
+        });
+        addr
+    }
+
+    fn serve(&self, mut socket: TcpStream) -> io::Result<()> {
+        let mut handshake = [0; 68];
+        socket.read_exact(&mut handshake)?;
+        if Handshake::decode(handshake).info_hash != self.info_hash {
+            return Ok(());
+        }
+        let reply = Handshake::new(
+            "BitTorrent protocol".to_string(),
+            self.info_hash,
+            self.peer_id,
+        );
+        socket.write_all(&reply.encode())?;
+
+        let piece_count = self.payload.len().div_ceil(self.piece_length);
+        let mut bitfield = vec![0xff; piece_count.div_ceil(8)];
+        if !piece_count.is_multiple_of(8) {
+            *bitfield.last_mut().unwrap() <<= 8 - piece_count % 8;
+        }
+        socket.write_all(&Message::new(MessageId::Bitfield, bitfield).encode())?;
+
+        let mut sent = 0;
+        // Bytes of each corrupt piece sent so far, to know when it has been sent whole.
+        let mut corrupted = HashMap::new();
+        loop {
+            if self.hang_up_after == Some(sent) {
+                return Ok(());
+            }
+            let Some(message) =
+                Message::read_from_socket(&mut socket).map_err(|_| io::ErrorKind::InvalidData)?
+            else {
+                continue;
+            };
+            match message.id {
+                MessageId::Interested => {
+                    socket.write_all(&Message::new(MessageId::Unchoke, vec![]).encode())?
+                }
+                MessageId::Request if self.stall_after == Some(sent) => {}
+                MessageId::Request => {
+                    let Some(request) = BlockRequest::decode(&message.payload) else {
+                        return Ok(());
+                    };
+                    let index = request.index as usize;
+                    let start = index * self.piece_length + request.begin as usize;
+                    let mut block = self.payload[start..start + request.length as usize].to_vec();
+                    if self.corrupt.contains(&index) {
+                        let corrupted = corrupted.entry(index).or_insert(0);
+                        if *corrupted < self.piece_length {
+                            block.iter_mut().for_each(|byte| *byte = !*byte);
+                        }
+                        *corrupted += block.len();
+                    }
+
+                    let piece = Message::piece(request.index, request.begin, &block);
+                    socket.write_all(&piece.encode())?;
+                    sent += 1;
+
+                    if self.choke_after == Some(sent) {
+                        socket.write_all(&Message::new(MessageId::Choke, vec![]).encode())?;
+                        thread::sleep(CHOKE_PAUSE);
+                        socket.write_all(&Message::new(MessageId::Unchoke, vec![]).encode())?;
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::{torrent, MockPeer, MockTracker};
+    use crate::{
+        coordinator::DownloadCoordinator, peer::PeerConnection, peer_manager::PeerManager,
+        storage::MemoryStorage, torrent::Torrent,
+    };
+
+    const PIECE_LENGTH: usize = 32 * 1024;
+
+    fn payload() -> Vec<u8> {
+        (0..PIECE_LENGTH * 3 + 500)
+            .map(|i| (i * 7 % 256) as u8)
+            .collect()
+    }
+
+    fn coordinator(torrent: Torrent) -> DownloadCoordinator {
+        DownloadCoordinator::new(torrent, 6000, Arc::new(Mutex::new(PeerManager::new())))
+    }
+
+    #[test]
+    fn downloads_from_a_peer_the_tracker_hands_out() {
+        let payload = payload();
+        let seeder = MockPeer::new(&torrent(&payload, PIECE_LENGTH, ""), payload.clone()).spawn();
+        let tracker = MockTracker::serve(vec![seeder]);
+        let torrent = torrent(&payload, PIECE_LENGTH, &tracker.url());
+        let info_hash = torrent.info_hash_bytes();
+
+        let mut coordinator = coordinator(torrent);
+        let peer = coordinator.connect(&[]).unwrap();
+        let (mut peer, handshake) = coordinator.handshake(peer).unwrap();
+        assert_eq!(handshake.peer_id, [7; 20]);
+        assert_eq!(peer.addr(), seeder);
+
+        let mut storage = MemoryStorage::new(PIECE_LENGTH, payload.len());
+        coordinator
+            .download_all_pieces(&mut peer, &mut storage)
+            .unwrap();
+        coordinator.close(Some(&mut peer), &mut storage).unwrap();
+        assert!(coordinator.is_complete());
+        assert_eq!(storage.contents(), payload);
+
+        let announces = tracker.announces();
+        assert_eq!(announces.len(), 2);
+        assert!(announces
+            .iter()
+            .all(|announce| announce.info_hash == info_hash));
+        assert!(announces.iter().all(|announce| announce.port == 6000));
+        assert_eq!(announces[0].left, payload.len() as u64);
+        assert_eq!(announces[0].event, None);
+        assert_eq!(announces[1].left, 0);
+        assert_eq!(announces[1].event.as_deref(), Some("stopped"));
+    }
+
+    #[test]
+    fn refuses_a_handshake_for_another_torrent() {
+        let payload = payload();
+        let other = torrent(&payload[1..], PIECE_LENGTH, "");
+        let seeder = MockPeer::new(&other, payload.clone()).spawn();
+
+        let torrent = torrent(&payload, PIECE_LENGTH, "");
+        let piece_count = torrent.info.pieces.len();
+        let mut coordinator = coordinator(torrent);
+        let peer = PeerConnection::connect(seeder, piece_count).unwrap();
+        assert!(coordinator.handshake(peer).is_err());
+    }
+
+    #[test]
+    fn carries_on_after_a_choke_partway_through_a_piece() {
+        let payload = payload();
+        let torrent = torrent(&payload, PIECE_LENGTH, "");
+        let piece_count = torrent.info.pieces.len();
+        let seeder = MockPeer::new(&torrent, payload.clone())
+            .choke_after(1)
+            .spawn();
+
+        let mut coordinator = coordinator(torrent);
+        let peer = PeerConnection::connect(seeder, piece_count).unwrap();
+        let (mut peer, _) = coordinator.handshake(peer).unwrap();
+        let mut storage = MemoryStorage::new(PIECE_LENGTH, payload.len());
+        coordinator
+            .download_all_pieces(&mut peer, &mut storage)
+            .unwrap();
+
+        assert!(coordinator.is_complete());
+        assert_eq!(storage.contents(), payload);
+        assert_eq!(peer.stats().bytes_downloaded, payload.len() as u64);
+    }
+
+    #[test]
+    fn fetches_a_corrupt_piece_again_and_verifies_it() {
+        let payload = payload();
+        let torrent = torrent(&payload, PIECE_LENGTH, "");
+        let piece_count = torrent.info.pieces.len();
+        let seeder = MockPeer::new(&torrent, payload.clone())
+            .corrupt_piece(1)
+            .spawn();
+
+        let mut coordinator = coordinator(torrent);
+        let peer = PeerConnection::connect(seeder, piece_count).unwrap();
+        let (mut peer, _) = coordinator.handshake(peer).unwrap();
+        let mut storage = MemoryStorage::new(PIECE_LENGTH, payload.len());
+        coordinator
+            .download_all_pieces(&mut peer, &mut storage)
+            .unwrap();
+
+        assert!(coordinator.is_complete());
+        assert_eq!(storage.contents(), payload);
+        assert_eq!(peer.stats().hash_fails, 1);
+        assert_eq!(
+            peer.stats().bytes_downloaded,
+            (payload.len() + PIECE_LENGTH) as u64
+        );
+    }
+}
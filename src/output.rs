@@ -0,0 +1,791 @@
+//! What each subcommand prints, as text for people or, with `--json`, as JSON for scripts. Field
+//! names are part of the JSON interface, so rename them only with care.
+
+use std::{collections::BTreeMap, fmt::Display};
+
+use serde::Serialize;
+
+use bittorrent_starter_rust::{
+    check::Problem,
+    doctor::{self, Finding},
+    geoip::Location,
+    history::{Completion, DailyTotal},
+    progress::format_bytes,
+    session::TorrentStatus,
+    tracker_check::{HttpCheck, TrackerCheck},
+};
+
+/// Prints `output` to stdout as JSON or as text.
+pub fn print<T: Serialize + Display>(output: &T, json: bool) {
+    if json {
+        println!("{}", to_json(output));
+    } else {
+        println!("{}", output);
+    }
+}
+
+pub fn to_json<T: Serialize>(output: &T) -> String {
+    serde_json::to_string(output).expect("Failed to serialize output")
+}
+
+#[derive(Debug, Serialize)]
+pub struct TorrentInfo {
+    pub tracker_url: String,
+    pub length: usize,
+    pub info_hash: String,
+    pub piece_length: usize,
+    pub piece_hashes: Vec<String>,
+}
+
+impl Display for TorrentInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Tracker URL: {}", self.tracker_url)?;
+        writeln!(f, "Length: {}", self.length)?;
+        writeln!(f, "Info Hash: {}", self.info_hash)?;
+        writeln!(f, "Piece Length: {}", self.piece_length)?;
+        write!(f, "Piece Hashes:")?;
+        for hash in &self.piece_hashes {
+            write!(f, "\n{}", hash)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Files {
+    pub files: Vec<FileListing>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FileListing {
+    pub path: String,
+    pub length: u64,
+    /// The pieces holding the file, absent for an empty file.
+    pub first_piece: Option<usize>,
+    pub last_piece: Option<usize>,
+    /// The percentage of the file on disk and verified, absent when there is no local data.
+    pub completion: Option<f64>,
+}
+
+impl Display for Files {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let lines = self.files.iter().map(|file| {
+            let mut line = format!("{}: {} bytes", file.path, file.length);
+            if let (Some(first), Some(last)) = (file.first_piece, file.last_piece) {
+                line.push_str(&format!(", pieces {}-{}", first, last));
+            }
+            if let Some(completion) = file.completion {
+                line.push_str(&format!(", {:.1}% complete", completion));
+            }
+            line
+        });
+        write!(f, "{}", lines.collect::<Vec<_>>().join("\n"))
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Pieces {
+    pub pieces: Vec<PieceListing>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PieceListing {
+    pub index: usize,
+    /// The piece's SHA-1, in hex.
+    pub hash: String,
+    pub length: u64,
+    /// The parts of files the piece holds, in content order.
+    pub files: Vec<PieceFile>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PieceFile {
+    pub path: String,
+    /// The byte range within the file, end exclusive.
+    pub start: u64,
+    pub end: u64,
+}
+
+impl Display for Pieces {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let index_width = self.pieces.len().saturating_sub(1).to_string().len();
+        let length_width = self
+            .pieces
+            .iter()
+            .map(|piece| piece.length.to_string().len())
+            .max()
+            .unwrap_or(0);
+        let lines = self.pieces.iter().map(|piece| {
+            let files = piece
+                .files
+                .iter()
+                .map(|file| format!("{} [{}..{})", file.path, file.start, file.end))
+                .collect::<Vec<_>>();
+            format!(
+                "{:>index_width$}  {}  {:>length_width$}  {}",
+                piece.index,
+                piece.hash,
+                piece.length,
+                files.join(", ")
+            )
+        });
+        write!(f, "{}", lines.collect::<Vec<_>>().join("\n"))
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct TrackerTest {
+    pub trackers: Vec<TrackerCheck>,
+}
+
+impl Display for TrackerTest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let reports = self.trackers.iter().map(|tracker| {
+            let mut lines = vec![tracker.url.clone()];
+            if let Some(dns_ms) = tracker.dns_ms {
+                lines.push(format!(
+                    "  DNS: {} ({} ms)",
+                    tracker.addresses.join(", "),
+                    dns_ms
+                ));
+            }
+            if let Some(connect_ms) = tracker.connect_ms {
+                lines.push(format!("  Connect: {} ms", connect_ms));
+            }
+            for (name, check) in [("Announce", &tracker.announce), ("Scrape", &tracker.scrape)] {
+                if let Some(check) = check {
+                    lines.push(format!("  {}: {}", name, describe_http_check(check)));
+                }
+            }
+            if let Some(error) = &tracker.error {
+                lines.push(format!("  Error: {}", error));
+            }
+            lines.join("\n")
+        });
+        write!(f, "{}", reports.collect::<Vec<_>>().join("\n"))
+    }
+}
+
+fn describe_http_check(check: &HttpCheck) -> String {
+    let mut parts = Vec::new();
+    match check.status {
+        Some(status) => parts.push(format!("HTTP {} in {} ms", status, check.milliseconds)),
+        None => parts.push(format!("no response after {} ms", check.milliseconds)),
+    }
+    if check.status.is_some() {
+        parts.push(
+            if check.bencode {
+                "valid bencode"
+            } else {
+                "not bencode"
+            }
+            .to_string(),
+        );
+    }
+    if let Some(peers) = check.peers {
+        parts.push(format!("{} peers", peers));
+    }
+    if let Some(seeders) = check.seeders {
+        parts.push(format!("{} seeders", seeders));
+    }
+    if let Some(leechers) = check.leechers {
+        parts.push(format!("{} leechers", leechers));
+    }
+    if let Some(failure) = &check.failure {
+        parts.push(failure.clone());
+    }
+    parts.join(", ")
+}
+
+#[derive(Debug, Serialize)]
+pub struct DhtResponse {
+    /// The node asked.
+    pub node: String,
+    /// The id the node answered with, in hex.
+    pub id: String,
+    pub milliseconds: u128,
+    pub nodes: Vec<DhtNode>,
+    /// Peers as `ip:port`, from `get_peers`.
+    pub peers: Vec<String>,
+    /// The announce token, in hex, from `get_peers`.
+    pub token: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DhtNode {
+    pub id: String,
+    pub addr: String,
+}
+
+impl Display for DhtResponse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} answered in {} ms with id {}",
+            self.node, self.milliseconds, self.id
+        )?;
+        if let Some(token) = &self.token {
+            write!(f, "\nToken: {}", token)?;
+        }
+        for node in &self.nodes {
+            write!(f, "\nNode: {} {}", node.id, node.addr)?;
+        }
+        for peer in &self.peers {
+            write!(f, "\nPeer: {}", peer)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Swarm {
+    pub info_hash: String,
+    /// Every distinct peer the DHT gave, as `ip:port`.
+    pub peers: Vec<String>,
+    /// The nodes that gave peers, as `ip:port`.
+    pub storing: Vec<String>,
+    /// How many nodes were asked, and how many answered.
+    pub queried: usize,
+    pub answered: usize,
+    pub milliseconds: u128,
+}
+
+impl Display for Swarm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Info Hash: {}", self.info_hash)?;
+        writeln!(f, "Peers: {}", self.peers.len())?;
+        writeln!(f, "Nodes storing the hash: {}", self.storing.len())?;
+        write!(
+            f,
+            "Nodes answering: {} of {} asked, in {:.1} s",
+            self.answered,
+            self.queried,
+            self.milliseconds as f64 / 1000.0
+        )
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Peers {
+    /// Each peer as `ip:port`.
+    pub peers: Vec<String>,
+    /// Where peers are, by `ip:port`, for those the GeoIP databases know.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub locations: BTreeMap<String, Location>,
+    pub tracker: String,
+    /// Seconds until the tracker wants us back, and its counts of the swarm, when it says.
+    pub interval: Option<u64>,
+    pub seeders: Option<u64>,
+    pub leechers: Option<u64>,
+    /// Whether the text form also shows the tracker's counts, which otherwise only JSON has.
+    #[serde(skip)]
+    pub verbose: bool,
+}
+
+impl Display for Peers {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let peers = self
+            .peers
+            .iter()
+            .map(|peer| match self.locations.get(peer) {
+                Some(location) => format!("{:<24} {}", peer, location),
+                None => peer.clone(),
+            })
+            .collect::<Vec<_>>();
+        write!(f, "{}", peers.join("\n"))?;
+        if !self.verbose {
+            return Ok(());
+        }
+        let swarm = [
+            self.seeders.map(|seeders| format!("{} seeders", seeders)),
+            self.leechers
+                .map(|leechers| format!("{} leechers", leechers)),
+            self.interval
+                .map(|interval| format!("announce every {}s", interval)),
+        ];
+        let swarm = swarm.into_iter().flatten().collect::<Vec<_>>();
+        if !swarm.is_empty() {
+            write!(f, "\n{}: {}", self.tracker, swarm.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct HandshakeResult {
+    pub peer_id: String,
+    /// The client named by the peer id, if it follows a known convention.
+    pub client: Option<String>,
+    /// The reserved bytes of the peer's handshake, in hex.
+    pub reserved: String,
+    pub capabilities: Vec<String>,
+    pub attempts: u32,
+    /// Whether the text form shows more than the peer id.
+    #[serde(skip)]
+    pub verbose: bool,
+}
+
+impl Display for HandshakeResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Peer ID: {}", self.peer_id)?;
+        if !self.verbose {
+            return Ok(());
+        }
+        if let Some(client) = &self.client {
+            write!(f, "\nClient: {}", client)?;
+        }
+        write!(f, "\nReserved: {}", self.reserved)?;
+        if self.capabilities.is_empty() {
+            write!(f, "\nSupports: nothing beyond the base protocol")
+        } else {
+            write!(f, "\nSupports: {}", self.capabilities.join(", "))
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct MagnetLink {
+    pub tracker_url: Option<String>,
+    pub info_hash: String,
+    pub name: Option<String>,
+}
+
+impl Display for MagnetLink {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(tracker_url) = &self.tracker_url {
+            writeln!(f, "Tracker URL: {}", tracker_url)?;
+        }
+        write!(f, "Info Hash: {}", self.info_hash)
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct MagnetHandshake {
+    pub peer_id: String,
+    /// The id the peer wants `ut_metadata` messages sent with, if it sends metadata at all.
+    pub metadata_extension_id: Option<u8>,
+}
+
+impl Display for MagnetHandshake {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Peer ID: {}", self.peer_id)?;
+        if let Some(id) = self.metadata_extension_id {
+            write!(f, "\nPeer Metadata Extension ID: {}", id)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct PieceDownloaded {
+    pub piece_index: usize,
+    pub path: String,
+    pub verified: bool,
+}
+
+impl Display for PieceDownloaded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Piece {} downloaded to {}.", self.piece_index, self.path)
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Created {
+    pub path: String,
+    /// The v1 info hash, absent for v2-only torrents.
+    pub info_hash: Option<String>,
+    /// The v2 info hash, absent for v1-only torrents.
+    pub info_hash_v2: Option<String>,
+    pub length: usize,
+    pub files: usize,
+}
+
+impl Display for Created {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Created {}.", self.path)?;
+        if let Some(info_hash) = &self.info_hash {
+            write!(f, "\nInfo Hash: {}", info_hash)?;
+        }
+        if let Some(info_hash) = &self.info_hash_v2 {
+            write!(f, "\nInfo Hash (v2): {}", info_hash)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Scrape {
+    pub trackers: Vec<TrackerScrape>,
+}
+
+/// One tracker's counts, or why it did not give them.
+#[derive(Debug, Serialize)]
+pub struct TrackerScrape {
+    pub url: String,
+    pub seeders: Option<u64>,
+    pub leechers: Option<u64>,
+    pub completed: Option<u64>,
+    pub error: Option<String>,
+}
+
+impl Display for Scrape {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let lines = self.trackers.iter().map(|tracker| match &tracker.error {
+            Some(error) => format!("{}: {}", tracker.url, error),
+            None => format!(
+                "{}: {} seeders, {} leechers, {} completed",
+                tracker.url,
+                tracker.seeders.unwrap_or_default(),
+                tracker.leechers.unwrap_or_default(),
+                tracker.completed.unwrap_or_default()
+            ),
+        });
+        write!(f, "{}", lines.collect::<Vec<_>>().join("\n"))
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Seeded {
+    pub torrent: String,
+    pub uploaded: u64,
+}
+
+impl Display for Seeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Seeded {}, uploaded {} bytes.",
+            self.torrent, self.uploaded
+        )
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Status {
+    pub torrent: String,
+    /// Where the download's data is, which is a partial file until it has every piece.
+    pub path: String,
+    pub pieces: usize,
+    pub piece_count: usize,
+    /// The percentage of the content verified.
+    pub completion: f64,
+    /// Bytes still to download.
+    pub remaining: u64,
+    /// Totals across every session, known only from resume data.
+    pub uploaded: Option<u64>,
+    pub downloaded: Option<u64>,
+    /// Uploaded over the torrent's size.
+    pub ratio: Option<f64>,
+    /// How the pieces were counted: from `resume` data, by a `recheck`, or `none` without data.
+    pub source: String,
+}
+
+impl Display for Status {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "{}: {} of {} pieces ({:.1}%), {} left",
+            self.torrent,
+            self.pieces,
+            self.piece_count,
+            self.completion,
+            format_bytes(self.remaining)
+        )?;
+        if let (Some(uploaded), Some(downloaded), Some(ratio)) =
+            (self.uploaded, self.downloaded, self.ratio)
+        {
+            writeln!(
+                f,
+                "Downloaded {}, uploaded {}, ratio {:.2}",
+                format_bytes(downloaded),
+                format_bytes(uploaded),
+                ratio
+            )?;
+        }
+        let source = match self.source.as_str() {
+            "resume" => "from resume data",
+            "recheck" => "by hashing",
+            _ => "with no data on disk",
+        };
+        write!(f, "Checked {} at {}", source, self.path)
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Verified {
+    pub path: String,
+    pub pieces: usize,
+    pub piece_count: usize,
+    pub bad_pieces: Vec<BadPiece>,
+}
+
+/// A piece that is missing or corrupt, with the byte range and files it covers.
+#[derive(Debug, Serialize)]
+pub struct BadPiece {
+    pub piece: usize,
+    pub problem: Problem,
+    pub start: u64,
+    pub end: u64,
+    pub files: Vec<String>,
+}
+
+impl Display for Verified {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for bad in &self.bad_pieces {
+            let problem = match bad.problem {
+                Problem::Missing => "missing",
+                Problem::Corrupt => "corrupt",
+            };
+            writeln!(
+                f,
+                "Piece {} {}: bytes {}..{} of {}",
+                bad.piece,
+                problem,
+                bad.start,
+                bad.end,
+                bad.files.join(", ")
+            )?;
+        }
+        write!(
+            f,
+            "{} of {} pieces in {} verify.",
+            self.pieces, self.piece_count, self.path
+        )
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct DaemonStatus {
+    pub torrents: Vec<TorrentStatus>,
+}
+
+impl Display for DaemonStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let lines = self.torrents.iter().map(|torrent| {
+            format!(
+                "{} {}: {}, uploaded {} bytes",
+                torrent.info_hash, torrent.name, torrent.state, torrent.uploaded
+            )
+        });
+        write!(f, "{}", lines.collect::<Vec<_>>().join("\n"))
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Downloaded {
+    pub torrent: String,
+    /// The output path, or `-` when streamed to stdout.
+    pub path: String,
+    pub complete: bool,
+    /// Whether the download stopped short because it reached its `--stop_after` quota.
+    pub stopped_at_quota: bool,
+    pub pieces: usize,
+    pub piece_count: usize,
+    pub downloaded: u64,
+    pub uploaded: u64,
+}
+
+impl Display for Downloaded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.stopped_at_quota {
+            write!(
+                f,
+                "Downloaded {} of {} pieces of {}, stopping at the quota.",
+                self.pieces, self.piece_count, self.torrent
+            )
+        } else if !self.complete {
+            write!(f, "Download of {} is incomplete.", self.torrent)
+        } else if self.path == "-" {
+            write!(f, "Streamed {} to stdout.", self.torrent)
+        } else {
+            write!(f, "Downloaded {} to {}.", self.torrent, self.path)
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Doctor {
+    pub findings: Vec<Finding>,
+}
+
+impl Display for Doctor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let lines = self.findings.iter().map(|finding| {
+            let status = match finding.status {
+                doctor::Status::Ok => "ok",
+                doctor::Status::Warning => "warning",
+                doctor::Status::Problem => "problem",
+            };
+            let mut line = format!("{:<8} {:<8} {}", status, finding.check, finding.detail);
+            if let Some(advice) = &finding.advice {
+                line.push_str(&format!("\n{:17} -> {}", "", advice));
+            }
+            line
+        });
+        write!(f, "{}", lines.collect::<Vec<_>>().join("\n"))
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct DryRun {
+    pub torrent: String,
+    /// The peer the pieces came from, absent if none was needed.
+    pub peer: Option<String>,
+    pub pieces: usize,
+    pub piece_count: usize,
+    pub bytes: u64,
+    pub seconds: f64,
+    /// Bytes per second.
+    pub rate: f64,
+}
+
+impl Display for DryRun {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Dry run of {}: verified {} of {} pieces",
+            self.torrent, self.pieces, self.piece_count
+        )?;
+        if let Some(peer) = &self.peer {
+            write!(f, " from {}", peer)?;
+        }
+        write!(
+            f,
+            ", {} in {:.2}s, {}/s",
+            format_bytes(self.bytes),
+            self.seconds,
+            format_bytes(self.rate as u64)
+        )
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Bench {
+    pub mode: String,
+    pub bytes: u64,
+    pub seconds: f64,
+    /// Bytes per second.
+    pub rate: f64,
+}
+
+impl Display for Bench {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}: {} in {:.2}s, {}/s",
+            self.mode,
+            format_bytes(self.bytes),
+            self.seconds,
+            format_bytes(self.rate as u64)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use bittorrent_starter_rust::geoip::Location;
+
+    use super::{to_json, Downloaded, HandshakeResult, Peers};
+
+    #[test]
+    fn keeps_text_and_json_forms_of_output() {
+        let mut peers = Peers {
+            peers: vec!["127.0.0.1:6881".to_string(), "10.0.0.1:51413".to_string()],
+            locations: BTreeMap::new(),
+            tracker: "http://tracker/announce".to_string(),
+            interval: None,
+            seeders: None,
+            leechers: None,
+            verbose: false,
+        };
+        assert_eq!(peers.to_string(), "127.0.0.1:6881\n10.0.0.1:51413");
+        assert_eq!(
+            to_json(&peers),
+            r#"{"peers":["127.0.0.1:6881","10.0.0.1:51413"],"tracker":"http://tracker/announce","interval":null,"seeders":null,"leechers":null}"#
+        );
+        peers.seeders = Some(3);
+        peers.interval = Some(1800);
+        assert_eq!(peers.to_string(), "127.0.0.1:6881\n10.0.0.1:51413");
+        peers.verbose = true;
+        assert_eq!(
+            peers.to_string(),
+            "127.0.0.1:6881\n10.0.0.1:51413\nhttp://tracker/announce: 3 seeders, announce every 1800s"
+        );
+        peers.locations.insert(
+            "10.0.0.1:51413".to_string(),
+            Location {
+                country: Some("NL".to_string()),
+                asn: Some(1136),
+                organization: None,
+            },
+        );
+        assert!(peers
+            .to_string()
+            .contains("\n10.0.0.1:51413           NL AS1136\n"));
+
+        let mut handshake = HandshakeResult {
+            peer_id: "2d5452333030302d".to_string(),
+            client: Some("Transmission 3.00".to_string()),
+            reserved: "0000000000100005".to_string(),
+            capabilities: vec!["extensions".to_string()],
+            attempts: 1,
+            verbose: false,
+        };
+        assert_eq!(handshake.to_string(), "Peer ID: 2d5452333030302d");
+        handshake.verbose = true;
+        assert_eq!(
+            handshake.to_string(),
+            "Peer ID: 2d5452333030302d\nClient: Transmission 3.00\nReserved: 0000000000100005\nSupports: extensions"
+        );
+
+        let downloaded = Downloaded {
+            torrent: "sample.torrent".to_string(),
+            path: "out".to_string(),
+            complete: true,
+            stopped_at_quota: false,
+            pieces: 3,
+            piece_count: 3,
+            downloaded: 92063,
+            uploaded: 0,
+        };
+        assert_eq!(downloaded.to_string(), "Downloaded sample.torrent to out.");
+        let json: serde_json::Value = serde_json::from_str(&to_json(&downloaded)).unwrap();
+        assert_eq!(json["complete"], true);
+        assert_eq!(json["downloaded"], 92063);
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct History {
+    pub days: Vec<DailyTotal>,
+    pub completions: Vec<Completion>,
+}
+
+impl Display for History {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.days.is_empty() && self.completions.is_empty() {
+            return write!(f, "No history recorded.");
+        }
+        let mut lines = self
+            .days
+            .iter()
+            .map(|total| {
+                format!(
+                    "{} {}: downloaded {}, uploaded {}",
+                    total.day,
+                    total.name,
+                    format_bytes(total.downloaded),
+                    format_bytes(total.uploaded)
+                )
+            })
+            .collect::<Vec<_>>();
+        lines.extend(
+            self.completions
+                .iter()
+                .map(|completion| format!("{} {}: completed", completion.day, completion.name)),
+        );
+        write!(f, "{}", lines.join("\n"))
+    }
+}
@@ -0,0 +1,842 @@
+use std::{
+    collections::HashMap,
+    io::{Read, Write},
+    marker::PhantomData,
+    net::{SocketAddr, TcpStream, ToSocketAddrs},
+    process,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use sha1::{Digest, Sha1};
+
+use crate::{
+    bitfield::Bitfield,
+    clock::Clock,
+    extension::{self, ExtensionHandshake},
+    holepunch::HolepunchMessage,
+    log,
+    phase::DownloadPhase,
+    stats::PeerStats,
+    wire::{
+        BlockRequest, Handshake, Message, MessageError, MessageId, MessageReader, MessageWriter,
+    },
+};
+
+// How long we wait for a peer to accept our connection and answer our handshake.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+// How long a block request may go unanswered before we ask for it again.
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+// Give up on a peer once this many of its requests have timed out.
+const MAX_REQUEST_TIMEOUTS: u32 = 5;
+
+/// Resolves a peer given as `ip:port`, `[ipv6]:port` or `host:port`, taking the first address
+/// a hostname resolves to.
+pub fn resolve_addr(addr: &str) -> std::io::Result<SocketAddr> {
+    addr.to_socket_addrs()?.next().ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("{} did not resolve to any address", addr),
+        )
+    })
+}
+
+/// The peer id tests present, where any fixed id will do.
+#[cfg(test)]
+pub(crate) const TEST_PEER_ID: [u8; 20] = *b"00000000000000000000";
+// Names us, in the `-XXvvvv-` form below, as version 0.1.
+const PEER_ID_PREFIX: &[u8; 8] = b"-BR0100-";
+
+/// A peer id naming this client, ending in digits drawn from the time and our process id so
+/// that two of us running at once tell trackers and peers apart.
+pub fn generate_peer_id() -> [u8; 20] {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_nanos());
+    let digest = Sha1::new()
+        .chain_update(nanos.to_be_bytes())
+        .chain_update(process::id().to_be_bytes())
+        .finalize();
+    let mut peer_id = [0; 20];
+    peer_id[..8].copy_from_slice(PEER_ID_PREFIX);
+    for (digit, byte) in peer_id[8..].iter_mut().zip(digest) {
+        *digit = b'0' + byte % 10;
+    }
+    peer_id
+}
+
+// Clients that put `-XXvvvv-` at the start of their peer id, by their two-letter code.
+const CLIENTS: &[(&str, &str)] = &[
+    ("AZ", "Vuze"),
+    ("BC", "BitComet"),
+    ("BI", "BiglyBT"),
+    ("DE", "Deluge"),
+    ("KT", "KTorrent"),
+    ("LT", "libtorrent"),
+    ("TR", "Transmission"),
+    ("UM", "\u{b5}Torrent Mac"),
+    ("UT", "\u{b5}Torrent"),
+    ("lt", "rTorrent"),
+    ("qB", "qBittorrent"),
+];
+
+/// Names the client that made `peer_id`, for ids in the common `-XXvvvv-` form.
+pub fn client_name(peer_id: &[u8; 20]) -> Option<String> {
+    if peer_id[0] != b'-' || peer_id[7] != b'-' {
+        return None;
+    }
+    let code = std::str::from_utf8(&peer_id[1..3]).ok()?;
+    let version = std::str::from_utf8(&peer_id[3..7]).ok()?;
+    let name = CLIENTS
+        .iter()
+        .find(|(known, _)| *known == code)
+        .map_or(code, |(_, name)| name);
+    let version = version.trim_end_matches('0');
+    let version = version.chars().map(String::from).collect::<Vec<_>>();
+    Some(
+        format!("{} {}", name, version.join("."))
+            .trim_end()
+            .to_string(),
+    )
+}
+
+/// What a peer connection reads and writes through: a TCP socket, or anything else that can
+/// stand in for one, such as the simulated peers of [`crate::sim`].
+pub trait Transport: Read + Write + Send {
+    /// Waits up to `timeout` for something to read, without consuming it.
+    fn wait_readable(&mut self, timeout: Duration) -> std::io::Result<bool>;
+
+    /// Gives up on reads and writes that take longer than `timeout`, or never with `None`.
+    fn set_timeout(&mut self, timeout: Option<Duration>) -> std::io::Result<()>;
+
+    fn shutdown(&mut self);
+}
+
+impl Transport for TcpStream {
+    fn wait_readable(&mut self, timeout: Duration) -> std::io::Result<bool> {
+        self.set_read_timeout(Some(timeout.max(Duration::from_millis(1))))?;
+        let ready = match self.peek(&mut [0; 1]) {
+            Ok(_) => true,
+            Err(error)
+                if matches!(
+                    error.kind(),
+                    std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                ) =>
+            {
+                false
+            }
+            Err(error) => return Err(error),
+        };
+        self.set_read_timeout(None)?;
+        Ok(ready)
+    }
+
+    fn set_timeout(&mut self, timeout: Option<Duration>) -> std::io::Result<()> {
+        self.set_read_timeout(timeout)?;
+        self.set_write_timeout(timeout)
+    }
+
+    fn shutdown(&mut self) {
+        let _ = TcpStream::shutdown(self, std::net::Shutdown::Both);
+    }
+}
+
+/// A connection that is open, with no handshake sent yet.
+pub struct Connected;
+/// Handshakes have been exchanged, so messages flow, but the peer may still send its bitfield.
+pub struct Handshaked;
+/// The peer has sent its bitfield, or shown it has none, so blocks can be requested from it.
+pub struct Ready;
+
+/// The states of a connection that messages can be sent and read in.
+pub trait Open {}
+
+impl Open for Handshaked {}
+impl Open for Ready {}
+
+/// A single TCP connection to a peer, along with everything we know about that peer: which
+/// pieces it has, whether we told it we are interested and which blocks we are waiting on.
+///
+/// `S` is how far the connection has got, from [`Connected`] through [`Handshaked`] to
+/// [`Ready`], and each step consumes the connection, so what a state does not allow, such as
+/// requesting blocks before the handshake, does not compile.
+pub struct PeerConnection<S = Ready> {
+    socket: Box<dyn Transport>,
+    clock: Clock,
+    reader: MessageReader,
+    writer: MessageWriter,
+    addr: SocketAddr,
+    phase: DownloadPhase,
+    stats: PeerStats,
+    // Learned from its handshake.
+    peer_id: Option<[u8; 20]>,
+    outstanding: HashMap<BlockRequest, Instant>,
+    pieces: Bitfield,
+    interested: bool,
+    supports_dht: bool,
+    supports_extensions: bool,
+    // Filled in once the peer sends its extension handshake.
+    extensions: Option<ExtensionHandshake>,
+    handshake_timeout: Duration,
+    // Entered while we talk to the peer, so what is logged says which connection it was.
+    span: log::Span,
+    state: PhantomData<S>,
+}
+
+impl PeerConnection<Connected> {
+    pub fn connect(addr: SocketAddr, piece_count: usize) -> std::io::Result<Self> {
+        let mut peer = Self::connect_timeout(addr, piece_count, CONNECT_TIMEOUT)?;
+        peer.handshake_timeout = HANDSHAKE_TIMEOUT;
+        Ok(peer)
+    }
+
+    /// Like `connect`, giving up on connecting, and later on the handshake, after `timeout`.
+    pub fn connect_timeout(
+        addr: SocketAddr,
+        piece_count: usize,
+        timeout: Duration,
+    ) -> std::io::Result<Self> {
+        let socket = TcpStream::connect_timeout(&addr, timeout)?;
+        let mut peer = Self::over(Box::new(socket), addr, piece_count);
+        peer.handshake_timeout = timeout;
+        Ok(peer)
+    }
+
+    /// A connection to the peer at `addr` through `transport`, which is already open.
+    pub fn over(transport: Box<dyn Transport>, addr: SocketAddr, piece_count: usize) -> Self {
+        Self {
+            socket: transport,
+            clock: Clock::system(),
+            reader: MessageReader::new(),
+            writer: MessageWriter::new(),
+            addr,
+            phase: DownloadPhase::default(),
+            stats: PeerStats::new(),
+            peer_id: None,
+            outstanding: HashMap::new(),
+            pieces: Bitfield::new(piece_count),
+            interested: false,
+            supports_dht: false,
+            supports_extensions: false,
+            extensions: None,
+            handshake_timeout: HANDSHAKE_TIMEOUT,
+            span: log::span!("peer", peer = addr),
+            state: PhantomData,
+        }
+    }
+
+    /// Exchanges handshakes for `info_hash`, presenting ourselves as `peer_id`. With a
+    /// `dht_port` we advertise DHT support and, if the peer supports it too, tell it where our
+    /// node listens. Peers that support extended messages are sent our extension handshake.
+    ///
+    /// The connection is closed if the peer is too slow to answer or answers for a different
+    /// protocol or torrent.
+    pub fn handshake(
+        mut self,
+        info_hash: String,
+        peer_id: [u8; 20],
+        dht_port: Option<u16>,
+    ) -> Result<(PeerConnection<Handshaked>, Handshake), HandshakeError> {
+        let handshake = self.exchange_handshakes(info_hash, peer_id, dht_port)?;
+
+        self.peer_id = Some(handshake.peer_id);
+        self.supports_dht = handshake.supports_dht();
+        if let (Some(port), true) = (dht_port, self.supports_dht) {
+            self.writer.write(&mut self.socket, &Message::port(port))?;
+        }
+        self.supports_extensions = handshake.supports_extensions();
+        if self.supports_extensions {
+            let ours = ExtensionHandshake::ours();
+            let message = extension::extended_message(extension::HANDSHAKE_ID, &ours.as_bytes());
+            self.writer.write(&mut self.socket, &message)?;
+        }
+
+        Ok((self.into_state(), handshake))
+    }
+
+    fn exchange_handshakes(
+        &mut self,
+        info_hash: String,
+        peer_id: [u8; 20],
+        dht_port: Option<u16>,
+    ) -> Result<Handshake, HandshakeError> {
+        let info_hash = hex::decode(&info_hash)
+            .ok()
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or(HandshakeError::InvalidInfoHash(info_hash))?;
+        let mut handshake = Handshake::new("BitTorrent protocol".to_string(), info_hash, peer_id);
+        if dht_port.is_some() {
+            handshake.set_supports_dht();
+        }
+        handshake.set_supports_extensions();
+
+        self.socket.set_timeout(Some(self.handshake_timeout))?;
+        self.socket.write_all(&handshake.encode())?;
+
+        let mut bytes = [0; 68];
+        self.socket.read_exact(&mut bytes)?;
+        self.socket.set_timeout(None)?;
+
+        if bytes[0] != 19 || &bytes[1..20] != b"BitTorrent protocol" {
+            return Err(HandshakeError::Protocol);
+        }
+
+        let reply = Handshake::decode(bytes);
+        if reply.info_hash != handshake.info_hash {
+            return Err(HandshakeError::InfoHash(hex::encode(reply.info_hash)));
+        }
+
+        Ok(reply)
+    }
+}
+
+impl PeerConnection<Handshaked> {
+    /// Reads messages until the peer's bitfield arrives, or until a message that may only
+    /// follow it shows the peer has nothing to offer yet. Returns every message read, the
+    /// bitfield included, for the caller to handle in order.
+    pub fn receive_bitfield(mut self) -> Result<(PeerConnection<Ready>, Vec<Message>), PeerError> {
+        let mut messages = vec![];
+        loop {
+            let message = self.read_message()?;
+            // Extension handshakes and DHT ports may come either side of the bitfield.
+            let waiting = matches!(message.id, MessageId::Extended | MessageId::Port);
+            messages.push(message);
+            if !waiting {
+                return Ok((self.into_state(), messages));
+            }
+        }
+    }
+}
+
+impl<S> PeerConnection<S> {
+    fn into_state<T>(self) -> PeerConnection<T> {
+        PeerConnection {
+            socket: self.socket,
+            clock: self.clock,
+            reader: self.reader,
+            writer: self.writer,
+            addr: self.addr,
+            phase: self.phase,
+            stats: self.stats,
+            peer_id: self.peer_id,
+            outstanding: self.outstanding,
+            pieces: self.pieces,
+            interested: self.interested,
+            supports_dht: self.supports_dht,
+            supports_extensions: self.supports_extensions,
+            extensions: self.extensions,
+            handshake_timeout: self.handshake_timeout,
+            span: self.span,
+            state: PhantomData,
+        }
+    }
+
+    /// Times requests by `clock` rather than the system clock.
+    pub fn set_clock(&mut self, clock: Clock) {
+        self.clock = clock;
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// The span for this connection.
+    pub fn span(&self) -> &log::Span {
+        &self.span
+    }
+
+    pub fn stats(&self) -> &PeerStats {
+        &self.stats
+    }
+
+    pub fn stats_mut(&mut self) -> &mut PeerStats {
+        &mut self.stats
+    }
+
+    pub fn peer_id(&self) -> Option<[u8; 20]> {
+        self.peer_id
+    }
+
+    pub fn supports_dht(&self) -> bool {
+        self.supports_dht
+    }
+}
+
+impl<S: Open> PeerConnection<S> {
+    pub fn record_extension_handshake(&mut self, payload: &[u8]) {
+        self.extensions = Some(ExtensionHandshake::from_bytes(payload));
+    }
+
+    /// Reads messages until the peer's extension handshake arrives, discarding anything else.
+    /// `None` if the peer does not support extended messages.
+    pub fn receive_extension_handshake(
+        &mut self,
+    ) -> Result<Option<&ExtensionHandshake>, PeerError> {
+        if !self.supports_extensions {
+            return Ok(None);
+        }
+        while self.extensions.is_none() {
+            let message = self.read_message()?;
+            if let Some((&extension::HANDSHAKE_ID, payload)) = message
+                .payload
+                .split_first()
+                .filter(|_| message.id == MessageId::Extended)
+            {
+                self.record_extension_handshake(payload);
+            }
+        }
+        Ok(self.extensions.as_ref())
+    }
+
+    /// Whether the peer told us in its extension handshake that it speaks `ut_holepunch`.
+    pub fn supports_holepunch(&self) -> bool {
+        self.extension_id("ut_holepunch").is_some()
+    }
+
+    /// Sends a `ut_holepunch` message, if the peer supports them.
+    pub fn send_holepunch(&mut self, message: &HolepunchMessage) -> Result<(), PeerError> {
+        self.send_extended("ut_holepunch", &message.as_bytes())?;
+        Ok(())
+    }
+
+    /// Sends `payload` to the peer's `extension`, returning whether the peer supports it.
+    pub fn send_extended(&mut self, extension: &str, payload: &[u8]) -> Result<bool, PeerError> {
+        let Some(id) = self.extension_id(extension) else {
+            return Ok(false);
+        };
+        self.send(&extension::extended_message(id, payload))?;
+        Ok(true)
+    }
+
+    /// The id the peer wants `name` messages sent with, once it has sent its extension
+    /// handshake.
+    pub fn extension_id(&self, name: &str) -> Option<u8> {
+        self.extensions.as_ref()?.id_for(name)
+    }
+
+    pub fn send(&mut self, message: &Message) -> Result<(), PeerError> {
+        self.writer.write(&mut self.socket, message)?;
+        Ok(())
+    }
+
+    /// Reads the next message we understand, skipping keep-alives and ids we don't support.
+    pub fn read_message(&mut self) -> Result<Message, PeerError> {
+        loop {
+            if let Received::Message(message) = self.receive()? {
+                return Ok(message);
+            }
+        }
+    }
+
+    /// Reads one frame. Blocks we asked for are copied straight from the read buffer into
+    /// `piece` at their offset, so receiving data does not allocate.
+    pub fn receive_into(&mut self, piece: &mut [u8]) -> Result<Received, PeerError> {
+        self.receive_into_at(piece, 0)
+    }
+
+    /// Like `receive_into`, for a buffer holding the piece from byte `start` on, such as a
+    /// single block.
+    pub fn receive_into_at(
+        &mut self,
+        buffer: &mut [u8],
+        start: usize,
+    ) -> Result<Received, PeerError> {
+        let received = match self.reader.read(&mut self.socket)? {
+            Some(message) if message.id == MessageId::Piece => {
+                match accept_block(&mut self.outstanding, &mut self.stats, message.payload) {
+                    Some((begin, block)) => {
+                        let target = begin
+                            .checked_sub(start)
+                            .and_then(|offset| buffer.get_mut(offset..offset + block.len()));
+                        if let Some(target) = target {
+                            target.copy_from_slice(block);
+                        }
+                        Received::Block(block.len())
+                    }
+                    None => Received::Nothing,
+                }
+            }
+            Some(message) => match message.id {
+                MessageId::Unknown(id) => {
+                    log::debug!(peer = self.addr; "skipping message with unknown id {}", id);
+                    Received::Nothing
+                }
+                id => {
+                    self.phase = self.phase.on_message(id);
+                    Received::Message(message.to_message())
+                }
+            },
+            None => Received::Nothing,
+        };
+        Ok(received)
+    }
+
+    fn receive(&mut self) -> Result<Received, PeerError> {
+        self.receive_into(&mut [])
+    }
+
+    /// Cancels anything still in flight, withdraws our interest and closes the socket.
+    pub fn close(&mut self) {
+        for (request, _) in self.outstanding.drain() {
+            let message = Message::new(MessageId::Cancel, request.encode());
+            if self.writer.write(&mut self.socket, &message).is_err() {
+                break;
+            }
+        }
+
+        if self.interested {
+            let _ = self
+                .writer
+                .write(&mut self.socket, &Message::not_interested());
+            self.interested = false;
+        }
+
+        self.socket.shutdown();
+    }
+}
+
+impl PeerConnection<Ready> {
+    /// The pieces this peer has told us it can serve.
+    pub fn pieces(&self) -> &Bitfield {
+        &self.pieces
+    }
+
+    pub fn is_interested(&self) -> bool {
+        self.interested
+    }
+
+    /// Whether we are interested in the peer and whether it has unchoked us.
+    pub fn phase(&self) -> DownloadPhase {
+        self.phase
+    }
+
+    /// Updates which pieces the peer has from a `Bitfield` or `Have` message, returning the
+    /// pieces that have newly become available from it.
+    pub fn record_availability(&mut self, message: &Message) -> Vec<usize> {
+        match message.id {
+            MessageId::Bitfield => {
+                self.pieces = Bitfield::from_bytes(&message.payload, self.pieces.len());
+                (0..self.pieces.len())
+                    .filter(|index| self.pieces.has(*index))
+                    .collect()
+            }
+            MessageId::Have if message.payload.len() == 4 => {
+                let index = u32::from_be_bytes(message.payload[..4].try_into().unwrap()) as usize;
+                if index >= self.pieces.len() || self.pieces.has(index) {
+                    return vec![];
+                }
+
+                self.pieces.set(index);
+                vec![index]
+            }
+            _ => vec![],
+        }
+    }
+
+    /// We are interested in a peer for as long as it has a piece we are still missing.
+    pub fn update_interest(&mut self, completed: &Bitfield) -> Result<(), PeerError> {
+        let interested = self.pieces.has_any_missing_from(completed);
+        if interested == self.interested {
+            return Ok(());
+        }
+
+        let message = if interested {
+            Message::interested()
+        } else {
+            Message::not_interested()
+        };
+        self.send(&message)?;
+        self.interested = interested;
+        self.phase = self.phase.on_interest(interested);
+        Ok(())
+    }
+
+    pub fn request_block(&mut self, request: BlockRequest) -> Result<Instant, PeerError> {
+        self.send(&Message::new(MessageId::Request, request.encode()))?;
+        let requested_at = self.clock.now();
+        self.outstanding.insert(request, requested_at);
+        Ok(requested_at)
+    }
+
+    pub fn outstanding_requests(&self) -> usize {
+        self.outstanding.len()
+    }
+
+    /// When the longest-waiting outstanding request was sent, if there is one.
+    pub fn oldest_request(&self) -> Option<Instant> {
+        self.outstanding.values().min().copied()
+    }
+
+    /// Waits until the peer has sent something or the deadline passes, without consuming any
+    /// bytes, so a timeout can never leave us halfway through a frame.
+    pub fn wait_for_data(&mut self, deadline: Instant) -> Result<bool, PeerError> {
+        let remaining = deadline.saturating_duration_since(self.clock.now());
+        Ok(self.socket.wait_readable(remaining)?)
+    }
+
+    /// Cancels every request still in flight, so a block the peer has yet to send is not
+    /// taken for the answer to a later request.
+    pub fn cancel_requests(&mut self) -> Result<(), PeerError> {
+        let requests = self.outstanding.drain().map(|(request, _)| request);
+        for request in requests.collect::<Vec<_>>() {
+            self.send(&Message::new(MessageId::Cancel, request.encode()))?;
+        }
+        Ok(())
+    }
+
+    /// Cancels requests that have gone unanswered for too long, returning them so another
+    /// source can be asked for the blocks instead.
+    pub fn cancel_stalled_blocks(&mut self) -> Result<Vec<BlockRequest>, PeerError> {
+        let now = self.clock.now();
+        let stalled = self
+            .outstanding
+            .iter()
+            .filter(|(_, sent_at)| now.duration_since(**sent_at) >= REQUEST_TIMEOUT)
+            .map(|(request, _)| *request)
+            .collect::<Vec<_>>();
+
+        for request in &stalled {
+            self.stats.record_timeout();
+            if self.stats.timeouts > MAX_REQUEST_TIMEOUTS {
+                return Err(PeerError::Unresponsive);
+            }
+
+            log::debug!(
+                peer = self.addr, piece = request.index;
+                "request at {} timed out, cancelling it",
+                request.begin
+            );
+            self.send(&Message::new(MessageId::Cancel, request.encode()))?;
+            self.outstanding.remove(request);
+        }
+        Ok(stalled)
+    }
+}
+
+/// Checks a `Piece` payload against our outstanding requests, returning the block if we asked
+/// for it. Anything unsolicited or duplicated is counted against the peer.
+fn accept_block<'a>(
+    outstanding: &mut HashMap<BlockRequest, Instant>,
+    stats: &mut PeerStats,
+    payload: &'a [u8],
+) -> Option<(usize, &'a [u8])> {
+    if payload.len() < 8 {
+        stats.record_unsolicited_block();
+        log::debug!("discarding truncated piece message");
+        return None;
+    }
+
+    let (header, block) = payload.split_at(8);
+    let response = BlockRequest {
+        index: u32::from_be_bytes(header[0..4].try_into().unwrap()),
+        begin: u32::from_be_bytes(header[4..8].try_into().unwrap()),
+        length: block.len() as u32,
+    };
+
+    if outstanding.remove(&response).is_some() {
+        return Some((response.begin as usize, block));
+    }
+
+    stats.record_unsolicited_block();
+    log::debug!(
+        piece = response.index;
+        "discarding unsolicited block (begin {}, length {})",
+        response.begin,
+        response.length
+    );
+    None
+}
+
+/// What a single frame from the peer turned out to be.
+#[derive(Debug)]
+pub enum Received {
+    /// A block we requested, of this many bytes.
+    Block(usize),
+    Message(Message),
+    /// A keep-alive, an unknown message or a block we did not ask for.
+    Nothing,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum HandshakeError {
+    #[error("failed to connect: {0}")]
+    Connect(std::io::Error),
+    #[error("handshake failed: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("peer does not speak the BitTorrent protocol")]
+    Protocol,
+    #[error("peer answered for a different torrent ({0})")]
+    InfoHash(String),
+    #[error("invalid info hash {0}")]
+    InvalidInfoHash(String),
+}
+
+/// Why a connection to a peer stopped being usable.
+#[derive(Debug, thiserror::Error)]
+pub enum PeerError {
+    #[error(transparent)]
+    Handshake(#[from] HandshakeError),
+    #[error("connection to peer failed: {0}")]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Message(#[from] MessageError),
+    #[error("peer stopped responding to block requests")]
+    Unresponsive,
+    #[error("peer stalled on a block request")]
+    Stalled,
+    #[error("peer does not have any pieces we need")]
+    NothingWanted,
+    #[error("peer does not have piece {0}")]
+    MissingPiece(usize),
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::TcpListener,
+        thread,
+    };
+
+    use super::{
+        client_name, generate_peer_id, resolve_addr, Connected, HandshakeError, PeerConnection,
+        TEST_PEER_ID,
+    };
+    use crate::wire::{Handshake, Message, MessageId};
+
+    const INFO_HASH: &str = "d69f91e6b2ae4c542468d1073a71d4ea13879a7f";
+
+    fn info_hash() -> [u8; 20] {
+        hex::decode(INFO_HASH).unwrap().try_into().unwrap()
+    }
+
+    /// Accepts one connection and answers its handshake with `reply`.
+    fn spawn_peer(reply: Vec<u8>) -> PeerConnection<Connected> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        thread::spawn(move || {
+            let (mut socket, _) = listener.accept().unwrap();
+            let mut handshake = [0; 68];
+            socket.read_exact(&mut handshake).unwrap();
+            socket.write_all(&reply).unwrap();
+        });
+
+        PeerConnection::connect(addr, 1).unwrap()
+    }
+
+    #[test]
+    fn resolves_addresses() {
+        assert_eq!(
+            resolve_addr("127.0.0.1:6881").unwrap(),
+            ([127, 0, 0, 1], 6881).into()
+        );
+        assert_eq!(
+            resolve_addr("[::1]:6881").unwrap(),
+            "[::1]:6881".parse().unwrap()
+        );
+        assert!(resolve_addr("localhost:6881").unwrap().ip().is_loopback());
+        assert!(resolve_addr("no-port").is_err());
+    }
+
+    #[test]
+    fn names_clients_from_peer_ids() {
+        assert_eq!(
+            client_name(b"-qB4250-abcdefghijkl").as_deref(),
+            Some("qBittorrent 4.2.5")
+        );
+        assert_eq!(
+            client_name(b"-XX1000-abcdefghijkl").as_deref(),
+            Some("XX 1")
+        );
+        assert_eq!(client_name(&[0; 20]), None);
+
+        let ours = generate_peer_id();
+        assert_eq!(client_name(&ours).as_deref(), Some("BR 0.1"));
+        assert!(ours[8..].iter().all(u8::is_ascii_digit));
+    }
+
+    #[test]
+    fn accepts_matching_handshake() {
+        let reply = Handshake::new(
+            "BitTorrent protocol".to_string(),
+            info_hash(),
+            [1; 20],
+        );
+        let peer = spawn_peer(reply.encode());
+
+        let (peer, handshake) = peer
+            .handshake(INFO_HASH.to_string(), TEST_PEER_ID, None)
+            .unwrap();
+        assert_eq!(handshake.peer_id, [1; 20]);
+        assert_eq!(peer.peer_id(), Some([1; 20]));
+    }
+
+    #[test]
+    fn rejects_other_info_hash() {
+        let reply = Handshake::new("BitTorrent protocol".to_string(), [0; 20], [1; 20]);
+        let peer = spawn_peer(reply.encode());
+
+        assert!(matches!(
+            peer.handshake(INFO_HASH.to_string(), TEST_PEER_ID, None),
+            Err(HandshakeError::InfoHash(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_malformed_info_hash() {
+        let reply = Handshake::new("BitTorrent protocol".to_string(), info_hash(), [1; 20]);
+        let peer = spawn_peer(reply.encode());
+
+        assert!(matches!(
+            peer.handshake("not hex".to_string(), TEST_PEER_ID, None),
+            Err(HandshakeError::InvalidInfoHash(_))
+        ));
+    }
+
+    #[test]
+    fn is_ready_once_the_bitfield_arrives() {
+        let mut reply = Handshake::new(
+            "BitTorrent protocol".to_string(),
+            info_hash(),
+            [1; 20],
+        )
+        .encode();
+        for message in [
+            Message::port(6881),
+            Message::new(MessageId::Bitfield, vec![0x80]),
+            Message::have(0),
+        ] {
+            reply.extend(message.encode());
+        }
+        let peer = spawn_peer(reply);
+
+        let (peer, _) = peer
+            .handshake(INFO_HASH.to_string(), TEST_PEER_ID, None)
+            .unwrap();
+        let (mut peer, messages) = peer.receive_bitfield().unwrap();
+        let ids = messages
+            .iter()
+            .map(|message| message.id)
+            .collect::<Vec<_>>();
+        assert_eq!(ids, [MessageId::Port, MessageId::Bitfield]);
+        assert_eq!(peer.record_availability(&messages[1]), [0]);
+        assert_eq!(peer.read_message().unwrap().id, MessageId::Have);
+        assert!(peer.phase().is_choked());
+    }
+
+    #[test]
+    fn rejects_other_protocol() {
+        let reply = Handshake::new(
+            "Not BitTorrent, no!".to_string(),
+            info_hash(),
+            [1; 20],
+        );
+        let peer = spawn_peer(reply.encode());
+
+        assert!(matches!(
+            peer.handshake(INFO_HASH.to_string(), TEST_PEER_ID, None),
+            Err(HandshakeError::Protocol)
+        ));
+    }
+}
@@ -0,0 +1,680 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io::Write,
+    net::{SocketAddr, TcpStream},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    geoip::Location, ip_filter::IpFilter, log, peer::client_name, stats::PeerStats, wire::Message,
+};
+
+// A peer that failed to connect is retried after this long, doubling with each failure in a row.
+const RETRY_BACKOFF: Duration = Duration::from_secs(2);
+// Peers that failed this many times in a row are given up on for the session.
+const MAX_CONNECT_ATTEMPTS: u32 = 5;
+// Peers that dropped an established connection this many times are given up on for the session.
+const MAX_DROPS: u32 = 3;
+
+/// Decides how attractive a peer is to connect to and download from. Higher is better.
+pub type ScoreFn = fn(&PeerCandidate) -> f64;
+
+/// Keeps track of the peers we know about, whether we found them through a tracker or they
+/// connected to us.
+#[derive(Debug)]
+pub struct PeerManager {
+    inbound: Vec<InboundPeer>,
+    candidates: HashMap<SocketAddr, PeerCandidate>,
+    score: ScoreFn,
+    dht_nodes: Vec<SocketAddr>,
+    ip_filter: IpFilter,
+    // Peers that sent us corrupt data, refused for the rest of the session.
+    banned: HashSet<SocketAddr>,
+    // Pieces that failed verification with a block from each peer, whether or not it is still
+    // connected.
+    hash_fails: HashMap<SocketAddr, u32>,
+    limits: ConnectionLimits,
+    outbound: HashMap<SocketAddr, [u8; 20]>,
+    half_open: HashSet<SocketAddr>,
+    // Bytes served to peers, by torrent.
+    uploaded: HashMap<[u8; 20], u64>,
+    // How each open connection is doing, as last reported by whoever drives it.
+    peers: HashMap<SocketAddr, PeerSnapshot>,
+}
+
+impl Default for PeerManager {
+    fn default() -> Self {
+        Self {
+            inbound: Vec::new(),
+            candidates: HashMap::new(),
+            score: default_score,
+            dht_nodes: Vec::new(),
+            ip_filter: IpFilter::default(),
+            banned: HashSet::new(),
+            hash_fails: HashMap::new(),
+            limits: ConnectionLimits::default(),
+            outbound: HashMap::new(),
+            half_open: HashSet::new(),
+            uploaded: HashMap::new(),
+            peers: HashMap::new(),
+        }
+    }
+}
+
+impl PeerManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Swaps in a different scoring heuristic for ranking candidates.
+    #[allow(dead_code)]
+    pub fn with_score(mut self, score: ScoreFn) -> Self {
+        self.score = score;
+        self
+    }
+
+    /// Stops us connecting to, or accepting connections from, addresses the filter blocks.
+    pub fn set_ip_filter(&mut self, ip_filter: IpFilter) {
+        self.ip_filter = ip_filter;
+        let ip_filter = &self.ip_filter;
+        self.candidates
+            .retain(|addr, _| !ip_filter.is_blocked(addr.ip()));
+    }
+
+    /// Whether the IP filter blocks `addr` or we have banned it.
+    pub fn is_blocked(&self, addr: SocketAddr) -> bool {
+        self.banned.contains(&addr) || self.ip_filter.is_blocked(addr.ip())
+    }
+
+    /// Refuses a peer for the rest of the session, forgetting it as a candidate and dropping
+    /// any inbound connection from it.
+    pub fn ban(&mut self, addr: SocketAddr) {
+        if !self.banned.insert(addr) {
+            return;
+        }
+        log::warn!(peer = addr; "banning peer");
+        self.candidates.remove(&addr);
+        self.inbound.retain(|peer| peer.addr != addr);
+    }
+
+    /// Counts a piece that failed verification against a peer that sent part of it, returning
+    /// how many it has now sent across all its connections.
+    pub fn record_hash_fail(&mut self, addr: SocketAddr) -> u32 {
+        let fails = self.hash_fails.entry(addr).or_default();
+        *fails += 1;
+        *fails
+    }
+
+    pub fn set_limits(&mut self, limits: ConnectionLimits) {
+        self.limits = limits;
+    }
+
+    /// Connections we hold open or are still establishing, across every torrent.
+    pub fn open_connections(&self) -> usize {
+        self.inbound.len() + self.outbound.len() + self.half_open.len()
+    }
+
+    fn torrent_connections(&self, info_hash: [u8; 20]) -> usize {
+        let inbound = self
+            .inbound
+            .iter()
+            .filter(|peer| peer.info_hash == info_hash)
+            .count();
+        let outbound = self
+            .outbound
+            .values()
+            .filter(|hash| **hash == info_hash)
+            .count();
+        inbound + outbound
+    }
+
+    /// Whether there is room for another connection.
+    pub fn has_capacity(&self) -> bool {
+        self.open_connections() < self.limits.global
+    }
+
+    /// Whether we can take another inbound peer for this torrent.
+    pub fn can_accept(&self, info_hash: [u8; 20]) -> bool {
+        self.has_capacity() && self.torrent_connections(info_hash) < self.limits.per_torrent
+    }
+
+    /// Reserves a half-open slot for dialling `addr`. When the limits are reached this returns
+    /// false and the candidate stays queued until a connection closes.
+    pub fn begin_connect(&mut self, addr: SocketAddr, info_hash: [u8; 20]) -> bool {
+        if self.half_open.len() >= self.limits.half_open
+            || !self.has_capacity()
+            || self.torrent_connections(info_hash) + self.half_open.len() >= self.limits.per_torrent
+        {
+            return false;
+        }
+
+        self.half_open.insert(addr)
+    }
+
+    /// Reserves a half-open slot for reading the handshake of a peer that connected to us from
+    /// `addr`. Returns false, and the connection should be dropped, if `addr` is blocked or the
+    /// limits are reached.
+    pub fn begin_accept(&mut self, addr: SocketAddr) -> bool {
+        if self.is_blocked(addr)
+            || self.half_open.len() >= self.limits.half_open
+            || !self.has_capacity()
+        {
+            return false;
+        }
+
+        self.half_open.insert(addr)
+    }
+
+    /// Releases the half-open slot of an inbound peer, and takes it on for `info_hash` if there
+    /// is room. Returns whether it was taken on, in which case it should be passed to
+    /// [`PeerManager::add_inbound`] once it has our handshake.
+    pub fn finish_accept(&mut self, addr: SocketAddr, info_hash: Option<[u8; 20]>) -> bool {
+        self.half_open.remove(&addr);
+        info_hash.is_some_and(|info_hash| self.can_accept(info_hash))
+    }
+
+    /// Releases the half-open slot for `addr`, counting it as open if the connection succeeded.
+    pub fn finish_connect(&mut self, addr: SocketAddr, info_hash: [u8; 20], connected: bool) {
+        self.half_open.remove(&addr);
+        if let Some(candidate) = self.candidates.get_mut(&addr) {
+            candidate.attempts += 1;
+            candidate.last_attempt = Some(Instant::now());
+        }
+
+        if connected {
+            self.outbound.insert(addr, info_hash);
+            if let Some(candidate) = self.candidates.get_mut(&addr) {
+                candidate.failures = 0;
+                candidate.retry_at = None;
+            }
+        } else {
+            self.record_failure(addr);
+        }
+    }
+
+    pub fn connection_closed(&mut self, addr: SocketAddr) {
+        self.outbound.remove(&addr);
+        self.inbound.retain(|peer| peer.addr != addr);
+        self.peers.remove(&addr);
+    }
+
+    pub fn add_inbound(&mut self, peer: InboundPeer) {
+        log::info!(
+            peer = peer.addr, torrent = hex::encode(peer.info_hash);
+            "accepted inbound peer {}",
+            hex::encode(peer.peer_id)
+        );
+        self.add_candidates([peer.addr], PeerSource::Inbound);
+        self.inbound.push(peer);
+    }
+
+    pub fn add_candidates<I: IntoIterator<Item = SocketAddr>>(
+        &mut self,
+        addrs: I,
+        source: PeerSource,
+    ) {
+        for addr in addrs {
+            if self.is_blocked(addr) {
+                log::debug!(peer = addr; "skipping peer: blocked");
+                continue;
+            }
+            self.candidates
+                .entry(addr)
+                .or_insert_with(|| PeerCandidate::new(addr, source));
+        }
+    }
+
+    /// Candidates we are not already connected to, ordered from most to least promising.
+    pub fn ranked_candidates(&self) -> Vec<SocketAddr> {
+        let mut ranked = self
+            .candidates
+            .values()
+            .filter(|candidate| {
+                !self.outbound.contains_key(&candidate.addr)
+                    && !self.half_open.contains(&candidate.addr)
+            })
+            .map(|candidate| (candidate.addr, (self.score)(candidate)))
+            .collect::<Vec<_>>();
+        ranked.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+        ranked.into_iter().map(|(addr, _)| addr).collect()
+    }
+
+    /// Ranked candidates that are not waiting out a backoff and have not failed too often.
+    pub fn connectable_candidates(&self, now: Instant) -> Vec<SocketAddr> {
+        self.ranked_candidates()
+            .into_iter()
+            .filter(|addr| {
+                let candidate = &self.candidates[addr];
+                candidate.is_worth_trying()
+                    && candidate.retry_at.is_none_or(|retry_at| retry_at <= now)
+            })
+            .collect()
+    }
+
+    /// When the next candidate waiting out a backoff can be tried again.
+    pub fn next_retry(&self) -> Option<Instant> {
+        self.candidates
+            .values()
+            .filter(|candidate| candidate.is_worth_trying())
+            .filter_map(|candidate| candidate.retry_at)
+            .min()
+    }
+
+    /// Counts a failed connection against the peer and backs off before it is tried again.
+    pub fn record_failure(&mut self, addr: SocketAddr) {
+        if let Some(candidate) = self.candidates.get_mut(&addr) {
+            candidate.failures += 1;
+            let backoff = RETRY_BACKOFF * 2u32.pow(candidate.failures - 1);
+            candidate.retry_at = Some(Instant::now() + backoff);
+        }
+    }
+
+    /// Counts a connection the peer broke off, or that failed in the middle of a download,
+    /// against it and backs off before it is tried again. Unlike connect failures these are not
+    /// forgiven when a later connection succeeds.
+    pub fn record_drop(&mut self, addr: SocketAddr) {
+        if let Some(candidate) = self.candidates.get_mut(&addr) {
+            candidate.drops += 1;
+            let backoff = RETRY_BACKOFF * 2u32.pow(candidate.drops - 1);
+            candidate.retry_at = Some(Instant::now() + backoff);
+        }
+    }
+
+    /// Candidates we have tried and failed to reach, which a relay might be able to introduce
+    /// us to.
+    pub fn unreachable_candidates(&self) -> Vec<SocketAddr> {
+        self.candidates
+            .values()
+            .filter(|candidate| candidate.failures > 0)
+            .map(|candidate| candidate.addr)
+            .collect()
+    }
+
+    /// A relay told us `addr` is dialling us, so its NAT should let our connection through now.
+    pub fn record_holepunch(&mut self, addr: SocketAddr) {
+        let candidate = self
+            .candidates
+            .entry(addr)
+            .or_insert_with(|| PeerCandidate::new(addr, PeerSource::Holepunch));
+        candidate.source = PeerSource::Holepunch;
+        candidate.failures = 0;
+        candidate.retry_at = None;
+    }
+
+    /// Folds what we saw during a connection into the peer's history.
+    pub fn record_session(&mut self, addr: SocketAddr, stats: &PeerStats) {
+        if let Some(candidate) = self.candidates.get_mut(&addr) {
+            candidate.download_rate = stats.download_rate();
+            candidate.latency = stats.average_latency();
+            candidate.hash_fails += stats.hash_fails;
+            candidate.unsolicited_blocks += stats.unsolicited_blocks;
+        }
+    }
+
+    /// Remembers a DHT node a peer told us about with a `Port` message, for seeding the routing
+    /// table.
+    pub fn add_dht_node(&mut self, node: SocketAddr) {
+        if !self.dht_nodes.contains(&node) {
+            self.dht_nodes.push(node);
+        }
+    }
+
+    pub fn dht_nodes(&self) -> &[SocketAddr] {
+        &self.dht_nodes
+    }
+
+    pub fn record_upload(&mut self, info_hash: [u8; 20], bytes: usize) {
+        *self.uploaded.entry(info_hash).or_default() += bytes as u64;
+    }
+
+    /// Bytes of a torrent served to peers, for working out our share ratio.
+    pub fn uploaded(&self, info_hash: [u8; 20]) -> u64 {
+        self.uploaded.get(&info_hash).copied().unwrap_or(0)
+    }
+
+    pub fn update_peer(&mut self, snapshot: PeerSnapshot) {
+        self.peers.insert(snapshot.addr, snapshot);
+    }
+
+    /// The open connections for a torrent, ordered by address.
+    pub fn peers(&self, info_hash: [u8; 20]) -> Vec<PeerSnapshot> {
+        let mut peers = self
+            .peers
+            .values()
+            .filter(|peer| peer.info_hash == info_hash)
+            .cloned()
+            .collect::<Vec<_>>();
+        peers.sort_by_key(|peer| peer.addr);
+        peers
+    }
+
+    /// Sends a message to every inbound peer, dropping any whose connection has gone away.
+    pub fn broadcast(&mut self, message: &Message) {
+        let bytes = message.encode();
+        self.inbound.retain_mut(|peer| {
+            let sent = peer.socket.write_all(&bytes).is_ok();
+            if !sent {
+                log::debug!(peer = peer.addr; "dropping inbound peer: connection closed");
+            }
+            sent
+        });
+    }
+
+    pub fn inbound(&self) -> &[InboundPeer] {
+        &self.inbound
+    }
+}
+
+/// Caps on open connections, so a large swarm cannot exhaust our file descriptors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionLimits {
+    /// Open connections across every torrent, including ones still being established.
+    pub global: usize,
+    /// Open connections for a single torrent.
+    pub per_torrent: usize,
+    /// Connections, in either direction, that have not completed their handshake yet.
+    pub half_open: usize,
+}
+
+impl Default for ConnectionLimits {
+    fn default() -> Self {
+        Self {
+            global: 200,
+            per_torrent: 50,
+            half_open: 8,
+        }
+    }
+}
+
+/// A peer that connected to our listener and completed a handshake for one of our torrents.
+#[derive(Debug)]
+pub struct InboundPeer {
+    pub addr: SocketAddr,
+    pub info_hash: [u8; 20],
+    pub peer_id: [u8; 20],
+    pub socket: TcpStream,
+}
+
+/// How an open connection is getting on, for showing to the user.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PeerSnapshot {
+    pub addr: SocketAddr,
+    #[serde(skip)]
+    pub info_hash: [u8; 20],
+    /// The client the peer runs, when its peer id says.
+    pub client: Option<String>,
+    pub downloaded: u64,
+    pub uploaded: u64,
+    /// Bytes per second.
+    pub download_rate: f64,
+    pub upload_rate: f64,
+    /// Whether the peer is refusing our requests.
+    pub choked: bool,
+    /// Whether we want pieces the peer has.
+    pub interested: bool,
+    /// Where the peer is, when the session has GeoIP databases to look it up in.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub location: Option<Location>,
+}
+
+impl PeerSnapshot {
+    pub fn new(
+        addr: SocketAddr,
+        info_hash: [u8; 20],
+        peer_id: Option<[u8; 20]>,
+        stats: &PeerStats,
+    ) -> Self {
+        Self {
+            addr,
+            info_hash,
+            client: peer_id.as_ref().and_then(client_name),
+            downloaded: stats.bytes_downloaded,
+            uploaded: stats.bytes_uploaded,
+            download_rate: stats.download_rate(),
+            upload_rate: stats.upload_rate(),
+            choked: false,
+            interested: false,
+            location: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerSource {
+    Tracker,
+    Inbound,
+    Manual,
+    Holepunch,
+}
+
+/// A peer we could connect to, along with what we have learned about it so far.
+#[derive(Debug, Clone)]
+pub struct PeerCandidate {
+    pub addr: SocketAddr,
+    pub source: PeerSource,
+    /// Connection attempts that failed in a row.
+    pub failures: u32,
+    /// Established connections that ended in an error.
+    pub drops: u32,
+    pub attempts: u32,
+    pub last_attempt: Option<Instant>,
+    pub retry_at: Option<Instant>,
+    pub hash_fails: u32,
+    pub unsolicited_blocks: u32,
+    pub download_rate: f64,
+    pub latency: Option<Duration>,
+}
+
+impl PeerCandidate {
+    fn new(addr: SocketAddr, source: PeerSource) -> Self {
+        Self {
+            addr,
+            source,
+            failures: 0,
+            drops: 0,
+            attempts: 0,
+            last_attempt: None,
+            retry_at: None,
+            hash_fails: 0,
+            unsolicited_blocks: 0,
+            download_rate: 0.0,
+            latency: None,
+        }
+    }
+
+    fn is_worth_trying(&self) -> bool {
+        self.failures < MAX_CONNECT_ATTEMPTS && self.drops < MAX_DROPS
+    }
+}
+
+/// Prefers peers the user asked for, then fast and responsive peers, and steers away from
+/// peers that failed to connect or sent us bad data.
+pub fn default_score(peer: &PeerCandidate) -> f64 {
+    let mut score = match peer.source {
+        PeerSource::Manual => 100.0,
+        PeerSource::Holepunch => 3.0,
+        PeerSource::Inbound => 2.0,
+        PeerSource::Tracker => 1.0,
+    };
+
+    score += peer.download_rate / 16384.0;
+    if let Some(latency) = peer.latency {
+        score -= latency.as_secs_f64();
+    }
+    score -= peer.failures as f64 * 5.0;
+    score -= peer.drops as f64 * 10.0;
+    score -= peer.hash_fails as f64 * 20.0;
+    score -= peer.unsolicited_blocks as f64;
+
+    score
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::SocketAddr,
+        time::{Duration, Instant},
+    };
+
+    use super::{ConnectionLimits, PeerManager, PeerSource, MAX_CONNECT_ATTEMPTS, MAX_DROPS};
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn failures_push_peers_down_the_ranking() {
+        let mut peer_manager = PeerManager::new();
+        peer_manager.add_candidates([addr(1), addr(2)], PeerSource::Tracker);
+        peer_manager.record_failure(addr(1));
+
+        assert_eq!(peer_manager.ranked_candidates(), vec![addr(2), addr(1)]);
+    }
+
+    #[test]
+    fn failed_peers_back_off_exponentially() {
+        let mut peer_manager = PeerManager::new();
+        peer_manager.add_candidates([addr(1), addr(2)], PeerSource::Tracker);
+        let info_hash = [0; 20];
+
+        assert!(peer_manager.begin_connect(addr(1), info_hash));
+        peer_manager.finish_connect(addr(1), info_hash, false);
+        let now = Instant::now();
+        assert_eq!(peer_manager.connectable_candidates(now), vec![addr(2)]);
+        let first_retry = peer_manager.next_retry().unwrap();
+        assert!(first_retry > now);
+        assert_eq!(
+            peer_manager.connectable_candidates(first_retry),
+            vec![addr(2), addr(1)]
+        );
+
+        peer_manager.record_failure(addr(1));
+        assert!(peer_manager.next_retry().unwrap() >= first_retry + Duration::from_secs(2));
+
+        for _ in 2..MAX_CONNECT_ATTEMPTS {
+            peer_manager.record_failure(addr(1));
+        }
+        let later = Instant::now() + Duration::from_secs(3600);
+        assert_eq!(peer_manager.connectable_candidates(later), vec![addr(2)]);
+        assert_eq!(peer_manager.next_retry(), None);
+    }
+
+    #[test]
+    fn dropped_peers_are_given_up_on_even_after_reconnecting() {
+        let mut peer_manager = PeerManager::new();
+        peer_manager.add_candidates([addr(1), addr(2)], PeerSource::Tracker);
+        let info_hash = [0; 20];
+
+        for _ in 0..MAX_DROPS {
+            assert!(peer_manager.begin_connect(addr(1), info_hash));
+            peer_manager.finish_connect(addr(1), info_hash, true);
+            peer_manager.connection_closed(addr(1));
+            peer_manager.record_drop(addr(1));
+        }
+        let later = Instant::now() + Duration::from_secs(3600);
+        assert_eq!(peer_manager.connectable_candidates(later), vec![addr(2)]);
+        assert_eq!(peer_manager.next_retry(), None);
+    }
+
+    #[test]
+    fn manual_peers_rank_first() {
+        let mut peer_manager = PeerManager::new();
+        peer_manager.add_candidates([addr(1)], PeerSource::Tracker);
+        peer_manager.add_candidates([addr(2)], PeerSource::Manual);
+
+        assert_eq!(peer_manager.ranked_candidates()[0], addr(2));
+    }
+
+    #[test]
+    fn holepunch_clears_failures() {
+        let mut peer_manager = PeerManager::new();
+        peer_manager.add_candidates([addr(1), addr(2)], PeerSource::Tracker);
+        peer_manager.record_failure(addr(1));
+        assert_eq!(peer_manager.unreachable_candidates(), vec![addr(1)]);
+
+        peer_manager.record_holepunch(addr(1));
+        assert!(peer_manager.unreachable_candidates().is_empty());
+        assert_eq!(peer_manager.ranked_candidates()[0], addr(1));
+    }
+
+    #[test]
+    fn blocked_peers_are_never_candidates() {
+        let mut peer_manager = PeerManager::new();
+        peer_manager.add_candidates([addr(1)], PeerSource::Tracker);
+        peer_manager.set_ip_filter("127.0.0.0/8".parse().unwrap());
+        peer_manager.add_candidates([addr(2)], PeerSource::Manual);
+
+        assert!(peer_manager.ranked_candidates().is_empty());
+        assert!(peer_manager.is_blocked(addr(3)));
+    }
+
+    #[test]
+    fn banned_peers_are_dropped() {
+        let mut peer_manager = PeerManager::new();
+        peer_manager.add_candidates([addr(1), addr(2)], PeerSource::Tracker);
+        peer_manager.ban(addr(1));
+        peer_manager.add_candidates([addr(1)], PeerSource::Manual);
+
+        assert_eq!(peer_manager.ranked_candidates(), vec![addr(2)]);
+        assert!(peer_manager.is_blocked(addr(1)));
+    }
+
+    #[test]
+    fn limits_queue_excess_candidates() {
+        let mut peer_manager = PeerManager::new();
+        peer_manager.set_limits(ConnectionLimits {
+            global: 2,
+            per_torrent: 2,
+            half_open: 1,
+        });
+        peer_manager.add_candidates([addr(1), addr(2), addr(3)], PeerSource::Tracker);
+
+        assert!(peer_manager.begin_connect(addr(1), [0; 20]));
+        assert!(!peer_manager.begin_connect(addr(2), [0; 20]));
+        peer_manager.finish_connect(addr(1), [0; 20], true);
+        assert!(peer_manager.begin_connect(addr(2), [0; 20]));
+        peer_manager.finish_connect(addr(2), [0; 20], true);
+
+        assert!(!peer_manager.begin_connect(addr(3), [0; 20]));
+        assert!(!peer_manager.can_accept([0; 20]));
+        assert_eq!(peer_manager.ranked_candidates(), vec![addr(3)]);
+
+        peer_manager.connection_closed(addr(1));
+        assert!(peer_manager.begin_connect(addr(3), [0; 20]));
+    }
+
+    #[test]
+    fn inbound_handshakes_count_as_half_open() {
+        let mut peer_manager = PeerManager::new();
+        peer_manager.set_limits(ConnectionLimits {
+            global: 2,
+            per_torrent: 1,
+            half_open: 1,
+        });
+
+        assert!(peer_manager.begin_accept(addr(1)));
+        assert!(!peer_manager.begin_accept(addr(2)));
+        assert!(!peer_manager.begin_connect(addr(2), [0; 20]));
+        assert!(!peer_manager.finish_accept(addr(1), None));
+
+        assert!(peer_manager.begin_accept(addr(2)));
+        assert!(peer_manager.finish_accept(addr(2), Some([0; 20])));
+        peer_manager.begin_connect(addr(3), [0; 20]);
+        peer_manager.finish_connect(addr(3), [0; 20], true);
+        assert!(peer_manager.begin_accept(addr(4)));
+        assert!(!peer_manager.finish_accept(addr(4), Some([0; 20])));
+    }
+
+    #[test]
+    fn custom_score() {
+        let mut peer_manager = PeerManager::new().with_score(|peer| peer.addr.port() as f64);
+        peer_manager.add_candidates([addr(1), addr(3), addr(2)], PeerSource::Tracker);
+
+        assert_eq!(
+            peer_manager.ranked_candidates(),
+            vec![addr(3), addr(2), addr(1)]
+        );
+    }
+}
@@ -0,0 +1,298 @@
+//! Peers that download blocks for the coordinator from threads of their own, so the blocks of a
+//! piece can come from several peers at once. The coordinator's own peer works through a
+//! piece's missing blocks while workers are handed others from the same queue; each worker
+//! fetches the blocks it is given one at a time and sends them back to be put into the piece.
+
+use std::{
+    net::SocketAddr,
+    sync::mpsc::{self, Receiver, Sender, TryRecvError},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    bitfield::Bitfield,
+    clock::Clock,
+    executor::{self, Task},
+    log,
+    phase::DownloadPhase,
+    peer::{PeerConnection, PeerError, Received, REQUEST_TIMEOUT},
+    shutdown::Shutdown,
+    wire::{BlockRequest, MessageId},
+};
+
+/// How many peers download alongside the coordinator's own.
+pub const DEFAULT_PEER_WORKERS: usize = 3;
+/// Blocks queued with a worker at once, so it has the next one to hand while the coordinator
+/// is busy with its own peer.
+pub const MAX_QUEUED_BLOCKS: usize = 4;
+// How often a worker waiting on its peer checks for new blocks to fetch and for being stopped.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// What a worker needs to connect to its peer.
+#[derive(Debug, Clone)]
+pub struct WorkerSetup {
+    pub info_hash: String,
+    pub piece_count: usize,
+    /// The pieces we had when the worker started, to tell whether the peer has any we need.
+    pub completed: Bitfield,
+    pub peer_id: [u8; 20],
+    pub dht_port: Option<u16>,
+    pub clock: Clock,
+}
+
+/// What a worker has to tell the coordinator.
+#[derive(Debug)]
+pub enum WorkerUpdate {
+    /// The peer finished its handshake and has these pieces.
+    Ready(Bitfield),
+    /// The peer now has these pieces too.
+    Have(Vec<usize>),
+    /// A block the worker was asked for.
+    Block {
+        request: BlockRequest,
+        data: Vec<u8>,
+        latency: Duration,
+    },
+    /// The connection failed. Nothing more comes from the worker.
+    Failed(PeerError),
+}
+
+/// A peer downloading on its own thread. Its task returns the connection once the worker
+/// stops, or nothing if it never got one, so how the peer did can be recorded.
+pub struct PeerWorker {
+    addr: SocketAddr,
+    // Known once the peer is ready.
+    pieces: Option<Bitfield>,
+    jobs: Sender<BlockRequest>,
+    updates: Receiver<WorkerUpdate>,
+    // Blocks asked for that have not come back yet.
+    queued: Vec<BlockRequest>,
+    stop: Shutdown,
+    // Taken once the worker is stopped.
+    task: Option<Task<Option<PeerConnection>>>,
+}
+
+impl PeerWorker {
+    /// Connects to `addr` on a background thread, which then fetches whatever blocks it is
+    /// asked for until `stop` is requested or the worker is dropped.
+    pub fn spawn(addr: SocketAddr, setup: WorkerSetup, stop: Shutdown) -> Self {
+        let (jobs, job_receiver) = mpsc::channel();
+        let (update_sender, updates) = mpsc::channel();
+        let worker_stop = stop.clone();
+
+        let task = executor::spawn("peer", move || {
+            let mut peer = match connect(addr, &setup) {
+                Ok(peer) => peer,
+                Err(error) => {
+                    let _ = update_sender.send(WorkerUpdate::Failed(error));
+                    return None;
+                }
+            };
+            let _span = peer.span().enter();
+            let _ = update_sender.send(WorkerUpdate::Ready(peer.pieces().clone()));
+
+            let mut worker = Worker {
+                peer: &mut peer,
+                clock: &setup.clock,
+                updates: &update_sender,
+                stop: &worker_stop,
+            };
+            if let Err(error) = worker.serve(&job_receiver) {
+                let _ = update_sender.send(WorkerUpdate::Failed(error));
+            }
+            Some(peer)
+        });
+
+        Self {
+            addr,
+            pieces: None,
+            jobs,
+            updates,
+            queued: Vec::new(),
+            stop,
+            task: Some(task),
+        }
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// The pieces the peer has, once it is ready.
+    pub fn pieces(&self) -> Option<&Bitfield> {
+        self.pieces.as_ref()
+    }
+
+    /// Whether the worker is ready and has room for another block.
+    pub fn wants_blocks(&self) -> bool {
+        self.pieces.is_some() && self.queued.len() < MAX_QUEUED_BLOCKS
+    }
+
+    /// Whether the worker has blocks still to send back.
+    pub fn is_busy(&self) -> bool {
+        !self.queued.is_empty()
+    }
+
+    /// The blocks asked for that have not come back yet.
+    pub fn queued(&self) -> &[BlockRequest] {
+        &self.queued
+    }
+
+    pub fn request(&mut self, request: BlockRequest) {
+        // A worker that has stopped says why in its updates.
+        if self.jobs.send(request).is_ok() {
+            self.queued.push(request);
+        }
+    }
+
+    /// The next thing the worker has to tell us, if there is one. Keeps track of the pieces
+    /// the peer has and of the blocks still queued on the way.
+    pub fn update(&mut self) -> Option<WorkerUpdate> {
+        let update = match self.updates.try_recv() {
+            Ok(update) => update,
+            Err(TryRecvError::Empty) => return None,
+            // Nothing more comes once the task has ended.
+            Err(TryRecvError::Disconnected) => return None,
+        };
+        match &update {
+            WorkerUpdate::Ready(pieces) => self.pieces = Some(pieces.clone()),
+            WorkerUpdate::Have(indexes) => {
+                if let Some(pieces) = &mut self.pieces {
+                    for index in indexes {
+                        pieces.set(*index);
+                    }
+                }
+            }
+            WorkerUpdate::Block { request, .. } => {
+                self.queued.retain(|queued| queued != request);
+            }
+            WorkerUpdate::Failed(_) => {}
+        }
+        Some(update)
+    }
+
+    /// Stops the worker and waits for it, returning its connection if it had one. Blocks it
+    /// had not sent back are left in `queued`, and updates sent before it stopped can still be
+    /// read.
+    pub fn stop(&mut self) -> Option<PeerConnection> {
+        self.stop.request();
+        self.task.take()?.join().ok().flatten()
+    }
+}
+
+fn connect(addr: SocketAddr, setup: &WorkerSetup) -> Result<PeerConnection, PeerError> {
+    let mut peer = PeerConnection::connect(addr, setup.piece_count)?;
+    peer.set_clock(setup.clock.clone());
+    let (peer, _) = peer.handshake(setup.info_hash.clone(), setup.peer_id, setup.dht_port)?;
+    let (mut peer, messages) = peer.receive_bitfield()?;
+    for message in &messages {
+        peer.record_availability(message);
+    }
+    peer.update_interest(&setup.completed)?;
+    if !peer.is_interested() {
+        return Err(PeerError::NothingWanted);
+    }
+    Ok(peer)
+}
+
+/// The worker thread's side of a `PeerWorker`.
+struct Worker<'a> {
+    peer: &'a mut PeerConnection,
+    clock: &'a Clock,
+    updates: &'a Sender<WorkerUpdate>,
+    stop: &'a Shutdown,
+}
+
+impl Worker<'_> {
+    /// Fetches each block it is sent in turn, reading what the peer sends while it waits for
+    /// the next one.
+    fn serve(&mut self, jobs: &Receiver<BlockRequest>) -> Result<(), PeerError> {
+        while !self.stop.is_requested() {
+            let request = match jobs.try_recv() {
+                Ok(request) => request,
+                Err(TryRecvError::Empty) => {
+                    if self.peer.wait_for_data(self.clock.now() + POLL_INTERVAL)? {
+                        self.receive(&mut [], 0)?;
+                    }
+                    continue;
+                }
+                Err(TryRecvError::Disconnected) => break,
+            };
+
+            let mut block = vec![0; request.length as usize];
+            let Some(requested_at) = self.fetch(request, &mut block)? else {
+                break;
+            };
+            let latency = self.clock.now().duration_since(requested_at);
+            self.peer.stats_mut().record_latency(latency);
+            self.peer.stats_mut().record_download(block.len());
+            let update = WorkerUpdate::Block {
+                request,
+                data: block,
+                latency,
+            };
+            if self.updates.send(update).is_err() {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Asks the peer for `request`, again whenever it chokes us, and waits for it. Returns when
+    /// it was last asked for, or `None` if the worker was stopped first. A request left
+    /// unanswered past its deadline fails with `PeerError::Stalled`.
+    fn fetch(
+        &mut self,
+        request: BlockRequest,
+        block: &mut [u8],
+    ) -> Result<Option<Instant>, PeerError> {
+        let mut requested_at = None;
+        loop {
+            if self.stop.is_requested() {
+                self.peer.cancel_requests()?;
+                return Ok(None);
+            }
+            if requested_at.is_none() && self.peer.phase() == DownloadPhase::Downloading {
+                log::trace!(peer = self.peer.addr(), piece = request.index; "requesting block at {}", request.begin);
+                requested_at = Some(self.peer.request_block(request)?);
+            }
+
+            let now = self.clock.now();
+            let deadline = self.peer.oldest_request().map(|sent| sent + REQUEST_TIMEOUT);
+            let wait_until = deadline.map_or(now + POLL_INTERVAL, |deadline| {
+                deadline.min(now + POLL_INTERVAL)
+            });
+            if !self.peer.wait_for_data(wait_until)? {
+                if deadline.is_some_and(|deadline| self.clock.now() >= deadline)
+                    && !self.peer.cancel_stalled_blocks()?.is_empty()
+                {
+                    return Err(PeerError::Stalled);
+                }
+                continue;
+            }
+
+            match self.receive(block, request.begin as usize)? {
+                Some(MessageId::Piece) => return Ok(requested_at),
+                // A peer that chokes us drops what we asked for.
+                Some(MessageId::Choke) => requested_at = None,
+                _ => {}
+            }
+        }
+    }
+
+    /// Reads one frame into `buffer`, which holds the piece from byte `start` on, passing on
+    /// pieces the peer says it now has. Returns what the frame was, if it was anything.
+    fn receive(&mut self, buffer: &mut [u8], start: usize) -> Result<Option<MessageId>, PeerError> {
+        match self.peer.receive_into_at(buffer, start)? {
+            Received::Block(_) => Ok(Some(MessageId::Piece)),
+            Received::Message(message) => {
+                let have = self.peer.record_availability(&message);
+                if !have.is_empty() {
+                    let _ = self.updates.send(WorkerUpdate::Have(have));
+                }
+                Ok(Some(message.id))
+            }
+            Received::Nothing => Ok(None),
+        }
+    }
+}
@@ -0,0 +1,79 @@
+//! How far a download from a ready peer has got. Unlike a connection's states, which only move
+//! forwards and are checked when compiling, the phase moves back and forth with the peer's
+//! chokes and unchokes and our interest, so it is followed at run time.
+
+use crate::wire::MessageId;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DownloadPhase {
+    /// We have not told the peer we want anything from it. It may have unchoked us already.
+    Idle { choked: bool },
+    /// We are interested and waiting for the peer to unchoke us.
+    WaitingForUnchoke,
+    /// We are interested and unchoked, so blocks can be requested.
+    Downloading,
+}
+
+impl Default for DownloadPhase {
+    /// Every connection starts out choked and uninterested.
+    fn default() -> Self {
+        Self::Idle { choked: true }
+    }
+}
+
+impl DownloadPhase {
+    /// The phase once we have told the peer whether we are `interested`.
+    pub fn on_interest(self, interested: bool) -> Self {
+        match (interested, self.is_choked()) {
+            (false, choked) => Self::Idle { choked },
+            (true, true) => Self::WaitingForUnchoke,
+            (true, false) => Self::Downloading,
+        }
+    }
+
+    /// The phase once the peer has sent a message with `id`. Only chokes and unchokes move it.
+    pub fn on_message(self, id: MessageId) -> Self {
+        match (self, id) {
+            (Self::Idle { .. }, MessageId::Choke) => Self::Idle { choked: true },
+            (Self::Idle { .. }, MessageId::Unchoke) => Self::Idle { choked: false },
+            (_, MessageId::Choke) => Self::WaitingForUnchoke,
+            (_, MessageId::Unchoke) => Self::Downloading,
+            (phase, _) => phase,
+        }
+    }
+
+    pub fn is_choked(self) -> bool {
+        match self {
+            Self::Idle { choked } => choked,
+            Self::WaitingForUnchoke => true,
+            Self::Downloading => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DownloadPhase;
+    use crate::wire::MessageId;
+
+    #[test]
+    fn follows_interest_and_chokes() {
+        let phase = DownloadPhase::default().on_interest(true);
+        assert_eq!(phase, DownloadPhase::WaitingForUnchoke);
+        let phase = phase.on_message(MessageId::Have);
+        assert_eq!(phase, DownloadPhase::WaitingForUnchoke);
+        let phase = phase.on_message(MessageId::Unchoke);
+        assert_eq!(phase, DownloadPhase::Downloading);
+        let phase = phase.on_message(MessageId::Choke);
+        assert_eq!(phase, DownloadPhase::WaitingForUnchoke);
+
+        // An unchoke that arrives before our interest is remembered.
+        let phase = DownloadPhase::default().on_message(MessageId::Unchoke);
+        assert_eq!(phase, DownloadPhase::Idle { choked: false });
+        assert_eq!(phase.on_interest(true), DownloadPhase::Downloading);
+        assert_eq!(
+            DownloadPhase::Downloading.on_interest(false),
+            DownloadPhase::Idle { choked: false }
+        );
+    }
+}
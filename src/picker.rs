@@ -0,0 +1,172 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::bitfield::Bitfield;
+
+// How many pieces the random-first strategy fetches at random before switching to rarest-first.
+const RANDOM_FIRST_PIECES: usize = 4;
+
+/// Chooses which piece to download next.
+pub trait PiecePicker: Send {
+    /// Picks a piece the peer has and we are still missing, or `None` if there isn't one.
+    /// `availability` holds how many known peers have each piece.
+    fn pick(
+        &mut self,
+        completed: &Bitfield,
+        peer_pieces: &Bitfield,
+        availability: &[u32],
+    ) -> Option<usize>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum PickerKind {
+    Sequential,
+    RarestFirst,
+    RandomFirst,
+}
+
+impl PickerKind {
+    pub fn build(self) -> Box<dyn PiecePicker> {
+        match self {
+            PickerKind::Sequential => Box::new(SequentialPicker),
+            PickerKind::RarestFirst => Box::new(RarestFirstPicker),
+            PickerKind::RandomFirst => Box::new(RandomFirstPicker::new(seed_from_clock())),
+        }
+    }
+}
+
+/// Downloads pieces in order, which is what streaming to a pipe needs.
+#[derive(Debug, Default)]
+pub struct SequentialPicker;
+
+impl PiecePicker for SequentialPicker {
+    fn pick(&mut self, completed: &Bitfield, peer_pieces: &Bitfield, _: &[u32]) -> Option<usize> {
+        wanted(completed, peer_pieces).next()
+    }
+}
+
+/// Downloads the pieces fewest peers have first, so they don't disappear from the swarm.
+#[derive(Debug, Default)]
+pub struct RarestFirstPicker;
+
+impl PiecePicker for RarestFirstPicker {
+    fn pick(
+        &mut self,
+        completed: &Bitfield,
+        peer_pieces: &Bitfield,
+        availability: &[u32],
+    ) -> Option<usize> {
+        wanted(completed, peer_pieces)
+            .min_by_key(|index| availability.get(*index).copied().unwrap_or(0))
+    }
+}
+
+/// Picks the first few pieces at random so we quickly have something to trade, then falls back
+/// to rarest-first.
+#[derive(Debug)]
+pub struct RandomFirstPicker {
+    state: u64,
+}
+
+impl RandomFirstPicker {
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed | 1 }
+    }
+
+    // xorshift64, good enough for spreading piece choices around.
+    fn next_random(&mut self) -> u64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.state
+    }
+}
+
+impl PiecePicker for RandomFirstPicker {
+    fn pick(
+        &mut self,
+        completed: &Bitfield,
+        peer_pieces: &Bitfield,
+        availability: &[u32],
+    ) -> Option<usize> {
+        let have = (0..completed.len())
+            .filter(|index| completed.has(*index))
+            .count();
+        if have >= RANDOM_FIRST_PIECES {
+            return RarestFirstPicker.pick(completed, peer_pieces, availability);
+        }
+
+        let candidates = wanted(completed, peer_pieces).collect::<Vec<_>>();
+        if candidates.is_empty() {
+            return None;
+        }
+
+        let choice = self.next_random() as usize % candidates.len();
+        Some(candidates[choice])
+    }
+}
+
+fn wanted<'a>(
+    completed: &'a Bitfield,
+    peer_pieces: &'a Bitfield,
+) -> impl Iterator<Item = usize> + 'a {
+    (0..completed.len()).filter(|index| peer_pieces.has(*index) && !completed.has(*index))
+}
+
+fn seed_from_clock() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_nanos() as u64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PiecePicker, RandomFirstPicker, RarestFirstPicker, SequentialPicker};
+    use crate::bitfield::Bitfield;
+
+    #[test]
+    fn sequential_skips_completed_and_unavailable() {
+        let mut completed = Bitfield::new(4);
+        completed.set(0);
+        let peer_pieces = Bitfield::from_bytes(&[0b1011_0000], 4);
+
+        assert_eq!(
+            SequentialPicker.pick(&completed, &peer_pieces, &[1, 0, 1, 1]),
+            Some(2)
+        );
+    }
+
+    #[test]
+    fn rarest_first_prefers_least_available() {
+        let completed = Bitfield::new(4);
+        let peer_pieces = Bitfield::from_bytes(&[0b1111_0000], 4);
+
+        assert_eq!(
+            RarestFirstPicker.pick(&completed, &peer_pieces, &[3, 2, 1, 2]),
+            Some(2)
+        );
+    }
+
+    #[test]
+    fn random_first_only_picks_wanted_pieces() {
+        let completed = Bitfield::new(8);
+        let peer_pieces = Bitfield::from_bytes(&[0b0100_0100], 8);
+        let mut picker = RandomFirstPicker::new(42);
+
+        for _ in 0..16 {
+            let pick = picker.pick(&completed, &peer_pieces, &[1; 8]).unwrap();
+            assert!(pick == 1 || pick == 5);
+        }
+    }
+
+    #[test]
+    fn nothing_left_to_pick() {
+        let completed = Bitfield::from_bytes(&[0b1100_0000], 2);
+        let peer_pieces = Bitfield::from_bytes(&[0b1100_0000], 2);
+
+        assert_eq!(
+            SequentialPicker.pick(&completed, &peer_pieces, &[1, 1]),
+            None
+        );
+    }
+}
@@ -0,0 +1,160 @@
+use std::{collections::VecDeque, fmt::Display, io};
+
+use crate::{
+    memory::{Charge, MemoryBudget, MemoryKind},
+    storage::Storage,
+};
+
+/// Bytes of recently served pieces kept in memory by default.
+pub const DEFAULT_CACHE_SIZE: usize = 16 * 1024 * 1024;
+
+/// Recently read pieces, kept so that a popular piece requested block by block, and by peer
+/// after peer, is only read from disk once. The least recently used piece is evicted when the
+/// cache grows past its size in bytes, or while the memory budget is exceeded.
+pub struct PieceCache {
+    capacity: usize,
+    size: usize,
+    charge: Charge,
+    // Most recently used at the back.
+    pieces: VecDeque<(usize, Vec<u8>)>,
+    stats: CacheStats,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl Display for CacheStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} hits, {} misses", self.hits, self.misses)
+    }
+}
+
+// Leaves out the cached bytes themselves.
+impl std::fmt::Debug for PieceCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PieceCache")
+            .field("capacity", &self.capacity)
+            .field("size", &self.size)
+            .field("pieces", &self.pieces.len())
+            .field("stats", &self.stats)
+            .finish()
+    }
+}
+
+impl PieceCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            size: 0,
+            charge: MemoryBudget::default().charge(MemoryKind::PieceCache, 0),
+            pieces: VecDeque::new(),
+            stats: CacheStats::default(),
+        }
+    }
+
+    /// Charges the cached pieces to `memory` instead.
+    pub fn set_memory_budget(&mut self, memory: &MemoryBudget) {
+        self.charge = memory.charge(MemoryKind::PieceCache, self.size as u64);
+    }
+
+    /// Reads `length` bytes at `begin` into piece `piece_index`, which is `piece_length` bytes
+    /// long, reading the whole piece from `storage` if it is not cached.
+    pub fn read_block(
+        &mut self,
+        storage: &mut dyn Storage,
+        piece_index: usize,
+        piece_length: usize,
+        begin: usize,
+        length: usize,
+    ) -> io::Result<Vec<u8>> {
+        if begin + length > piece_length {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "block runs past the end of the piece",
+            ));
+        }
+
+        let position = self
+            .pieces
+            .iter()
+            .position(|(index, _)| *index == piece_index);
+        let entry = match position {
+            Some(position) => {
+                self.stats.hits += 1;
+                self.pieces.remove(position).unwrap()
+            }
+            None => {
+                self.stats.misses += 1;
+                let piece = storage.read_block(piece_index, 0, piece_length)?;
+                self.size += piece.len();
+                (piece_index, piece)
+            }
+        };
+        let block = entry.1[begin..begin + length].to_vec();
+        self.pieces.push_back(entry);
+
+        // Always keep the piece just read, even if it is larger than the whole cache.
+        self.charge.set(self.size as u64);
+        while (self.size > self.capacity || self.charge.is_exceeded()) && self.pieces.len() > 1 {
+            let (_, evicted) = self.pieces.pop_front().unwrap();
+            self.size -= evicted.len();
+            self.charge.set(self.size as u64);
+        }
+        if self.capacity == 0 {
+            self.pieces.clear();
+            self.size = 0;
+            self.charge.set(0);
+        }
+
+        Ok(block)
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CacheStats, PieceCache};
+    use crate::storage::{MemoryStorage, Storage};
+
+    #[test]
+    fn evicts_the_least_recently_used_piece() {
+        let mut storage = MemoryStorage::new(4, 12);
+        for piece_index in 0..3 {
+            storage
+                .write_block(piece_index, 0, &[piece_index as u8; 4])
+                .unwrap();
+        }
+
+        let mut cache = PieceCache::new(8);
+        let mut read = |cache: &mut PieceCache, piece_index| {
+            cache
+                .read_block(&mut storage, piece_index, 4, 1, 2)
+                .unwrap()
+        };
+        assert_eq!(read(&mut cache, 0), [0, 0]);
+        assert_eq!(read(&mut cache, 1), [1, 1]);
+        assert_eq!(read(&mut cache, 0), [0, 0]);
+        // Piece 1 is now the least recently used, so it makes room for piece 2.
+        assert_eq!(read(&mut cache, 2), [2, 2]);
+        assert_eq!(read(&mut cache, 0), [0, 0]);
+        assert_eq!(read(&mut cache, 1), [1, 1]);
+        assert_eq!(cache.stats(), CacheStats { hits: 2, misses: 4 });
+
+        assert!(cache.read_block(&mut storage, 0, 4, 3, 2).is_err());
+    }
+
+    #[test]
+    fn caches_nothing_when_disabled() {
+        let mut storage = MemoryStorage::new(4, 4);
+        let mut cache = PieceCache::new(0);
+        cache.read_block(&mut storage, 0, 4, 0, 4).unwrap();
+        cache.read_block(&mut storage, 0, 4, 0, 4).unwrap();
+        assert_eq!(cache.stats(), CacheStats { hits: 0, misses: 2 });
+    }
+}
@@ -0,0 +1,207 @@
+use std::{
+    collections::HashSet,
+    io::{self, Write},
+    sync::mpsc::{Receiver, RecvTimeoutError},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    events::{Event, TorrentEvent},
+    executor::{self, Task},
+};
+
+// How often the progress line is redrawn.
+const REDRAW_INTERVAL: Duration = Duration::from_millis(200);
+const BAR_WIDTH: usize = 30;
+
+/// A single progress line on stderr, redrawn in place as the download goes: how many pieces we
+/// have, how fast they are arriving, when we should be done and how many peers we are talking to.
+/// It follows the download through its events.
+pub struct Progress {
+    started: Instant,
+    last_drawn: Option<Instant>,
+    total_pieces: usize,
+    total_bytes: u64,
+    // Bytes we already had when the download started, which do not count towards the rate.
+    initial_bytes: u64,
+    pieces: usize,
+    bytes: u64,
+    // Peers and web seeds we are connected to.
+    peers: HashSet<String>,
+}
+
+impl Progress {
+    /// Starts from the `pieces` and `bytes` we already have.
+    pub fn new(total_pieces: usize, total_bytes: u64, pieces: usize, bytes: u64) -> Self {
+        Self {
+            started: Instant::now(),
+            last_drawn: None,
+            total_pieces,
+            total_bytes,
+            initial_bytes: bytes,
+            pieces,
+            bytes,
+            peers: HashSet::new(),
+        }
+    }
+
+    /// Draws the line from `events` on its own thread, moving past it once the download stops.
+    pub fn spawn(mut self, events: Receiver<Event>) -> Task<()> {
+        executor::spawn("progress", move || loop {
+            // Redrawn between events too, so the rate and ETA keep moving.
+            match events.recv_timeout(REDRAW_INTERVAL) {
+                Ok(event) if !self.apply(&event.event) => return self.finish(),
+                Ok(_) | Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return self.finish(),
+            }
+            if self.is_due() {
+                self.draw();
+            }
+        })
+    }
+
+    /// Takes in an event, returning whether the download is still going.
+    fn apply(&mut self, event: &TorrentEvent) -> bool {
+        match event {
+            TorrentEvent::Checked { pieces, bytes, .. }
+            | TorrentEvent::PieceVerified { pieces, bytes, .. } => {
+                self.pieces = *pieces;
+                self.bytes = *bytes;
+            }
+            TorrentEvent::PeerConnected { addr } => {
+                self.peers.insert(addr.clone());
+            }
+            TorrentEvent::PeerDisconnected { addr } => {
+                self.peers.remove(addr);
+            }
+            TorrentEvent::Stopped => return false,
+            _ => {}
+        }
+        true
+    }
+
+    /// Whether enough time has passed to draw the line again.
+    fn is_due(&self) -> bool {
+        self.last_drawn
+            .is_none_or(|drawn| drawn.elapsed() >= REDRAW_INTERVAL)
+    }
+
+    fn draw(&mut self) {
+        self.last_drawn = Some(Instant::now());
+        let line = self.render(self.started.elapsed());
+        let mut stderr = io::stderr().lock();
+        let _ = write!(stderr, "\r\x1b[2K{}", line);
+        let _ = stderr.flush();
+    }
+
+    /// Draws the line one last time and moves past it.
+    fn finish(&mut self) {
+        self.draw();
+        eprintln!();
+    }
+
+    fn render(&self, elapsed: Duration) -> String {
+        let (pieces, bytes, peers) = (self.pieces, self.bytes, self.peers.len());
+        let filled = (pieces * BAR_WIDTH)
+            .checked_div(self.total_pieces)
+            .unwrap_or(BAR_WIDTH);
+        let bar = format!("{}{}", "#".repeat(filled), ".".repeat(BAR_WIDTH - filled));
+
+        let rate = bytes.saturating_sub(self.initial_bytes) as f64 / elapsed.as_secs_f64();
+        let remaining = self.total_bytes.saturating_sub(bytes);
+        let eta = if remaining == 0 {
+            "done".to_string()
+        } else if rate > 0.0 {
+            format_duration(Duration::from_secs_f64(remaining as f64 / rate))
+        } else {
+            "--:--".to_string()
+        };
+
+        format!(
+            "[{}] {}/{} pieces  {}/s  ETA {}  {} peers",
+            bar,
+            pieces,
+            self.total_pieces,
+            format_bytes(rate as u64),
+            eta,
+            peers
+        )
+    }
+}
+
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    if seconds >= 3600 {
+        format!(
+            "{}:{:02}:{:02}",
+            seconds / 3600,
+            seconds / 60 % 60,
+            seconds % 60
+        )
+    } else {
+        format!("{}:{:02}", seconds / 60, seconds % 60)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::Progress;
+    use crate::events::TorrentEvent;
+
+    #[test]
+    fn renders_rate_and_eta() {
+        let mut progress = Progress::new(10, 10 * 1024 * 1024, 1, 1024 * 1024);
+        assert!(progress.is_due());
+        let line = progress.render(Duration::from_secs(2));
+        assert!(line.contains("0 B/s  ETA --:--  0 peers"));
+
+        for addr in [
+            "10.0.0.1:6881",
+            "10.0.0.2:6881",
+            "http://seed/",
+            "10.0.0.2:6881",
+        ] {
+            let addr = addr.to_string();
+            assert!(progress.apply(&TorrentEvent::PeerConnected { addr }));
+        }
+        let verified = TorrentEvent::PieceVerified {
+            piece: 3,
+            pieces: 4,
+            bytes: 4 * 1024 * 1024,
+        };
+        assert!(progress.apply(&verified));
+        let line = progress.render(Duration::from_secs(2));
+        assert_eq!(
+            line,
+            "[############..................] 4/10 pieces  1.5 MiB/s  ETA 0:04  3 peers"
+        );
+
+        let verified = TorrentEvent::PieceVerified {
+            piece: 9,
+            pieces: 10,
+            bytes: 10 * 1024 * 1024,
+        };
+        assert!(progress.apply(&verified));
+        assert!(progress
+            .render(Duration::from_secs(3600))
+            .contains("ETA done"));
+        assert!(!progress.apply(&TorrentEvent::Stopped));
+    }
+}
@@ -0,0 +1,126 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::bitfield::Bitfield;
+
+/// What we knew about a torrent's download when we last stopped. If the output files are the
+/// same size and were last modified at the same time, the pieces can be trusted without
+/// hashing them all again.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResumeData {
+    pub info_hash: String,
+    /// The verified pieces, as a hex-encoded bitfield.
+    pub pieces: String,
+    pub files: Vec<FileState>,
+    pub uploaded: u64,
+    pub downloaded: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileState {
+    pub path: PathBuf,
+    pub size: u64,
+    /// Nanoseconds since the Unix epoch.
+    pub modified: u128,
+}
+
+impl FileState {
+    /// The size and modification time of each file, or `None` if any of them is missing.
+    pub fn read_all(paths: &[PathBuf]) -> Option<Vec<Self>> {
+        paths
+            .iter()
+            .map(|path| {
+                let metadata = fs::metadata(path).ok()?;
+                let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+                Some(Self {
+                    path: path.clone(),
+                    size: metadata.len(),
+                    modified: modified.as_nanos(),
+                })
+            })
+            .collect()
+    }
+}
+
+impl ResumeData {
+    pub fn new(
+        info_hash: String,
+        pieces: &Bitfield,
+        files: Vec<FileState>,
+        uploaded: u64,
+        downloaded: u64,
+    ) -> Self {
+        Self {
+            info_hash,
+            pieces: hex::encode(pieces.as_bytes()),
+            files,
+            uploaded,
+            downloaded,
+        }
+    }
+
+    /// Reads a resume file, treating one that is missing or unreadable as absent.
+    pub fn load<P: AsRef<Path>>(path: P) -> Option<Self> {
+        let contents = fs::read_to_string(path).ok()?;
+        serde_json::from_str(&contents).ok()
+    }
+
+    /// Writes the resume file through a temporary file, so a crash never leaves half of one.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let path = path.as_ref();
+        let temporary = path.with_extension("resume.tmp");
+        let contents = serde_json::to_string(self).expect("Failed to serialize resume data");
+        fs::write(&temporary, contents)?;
+        fs::rename(temporary, path)
+    }
+
+    /// The pieces recorded as verified, if the resume data is for this torrent and the files
+    /// on disk are exactly as we left them.
+    pub fn pieces_if_unchanged(
+        &self,
+        info_hash: &str,
+        files: &[FileState],
+        piece_count: usize,
+    ) -> Option<Bitfield> {
+        if self.info_hash != info_hash || self.files != files {
+            return None;
+        }
+        let bytes = hex::decode(&self.pieces).ok()?;
+        Some(Bitfield::from_bytes(&bytes, piece_count))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FileState, ResumeData};
+    use crate::bitfield::Bitfield;
+
+    #[test]
+    fn round_trips_and_notices_changed_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let payload = dir.path().join("payload");
+        std::fs::write(&payload, [0; 16]).unwrap();
+        let files = FileState::read_all(std::slice::from_ref(&payload)).unwrap();
+
+        let mut pieces = Bitfield::new(3);
+        pieces.set(1);
+        let resume = ResumeData::new("abcd".to_string(), &pieces, files.clone(), 10, 20);
+        let path = dir.path().join("payload.resume");
+        resume.save(&path).unwrap();
+
+        let loaded = ResumeData::load(&path).unwrap();
+        assert_eq!(loaded, resume);
+        assert_eq!(loaded.pieces_if_unchanged("abcd", &files, 3), Some(pieces));
+        assert_eq!(loaded.pieces_if_unchanged("ef01", &files, 3), None);
+
+        std::fs::write(&payload, [0; 8]).unwrap();
+        let changed = FileState::read_all(&[payload]).unwrap();
+        assert_eq!(loaded.pieces_if_unchanged("abcd", &changed, 3), None);
+        assert!(FileState::read_all(&[dir.path().join("missing")]).is_none());
+    }
+}
@@ -0,0 +1,379 @@
+//! A JSON-RPC control API for the daemon, over plain HTTP. Clients POST
+//! `{"method": "...", "params": {...}, "id": ...}` to `/rpc` with the daemon's token in an
+//! `Authorization: Bearer` header.
+//!
+//! Methods: `add` (`path` of a .torrent file or `magnet` link), `remove`, `pause`, `resume`,
+//! `status` (every torrent, or one `info_hash`), `peers` (of one `info_hash`), `session_stats`,
+//! `set_rate_limit` (`limit` in bytes per second, `"unlimited"` or `"paused"`) and `events`
+//! (those from number `since` on, with the `next` number to ask from).
+//!
+//! Connections are served within the timeouts and limits of [`http_server`].
+
+use std::{
+    io::{self, BufRead, BufReader, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::mpsc::{self, Sender},
+};
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::{bandwidth::Limit, daemon::Daemon, http_server, magnet::Magnet, torrent::Torrent};
+
+// Requests bigger than this are refused rather than read into memory.
+const MAX_BODY_LENGTH: usize = 1024 * 1024;
+
+/// The methods [`dispatch`] answers.
+pub const METHODS: &[&str] = &[
+    "add",
+    "remove",
+    "pause",
+    "resume",
+    "status",
+    "peers",
+    "session_stats",
+    "set_rate_limit",
+    "events",
+];
+
+/// A call for the daemon to answer on its own thread.
+pub struct RpcCall {
+    pub method: String,
+    pub params: Value,
+    pub reply: Sender<Result<Value, RpcError>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RpcRequest {
+    method: String,
+    #[serde(default)]
+    params: Value,
+    #[serde(default)]
+    id: Value,
+}
+
+#[derive(Debug, Deserialize)]
+struct AddParams {
+    path: Option<String>,
+    magnet: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TorrentParams {
+    info_hash: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct RateLimitParams {
+    limit: Value,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct StatusParams {
+    info_hash: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct EventsParams {
+    #[serde(default)]
+    since: u64,
+}
+
+/// Listens on `addr`, passing calls from clients with `token` on to `calls`. Returns the
+/// address listened on.
+pub fn serve(addr: &str, token: String, calls: Sender<RpcCall>) -> io::Result<SocketAddr> {
+    let listener = TcpListener::bind(addr)?;
+    let addr = listener.local_addr()?;
+    http_server::serve(listener, "api", move |stream| {
+        handle_connection(stream, &token, &calls)
+    });
+    Ok(addr)
+}
+
+/// Answers a call on the daemon's behalf.
+pub fn dispatch(daemon: &mut Daemon, method: &str, params: Value) -> Result<Value, RpcError> {
+    match method {
+        "add" => {
+            let params: AddParams = parse_params(params)?;
+            let info_hash = match (params.path, params.magnet) {
+                (Some(path), None) => {
+                    let torrent = Torrent::open(&path)
+                        .map_err(|error| RpcError::Failed(format!("{}: {}", path, error)))?;
+                    daemon.session_mut().add(torrent)
+                }
+                (None, Some(link)) => {
+                    let magnet = Magnet::parse(&link)
+                        .map_err(|error| RpcError::Failed(error.to_string()))?;
+                    daemon.session_mut().add_magnet(magnet)
+                }
+                _ => {
+                    return Err(RpcError::InvalidParams(
+                        "expected one of path or magnet".to_string(),
+                    ))
+                }
+            };
+            let info_hash = info_hash.map_err(|error| RpcError::Failed(error.to_string()))?;
+            Ok(json!({ "info_hash": info_hash }))
+        }
+        "remove" | "pause" | "resume" => {
+            let params: TorrentParams = parse_params(params)?;
+            let result = match method {
+                "remove" => daemon.session_mut().remove(&params.info_hash),
+                "pause" => daemon.session_mut().pause(&params.info_hash),
+                _ => daemon.session_mut().resume(&params.info_hash),
+            };
+            result.map_err(|error| RpcError::Failed(error.to_string()))?;
+            Ok(Value::Null)
+        }
+        "status" => {
+            let params: StatusParams = if params.is_null() {
+                StatusParams::default()
+            } else {
+                parse_params(params)?
+            };
+            match params.info_hash {
+                Some(info_hash) => daemon
+                    .session()
+                    .status_of(&info_hash)
+                    .map(|status| json!(status))
+                    .ok_or_else(|| {
+                        RpcError::Failed(format!("no torrent with info hash {}", info_hash))
+                    }),
+                None => Ok(json!(daemon.session().status())),
+            }
+        }
+        "peers" => {
+            let params: TorrentParams = parse_params(params)?;
+            daemon
+                .session()
+                .peers(&params.info_hash)
+                .map(|peers| json!(peers))
+                .ok_or_else(|| {
+                    RpcError::Failed(format!("no torrent with info hash {}", params.info_hash))
+                })
+        }
+        "session_stats" => Ok(json!(daemon.session().session_stats())),
+        "set_rate_limit" => {
+            let params: RateLimitParams = parse_params(params)?;
+            let limit = match params.limit {
+                Value::Number(rate) => rate.as_u64().map(Limit::BytesPerSecond),
+                Value::String(limit) => limit.parse().ok(),
+                _ => None,
+            };
+            let limit = limit.ok_or_else(|| {
+                RpcError::InvalidParams(
+                    "limit must be bytes per second, \"unlimited\" or \"paused\"".to_string(),
+                )
+            })?;
+            daemon.session_mut().set_rate_limit(limit);
+            Ok(Value::Null)
+        }
+        "events" => {
+            let params: EventsParams = if params.is_null() {
+                EventsParams::default()
+            } else {
+                parse_params(params)?
+            };
+            let (next, events) = daemon.events_since(params.since);
+            Ok(json!({ "next": next, "events": events }))
+        }
+        _ => Err(RpcError::UnknownMethod(method.to_string())),
+    }
+}
+
+fn parse_params<T: for<'de> Deserialize<'de>>(params: Value) -> Result<T, RpcError> {
+    serde_json::from_value(params).map_err(|error| RpcError::InvalidParams(error.to_string()))
+}
+
+struct HttpRequest {
+    method: String,
+    path: String,
+    authorization: Option<String>,
+    body: Vec<u8>,
+}
+
+fn handle_connection(stream: TcpStream, token: &str, calls: &Sender<RpcCall>) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let request = read_request(&mut reader)?;
+    let mut stream = stream;
+
+    if request.path != "/rpc" {
+        return write_response(
+            &mut stream,
+            "404 Not Found",
+            &json!({ "error": "not found" }),
+        );
+    }
+    if request.method != "POST" {
+        return write_response(
+            &mut stream,
+            "405 Method Not Allowed",
+            &json!({ "error": "use POST" }),
+        );
+    }
+    let presented = request
+        .authorization
+        .as_deref()
+        .and_then(|value| value.strip_prefix("Bearer "));
+    if !presented.is_some_and(|presented| tokens_match(presented, token)) {
+        return write_response(
+            &mut stream,
+            "401 Unauthorized",
+            &json!({ "error": "missing or wrong token" }),
+        );
+    }
+
+    let request: RpcRequest = match serde_json::from_slice(&request.body) {
+        Ok(request) => request,
+        Err(error) => {
+            let error = json!({ "code": -32700, "message": error.to_string() });
+            let response = json!({ "jsonrpc": "2.0", "error": error, "id": Value::Null });
+            return write_response(&mut stream, "400 Bad Request", &response);
+        }
+    };
+
+    let (reply, result) = mpsc::channel();
+    let call = RpcCall {
+        method: request.method,
+        params: request.params,
+        reply,
+    };
+    let result = calls
+        .send(call)
+        .ok()
+        .and_then(|_| result.recv().ok())
+        .unwrap_or_else(|| Err(RpcError::Failed("the daemon is shutting down".to_string())));
+    let response = match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "result": result, "id": request.id }),
+        Err(error) => {
+            let error = json!({ "code": error.code(), "message": error.to_string() });
+            json!({ "jsonrpc": "2.0", "error": error, "id": request.id })
+        }
+    };
+    write_response(&mut stream, "200 OK", &response)
+}
+
+fn read_request<R: BufRead>(reader: &mut R) -> io::Result<HttpRequest> {
+    let head = http_server::read_head(reader)?;
+    let content_length = match head.header("content-length") {
+        Some(value) => value
+            .parse()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "bad content length"))?,
+        None => 0,
+    };
+    if content_length > MAX_BODY_LENGTH {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "body too large"));
+    }
+
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+    Ok(HttpRequest {
+        authorization: head.header("authorization").map(str::to_string),
+        method: head.method,
+        path: head.target,
+        body,
+    })
+}
+
+fn write_response(stream: &mut TcpStream, status: &str, body: &Value) -> io::Result<()> {
+    let body = body.to_string();
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+}
+
+/// Compares without stopping at the first difference, so response times do not reveal how
+/// much of a guessed token is right.
+fn tokens_match(presented: &str, token: &str) -> bool {
+    presented.len() == token.len()
+        && presented
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |difference, (a, b)| difference | (a ^ b))
+            == 0
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum RpcError {
+    #[error("unknown method {0}")]
+    UnknownMethod(String),
+    #[error("invalid params: {0}")]
+    InvalidParams(String),
+    #[error("{0}")]
+    Failed(String),
+}
+
+impl RpcError {
+    /// The JSON-RPC error code, with application errors in the range reserved for servers.
+    fn code(&self) -> i64 {
+        match self {
+            RpcError::UnknownMethod(_) => -32601,
+            RpcError::InvalidParams(_) => -32602,
+            RpcError::Failed(_) => -32000,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::TcpStream,
+        sync::mpsc,
+        thread,
+    };
+
+    use serde_json::{json, Value};
+
+    use super::{serve, tokens_match};
+
+    fn post(addr: std::net::SocketAddr, token: &str, body: &str) -> (String, Value) {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(
+            stream,
+            "POST /rpc HTTP/1.1\r\nAuthorization: Bearer {}\r\nContent-Length: {}\r\n\r\n{}",
+            token,
+            body.len(),
+            body
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        let status = head.lines().next().unwrap().to_string();
+        (status, serde_json::from_str(body).unwrap())
+    }
+
+    #[test]
+    fn answers_authorized_calls() {
+        let (calls, received) = mpsc::channel();
+        let addr = serve("127.0.0.1:0", "secret".to_string(), calls).unwrap();
+        thread::spawn(move || {
+            for call in received {
+                let call: super::RpcCall = call;
+                let _ = call.reply.send(Ok(json!({ "echo": call.method })));
+            }
+        });
+
+        let (status, response) = post(addr, "secret", r#"{"method":"session_stats","id":7}"#);
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert_eq!(response["result"]["echo"], "session_stats");
+        assert_eq!(response["id"], 7);
+
+        let (status, _) = post(addr, "guess", r#"{"method":"session_stats"}"#);
+        assert_eq!(status, "HTTP/1.1 401 Unauthorized");
+        let (_, response) = post(addr, "secret", "not json");
+        assert_eq!(response["error"]["code"], -32700);
+    }
+
+    #[test]
+    fn compares_tokens_exactly() {
+        assert!(tokens_match("abc", "abc"));
+        assert!(!tokens_match("abd", "abc"));
+        assert!(!tokens_match("ab", "abc"));
+    }
+}
@@ -0,0 +1,139 @@
+use std::collections::HashMap;
+
+use crate::{
+    bencode::{Bencode, Value},
+    http::{self, HttpError, HttpRequest},
+};
+
+/// What a tracker knows about a torrent's swarm (BEP 48).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScrapeStats {
+    pub seeders: u64,
+    pub leechers: u64,
+    /// How many peers have ever finished downloading.
+    pub completed: u64,
+}
+
+/// The scrape URL for an HTTP tracker's announce URL. Trackers only support scraping where the
+/// last path component of their announce URL starts with `announce`.
+pub fn scrape_url(announce: &str) -> Option<String> {
+    let path_start = announce.rfind('/')?;
+    let (base, last) = announce.split_at(path_start + 1);
+    let rest = last.strip_prefix("announce")?;
+    if !base.starts_with("http://") && !base.starts_with("https://") {
+        return None;
+    }
+    Some(format!("{}scrape{}", base, rest))
+}
+
+/// The URL to scrape the tracker at `announce` for the torrent with `info_hash`.
+pub fn request_url(announce: &str, info_hash: &[u8; 20]) -> Result<String, ScrapeError> {
+    let url = scrape_url(announce).ok_or(ScrapeError::Unsupported)?;
+    let encoded_info_hash = info_hash
+        .iter()
+        .map(|byte| format!("%{:02x}", byte))
+        .collect::<String>();
+    let separator = if url.contains('?') { '&' } else { '?' };
+    Ok(format!(
+        "{}{}info_hash={}",
+        url, separator, encoded_info_hash
+    ))
+}
+
+/// Asks the tracker at `announce` about the torrent with `info_hash`.
+pub fn scrape(announce: &str, info_hash: &[u8; 20]) -> Result<ScrapeStats, ScrapeError> {
+    let url = request_url(announce, info_hash)?;
+    let response = http::fetcher()
+        .get(&HttpRequest::get(url))?
+        .error_for_status()?;
+    parse_response(&response.body, info_hash)
+}
+
+/// The stats for `info_hash` in a scrape response.
+pub fn parse_response(bytes: &[u8], info_hash: &[u8; 20]) -> Result<ScrapeStats, ScrapeError> {
+    let Ok(Value::Dictionary(mut response)) = Bencode::new(bytes).decode() else {
+        return Err(ScrapeError::Malformed);
+    };
+    if let Some(Value::String(reason)) = response.get("failure reason") {
+        return Err(ScrapeError::Failure(reason.clone()));
+    }
+    let Some(Value::Dictionary(mut files)) = response.remove("files") else {
+        return Err(ScrapeError::Malformed);
+    };
+
+    // Files are keyed by raw info hash, which the decoder keeps lossily.
+    let key = String::from_utf8_lossy(info_hash).into_owned();
+    let Some(Value::Dictionary(file)) = files.remove(&key) else {
+        return Err(ScrapeError::NotFound);
+    };
+    Ok(ScrapeStats {
+        seeders: count(&file, "complete")?,
+        leechers: count(&file, "incomplete")?,
+        completed: count(&file, "downloaded")?,
+    })
+}
+
+fn count(file: &HashMap<String, Value>, key: &str) -> Result<u64, ScrapeError> {
+    match file.get(key) {
+        Some(Value::Number(number)) if *number >= 0 => Ok(*number as u64),
+        _ => Err(ScrapeError::Malformed),
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ScrapeError {
+    #[error("tracker does not support scraping")]
+    Unsupported,
+    #[error("scrape failed: {0}")]
+    Http(#[from] HttpError),
+    #[error("tracker refused: {0}")]
+    Failure(String),
+    #[error("tracker does not know the torrent")]
+    NotFound,
+    #[error("malformed scrape response")]
+    Malformed,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_response, scrape_url, ScrapeError, ScrapeStats};
+
+    #[test]
+    fn derives_scrape_urls() {
+        assert_eq!(
+            scrape_url("http://example.com/announce").as_deref(),
+            Some("http://example.com/scrape")
+        );
+        assert_eq!(
+            scrape_url("http://example.com/x/announce.php?key=1").as_deref(),
+            Some("http://example.com/x/scrape.php?key=1")
+        );
+        assert_eq!(scrape_url("http://example.com/a"), None);
+        assert_eq!(scrape_url("udp://example.com:80/announce"), None);
+    }
+
+    #[test]
+    fn reads_counts_for_our_torrent() {
+        let info_hash = [0xaa; 20];
+        let mut response = b"d5:filesd20:".to_vec();
+        response.extend(info_hash);
+        response.extend(b"d8:completei5e10:downloadedi50e10:incompletei10eeee");
+
+        assert_eq!(
+            parse_response(&response, &info_hash).unwrap(),
+            ScrapeStats {
+                seeders: 5,
+                leechers: 10,
+                completed: 50,
+            }
+        );
+        assert!(matches!(
+            parse_response(&response, &[b'b'; 20]),
+            Err(ScrapeError::NotFound)
+        ));
+        assert!(matches!(
+            parse_response(b"d14:failure reason6:bannede", &info_hash),
+            Err(ScrapeError::Failure(reason)) if reason == "banned"
+        ));
+    }
+}
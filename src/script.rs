@@ -0,0 +1,262 @@
+//! Automation for the daemon: a script of rules, each making a control API call when a torrent
+//! event matches it, so the daemon can react to torrents without a wrapper polling the API.
+//! One rule per line:
+//!
+//! ```text
+//! # Stop seeding what finishes, and slow everything down once a peer is banned.
+//! on completed call pause
+//! on peer_banned call set_rate_limit {"limit": 1048576}
+//! on state_changed if state == failed call remove
+//! ```
+//!
+//! Events are named as in the event log and calls as in [`crate::rpc`]. A call without
+//! parameters is given the event's torrent, as `{"info_hash": ...}`. In parameters that are
+//! given, a string such as `"$info_hash"` or `"$addr"` stands for that field of the event.
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use serde_json::{json, Map, Value};
+
+use crate::{events::Event, rpc};
+
+/// The events rules can react to.
+const EVENTS: &[&str] = &[
+    "added",
+    "checked",
+    "tracker_announced",
+    "peer_connected",
+    "peer_disconnected",
+    "peer_banned",
+    "piece_verified",
+    "piece_failed",
+    "completed",
+    "stopped",
+    "state_changed",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Script {
+    rules: Vec<Rule>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Rule {
+    line: usize,
+    event: String,
+    /// A field of the event and the value it must have.
+    condition: Option<(String, String)>,
+    method: String,
+    params: Option<Value>,
+}
+
+/// A control API call a rule makes for an event.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Action {
+    /// The line of the rule making it.
+    pub line: usize,
+    pub method: String,
+    pub params: Value,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ScriptError {
+    #[error("cannot read {0}: {1}")]
+    Read(PathBuf, io::Error),
+    #[error("line {0}: {1}")]
+    Syntax(usize, String),
+}
+
+impl Script {
+    pub fn load(path: &Path) -> Result<Self, ScriptError> {
+        fs::read_to_string(path)
+            .map_err(|error| ScriptError::Read(path.to_path_buf(), error))?
+            .parse()
+    }
+
+    /// The calls the script makes for `event`, in the order of its rules.
+    pub fn actions(&self, event: &Event) -> Vec<Action> {
+        let Ok(Value::Object(fields)) = serde_json::to_value(event) else {
+            return Vec::new();
+        };
+        self.rules
+            .iter()
+            .filter(|rule| fields.get("event").and_then(Value::as_str) == Some(&rule.event))
+            .filter(|rule| {
+                rule.condition.as_ref().is_none_or(|(field, expected)| {
+                    fields.get(field).is_some_and(|value| match value {
+                        Value::String(value) => value == expected,
+                        value => serde_json::from_str::<Value>(expected).is_ok_and(|expected| expected == *value),
+                    })
+                })
+            })
+            .map(|rule| Action {
+                line: rule.line,
+                method: rule.method.clone(),
+                params: match &rule.params {
+                    Some(params) => substitute(params, &fields),
+                    None => json!({ "info_hash": event.info_hash }),
+                },
+            })
+            .collect()
+    }
+}
+
+impl FromStr for Script {
+    type Err = ScriptError;
+
+    fn from_str(source: &str) -> Result<Self, Self::Err> {
+        let rules = source
+            .lines()
+            .enumerate()
+            .map(|(index, line)| (index + 1, line.trim()))
+            .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+            .map(|(number, line)| {
+                parse_rule(number, line).map_err(|error| ScriptError::Syntax(number, error))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self { rules })
+    }
+}
+
+/// Parses `on <event> [if <field> == <value>] call <method> [<params>]`, on line `number`.
+fn parse_rule(number: usize, line: &str) -> Result<Rule, String> {
+    let mut rest = line;
+    let mut word = || {
+        let trimmed = rest.trim_start();
+        let end = trimmed.find(char::is_whitespace).unwrap_or(trimmed.len());
+        rest = &trimmed[end..];
+        Some(&trimmed[..end]).filter(|word| !word.is_empty())
+    };
+
+    if word() != Some("on") {
+        return Err("expected a rule, as `on <event> call <method>`".to_string());
+    }
+    let event = word().ok_or("expected an event after `on`")?.to_string();
+    if !EVENTS.contains(&event.as_str()) {
+        return Err(format!("unknown event {}", event));
+    }
+
+    let mut condition = None;
+    let mut next = word();
+    if next == Some("if") {
+        let field = word().ok_or("expected a field after `if`")?;
+        if word() != Some("==") {
+            return Err("expected `==` after the field".to_string());
+        }
+        let value = word().ok_or("expected a value after `==`")?;
+        condition = Some((field.to_string(), value.trim_matches('"').to_string()));
+        next = word();
+    }
+    if next != Some("call") {
+        return Err("expected `call` and a method".to_string());
+    }
+    let method = word().ok_or("expected a method after `call`")?.to_string();
+    if !rpc::METHODS.contains(&method.as_str()) {
+        return Err(format!("unknown method {}", method));
+    }
+
+    let params = match rest.trim() {
+        "" => None,
+        params => match serde_json::from_str(params) {
+            Ok(params @ Value::Object(_)) => Some(params),
+            Ok(_) => return Err("parameters must be a JSON object".to_string()),
+            Err(error) => return Err(format!("bad parameters: {}", error)),
+        },
+    };
+
+    Ok(Rule {
+        line: number,
+        event,
+        condition,
+        method,
+        params,
+    })
+}
+
+/// `params` with every `"$field"` string replaced by that field of the event.
+fn substitute(params: &Value, fields: &Map<String, Value>) -> Value {
+    match params {
+        Value::String(text) => text
+            .strip_prefix('$')
+            .and_then(|field| fields.get(field))
+            .unwrap_or(params)
+            .clone(),
+        Value::Array(values) => values
+            .iter()
+            .map(|value| substitute(value, fields))
+            .collect(),
+        Value::Object(entries) => entries
+            .iter()
+            .map(|(key, value)| (key.clone(), substitute(value, fields)))
+            .collect(),
+        value => value.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{Script, ScriptError};
+    use crate::{
+        events::{Event, TorrentEvent},
+        session::TorrentState,
+    };
+
+    fn event(event: TorrentEvent) -> Event {
+        Event {
+            info_hash: "abcd".to_string(),
+            event,
+        }
+    }
+
+    #[test]
+    fn calls_the_api_for_matching_events() {
+        let script: Script = "
+            # Comments and blank lines are skipped.
+
+            on completed call pause
+            on peer_banned call set_rate_limit {\"limit\": 1024, \"note\": \"$addr\"}
+            on state_changed if state == failed call remove
+        "
+        .parse()
+        .unwrap();
+
+        let actions = script.actions(&event(TorrentEvent::Completed));
+        assert_eq!(actions.len(), 1);
+        assert_eq!(actions[0].line, 4);
+        assert_eq!(actions[0].method, "pause");
+        assert_eq!(actions[0].params, json!({ "info_hash": "abcd" }));
+
+        let banned = TorrentEvent::PeerBanned {
+            addr: "10.0.0.1:6881".to_string(),
+        };
+        assert_eq!(
+            script.actions(&event(banned))[0].params,
+            json!({ "limit": 1024, "note": "10.0.0.1:6881" })
+        );
+
+        let state = |state| event(TorrentEvent::StateChanged { state });
+        assert!(script.actions(&state(TorrentState::Seeding)).is_empty());
+        assert_eq!(script.actions(&state(TorrentState::Failed))[0].method, "remove");
+    }
+
+    #[test]
+    fn points_at_the_line_in_error() {
+        for (source, line) in [
+            ("on completed call pause\non finished call pause", 2),
+            ("on completed call explode", 1),
+            ("\n\non completed pause", 3),
+            ("on completed call pause [1]", 1),
+        ] {
+            match source.parse::<Script>() {
+                Err(ScriptError::Syntax(number, _)) => assert_eq!(number, line, "{}", source),
+                other => panic!("{} parsed as {:?}", source, other),
+            }
+        }
+    }
+}
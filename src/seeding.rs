@@ -0,0 +1,276 @@
+use std::{
+    fmt::Display,
+    io::Write,
+    net::{SocketAddr, TcpStream},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use crate::{
+    bitfield::Bitfield,
+    executor::{self, Task},
+    log,
+    peer_manager::{PeerManager, PeerSnapshot},
+    piece_cache::PieceCache,
+    stats::PeerStats,
+    storage::{self, Storage},
+    torrent::Info,
+    wire::{BlockRequest, Message, MessageId},
+};
+
+// Peers ask for 16 KiB blocks; we refuse anything over the 128 KiB some clients allow.
+pub const MAX_BLOCK_LENGTH: usize = 128 * 1024;
+
+/// When to stop seeding a completed torrent. Seeding stops at whichever limit is hit first; with
+/// neither set we do not seed at all.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SeedLimits {
+    /// Stop once we have uploaded this many times the torrent's size.
+    pub ratio: Option<f64>,
+    /// Stop after seeding for this long.
+    pub time: Option<Duration>,
+}
+
+impl SeedLimits {
+    pub fn is_set(&self) -> bool {
+        self.ratio.is_some() || self.time.is_some()
+    }
+
+    /// Which limit, if any, has been reached at this share ratio and time spent seeding.
+    pub fn reached(&self, ratio: f64, seeding_for: Duration) -> Option<SeedStop> {
+        if let Some(target) = self.ratio {
+            if ratio >= target {
+                return Some(SeedStop::Ratio(ratio));
+            }
+        }
+        if let Some(limit) = self.time {
+            if seeding_for >= limit {
+                return Some(SeedStop::Time(seeding_for));
+            }
+        }
+
+        None
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SeedStop {
+    Ratio(f64),
+    Time(Duration),
+}
+
+impl Display for SeedStop {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SeedStop::Ratio(ratio) => write!(f, "reached share ratio {:.2}", ratio),
+            SeedStop::Time(time) => write!(f, "seeded for {} minutes", time.as_secs() / 60),
+        }
+    }
+}
+
+/// Serves blocks of a torrent's verified pieces to inbound peers, through a piece cache shared
+/// by every peer so a popular piece is read from disk once.
+#[derive(Debug, Clone)]
+pub struct Seeder {
+    pub info: Info,
+    /// The torrent's file, or the directory holding the files of a multi-file torrent.
+    pub path: PathBuf,
+    pub info_hash: [u8; 20],
+    pub completed: Bitfield,
+    pub peer_manager: Arc<Mutex<PeerManager>>,
+    pub piece_cache: Arc<Mutex<PieceCache>>,
+}
+
+impl Seeder {
+    /// Sends the peer our bitfield, unchokes it and answers its requests on a thread of its own
+    /// until it disconnects.
+    pub fn serve(&self, addr: SocketAddr, socket: TcpStream) -> Task<()> {
+        let seeder = self.clone();
+        executor::spawn("seeder", move || {
+            if let Err(error) = seeder.serve_blocks(addr, socket) {
+                log::debug!(peer = addr; "stopped serving peer: {}", error);
+            }
+            seeder
+                .peer_manager
+                .lock()
+                .expect("Peer manager lock poisoned")
+                .connection_closed(addr);
+        })
+    }
+
+    fn serve_blocks(&self, addr: SocketAddr, mut socket: TcpStream) -> std::io::Result<()> {
+        // Pieces missing from the cache are read through each peer's own handles.
+        let mut storage = storage::open_existing(&self.path, &self.info)?;
+        let bitfield = Message::new(MessageId::Bitfield, self.completed.as_bytes().to_vec());
+        socket.write_all(&bitfield.encode())?;
+        socket.write_all(&Message::new(MessageId::Unchoke, vec![]).encode())?;
+        let peer_id = self
+            .peer_manager
+            .lock()
+            .expect("Peer manager lock poisoned")
+            .inbound()
+            .iter()
+            .find(|peer| peer.addr == addr)
+            .map(|peer| peer.peer_id);
+        let mut stats = PeerStats::new();
+
+        loop {
+            let message = match Message::read_from_socket(&mut socket) {
+                Ok(Some(message)) => message,
+                // Keep-alive.
+                Ok(None) => continue,
+                Err(error) => return Err(std::io::Error::other(error)),
+            };
+            if message.id != MessageId::Request {
+                continue;
+            }
+            let Some(request) = BlockRequest::decode(&message.payload) else {
+                continue;
+            };
+            let Some(block) = self.read_block(storage.as_mut(), &request) else {
+                continue;
+            };
+
+            socket.write_all(&Message::piece(request.index, request.begin, &block).encode())?;
+            stats.record_upload(block.len());
+            let mut peer_manager = self
+                .peer_manager
+                .lock()
+                .expect("Peer manager lock poisoned");
+            peer_manager.record_upload(self.info_hash, block.len());
+            peer_manager.update_peer(PeerSnapshot::new(addr, self.info_hash, peer_id, &stats));
+        }
+    }
+
+    /// The requested block, if it lies within a piece we have.
+    fn read_block(&self, storage: &mut dyn Storage, request: &BlockRequest) -> Option<Vec<u8>> {
+        let piece_index = request.index as usize;
+        let (begin, length) = (request.begin as usize, request.length as usize);
+        if piece_index >= self.completed.len() || !self.completed.has(piece_index) {
+            return None;
+        }
+        let piece_length = usize::min(
+            self.info.length - piece_index * self.info.piece_length,
+            self.info.piece_length,
+        );
+        if length == 0 || length > MAX_BLOCK_LENGTH || begin + length > piece_length {
+            return None;
+        }
+
+        let block = self
+            .piece_cache
+            .lock()
+            .expect("Piece cache lock poisoned")
+            .read_block(storage, piece_index, piece_length, begin, length);
+        match block {
+            Ok(block) => Some(block),
+            Err(error) => {
+                log::warn!(torrent = self.info.name; "not serving {:?}: {}", request, error);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Write,
+        net::{TcpListener, TcpStream},
+        sync::{Arc, Mutex},
+        thread,
+        time::Duration,
+    };
+
+    use super::{SeedLimits, SeedStop, Seeder};
+    use crate::{
+        bitfield::Bitfield,
+        peer_manager::PeerManager,
+        piece_cache::{PieceCache, DEFAULT_CACHE_SIZE},
+        torrent::Info,
+        wire::{BlockRequest, Message, MessageId},
+    };
+
+    #[test]
+    fn serves_blocks_of_pieces_we_have() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data");
+        let data = (0..40).collect::<Vec<u8>>();
+        std::fs::write(&path, &data).unwrap();
+        let mut completed = Bitfield::new(3);
+        completed.set(1);
+        completed.set(2);
+        let seeder = Seeder {
+            info: Info {
+                length: data.len(),
+                name: "data".to_string(),
+                piece_length: 16,
+                pieces: vec![[0; 20]; 3],
+                files: vec![],
+                private: false,
+            },
+            path,
+            info_hash: [1; 20],
+            completed,
+            peer_manager: Arc::new(Mutex::new(PeerManager::new())),
+            piece_cache: Arc::new(Mutex::new(PieceCache::new(DEFAULT_CACHE_SIZE))),
+        };
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (socket, addr) = listener.accept().unwrap();
+        seeder.serve(addr, socket);
+
+        let mut read = || Message::read_from_socket(&mut client).unwrap().unwrap();
+        let bitfield = read();
+        assert_eq!(bitfield.id, MessageId::Bitfield);
+        assert_eq!(bitfield.payload, vec![0b0110_0000]);
+        assert_eq!(read().id, MessageId::Unchoke);
+
+        let request = |index, begin, length| {
+            let request = BlockRequest {
+                index,
+                begin,
+                length,
+            };
+            Message::new(MessageId::Request, request.encode()).encode()
+        };
+        // Piece 0 is missing and the last piece is only 8 bytes long, so only the third
+        // request is answered.
+        client.write_all(&request(0, 0, 16)).unwrap();
+        client.write_all(&request(2, 4, 8)).unwrap();
+        client.write_all(&request(2, 2, 4)).unwrap();
+        let piece = Message::read_from_socket(&mut client).unwrap().unwrap();
+        assert_eq!(piece.id, MessageId::Piece);
+        assert_eq!(&piece.payload[8..], &data[34..38]);
+
+        // The piece was read once, and the next block of it comes from the cache.
+        client.write_all(&request(2, 0, 2)).unwrap();
+        let piece = Message::read_from_socket(&mut client).unwrap().unwrap();
+        assert_eq!(&piece.payload[8..], &data[32..34]);
+        let stats = seeder.piece_cache.lock().unwrap().stats();
+        assert_eq!((stats.hits, stats.misses), (1, 1));
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(seeder.peer_manager.lock().unwrap().uploaded([1; 20]), 6);
+    }
+
+    #[test]
+    fn stops_at_the_first_limit_reached() {
+        let limits = SeedLimits {
+            ratio: Some(2.0),
+            time: Some(Duration::from_secs(3600)),
+        };
+
+        assert_eq!(limits.reached(1.5, Duration::from_secs(60)), None);
+        assert_eq!(
+            limits.reached(2.0, Duration::from_secs(60)),
+            Some(SeedStop::Ratio(2.0))
+        );
+        assert_eq!(
+            limits.reached(0.1, Duration::from_secs(3600)),
+            Some(SeedStop::Time(Duration::from_secs(3600)))
+        );
+        assert!(!SeedLimits::default().is_set());
+    }
+}
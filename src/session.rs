@@ -0,0 +1,903 @@
+//! Many torrents downloading and seeding at once. A session owns what they share: the
+//! listening port, the peer manager and its connection caps, the rate limiter, the memory
+//! budget, our peer id and DHT port, and the bus their events are published on. Every torrent
+//! runs on its own thread.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    io,
+    path::{Path, PathBuf},
+    sync::{mpsc::Receiver, Arc, Mutex, RwLock},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    assembly::BLOCK_SIZE,
+    bandwidth::{BandwidthSchedule, Limit, RateLimiter},
+    coordinator::DownloadCoordinator,
+    error::Error,
+    events::{Event, EventBus, TorrentEvent},
+    executor::{self, Task},
+    geoip::GeoIp,
+    history::History,
+    hook,
+    listener::{Listener, DEFAULT_PORT},
+    log,
+    magnet::Magnet,
+    memory::{MemoryBudget, MemoryKind, MemoryUsage},
+    peer,
+    peer_manager::{ConnectionLimits, PeerManager, PeerSnapshot},
+    resume::{FileState, ResumeData},
+    seeding::SeedLimits,
+    shutdown::Shutdown,
+    storage::{self, StorageError, StorageKind},
+    torrent::Torrent,
+};
+
+/// Settings shared by every torrent in a session.
+#[derive(Debug, Clone)]
+pub struct SessionConfig {
+    /// Where downloads are saved, each under its torrent's name.
+    pub download_dir: PathBuf,
+    pub port: u16,
+    /// Where our DHT node listens, advertised to peers that support DHT.
+    pub dht_port: Option<u16>,
+    /// The id we present to peers and trackers. A new one is generated when this is `None`.
+    pub peer_id: Option<[u8; 20]>,
+    /// How many bytes of a piece we ask a peer for at a time.
+    pub block_size: usize,
+    /// When to stop seeding a finished torrent. With no limits it seeds until the session stops.
+    pub seed_limits: SeedLimits,
+    /// A shell command to run for each torrent that finishes downloading.
+    pub on_complete: Option<String>,
+    /// A file to record each torrent's daily transfers and completions in, see [`History`].
+    pub history: Option<PathBuf>,
+    /// The most bytes torrents may hold in memory between them, see [`MemoryBudget`].
+    pub memory_budget: Option<u64>,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            download_dir: PathBuf::from("."),
+            port: DEFAULT_PORT,
+            dht_port: None,
+            peer_id: None,
+            block_size: BLOCK_SIZE,
+            seed_limits: SeedLimits::default(),
+            on_complete: None,
+            history: None,
+            memory_budget: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TorrentState {
+    /// Fetching the info dictionary of a magnet link from the swarm.
+    FetchingMetadata,
+    /// Checking what is already on disk.
+    Checking,
+    Downloading,
+    Seeding,
+    Stopped,
+    Paused,
+    /// The torrent's thread gave up, for instance because no peer could be reached.
+    Failed,
+}
+
+impl Display for TorrentState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let state = match self {
+            TorrentState::FetchingMetadata => "fetching metadata",
+            TorrentState::Checking => "checking",
+            TorrentState::Downloading => "downloading",
+            TorrentState::Seeding => "seeding",
+            TorrentState::Stopped => "stopped",
+            TorrentState::Paused => "paused",
+            TorrentState::Failed => "failed",
+        };
+        write!(f, "{}", state)
+    }
+}
+
+/// A torrent in the session, and how it is getting on.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TorrentStatus {
+    pub info_hash: String,
+    pub name: String,
+    pub path: String,
+    pub state: TorrentState,
+    pub pieces: usize,
+    /// Zero until the torrent has been checked, which for a magnet link waits on its metadata.
+    pub piece_count: usize,
+    pub uploaded: u64,
+    /// Bytes per second, across the torrent's open connections.
+    pub download_rate: f64,
+    pub upload_rate: f64,
+    pub peers: usize,
+}
+
+/// Totals across every torrent in the session.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionStats {
+    pub torrents: usize,
+    pub downloading: usize,
+    pub seeding: usize,
+    pub uploaded: u64,
+    pub download_rate: f64,
+    pub upload_rate: f64,
+    pub open_connections: usize,
+    /// The download rate limit in force, as given to `--rate_limit`.
+    pub rate_limit: String,
+    pub memory: MemoryUsage,
+}
+
+/// Where a torrent's info dictionary comes from.
+#[derive(Debug, Clone)]
+pub enum Source {
+    Torrent(Torrent),
+    /// Fetched from the swarm each time the torrent starts.
+    Magnet(Magnet),
+}
+
+/// A torrent's state, shared between the session and the torrent's thread. Changes are
+/// published as events.
+#[derive(Clone)]
+struct SharedState {
+    state: Arc<Mutex<TorrentState>>,
+    info_hash: String,
+    events: EventBus,
+}
+
+impl SharedState {
+    fn get(&self) -> TorrentState {
+        *self.state.lock().expect("Torrent state lock poisoned")
+    }
+
+    fn set(&self, state: TorrentState) {
+        *self.state.lock().expect("Torrent state lock poisoned") = state;
+        self.events
+            .publish(&self.info_hash, TorrentEvent::StateChanged { state });
+    }
+}
+
+/// A torrent the session runs on a thread of its own, which can be paused and resumed.
+pub struct TorrentHandle {
+    info_hash: [u8; 20],
+    name: String,
+    out: PathBuf,
+    source: Source,
+    state: SharedState,
+    // Followed through the torrent's events.
+    pieces: usize,
+    piece_count: usize,
+    // What the torrent's thread is started with, its shutdown being the current cancellation
+    // token.
+    job: Job,
+    session_shutdown: Shutdown,
+    info_hashes: Arc<RwLock<Vec<String>>>,
+    thread: Option<Task<Result<(), Error>>>,
+}
+
+impl TorrentHandle {
+    pub fn info_hash(&self) -> [u8; 20] {
+        self.info_hash
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Where the torrent is saved.
+    pub fn path(&self) -> &Path {
+        &self.out
+    }
+
+    pub fn state(&self) -> TorrentState {
+        self.state.get()
+    }
+
+    /// Whether the torrent's thread is running.
+    pub fn is_running(&self) -> bool {
+        self.thread.is_some()
+    }
+
+    /// A token that cancels the torrent's work when requested, like `pause` but from any
+    /// thread and without waiting. The session collects the stopped torrent when it next reaps.
+    pub fn cancellation_token(&self) -> Shutdown {
+        self.job.shutdown.clone()
+    }
+
+    /// Stops the torrent's network activity and waits for it to flush its data and save its
+    /// resume data, keeping it so that `resume` carries on from there. The rest of the session
+    /// keeps running.
+    pub fn pause(&mut self) {
+        self.job.shutdown.request();
+        self.join();
+        if self.state() != TorrentState::Failed {
+            self.set_state(TorrentState::Paused);
+        }
+        self.info_hashes
+            .write()
+            .expect("Info hash lock poisoned")
+            .retain(|known| *known != self.state.info_hash);
+        log::info!(torrent = self.name; "paused");
+    }
+
+    /// Starts a paused, stopped or failed torrent again from what it has on disk. Does nothing
+    /// while it runs.
+    pub fn resume(&mut self) {
+        if self.thread.is_some() {
+            return;
+        }
+
+        self.job.shutdown = self.session_shutdown.child();
+        self.set_state(TorrentState::Checking);
+        let mut info_hashes = self.info_hashes.write().expect("Info hash lock poisoned");
+        if !info_hashes.contains(&self.state.info_hash) {
+            info_hashes.push(self.state.info_hash.clone());
+        }
+        drop(info_hashes);
+
+        let source = self.source.clone();
+        let out = self.out.clone();
+        let job = self.job.clone();
+        self.thread = Some(executor::spawn("torrent", move || {
+            run_torrent(source, &out, job)
+        }));
+    }
+
+    fn set_state(&self, state: TorrentState) {
+        self.state.set(state);
+    }
+
+    /// Waits for the torrent's thread to stop, if it is running.
+    fn join(&mut self) {
+        let Some(thread) = self.thread.take() else {
+            return;
+        };
+        match thread.join() {
+            Ok(Ok(())) => {}
+            Ok(Err(error)) => {
+                log::error!(torrent = self.name; "{}", error);
+                self.set_state(TorrentState::Failed);
+            }
+            Err(_) => {
+                log::error!(torrent = self.name; "stopped after an error");
+                self.set_state(TorrentState::Failed);
+            }
+        }
+    }
+}
+
+/// What a torrent's thread shares with the rest of the session.
+#[derive(Clone)]
+struct Job {
+    peer_id: [u8; 20],
+    port: u16,
+    dht_port: Option<u16>,
+    block_size: usize,
+    peer_manager: Arc<Mutex<PeerManager>>,
+    rate_limiter: Arc<Mutex<RateLimiter>>,
+    memory: MemoryBudget,
+    seed_limits: SeedLimits,
+    on_complete: Option<String>,
+    history: Option<History>,
+    shutdown: Shutdown,
+    state: SharedState,
+    events: EventBus,
+}
+
+impl Job {
+    fn set_state(&self, state: TorrentState) {
+        self.state.set(state);
+    }
+}
+
+pub struct Session {
+    config: SessionConfig,
+    history: Option<History>,
+    peer_id: [u8; 20],
+    // The port we listen on, which differs from the configured one when that is zero.
+    port: u16,
+    peer_manager: Arc<Mutex<PeerManager>>,
+    rate_limiter: Arc<Mutex<RateLimiter>>,
+    memory: MemoryBudget,
+    geoip: GeoIp,
+    // Shared with the listener, so peers are accepted for torrents added later.
+    info_hashes: Arc<RwLock<Vec<String>>>,
+    torrents: BTreeMap<String, TorrentHandle>,
+    // Every torrent's cancellation token is a child of this.
+    shutdown: Shutdown,
+    events: EventBus,
+    received_events: Receiver<Event>,
+}
+
+impl Session {
+    /// Starts listening for peers on the configured port, under a new peer id. Torrents are run
+    /// once added.
+    pub fn start(
+        config: SessionConfig,
+        peer_manager: PeerManager,
+        rate_limiter: RateLimiter,
+    ) -> io::Result<Self> {
+        let peer_id = config.peer_id.unwrap_or_else(peer::generate_peer_id);
+        let peer_manager = Arc::new(Mutex::new(peer_manager));
+        let mut listener = Listener::bind(config.port, vec![])?;
+        listener.set_peer_id(peer_id);
+        let port = listener.port();
+        log::info!("listening for peers on port {}", port);
+        let info_hashes = listener.info_hashes();
+        listener.spawn(peer_manager.clone());
+        let events = EventBus::new();
+        let received_events = events.subscribe();
+
+        Ok(Self {
+            history: config.history.as_ref().map(History::new),
+            memory: MemoryBudget::new(config.memory_budget),
+            config,
+            peer_id,
+            port,
+            peer_manager,
+            rate_limiter: Arc::new(Mutex::new(rate_limiter)),
+            geoip: GeoIp::default(),
+            info_hashes,
+            torrents: BTreeMap::new(),
+            shutdown: Shutdown::new(),
+            events,
+            received_events,
+        })
+    }
+
+    /// The id we present to peers and trackers.
+    pub fn peer_id(&self) -> [u8; 20] {
+        self.peer_id
+    }
+
+    /// The port we listen for peers on.
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// The bus every torrent's events are published on.
+    pub fn events(&self) -> EventBus {
+        self.events.clone()
+    }
+
+    /// The torrent with `info_hash`, if we have it.
+    pub fn torrent(&self, info_hash: &str) -> Option<&TorrentHandle> {
+        self.torrents.get(info_hash)
+    }
+
+    pub fn torrent_mut(&mut self, info_hash: &str) -> Option<&mut TorrentHandle> {
+        self.torrents.get_mut(info_hash)
+    }
+
+    pub fn torrents(&self) -> impl Iterator<Item = &TorrentHandle> {
+        self.torrents.values()
+    }
+
+    /// Starts downloading, or seeding if it is already on disk, `torrent` into the download
+    /// directory. Returns its info hash.
+    pub fn add(&mut self, torrent: Torrent) -> Result<String, SessionError> {
+        let name = torrent.info.name.clone();
+        self.insert(torrent.info_hash_bytes(), name, Source::Torrent(torrent))
+    }
+
+    /// Like [`Session::add`], for a magnet link whose metadata is fetched from the swarm. It is
+    /// saved under the link's name, or its info hash if it has none.
+    pub fn add_magnet(&mut self, magnet: Magnet) -> Result<String, SessionError> {
+        let name = magnet.name.clone().unwrap_or_else(|| magnet.info_hash());
+        self.insert(magnet.info_hash, name, Source::Magnet(magnet))
+    }
+
+    fn insert(
+        &mut self,
+        info_hash: [u8; 20],
+        name: String,
+        source: Source,
+    ) -> Result<String, SessionError> {
+        let key = hex::encode(info_hash);
+        if self.torrents.contains_key(&key) {
+            return Err(SessionError::Duplicate(key));
+        }
+
+        let out = self
+            .config
+            .download_dir
+            .join(storage::safe_component(&name));
+        log::info!(torrent = name; "added {}", key);
+        let state = SharedState {
+            state: Arc::new(Mutex::new(TorrentState::Checking)),
+            info_hash: key.clone(),
+            events: self.events.clone(),
+        };
+        let job = Job {
+            peer_id: self.peer_id,
+            port: self.port,
+            dht_port: self.config.dht_port,
+            block_size: self.config.block_size,
+            peer_manager: self.peer_manager.clone(),
+            rate_limiter: self.rate_limiter.clone(),
+            memory: self.memory.clone(),
+            seed_limits: self.config.seed_limits,
+            on_complete: self.config.on_complete.clone(),
+            history: self.history.clone(),
+            shutdown: self.shutdown.child(),
+            state: state.clone(),
+            events: self.events.clone(),
+        };
+        let mut torrent = TorrentHandle {
+            info_hash,
+            name,
+            out,
+            source,
+            state,
+            pieces: 0,
+            piece_count: 0,
+            job,
+            session_shutdown: self.shutdown.clone(),
+            info_hashes: self.info_hashes.clone(),
+            thread: None,
+        };
+        self.events.publish(
+            &key,
+            TorrentEvent::Added {
+                name: torrent.name.clone(),
+            },
+        );
+        torrent.resume();
+        self.torrents.insert(key.clone(), torrent);
+        Ok(key)
+    }
+
+    /// Stops a torrent, keeping it so it can be resumed. See [`TorrentHandle::pause`].
+    pub fn pause(&mut self, info_hash: &str) -> Result<(), SessionError> {
+        self.torrent_mut(info_hash)
+            .ok_or_else(|| SessionError::Unknown(info_hash.to_string()))?
+            .pause();
+        Ok(())
+    }
+
+    /// Starts a paused, stopped or failed torrent again. Running torrents are left alone.
+    pub fn resume(&mut self, info_hash: &str) -> Result<(), SessionError> {
+        self.torrent_mut(info_hash)
+            .ok_or_else(|| SessionError::Unknown(info_hash.to_string()))?
+            .resume();
+        Ok(())
+    }
+
+    /// Stops a torrent and forgets it. Its data stays on disk.
+    pub fn remove(&mut self, info_hash: &str) -> Result<(), SessionError> {
+        self.pause(info_hash)?;
+        if let Some(torrent) = self.torrents.remove(info_hash) {
+            log::info!(torrent = torrent.name; "removed");
+        }
+        Ok(())
+    }
+
+    pub fn status(&self) -> Vec<TorrentStatus> {
+        self.torrents
+            .keys()
+            .filter_map(|info_hash| self.status_of(info_hash))
+            .collect()
+    }
+
+    pub fn status_of(&self, info_hash: &str) -> Option<TorrentStatus> {
+        let torrent = self.torrents.get(info_hash)?;
+        let peer_manager = self
+            .peer_manager
+            .lock()
+            .expect("Peer manager lock poisoned");
+        let uploaded = peer_manager.uploaded(torrent.info_hash);
+        let peers = peer_manager.peers(torrent.info_hash);
+        drop(peer_manager);
+        Some(TorrentStatus {
+            info_hash: info_hash.to_string(),
+            name: torrent.name.clone(),
+            path: torrent.out.display().to_string(),
+            state: torrent.state(),
+            pieces: torrent.pieces,
+            piece_count: torrent.piece_count,
+            uploaded,
+            download_rate: peers.iter().map(|peer| peer.download_rate).sum(),
+            upload_rate: peers.iter().map(|peer| peer.upload_rate).sum(),
+            peers: peers.len(),
+        })
+    }
+
+    /// The torrent's open connections, if we have the torrent, located with the session's
+    /// GeoIP databases.
+    pub fn peers(&self, info_hash: &str) -> Option<Vec<PeerSnapshot>> {
+        let torrent = self.torrents.get(info_hash)?;
+        let peer_manager = self
+            .peer_manager
+            .lock()
+            .expect("Peer manager lock poisoned");
+        let mut peers = peer_manager.peers(torrent.info_hash);
+        drop(peer_manager);
+        for peer in &mut peers {
+            peer.location = self.geoip.lookup(peer.addr.ip());
+        }
+        Some(peers)
+    }
+
+    /// Looks peers up in `geoip` from now on.
+    pub fn set_geoip(&mut self, geoip: GeoIp) {
+        self.geoip = geoip;
+    }
+
+    /// Replaces the download rate limit shared by every torrent. Scheduled windows still take
+    /// precedence while they apply.
+    pub fn set_rate_limit(&mut self, limit: Limit) {
+        self.rate_limiter
+            .lock()
+            .expect("Rate limiter lock poisoned")
+            .schedule_mut()
+            .set_default(limit);
+        log::info!("rate limit set to {}", limit);
+    }
+
+    /// Replaces the rate limit and its scheduled windows.
+    pub fn set_rate_limits(&mut self, schedule: BandwidthSchedule) {
+        let mut rate_limiter = self.rate_limiter.lock().expect("Rate limiter lock poisoned");
+        *rate_limiter.schedule_mut() = schedule;
+        log::info!("rate limit now {}", rate_limiter.current_limit());
+    }
+
+    /// Replaces the connection caps. Connections already open over the new caps are kept, and
+    /// new ones wait until there is room.
+    pub fn set_connection_limits(&mut self, limits: ConnectionLimits) {
+        self.peer_manager
+            .lock()
+            .expect("Peer manager lock poisoned")
+            .set_limits(limits);
+    }
+
+    /// Replaces the memory budget. Torrents over a lowered budget start no new pieces until
+    /// they are back under it.
+    pub fn set_memory_budget(&mut self, limit: Option<u64>) {
+        self.memory.set_limit(limit);
+        match limit {
+            Some(limit) => log::info!("memory budget set to {} bytes", limit),
+            None => log::info!("memory budget removed"),
+        }
+    }
+
+    pub fn session_stats(&self) -> SessionStats {
+        let states = self
+            .torrents
+            .values()
+            .map(TorrentHandle::state)
+            .collect::<Vec<_>>();
+        let rate_limit = self
+            .rate_limiter
+            .lock()
+            .expect("Rate limiter lock poisoned")
+            .current_limit();
+        let peer_manager = self
+            .peer_manager
+            .lock()
+            .expect("Peer manager lock poisoned");
+        let peers = self
+            .torrents
+            .values()
+            .flat_map(|torrent| peer_manager.peers(torrent.info_hash))
+            .collect::<Vec<_>>();
+        SessionStats {
+            torrents: states.len(),
+            downloading: states
+                .iter()
+                .filter(|state| **state == TorrentState::Downloading)
+                .count(),
+            seeding: states
+                .iter()
+                .filter(|state| **state == TorrentState::Seeding)
+                .count(),
+            uploaded: self
+                .torrents
+                .values()
+                .map(|torrent| peer_manager.uploaded(torrent.info_hash))
+                .sum(),
+            download_rate: peers.iter().map(|peer| peer.download_rate).sum(),
+            upload_rate: peers.iter().map(|peer| peer.upload_rate).sum(),
+            open_connections: peer_manager.open_connections(),
+            rate_limit: rate_limit.to_string(),
+            memory: self.memory.usage(),
+        }
+    }
+
+    /// Takes in the events published since the last call and returns them. Each torrent's
+    /// pieces, as `status` reports them, are followed from these, so they are only as fresh as
+    /// the last call.
+    pub fn poll_events(&mut self) -> Vec<Event> {
+        let events = self.received_events.try_iter().collect::<Vec<_>>();
+        for event in &events {
+            let Some(torrent) = self.torrents.get_mut(&event.info_hash) else {
+                continue;
+            };
+            match event.event {
+                TorrentEvent::Checked {
+                    pieces,
+                    piece_count,
+                    ..
+                } => {
+                    torrent.pieces = pieces;
+                    torrent.piece_count = piece_count;
+                }
+                TorrentEvent::PieceVerified { pieces, .. } => torrent.pieces = pieces,
+                _ => {}
+            }
+        }
+        events
+    }
+
+    /// Collects the threads of torrents that have stopped by themselves.
+    pub fn reap(&mut self) {
+        for torrent in self.torrents.values_mut() {
+            if torrent.thread.as_ref().is_some_and(Task::is_finished) {
+                torrent.join();
+            }
+        }
+    }
+
+    /// Stops every torrent, waiting for each to leave its swarm and save its resume data. The
+    /// session is done with afterwards: torrents resumed later stop straight away.
+    pub fn stop(&mut self) {
+        self.shutdown.request();
+        for torrent in self.torrents.values_mut() {
+            torrent.join();
+        }
+    }
+}
+
+/// Fetches the torrent's metadata if need be, brings it up to date on disk at `out` and
+/// seeds it, writing resume data when it stops.
+fn run_torrent(source: Source, out: &Path, job: Job) -> Result<(), Error> {
+    let torrent = match source {
+        Source::Torrent(torrent) => torrent,
+        Source::Magnet(magnet) => {
+            job.set_state(TorrentState::FetchingMetadata);
+            let info = magnet.fetch_info(job.peer_id, job.port)?;
+            job.set_state(TorrentState::Checking);
+            magnet.into_torrent(info)
+        }
+    };
+    let name = torrent.info.name.clone();
+    let info = torrent.info.clone();
+    let info_hash = torrent.info_hash();
+    let info_hash_bytes = torrent.info_hash_bytes();
+    let piece_count = info.pieces.len();
+    let _metadata = job
+        .memory
+        .charge(MemoryKind::Metadata, (piece_count * 20) as u64);
+
+    let part_path = PathBuf::from(format!("{}.part", out.display()));
+    let working = storage::working_path(out, &part_path);
+    let mut storage = StorageKind::File
+        .open(&working, &info)
+        .map_err(|error| StorageError::Open(working.clone(), error))?;
+    let mut content_paths = storage::content_paths(&working, &info);
+    // The peer manager counts uploads for the whole session, across pauses.
+    let uploaded_before = job
+        .peer_manager
+        .lock()
+        .expect("Peer manager lock poisoned")
+        .uploaded(info_hash_bytes);
+
+    let mut coordinator = DownloadCoordinator::new(torrent, job.port, job.peer_manager.clone());
+    coordinator.set_peer_id(job.peer_id);
+    coordinator.set_block_size(job.block_size);
+    if let Some(dht_port) = job.dht_port {
+        coordinator.set_dht_port(dht_port);
+    }
+    coordinator.set_shutdown(job.shutdown.clone());
+    coordinator.set_rate_limiter(job.rate_limiter.clone());
+    coordinator.set_memory_budget(job.memory.clone());
+    coordinator.set_events(job.events.clone());
+
+    let resume_path = format!("{}.resume", out.display());
+    let resumed = ResumeData::load(&resume_path);
+    let restored = resumed.as_ref().and_then(|resume| {
+        let files = FileState::read_all(&content_paths)?;
+        resume.pieces_if_unchanged(&info_hash, &files, piece_count)
+    });
+    let found = match restored {
+        Some(pieces) => coordinator.restore(&pieces),
+        None => coordinator.recheck(storage.as_mut()),
+    };
+    log::info!(torrent = name; "have {} of {} pieces", found, piece_count);
+
+    let mut peer = None;
+    let mut failure = None;
+    let already_complete = coordinator.is_complete();
+    if !already_complete && !job.shutdown.is_requested() {
+        job.set_state(TorrentState::Downloading);
+        // What was fetched before the swarm failed us is still saved below.
+        match coordinator.download_from_swarm(&[], storage.as_mut()) {
+            Ok(finished) => peer = finished,
+            Err(error) => failure = Some(error),
+        }
+    }
+    if coordinator.is_complete() && working != out {
+        storage.sync().map_err(StorageError::Sync)?;
+        storage::finish(&working, out, &info)
+            .map_err(|error| StorageError::Finish(out.to_path_buf(), error))?;
+        log::info!(torrent = name; "moved {} to {}", working.display(), out.display());
+        content_paths = storage::content_paths(out, &info);
+    }
+    let completed = coordinator.is_complete() && !already_complete;
+    if let Some(history) = &job.history {
+        if completed {
+            if let Err(error) = history.record_completed(&info_hash, &name) {
+                log::warn!(torrent = name; "failed to record history in {}: {}", history.path().display(), error);
+            }
+        }
+    }
+    if let Some(command) = &job.on_complete {
+        if completed {
+            let completion = hook::Completion {
+                name: &name,
+                path: out,
+                info_hash: &info_hash,
+                bytes: info.length as u64,
+            };
+            hook::on_complete(command, completion);
+        }
+    }
+
+    if coordinator.is_complete() && !job.shutdown.is_requested() {
+        job.set_state(TorrentState::Seeding);
+        coordinator.seed(&job.seed_limits, out);
+    }
+    coordinator.close(peer.as_mut(), storage.as_mut())?;
+
+    let (uploaded, downloaded) =
+        resumed.map_or((0, 0), |resume| (resume.uploaded, resume.downloaded));
+    let session_downloaded = coordinator.bytes_downloaded();
+    let session_uploaded = job
+        .peer_manager
+        .lock()
+        .expect("Peer manager lock poisoned")
+        .uploaded(info_hash_bytes)
+        - uploaded_before;
+    if let Some(history) = &job.history {
+        if let Err(error) =
+            history.record_transfer(&info_hash, &name, session_uploaded, session_downloaded)
+        {
+            log::warn!(torrent = name; "failed to record history in {}: {}", history.path().display(), error);
+        }
+    }
+    if let Some(files) = FileState::read_all(&content_paths) {
+        let resume = ResumeData::new(
+            info_hash,
+            coordinator.completed(),
+            files,
+            uploaded + session_uploaded,
+            downloaded + session_downloaded,
+        );
+        if let Err(error) = resume.save(&resume_path) {
+            log::warn!(torrent = name; "failed to save {}: {}", resume_path, error);
+        }
+    }
+    match failure {
+        Some(error) => Err(error),
+        None => {
+            job.set_state(TorrentState::Stopped);
+            Ok(())
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum SessionError {
+    #[error("torrent {0} has already been added")]
+    Duplicate(String),
+    #[error("no torrent with info hash {0}")]
+    Unknown(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        net::TcpListener,
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use super::{Session, SessionConfig, SessionError, TorrentState};
+    use crate::{
+        bandwidth::{BandwidthSchedule, Limit, RateLimiter},
+        create::{TorrentCreator, TorrentVersion},
+        peer::{client_name, TEST_PEER_ID},
+        peer_manager::PeerManager,
+        torrent::{announce, Torrent},
+        tracker_server,
+    };
+
+    #[test]
+    fn pauses_resumes_cancels_and_removes_torrents() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = dir.path().join("payload");
+        fs::write(&data, vec![7; 40_000]).unwrap();
+        let tracker = tracker_server::serve("127.0.0.1:0", Duration::from_secs(60)).unwrap();
+        let url = format!("http://{}/announce", tracker);
+        let creator = TorrentCreator {
+            announce: url.clone(),
+            piece_length: 16 * 1024,
+            private: false,
+            version: TorrentVersion::V1,
+        };
+        let torrent = creator.create(&data).unwrap().bytes;
+        let torrent_file = dir.path().join("payload.torrent");
+        fs::write(&torrent_file, torrent).unwrap();
+        fs::remove_file(&data).unwrap();
+
+        // The only peer in the swarm refuses connections, so the torrent keeps waiting to
+        // retry it until cancelled.
+        let closed = TcpListener::bind("127.0.0.1:0").unwrap();
+        let closed_port = closed.local_addr().unwrap().port();
+        drop(closed);
+        let torrent = Torrent::open(&torrent_file).unwrap();
+        announce(
+            &url,
+            &torrent.info_hash(),
+            &TEST_PEER_ID,
+            closed_port,
+            0,
+            None,
+        )
+        .unwrap();
+
+        let config = SessionConfig {
+            download_dir: dir.path().to_path_buf(),
+            port: 0,
+            ..SessionConfig::default()
+        };
+        let schedule = BandwidthSchedule::new(Limit::Unlimited, vec![]);
+        let mut session =
+            Session::start(config, PeerManager::new(), RateLimiter::new(schedule)).unwrap();
+        assert_ne!(session.port(), 0);
+        assert_eq!(client_name(&session.peer_id()).as_deref(), Some("BR 0.1"));
+
+        let info_hash = session.add(torrent).unwrap();
+        let wait_for = |session: &mut Session, state| {
+            let started = Instant::now();
+            while session.torrent(&info_hash).unwrap().state() != state {
+                assert!(started.elapsed() < Duration::from_secs(10));
+                thread::sleep(Duration::from_millis(20));
+                session.reap();
+            }
+        };
+        wait_for(&mut session, TorrentState::Downloading);
+        session
+            .torrent(&info_hash)
+            .unwrap()
+            .cancellation_token()
+            .request();
+        wait_for(&mut session, TorrentState::Stopped);
+        assert!(!session.torrent(&info_hash).unwrap().is_running());
+
+        session.resume(&info_hash).unwrap();
+        wait_for(&mut session, TorrentState::Downloading);
+        let started = Instant::now();
+        session.pause(&info_hash).unwrap();
+        assert!(started.elapsed() < Duration::from_secs(2));
+        let torrent = session.torrent(&info_hash).unwrap();
+        assert_eq!(torrent.state(), TorrentState::Paused);
+        assert!(!torrent.is_running());
+        assert_eq!(torrent.path(), dir.path().join("payload"));
+
+        session.remove(&info_hash).unwrap();
+        assert_eq!(session.torrents().count(), 0);
+        assert_eq!(
+            session.pause(&info_hash),
+            Err(SessionError::Unknown(info_hash))
+        );
+    }
+}
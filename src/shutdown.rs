@@ -0,0 +1,115 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::log;
+
+// How often `sleep` checks whether it should wake early.
+const SLEEP_SLICE: Duration = Duration::from_millis(100);
+
+/// A flag the download loops poll at safe points so they can stop cleanly instead of the
+/// process exiting mid-protocol.
+///
+/// It doubles as a cancellation token: a [`Shutdown::child`] stops one torrent, to pause or
+/// remove it, without stopping anything else.
+#[derive(Debug, Clone, Default)]
+pub struct Shutdown {
+    requested: Arc<AtomicBool>,
+    // Requesting the parent requests this one too.
+    parent: Option<Box<Shutdown>>,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn request(&self) {
+        self.requested.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+            || self
+                .parent
+                .as_ref()
+                .is_some_and(|parent| parent.is_requested())
+    }
+
+    /// A shutdown that can be requested on its own, and is requested whenever this one is.
+    pub fn child(&self) -> Shutdown {
+        Shutdown {
+            requested: Arc::default(),
+            parent: Some(Box::new(self.clone())),
+        }
+    }
+
+    /// Sleeps for `duration`, waking early if a shutdown is requested meanwhile. Returns
+    /// whether one was.
+    pub fn sleep(&self, duration: Duration) -> bool {
+        let deadline = Instant::now() + duration;
+        while !self.is_requested() {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return false;
+            }
+            thread::sleep(remaining.min(SLEEP_SLICE));
+        }
+        true
+    }
+
+    /// Requests a shutdown on the first Ctrl-C. A second Ctrl-C exits straight away, in case
+    /// we are stuck waiting on a peer that never answers.
+    pub fn request_on_ctrl_c(&self) {
+        let shutdown = self.clone();
+        thread::spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .expect("Failed to build signal runtime");
+
+            runtime.block_on(async {
+                tokio::signal::ctrl_c()
+                    .await
+                    .expect("Failed to listen for Ctrl-C");
+                log::warn!("shutting down, press Ctrl-C again to exit immediately");
+                shutdown.request();
+
+                tokio::signal::ctrl_c()
+                    .await
+                    .expect("Failed to listen for Ctrl-C");
+                std::process::exit(130);
+            });
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::Shutdown;
+
+    #[test]
+    fn children_stop_with_their_parent_but_not_the_other_way_round() {
+        let session = Shutdown::new();
+        let first = session.child();
+        let second = session.child();
+
+        first.request();
+        assert!(first.is_requested());
+        assert!(!second.is_requested() && !session.is_requested());
+        assert!(!second.sleep(Duration::from_millis(10)));
+
+        session.request();
+        assert!(second.is_requested());
+        let started = Instant::now();
+        assert!(second.sleep(Duration::from_secs(60)));
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,586 @@
+//! Deterministic simulation of a download. The coordinator downloads from made-up peers over a
+//! simulated clock, and everything left to chance — how slowly each peer answers, which
+//! requests it drops, when it chokes us, which blocks arrive corrupt, which pieces it has and
+//! which pieces we pick — is drawn from one seed. A run takes no real time, and running the
+//! same seed again takes exactly the same steps, so a scheduling bug found by one seed can be
+//! replayed from it.
+//!
+//! ```
+//! use bittorrent_starter_rust::sim::Simulation;
+//!
+//! let outcome = Simulation::new(7).run();
+//! assert!(outcome.complete);
+//! assert_eq!(outcome.trace, Simulation::new(7).run().trace);
+//! ```
+
+use std::{
+    collections::VecDeque,
+    io::{self, Read, Write},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use sha1::{Digest, Sha1};
+
+use crate::{
+    bitfield::Bitfield,
+    clock::Clock,
+    coordinator::DownloadCoordinator,
+    peer::{PeerConnection, Transport},
+    peer_manager::PeerManager,
+    picker::RandomFirstPicker,
+    storage::MemoryStorage,
+    torrent::{Info, Torrent},
+    wire::{BlockRequest, Handshake, Message, MessageId},
+};
+
+const PIECE_LENGTH: usize = 32 * 1024;
+
+/// xorshift64*, which is all a simulation needs to be repeatable. The multiply at the end
+/// keeps the low bits, which `one_in` draws from, as random as the rest.
+#[derive(Debug, Clone)]
+struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        Self {
+            state: seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1,
+        }
+    }
+
+    fn next(&mut self) -> u64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.state.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// A number in `range`, which must not be empty.
+    fn between(&mut self, range: std::ops::Range<u64>) -> u64 {
+        range.start + self.next() % (range.end - range.start)
+    }
+
+    /// True once in `n` times on average, or never for zero.
+    fn one_in(&mut self, n: u32) -> bool {
+        n != 0 && self.next().is_multiple_of(n as u64)
+    }
+
+    fn millis(&mut self, range: std::ops::Range<u64>) -> Duration {
+        Duration::from_millis(self.between(range))
+    }
+}
+
+/// How a simulated peer treats us.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Behaviour {
+    /// How long the peer takes to answer anything, at least.
+    pub latency: Duration,
+    /// Up to how much longer it takes on top, drawn for each answer.
+    pub jitter: Duration,
+    /// Ignores one request in this many, so we have to ask again. Never for zero.
+    pub drop_one_in: u32,
+    /// Chokes us after sending this many blocks, then unchokes us `choke_for` later.
+    pub choke_every: Option<u32>,
+    pub choke_for: Duration,
+    /// Sends one block in this many with its bytes flipped. Never for zero.
+    pub corrupt_one_in: u32,
+    /// Hangs up once it has sent this many blocks.
+    pub hang_up_after: Option<u32>,
+    /// Which pieces the peer has, out of every one if `None`.
+    pub pieces: Option<Vec<usize>>,
+}
+
+impl Behaviour {
+    /// A peer with every piece that answers everything promptly.
+    pub fn reliable() -> Self {
+        Self {
+            latency: Duration::from_millis(20),
+            jitter: Duration::ZERO,
+            drop_one_in: 0,
+            choke_every: None,
+            choke_for: Duration::ZERO,
+            corrupt_one_in: 0,
+            hang_up_after: None,
+            pieces: None,
+        }
+    }
+
+    fn random(rng: &mut Rng, piece_count: usize) -> Self {
+        let has_everything = rng.one_in(2);
+        Self {
+            latency: rng.millis(1..200),
+            jitter: rng.millis(0..50),
+            drop_one_in: [0, 0, 4, 12][rng.between(0..4) as usize],
+            choke_every: rng.one_in(2).then(|| rng.between(1..8) as u32),
+            choke_for: rng.millis(50..5000),
+            corrupt_one_in: [0, 0, 0, 6][rng.between(0..4) as usize],
+            hang_up_after: rng.one_in(4).then(|| rng.between(0..16) as u32),
+            pieces: (!has_everything)
+                .then(|| (0..piece_count).filter(|_| !rng.one_in(4)).collect()),
+        }
+    }
+}
+
+/// What became of a simulated download.
+#[derive(Debug)]
+pub struct Outcome {
+    pub complete: bool,
+    /// What was written to storage, with zeroes where pieces are missing.
+    pub data: Vec<u8>,
+    /// How much simulated time the download took.
+    pub elapsed: Duration,
+    /// Every message exchanged, and how each peer's download ended, with simulated times.
+    pub trace: Vec<String>,
+}
+
+/// A payload and the peers to download it from, tried one after another until we have it all.
+pub struct Simulation {
+    seed: u64,
+    torrent: Torrent,
+    payload: Arc<Vec<u8>>,
+    peers: Vec<Behaviour>,
+}
+
+impl Simulation {
+    /// A swarm made up from `seed`: a payload of a few pieces and up to three peers behaving
+    /// at random, followed by a reliable one so the download can always finish.
+    pub fn new(seed: u64) -> Self {
+        let mut rng = Rng::new(seed);
+        let pieces = rng.between(3..12) as usize;
+        let length = pieces * PIECE_LENGTH - rng.between(0..PIECE_LENGTH as u64) as usize;
+        let payload = (0..length).map(|_| rng.next() as u8).collect::<Vec<_>>();
+        let mut peers = (0..rng.between(0..4))
+            .map(|_| Behaviour::random(&mut rng, pieces))
+            .collect::<Vec<_>>();
+        peers.push(Behaviour::reliable());
+        Self::with_peers(seed, payload, peers)
+    }
+
+    /// `payload` shared by `peers`, with what is left to chance drawn from `seed`.
+    pub fn with_peers(seed: u64, payload: Vec<u8>, peers: Vec<Behaviour>) -> Self {
+        let info = Info {
+            length: payload.len(),
+            name: format!("simulation-{}", seed),
+            piece_length: PIECE_LENGTH,
+            pieces: payload
+                .chunks(PIECE_LENGTH)
+                .map(|piece| Sha1::digest(piece).into())
+                .collect(),
+            files: vec![],
+            private: false,
+        };
+        let info_hash = info.hash();
+        Self {
+            seed,
+            torrent: Torrent::new(String::new(), vec![], info, info_hash),
+            payload: Arc::new(payload),
+            peers,
+        }
+    }
+
+    pub fn payload(&self) -> &[u8] {
+        &self.payload
+    }
+
+    pub fn peers(&self) -> &[Behaviour] {
+        &self.peers
+    }
+
+    pub fn run(&self) -> Outcome {
+        let clock = Clock::simulated();
+        let trace = Arc::new(Mutex::new(Vec::new()));
+        let mut rng = Rng::new(self.seed);
+        let piece_count = self.torrent.info.pieces.len();
+
+        let peer_manager = Arc::new(Mutex::new(PeerManager::new()));
+        let mut coordinator = DownloadCoordinator::new(self.torrent.clone(), 0, peer_manager);
+        coordinator.set_clock(clock.clone());
+        coordinator.set_picker(Box::new(RandomFirstPicker::new(rng.next())));
+        let mut storage = MemoryStorage::new(PIECE_LENGTH, self.payload.len());
+
+        for (index, behaviour) in self.peers.iter().enumerate() {
+            let addr = SocketAddr::from(([10, 0, 0, index as u8 + 1], 6881));
+            let mut pieces = Bitfield::new(piece_count);
+            for piece in behaviour
+                .pieces
+                .clone()
+                .unwrap_or_else(|| (0..piece_count).collect())
+            {
+                pieces.set(piece);
+            }
+            let peer = SimPeer {
+                addr,
+                behaviour: behaviour.clone(),
+                clock: clock.clone(),
+                rng: Rng::new(rng.next()),
+                payload: self.payload.clone(),
+                info_hash: self.torrent.info_hash_bytes(),
+                pieces,
+                trace: trace.clone(),
+                written: Vec::new(),
+                shaken: false,
+                scheduled: VecDeque::new(),
+                readable: VecDeque::new(),
+                choked: None,
+                blocks_sent: 0,
+                closed: false,
+            };
+
+            let connection = PeerConnection::over(Box::new(peer), addr, piece_count);
+            let ended = match coordinator.handshake(connection) {
+                Ok((mut peer, _)) => {
+                    let result = coordinator.download_all_pieces(&mut peer, &mut storage);
+                    let _ = coordinator.close(Some(&mut peer), &mut storage);
+                    match result {
+                        Ok(()) => "done".to_string(),
+                        Err(error) => error.to_string(),
+                    }
+                }
+                Err(error) => error.to_string(),
+            };
+            record(&trace, &clock, addr, &format!("ended: {}", ended));
+            if coordinator.is_complete() {
+                break;
+            }
+        }
+
+        let trace = trace.lock().expect("Trace lock poisoned").clone();
+        Outcome {
+            complete: coordinator.is_complete(),
+            data: storage.contents().to_vec(),
+            elapsed: clock.elapsed(),
+            trace,
+        }
+    }
+}
+
+fn record(trace: &Mutex<Vec<String>>, clock: &Clock, addr: SocketAddr, line: &str) {
+    trace.lock().expect("Trace lock poisoned").push(format!(
+        "{:>10.3}s {} {}",
+        clock.elapsed().as_secs_f64(),
+        addr,
+        line
+    ));
+}
+
+/// Something the peer will have sent by `at`. `None` is the peer hanging up.
+struct Delivery {
+    at: Instant,
+    message: Option<Vec<u8>>,
+    description: String,
+    /// The request a `Piece` answers, so a cancel can take it back.
+    answers: Option<BlockRequest>,
+}
+
+/// A peer living in a [`Transport`]: whatever we write to it is answered by scheduling replies
+/// on the simulated clock, and reading moves the clock on to when the next reply arrives.
+struct SimPeer {
+    addr: SocketAddr,
+    behaviour: Behaviour,
+    clock: Clock,
+    rng: Rng,
+    payload: Arc<Vec<u8>>,
+    info_hash: [u8; 20],
+    pieces: Bitfield,
+    trace: Arc<Mutex<Vec<String>>>,
+    // What we have written that does not make up a whole frame yet.
+    written: Vec<u8>,
+    shaken: bool,
+    // Replies in the order they arrive, which is never earlier than the one before.
+    scheduled: VecDeque<Delivery>,
+    readable: VecDeque<u8>,
+    // When the peer is choking us between, so ignores our requests.
+    choked: Option<(Instant, Instant)>,
+    blocks_sent: u32,
+    closed: bool,
+}
+
+impl SimPeer {
+    fn record(&self, line: &str) {
+        record(&self.trace, &self.clock, self.addr, line);
+    }
+
+    fn schedule(&mut self, message: Option<Message>, description: String) -> Instant {
+        let delay = self.behaviour.latency
+            + self
+                .rng
+                .millis(0..self.behaviour.jitter.as_millis() as u64 + 1);
+        let after = self.scheduled.back().map(|last| last.at);
+        let at = after.map_or(self.clock.now() + delay, |after| {
+            after.max(self.clock.now() + delay)
+        });
+        self.scheduled.push_back(Delivery {
+            at,
+            message: message.map(|message| message.encode()),
+            description,
+            answers: None,
+        });
+        at
+    }
+
+    fn deliver(&mut self) {
+        let delivery = self.scheduled.pop_front().expect("Nothing to deliver");
+        self.clock.advance_to(delivery.at);
+        self.record(&format!("<- {}", delivery.description));
+        match delivery.message {
+            Some(bytes) => self.readable.extend(bytes),
+            None => {
+                self.closed = true;
+                self.scheduled.clear();
+            }
+        }
+    }
+
+    fn received_handshake(&mut self) {
+        let bytes: [u8; 68] = self
+            .written
+            .drain(..68)
+            .collect::<Vec<_>>()
+            .try_into()
+            .unwrap();
+        self.shaken = true;
+        self.record("-> handshake");
+        if Handshake::decode(bytes).info_hash != self.info_hash {
+            self.schedule(None, "hang up: wrong torrent".to_string());
+            return;
+        }
+
+        let reply = Handshake::new(
+            "BitTorrent protocol".to_string(),
+            self.info_hash,
+            [9; 20],
+        );
+        self.scheduled.push_back(Delivery {
+            at: self.clock.now() + self.behaviour.latency,
+            message: Some(reply.encode()),
+            description: "handshake".to_string(),
+            answers: None,
+        });
+        let bitfield = Message::new(MessageId::Bitfield, self.pieces.as_bytes().to_vec());
+        self.schedule(
+            Some(bitfield),
+            format!("bitfield of {} pieces", self.pieces.count()),
+        );
+    }
+
+    fn received(&mut self, message: Message) {
+        match message.id {
+            MessageId::Request => match BlockRequest::decode(&message.payload) {
+                Some(request) => self.requested(request),
+                None => {
+                    self.record("-> malformed request");
+                    self.schedule(None, "hang up: malformed request".to_string());
+                }
+            },
+            MessageId::Cancel => {
+                let Some(request) = BlockRequest::decode(&message.payload) else {
+                    return;
+                };
+                self.record(&format!("-> cancel {}", describe(&request)));
+                self.scheduled
+                    .retain(|delivery| delivery.answers != Some(request));
+            }
+            MessageId::Interested => {
+                self.record("-> interested");
+                if self.choked.is_none() {
+                    self.schedule(
+                        Some(Message::new(MessageId::Unchoke, vec![])),
+                        "unchoke".to_string(),
+                    );
+                }
+            }
+            id => self.record(&format!("-> {:?}", id)),
+        }
+    }
+
+    fn requested(&mut self, request: BlockRequest) {
+        self.record(&format!("-> request {}", describe(&request)));
+        let now = self.clock.now();
+        if self
+            .choked
+            .is_some_and(|(from, to)| from <= now && now < to)
+        {
+            return;
+        }
+        if self.rng.one_in(self.behaviour.drop_one_in) || !self.pieces.has(request.index as usize) {
+            return;
+        }
+
+        let start = request.index as usize * PIECE_LENGTH + request.begin as usize;
+        let Some(block) = self.payload.get(start..start + request.length as usize) else {
+            self.schedule(None, "hang up: request out of range".to_string());
+            return;
+        };
+        let mut block = block.to_vec();
+        let mut description = format!("piece {}", describe(&request));
+        if self.rng.one_in(self.behaviour.corrupt_one_in) {
+            block.iter_mut().for_each(|byte| *byte = !*byte);
+            description.push_str(", corrupt");
+        }
+        let piece = Message::piece(request.index, request.begin, &block);
+        let at = self.schedule(Some(piece), description);
+        self.scheduled.back_mut().unwrap().answers = Some(request);
+        self.blocks_sent += 1;
+
+        if self.behaviour.hang_up_after == Some(self.blocks_sent) {
+            self.schedule(None, "hang up".to_string());
+        } else if self
+            .behaviour
+            .choke_every
+            .is_some_and(|every| self.blocks_sent.is_multiple_of(every))
+        {
+            self.schedule(
+                Some(Message::new(MessageId::Choke, vec![])),
+                "choke".to_string(),
+            );
+            let until = at + self.behaviour.choke_for;
+            self.choked = Some((at, until));
+            self.scheduled.push_back(Delivery {
+                at: until,
+                message: Some(Message::new(MessageId::Unchoke, vec![]).encode()),
+                description: "unchoke".to_string(),
+                answers: None,
+            });
+        }
+    }
+}
+
+fn describe(request: &BlockRequest) -> String {
+    format!("{}:{}+{}", request.index, request.begin, request.length)
+}
+
+impl Read for SimPeer {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.readable.is_empty() && !self.closed {
+            if self.scheduled.is_empty() {
+                // Nothing is coming, and with nobody else to move the clock on we would wait
+                // forever.
+                return Err(io::ErrorKind::TimedOut.into());
+            }
+            self.deliver();
+        }
+        let length = buf.len().min(self.readable.len());
+        for (target, byte) in buf.iter_mut().zip(self.readable.drain(..length)) {
+            *target = byte;
+        }
+        Ok(length)
+    }
+}
+
+impl Write for SimPeer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.closed {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        self.written.extend_from_slice(buf);
+        if !self.shaken {
+            if self.written.len() >= 68 {
+                self.received_handshake();
+            } else {
+                return Ok(buf.len());
+            }
+        }
+        while let Some(header) = self.written.get(..4) {
+            let length = u32::from_be_bytes(header.try_into().unwrap()) as usize;
+            if self.written.len() < 4 + length {
+                break;
+            }
+            let frame = self.written.drain(..4 + length).collect::<Vec<_>>();
+            if length > 0 {
+                self.received(Message::new(MessageId::from(frame[4]), frame[5..].to_vec()));
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for SimPeer {
+    fn wait_readable(&mut self, timeout: Duration) -> io::Result<bool> {
+        if !self.readable.is_empty() || self.closed {
+            return Ok(true);
+        }
+        let deadline = self.clock.now() + timeout;
+        if self
+            .scheduled
+            .front()
+            .is_some_and(|next| next.at <= deadline)
+        {
+            self.deliver();
+            return Ok(true);
+        }
+        self.clock.advance_to(deadline);
+        Ok(false)
+    }
+
+    fn set_timeout(&mut self, _: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn shutdown(&mut self) {
+        if !self.closed {
+            self.record("-> hang up");
+            self.closed = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{Behaviour, Simulation};
+
+    #[test]
+    fn the_same_seed_takes_the_same_steps() {
+        for seed in 0..8 {
+            let first = Simulation::new(seed).run();
+            let second = Simulation::new(seed).run();
+            assert_eq!(first.trace, second.trace, "seed {}", seed);
+            assert_eq!(first.elapsed, second.elapsed, "seed {}", seed);
+        }
+        assert_ne!(
+            Simulation::new(1).run().trace,
+            Simulation::new(2).run().trace
+        );
+    }
+
+    #[test]
+    fn finishes_with_the_right_data_whatever_the_seed() {
+        for seed in 0..64 {
+            let simulation = Simulation::new(seed);
+            let outcome = simulation.run();
+            assert!(
+                outcome.complete && outcome.data == simulation.payload(),
+                "seed {} failed, replay it with Simulation::new({}):\n{}",
+                seed,
+                seed,
+                outcome.trace.join("\n")
+            );
+        }
+    }
+
+    #[test]
+    fn asks_again_for_dropped_requests_without_waiting_in_real_time() {
+        let payload = (0..100_000).map(|i| (i % 253) as u8).collect::<Vec<_>>();
+        let flaky = Behaviour {
+            drop_one_in: 2,
+            ..Behaviour::reliable()
+        };
+        let simulation = Simulation::with_peers(3, payload.clone(), vec![flaky]);
+
+        let started = Instant::now();
+        let outcome = simulation.run();
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(outcome.complete, "{}", outcome.trace.join("\n"));
+        assert_eq!(outcome.data, payload);
+        assert!(outcome.elapsed >= Duration::from_secs(30));
+        assert!(outcome.trace.iter().any(|line| line.contains("-> cancel")));
+    }
+}
@@ -0,0 +1,132 @@
+use std::{
+    collections::VecDeque,
+    fmt::Display,
+    time::{Duration, Instant},
+};
+
+// How far back the rolling rates look when averaging transfer speed.
+const RATE_WINDOW: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Default)]
+pub struct PeerStats {
+    pub bytes_downloaded: u64,
+    pub bytes_uploaded: u64,
+    pub hash_fails: u32,
+    pub unsolicited_blocks: u32,
+    pub timeouts: u32,
+    download_rate: RollingRate,
+    upload_rate: RollingRate,
+    latency_total: Duration,
+    latency_samples: u32,
+}
+
+impl PeerStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record_download(&mut self, bytes: usize) {
+        self.bytes_downloaded += bytes as u64;
+        self.download_rate.record(bytes);
+    }
+
+    pub fn record_upload(&mut self, bytes: usize) {
+        self.bytes_uploaded += bytes as u64;
+        self.upload_rate.record(bytes);
+    }
+
+    pub fn record_latency(&mut self, latency: Duration) {
+        self.latency_total += latency;
+        self.latency_samples += 1;
+    }
+
+    pub fn record_hash_fail(&mut self) {
+        self.hash_fails += 1;
+    }
+
+    /// Counts a block we never asked for (or already received) against the peer.
+    pub fn record_unsolicited_block(&mut self) {
+        self.unsolicited_blocks += 1;
+    }
+
+    pub fn record_timeout(&mut self) {
+        self.timeouts += 1;
+    }
+
+    /// Bytes per second received over the rolling window.
+    pub fn download_rate(&self) -> f64 {
+        self.download_rate.rate()
+    }
+
+    /// Bytes per second sent over the rolling window.
+    pub fn upload_rate(&self) -> f64 {
+        self.upload_rate.rate()
+    }
+
+    /// Mean time between sending a block request and receiving the piece.
+    pub fn average_latency(&self) -> Option<Duration> {
+        if self.latency_samples == 0 {
+            return None;
+        }
+
+        Some(self.latency_total / self.latency_samples)
+    }
+}
+
+impl Display for PeerStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "down: {} bytes ({:.1} KiB/s), up: {} bytes ({:.1} KiB/s), latency: ",
+            self.bytes_downloaded,
+            self.download_rate() / 1024.0,
+            self.bytes_uploaded,
+            self.upload_rate() / 1024.0,
+        )?;
+
+        match self.average_latency() {
+            Some(latency) => write!(f, "{}ms", latency.as_millis())?,
+            None => write!(f, "n/a")?,
+        }
+
+        write!(
+            f,
+            ", hash fails: {}, unsolicited blocks: {}, timeouts: {}",
+            self.hash_fails, self.unsolicited_blocks, self.timeouts
+        )
+    }
+}
+
+#[derive(Debug, Default)]
+struct RollingRate {
+    samples: VecDeque<(Instant, usize)>,
+}
+
+impl RollingRate {
+    fn record(&mut self, bytes: usize) {
+        let now = Instant::now();
+        self.samples.push_back((now, bytes));
+        self.expire(now);
+    }
+
+    fn rate(&self) -> f64 {
+        let now = Instant::now();
+        let total: usize = self
+            .samples
+            .iter()
+            .filter(|(at, _)| now.duration_since(*at) <= RATE_WINDOW)
+            .map(|(_, bytes)| bytes)
+            .sum();
+
+        total as f64 / RATE_WINDOW.as_secs_f64()
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while let Some((at, _)) = self.samples.front() {
+            if now.duration_since(*at) <= RATE_WINDOW {
+                break;
+            }
+            self.samples.pop_front();
+        }
+    }
+}
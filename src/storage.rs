@@ -0,0 +1,908 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    ops::Range,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use sha1::{Digest, Sha1};
+
+use crate::{
+    log,
+    torrent::{FileEntry, Info},
+};
+
+#[cfg(target_os = "linux")]
+use crate::direct_io::DirectStorage;
+#[cfg(unix)]
+use crate::mmap::MmapStorage;
+
+/// Where downloaded data ends up. Blocks are addressed by piece and offset within it, so pieces
+/// and the blocks within them can arrive in any order.
+pub trait Storage {
+    /// Writes `data` at `begin` bytes into piece `piece_index`.
+    fn write_block(&mut self, piece_index: usize, begin: usize, data: &[u8]) -> io::Result<()>;
+
+    /// Reads `length` bytes at `begin` bytes into piece `piece_index`.
+    fn read_block(
+        &mut self,
+        piece_index: usize,
+        begin: usize,
+        length: usize,
+    ) -> io::Result<Vec<u8>>;
+
+    /// Makes sure everything written so far is on disk.
+    fn sync(&mut self) -> io::Result<()>;
+
+    /// Called once every block of a piece has been written.
+    fn piece_written(&mut self, _piece_index: usize) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Reads back the `length` bytes of piece `piece_index` and checks them against `hash`.
+    fn verify_piece(
+        &mut self,
+        piece_index: usize,
+        length: usize,
+        hash: &[u8; 20],
+    ) -> io::Result<bool> {
+        let piece = self.read_block(piece_index, 0, length)?;
+        Ok(Sha1::digest(piece).as_slice() == hash)
+    }
+}
+
+/// When written data is forced out to disk. Syncing more often loses less on a crash but
+/// slows the download down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlushPolicy {
+    Block,
+    Piece,
+    Periodic(Duration),
+    Completion,
+}
+
+impl FromStr for FlushPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "block" => Ok(FlushPolicy::Block),
+            "piece" => Ok(FlushPolicy::Piece),
+            "completion" => Ok(FlushPolicy::Completion),
+            seconds => seconds
+                .parse::<u64>()
+                .ok()
+                .filter(|seconds| *seconds > 0)
+                .map(|seconds| FlushPolicy::Periodic(Duration::from_secs(seconds)))
+                .ok_or_else(|| format!("Invalid flush policy: {}", seconds)),
+        }
+    }
+}
+
+/// Syncs the storage it wraps according to a [`FlushPolicy`].
+pub struct FlushingStorage {
+    inner: Box<dyn Storage>,
+    policy: FlushPolicy,
+    last_sync: Instant,
+}
+
+impl FlushingStorage {
+    pub fn new(inner: Box<dyn Storage>, policy: FlushPolicy) -> Self {
+        Self {
+            inner,
+            policy,
+            last_sync: Instant::now(),
+        }
+    }
+}
+
+impl Storage for FlushingStorage {
+    fn write_block(&mut self, piece_index: usize, begin: usize, data: &[u8]) -> io::Result<()> {
+        self.inner.write_block(piece_index, begin, data)?;
+        match self.policy {
+            FlushPolicy::Block => self.sync(),
+            FlushPolicy::Periodic(interval) if self.last_sync.elapsed() >= interval => self.sync(),
+            _ => Ok(()),
+        }
+    }
+
+    fn read_block(
+        &mut self,
+        piece_index: usize,
+        begin: usize,
+        length: usize,
+    ) -> io::Result<Vec<u8>> {
+        self.inner.read_block(piece_index, begin, length)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.last_sync = Instant::now();
+        self.inner.sync()
+    }
+
+    fn piece_written(&mut self, piece_index: usize) -> io::Result<()> {
+        self.inner.piece_written(piece_index)?;
+        if self.policy == FlushPolicy::Piece {
+            self.sync()?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum StorageKind {
+    /// Seek and write through a file handle
+    File,
+    /// Map the whole file into memory
+    Mmap,
+    /// Bypass the page cache with direct I/O where the platform allows it
+    Direct,
+}
+
+impl StorageKind {
+    /// Opens the output for a torrent at `path`, creating it if needed and keeping any data
+    /// already there: a file for a single-file torrent, or a directory holding every file of a
+    /// multi-file one.
+    pub fn open<P: AsRef<Path>>(self, path: P, info: &Info) -> io::Result<Box<dyn Storage>> {
+        if !info.files.is_empty() {
+            if self != StorageKind::File {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "multi-file torrents can only be written through file storage",
+                ));
+            }
+            return Ok(Box::new(MultiFileStorage::open(
+                path,
+                &info.files,
+                info.piece_length,
+            )?));
+        }
+
+        match self {
+            StorageKind::File => Ok(Box::new(FileStorage::open(path, info.piece_length)?)),
+            #[cfg(unix)]
+            StorageKind::Mmap => Ok(Box::new(MmapStorage::open(
+                path,
+                info.piece_length,
+                info.length,
+            )?)),
+            #[cfg(not(unix))]
+            StorageKind::Mmap => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "memory-mapped storage needs a unix platform",
+            )),
+            #[cfg(target_os = "linux")]
+            StorageKind::Direct => {
+                match DirectStorage::open(&path, info.piece_length, info.length) {
+                    Ok(storage) => Ok(Box::new(storage)),
+                    Err(error) => {
+                        log::warn!("direct I/O unavailable ({}), using buffered writes", error);
+                        Ok(Box::new(FileStorage::open(path, info.piece_length)?))
+                    }
+                }
+            }
+            #[cfg(not(target_os = "linux"))]
+            StorageKind::Direct => {
+                log::warn!("direct I/O needs Linux, using buffered writes");
+                Ok(Box::new(FileStorage::open(path, info.piece_length)?))
+            }
+        }
+    }
+}
+
+/// Opens data already on disk for reading only, as when seeding it. Unlike
+/// [`StorageKind::open`] this never creates, extends or truncates files.
+pub fn open_existing<P: AsRef<Path>>(path: P, info: &Info) -> io::Result<Box<dyn Storage>> {
+    if info.files.is_empty() {
+        let file = File::open(path)?;
+        return Ok(Box::new(FileStorage::new(file, info.piece_length)));
+    }
+
+    let mut files = Vec::with_capacity(info.files.len());
+    let mut offset = 0;
+    for (path, entry) in content_paths(path.as_ref(), info).iter().zip(&info.files) {
+        files.push((offset, entry.length as u64, File::open(path)?));
+        offset += entry.length as u64;
+    }
+    Ok(Box::new(MultiFileStorage {
+        files,
+        length: offset,
+        piece_length: info.piece_length,
+    }))
+}
+
+/// The files a torrent's content is written to: `path` itself for a single-file torrent, or
+/// each of its files below `path` for a multi-file one.
+pub fn content_paths(path: &Path, info: &Info) -> Vec<PathBuf> {
+    if info.files.is_empty() {
+        return vec![path.to_path_buf()];
+    }
+    info.files
+        .iter()
+        .map(|file| path.join(safe_relative_path(file)))
+        .collect()
+}
+
+// Longest file name most filesystems allow, in bytes.
+const MAX_COMPONENT_LENGTH: usize = 255;
+// Names Windows reserves for devices, whatever their extension.
+const RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Where a file goes below the torrent's directory. The path comes from the torrent, so each
+/// component is made safe to use as a single file name: nothing can climb out with `..`, start
+/// again from the root, name a device or be too long to create.
+pub fn safe_relative_path(entry: &FileEntry) -> PathBuf {
+    let path = entry
+        .path
+        .iter()
+        .filter(|component| !component.is_empty() && *component != ".")
+        .map(|component| safe_component(component))
+        .collect::<PathBuf>();
+    if path.as_os_str().is_empty() {
+        PathBuf::from("_")
+    } else {
+        path
+    }
+}
+
+pub fn safe_component(component: &str) -> String {
+    let mut name = component
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '\0' => '_',
+            c => c,
+        })
+        .collect::<String>();
+
+    // Windows drops trailing dots and spaces, which would turn `..` back into a way up.
+    let trimmed = name.trim_end_matches(['.', ' ']).len();
+    name.truncate(trimmed);
+    if name.is_empty() {
+        name.push('_');
+    }
+
+    let stem = name.split('.').next().unwrap_or_default();
+    if RESERVED_NAMES
+        .iter()
+        .any(|reserved| stem.eq_ignore_ascii_case(reserved))
+    {
+        name.insert(0, '_');
+    }
+
+    if name.len() > MAX_COMPONENT_LENGTH {
+        // Keep a short extension so the file still opens with the right program.
+        let extension = name
+            .rfind('.')
+            .map(|dot| name[dot..].to_string())
+            .filter(|extension| extension.len() <= 16)
+            .unwrap_or_default();
+        let mut end = MAX_COMPONENT_LENGTH - extension.len();
+        while !name.is_char_boundary(end) {
+            end -= 1;
+        }
+        name.truncate(end);
+        name.push_str(&extension);
+    }
+    name
+}
+
+/// Where a download to `out` is written until every piece has verified. A finished output with
+/// nothing partial beside it is used as it is, so it can be rechecked and seeded in place.
+pub fn working_path(out: &Path, partial: &Path) -> PathBuf {
+    if out.exists() && !partial.exists() {
+        out.to_path_buf()
+    } else {
+        partial.to_path_buf()
+    }
+}
+
+/// Moves a verified download from where it was written to `out` in a single rename, so nobody
+/// watching `out` ever sees it half written. When `out` is on another filesystem the download
+/// is copied next to it, checked against the torrent's piece hashes and then renamed.
+pub fn finish(working: &Path, out: &Path, info: &Info) -> io::Result<()> {
+    if working == out {
+        return Ok(());
+    }
+    match fs::rename(working, out) {
+        Err(error) if error.kind() == io::ErrorKind::CrossesDevices => {
+            copy_and_verify(working, out, info)
+        }
+        result => result,
+    }
+}
+
+/// Copies a download to another filesystem, only putting it in place at `out` once every piece
+/// of the copy verifies. The original is removed afterwards.
+pub fn copy_and_verify(working: &Path, out: &Path, info: &Info) -> io::Result<()> {
+    let mut staging = out.as_os_str().to_owned();
+    staging.push(".part");
+    let staging = PathBuf::from(staging);
+
+    copy_recursively(working, &staging)?;
+    let verified = verify_all(&staging, info);
+    if !matches!(verified, Ok(true)) {
+        remove_recursively(&staging)?;
+        return match verified {
+            Err(error) => Err(error),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("the copy of {} did not verify", working.display()),
+            )),
+        };
+    }
+
+    fs::rename(&staging, out)?;
+    remove_recursively(working)
+}
+
+/// Whether every piece of the download at `path` matches its hash.
+fn verify_all(path: &Path, info: &Info) -> io::Result<bool> {
+    let mut storage = StorageKind::File.open(path, info)?;
+    for (piece_index, hash) in info.pieces.iter().enumerate() {
+        let length = usize::min(
+            info.length - piece_index * info.piece_length,
+            info.piece_length,
+        );
+        if !storage.verify_piece(piece_index, length, hash)? {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+fn copy_recursively(from: &Path, to: &Path) -> io::Result<()> {
+    if !from.is_dir() {
+        return fs::copy(from, to).map(|_| ());
+    }
+    fs::create_dir_all(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        copy_recursively(&entry.path(), &to.join(entry.file_name()))?;
+    }
+    Ok(())
+}
+
+fn remove_recursively(path: &Path) -> io::Result<()> {
+    if path.is_dir() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    }
+}
+
+/// Seeks to each block's offset in the file before writing it.
+pub struct FileStorage {
+    file: File,
+    piece_length: usize,
+}
+
+impl FileStorage {
+    pub fn new(file: File, piece_length: usize) -> Self {
+        Self { file, piece_length }
+    }
+
+    /// Creates an empty file at `path`, replacing anything already there.
+    pub fn create<P: AsRef<Path>>(path: P, piece_length: usize) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        Ok(Self::new(file, piece_length))
+    }
+
+    /// Opens the file at `path`, creating it if needed and keeping its contents.
+    pub fn open<P: AsRef<Path>>(path: P, piece_length: usize) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        Ok(Self::new(file, piece_length))
+    }
+
+    fn seek_to(&mut self, piece_index: usize, begin: usize) -> io::Result<()> {
+        let offset = (piece_index * self.piece_length + begin) as u64;
+        self.file.seek(SeekFrom::Start(offset)).map(|_| ())
+    }
+}
+
+impl Storage for FileStorage {
+    fn write_block(&mut self, piece_index: usize, begin: usize, data: &[u8]) -> io::Result<()> {
+        self.seek_to(piece_index, begin)?;
+        self.file.write_all(data)
+    }
+
+    fn read_block(
+        &mut self,
+        piece_index: usize,
+        begin: usize,
+        length: usize,
+    ) -> io::Result<Vec<u8>> {
+        self.seek_to(piece_index, begin)?;
+        let mut block = vec![0; length];
+        self.file.read_exact(&mut block)?;
+        Ok(block)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        self.file.sync_all()
+    }
+}
+
+/// Keeps a single piece at the start of the storage it wraps, for outputs holding only that
+/// piece rather than the whole torrent.
+pub struct SinglePieceStorage<S> {
+    inner: S,
+    piece_index: usize,
+}
+
+impl<S: Storage> SinglePieceStorage<S> {
+    pub fn new(inner: S, piece_index: usize) -> Self {
+        Self { inner, piece_index }
+    }
+
+    fn check(&self, piece_index: usize) -> io::Result<()> {
+        if piece_index != self.piece_index {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("only piece {} is kept", self.piece_index),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl<S: Storage> Storage for SinglePieceStorage<S> {
+    fn write_block(&mut self, piece_index: usize, begin: usize, data: &[u8]) -> io::Result<()> {
+        self.check(piece_index)?;
+        self.inner.write_block(0, begin, data)
+    }
+
+    fn read_block(
+        &mut self,
+        piece_index: usize,
+        begin: usize,
+        length: usize,
+    ) -> io::Result<Vec<u8>> {
+        self.check(piece_index)?;
+        self.inner.read_block(0, begin, length)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.inner.sync()
+    }
+
+    fn piece_written(&mut self, piece_index: usize) -> io::Result<()> {
+        self.check(piece_index)?;
+        self.inner.piece_written(0)
+    }
+}
+
+/// Drops everything written to it, for downloads whose output is only streamed. Nothing can be
+/// read back, so pieces are never served to other peers.
+pub struct NullStorage;
+
+impl Storage for NullStorage {
+    fn write_block(&mut self, _piece_index: usize, _begin: usize, _data: &[u8]) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn read_block(
+        &mut self,
+        _piece_index: usize,
+        _begin: usize,
+        _length: usize,
+    ) -> io::Result<Vec<u8>> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "streamed output is not kept",
+        ))
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Keeps the whole torrent in memory, for exercising the download engine without a filesystem.
+// Only the tests write to memory so far.
+#[allow(dead_code)]
+pub struct MemoryStorage {
+    data: Vec<u8>,
+    piece_length: usize,
+}
+
+#[allow(dead_code)]
+impl MemoryStorage {
+    pub fn new(piece_length: usize, length: usize) -> Self {
+        Self {
+            data: vec![0; length],
+            piece_length,
+        }
+    }
+
+    pub fn contents(&self) -> &[u8] {
+        &self.data
+    }
+
+    fn range(&self, piece_index: usize, begin: usize, length: usize) -> io::Result<Range<usize>> {
+        let start = piece_index * self.piece_length + begin;
+        if start + length > self.data.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "block runs past the end of the torrent",
+            ));
+        }
+        Ok(start..start + length)
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn write_block(&mut self, piece_index: usize, begin: usize, data: &[u8]) -> io::Result<()> {
+        let range = self.range(piece_index, begin, data.len())?;
+        self.data[range].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn read_block(
+        &mut self,
+        piece_index: usize,
+        begin: usize,
+        length: usize,
+    ) -> io::Result<Vec<u8>> {
+        let range = self.range(piece_index, begin, length)?;
+        Ok(self.data[range].to_vec())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Spreads the content of a multi-file torrent across its files, splitting any block that
+/// straddles a boundary between them.
+pub struct MultiFileStorage {
+    // Each file with the offset its content starts at and its length.
+    files: Vec<(u64, u64, File)>,
+    length: u64,
+    piece_length: usize,
+}
+
+impl MultiFileStorage {
+    /// Opens every file below `root`, creating any that are missing along with the
+    /// directories their paths need.
+    pub fn open<P: AsRef<Path>>(
+        root: P,
+        entries: &[FileEntry],
+        piece_length: usize,
+    ) -> io::Result<Self> {
+        let mut files = Vec::with_capacity(entries.len());
+        let mut offset = 0;
+        for entry in entries {
+            let path = root.as_ref().join(safe_relative_path(entry));
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&path)?;
+            file.set_len(entry.length as u64)?;
+
+            files.push((offset, entry.length as u64, file));
+            offset += entry.length as u64;
+        }
+
+        Ok(Self {
+            files,
+            length: offset,
+            piece_length,
+        })
+    }
+
+    /// Calls `f` with each file the `length` bytes at `offset` overlap, the position within
+    /// that file, and the range of the block that belongs there.
+    fn for_each_span<F>(&mut self, offset: u64, length: usize, mut f: F) -> io::Result<()>
+    where
+        F: FnMut(&mut File, u64, std::ops::Range<usize>) -> io::Result<()>,
+    {
+        let end = offset + length as u64;
+        if end > self.length {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "block runs past the end of the torrent",
+            ));
+        }
+
+        for (start, file_length, file) in &mut self.files {
+            let file_end = *start + *file_length;
+            if file_end <= offset || *start >= end {
+                continue;
+            }
+
+            let from = offset.max(*start);
+            let to = end.min(file_end);
+            let span = (from - offset) as usize..(to - offset) as usize;
+            f(file, from - *start, span)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Storage for MultiFileStorage {
+    fn write_block(&mut self, piece_index: usize, begin: usize, data: &[u8]) -> io::Result<()> {
+        let offset = (piece_index * self.piece_length + begin) as u64;
+        self.for_each_span(offset, data.len(), |file, position, span| {
+            file.seek(SeekFrom::Start(position))?;
+            file.write_all(&data[span])
+        })
+    }
+
+    fn read_block(
+        &mut self,
+        piece_index: usize,
+        begin: usize,
+        length: usize,
+    ) -> io::Result<Vec<u8>> {
+        let offset = (piece_index * self.piece_length + begin) as u64;
+        let mut block = vec![0; length];
+        self.for_each_span(offset, length, |file, position, span| {
+            file.seek(SeekFrom::Start(position))?;
+            file.read_exact(&mut block[span])
+        })?;
+        Ok(block)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        for (_, _, file) in &mut self.files {
+            file.flush()?;
+            file.sync_all()?;
+        }
+        Ok(())
+    }
+}
+
+/// Why a download could not be kept on disk.
+#[derive(Debug, thiserror::Error)]
+pub enum StorageError {
+    #[error("cannot open {}: {1}", .0.display())]
+    Open(PathBuf, io::Error),
+    #[error("cannot write piece {0}: {1}")]
+    Write(usize, io::Error),
+    #[error("cannot sync the download to disk: {0}")]
+    Sync(io::Error),
+    #[error("cannot move the download to {}: {1}", .0.display())]
+    Finish(PathBuf, io::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Seek};
+
+    use std::{io, path::Path, time::Duration};
+
+    use sha1::{Digest, Sha1};
+
+    use super::{
+        copy_and_verify, finish, safe_relative_path, working_path, FileStorage, FlushPolicy,
+        FlushingStorage, MemoryStorage, MultiFileStorage, SinglePieceStorage, Storage,
+    };
+    use crate::torrent::{FileEntry, Info};
+
+    #[test]
+    fn writes_blocks_at_their_offsets() {
+        let mut file = tempfile::tempfile().unwrap();
+        let mut storage = FileStorage::new(file.try_clone().unwrap(), 8);
+        storage.write_block(1, 4, &[4; 4]).unwrap();
+        storage.write_block(1, 0, &[3; 4]).unwrap();
+        storage.write_block(0, 0, &[1; 8]).unwrap();
+        storage.sync().unwrap();
+        assert_eq!(storage.read_block(1, 2, 4).unwrap(), [3, 3, 4, 4]);
+
+        let mut content = Vec::new();
+        file.rewind().unwrap();
+        file.read_to_end(&mut content).unwrap();
+        assert_eq!(content, [[1; 8], [3, 3, 3, 3, 4, 4, 4, 4]].concat());
+    }
+
+    #[test]
+    fn keeps_a_single_piece_at_the_start() {
+        let mut file = tempfile::tempfile().unwrap();
+        let inner = FileStorage::new(file.try_clone().unwrap(), 8);
+        let mut storage = SinglePieceStorage::new(inner, 3);
+        storage.write_block(3, 4, &[4; 4]).unwrap();
+        storage.write_block(3, 0, &[3; 4]).unwrap();
+        assert!(storage.write_block(0, 0, &[1; 8]).is_err());
+        assert_eq!(storage.read_block(3, 2, 4).unwrap(), [3, 3, 4, 4]);
+
+        let mut content = Vec::new();
+        file.rewind().unwrap();
+        file.read_to_end(&mut content).unwrap();
+        assert_eq!(content, [3, 3, 3, 3, 4, 4, 4, 4]);
+    }
+
+    #[test]
+    fn keeps_blocks_in_memory_and_verifies_pieces() {
+        let mut storage = MemoryStorage::new(4, 6);
+        storage.write_block(1, 0, &[2, 2]).unwrap();
+        storage.write_block(0, 0, &[1; 4]).unwrap();
+        assert!(storage.write_block(1, 1, &[0; 2]).is_err());
+        assert_eq!(storage.contents(), [1, 1, 1, 1, 2, 2]);
+
+        let hash = Sha1::digest([1; 4]).into();
+        assert!(storage.verify_piece(0, 4, &hash).unwrap());
+        assert!(!storage.verify_piece(1, 2, &hash).unwrap());
+        assert!(storage.verify_piece(1, 4, &hash).is_err());
+    }
+
+    #[test]
+    fn splits_blocks_across_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let entries = [
+            FileEntry {
+                length: 5,
+                path: vec!["a".to_string()],
+            },
+            FileEntry {
+                length: 2,
+                path: vec!["nested".to_string(), "b".to_string()],
+            },
+            FileEntry {
+                length: 5,
+                path: vec!["c".to_string()],
+            },
+        ];
+        let mut storage = MultiFileStorage::open(dir.path(), &entries, 8).unwrap();
+        storage
+            .write_block(0, 0, &[1, 2, 3, 4, 5, 6, 7, 8])
+            .unwrap();
+        storage.write_block(1, 0, &[9, 10, 11, 12]).unwrap();
+        assert!(storage.write_block(1, 2, &[0; 4]).is_err());
+        storage.sync().unwrap();
+
+        let read = |path: &str| std::fs::read(dir.path().join(path)).unwrap();
+        assert_eq!(read("a"), [1, 2, 3, 4, 5]);
+        assert_eq!(read("nested/b"), [6, 7]);
+        assert_eq!(read("c"), [8, 9, 10, 11, 12]);
+        assert_eq!(storage.read_block(0, 4, 5).unwrap(), [5, 6, 7, 8, 9]);
+    }
+
+    fn info(payload: &[u8]) -> Info {
+        Info {
+            length: payload.len(),
+            name: "payload".to_string(),
+            piece_length: 4,
+            pieces: payload
+                .chunks(4)
+                .map(|piece| Sha1::digest(piece).into())
+                .collect(),
+            files: vec![],
+            private: false,
+        }
+    }
+
+    #[test]
+    fn renames_the_partial_download_when_finished() {
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("payload");
+        let partial = dir.path().join("payload.part");
+        assert_eq!(working_path(&out, &partial), partial);
+
+        std::fs::write(&partial, [1; 4]).unwrap();
+        finish(&partial, &out, &info(&[1; 4])).unwrap();
+        assert!(!partial.exists());
+        assert_eq!(std::fs::read(&out).unwrap(), [1; 4]);
+
+        // Once finished, the output itself is rechecked and seeded.
+        assert_eq!(working_path(&out, &partial), out);
+        finish(&out, &out, &info(&[1; 4])).unwrap();
+        assert!(out.exists());
+    }
+
+    /// Counts syncs instead of making them.
+    #[derive(Default)]
+    struct CountingStorage(std::rc::Rc<std::cell::Cell<usize>>);
+
+    impl Storage for CountingStorage {
+        fn write_block(&mut self, _: usize, _: usize, _: &[u8]) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn read_block(&mut self, _: usize, _: usize, length: usize) -> io::Result<Vec<u8>> {
+            Ok(vec![0; length])
+        }
+
+        fn sync(&mut self) -> io::Result<()> {
+            self.0.set(self.0.get() + 1);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn syncs_according_to_the_flush_policy() {
+        assert_eq!("piece".parse(), Ok(FlushPolicy::Piece));
+        assert_eq!(
+            "30".parse(),
+            Ok(FlushPolicy::Periodic(Duration::from_secs(30)))
+        );
+        assert!("0".parse::<FlushPolicy>().is_err());
+        assert!("sometimes".parse::<FlushPolicy>().is_err());
+
+        let syncs = |policy: FlushPolicy| {
+            let inner = CountingStorage::default();
+            let count = inner.0.clone();
+            let mut storage = FlushingStorage::new(Box::new(inner), policy);
+            for piece_index in 0..2 {
+                storage.write_block(piece_index, 0, &[0; 4]).unwrap();
+                storage.write_block(piece_index, 4, &[0; 4]).unwrap();
+                storage.piece_written(piece_index).unwrap();
+            }
+            count.get()
+        };
+        assert_eq!(syncs(FlushPolicy::Block), 4);
+        assert_eq!(syncs(FlushPolicy::Piece), 2);
+        assert_eq!(syncs(FlushPolicy::Periodic(Duration::ZERO)), 4);
+        assert_eq!(syncs(FlushPolicy::Periodic(Duration::from_secs(60))), 0);
+        assert_eq!(syncs(FlushPolicy::Completion), 0);
+    }
+
+    #[test]
+    fn keeps_torrent_paths_inside_the_output_directory() {
+        let path = |components: &[&str]| {
+            safe_relative_path(&FileEntry {
+                length: 0,
+                path: components.iter().map(|c| c.to_string()).collect(),
+            })
+        };
+        assert_eq!(path(&["dir", "file.txt"]), Path::new("dir/file.txt"));
+        assert_eq!(
+            path(&["..", "..", "etc", "passwd"]),
+            Path::new("_/_/etc/passwd")
+        );
+        assert_eq!(path(&["/etc/passwd"]), Path::new("_etc_passwd"));
+        assert_eq!(path(&["C:", "evil"]), Path::new("C_/evil"));
+        assert_eq!(path(&["a\\..\\b"]), Path::new("a_.._b"));
+        assert_eq!(path(&[".", "", "ok"]), Path::new("ok"));
+        assert_eq!(path(&["... "]), Path::new("_"));
+        assert_eq!(path(&[]), Path::new("_"));
+        assert_eq!(path(&["con.txt", "Lpt1"]), Path::new("_con.txt/_Lpt1"));
+        assert_eq!(path(&["console"]), Path::new("console"));
+
+        let long = format!("{}.mkv", "é".repeat(200));
+        let shortened = path(&[&long]);
+        let name = shortened.to_str().unwrap();
+        assert!(name.len() <= 255);
+        assert!(name.ends_with("é.mkv"));
+    }
+
+    #[test]
+    fn copies_and_verifies_across_filesystems() {
+        let dir = tempfile::tempdir().unwrap();
+        let working = dir.path().join("incomplete").join("payload");
+        let out = dir.path().join("payload");
+        std::fs::create_dir(dir.path().join("incomplete")).unwrap();
+        std::fs::write(&working, [1, 2, 3, 4, 5, 6]).unwrap();
+
+        // A copy that does not match the torrent is never put in place.
+        assert!(copy_and_verify(&working, &out, &info(&[0; 6])).is_err());
+        assert!(!out.exists());
+        assert!(working.exists());
+
+        copy_and_verify(&working, &out, &info(&[1, 2, 3, 4, 5, 6])).unwrap();
+        assert_eq!(std::fs::read(&out).unwrap(), [1, 2, 3, 4, 5, 6]);
+        assert!(!working.exists());
+        assert!(!dir.path().join("payload.part").exists());
+    }
+}
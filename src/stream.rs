@@ -0,0 +1,141 @@
+use std::{
+    collections::BTreeMap,
+    io::{self, Write},
+    sync::mpsc::{self, Receiver, Sender},
+};
+
+use crate::{
+    executor::{self, Task},
+    shutdown::Shutdown,
+};
+
+/// A piece that passed verification, along with where it belongs in the torrent's content.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifiedPiece {
+    pub index: usize,
+    pub offset: u64,
+    pub data: Vec<u8>,
+}
+
+/// Hands verified pieces to a consumer strictly in order, holding back any that complete ahead
+/// of a missing one. With a non-sequential picker that can mean buffering a lot of the torrent.
+pub struct PieceStream {
+    next: usize,
+    held_back: BTreeMap<usize, VerifiedPiece>,
+    sender: Sender<VerifiedPiece>,
+}
+
+impl PieceStream {
+    pub fn new() -> (Self, Receiver<VerifiedPiece>) {
+        let (sender, receiver) = mpsc::channel();
+        let stream = Self {
+            next: 0,
+            held_back: BTreeMap::new(),
+            sender,
+        };
+
+        (stream, receiver)
+    }
+
+    /// Sends this piece and any held back behind it that are now next in line. Returns false
+    /// once the consumer has hung up.
+    pub fn push(&mut self, piece: VerifiedPiece) -> bool {
+        if piece.index < self.next {
+            return true;
+        }
+        self.held_back.insert(piece.index, piece);
+
+        while let Some(piece) = self.held_back.remove(&self.next) {
+            if self.sender.send(piece).is_err() {
+                self.held_back.clear();
+                return false;
+            }
+            self.next += 1;
+        }
+
+        true
+    }
+}
+
+/// Writes streamed pieces to `writer` as they arrive, handing it back once the stream ends. If
+/// a write fails, say because whoever was reading a pipe went away, the download is shut down.
+pub fn write_pieces<W: Write + Send + 'static>(
+    receiver: Receiver<VerifiedPiece>,
+    mut writer: W,
+    shutdown: Shutdown,
+) -> Task<io::Result<W>> {
+    executor::spawn("stream writer", move || {
+        for piece in receiver {
+            if let Err(error) = writer.write_all(&piece.data).and_then(|()| writer.flush()) {
+                shutdown.request();
+                return Err(error);
+            }
+        }
+        Ok(writer)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Write};
+
+    use super::{write_pieces, PieceStream, VerifiedPiece};
+    use crate::shutdown::Shutdown;
+
+    fn piece(index: usize) -> VerifiedPiece {
+        VerifiedPiece {
+            index,
+            offset: index as u64 * 4,
+            data: vec![index as u8; 4],
+        }
+    }
+
+    #[test]
+    fn delivers_pieces_in_order() {
+        let (mut stream, receiver) = PieceStream::new();
+        stream.push(piece(1));
+        stream.push(piece(2));
+        assert!(receiver.try_recv().is_err());
+
+        stream.push(piece(0));
+        stream.push(piece(3));
+        let indices = receiver
+            .try_iter()
+            .map(|piece| piece.index)
+            .collect::<Vec<_>>();
+        assert_eq!(indices, vec![0, 1, 2, 3]);
+
+        drop(receiver);
+        assert!(!stream.push(piece(4)));
+    }
+
+    struct BrokenPipe;
+
+    impl Write for BrokenPipe {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::BrokenPipe.into())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn writes_pieces_until_the_stream_ends() {
+        let (mut stream, receiver) = PieceStream::new();
+        let writer = write_pieces(receiver, Vec::new(), Shutdown::new());
+        stream.push(piece(1));
+        stream.push(piece(0));
+        drop(stream);
+        assert_eq!(writer.join().unwrap().unwrap(), [0, 0, 0, 0, 1, 1, 1, 1]);
+
+        let (mut stream, receiver) = PieceStream::new();
+        let shutdown = Shutdown::new();
+        let writer = write_pieces(receiver, BrokenPipe, shutdown.clone());
+        stream.push(piece(0));
+        assert!(writer.join().unwrap().is_err());
+        assert!(shutdown.is_requested());
+        assert!(!stream.push(piece(1)));
+    }
+}
@@ -0,0 +1,241 @@
+//! Estimates how big a swarm is from the DHT alone (BEP 5), without downloading anything or
+//! contacting a single peer. It is a wide `get_peers` lookup: starting from the bootstrap
+//! nodes, it keeps asking the nodes closest to the info hash, collecting every peer they hold,
+//! until the closest it knows of have all been asked. Nodes store peers for a while after they
+//! announce, so the count includes some that have since left.
+
+use std::{
+    collections::{BTreeSet, HashSet},
+    net::SocketAddr,
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    krpc::{self, Query},
+    log,
+};
+
+/// How far a crawl goes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CrawlLimits {
+    /// How many of the nodes closest to the info hash must have been asked before stopping.
+    pub width: usize,
+    /// Stop after asking this many nodes, however close the crawl has got.
+    pub max_queries: usize,
+    /// How many nodes are asked at once.
+    pub parallel: usize,
+    /// How long to wait for each node.
+    pub timeout: Duration,
+}
+
+impl Default for CrawlLimits {
+    fn default() -> Self {
+        Self {
+            width: 32,
+            max_queries: 500,
+            parallel: 8,
+            timeout: Duration::from_secs(2),
+        }
+    }
+}
+
+/// What a crawl found.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Crawl {
+    /// Every distinct peer any node gave.
+    pub peers: BTreeSet<SocketAddr>,
+    /// The nodes that gave peers, and so store the info hash.
+    pub storing: BTreeSet<SocketAddr>,
+    pub queried: usize,
+    pub answered: usize,
+    pub elapsed: Duration,
+}
+
+/// Asks the DHT for peers of `info_hash`, starting from `bootstrap`, within `limits`.
+pub fn crawl(info_hash: [u8; 20], bootstrap: &[SocketAddr], limits: CrawlLimits) -> Crawl {
+    let started = Instant::now();
+    let mut crawl = Crawl::default();
+    // Nodes that answered or have yet to be asked, closest to the info hash first.
+    let mut known = BTreeSet::<([u8; 20], SocketAddr)>::new();
+    let mut asked = HashSet::<SocketAddr>::new();
+    let mut unasked_bootstrap = bootstrap.to_vec();
+
+    while crawl.queried < limits.max_queries {
+        let room = limits
+            .parallel
+            .min(limits.max_queries - crawl.queried)
+            .max(1);
+        let batch = if unasked_bootstrap.is_empty() {
+            known
+                .iter()
+                .take(limits.width)
+                .map(|&(_, addr)| addr)
+                .filter(|addr| !asked.contains(addr))
+                .take(room)
+                .collect::<Vec<_>>()
+        } else {
+            let split = unasked_bootstrap.len().saturating_sub(room);
+            unasked_bootstrap.split_off(split)
+        };
+        if batch.is_empty() {
+            break;
+        }
+
+        asked.extend(&batch);
+        crawl.queried += batch.len();
+        let answers = thread::scope(|scope| {
+            let queries = batch
+                .iter()
+                .map(|&addr| {
+                    scope.spawn(move || {
+                        (addr, krpc::query(addr, Query::GetPeers(info_hash), limits.timeout))
+                    })
+                })
+                .collect::<Vec<_>>();
+            queries
+                .into_iter()
+                .map(|query| query.join().expect("DHT query panicked"))
+                .collect::<Vec<_>>()
+        });
+
+        for (addr, answer) in answers {
+            let response = match answer {
+                Ok(response) => response,
+                Err(error) => {
+                    log::debug!("{}: {}", addr, error);
+                    known.retain(|&(_, known)| known != addr);
+                    continue;
+                }
+            };
+            crawl.answered += 1;
+            if !response.peers.is_empty() {
+                crawl.storing.insert(addr);
+                crawl.peers.extend(response.peers);
+            }
+            known.insert((distance(&response.id, &info_hash), addr));
+            for (id, node) in response.nodes {
+                if node.port() != 0 && !node.ip().is_unspecified() && !asked.contains(&node) {
+                    known.insert((distance(&id, &info_hash), node));
+                }
+            }
+        }
+    }
+
+    crawl.elapsed = started.elapsed();
+    crawl
+}
+
+/// The XOR distance between two ids, which orders as the DHT measures closeness.
+fn distance(id: &[u8; 20], target: &[u8; 20]) -> [u8; 20] {
+    std::array::from_fn(|index| id[index] ^ target[index])
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::{BTreeSet, HashMap},
+        net::{SocketAddr, UdpSocket},
+        thread,
+        time::Duration,
+    };
+
+    use super::{crawl, CrawlLimits};
+    use crate::bencode::{Bencode, Value};
+
+    /// Answers every `get_peers` on `socket` with `nodes` and `peers`, as the node `id`.
+    fn answer(socket: UdpSocket, id: [u8; 20], nodes: Vec<([u8; 20], SocketAddr)>, peers: &[&str]) {
+        let compact = |addr: SocketAddr| {
+            let SocketAddr::V4(addr) = addr else {
+                unreachable!()
+            };
+            let mut bytes = addr.ip().octets().to_vec();
+            bytes.extend(addr.port().to_be_bytes());
+            bytes
+        };
+        let mut compact_nodes = Vec::new();
+        for (id, addr) in nodes {
+            compact_nodes.extend(id);
+            compact_nodes.extend(compact(addr));
+        }
+        let peers = peers
+            .iter()
+            .map(|peer| compact(peer.parse().unwrap()))
+            .collect::<Vec<_>>();
+        let reply = move |transaction: Vec<u8>| {
+            let mut response = HashMap::from([
+                ("id".to_string(), Value::Blob(id.to_vec())),
+                ("nodes".to_string(), Value::Blob(compact_nodes.clone())),
+            ]);
+            if !peers.is_empty() {
+                let values = peers.iter().cloned().map(Value::Blob).collect();
+                response.insert("values".to_string(), Value::List(values));
+            }
+            Bencode::encode(&Value::Dictionary(HashMap::from([
+                ("t".to_string(), Value::Blob(transaction)),
+                ("y".to_string(), Value::String("r".to_string())),
+                ("r".to_string(), Value::Dictionary(response)),
+            ])))
+        };
+
+        socket
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        thread::spawn(move || {
+            let mut buffer = [0; 1500];
+            while let Ok((length, from)) = socket.recv_from(&mut buffer) {
+                let Ok(Value::Dictionary(query)) = Bencode::new(&buffer[..length]).decode() else {
+                    continue;
+                };
+                let transaction = match &query["t"] {
+                    Value::Blob(blob) => blob.clone(),
+                    Value::String(string) => string.as_bytes().to_vec(),
+                    _ => continue,
+                };
+                socket.send_to(&reply(transaction), from).unwrap();
+            }
+        });
+    }
+
+    #[test]
+    fn collects_peers_from_every_close_node() {
+        let info_hash = [0; 20];
+        let sockets = (0..4)
+            .map(|_| UdpSocket::bind("127.0.0.1:0").unwrap())
+            .collect::<Vec<_>>();
+        let addrs = sockets
+            .iter()
+            .map(|socket| socket.local_addr().unwrap())
+            .collect::<Vec<_>>();
+        let [bootstrap, near, nearer, silent] = sockets.try_into().unwrap();
+
+        // The bootstrap node knows two nodes near the info hash, and one that never answers.
+        answer(
+            bootstrap,
+            [0xff; 20],
+            vec![([1; 20], addrs[1]), ([2; 20], addrs[2]), ([3; 20], addrs[3])],
+            &[],
+        );
+        answer(near, [2; 20], vec![([1; 20], addrs[2])], &["10.0.0.1:6881", "10.0.0.2:6881"]);
+        answer(nearer, [1; 20], vec![], &["10.0.0.2:6881", "10.0.0.3:51413"]);
+        drop(silent);
+
+        let limits = CrawlLimits {
+            timeout: Duration::from_millis(500),
+            ..CrawlLimits::default()
+        };
+        let found = crawl(info_hash, &[addrs[0]], limits);
+        let peers = ["10.0.0.1:6881", "10.0.0.2:6881", "10.0.0.3:51413"]
+            .map(|peer| peer.parse().unwrap());
+        assert_eq!(found.peers, BTreeSet::from(peers));
+        assert_eq!(found.storing, BTreeSet::from([addrs[1], addrs[2]]));
+        assert_eq!((found.queried, found.answered), (4, 3));
+
+        // A crawl asks no more nodes than it is allowed.
+        let limits = CrawlLimits {
+            max_queries: 2,
+            ..limits
+        };
+        assert_eq!(crawl(info_hash, &[addrs[0]], limits).queried, 2);
+    }
+}
@@ -0,0 +1,193 @@
+use std::{
+    collections::BTreeMap,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+/// Timings for a download: how long each piece took, who served each block and how deep our
+/// queues were along the way. Times are milliseconds since the download started.
+#[derive(Debug, Serialize)]
+pub struct Telemetry {
+    #[serde(skip)]
+    started: Instant,
+    pieces: BTreeMap<usize, PieceTiming>,
+    queue_depths: Vec<QueueSample>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PieceTiming {
+    /// The peer address or web seed URL the piece was fetched from.
+    pub source: String,
+    /// How many times we started downloading this piece.
+    pub attempts: u32,
+    pub started_ms: u64,
+    pub downloaded_ms: Option<u64>,
+    pub verified_ms: Option<u64>,
+    pub valid: Option<bool>,
+    pub blocks: Vec<BlockTiming>,
+}
+
+impl PieceTiming {
+    /// Time from starting the piece to it passing or failing verification.
+    pub fn total(&self) -> Option<Duration> {
+        Some(Duration::from_millis(self.verified_ms? - self.started_ms))
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BlockTiming {
+    pub begin: u32,
+    pub length: u32,
+    pub source: String,
+    pub latency_ms: u64,
+}
+
+/// A snapshot of how much work was queued at one moment.
+#[derive(Debug, Clone, Serialize)]
+pub struct QueueSample {
+    pub at_ms: u64,
+    pub outstanding_requests: usize,
+    pub verifying: usize,
+    pub busy_web_seeds: usize,
+    pub piece_buffers: usize,
+}
+
+impl Default for Telemetry {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            pieces: BTreeMap::new(),
+            queue_depths: Vec::new(),
+        }
+    }
+}
+
+impl Telemetry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn now_ms(&self) -> u64 {
+        self.started.elapsed().as_millis() as u64
+    }
+
+    pub fn piece_started(&mut self, index: usize, source: String) {
+        let now = self.now_ms();
+        let attempts = self.pieces.get(&index).map_or(0, |piece| piece.attempts);
+        self.pieces.insert(
+            index,
+            PieceTiming {
+                source,
+                attempts: attempts + 1,
+                started_ms: now,
+                downloaded_ms: None,
+                verified_ms: None,
+                valid: None,
+                blocks: Vec::new(),
+            },
+        );
+    }
+
+    pub fn block_received(
+        &mut self,
+        index: usize,
+        begin: u32,
+        length: u32,
+        source: String,
+        latency: Duration,
+    ) {
+        if let Some(piece) = self.pieces.get_mut(&index) {
+            piece.blocks.push(BlockTiming {
+                begin,
+                length,
+                source,
+                latency_ms: latency.as_millis() as u64,
+            });
+        }
+    }
+
+    pub fn piece_downloaded(&mut self, index: usize) {
+        let now = self.now_ms();
+        if let Some(piece) = self.pieces.get_mut(&index) {
+            piece.downloaded_ms = Some(now);
+        }
+    }
+
+    pub fn piece_verified(&mut self, index: usize, valid: bool) {
+        let now = self.now_ms();
+        if let Some(piece) = self.pieces.get_mut(&index) {
+            piece.verified_ms = Some(now);
+            piece.valid = Some(valid);
+        }
+    }
+
+    pub fn sample_queues(
+        &mut self,
+        outstanding_requests: usize,
+        verifying: usize,
+        busy_web_seeds: usize,
+        piece_buffers: usize,
+    ) {
+        let sample = QueueSample {
+            at_ms: self.now_ms(),
+            outstanding_requests,
+            verifying,
+            busy_web_seeds,
+            piece_buffers,
+        };
+        self.queue_depths.push(sample);
+    }
+
+    /// Finished pieces, slowest first.
+    pub fn slowest_pieces(&self) -> Vec<(usize, Duration)> {
+        let mut pieces = self
+            .pieces
+            .iter()
+            .filter_map(|(index, piece)| Some((*index, piece.total()?)))
+            .collect::<Vec<_>>();
+        pieces.sort_by(|(_, a), (_, b)| b.cmp(a));
+        pieces
+    }
+
+    pub fn queue_depths(&self) -> &[QueueSample] {
+        &self.queue_depths
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("Failed to serialize telemetry")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::Telemetry;
+
+    #[test]
+    fn records_piece_and_block_timings() {
+        let mut telemetry = Telemetry::new();
+        telemetry.piece_started(3, "127.0.0.1:6881".to_string());
+        telemetry.block_received(
+            3,
+            0,
+            16384,
+            "127.0.0.1:6881".to_string(),
+            Duration::from_millis(12),
+        );
+        telemetry.piece_downloaded(3);
+        telemetry.piece_verified(3, true);
+        telemetry.sample_queues(1, 0, 0, 1);
+
+        let piece = &telemetry.pieces[&3];
+        assert_eq!(piece.attempts, 1);
+        assert_eq!(piece.blocks[0].latency_ms, 12);
+        assert_eq!(piece.valid, Some(true));
+        assert_eq!(telemetry.slowest_pieces()[0].0, 3);
+        assert_eq!(telemetry.queue_depths().len(), 1);
+
+        let json: serde_json::Value = serde_json::from_str(&telemetry.to_json()).unwrap();
+        assert_eq!(json["pieces"]["3"]["blocks"][0]["length"], 16384);
+    }
+}
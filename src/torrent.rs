@@ -2,102 +2,341 @@ use serde::Serialize;
 use sha1::Digest;
 use std::{
     collections::HashMap,
-    fs::File,
-    io::Read,
-    net::{Ipv4Addr, SocketAddrV4},
+    fs, io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    ops::Range,
     path::Path,
 };
 
-use crate::bencode::{Bencode, Value};
+use crate::{
+    bencode::{Bencode, BencodeError, Value},
+    http::HttpError,
+};
+#[cfg(not(target_arch = "wasm32"))]
+use crate::{
+    http::{self, HttpRequest},
+    log,
+};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Torrent {
     pub announce: String,
+    /// Tiers of backup trackers (BEP 12), which usually repeat `announce`.
+    pub announce_list: Vec<Vec<String>>,
+    /// Web seed URLs (BEP 19).
+    pub url_list: Vec<String>,
     pub info: Info,
+    // Hashed from the info dictionary as it was given to us, which may hold keys `Info` drops.
+    info_hash: [u8; 20],
 }
 
 impl Torrent {
-    pub fn open<P: AsRef<Path>>(path: P) -> Self {
-        let mut file = File::open(path).expect("Failed to open torrent file");
-        let mut buf: Vec<u8> = Vec::new();
-        file.read_to_end(&mut buf)
-            .expect("Failed to read torrent file");
-
-        let decoded = Bencode::new(&buf).decode();
-        let decoded_hash_map = match decoded {
+    pub fn new(announce: String, url_list: Vec<String>, info: Info, info_hash: [u8; 20]) -> Self {
+        Self {
+            announce,
+            announce_list: vec![],
+            url_list,
+            info,
+            info_hash,
+        }
+    }
+
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, TorrentError> {
+        Self::from_bytes(&fs::read(path)?)
+    }
+
+    /// Reads the contents of a `.torrent` file, such as one fetched from a feed.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, TorrentError> {
+        let decoded = Bencode::new(bytes).decode()?;
+        let mut decoded_hash_map = match decoded {
             Value::Dictionary(hash_map) => hash_map,
-            _ => panic!("Expected torrent file to decode to a dictionary"),
+            _ => return Err(TorrentError::Invalid("not a dictionary")),
         };
 
         let announce = match decoded_hash_map.get("announce") {
             Some(Value::String(string)) => string.clone(),
-            _ => panic!("Decoded torrent file did not contain an announce string"),
+            _ => return Err(TorrentError::Invalid("no announce URL")),
+        };
+
+        let info = decoded_hash_map
+            .remove("info")
+            .ok_or(TorrentError::Invalid("no info dictionary"))?;
+        let info_hash = sha1::Sha1::digest(Bencode::encode(&info)).into();
+        let info_hash_map = match &info {
+            Value::Dictionary(hash_map) => hash_map,
+            _ => return Err(TorrentError::Invalid("no info dictionary")),
+        };
+
+        let strings = |list: &[Value]| {
+            list.iter()
+                .filter_map(|url| match url {
+                    Value::String(url) => Some(url.clone()),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+        let announce_list = match decoded_hash_map.get("announce-list") {
+            Some(Value::List(tiers)) => tiers
+                .iter()
+                .filter_map(|tier| match tier {
+                    Value::List(tier) => Some(strings(tier)),
+                    _ => None,
+                })
+                .collect(),
+            _ => vec![],
         };
 
-        let info_hash_map = match decoded_hash_map.get("info") {
-            Some(Value::Dictionary(hash_map)) => hash_map,
-            _ => panic!("Decoded torrent file did not contain an info dictionary"),
+        let url_list = match decoded_hash_map.get("url-list") {
+            Some(Value::String(url)) => vec![url.clone()],
+            Some(Value::List(urls)) => strings(urls),
+            _ => vec![],
         };
 
-        let info: Info = info_hash_map.into();
+        let info = Info::try_from(info_hash_map)?;
 
-        Self { announce, info }
+        Ok(Self {
+            announce,
+            announce_list,
+            url_list,
+            info,
+            info_hash,
+        })
+    }
+
+    /// Every tracker the torrent lists, `announce` first, without repeats.
+    pub fn trackers(&self) -> Vec<&str> {
+        let mut trackers = vec![self.announce.as_str()];
+        for url in self.announce_list.iter().flatten() {
+            if !trackers.contains(&url.as_str()) {
+                trackers.push(url);
+            }
+        }
+        trackers.retain(|url| !url.is_empty());
+        trackers
+    }
+
+    pub fn info_hash_bytes(&self) -> [u8; 20] {
+        self.info_hash
     }
 
     pub fn info_hash(&self) -> String {
-        let info_hash_map = (&self.info).into();
-        let encoded = Bencode::encode(&Value::Dictionary(info_hash_map));
+        hex::encode(self.info_hash)
+    }
+}
 
-        let mut hasher = sha1::Sha1::new();
-        hasher.update(&encoded);
-        hex::encode(hasher.finalize())
+// Announcing needs the network, which a wasm32 build does without.
+#[cfg(not(target_arch = "wasm32"))]
+impl Torrent {
+    pub fn get_peers(
+        &self,
+        peer_id: &[u8; 20],
+        port: u16,
+    ) -> Result<Vec<SocketAddr>, TrackerError> {
+        let response = self.send_announce(peer_id, port, self.info.length, None)?;
+        peers_from_response(&response)
     }
 
-    pub fn get_peers(&self) -> Vec<SocketAddrV4> {
-        let client = reqwest::blocking::Client::new();
+    /// Announces to `url`, which need not be one of the torrent's trackers, asking for up to
+    /// `numwant` peers, and returns everything the tracker said.
+    pub fn query_tracker(
+        &self,
+        url: &str,
+        peer_id: &[u8; 20],
+        port: u16,
+        numwant: Option<u32>,
+    ) -> Result<TrackerResponse, TrackerError> {
+        let mut request = Request::new(peer_id, port, self.info.length);
+        request.numwant = numwant;
+        let response = send_request(url, &self.info_hash(), request)?;
+        TrackerResponse::try_from(&response)
+    }
 
-        let request = Request::new("00000000000000000000".to_string(), 6881, self.info.length);
+    /// Tells the tracker we have finished downloading and are now a seed.
+    pub fn announce_completed(&self, peer_id: &[u8; 20], port: u16) -> Result<(), TrackerError> {
+        self.send_announce(peer_id, port, 0, Some("completed"))
+            .map(drop)
+    }
 
-        let mut encoded_info_hash = String::new();
-        for chunk in self.info_hash().as_bytes().chunks(2) {
-            let chunk_str = format!("%{}{}", chunk[0] as char, chunk[1] as char);
-            encoded_info_hash.push_str(&chunk_str);
-        }
+    /// Tells the tracker we are joining the swarm with every piece, to seed.
+    pub fn announce_seeding(&self, peer_id: &[u8; 20], port: u16) -> Result<(), TrackerError> {
+        self.send_announce(peer_id, port, 0, Some("started"))
+            .map(drop)
+    }
 
-        let encoded = serde_urlencoded::to_string(request);
+    /// Tells the tracker we are leaving the swarm so it stops handing us out as a peer.
+    pub fn announce_stopped(
+        &self,
+        peer_id: &[u8; 20],
+        port: u16,
+        left: usize,
+    ) -> Result<(), TrackerError> {
+        self.send_announce(peer_id, port, left, Some("stopped"))
+            .map(drop)
+    }
 
-        let url = format!(
-            "{}?info_hash={}&{}",
-            self.announce,
-            encoded_info_hash,
-            encoded.unwrap()
-        );
+    fn send_announce(
+        &self,
+        peer_id: &[u8; 20],
+        port: u16,
+        left: usize,
+        event: Option<&'static str>,
+    ) -> Result<HashMap<String, Value>, TrackerError> {
+        announce(
+            &self.announce,
+            &self.info_hash(),
+            peer_id,
+            port,
+            left,
+            event,
+        )
+    }
+}
 
-        let response = client.get(url).send().expect("Failed to send request");
+/// Announces us, as `peer_id`, to the tracker at `url` for the torrent with the hex
+/// `info_hash`, with `left` bytes still to download.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn announce(
+    url: &str,
+    info_hash: &str,
+    peer_id: &[u8; 20],
+    port: u16,
+    left: usize,
+    event: Option<&'static str>,
+) -> Result<HashMap<String, Value>, TrackerError> {
+    let mut request = Request::new(peer_id, port, left);
+    request.event = event;
+    send_request(url, info_hash, request)
+}
 
-        let decoded = Bencode::new(&response.bytes().expect("Failed to read response")).decode();
-        let decoded_hash_map = match decoded {
-            Value::Dictionary(hash_map) => hash_map,
-            _ => panic!("Expected tracker response to decode to a dictionary"),
-        };
+/// The URL `announce` requests to announce to the tracker at `url`.
+pub fn announce_url(
+    url: &str,
+    info_hash: &str,
+    peer_id: &[u8; 20],
+    port: u16,
+    left: usize,
+    event: Option<&'static str>,
+) -> String {
+    let mut request = Request::new(peer_id, port, left);
+    request.event = event;
+    request_url(url, info_hash, request)
+}
 
-        let peers = match decoded_hash_map.get("peers") {
-            Some(Value::Blob(blob)) => blob,
-            _ => panic!("Decoded tracker response did not contain a peers blob"),
-        };
+fn request_url(url: &str, info_hash: &str, request: Request) -> String {
+    let mut encoded_info_hash = String::new();
+    for chunk in info_hash.as_bytes().chunks(2) {
+        let chunk_str = format!("%{}{}", chunk[0] as char, chunk[1] as char);
+        encoded_info_hash.push_str(&chunk_str);
+    }
 
-        peers
-            .chunks_exact(6)
-            .map(|chunk| {
-                let mut array = [0; 6];
-                array.copy_from_slice(chunk);
-                let ip = Ipv4Addr::new(array[0], array[1], array[2], array[3]);
-                let port = u16::from_be_bytes([array[4], array[5]]);
-                println!("{}:{}", ip, port);
-                SocketAddrV4::new(ip, port)
-            })
-            .collect()
+    let encoded =
+        serde_urlencoded::to_string(request).expect("Announce parameters are always encodable");
+
+    format!("{}?info_hash={}&{}", url, encoded_info_hash, encoded)
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn send_request(
+    url: &str,
+    info_hash: &str,
+    request: Request,
+) -> Result<HashMap<String, Value>, TrackerError> {
+    let span = log::span!("announce", tracker = url);
+    let _span = span.enter();
+    log::debug!("announcing, event {}", request.event.unwrap_or("none"));
+    let url = request_url(url, info_hash, request);
+    let response = http::fetcher().get(&HttpRequest::get(url))?;
+    log::debug!("tracker answered with status {}", response.status);
+    let response = response.error_for_status()?;
+
+    let Ok(Value::Dictionary(hash_map)) = Bencode::new(&response.body).decode() else {
+        return Err(TrackerError::Malformed);
+    };
+    if let Some(Value::String(reason)) = hash_map.get("failure reason") {
+        return Err(TrackerError::Failure(reason.clone()));
     }
+    Ok(hash_map)
+}
+
+/// The peers in a tracker's announce response, IPv4 and IPv6 alike.
+pub fn peers_from_response(
+    response: &HashMap<String, Value>,
+) -> Result<Vec<SocketAddr>, TrackerError> {
+    // Compact peers that happen to be valid UTF-8, or that are empty, decode as strings.
+    let bytes = |key: &str| match response.get(key) {
+        Some(Value::Blob(blob)) => Some(blob.as_slice()),
+        Some(Value::String(string)) => Some(string.as_bytes()),
+        _ => None,
+    };
+    let peers = bytes("peers");
+    // IPv6 peers come in a separate list (BEP 7).
+    let peers6 = bytes("peers6");
+    if peers.is_none() && peers6.is_none() {
+        return Err(TrackerError::Malformed);
+    }
+
+    let v4 = peers.into_iter().flat_map(|peers| {
+        peers.chunks_exact(6).map(|chunk| {
+            let mut array = [0; 6];
+            array.copy_from_slice(chunk);
+            let ip = Ipv4Addr::new(array[0], array[1], array[2], array[3]);
+            let port = u16::from_be_bytes([array[4], array[5]]);
+            SocketAddr::from((ip, port))
+        })
+    });
+    let v6 = peers6.into_iter().flat_map(|peers| {
+        peers.chunks_exact(18).map(|chunk| {
+            let mut octets = [0; 16];
+            octets.copy_from_slice(&chunk[..16]);
+            let ip = Ipv6Addr::from(octets);
+            let port = u16::from_be_bytes([chunk[16], chunk[17]]);
+            SocketAddr::from((ip, port))
+        })
+    });
+
+    Ok(v4.chain(v6).collect())
+}
+
+/// A tracker's answer to an announce: its peers, and what it knows of the swarm.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrackerResponse {
+    pub peers: Vec<SocketAddr>,
+    /// Seconds the tracker wants us to wait before announcing again.
+    pub interval: Option<u64>,
+    pub seeders: Option<u64>,
+    pub leechers: Option<u64>,
+}
+
+impl TryFrom<&HashMap<String, Value>> for TrackerResponse {
+    type Error = TrackerError;
+
+    fn try_from(response: &HashMap<String, Value>) -> Result<Self, Self::Error> {
+        let count = |key: &str| match response.get(key) {
+            Some(Value::Number(count)) => u64::try_from(*count).ok(),
+            _ => None,
+        };
+        Ok(Self {
+            peers: peers_from_response(response)?,
+            interval: count("interval"),
+            seeders: count("complete"),
+            leechers: count("incomplete"),
+        })
+    }
+}
+
+/// Why an announce got us nothing we could use.
+#[derive(Debug, thiserror::Error)]
+pub enum TrackerError {
+    #[error("no tracker to announce to")]
+    NoTracker,
+    #[error("announce failed: {0}")]
+    Http(#[from] HttpError),
+    #[error("tracker refused: {0}")]
+    Failure(String),
+    #[error("tracker sent a malformed response")]
+    Malformed,
+    #[error("tracker returned no peers")]
+    NoPeers,
 }
 
 #[derive(Debug, Serialize)]
@@ -108,49 +347,161 @@ struct Request {
     downloaded: usize,
     left: usize,
     compact: u8,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    event: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    numwant: Option<u32>,
 }
 
 impl Request {
-    fn new(peer_id: String, port: u16, left: usize) -> Self {
+    fn new(peer_id: &[u8; 20], port: u16, left: usize) -> Self {
         Self {
-            peer_id,
+            peer_id: String::from_utf8_lossy(peer_id).into_owned(),
             port,
             uploaded: 0,
             downloaded: 0,
             left,
             compact: 1,
+            event: None,
+            numwant: None,
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Info {
+    /// The length of the whole content, summed across files for a multi-file torrent.
     pub length: usize,
+    /// The file name, or the directory name for a multi-file torrent.
     pub name: String,
     pub piece_length: usize,
     pub pieces: Vec<[u8; 20]>,
+    /// The files making up a multi-file torrent, empty for a single file.
+    pub files: Vec<FileEntry>,
+    /// Private torrents (BEP 27) only get peers from their trackers.
+    pub private: bool,
 }
 
-impl From<&HashMap<String, Value>> for Info {
-    fn from(value: &HashMap<String, Value>) -> Self {
-        let length = match value.get("length") {
-            Some(Value::Number(number)) => *number as usize,
-            _ => panic!("Decoded info dictionary did not contain a length number"),
+/// One file in a multi-file torrent. Files are laid out back to back in the order listed, so
+/// pieces and even blocks can span several of them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileEntry {
+    pub length: usize,
+    /// Path components below the torrent's directory.
+    pub path: Vec<String>,
+}
+
+/// Where a file lies in a torrent's content, as if its files were laid end to end.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileSpan {
+    /// The file's path within the torrent, with `/` between components.
+    pub path: String,
+    pub offset: u64,
+    pub length: u64,
+}
+
+impl FileSpan {
+    /// The pieces holding any of the file, empty for an empty file.
+    pub fn pieces(&self, piece_length: usize) -> Range<usize> {
+        if self.length == 0 {
+            return 0..0;
+        }
+        let piece_length = piece_length as u64;
+        let first = self.offset / piece_length;
+        let last = (self.offset + self.length - 1) / piece_length;
+        first as usize..last as usize + 1
+    }
+}
+
+impl Info {
+    /// Each file of the torrent, or the one file of a single-file torrent, in content order.
+    pub fn file_spans(&self) -> Vec<FileSpan> {
+        if self.files.is_empty() {
+            return vec![FileSpan {
+                path: self.name.clone(),
+                offset: 0,
+                length: self.length as u64,
+            }];
+        }
+
+        let mut offset = 0;
+        self.files
+            .iter()
+            .map(|file| {
+                let span = FileSpan {
+                    path: file.path.join("/"),
+                    offset,
+                    length: file.length as u64,
+                };
+                offset += file.length as u64;
+                span
+            })
+            .collect()
+    }
+
+    /// Where piece `index` lies in the content. Every piece is `piece_length` long but the last,
+    /// which holds whatever is left.
+    pub fn piece_range(&self, index: usize) -> Range<u64> {
+        let start = (index * self.piece_length) as u64;
+        start..u64::min(start + self.piece_length as u64, self.length as u64)
+    }
+
+    /// The part of each file that piece `index` covers, as byte ranges within the files. Empty
+    /// files are in no piece.
+    pub fn piece_files(&self, index: usize) -> Vec<(String, Range<u64>)> {
+        let piece = self.piece_range(index);
+        self.file_spans()
+            .into_iter()
+            .filter(|file| file.pieces(self.piece_length).contains(&index))
+            .map(|file| {
+                let start = piece.start.max(file.offset) - file.offset;
+                let end = piece.end.min(file.offset + file.length) - file.offset;
+                (file.path, start..end)
+            })
+            .collect()
+    }
+
+    /// The SHA-1 of the dictionary this encodes to. Only the same as the info hash for an info
+    /// dictionary holding nothing `Info` leaves out, such as one we made ourselves.
+    // Only tests build their own info dictionaries so far.
+    #[allow(dead_code)]
+    pub fn hash(&self) -> [u8; 20] {
+        let encoded = Bencode::encode(&Value::Dictionary(self.into()));
+        sha1::Sha1::digest(encoded).into()
+    }
+}
+
+impl TryFrom<&HashMap<String, Value>> for Info {
+    type Error = TorrentError;
+
+    fn try_from(value: &HashMap<String, Value>) -> Result<Self, Self::Error> {
+        let files = match value.get("files") {
+            Some(Value::List(files)) => files
+                .iter()
+                .map(FileEntry::try_from)
+                .collect::<Result<_, _>>()?,
+            _ => vec![],
+        };
+
+        let length = match size(value, "length") {
+            Some(length) => length,
+            None if !files.is_empty() => files.iter().map(|file: &FileEntry| file.length).sum(),
+            None => return Err(TorrentError::Invalid("no length")),
         };
 
         let name = match value.get("name") {
             Some(Value::String(string)) => string.clone(),
-            _ => panic!("Decoded info dictionary did not contain a name string"),
+            _ => return Err(TorrentError::Invalid("no name")),
         };
 
-        let piece_length = match value.get("piece length") {
-            Some(Value::Number(number)) => *number as usize,
-            _ => panic!("Decoded info dictionary did not contain a piece length number"),
+        let piece_length = match size(value, "piece length") {
+            Some(piece_length) if piece_length > 0 => piece_length,
+            _ => return Err(TorrentError::Invalid("no piece length")),
         };
 
         let all_pieces = match value.get("pieces") {
             Some(Value::Blob(blob)) => blob,
-            _ => panic!("Decoded info dictionary did not contain a pieces blob"),
+            _ => return Err(TorrentError::Invalid("no piece hashes")),
         };
 
         let pieces = all_pieces
@@ -162,12 +513,73 @@ impl From<&HashMap<String, Value>> for Info {
             })
             .collect();
 
-        Self {
+        Ok(Self {
             length,
             name,
             piece_length,
             pieces,
-        }
+            files,
+            private: matches!(value.get("private"), Some(Value::Number(1))),
+        })
+    }
+}
+
+impl TryFrom<&Value> for FileEntry {
+    type Error = TorrentError;
+
+    fn try_from(value: &Value) -> Result<Self, Self::Error> {
+        let Value::Dictionary(file) = value else {
+            return Err(TorrentError::Invalid("file entry is not a dictionary"));
+        };
+
+        let length = size(file, "length").ok_or(TorrentError::Invalid("file with no length"))?;
+
+        let path = match file.get("path") {
+            Some(Value::List(components)) => components
+                .iter()
+                .map(|component| match component {
+                    Value::String(component) => Ok(component.clone()),
+                    _ => Err(TorrentError::Invalid("file path is not text")),
+                })
+                .collect::<Result<_, _>>()?,
+            _ => return Err(TorrentError::Invalid("file with no path")),
+        };
+
+        Ok(Self { length, path })
+    }
+}
+
+/// The non-negative number under `key`.
+fn size(dictionary: &HashMap<String, Value>, key: &str) -> Option<usize> {
+    match dictionary.get(key) {
+        Some(Value::Number(number)) => usize::try_from(*number).ok(),
+        _ => None,
+    }
+}
+
+/// Why a torrent file, or an info dictionary fetched from peers, could not be used.
+#[derive(Debug, thiserror::Error)]
+pub enum TorrentError {
+    #[error("cannot read torrent file: {0}")]
+    Read(#[from] io::Error),
+    #[error("not a torrent file: {0}")]
+    Decode(#[from] BencodeError),
+    #[error("invalid torrent: {0}")]
+    Invalid(&'static str),
+}
+
+impl From<&FileEntry> for Value {
+    fn from(value: &FileEntry) -> Self {
+        let path = value
+            .path
+            .iter()
+            .map(|component| Value::String(component.clone()))
+            .collect();
+
+        let mut hash_map = HashMap::new();
+        hash_map.insert("length".to_string(), Value::Number(value.length as i64));
+        hash_map.insert("path".to_string(), Value::List(path));
+        Value::Dictionary(hash_map)
     }
 }
 
@@ -180,14 +592,92 @@ impl From<&Info> for HashMap<String, Value> {
             .collect();
 
         let mut hash_map = HashMap::new();
-        hash_map.insert("length".to_string(), Value::Number(value.length as i64));
+        if value.files.is_empty() {
+            hash_map.insert("length".to_string(), Value::Number(value.length as i64));
+        } else {
+            let files = value.files.iter().map(Value::from).collect();
+            hash_map.insert("files".to_string(), Value::List(files));
+        }
         hash_map.insert("name".to_string(), Value::String(value.name.clone()));
         hash_map.insert(
             "piece length".to_string(),
             Value::Number(value.piece_length as i64),
         );
         hash_map.insert("pieces".to_string(), Value::Blob(pieces));
+        if value.private {
+            hash_map.insert("private".to_string(), Value::Number(1));
+        }
 
         hash_map
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{peers_from_response, FileEntry, Info, TorrentError, TrackerError};
+    use crate::bencode::Value;
+
+    #[test]
+    fn reads_compact_peers_decoded_as_text() {
+        let mut response = HashMap::new();
+        assert!(matches!(
+            peers_from_response(&response),
+            Err(TrackerError::Malformed)
+        ));
+
+        response.insert("peers".to_string(), Value::String(String::new()));
+        assert_eq!(peers_from_response(&response).unwrap(), vec![]);
+
+        let peer = "AAAA\0P".to_string();
+        response.insert("peers".to_string(), Value::String(peer));
+        assert_eq!(
+            peers_from_response(&response).unwrap(),
+            vec!["65.65.65.65:80".parse().unwrap()]
+        );
+    }
+
+    #[test]
+    fn pieces_map_onto_the_files_they_cover() {
+        let info = Info {
+            length: 40,
+            name: "album".to_string(),
+            piece_length: 16,
+            pieces: vec![[0; 20]; 3],
+            files: [("a", 10), ("empty", 0), ("b", 30)]
+                .into_iter()
+                .map(|(name, length)| FileEntry {
+                    length,
+                    path: vec![name.to_string()],
+                })
+                .collect(),
+            private: false,
+        };
+        assert_eq!(info.piece_range(2), 32..40);
+        assert_eq!(
+            info.piece_files(0),
+            vec![("a".to_string(), 0..10), ("b".to_string(), 0..6)]
+        );
+        assert_eq!(info.piece_files(2), vec![("b".to_string(), 22..30)]);
+    }
+
+    #[test]
+    fn rejects_info_dictionaries_missing_fields() {
+        let mut info = HashMap::new();
+        info.insert("length".to_string(), Value::Number(10));
+        info.insert("piece length".to_string(), Value::Number(16));
+        info.insert("pieces".to_string(), Value::Blob(vec![0; 20]));
+        assert!(matches!(
+            Info::try_from(&info),
+            Err(TorrentError::Invalid("no name"))
+        ));
+
+        info.insert("name".to_string(), Value::String("a".to_string()));
+        info.insert("length".to_string(), Value::Number(-1));
+        assert!(matches!(
+            Info::try_from(&info),
+            Err(TorrentError::Invalid("no length"))
+        ));
+    }
+}
@@ -0,0 +1,220 @@
+//! Step-by-step checks of a tracker, for working out why it gives us no peers: whether its host
+//! resolves, whether it accepts connections, and what it answers an announce and a scrape with.
+
+use std::{
+    collections::HashMap,
+    net::{SocketAddr, TcpStream, ToSocketAddrs},
+    time::{Duration, Instant},
+};
+
+use reqwest::Url;
+use serde::Serialize;
+
+use crate::{
+    bencode::{Bencode, Value},
+    http::{self, HttpFetch, HttpRequest},
+    scrape,
+    torrent::{self, TrackerResponse},
+};
+
+/// How far a tracker got through the checks, stopping at the first that failed.
+#[derive(Debug, Serialize)]
+pub struct TrackerCheck {
+    pub url: String,
+    /// The addresses its host resolved to.
+    pub addresses: Vec<String>,
+    pub dns_ms: Option<u128>,
+    pub connect_ms: Option<u128>,
+    pub announce: Option<HttpCheck>,
+    /// Absent for trackers whose announce URL has no scrape counterpart.
+    pub scrape: Option<HttpCheck>,
+    /// Why the checks stopped early.
+    pub error: Option<String>,
+}
+
+/// What a tracker answered one request with.
+#[derive(Debug, Default, Serialize)]
+pub struct HttpCheck {
+    pub status: Option<u16>,
+    pub milliseconds: u128,
+    /// Whether the body decoded as a bencoded dictionary.
+    pub bencode: bool,
+    /// The tracker's failure reason, or what stopped the request.
+    pub failure: Option<String>,
+    pub peers: Option<usize>,
+    pub seeders: Option<u64>,
+    pub leechers: Option<u64>,
+}
+
+impl TrackerCheck {
+    /// Whether the tracker answered the announce without refusing it.
+    pub fn announced(&self) -> bool {
+        self.announce
+            .as_ref()
+            .is_some_and(|announce| announce.bencode && announce.failure.is_none())
+    }
+}
+
+/// Runs every check against the tracker at `url` for the torrent with `info_hash`, announcing as
+/// `peer_id` and waiting up to `timeout` for each step.
+pub fn check(
+    url: &str,
+    info_hash: &[u8; 20],
+    peer_id: &[u8; 20],
+    port: u16,
+    timeout: Duration,
+) -> TrackerCheck {
+    let mut report = TrackerCheck {
+        url: url.to_string(),
+        addresses: Vec::new(),
+        dns_ms: None,
+        connect_ms: None,
+        announce: None,
+        scrape: None,
+        error: None,
+    };
+    let parsed = match Url::parse(url) {
+        Ok(parsed) if matches!(parsed.scheme(), "http" | "https") => parsed,
+        Ok(parsed) => {
+            report.error = Some(format!("{} trackers are not supported", parsed.scheme()));
+            return report;
+        }
+        Err(error) => {
+            report.error = Some(format!("invalid URL: {}", error));
+            return report;
+        }
+    };
+    let (Some(host), Some(host_port)) = (parsed.host_str(), parsed.port_or_known_default()) else {
+        report.error = Some("URL has no host".to_string());
+        return report;
+    };
+
+    let started = Instant::now();
+    let addresses = match (host, host_port).to_socket_addrs() {
+        Ok(addresses) => addresses.collect::<Vec<SocketAddr>>(),
+        Err(error) => {
+            report.error = Some(format!("cannot resolve {}: {}", host, error));
+            return report;
+        }
+    };
+    report.dns_ms = Some(started.elapsed().as_millis());
+    report.addresses = addresses.iter().map(ToString::to_string).collect();
+
+    let started = Instant::now();
+    let mut failure = format!("{} resolved to no addresses", host);
+    for addr in &addresses {
+        match TcpStream::connect_timeout(addr, timeout) {
+            Ok(_) => {
+                report.connect_ms = Some(started.elapsed().as_millis());
+                break;
+            }
+            Err(error) => failure = format!("cannot connect to {}: {}", addr, error),
+        }
+    }
+    if report.connect_ms.is_none() {
+        report.error = Some(failure);
+        return report;
+    }
+
+    let fetcher = http::fetcher();
+    let get = |url: &str| HttpRequest::get(url).timeout(timeout);
+    let info_hash_hex = hex::encode(info_hash);
+    let announce_url = torrent::announce_url(url, &info_hash_hex, peer_id, port, 0, None);
+    let read_peers = |check: &mut HttpCheck, response: &HashMap<String, Value>, _: &[u8]| {
+        match TrackerResponse::try_from(response) {
+            Ok(response) => {
+                check.peers = Some(response.peers.len());
+                check.seeders = response.seeders;
+                check.leechers = response.leechers;
+            }
+            Err(error) => check.failure = Some(error.to_string()),
+        }
+    };
+    report.announce = Some(request(fetcher.as_ref(), get(&announce_url), read_peers));
+    if report.announced() {
+        // Leave the swarm as we found it, rather than listed as a seeder until we time out.
+        let stopped = torrent::announce_url(
+            url,
+            &info_hash_hex,
+            peer_id,
+            port,
+            0,
+            Some("stopped"),
+        );
+        let _ = fetcher.get(&get(&stopped));
+    }
+
+    let read_stats = |check: &mut HttpCheck, _: &HashMap<String, Value>, body: &[u8]| {
+        match scrape::parse_response(body, info_hash) {
+            Ok(stats) => {
+                check.seeders = Some(stats.seeders);
+                check.leechers = Some(stats.leechers);
+            }
+            Err(error) => check.failure = Some(error.to_string()),
+        }
+    };
+    report.scrape = scrape::request_url(url, info_hash)
+        .ok()
+        .map(|scrape_url| request(fetcher.as_ref(), get(&scrape_url), read_stats));
+    report
+}
+
+/// Sends `get`, then has `inspect` fill in what the answer, decoded and raw, says.
+fn request(
+    fetcher: &dyn HttpFetch,
+    get: HttpRequest,
+    inspect: impl FnOnce(&mut HttpCheck, &HashMap<String, Value>, &[u8]),
+) -> HttpCheck {
+    let mut check = HttpCheck::default();
+    let started = Instant::now();
+    let response = fetcher.get(&get);
+    check.milliseconds = started.elapsed().as_millis();
+    let response = match response {
+        Ok(response) => response,
+        Err(error) => {
+            check.failure = Some(error.to_string());
+            return check;
+        }
+    };
+    check.status = Some(response.status);
+    let body = response.body;
+    let Ok(Value::Dictionary(decoded)) = Bencode::new(&body).decode() else {
+        return check;
+    };
+    check.bencode = true;
+    if let Some(Value::String(reason)) = decoded.get("failure reason") {
+        check.failure = Some(reason.clone());
+        return check;
+    }
+    inspect(&mut check, &decoded, &body);
+    check
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::check;
+    use crate::{peer::TEST_PEER_ID, tracker_server};
+
+    #[test]
+    fn reports_each_step_against_a_working_tracker() {
+        let addr = tracker_server::serve("127.0.0.1:0", Duration::from_secs(60)).unwrap();
+        let url = format!("http://{}/announce", addr);
+        let report = check(&url, &[1; 20], &TEST_PEER_ID, 6881, Duration::from_secs(5));
+        assert_eq!(report.error, None);
+        assert_eq!(report.addresses, vec![addr.to_string()]);
+        assert!(report.connect_ms.is_some());
+        assert!(report.announced());
+        let announce = report.announce.unwrap();
+        assert_eq!(announce.status, Some(200));
+        assert_eq!(announce.peers, Some(0));
+        // The stopped announce after the check leaves nobody in the swarm.
+        let scrape = report.scrape.unwrap();
+        assert_eq!((scrape.seeders, scrape.leechers), (Some(0), Some(0)));
+
+        let refused = check("udp://127.0.0.1:1", &[1; 20], &TEST_PEER_ID, 6881, Duration::from_secs(5));
+        assert!(!refused.announced());
+        assert!(refused.error.is_some());
+    }
+}
@@ -0,0 +1,328 @@
+//! A minimal HTTP tracker for trying the client out locally. It answers announces with compact
+//! peer lists (BEP 23) and scrapes (BEP 48) for any torrent, keeping its swarms in memory.
+//! Connections are served within the timeouts and limits of [`http_server`].
+
+use std::{
+    collections::HashMap,
+    io::{self, BufReader, Write},
+    net::{IpAddr, SocketAddr, TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    bencode::{Bencode, Value},
+    http_server, log,
+};
+
+// Peers asking for more than this get this many.
+const MAX_NUMWANT: usize = 200;
+const DEFAULT_NUMWANT: usize = 50;
+
+#[derive(Debug, Default)]
+struct Swarm {
+    peers: HashMap<SocketAddr, SwarmPeer>,
+    /// How many peers have announced finishing.
+    completed: u64,
+}
+
+#[derive(Debug)]
+struct SwarmPeer {
+    left: u64,
+    last_seen: Instant,
+}
+
+impl Swarm {
+    fn seeders(&self) -> u64 {
+        self.peers.values().filter(|peer| peer.left == 0).count() as u64
+    }
+
+    fn leechers(&self) -> u64 {
+        self.peers.len() as u64 - self.seeders()
+    }
+
+    fn stats(&self) -> Value {
+        Value::Dictionary(HashMap::from([
+            ("complete".to_string(), Value::Number(self.seeders() as i64)),
+            (
+                "incomplete".to_string(),
+                Value::Number(self.leechers() as i64),
+            ),
+            (
+                "downloaded".to_string(),
+                Value::Number(self.completed as i64),
+            ),
+        ]))
+    }
+}
+
+type Swarms = Arc<Mutex<HashMap<[u8; 20], Swarm>>>;
+
+/// Listens on `addr`, telling peers to announce again every `interval` and forgetting those
+/// that have not for twice that. Returns the address listened on.
+pub fn serve(addr: &str, interval: Duration) -> io::Result<SocketAddr> {
+    let listener = TcpListener::bind(addr)?;
+    let addr = listener.local_addr()?;
+    let swarms = Swarms::default();
+    http_server::serve(listener, "tracker", move |stream| {
+        handle_connection(stream, &swarms, interval)
+    });
+    Ok(addr)
+}
+
+fn handle_connection(stream: TcpStream, swarms: &Swarms, interval: Duration) -> io::Result<()> {
+    let peer_ip = stream.peer_addr()?.ip();
+    let target = http_server::read_head(&mut BufReader::new(stream.try_clone()?))?.target;
+    let mut stream = stream;
+    let (path, query) = target.split_once('?').unwrap_or((&target, ""));
+    let query = parse_query(query);
+
+    let body = match path {
+        "/announce" => {
+            let mut swarms = swarms.lock().expect("Swarms lock poisoned");
+            announce(&mut swarms, peer_ip, &query, interval)
+        }
+        "/scrape" => scrape(&swarms.lock().expect("Swarms lock poisoned"), &query),
+        _ => return write_response(&mut stream, "404 Not Found", b"not found"),
+    };
+    write_response(&mut stream, "200 OK", &body)
+}
+
+fn announce(
+    swarms: &mut HashMap<[u8; 20], Swarm>,
+    ip: IpAddr,
+    query: &[(String, Vec<u8>)],
+    interval: Duration,
+) -> Vec<u8> {
+    let Some(info_hash) = info_hashes(query).next() else {
+        return failure("missing or malformed info_hash");
+    };
+    let Some(port) = number(query, "port").and_then(|port| u16::try_from(port).ok()) else {
+        return failure("missing or malformed port");
+    };
+    let left = number(query, "left").unwrap_or(0);
+    let event = param(query, "event")
+        .map(|event| String::from_utf8_lossy(&event).into_owned())
+        .unwrap_or_default();
+    let numwant = number(query, "numwant").map_or(DEFAULT_NUMWANT, |numwant| {
+        usize::min(numwant as usize, MAX_NUMWANT)
+    });
+
+    let swarm = swarms.entry(info_hash).or_default();
+    let now = Instant::now();
+    swarm
+        .peers
+        .retain(|_, peer| now.duration_since(peer.last_seen) < interval * 2);
+    let addr = SocketAddr::new(ip, port);
+    log::debug!(
+        "{} announced {} for {}",
+        addr,
+        if event.is_empty() { "in" } else { &event },
+        hex::encode(info_hash)
+    );
+    if event == "stopped" {
+        swarm.peers.remove(&addr);
+    } else {
+        if event == "completed" {
+            swarm.completed += 1;
+        }
+        swarm.peers.insert(
+            addr,
+            SwarmPeer {
+                left,
+                last_seen: now,
+            },
+        );
+    }
+
+    let (mut peers, mut peers6) = (Vec::new(), Vec::new());
+    for other in swarm
+        .peers
+        .keys()
+        .filter(|other| **other != addr)
+        .take(numwant)
+    {
+        match other {
+            SocketAddr::V4(other) => {
+                peers.extend(other.ip().octets());
+                peers.extend(other.port().to_be_bytes());
+            }
+            SocketAddr::V6(other) => {
+                peers6.extend(other.ip().octets());
+                peers6.extend(other.port().to_be_bytes());
+            }
+        }
+    }
+    let mut response = HashMap::from([
+        (
+            "interval".to_string(),
+            Value::Number(interval.as_secs() as i64),
+        ),
+        (
+            "complete".to_string(),
+            Value::Number(swarm.seeders() as i64),
+        ),
+        (
+            "incomplete".to_string(),
+            Value::Number(swarm.leechers() as i64),
+        ),
+        ("peers".to_string(), Value::Blob(peers)),
+    ]);
+    if !peers6.is_empty() {
+        response.insert("peers6".to_string(), Value::Blob(peers6));
+    }
+    Bencode::encode(&Value::Dictionary(response))
+}
+
+/// The swarms asked about, or every swarm when the scrape names none.
+fn scrape(swarms: &HashMap<[u8; 20], Swarm>, query: &[(String, Vec<u8>)]) -> Vec<u8> {
+    let mut requested = info_hashes(query).collect::<Vec<_>>();
+    if requested.is_empty() {
+        requested = swarms.keys().copied().collect();
+    }
+    requested.sort();
+    requested.dedup();
+
+    // Files are keyed by raw info hash, which a `Value::Dictionary` cannot hold, so the
+    // outer dictionaries are written out by hand.
+    let mut body = b"d5:filesd".to_vec();
+    for info_hash in requested {
+        let Some(swarm) = swarms.get(&info_hash) else {
+            continue;
+        };
+        body.extend(b"20:");
+        body.extend(info_hash);
+        body.extend(Bencode::encode(&swarm.stats()));
+    }
+    body.extend(b"ee");
+    body
+}
+
+fn failure(reason: &str) -> Vec<u8> {
+    Bencode::encode(&Value::Dictionary(HashMap::from([(
+        "failure reason".to_string(),
+        Value::String(reason.to_string()),
+    )])))
+}
+
+fn info_hashes(query: &[(String, Vec<u8>)]) -> impl Iterator<Item = [u8; 20]> + '_ {
+    query
+        .iter()
+        .filter(|(key, _)| key == "info_hash")
+        .filter_map(|(_, value)| value.as_slice().try_into().ok())
+}
+
+fn param(query: &[(String, Vec<u8>)], key: &str) -> Option<Vec<u8>> {
+    query
+        .iter()
+        .find(|(name, _)| name == key)
+        .map(|(_, value)| value.clone())
+}
+
+fn number(query: &[(String, Vec<u8>)], key: &str) -> Option<u64> {
+    String::from_utf8(param(query, key)?).ok()?.parse().ok()
+}
+
+/// The query's parameters in order, with values left as bytes since info hashes and peer ids
+/// are binary.
+pub(crate) fn parse_query(query: &str) -> Vec<(String, Vec<u8>)> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let key = String::from_utf8_lossy(&percent_decode(key)).into_owned();
+            (key, percent_decode(value))
+        })
+        .collect()
+}
+
+fn percent_decode(text: &str) -> Vec<u8> {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut at = 0;
+    while at < bytes.len() {
+        let escaped = bytes
+            .get(at + 1..at + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[at], escaped) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                at += 3;
+                continue;
+            }
+            (b'+', _) => decoded.push(b' '),
+            (byte, _) => decoded.push(byte),
+        }
+        at += 1;
+    }
+    decoded
+}
+
+pub(crate) fn write_response(stream: &mut TcpStream, status: &str, body: &[u8]) -> io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        body.len()
+    )?;
+    stream.write_all(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{parse_query, serve};
+    use crate::{
+        peer::TEST_PEER_ID,
+        scrape::{scrape, ScrapeStats},
+        torrent::{announce, peers_from_response, TrackerError},
+    };
+
+    #[test]
+    fn keeps_track_of_a_swarm() {
+        let addr = serve("127.0.0.1:0", Duration::from_secs(60)).unwrap();
+        let url = format!("http://{}/announce", addr);
+        let info_hash = [0xab; 20];
+        let hex = hex::encode(info_hash);
+
+        announce(&url, &hex, &TEST_PEER_ID, 1000, 0, Some("started")).unwrap();
+        let response = announce(&url, &hex, &TEST_PEER_ID, 2000, 100, Some("started")).unwrap();
+        assert_eq!(
+            peers_from_response(&response).unwrap(),
+            vec!["127.0.0.1:1000".parse().unwrap()]
+        );
+        let stats = ScrapeStats {
+            seeders: 1,
+            leechers: 1,
+            completed: 0,
+        };
+        assert_eq!(scrape(&url, &info_hash).unwrap(), stats);
+
+        announce(&url, &hex, &TEST_PEER_ID, 2000, 0, Some("completed")).unwrap();
+        announce(&url, &hex, &TEST_PEER_ID, 1000, 0, Some("stopped")).unwrap();
+        let stats = ScrapeStats {
+            seeders: 1,
+            leechers: 0,
+            completed: 1,
+        };
+        assert_eq!(scrape(&url, &info_hash).unwrap(), stats);
+
+        let refused = announce(&url, "abcd", &TEST_PEER_ID, 1000, 0, None);
+        assert!(matches!(refused, Err(TrackerError::Failure(_))));
+    }
+
+    #[test]
+    fn decodes_binary_query_values() {
+        assert_eq!(
+            parse_query("info_hash=%AB%00z&event=started&left=1+2"),
+            vec![
+                ("info_hash".to_string(), vec![0xab, 0, b'z']),
+                ("event".to_string(), b"started".to_vec()),
+                ("left".to_string(), b"1 2".to_vec()),
+            ]
+        );
+    }
+}
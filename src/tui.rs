@@ -0,0 +1,559 @@
+//! A full-screen dashboard for the daemon, drawn with ANSI escape codes: every torrent's
+//! progress and rates, a table of the selected torrent's peers, and what recently happened. It
+//! drives the daemon through the same calls as the control API, and redraws as the daemon's
+//! events arrive. Log lines go to stderr, so redirect it to keep them off the screen.
+//!
+//! It stands in for a ratatui interface, which the crate's locked dependencies leave out, and
+//! does less. It never asks the terminal for its size, so it does not follow resizes: rows
+//! wider or longer than the window wrap or scroll off instead of being fitted to it. Every
+//! redraw clears and rewrites the whole screen rather than what changed. Keys are read as they
+//! are pressed only where `stty` can put the terminal in raw mode, as on Unix; elsewhere they
+//! wait for Enter.
+
+use std::{
+    collections::VecDeque,
+    fmt::Write as _,
+    fs::File,
+    io::{self, Read, Write},
+    process::{Command, Stdio},
+    sync::mpsc::{self, Receiver, RecvTimeoutError, Sender},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
+
+use bittorrent_starter_rust::{
+    bandwidth::Limit,
+    events::{Event, TorrentEvent},
+    log,
+    peer_manager::PeerSnapshot,
+    progress::format_bytes,
+    rpc::RpcCall,
+    session::{SessionStats, TorrentStatus},
+    shutdown::Shutdown,
+};
+
+// How often the screen is redrawn when no key is pressed and nothing happens.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+// Events come in bursts, so we wait this long for the rest of one before redrawing.
+const EVENT_BATCH_DELAY: Duration = Duration::from_millis(200);
+// How many of the latest notable events are listed.
+const RECENT_EVENTS: usize = 5;
+// How long we wait for the daemon to answer a call before giving up on it.
+const CALL_TIMEOUT: Duration = Duration::from_secs(5);
+// Where `-` starts from when there is no limit yet, and past which `+` lifts the limit.
+const DEFAULT_STEP_LIMIT: u64 = 1024 * 1024;
+const MAX_STEP_LIMIT: u64 = 64 * 1024 * 1024;
+const NAME_WIDTH: usize = 28;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Key {
+    Up,
+    Down,
+    Pause,
+    Resume,
+    Faster,
+    Slower,
+    Unlimited,
+    Quit,
+}
+
+/// Reads the keys in a chunk of terminal input, skipping any we do not bind.
+pub fn keys(input: &[u8]) -> Vec<Key> {
+    let mut keys = Vec::new();
+    let mut bytes = input.iter().peekable();
+    while let Some(byte) = bytes.next() {
+        let key = match byte {
+            // Arrow keys arrive as `ESC [ A` and `ESC [ B`.
+            0x1b if bytes.next_if_eq(&&b'[').is_some() => match bytes.next() {
+                Some(b'A') => Key::Up,
+                Some(b'B') => Key::Down,
+                _ => continue,
+            },
+            b'k' => Key::Up,
+            b'j' => Key::Down,
+            b'p' => Key::Pause,
+            b'r' => Key::Resume,
+            b'+' | b'=' => Key::Faster,
+            b'-' => Key::Slower,
+            b'u' => Key::Unlimited,
+            b'q' => Key::Quit,
+            _ => continue,
+        };
+        keys.push(key);
+    }
+    keys
+}
+
+/// What the dashboard waits on between redraws.
+enum Input {
+    Key(Key),
+    Event(Event),
+}
+
+/// Runs the dashboard on its own thread until the daemon is asked to shut down, which `q`
+/// does too. `events` are the daemon's.
+pub fn spawn(
+    control: Sender<RpcCall>,
+    events: Receiver<Event>,
+    shutdown: Shutdown,
+) -> JoinHandle<()> {
+    thread::spawn(move || {
+        let terminal = Terminal::enter();
+        let (inputs, received) = mpsc::channel();
+        read_keys(inputs.clone());
+        thread::spawn(move || {
+            for event in events {
+                if inputs.send(Input::Event(event)).is_err() {
+                    return;
+                }
+            }
+        });
+        let mut tui = Tui {
+            control,
+            shutdown,
+            selected: 0,
+            recent: VecDeque::new(),
+        };
+        tui.run(&received);
+        drop(terminal);
+    })
+}
+
+struct Tui {
+    control: Sender<RpcCall>,
+    shutdown: Shutdown,
+    selected: usize,
+    // The latest notable events, described, oldest first.
+    recent: VecDeque<String>,
+}
+
+impl Tui {
+    fn run(&mut self, inputs: &Receiver<Input>) {
+        while !self.shutdown.is_requested() {
+            let Some(screen) = self.screen() else {
+                break;
+            };
+            let mut stdout = io::stdout().lock();
+            let _ = write!(stdout, "\x1b[H\x1b[2J{}", screen.render(self.selected));
+            let _ = stdout.flush();
+            drop(stdout);
+
+            match inputs.recv_timeout(REFRESH_INTERVAL) {
+                Ok(Input::Key(key)) => self.handle(key, &screen),
+                Ok(Input::Event(event)) => {
+                    thread::sleep(EVENT_BATCH_DELAY);
+                    self.record(&event, &screen);
+                    for input in inputs.try_iter() {
+                        match input {
+                            Input::Key(key) => self.handle(key, &screen),
+                            Input::Event(event) => self.record(&event, &screen),
+                        }
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                // The daemon is gone, and stdin closed.
+                Err(RecvTimeoutError::Disconnected) => thread::sleep(REFRESH_INTERVAL),
+            }
+        }
+    }
+
+    /// Adds `event` to the recent ones, if it is worth listing.
+    fn record(&mut self, event: &Event, screen: &Screen) {
+        let Some(description) = describe(event, &screen.torrents) else {
+            return;
+        };
+        if self.recent.len() == RECENT_EVENTS {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(description);
+    }
+
+    fn screen(&mut self) -> Option<Screen> {
+        let torrents: Vec<TorrentStatus> = self.call("status", Value::Null)?;
+        let stats: SessionStats = self.call("session_stats", Value::Null)?;
+        self.selected = self.selected.min(torrents.len().saturating_sub(1));
+        let peers = match torrents.get(self.selected) {
+            Some(torrent) => self
+                .call("peers", json!({ "info_hash": torrent.info_hash }))
+                .unwrap_or_default(),
+            None => vec![],
+        };
+        Some(Screen {
+            torrents,
+            stats,
+            peers,
+            recent: self.recent.iter().cloned().collect(),
+        })
+    }
+
+    fn handle(&mut self, key: Key, screen: &Screen) {
+        let selected = screen.torrents.get(self.selected);
+        match key {
+            Key::Up => self.selected = self.selected.saturating_sub(1),
+            Key::Down => self.selected += 1,
+            Key::Pause | Key::Resume => {
+                let Some(torrent) = selected else {
+                    return;
+                };
+                let method = if key == Key::Pause { "pause" } else { "resume" };
+                let _: Option<Value> = self.call(method, json!({ "info_hash": torrent.info_hash }));
+            }
+            Key::Faster | Key::Slower | Key::Unlimited => {
+                let current = screen.stats.rate_limit.parse().unwrap_or(Limit::Unlimited);
+                let limit = match key {
+                    Key::Faster => faster(current),
+                    Key::Slower => slower(current),
+                    _ => Limit::Unlimited,
+                };
+                let _: Option<Value> =
+                    self.call("set_rate_limit", json!({ "limit": limit.to_string() }));
+            }
+            Key::Quit => self.shutdown.request(),
+        }
+    }
+
+    /// Asks the daemon to answer `method`, or `None` if it has stopped answering.
+    fn call<T: DeserializeOwned>(&self, method: &str, params: Value) -> Option<T> {
+        let (reply, result) = mpsc::channel();
+        let call = RpcCall {
+            method: method.to_string(),
+            params,
+            reply,
+        };
+        self.control.send(call).ok()?;
+        match result.recv_timeout(CALL_TIMEOUT).ok()? {
+            Ok(value) => serde_json::from_value(value).ok(),
+            Err(error) => {
+                log::warn!("{} failed: {}", method, error);
+                None
+            }
+        }
+    }
+}
+
+/// Doubles the limit, lifting it once it is large.
+fn faster(limit: Limit) -> Limit {
+    match limit {
+        Limit::Paused => Limit::BytesPerSecond(DEFAULT_STEP_LIMIT),
+        Limit::BytesPerSecond(rate) if rate.saturating_mul(2) <= MAX_STEP_LIMIT => {
+            Limit::BytesPerSecond(rate * 2)
+        }
+        _ => Limit::Unlimited,
+    }
+}
+
+/// Halves the limit, starting from a default when there is none.
+fn slower(limit: Limit) -> Limit {
+    match limit {
+        Limit::Unlimited => Limit::BytesPerSecond(DEFAULT_STEP_LIMIT),
+        Limit::BytesPerSecond(rate) => Limit::BytesPerSecond((rate / 2).max(1024)),
+        Limit::Paused => Limit::Paused,
+    }
+}
+
+/// Describes an event for the recent list, leaving out those too frequent to read, like each
+/// verified piece.
+fn describe(event: &Event, torrents: &[TorrentStatus]) -> Option<String> {
+    let what = match &event.event {
+        TorrentEvent::TrackerAnnounced {
+            url,
+            peers: Some(peers),
+        } => format!("{} gave {} peers", url, peers),
+        TorrentEvent::TrackerAnnounced { url, peers: None } => format!("announced to {}", url),
+        TorrentEvent::PeerConnected { addr } => format!("connected to {}", addr),
+        TorrentEvent::PeerDisconnected { addr } => format!("disconnected from {}", addr),
+        TorrentEvent::PeerBanned { addr } => format!("banned {}", addr),
+        TorrentEvent::PieceFailed { piece } => format!("piece {} failed its hash", piece),
+        TorrentEvent::Added { .. } => "added".to_string(),
+        TorrentEvent::Completed => "completed".to_string(),
+        TorrentEvent::StateChanged { state } => state.to_string(),
+        TorrentEvent::Checked { .. }
+        | TorrentEvent::PieceVerified { .. }
+        | TorrentEvent::Stopped => return None,
+    };
+    let name = torrents
+        .iter()
+        .find(|torrent| torrent.info_hash == event.info_hash)
+        .map_or(&event.info_hash, |torrent| &torrent.name);
+    Some(format!("{}: {}", name, what))
+}
+
+/// What the daemon told us on one refresh.
+struct Screen {
+    torrents: Vec<TorrentStatus>,
+    stats: SessionStats,
+    peers: Vec<PeerSnapshot>,
+    recent: Vec<String>,
+}
+
+impl Screen {
+    fn render(&self, selected: usize) -> String {
+        let stats = &self.stats;
+        let limit = match stats.rate_limit.parse() {
+            Ok(Limit::BytesPerSecond(limit)) => rate(limit as f64),
+            _ => stats.rate_limit.clone(),
+        };
+        let mut screen = format!(
+            "{} torrents, {} downloading, {} seeding  down {}  up {}  limit {}  {} connections\n\n",
+            stats.torrents,
+            stats.downloading,
+            stats.seeding,
+            rate(stats.download_rate),
+            rate(stats.upload_rate),
+            limit,
+            stats.open_connections,
+        );
+
+        let _ = writeln!(
+            screen,
+            "  {:<NAME_WIDTH$} {:<18} {:>7} {:>12} {:>12} {:>5}",
+            "NAME", "STATE", "DONE", "DOWN", "UP", "PEERS"
+        );
+        for (index, torrent) in self.torrents.iter().enumerate() {
+            let done = if torrent.piece_count == 0 {
+                "-".to_string()
+            } else {
+                format!(
+                    "{:.1}%",
+                    torrent.pieces as f64 * 100.0 / torrent.piece_count as f64
+                )
+            };
+            let _ = writeln!(
+                screen,
+                "{} {:<NAME_WIDTH$} {:<18} {:>7} {:>12} {:>12} {:>5}",
+                if index == selected { '>' } else { ' ' },
+                truncate(&torrent.name, NAME_WIDTH),
+                torrent.state.to_string(),
+                done,
+                rate(torrent.download_rate),
+                rate(torrent.upload_rate),
+                torrent.peers
+            );
+        }
+
+        if let Some(torrent) = self.torrents.get(selected) {
+            let _ = writeln!(screen, "\nPeers of {}", torrent.name);
+            // Where peers are is only shown when the daemon has GeoIP databases.
+            let located = self.peers.iter().any(|peer| peer.location.is_some());
+            let _ = writeln!(
+                screen,
+                "  {:<24} {:<20} {:>12} {:>12}  {}",
+                "ADDRESS",
+                "CLIENT",
+                "DOWN",
+                "UP",
+                if located {
+                    "STATE                WHERE"
+                } else {
+                    "STATE"
+                }
+            );
+            for peer in &self.peers {
+                let choke = if peer.choked { "choked" } else { "unchoked" };
+                let interest = if peer.interested { ", interested" } else { "" };
+                let mut state = format!("{}{}", choke, interest);
+                if let Some(location) = &peer.location {
+                    state = format!("{:<20} {}", state, location);
+                }
+                let _ = writeln!(
+                    screen,
+                    "  {:<24} {:<20} {:>12} {:>12}  {}",
+                    peer.addr.to_string(),
+                    truncate(peer.client.as_deref().unwrap_or("unknown"), 20),
+                    rate(peer.download_rate),
+                    rate(peer.upload_rate),
+                    state
+                );
+            }
+        }
+
+        if !self.recent.is_empty() {
+            screen.push_str("\nRecently\n");
+            for description in &self.recent {
+                let _ = writeln!(screen, "  {}", description);
+            }
+        }
+
+        screen.push_str("\nj/k select  p pause  r resume  +/- rate limit  u unlimited  q quit\n");
+        screen
+    }
+}
+
+fn rate(bytes_per_second: f64) -> String {
+    format!("{}/s", format_bytes(bytes_per_second as u64))
+}
+
+fn truncate(text: &str, width: usize) -> String {
+    if text.chars().count() <= width {
+        return text.to_string();
+    }
+    let mut truncated = text.chars().take(width - 1).collect::<String>();
+    truncated.push('~');
+    truncated
+}
+
+/// Feeds keys typed on stdin to `inputs` until stdin closes.
+fn read_keys(inputs: Sender<Input>) {
+    thread::spawn(move || {
+        let mut stdin = io::stdin();
+        let mut buffer = [0; 16];
+        while let Ok(read @ 1..) = stdin.read(&mut buffer) {
+            for key in keys(&buffer[..read]) {
+                if inputs.send(Input::Key(key)).is_err() {
+                    return;
+                }
+            }
+        }
+    });
+}
+
+/// Switches the terminal to a blank alternate screen, taking keys as they are pressed rather
+/// than a line at a time, and puts everything back when dropped.
+struct Terminal {
+    // The settings `stty` reported before we changed them.
+    saved: Option<String>,
+}
+
+impl Terminal {
+    fn enter() -> Self {
+        let saved = stty(&["-g"]).filter(|_| stty(&["-icanon", "-echo", "min", "1"]).is_some());
+        print!("\x1b[?1049h\x1b[?25l");
+        let _ = io::stdout().flush();
+        Self { saved }
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        print!("\x1b[?25h\x1b[?1049l");
+        let _ = io::stdout().flush();
+        if let Some(saved) = &self.saved {
+            stty(&[saved.trim()]);
+        }
+    }
+}
+
+/// Runs `stty` on the controlling terminal, returning what it printed if it succeeded.
+fn stty(args: &[&str]) -> Option<String> {
+    let tty = File::open("/dev/tty").ok()?;
+    let output = Command::new("stty")
+        .args(args)
+        .stdin(tty)
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{describe, faster, keys, slower, Event, Key, Screen, TorrentEvent};
+    use bittorrent_starter_rust::{
+        bandwidth::Limit,
+        geoip::Location,
+        memory::MemoryUsage,
+        peer_manager::PeerSnapshot,
+        session::{SessionStats, TorrentState, TorrentStatus},
+    };
+
+    #[test]
+    fn reads_bound_keys() {
+        assert_eq!(
+            keys(b"jk\x1b[A\x1b[Bx+-uq"),
+            vec![
+                Key::Down,
+                Key::Up,
+                Key::Up,
+                Key::Down,
+                Key::Faster,
+                Key::Slower,
+                Key::Unlimited,
+                Key::Quit
+            ]
+        );
+        assert_eq!(slower(Limit::Unlimited), Limit::BytesPerSecond(1024 * 1024));
+        assert_eq!(
+            faster(Limit::BytesPerSecond(64 * 1024 * 1024)),
+            Limit::Unlimited
+        );
+    }
+
+    #[test]
+    fn shows_torrents_and_the_selected_torrents_peers() {
+        let torrent = |name: &str| TorrentStatus {
+            info_hash: "00".repeat(20),
+            name: name.to_string(),
+            path: name.to_string(),
+            state: TorrentState::Downloading,
+            pieces: 1,
+            piece_count: 4,
+            uploaded: 0,
+            download_rate: 2048.0,
+            upload_rate: 0.0,
+            peers: 1,
+        };
+        let screen = Screen {
+            torrents: vec![torrent("first"), torrent("second")],
+            stats: SessionStats {
+                torrents: 2,
+                downloading: 2,
+                seeding: 0,
+                uploaded: 0,
+                download_rate: 4096.0,
+                upload_rate: 0.0,
+                open_connections: 2,
+                rate_limit: "unlimited".to_string(),
+                memory: MemoryUsage::default(),
+            },
+            peers: vec![PeerSnapshot {
+                addr: "10.0.0.1:6881".parse().unwrap(),
+                info_hash: [0; 20],
+                client: Some("qBittorrent 4.2.5".to_string()),
+                downloaded: 4096,
+                uploaded: 0,
+                download_rate: 2048.0,
+                upload_rate: 0.0,
+                choked: false,
+                interested: true,
+                location: Some(Location {
+                    country: Some("NL".to_string()),
+                    asn: Some(1136),
+                    organization: None,
+                }),
+            }],
+            recent: vec!["second: connected to 10.0.0.1:6881".to_string()],
+        };
+
+        let text = screen.render(1);
+        assert!(text.contains("down 4.0 KiB/s"));
+        assert!(text.contains("  first "));
+        assert!(text.contains("> second "));
+        assert!(text.contains("25.0%"));
+        assert!(text.contains("Peers of second"));
+        assert!(text.contains("qBittorrent 4.2.5"));
+        assert!(text.contains("unchoked, interested NL AS1136"));
+        assert!(text.contains("Recently\n  second: connected to 10.0.0.1:6881\n"));
+
+        let event = |event| Event {
+            info_hash: "00".repeat(20),
+            event,
+        };
+        let failed = event(TorrentEvent::PieceFailed { piece: 2 });
+        assert_eq!(
+            describe(&failed, &screen.torrents).unwrap(),
+            "first: piece 2 failed its hash"
+        );
+        let verified = event(TorrentEvent::PieceVerified {
+            piece: 2,
+            pieces: 2,
+            bytes: 2048,
+        });
+        assert_eq!(describe(&verified, &screen.torrents), None);
+    }
+}
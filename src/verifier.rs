@@ -0,0 +1,171 @@
+use std::{
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex,
+    },
+    thread,
+};
+
+use sha1::{Digest, Sha1};
+
+use crate::executor;
+
+/// The outcome of checking a downloaded piece against its hash from the metainfo. The piece's
+/// data is handed back so it can be passed on once verified.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Verification {
+    pub piece_index: usize,
+    pub valid: bool,
+    pub data: Vec<u8>,
+}
+
+struct Job {
+    piece_index: usize,
+    data: Vec<u8>,
+    expected: [u8; 20],
+}
+
+/// Hashes pieces on a pool of worker threads so SHA-1 never holds up the network loop.
+/// Results come back in the order pieces finish hashing, not the order they were submitted.
+pub struct VerifyPool {
+    jobs: Sender<Job>,
+    results: Receiver<Verification>,
+    /// Set for a pool without workers, which verifies pieces as they are submitted.
+    inline: Option<Sender<Verification>>,
+    pending: usize,
+}
+
+impl Job {
+    fn verify(self) -> Verification {
+        Verification {
+            piece_index: self.piece_index,
+            valid: Sha1::digest(&self.data).as_slice() == self.expected,
+            data: self.data,
+        }
+    }
+}
+
+impl VerifyPool {
+    pub fn new(threads: usize) -> Self {
+        let (jobs, job_receiver) = mpsc::channel::<Job>();
+        let (result_sender, results) = mpsc::channel();
+        let job_receiver = Arc::new(Mutex::new(job_receiver));
+
+        for _ in 0..threads.max(1) {
+            let job_receiver = job_receiver.clone();
+            let result_sender = result_sender.clone();
+            executor::spawn("verifier", move || loop {
+                let job = job_receiver.lock().expect("Job queue lock poisoned").recv();
+                // The pool was dropped, so there is nothing left to verify.
+                let Ok(job) = job else { break };
+
+                if result_sender.send(job.verify()).is_err() {
+                    break;
+                }
+            });
+        }
+
+        Self {
+            jobs,
+            results,
+            inline: None,
+            pending: 0,
+        }
+    }
+
+    /// A pool that hashes each piece on the thread submitting it, so results come back in the
+    /// order pieces were submitted.
+    pub fn inline() -> Self {
+        let (jobs, _) = mpsc::channel();
+        let (result_sender, results) = mpsc::channel();
+        Self {
+            jobs,
+            results,
+            inline: Some(result_sender),
+            pending: 0,
+        }
+    }
+
+    /// A pool with a worker for each CPU.
+    pub fn with_available_parallelism() -> Self {
+        Self::new(thread::available_parallelism().map_or(1, |threads| threads.get()))
+    }
+
+    pub fn submit(&mut self, piece_index: usize, data: Vec<u8>, expected: [u8; 20]) {
+        let job = Job {
+            piece_index,
+            data,
+            expected,
+        };
+        match &self.inline {
+            Some(results) => results
+                .send(job.verify())
+                .expect("Verification results dropped"),
+            None => self.jobs.send(job).expect("Verification workers stopped"),
+        }
+        self.pending += 1;
+    }
+
+    /// Pieces submitted that have not had their result collected yet.
+    pub fn pending(&self) -> usize {
+        self.pending
+    }
+
+    /// Results that are ready now, without waiting for the rest.
+    pub fn ready(&mut self) -> Vec<Verification> {
+        let results = self.results.try_iter().collect::<Vec<_>>();
+        self.pending -= results.len();
+        results
+    }
+
+    /// Waits for the next result, if anything is still being verified.
+    pub fn next(&mut self) -> Option<Verification> {
+        if self.pending == 0 {
+            return None;
+        }
+
+        let result = self.results.recv().expect("Verification workers stopped");
+        self.pending -= 1;
+        Some(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sha1::{Digest, Sha1};
+
+    use super::{Verification, VerifyPool};
+
+    #[test]
+    fn verifies_pieces_in_parallel() {
+        let mut pool = VerifyPool::new(4);
+        let pieces = (0..16u8).map(|i| vec![i; 1024]).collect::<Vec<_>>();
+        for (index, piece) in pieces.iter().enumerate() {
+            let mut expected: [u8; 20] = Sha1::digest(piece).into();
+            if index == 3 {
+                expected[0] ^= 0xff;
+            }
+            pool.submit(index, piece.clone(), expected);
+        }
+
+        let mut results = Vec::new();
+        while let Some(result) = pool.next() {
+            results.push(result);
+        }
+        results.sort_by_key(|result| result.piece_index);
+
+        assert_eq!(results.len(), 16);
+        assert_eq!(
+            results[3],
+            Verification {
+                piece_index: 3,
+                valid: false,
+                data: vec![3; 1024],
+            }
+        );
+        assert!(results
+            .iter()
+            .all(|result| result.valid == (result.piece_index != 3)));
+        assert_eq!(pool.next(), None);
+    }
+}
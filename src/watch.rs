@@ -0,0 +1,177 @@
+//! Picks up `.torrent` files and `.magnet` files, text files holding a magnet link, dropped
+//! into a directory. Consumed files are moved into its `processed` subdirectory, and ones we
+//! cannot read into `failed`, so each is only looked at once.
+
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    magnet::{Magnet, MagnetError},
+    torrent::{Torrent, TorrentError},
+};
+
+pub const PROCESSED_DIR: &str = "processed";
+pub const FAILED_DIR: &str = "failed";
+
+/// A torrent found in the watched directory.
+pub enum Found {
+    Torrent(Torrent),
+    Magnet(Magnet),
+}
+
+pub struct WatchDir {
+    dir: PathBuf,
+    // The size of each candidate file when we last looked, to tell when it has been written.
+    sizes: HashMap<PathBuf, u64>,
+}
+
+impl WatchDir {
+    pub fn new(dir: &Path) -> io::Result<Self> {
+        fs::create_dir_all(dir.join(PROCESSED_DIR))?;
+        fs::create_dir_all(dir.join(FAILED_DIR))?;
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            sizes: HashMap::new(),
+        })
+    }
+
+    /// Reads the files that have appeared since the last scan and moves them out of the way.
+    /// A file is only read once its size is the same as at the previous scan, so one that is
+    /// still being copied in is left for later.
+    pub fn scan(&mut self) -> Vec<Result<Found, WatchError>> {
+        let Ok(entries) = fs::read_dir(&self.dir) else {
+            return vec![];
+        };
+        let mut sizes = HashMap::new();
+        let mut found = Vec::new();
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let Ok(metadata) = entry.metadata() else {
+                continue;
+            };
+            if !metadata.is_file() || kind(&path).is_none() {
+                continue;
+            }
+            if self.sizes.get(&path) != Some(&metadata.len()) {
+                sizes.insert(path, metadata.len());
+                continue;
+            }
+
+            let result = read(&path);
+            let into = if result.is_ok() {
+                PROCESSED_DIR
+            } else {
+                FAILED_DIR
+            };
+            let moved = path
+                .file_name()
+                .map(|name| self.dir.join(into).join(name))
+                .map(|to| fs::rename(&path, to));
+            found.push(match moved {
+                Some(Err(error)) => Err(WatchError::Move(path, error)),
+                _ => result,
+            });
+        }
+        self.sizes = sizes;
+        found
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Kind {
+    Torrent,
+    Magnet,
+}
+
+fn kind(path: &Path) -> Option<Kind> {
+    match path.extension()?.to_str()? {
+        "torrent" => Some(Kind::Torrent),
+        "magnet" => Some(Kind::Magnet),
+        _ => None,
+    }
+}
+
+fn read(path: &Path) -> Result<Found, WatchError> {
+    match kind(path) {
+        Some(Kind::Torrent) => Torrent::open(path)
+            .map(Found::Torrent)
+            .map_err(|error| WatchError::Torrent(path.to_path_buf(), error)),
+        Some(Kind::Magnet) => {
+            let text =
+                fs::read_to_string(path).map_err(|error| WatchError::Read(path.into(), error))?;
+            let link = text.lines().map(str::trim).find(|line| !line.is_empty());
+            Magnet::parse(link.unwrap_or_default())
+                .map(Found::Magnet)
+                .map_err(|error| WatchError::Magnet(path.to_path_buf(), error))
+        }
+        None => unreachable!("only torrent and magnet files are read"),
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum WatchError {
+    #[error("cannot read {0}: {1}")]
+    Read(PathBuf, io::Error),
+    #[error("{0}: {1}")]
+    Torrent(PathBuf, TorrentError),
+    #[error("{0}: {1}")]
+    Magnet(PathBuf, MagnetError),
+    #[error("cannot move {0} out of the watch directory: {1}")]
+    Move(PathBuf, io::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::{Found, WatchDir, WatchError, FAILED_DIR, PROCESSED_DIR};
+    use crate::create::{TorrentCreator, TorrentVersion};
+
+    #[test]
+    fn takes_files_once_they_stop_growing() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = dir.path().join("payload");
+        fs::write(&data, vec![7; 1000]).unwrap();
+        let creator = TorrentCreator {
+            announce: "http://127.0.0.1:1/announce".to_string(),
+            piece_length: 16 * 1024,
+            private: false,
+            version: TorrentVersion::V1,
+        };
+        let watched = dir.path().join("watched");
+        let mut watch = WatchDir::new(&watched).unwrap();
+        fs::write(
+            watched.join("a.torrent"),
+            creator.create(&data).unwrap().bytes,
+        )
+        .unwrap();
+        fs::write(
+            watched.join("b.magnet"),
+            "\nmagnet:?xt=urn:btih:d69f91e6b2ae4c542468d1073a71d4ea13879a7f\n",
+        )
+        .unwrap();
+        fs::write(watched.join("c.magnet"), "not a link").unwrap();
+        fs::write(watched.join("notes.txt"), "ignored").unwrap();
+
+        assert!(watch.scan().is_empty());
+        let mut found = watch.scan();
+        found.sort_by_key(|result| match result {
+            Ok(Found::Torrent(_)) => 0,
+            Ok(Found::Magnet(_)) => 1,
+            Err(_) => 2,
+        });
+        assert_eq!(found.len(), 3);
+        assert!(matches!(&found[0], Ok(Found::Torrent(torrent)) if torrent.info.name == "payload"));
+        assert!(matches!(&found[1], Ok(Found::Magnet(_))));
+        assert!(matches!(&found[2], Err(WatchError::Magnet(..))));
+
+        assert!(watched.join(PROCESSED_DIR).join("a.torrent").exists());
+        assert!(watched.join(PROCESSED_DIR).join("b.magnet").exists());
+        assert!(watched.join(FAILED_DIR).join("c.magnet").exists());
+        assert!(watched.join("notes.txt").exists());
+        assert!(watch.scan().is_empty());
+    }
+}
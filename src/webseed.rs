@@ -0,0 +1,246 @@
+use std::{
+    sync::mpsc::{self, Receiver, Sender},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    executor,
+    http::{self, HttpError, HttpFetch, HttpRequest},
+};
+
+/// An HTTP server holding a copy of the torrent's content (BEP 19). A URL ending in `/` is a
+/// directory the file is found in by name; any other URL is the file itself.
+#[derive(Debug, Clone)]
+pub struct WebSeed {
+    url: String,
+}
+
+impl WebSeed {
+    pub fn new(url: String, name: &str) -> Self {
+        let url = if url.ends_with('/') {
+            format!("{}{}", url, name)
+        } else {
+            url
+        };
+
+        Self { url }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Fetches `length` bytes starting at `offset` with a range request.
+    pub fn fetch(
+        &self,
+        fetcher: &dyn HttpFetch,
+        offset: u64,
+        length: u64,
+    ) -> Result<Vec<u8>, WebSeedError> {
+        let range = format!("bytes={}-{}", offset, offset + length - 1);
+        let response = fetcher.get(&HttpRequest::get(&self.url).header("Range", range))?;
+
+        let status = response.status;
+        if status != 206 && status != 200 {
+            return Err(WebSeedError::Status(status));
+        }
+
+        let mut bytes = response.body;
+        // A server that ignores the range sends the whole file back.
+        if status == 200 {
+            let start = (offset as usize).min(bytes.len());
+            bytes.drain(..start);
+            bytes.truncate(length as usize);
+        }
+        if bytes.len() as u64 != length {
+            return Err(WebSeedError::ShortRead {
+                expected: length,
+                got: bytes.len() as u64,
+            });
+        }
+
+        Ok(bytes)
+    }
+
+    /// Runs range requests on a background thread so the web seed downloads alongside peers.
+    pub fn spawn(self) -> WebSeedWorker {
+        let (jobs, job_receiver) = mpsc::channel::<RangeRequest>();
+        let (result_sender, results) = mpsc::channel();
+        let seed = self.clone();
+
+        executor::spawn("web seed", move || {
+            let fetcher = http::fetcher();
+            for job in job_receiver {
+                let started = Instant::now();
+                let result = RangeResult {
+                    piece_index: job.piece_index,
+                    offset: job.offset,
+                    length: job.length,
+                    data: seed.fetch(fetcher.as_ref(), job.offset, job.length),
+                    elapsed: started.elapsed(),
+                };
+                if result_sender.send(result).is_err() {
+                    break;
+                }
+            }
+        });
+
+        WebSeedWorker {
+            seed: self,
+            jobs,
+            results,
+            busy: false,
+        }
+    }
+}
+
+struct RangeRequest {
+    piece_index: usize,
+    offset: u64,
+    length: u64,
+}
+
+/// A finished range request for part of a piece.
+pub struct RangeResult {
+    pub piece_index: usize,
+    /// Where the range starts in the torrent's content.
+    pub offset: u64,
+    pub length: u64,
+    pub data: Result<Vec<u8>, WebSeedError>,
+    pub elapsed: Duration,
+}
+
+/// A web seed fetching one range at a time on its own thread.
+pub struct WebSeedWorker {
+    seed: WebSeed,
+    jobs: Sender<RangeRequest>,
+    results: Receiver<RangeResult>,
+    busy: bool,
+}
+
+impl WebSeedWorker {
+    pub fn url(&self) -> &str {
+        self.seed.url()
+    }
+
+    pub fn is_busy(&self) -> bool {
+        self.busy
+    }
+
+    pub fn request(&mut self, piece_index: usize, offset: u64, length: u64) {
+        self.jobs
+            .send(RangeRequest {
+                piece_index,
+                offset,
+                length,
+            })
+            .expect("Web seed worker stopped");
+        self.busy = true;
+    }
+
+    /// The range the worker was fetching, if it has finished.
+    pub fn ready(&mut self) -> Option<RangeResult> {
+        let result = self.results.try_recv().ok()?;
+        self.busy = false;
+        Some(result)
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum WebSeedError {
+    #[error("request failed: {0}")]
+    Http(#[from] HttpError),
+    #[error("server answered with status {0}")]
+    Status(u16),
+    #[error("expected {expected} bytes but got {got}")]
+    ShortRead { expected: u64, got: u64 },
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader, Write},
+        net::TcpListener,
+        thread,
+    };
+
+    use crate::http::{HttpError, HttpFetch, HttpRequest, HttpResponse, ReqwestFetch};
+
+    use super::WebSeed;
+
+    /// Answers one range request for `content` with a 206.
+    fn spawn_server(content: Vec<u8>) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        thread::spawn(move || {
+            let (socket, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(socket);
+            let mut range = None;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let line = line.trim_end().to_ascii_lowercase();
+                if line.is_empty() {
+                    break;
+                }
+                if let Some(value) = line.strip_prefix("range: bytes=") {
+                    let (start, end) = value.split_once('-').unwrap();
+                    range = Some((
+                        start.parse::<usize>().unwrap(),
+                        end.parse::<usize>().unwrap(),
+                    ));
+                }
+            }
+
+            let (start, end) = range.unwrap();
+            let body = &content[start..=end];
+            let mut socket = reader.into_inner();
+            write!(
+                socket,
+                "HTTP/1.1 206 Partial Content\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                body.len()
+            )
+            .unwrap();
+            socket.write_all(body).unwrap();
+        });
+
+        port
+    }
+
+    #[test]
+    fn fetches_a_range() {
+        let content = (0..=255u8).collect::<Vec<_>>();
+        let port = spawn_server(content.clone());
+        let seed = WebSeed::new(format!("http://127.0.0.1:{}/files/", port), "payload");
+        assert!(seed.url().ends_with("/files/payload"));
+
+        let bytes = seed.fetch(&ReqwestFetch::new(), 16, 32).unwrap();
+        assert_eq!(bytes, content[16..48]);
+    }
+
+    /// A server that ignores range requests and sends the whole file.
+    struct WholeFile(Vec<u8>);
+
+    impl HttpFetch for WholeFile {
+        fn get(&self, request: &HttpRequest) -> Result<HttpResponse, HttpError> {
+            assert_eq!(
+                request.headers,
+                [("Range".to_string(), "bytes=16-47".to_string())]
+            );
+            Ok(HttpResponse {
+                status: 200,
+                body: self.0.clone(),
+            })
+        }
+    }
+
+    #[test]
+    fn cuts_the_range_out_of_a_whole_file() {
+        let content = (0..=255u8).collect::<Vec<_>>();
+        let seed = WebSeed::new("http://example.com/payload".to_string(), "payload");
+
+        let bytes = seed.fetch(&WholeFile(content.clone()), 16, 32).unwrap();
+        assert_eq!(bytes, content[16..48]);
+    }
+}
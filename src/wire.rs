@@ -0,0 +1,724 @@
+//! The peer wire protocol (BEP 3): the handshake that opens a connection, and the
+//! length-prefixed messages that follow it. Each type encodes to the bytes sent on the wire and
+//! decodes from the bytes received, and [`MessageReader`] and [`MessageWriter`] frame messages
+//! on a socket without allocating for each one.
+
+use std::io::{Cursor, Read, Write};
+
+/// A frame of length zero, sent to keep an idle connection open. It carries no message.
+pub const KEEP_ALIVE: [u8; 4] = [0; 4];
+
+/// The payload of a request or cancel message: a block of a piece.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BlockRequest {
+    pub index: u32,
+    pub begin: u32,
+    pub length: u32,
+}
+
+impl BlockRequest {
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != 12 {
+            return None;
+        }
+        let word = |at: usize| u32::from_be_bytes(bytes[at..at + 4].try_into().unwrap());
+        Some(Self {
+            index: word(0),
+            begin: word(4),
+            length: word(8),
+        })
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend(&self.index.to_be_bytes());
+        bytes.extend(&self.begin.to_be_bytes());
+        bytes.extend(&self.length.to_be_bytes());
+        bytes
+    }
+}
+
+#[derive(Debug)]
+pub struct Message {
+    pub length: u32,
+    pub id: MessageId,
+    pub payload: Vec<u8>,
+}
+
+impl Message {
+    pub fn new(id: MessageId, payload: Vec<u8>) -> Self {
+        let length = payload.len() as u32 + 1;
+        Self {
+            length,
+            id,
+            payload,
+        }
+    }
+
+    pub fn interested() -> Self {
+        Self::new(MessageId::Interested, vec![])
+    }
+
+    pub fn not_interested() -> Self {
+        Self::new(MessageId::NotInterested, vec![])
+    }
+
+    pub fn have(piece_index: u32) -> Self {
+        Self::new(MessageId::Have, piece_index.to_be_bytes().to_vec())
+    }
+
+    pub fn port(port: u16) -> Self {
+        Self::new(MessageId::Port, port.to_be_bytes().to_vec())
+    }
+
+    /// A piece message carrying `block`, which starts `begin` bytes into piece `index`.
+    pub fn piece(index: u32, begin: u32, block: &[u8]) -> Self {
+        let mut payload = Vec::with_capacity(8 + block.len());
+        payload.extend(index.to_be_bytes());
+        payload.extend(begin.to_be_bytes());
+        payload.extend(block);
+        Self::new(MessageId::Piece, payload)
+    }
+
+    /// The framed message, as sent on the wire.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(4 + self.length as usize);
+        self.encode_into(&mut bytes);
+        bytes
+    }
+
+    /// Appends the framed message to `buf`, so a connection can reuse one buffer for every
+    /// message it sends.
+    pub fn encode_into(&self, buf: &mut Vec<u8>) {
+        buf.extend(self.length.to_be_bytes());
+        buf.push(self.id.into());
+        buf.extend(&self.payload);
+    }
+
+    /// The message in `bytes`, which hold exactly one frame. A keep-alive comes back as `None`.
+    pub fn decode(bytes: &[u8]) -> Result<Option<Self>, MessageError> {
+        let mut cursor = Cursor::new(bytes);
+        let message = Self::read_from_socket(&mut cursor)?;
+        let trailing = bytes.len() - cursor.position() as usize;
+        if trailing > 0 {
+            return Err(MessageError::Trailing(trailing));
+        }
+        Ok(message)
+    }
+
+    /// Reads one frame from the peer. Keep-alives carry no message, so they come back as `None`.
+    pub fn read_from_socket<R: Read>(socket: &mut R) -> Result<Option<Self>, MessageError> {
+        let Some((length, id)) = read_header(socket)? else {
+            return Ok(None);
+        };
+
+        let mut payload = Vec::new();
+        read_payload(socket, &mut payload, length as usize - 1)?;
+
+        Ok(Some(Self {
+            length,
+            id,
+            payload,
+        }))
+    }
+}
+
+/// A message whose payload borrows from the buffer of the `MessageReader` that read it.
+#[derive(Debug)]
+pub struct MessageRef<'a> {
+    pub id: MessageId,
+    pub payload: &'a [u8],
+}
+
+impl MessageRef<'_> {
+    pub fn to_message(&self) -> Message {
+        Message::new(self.id, self.payload.to_vec())
+    }
+}
+
+/// Reads frames into one buffer that is reused for every message on a connection, so the hot
+/// path of receiving blocks does not allocate.
+#[derive(Debug, Default)]
+pub struct MessageReader {
+    buf: Vec<u8>,
+}
+
+impl MessageReader {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Like `Message::read_from_socket`, but the payload is only valid until the next read.
+    pub fn read<R: Read>(
+        &mut self,
+        socket: &mut R,
+    ) -> Result<Option<MessageRef<'_>>, MessageError> {
+        let Some((length, id)) = read_header(socket)? else {
+            return Ok(None);
+        };
+
+        read_payload(socket, &mut self.buf, length as usize - 1)?;
+
+        Ok(Some(MessageRef {
+            id,
+            payload: &self.buf,
+        }))
+    }
+}
+
+/// Writes framed messages through one reused buffer.
+#[derive(Debug, Default)]
+pub struct MessageWriter {
+    buf: Vec<u8>,
+}
+
+impl MessageWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn write<W: Write>(&mut self, socket: &mut W, message: &Message) -> std::io::Result<()> {
+        self.buf.clear();
+        message.encode_into(&mut self.buf);
+        socket.write_all(&self.buf)
+    }
+}
+
+/// Reads a frame's length and id, or `None` for a keep-alive.
+fn read_header<R: Read>(socket: &mut R) -> Result<Option<(u32, MessageId)>, MessageError> {
+    let mut buf = [0; 4];
+    socket.read_exact(&mut buf)?;
+    let length = u32::from_be_bytes(buf);
+
+    if length == 0 {
+        return Ok(None);
+    }
+    if length > MAX_MESSAGE_LENGTH {
+        return Err(MessageError::TooLarge(length));
+    }
+
+    let mut id = [0; 1];
+    socket.read_exact(&mut id)?;
+    Ok(Some((length, id[0].into())))
+}
+
+/// Reads a payload of `length` bytes into `buf`. It grows as the bytes arrive rather than to
+/// the length the peer claims, so a frame that claims a lot and sends little costs little.
+fn read_payload<R: Read>(socket: &mut R, buf: &mut Vec<u8>, length: usize) -> std::io::Result<()> {
+    buf.clear();
+    while buf.len() < length {
+        let start = buf.len();
+        buf.resize(length.min(start + PAYLOAD_CHUNK), 0);
+        socket.read_exact(&mut buf[start..])?;
+    }
+    Ok(())
+}
+
+// Comfortably above a 16 KiB block plus its header, or the bitfield of a very large torrent.
+const MAX_MESSAGE_LENGTH: u32 = 1 << 21;
+// How much more of a payload we make room for at a time.
+const PAYLOAD_CHUNK: usize = 64 * 1024;
+
+#[derive(Debug, thiserror::Error)]
+pub enum MessageError {
+    #[error("failed to read message: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("message length {0} exceeds the maximum frame size")]
+    TooLarge(u32),
+    #[error("{0} bytes left over after the message")]
+    Trailing(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageId {
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    Have,
+    Bitfield,
+    Request,
+    Piece,
+    Cancel,
+    Port,
+    Extended,
+    HashRequest,
+    Hashes,
+    HashReject,
+    Unknown(u8),
+}
+
+impl From<u8> for MessageId {
+    fn from(id: u8) -> Self {
+        match id {
+            0 => Self::Choke,
+            1 => Self::Unchoke,
+            2 => Self::Interested,
+            3 => Self::NotInterested,
+            4 => Self::Have,
+            5 => Self::Bitfield,
+            6 => Self::Request,
+            7 => Self::Piece,
+            8 => Self::Cancel,
+            9 => Self::Port,
+            20 => Self::Extended,
+            21 => Self::HashRequest,
+            22 => Self::Hashes,
+            23 => Self::HashReject,
+            id => Self::Unknown(id),
+        }
+    }
+}
+
+impl From<MessageId> for u8 {
+    fn from(id: MessageId) -> Self {
+        match id {
+            MessageId::Choke => 0,
+            MessageId::Unchoke => 1,
+            MessageId::Interested => 2,
+            MessageId::NotInterested => 3,
+            MessageId::Have => 4,
+            MessageId::Bitfield => 5,
+            MessageId::Request => 6,
+            MessageId::Piece => 7,
+            MessageId::Cancel => 8,
+            MessageId::Port => 9,
+            MessageId::Extended => 20,
+            MessageId::HashRequest => 21,
+            MessageId::Hashes => 22,
+            MessageId::HashReject => 23,
+            MessageId::Unknown(id) => id,
+        }
+    }
+}
+
+/// The first thing each side sends: the protocol, the extensions it supports in the reserved
+/// bits, the torrent it wants and who it is.
+pub struct Handshake {
+    pub pstr: String,
+    pub reserved: [u8; 8],
+    pub info_hash: [u8; 20],
+    pub peer_id: [u8; 20],
+}
+
+impl Handshake {
+    pub fn new(pstr: String, info_hash: [u8; 20], peer_id: [u8; 20]) -> Self {
+        Self {
+            pstr,
+            reserved: [0; 8],
+            info_hash,
+            peer_id,
+        }
+    }
+
+    /// Peers running a DHT node set the last bit of the reserved bytes (BEP 5).
+    pub fn supports_dht(&self) -> bool {
+        self.reserved[7] & 0x01 != 0
+    }
+
+    pub fn set_supports_dht(&mut self) {
+        self.reserved[7] |= 0x01;
+    }
+
+    /// Peers that understand extended messages set bit 20 of the reserved bytes (BEP 10).
+    pub fn supports_extensions(&self) -> bool {
+        self.reserved[5] & 0x10 != 0
+    }
+
+    pub fn set_supports_extensions(&mut self) {
+        self.reserved[5] |= 0x10;
+    }
+
+    /// Peers that implement the fast extension set the third-lowest bit (BEP 6).
+    pub fn supports_fast(&self) -> bool {
+        self.reserved[7] & 0x04 != 0
+    }
+
+    /// What the reserved bits say the peer supports, by name.
+    pub fn capabilities(&self) -> Vec<&'static str> {
+        [
+            (self.supports_extensions(), "extension protocol"),
+            (self.supports_dht(), "DHT"),
+            (self.supports_fast(), "fast extension"),
+        ]
+        .into_iter()
+        .filter_map(|(supported, name)| supported.then_some(name))
+        .collect()
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.push(self.pstr.len() as u8);
+        bytes.extend(self.pstr.as_bytes());
+        bytes.extend(&self.reserved);
+        bytes.extend(&self.info_hash);
+        bytes.extend(&self.peer_id);
+        bytes
+    }
+
+    /// Reads a handshake for the 19-byte "BitTorrent protocol", the only one 68 bytes long.
+    pub fn decode(bytes: [u8; 68]) -> Self {
+        // Checked against the protocol name by the caller, so another only has to survive being
+        // compared.
+        let pstr_len = (bytes[0] as usize).min(19);
+        let pstr = String::from_utf8_lossy(&bytes[1..pstr_len + 1]).into_owned();
+        let reserved = bytes[20..28].try_into().expect("Failed to parse reserved");
+        let info_hash = bytes[28..48].try_into().expect("Failed to parse info hash");
+        let peer_id = bytes[48..68].try_into().expect("Failed to parse peer id");
+
+        Self {
+            pstr,
+            reserved,
+            info_hash,
+            peer_id,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Cursor, Write},
+        time::Instant,
+    };
+
+    use super::{
+        BlockRequest, Handshake, Message, MessageError, MessageId, MessageReader, MessageWriter,
+        KEEP_ALIVE,
+    };
+    use crate::arbitrary::{self, Gen};
+
+    #[test]
+    fn names_capabilities_from_reserved_bits() {
+        let mut handshake = Handshake::decode([0; 68]);
+        assert!(handshake.capabilities().is_empty());
+        handshake.reserved = [0, 0, 0, 0, 0, 0x10, 0, 0x05];
+        assert_eq!(
+            handshake.capabilities(),
+            vec!["extension protocol", "DHT", "fast extension"]
+        );
+    }
+
+    #[test]
+    fn keep_alive_has_no_message() {
+        let mut bytes = Cursor::new(KEEP_ALIVE);
+        assert!(Message::read_from_socket(&mut bytes).unwrap().is_none());
+        assert!(Message::decode(&KEEP_ALIVE).unwrap().is_none());
+    }
+
+    #[test]
+    fn messages_are_framed_as_the_spec_says() {
+        let request = BlockRequest {
+            index: 1,
+            begin: 0x4000,
+            length: 0x4000,
+        };
+        let cases: Vec<(Message, Vec<u8>)> = vec![
+            (Message::new(MessageId::Choke, vec![]), vec![0, 0, 0, 1, 0]),
+            (
+                Message::new(MessageId::Unchoke, vec![]),
+                vec![0, 0, 0, 1, 1],
+            ),
+            (Message::interested(), vec![0, 0, 0, 1, 2]),
+            (Message::not_interested(), vec![0, 0, 0, 1, 3]),
+            (Message::have(258), vec![0, 0, 0, 5, 4, 0, 0, 1, 2]),
+            (
+                Message::new(MessageId::Bitfield, vec![0b1010_0000, 0b0000_0001]),
+                vec![0, 0, 0, 3, 5, 0b1010_0000, 0b0000_0001],
+            ),
+            (
+                Message::new(MessageId::Request, request.encode()),
+                vec![0, 0, 0, 13, 6, 0, 0, 0, 1, 0, 0, 0x40, 0, 0, 0, 0x40, 0],
+            ),
+            (
+                Message::piece(1, 0x4000, &[9, 8, 7]),
+                vec![0, 0, 0, 12, 7, 0, 0, 0, 1, 0, 0, 0x40, 0, 9, 8, 7],
+            ),
+            (
+                Message::new(MessageId::Cancel, request.encode()),
+                vec![0, 0, 0, 13, 8, 0, 0, 0, 1, 0, 0, 0x40, 0, 0, 0, 0x40, 0],
+            ),
+            (Message::port(6881), vec![0, 0, 0, 3, 9, 0x1a, 0xe1]),
+            (
+                Message::new(MessageId::Extended, vec![0, b'd', b'e']),
+                vec![0, 0, 0, 4, 20, 0, b'd', b'e'],
+            ),
+        ];
+
+        for (message, bytes) in cases {
+            assert_eq!(message.encode(), bytes, "encoding {:?}", message.id);
+            let decoded = Message::decode(&bytes).unwrap().unwrap();
+            assert_eq!(decoded.id, message.id);
+            assert_eq!(decoded.length, message.length);
+            assert_eq!(decoded.payload, message.payload);
+        }
+    }
+
+    #[test]
+    fn decoding_wants_exactly_one_frame() {
+        assert!(matches!(
+            Message::decode(&[0, 0, 0, 2, 4, 0]),
+            Ok(Some(Message {
+                id: MessageId::Have,
+                ..
+            }))
+        ));
+        assert!(matches!(
+            Message::decode(&[0, 0, 0, 1, 1, 0, 0, 0, 0]),
+            Err(MessageError::Trailing(4))
+        ));
+        assert!(matches!(
+            Message::decode(&[0, 0, 0, 5, 4, 0]),
+            Err(MessageError::Io(_))
+        ));
+        assert!(matches!(Message::decode(&[]), Err(MessageError::Io(_))));
+    }
+
+    #[test]
+    fn handshakes_are_laid_out_as_the_spec_says() {
+        let mut handshake =
+            Handshake::new("BitTorrent protocol".to_string(), [0x11; 20], [0x22; 20]);
+        handshake.set_supports_extensions();
+        handshake.set_supports_dht();
+
+        let bytes = handshake.encode();
+        assert_eq!(bytes[0], 19);
+        assert_eq!(&bytes[1..20], b"BitTorrent protocol");
+        assert_eq!(bytes[20..28], [0, 0, 0, 0, 0, 0x10, 0, 0x01]);
+        assert_eq!(bytes[28..48], [0x11; 20]);
+        assert_eq!(bytes[48..68], [0x22; 20]);
+
+        // Another protocol is the caller's to reject; reading it must not panic.
+        let mut other = [0xff; 68];
+        other[0] = 200;
+        let read = Handshake::decode(other);
+        assert_eq!(read.info_hash, [0xff; 20]);
+        assert_eq!(read.peer_id, [0xff; 20]);
+    }
+
+    #[test]
+    fn unknown_ids_are_preserved() {
+        let mut bytes = Cursor::new(vec![0, 0, 0, 3, 42, 1, 2]);
+        let message = Message::read_from_socket(&mut bytes).unwrap().unwrap();
+        assert_eq!(message.id, MessageId::Unknown(42));
+        assert_eq!(message.payload, vec![1, 2]);
+    }
+
+    #[test]
+    fn truncated_frame_is_an_error() {
+        let mut bytes = Cursor::new(vec![0, 0, 0, 5, 7, 0]);
+        assert!(matches!(
+            Message::read_from_socket(&mut bytes),
+            Err(MessageError::Io(_))
+        ));
+    }
+
+    #[test]
+    fn oversized_frame_is_rejected() {
+        let mut bytes = Cursor::new(vec![0xff, 0xff, 0xff, 0xff]);
+        assert!(matches!(
+            Message::read_from_socket(&mut bytes),
+            Err(MessageError::TooLarge(_))
+        ));
+    }
+
+    #[test]
+    fn claimed_length_is_not_allocated_up_front() {
+        // Claims 2 MiB, then sends a few bytes.
+        let bytes = [0, 0x20, 0, 0, 7, 1, 2, 3];
+        let mut reader = MessageReader::new();
+        assert!(matches!(
+            reader.read(&mut Cursor::new(bytes)),
+            Err(MessageError::Io(_))
+        ));
+        assert!(reader.buf.capacity() <= 64 * 1024);
+    }
+
+    #[test]
+    fn reader_reuses_its_buffer() {
+        let mut bytes = Vec::new();
+        for index in 0..3u8 {
+            Message::new(MessageId::Piece, vec![index; 16]).encode_into(&mut bytes);
+        }
+        let mut socket = Cursor::new(bytes);
+        let mut reader = MessageReader::new();
+
+        for index in 0..3u8 {
+            let message = reader.read(&mut socket).unwrap().unwrap();
+            assert_eq!(message.id, MessageId::Piece);
+            assert_eq!(message.payload, &[index; 16]);
+        }
+        assert_eq!(reader.buf.capacity(), 16);
+    }
+
+    fn message(gen: &mut Gen) -> Message {
+        Message::new(MessageId::from(gen.byte()), gen.bytes(64))
+    }
+
+    #[test]
+    fn every_message_id_survives_a_round_trip() {
+        for id in 0..=u8::MAX {
+            assert_eq!(u8::from(MessageId::from(id)), id);
+        }
+    }
+
+    #[test]
+    fn messages_read_back_as_they_were_written() {
+        arbitrary::check(|gen| {
+            let messages = (0..gen.below(4) + 1)
+                .map(|_| message(gen))
+                .collect::<Vec<_>>();
+            let mut bytes = Vec::new();
+            let mut writer = MessageWriter::new();
+            for message in &messages {
+                writer.write(&mut bytes, message).unwrap();
+            }
+            assert_eq!(
+                bytes,
+                messages
+                    .iter()
+                    .flat_map(Message::encode)
+                    .collect::<Vec<_>>()
+            );
+
+            let mut socket = Cursor::new(&bytes);
+            let mut reused = Cursor::new(&bytes);
+            let mut reader = MessageReader::new();
+            for message in &messages {
+                assert_eq!(message.length as usize, message.payload.len() + 1);
+                let read = Message::read_from_socket(&mut socket).unwrap().unwrap();
+                assert_eq!(read.length, message.length);
+                assert_eq!(read.id, message.id);
+                assert_eq!(read.payload, message.payload);
+                let read = reader.read(&mut reused).unwrap().unwrap();
+                assert_eq!(read.id, message.id);
+                assert_eq!(read.payload, message.payload);
+            }
+            assert_eq!(socket.position() as usize, bytes.len());
+        });
+    }
+
+    #[test]
+    fn truncated_messages_are_errors() {
+        arbitrary::check(|gen| {
+            let bytes = message(gen).encode();
+            let mut truncated = Cursor::new(&bytes[..gen.below(bytes.len())]);
+            assert!(Message::read_from_socket(&mut truncated).is_err());
+        });
+    }
+
+    #[test]
+    fn block_requests_read_back_as_they_were_written() {
+        arbitrary::check(|gen| {
+            let request = BlockRequest {
+                index: gen.u64() as u32,
+                begin: gen.u64() as u32,
+                length: gen.u64() as u32,
+            };
+            assert_eq!(BlockRequest::decode(&request.encode()), Some(request));
+            let bytes = gen.bytes(24);
+            if bytes.len() != 12 {
+                assert_eq!(BlockRequest::decode(&bytes), None);
+            }
+        });
+    }
+
+    #[test]
+    fn handshakes_read_back_as_they_were_written() {
+        arbitrary::check(|gen| {
+            let info_hash: [u8; 20] = gen.array();
+            let peer_id = gen.array();
+            let mut handshake =
+                Handshake::new("BitTorrent protocol".to_string(), info_hash, peer_id);
+            handshake.reserved = gen.array();
+
+            let bytes = handshake.encode();
+            assert_eq!(bytes.len(), 68);
+            assert_eq!(&bytes[..20], b"\x13BitTorrent protocol");
+            let read = Handshake::decode(bytes.try_into().unwrap());
+            assert_eq!(read.pstr, handshake.pstr);
+            assert_eq!(read.reserved, handshake.reserved);
+            assert_eq!(read.info_hash, info_hash);
+            assert_eq!(read.peer_id, peer_id);
+        });
+    }
+
+    #[test]
+    fn setting_a_capability_leaves_the_other_reserved_bits_alone() {
+        arbitrary::check(|gen| {
+            let mut handshake = Handshake::decode([0; 68]);
+            handshake.reserved = gen.array();
+            let before = handshake.reserved;
+            if gen.bool() {
+                handshake.set_supports_dht();
+                assert!(handshake.supports_dht());
+            } else {
+                handshake.set_supports_extensions();
+                assert!(handshake.supports_extensions());
+            }
+            let changed = handshake
+                .reserved
+                .iter()
+                .zip(before)
+                .map(|(after, before)| (after ^ before).count_ones())
+                .sum::<u32>();
+            assert!(changed <= 1);
+        });
+    }
+
+    /// Compares the allocating codec with the reused buffers over 10k block-sized messages.
+    /// Run with `cargo test --release -- --ignored --nocapture codec_throughput`.
+    #[test]
+    #[ignore]
+    fn codec_throughput() {
+        const MESSAGES: usize = 10_000;
+        let message = Message::new(MessageId::Piece, vec![7; 16384 + 8]);
+
+        let mut bytes = Vec::new();
+        for _ in 0..MESSAGES {
+            message.encode_into(&mut bytes);
+        }
+
+        let start = Instant::now();
+        for _ in 0..MESSAGES {
+            std::io::sink().write_all(&message.encode()).unwrap();
+        }
+        let allocating_write = start.elapsed();
+
+        let start = Instant::now();
+        let mut writer = MessageWriter::new();
+        for _ in 0..MESSAGES {
+            writer.write(&mut std::io::sink(), &message).unwrap();
+        }
+        let reused_write = start.elapsed();
+
+        let start = Instant::now();
+        let mut socket = Cursor::new(&bytes);
+        let mut total = 0;
+        for _ in 0..MESSAGES {
+            let message = Message::read_from_socket(&mut socket).unwrap().unwrap();
+            total += message.payload.len();
+        }
+        let allocating_read = start.elapsed();
+
+        let start = Instant::now();
+        let mut socket = Cursor::new(&bytes);
+        let mut reader = MessageReader::new();
+        for _ in 0..MESSAGES {
+            let message = reader.read(&mut socket).unwrap().unwrap();
+            total -= message.payload.len();
+        }
+        let reused_read = start.elapsed();
+        assert_eq!(total, 0);
+
+        let per_second = |elapsed: std::time::Duration| MESSAGES as f64 / elapsed.as_secs_f64();
+        println!(
+            "{} messages: write {:.0}/s -> {:.0}/s, read {:.0}/s -> {:.0}/s",
+            MESSAGES,
+            per_second(allocating_write),
+            per_second(reused_write),
+            per_second(allocating_read),
+            per_second(reused_read)
+        );
+    }
+}